@@ -0,0 +1,28 @@
+//! 启用 `embedded` 特性时，把环境变量指定的模型和分词器复制到 `OUT_DIR`，供 `include_bytes!` 嵌入。
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_EMBEDDED").is_none() {
+        return;
+    }
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    for (var, name) in [
+        ("LLM_RS_EMBED_MODEL", "model.bin"),
+        ("LLM_RS_EMBED_TOKENIZER", "tokenizer.bin"),
+    ] {
+        println!("cargo:rerun-if-env-changed={var}");
+        // 没有指定文件时嵌入空文件，使 `--all-features` 的检查可以通过，运行时由 `embedded` 模块报错
+        let Some(src) = env::var_os(var) else {
+            println!("cargo:warning=feature `embedded` without {var}, embedding an empty file");
+            fs::write(out.join(name), []).unwrap();
+            continue;
+        };
+        let src = PathBuf::from(src);
+        println!("cargo:rerun-if-changed={}", src.display());
+        fs::copy(&src, out.join(name))
+            .unwrap_or_else(|e| panic!("failed to copy {}: {e}", src.display()));
+    }
+}
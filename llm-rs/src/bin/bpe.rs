@@ -0,0 +1,41 @@
+//! GPT-2 字节级 BPE 分词：把 `vocab.json` 和 `merges.txt` 转为单文件的二进制格式，或编码文本并显示词边界。
+//!
+//! ```shell
+//! cargo run --release --bin bpe -- convert <vocab.json> <merges.txt> <tokenizer.bpe>
+//! cargo run --release --bin bpe -- encode <tokenizer.bpe> <text>
+//! ```
+
+use llm_rs::bpe::Bpe;
+use std::{env::args, path::Path, process::exit};
+
+fn main() {
+    let args = args().collect::<Vec<_>>();
+    match &*args.iter().map(String::as_str).collect::<Vec<_>>() {
+        [_, "convert", vocab, merges, output] => {
+            let bpe = Bpe::from_files(vocab, merges).unwrap();
+            bpe.save(output).unwrap();
+            println!(
+                "{} tokens, eos {:?}, saved {}",
+                bpe.n_voc(),
+                bpe.eos,
+                Path::new(output).display()
+            )
+        }
+        [_, "encode", path, text] => {
+            let bpe = Bpe::load(path).unwrap();
+            let tokens = bpe.encode(text);
+            let pieces = tokens
+                .iter()
+                .map(|&t| bpe.decode(t).escape_ascii().to_string())
+                .collect::<Vec<_>>();
+            println!("{tokens:?}");
+            println!("{}", pieces.join("|"))
+        }
+        _ => {
+            eprintln!(
+                "usage: bpe convert <vocab.json> <merges.txt> <tokenizer.bpe>\n       bpe encode <tokenizer.bpe> <text>"
+            );
+            exit(1)
+        }
+    }
+}
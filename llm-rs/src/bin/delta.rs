@@ -0,0 +1,70 @@
+//! 生成或应用参数增量补丁，模型可以是 llm.c 的 `.bin` 或 safetensors 检查点。
+//!
+//! ```shell
+//! cargo run --release --bin delta -- diff <base> <tuned> <delta.safetensors> [max_rank]
+//! cargo run --release --bin delta -- apply <base> <delta.safetensors> <output.safetensors>
+//! ```
+
+use llm_rs::{
+    Blob,
+    delta::{Delta, DeltaOptions},
+    llmc,
+    quant::{Checkpoint, save_gpt2},
+};
+use memmap2::Mmap;
+use std::{env::args, fs, path::Path};
+
+fn load(path: &str) -> llmc::Gpt2<Blob> {
+    if path.ends_with(".safetensors") {
+        Checkpoint::open(path).unwrap().gpt2().unwrap()
+    } else {
+        let file = fs::File::open(path).unwrap();
+        let mmap = unsafe { Mmap::map(&file) }.unwrap();
+        llmc::Gpt2::new(&mmap).map(Blob::from)
+    }
+}
+
+fn mib(n: usize) -> f64 {
+    n as f64 / (1 << 20) as f64
+}
+
+fn main() {
+    let args = args().collect::<Vec<_>>();
+    match &*args.iter().map(String::as_str).collect::<Vec<_>>() {
+        [_, "diff", base, tuned, output, rest @ ..] => {
+            let mut options = DeltaOptions::default();
+            if let [max_rank] = rest {
+                options.max_rank = max_rank.parse().unwrap()
+            }
+            let base = load(base);
+            let tuned = load(tuned);
+            let delta = Delta::diff(&base, &tuned, &options);
+            for (name, patch) in &delta.patches {
+                println!("{name:<24} {:.3} MiB", mib(patch.nbytes()))
+            }
+            delta.save(output).unwrap();
+
+            let mut size = 0;
+            base.for_each(|_, t| size += t.get().len());
+            println!(
+                "saved {} ({:.1} MiB, {:.2}% of the model)",
+                Path::new(output).display(),
+                mib(delta.nbytes()),
+                delta.nbytes() as f64 / size as f64 * 100.
+            )
+        }
+        [_, "apply", base, delta, output] => {
+            let delta = Delta::load(delta).unwrap();
+            let gpt2 = delta.apply(load(base)).unwrap();
+            save_gpt2(output, &gpt2).unwrap();
+            println!(
+                "applied {} patches, saved {}",
+                delta.patches.len(),
+                Path::new(output).display()
+            )
+        }
+        _ => panic!(
+            "usage: delta diff <base> <tuned> <delta.safetensors> [max_rank]\n       delta apply <base> <delta.safetensors> <output.safetensors>"
+        ),
+    }
+}
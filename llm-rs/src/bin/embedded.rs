@@ -0,0 +1,55 @@
+//! 用嵌入二进制的模型和分词器生成文本，运行时不读取任何文件。
+//!
+//! ```shell
+//! LLM_RS_EMBED_MODEL=<gpt2_124M.bin> LLM_RS_EMBED_TOKENIZER=<gpt2_tokenizer.bin> \
+//!     cargo run --release --features embedded --bin embedded -- [prompt] [max_tokens]
+//! ```
+
+use llm_rs::{
+    Blob, Context, embedded,
+    generate::{GenerationConfig, generate_cached},
+    llmc::safe_print,
+    log,
+    nn::gpt2::Gpt2,
+    op::topk,
+    truncate::Truncation,
+};
+use rw_rc::RwRc;
+use std::{env::args, process::exit};
+
+fn main() {
+    let _trace = log::init();
+    let prompt = args().nth(1).unwrap_or_else(|| "Once upon a time".into());
+    let max_tokens = args().nth(2).map_or(32, |n| n.parse().unwrap());
+
+    let (tokenizer, gpt2) = match (embedded::tokenizer(), embedded::model()) {
+        (Ok(tokenizer), Ok(gpt2)) => (tokenizer, gpt2),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{e}");
+            exit(1)
+        }
+    };
+    let prompt = tokenizer.encode(prompt.as_bytes()).unwrap();
+    let config = GenerationConfig {
+        n_ctx: gpt2.config.n_seq,
+        n_voc: gpt2.config.n_voc,
+        max_tokens,
+        eos: Some(tokenizer.eos),
+        truncation: Truncation::KeepTail,
+        echo: false,
+        stop: None,
+        cost: gpt2.config.cost(),
+    };
+
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", gpt2.map(Blob::from).map(RwRc::new));
+    let argmax = |logits: &[f32]| topk::argmax(logits) as u16;
+    let result = generate_cached(&mut ctx, "gpt2", &mut gpt2, &prompt, &config, argmax, |t| {
+        tokenizer.decode(t)
+    });
+    for &t in &prompt {
+        safe_print(tokenizer.decode(t))
+    }
+    safe_print(result.text.as_bytes());
+    println!()
+}
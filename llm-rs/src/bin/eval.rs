@@ -0,0 +1,34 @@
+//! 在一个目录的任务上评估模型，打印各任务的准确率，可选地把报告写成 JSON。
+//!
+//! ```shell
+//! cargo run --release --bin eval -- <gpt2_124M.bin> <gpt2_tokenizer.bin> <tasks> [report.json]
+//! ```
+
+use llm_rs::{
+    eval,
+    llmc::{self, Tokenizer},
+    log,
+};
+use memmap2::Mmap;
+use std::{env::args_os, fs, sync::Arc};
+
+fn main() {
+    let _trace = log::init();
+    let args = args_os().collect::<Vec<_>>();
+    let (model, tokenizer, tasks, output) = match &*args {
+        [_, model, tokenizer, tasks] => (model, tokenizer, tasks, None),
+        [_, model, tokenizer, tasks, output] => (model, tokenizer, tasks, Some(output)),
+        _ => panic!("usage: eval <model> <tokenizer> <tasks> [report.json]"),
+    };
+
+    let file = fs::File::open(model).unwrap();
+    let mmap = Arc::new(unsafe { Mmap::map(&file) }.unwrap());
+    let gpt2 = llmc::Gpt2::mapped(&mmap);
+    let tokenizer = Tokenizer::new(tokenizer).unwrap();
+
+    let report = eval::run_suite(tasks, &gpt2, &tokenizer).unwrap();
+    print!("{report}");
+    if let Some(output) = output {
+        fs::write(output, serde_json::to_string_pretty(&report).unwrap()).unwrap()
+    }
+}
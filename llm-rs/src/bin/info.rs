@@ -0,0 +1,30 @@
+//! 以 JSON 输出模型卡片：架构、参数量、上下文长度、量化方案、词表、来源哈希和许可证。
+//!
+//! ```shell
+//! cargo run --release --bin info -- <model.bin|model.safetensors> [tokenizer.bin|tokenizer.bpe]
+//! ```
+
+use llm_rs::{bpe::Bpe, llmc::Tokenizer, metadata::ModelMetadata};
+use std::{env::args_os, path::Path, process::exit};
+
+fn main() {
+    let args = args_os().collect::<Vec<_>>();
+    let (model, tokenizer) = match &*args {
+        [_, model] => (model, None),
+        [_, model, tokenizer] => (model, Some(Path::new(tokenizer))),
+        _ => {
+            eprintln!("usage: info <model.bin|model.safetensors> [tokenizer.bin|tokenizer.bpe]");
+            exit(1)
+        }
+    };
+
+    let mut meta = ModelMetadata::open(model).unwrap();
+    if let Some(path) = tokenizer {
+        meta = if path.extension().is_some_and(|e| e == "bpe") {
+            meta.with_tokenizer(&Bpe::load(path).unwrap())
+        } else {
+            meta.with_tokenizer(&Tokenizer::new(path).unwrap())
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&meta).unwrap())
+}
@@ -0,0 +1,33 @@
+//! 大海捞针评估：在不同长度的填充上下文中不同深度处插入口令，打印检索准确率。
+//!
+//! ```shell
+//! cargo run --release --bin needle -- <gpt2_124M.bin> <gpt2_tokenizer.bin> [len]...
+//! ```
+
+use llm_rs::{
+    eval::{self, NeedleConfig},
+    llmc::{self, Tokenizer},
+    log,
+};
+use memmap2::Mmap;
+use std::{env::args, fs, sync::Arc};
+
+fn main() {
+    let _trace = log::init();
+    let args = args().collect::<Vec<_>>();
+    let [_, model, tokenizer, lengths @ ..] = &*args else {
+        panic!("usage: needle <model> <tokenizer> [len]...")
+    };
+
+    let file = fs::File::open(model).unwrap();
+    let mmap = Arc::new(unsafe { Mmap::map(&file) }.unwrap());
+    let gpt2 = llmc::Gpt2::mapped(&mmap);
+    let tokenizer = Tokenizer::new(tokenizer).unwrap();
+
+    let mut config = NeedleConfig::default();
+    if !lengths.is_empty() {
+        config.lengths = lengths.iter().map(|s| s.parse().unwrap()).collect()
+    }
+    let report = eval::needle(&gpt2, &tokenizer, &config).unwrap();
+    print!("{report}")
+}
@@ -0,0 +1,32 @@
+//! 在校准集上比较 f32 模型与量化模型的逐层误差和困惑度。
+//!
+//! ```shell
+//! cargo run --release --bin quant_report -- <llm.c> <recipe> [n_batch]
+//! ```
+
+use llm_rs::{
+    Blob,
+    llmc::{self, DataLoader},
+    quant::{Recipe, calibrate},
+};
+use memmap2::Mmap;
+use std::{env::args_os, fs, path::PathBuf};
+
+fn main() {
+    let args = args_os().collect::<Vec<_>>();
+    let (path, recipe, n_batch) = match &*args {
+        [_, path, recipe] => (path, recipe, 8),
+        [_, path, recipe, n] => (path, recipe, n.to_str().unwrap().parse().unwrap()),
+        _ => panic!("usage: quant_report <llm.c> <recipe> [n_batch]"),
+    };
+    let path = PathBuf::from(path);
+
+    let recipe = Recipe::parse(&fs::read_to_string(recipe).unwrap()).unwrap();
+    let mut loader = DataLoader::new(&path, "*/tiny_shakespeare_val.bin", 4, 64, false);
+
+    let file = fs::File::open(path.join("gpt2_124M.bin")).unwrap();
+    let mmap = unsafe { Mmap::map(&file) }.unwrap();
+    let gpt2 = llmc::Gpt2::new(&mmap).map(Blob::from);
+
+    print!("{}", calibrate(&gpt2, &recipe, &mut loader, n_batch))
+}
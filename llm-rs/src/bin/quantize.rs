@@ -0,0 +1,39 @@
+//! 按配方量化 llm.c 的 GPT-2 检查点，保存为 safetensors。
+//!
+//! ```shell
+//! cargo run --release --bin quantize -- <gpt2_124M.bin> <recipe> <output.safetensors>
+//! ```
+
+use llm_rs::{
+    Blob, llmc,
+    quant::{Recipe, quantize_gpt2, save_gpt2},
+};
+use memmap2::Mmap;
+use std::{env::args_os, fs, path::Path};
+
+fn main() {
+    let [_, model, recipe, output] = &*args_os().collect::<Vec<_>>() else {
+        panic!("usage: quantize <gpt2_124M.bin> <recipe> <output.safetensors>")
+    };
+
+    let recipe = Recipe::parse(&fs::read_to_string(recipe).unwrap()).unwrap();
+
+    let file = fs::File::open(model).unwrap();
+    let mmap = unsafe { Mmap::map(&file) }.unwrap();
+    let gpt2 = llmc::Gpt2::new(&mmap).map(Blob::from);
+
+    let (gpt2, schemes) = quantize_gpt2(gpt2, &recipe);
+    for (name, scheme) in schemes {
+        println!("{name:<24} {scheme}")
+    }
+
+    save_gpt2(output, &gpt2).unwrap();
+
+    let mut size = 0;
+    gpt2.for_each(|_, t| size += t.get().len());
+    println!(
+        "saved {} ({:.1} MiB)",
+        Path::new(output).display(),
+        size as f64 / (1 << 20) as f64
+    )
+}
@@ -0,0 +1,118 @@
+//! 在合成任务上从头训练小模型，然后逐个样本补全并统计准确率。
+//!
+//! ```shell
+//! cargo run --release --bin tiny -- <copy|addition|induction> [steps]
+//! ```
+
+use digit_layout::types;
+use llm_rs::{
+    Context, Tensor,
+    generate::{GenerationConfig, Usage, generate_cached},
+    llmc::{self, DataLoader, Gpt2Config},
+    log, nn,
+    op::topk,
+    optimizer::AdamW,
+    synthetic::{N_VOC, Task},
+    truncate::Truncation,
+};
+use rand::{SeedableRng, rngs::StdRng};
+use rw_rc::RwRc;
+use std::{env::args, slice, time::Instant};
+
+fn main() {
+    let _trace = log::init();
+    let args = args().collect::<Vec<_>>();
+    let (task, steps) = match &*args {
+        [_, task] => (task, 1000),
+        [_, task, steps] => (task, steps.parse().unwrap()),
+        _ => panic!("usage: tiny <copy|addition|induction> [steps]"),
+    };
+    let task = task.parse::<Task>().unwrap();
+
+    let batch_size = 8;
+    let config = Gpt2Config::tiny(N_VOC);
+    let cost = config.cost();
+    let seq_len = 24;
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let tokens = task.tokens(seq_len, batch_size * steps, &mut rng);
+    let mut loader = DataLoader::from_tokens(tokens, batch_size, seq_len, false);
+
+    let mut ctx = Context::new(false);
+    let gpt2 = llmc::Gpt2::random(config, &mut rng).map(RwRc::new);
+    let mut gpt2 = ctx.init::<nn::gpt2::Gpt2>("gpt2", gpt2);
+    let mut loss = ctx.init::<nn::loss::Loss>("loss", N_VOC);
+    let mut adamw = AdamW::new(1e-3, 0.9, 0.999, 1e-8, 0.);
+
+    let time = Instant::now();
+    for step in 1..=steps {
+        let _span = tracing::info_span!("step", step).entered();
+        let [inputs, targets] = loader.load();
+        let shape = [batch_size, seq_len];
+        let tokens = Tensor::new(types::U16, &shape).map(|_| RwRc::new(inputs.into()));
+        let targets = Tensor::new(types::U16, &shape).map(|_| RwRc::new(targets.into()));
+
+        let logits = ctx.forward("gpt2", &mut gpt2, [tokens.share()]);
+        let losses = ctx.forward("loss", &mut loss, [logits[0].clone(), targets.share()]);
+        let losses_ = losses[0].cloned().merge(0, 2);
+        let losses_ = losses_.as_ref().map(|b| &**b.read()).vector::<f32>();
+        let train_loss = losses_.iter().sum::<f32>() / losses_.len() as f32;
+        ctx.zero_grad();
+
+        let dlosses = ctx.tensor(types::F32, &shape);
+        dlosses
+            .cloned()
+            .merge(0, 2)
+            .as_ref()
+            .map(|b| &mut **b.write())
+            .vector_mut::<f32>()
+            .fill(1. / (batch_size * seq_len) as f32);
+        let dlogits = ctx.backward("loss", &mut loss, [dlosses.share()]);
+        let _ = ctx.backward("gpt2", &mut gpt2, dlogits);
+        ctx.update(&mut adamw);
+        adamw.next();
+
+        if step % 50 == 0 || step == steps {
+            tracing::info!(train_loss, elapsed = ?time.elapsed(), "trained")
+        }
+    }
+
+    let generation = GenerationConfig {
+        n_ctx: seq_len,
+        n_voc: N_VOC,
+        max_tokens: seq_len,
+        eos: Some(b'\n' as _),
+        truncation: Truncation::KeepTail,
+        echo: false,
+        stop: None,
+        cost,
+    };
+    let bytes = (0..=u8::MAX).collect::<Vec<_>>();
+    let decode = |t: u16| slice::from_ref(&bytes[t as usize]);
+    let argmax = |logits: &[f32]| topk::argmax(logits) as u16;
+
+    let n_test = 20;
+    let mut n_correct = 0;
+    let mut usage = Usage::default();
+    for _ in 0..n_test {
+        let example = task.example(&mut rng);
+        let (prompt, answer) = task.split(&example);
+        let prompt_ = prompt.bytes().map(u16::from).collect::<Vec<_>>();
+        let output = generate_cached(
+            &mut ctx,
+            "gpt2",
+            &mut gpt2,
+            &prompt_,
+            &generation,
+            argmax,
+            decode,
+        );
+        usage.add(&output);
+        let output = output.text;
+        let ok = output == answer;
+        n_correct += ok as usize;
+        println!("{} {prompt}{output}", if ok { "✓" } else { "✗" })
+    }
+    println!("{task}: {n_correct}/{n_test} correct");
+    println!("{}", serde_json::to_string(&usage).unwrap())
+}
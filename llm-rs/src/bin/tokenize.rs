@@ -0,0 +1,108 @@
+//! 检查分词器：编码、解码并显示词边界，或在文件上验证编码后解码能否还原并报告编码吞吐。
+//!
+//! ```shell
+//! cargo run --release --bin tokenize -- <tokenizer.bin> encode <text>
+//! cargo run --release --bin tokenize -- <tokenizer.bin> decode <id>...
+//! cargo run --release --bin tokenize -- <tokenizer.bin> verify <file>
+//! ```
+
+use llm_rs::llmc::Tokenizer;
+use std::{env::args, fs, process::exit, time::Instant};
+
+/// 并行编码时每块的字节数。
+const CHUNK_SIZE: usize = 1 << 20;
+
+fn main() {
+    let args = args().collect::<Vec<_>>();
+    let [_, path, cmd, rest @ ..] = &*args else {
+        usage()
+    };
+    let tokenizer = Tokenizer::new(path).unwrap();
+    match (&**cmd, rest) {
+        ("encode", [text]) => {
+            let tokens = tokenizer.encode(text.as_bytes()).unwrap_or_else(|i| {
+                eprintln!("byte {i} is not covered by the vocabulary");
+                exit(1)
+            });
+            show(&tokenizer, &tokens);
+            println!(
+                "{} bytes, {} chars, {} tokens, {:.2} bytes/token",
+                text.len(),
+                text.chars().count(),
+                tokens.len(),
+                text.len() as f64 / tokens.len().max(1) as f64,
+            )
+        }
+        ("decode", ids) if !ids.is_empty() => {
+            let tokens = ids
+                .iter()
+                .map(|id| match id.parse::<u16>() {
+                    Ok(id) if (id as usize) < tokenizer.n_voc() => id,
+                    _ => {
+                        eprintln!("invalid token id {id}");
+                        exit(1)
+                    }
+                })
+                .collect::<Vec<_>>();
+            show(&tokenizer, &tokens);
+            let bytes = decode(&tokenizer, &tokens);
+            println!("{}", String::from_utf8_lossy(&bytes))
+        }
+        ("verify", [file]) => {
+            let text = fs::read(file).unwrap();
+            let time = Instant::now();
+            let tokens = tokenizer
+                .encode_parallel(&text, CHUNK_SIZE)
+                .unwrap_or_else(|i| {
+                    eprintln!("byte {i} is not covered by the vocabulary");
+                    exit(1)
+                });
+            let time = time.elapsed();
+            let bytes = decode(&tokenizer, &tokens);
+            println!(
+                "{} bytes, {} tokens, {:.2} bytes/token, {:.2} MB/s on {} threads",
+                text.len(),
+                tokens.len(),
+                text.len() as f64 / tokens.len().max(1) as f64,
+                text.len() as f64 / 1e6 / time.as_secs_f64(),
+                rayon::current_num_threads(),
+            );
+            match text.iter().zip(&bytes).position(|(a, b)| a != b) {
+                None if text.len() == bytes.len() => println!("round-trip ok"),
+                i => {
+                    let i = i.unwrap_or(text.len().min(bytes.len()));
+                    println!("round-trip mismatch at byte {i}");
+                    exit(1)
+                }
+            }
+        }
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: tokenize <tokenizer.bin> <encode <text>|decode <id>...|verify <file>>");
+    exit(1)
+}
+
+fn decode(tokenizer: &Tokenizer, tokens: &[u16]) -> Vec<u8> {
+    tokens
+        .iter()
+        .flat_map(|&t| tokenizer.decode(t))
+        .copied()
+        .collect()
+}
+
+/// 每行一个词：编号、字节数和转义后的内容，最后一行用 `|` 标出词边界。
+fn show(tokenizer: &Tokenizer, tokens: &[u16]) {
+    let mut boundaries = String::new();
+    for &t in tokens {
+        let bytes = tokenizer.decode(t);
+        let piece = bytes.escape_ascii().to_string();
+        println!("{t:>6} {:>3} {piece}", bytes.len());
+        boundaries.push_str(&piece);
+        boundaries.push('|')
+    }
+    boundaries.pop();
+    println!("{boundaries}")
+}
@@ -1,43 +1,116 @@
-﻿use std::{
+﻿use crate::prefetch::Region;
+use memmap2::Mmap;
+use std::{
     alloc::{Layout, alloc, alloc_zeroed, dealloc},
     ops::{Deref, DerefMut},
     ptr::NonNull,
+    sync::{Arc, OnceLock},
 };
 
-pub struct Blob {
-    ptr: NonNull<u8>,
+/// 字节缓冲区，克隆时共享内存，修改时才复制（写时复制）。
+pub struct Blob(Arc<Raw>);
+
+struct Raw {
+    /// 延迟分配的缓冲区在第一次访问时才分配。
+    ptr: OnceLock<NonNull<u8>>,
     len: usize,
+    zeroed: bool,
+    /// 引用内存映射的只读数据，`ptr` 指向其中。
+    mapped: Option<Region>,
 }
 
+unsafe impl Send for Raw {}
+unsafe impl Sync for Raw {}
+
 impl Blob {
     pub fn new(len: usize) -> Self {
-        Self {
-            ptr: NonNull::new(unsafe {
-                alloc(Layout::from_size_align(len, align_of::<usize>()).unwrap())
-            })
-            .unwrap(),
-            len,
-        }
+        let blob = Self::lazy(len, false);
+        blob.0.ptr();
+        blob
     }
 
     pub fn new_zeroed(len: usize) -> Self {
-        Self {
-            ptr: NonNull::new(unsafe {
-                alloc_zeroed(Layout::from_size_align(len, align_of::<usize>()).unwrap())
-            })
-            .unwrap(),
+        let blob = Self::lazy(len, true);
+        blob.0.ptr();
+        blob
+    }
+
+    /// 延迟分配的缓冲区，长度已知，第一次读写时才分配内存，`zeroed` 时分配后清零。
+    pub fn lazy(len: usize, zeroed: bool) -> Self {
+        Self(Arc::new(Raw {
+            ptr: OnceLock::new(),
             len,
-        }
+            zeroed,
+            mapped: None,
+        }))
+    }
+
+    /// 直接引用内存映射中的 `data`，不复制，第一次修改时才复制出来。
+    pub fn mapped(mmap: &Arc<Mmap>, data: &[u8]) -> Self {
+        let start = (data.as_ptr() as usize)
+            .checked_sub(mmap.as_ptr() as usize)
+            .expect("data is not in the mapping");
+        let region = Region::new(mmap.clone(), start..start + data.len());
+        Self(Arc::new(Raw {
+            ptr: NonNull::new(data.as_ptr().cast_mut()).unwrap().into(),
+            len: data.len(),
+            zeroed: false,
+            mapped: Some(region),
+        }))
+    }
+
+    /// 内存映射的 `Blob` 在文件中的位置，供 [`Region::will_need`] 预取。
+    pub fn region(&self) -> Option<&Region> {
+        self.0.mapped.as_ref()
+    }
+
+    /// 字节数，不会触发分配。
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.0.len
+    }
+
+    /// 是否已经分配了内存。
+    pub fn is_allocated(&self) -> bool {
+        self.0.ptr.get().is_some()
+    }
+
+    /// 是否与其他 `Blob` 共享内存。
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.0) > 1
+    }
+}
+
+impl Raw {
+    fn layout(&self) -> Layout {
+        Layout::from_size_align(self.len, align_of::<usize>()).unwrap()
+    }
+
+    fn ptr(&self) -> NonNull<u8> {
+        *self.ptr.get_or_init(|| {
+            // 不能分配 0 字节，空缓冲区使用对齐的悬空指针
+            if self.len == 0 {
+                return NonNull::<usize>::dangling().cast();
+            }
+            let ptr = unsafe {
+                if self.zeroed {
+                    alloc_zeroed(self.layout())
+                } else {
+                    alloc(self.layout())
+                }
+            };
+            NonNull::new(ptr).unwrap()
+        })
     }
 }
 
-impl Drop for Blob {
+impl Drop for Raw {
     fn drop(&mut self) {
-        unsafe {
-            dealloc(
-                self.ptr.as_ptr(),
-                Layout::from_size_align(self.len, align_of::<usize>()).unwrap(),
-            )
+        if self.mapped.is_some() || self.len == 0 {
+            return;
+        }
+        if let Some(ptr) = self.ptr.get() {
+            unsafe { dealloc(ptr.as_ptr(), self.layout()) }
         }
     }
 }
@@ -45,13 +118,21 @@ impl Drop for Blob {
 impl Deref for Blob {
     type Target = [u8];
     fn deref(&self) -> &Self::Target {
-        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+        unsafe { std::slice::from_raw_parts(self.0.ptr().as_ptr(), self.0.len) }
     }
 }
 
 impl DerefMut for Blob {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+        // 共享或引用内存映射时先复制出独占的副本
+        if self.0.mapped.is_some() || Arc::get_mut(&mut self.0).is_none() {
+            *self = if self.is_allocated() {
+                Self::from(&**self)
+            } else {
+                Self::lazy(self.0.len, self.0.zeroed)
+            }
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.0.ptr().as_ptr(), self.0.len) }
     }
 }
 
@@ -68,8 +149,16 @@ impl<T: Copy> From<&[T]> for Blob {
 
 impl Clone for Blob {
     fn clone(&self) -> Self {
-        let mut ans = Self::new(self.len);
-        ans.copy_from_slice(self);
-        ans
+        Self(self.0.clone())
     }
 }
+
+#[test]
+fn test_empty() {
+    let mut blob = Blob::new_zeroed(0);
+    assert!(blob.is_empty() && blob.as_ptr().cast::<usize>().is_aligned());
+    let clone = blob.clone();
+    blob.copy_from_slice(&[]);
+    assert_eq!(Blob::from(&[0u32; 0][..]).len(), 0);
+    drop(clone)
+}
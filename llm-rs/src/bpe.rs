@@ -0,0 +1,358 @@
+//! GPT-2 的字节级 BPE 分词器。
+//!
+//! 文本先按 GPT-2 的正则切成若干片，每片的 UTF-8 字节各对应一个单字节词，再按合并规则的优先级
+//! 反复合并相邻的一对词，结果与 OpenAI 的 `encoder.py` 相同。可以从 `vocab.json` 和 `merges.txt`
+//! 加载，也可以保存为紧凑的二进制格式。`<|endoftext|>` 等特殊词不从文本中识别，按普通文本编码。
+
+use regex::Regex;
+use std::{collections::HashMap, fmt, fs, io, path::Path};
+
+/// GPT-2 的预分词正则，原式最后的 `\s+(?!\S)` 需要前瞻，由 [`Bpe::split`] 处理。
+const PATTERN: &str = r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+";
+
+/// 二进制格式的魔数。
+const MAGIC: u32 = 20241020;
+const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum BpeError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Format(String),
+}
+
+impl fmt::Display for BpeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "io error: {e}"),
+            Self::Json(e) => write!(f, "invalid vocab.json: {e}"),
+            Self::Format(message) => write!(f, "invalid tokenizer: {message}"),
+        }
+    }
+}
+
+impl From<io::Error> for BpeError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<serde_json::Error> for BpeError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(value)
+    }
+}
+
+fn format<T>(message: impl Into<String>) -> Result<T, BpeError> {
+    Err(BpeError::Format(message.into()))
+}
+
+pub struct Bpe {
+    tokens: Vec<Vec<u8>>,
+    /// 按优先级排列的合并规则。
+    merges: Vec<(u16, u16)>,
+    /// 相邻一对词合并的优先级（越小越先合并）和合并后的词。
+    ranks: HashMap<(u16, u16), (u32, u16)>,
+    /// 每个字节对应的单字节词。
+    bytes: [u16; 256],
+    pattern: Regex,
+    /// `<|endoftext|>` 的编号。
+    pub eos: Option<u16>,
+}
+
+/// GPT-2 在 `vocab.json` 中把字节写成可打印字符：可打印的 Latin-1 字节不变，其余依次映射到 U+0100 之后。
+fn byte_chars() -> [char; 256] {
+    let mut ans = ['\0'; 256];
+    let mut n = 0;
+    for b in 0..=255u8 {
+        ans[b as usize] = if matches!(b, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff) {
+            b as char
+        } else {
+            n += 1;
+            char::from_u32(255 + n).unwrap()
+        }
+    }
+    ans
+}
+
+impl Bpe {
+    /// 从 Hugging Face 或 OpenAI 发布的 `vocab.json` 和 `merges.txt` 加载。
+    pub fn from_files(vocab: impl AsRef<Path>, merges: impl AsRef<Path>) -> Result<Self, BpeError> {
+        let vocab = serde_json::from_slice(&fs::read(vocab)?)?;
+        Self::from_vocab(&vocab, &fs::read_to_string(merges)?)
+    }
+
+    /// 由 `vocab.json` 的内容（词到编号）和 `merges.txt` 的文本构造。
+    pub fn from_vocab(vocab: &HashMap<String, u32>, merges: &str) -> Result<Self, BpeError> {
+        let chars = byte_chars();
+        let unicode = (0..=255u8)
+            .map(|b| (chars[b as usize], b))
+            .collect::<HashMap<_, _>>();
+
+        let n_voc = vocab.values().max().map_or(0, |&id| id as usize + 1);
+        if n_voc > 1 << 16 {
+            return format(format!("{n_voc} tokens do not fit in u16"));
+        }
+        let mut tokens = vec![None; n_voc];
+        for (text, &id) in vocab {
+            let Some(bytes) = text
+                .chars()
+                .map(|c| unicode.get(&c).copied())
+                .collect::<Option<Vec<_>>>()
+            else {
+                return format(format!("token {text:?} is not byte-level"));
+            };
+            tokens[id as usize] = Some(bytes)
+        }
+        let tokens = tokens
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| t.map_or_else(|| format(format!("token id {i} is missing")), Ok))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let id = |text: &str| match vocab.get(text) {
+            Some(&id) => Ok(id as u16),
+            None => format(format!("merge refers to unknown token {text:?}")),
+        };
+        let mut pairs = Vec::new();
+        for line in merges.lines() {
+            if line.starts_with("#version") || line.trim().is_empty() {
+                continue;
+            }
+            let Some((a, b)) = line.split_once(' ') else {
+                return format(format!("invalid merge {line:?}"));
+            };
+            pairs.push((id(a)?, id(b)?))
+        }
+        let eos = vocab.get("<|endoftext|>").map(|&id| id as u16);
+        Self::new(tokens, pairs, eos)
+    }
+
+    /// 由各词的字节和按优先级排列的合并规则构造，每条合并的结果必须在词表中。
+    pub fn new(
+        tokens: Vec<Vec<u8>>,
+        merges: Vec<(u16, u16)>,
+        eos: Option<u16>,
+    ) -> Result<Self, BpeError> {
+        // 重复的词保留编号最小的
+        let mut index = HashMap::with_capacity(tokens.len());
+        for (i, token) in tokens.iter().enumerate().rev() {
+            index.insert(&token[..], i as u16);
+        }
+        let mut bytes = [0; 256];
+        for (b, id) in bytes.iter_mut().enumerate() {
+            match index.get(&[b as u8][..]) {
+                Some(&i) => *id = i,
+                None => return format(format!("byte {b:#04x} has no token")),
+            }
+        }
+        let mut ranks = HashMap::with_capacity(merges.len());
+        for (rank, &(a, b)) in merges.iter().enumerate() {
+            let (Some(x), Some(y)) = (tokens.get(a as usize), tokens.get(b as usize)) else {
+                return format(format!("merge {a} {b} is out of vocabulary"));
+            };
+            let Some(&merged) = index.get(&[&x[..], y].concat()[..]) else {
+                return format(format!("result of merge {a} {b} is not a token"));
+            };
+            ranks.entry((a, b)).or_insert((rank as u32, merged));
+        }
+        Ok(Self {
+            tokens,
+            merges,
+            ranks,
+            bytes,
+            pattern: Regex::new(PATTERN).unwrap(),
+            eos,
+        })
+    }
+
+    pub fn n_voc(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// 词的字节，可以直接作为生成函数的 `decode` 参数。
+    pub fn decode(&self, token: u16) -> &[u8] {
+        &self.tokens[token as usize]
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u16> {
+        let mut ans = Vec::new();
+        for piece in self.split(text) {
+            self.merge(piece.as_bytes(), &mut ans)
+        }
+        ans
+    }
+
+    /// 按 GPT-2 的正则预分词。
+    pub fn split<'t>(&self, text: &'t str) -> Vec<&'t str> {
+        let mut ans = Vec::new();
+        let mut pos = 0;
+        while let Some(m) = self.pattern.find_at(text, pos) {
+            let mut end = m.end();
+            // `\s+(?!\S)`：空白后面还有其他字符时，最后一个空白字符留给下一片
+            let last = m.as_str().chars().next_back().unwrap();
+            if end < text.len() && m.len() > last.len_utf8() && m.as_str().trim().is_empty() {
+                end -= last.len_utf8()
+            }
+            ans.push(&text[m.start()..end]);
+            pos = end
+        }
+        ans
+    }
+
+    /// 对一片的字节反复合并优先级最高的一对，同一对的所有出现从左到右一起合并。
+    fn merge(&self, piece: &[u8], out: &mut Vec<u16>) {
+        let mut parts = piece
+            .iter()
+            .map(|&b| self.bytes[b as usize])
+            .collect::<Vec<_>>();
+        while let Some((_, pair, merged)) = parts
+            .windows(2)
+            .filter_map(|w| {
+                let pair = (w[0], w[1]);
+                self.ranks.get(&pair).map(|&(rank, id)| (rank, pair, id))
+            })
+            .min()
+        {
+            let mut next = Vec::with_capacity(parts.len());
+            let mut i = 0;
+            while i < parts.len() {
+                if parts.get(i + 1).is_some_and(|&b| (parts[i], b) == pair) {
+                    next.push(merged);
+                    i += 2
+                } else {
+                    next.push(parts[i]);
+                    i += 1
+                }
+            }
+            parts = next
+        }
+        out.extend(parts)
+    }
+
+    /// 保存为二进制格式：5 个小端 `u32` 的头（魔数、版本、词数、合并数、结束词，没有时为 `u32::MAX`），
+    /// 之后每个词是 1 字节的长度和内容，最后每条合并是两个小端 `u16` 的词号，按优先级排列。
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), BpeError> {
+        let header = [
+            MAGIC,
+            VERSION,
+            self.tokens.len() as u32,
+            self.merges.len() as u32,
+            self.eos.map_or(u32::MAX, u32::from),
+        ];
+        let mut buf = header
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+        for token in &self.tokens {
+            let Ok(len) = u8::try_from(token.len()) else {
+                return format(format!("token of {} bytes is too long", token.len()));
+            };
+            buf.push(len);
+            buf.extend(token)
+        }
+        for &(a, b) in &self.merges {
+            buf.extend(a.to_le_bytes());
+            buf.extend(b.to_le_bytes())
+        }
+        Ok(fs::write(path, buf)?)
+    }
+
+    /// 加载 [`Self::save`] 保存的二进制格式。
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, BpeError> {
+        let data = fs::read(path)?;
+        let mut rest = &data[..];
+        let mut take = |n: usize| match rest.split_at_checked(n) {
+            Some((head, tail)) => {
+                rest = tail;
+                Ok(head)
+            }
+            None => format("unexpected end of file"),
+        };
+        let mut header = [0u32; 5];
+        for x in &mut header {
+            *x = u32::from_le_bytes(take(4)?.try_into().unwrap())
+        }
+        let [magic, version, n_voc, n_merges, eos] = header;
+        if magic != MAGIC || version != VERSION {
+            return format("not a bpe tokenizer file");
+        }
+        let mut tokens = Vec::with_capacity(n_voc as usize);
+        for _ in 0..n_voc {
+            let len = take(1)?[0] as usize;
+            tokens.push(take(len)?.to_vec())
+        }
+        let mut merges = Vec::with_capacity(n_merges as usize);
+        for _ in 0..n_merges {
+            let pair = take(4)?;
+            merges.push((
+                u16::from_le_bytes([pair[0], pair[1]]),
+                u16::from_le_bytes([pair[2], pair[3]]),
+            ))
+        }
+        let eos = (eos != u32::MAX).then_some(eos as u16);
+        Self::new(tokens, merges, eos)
+    }
+}
+
+#[test]
+fn test_bpe() {
+    // 256 个单字节词，之后是合并出的词和结束词
+    let chars = byte_chars();
+    let mut vocab = (0..256)
+        .map(|b| (chars[b].to_string(), b as u32))
+        .collect::<HashMap<_, _>>();
+    for word in [
+        "he",
+        "ll",
+        "Ġt",
+        "Ġth",
+        "Ġthe",
+        "hell",
+        "hello",
+        "<|endoftext|>",
+    ] {
+        vocab.insert(word.into(), vocab.len() as u32);
+    }
+    let merges = "#version: 0.2\nĠ t\nĠt h\nĠth e\nh e\nl l\nhe ll\nhell o\n";
+    let bpe = Bpe::from_vocab(&vocab, merges).unwrap();
+    assert_eq!(bpe.n_voc(), 264);
+    assert_eq!(bpe.eos, Some(263));
+
+    assert_eq!(
+        bpe.split("hello the world's\n\n  x 42!?"),
+        ["hello", " the", " world", "'s", "\n\n ", " x", " 42", "!?"]
+    );
+    let id = |s: &str| vocab[s] as u16;
+    // " the" 中 "Ġ t" 先于 "h e" 合并
+    assert_eq!(bpe.encode("hello the"), [id("hello"), id("Ġthe")]);
+    // "hel" 先合并 "h e"，"e l" 不是合并规则
+    assert_eq!(bpe.encode("hel"), [id("he"), b'l' as u16]);
+    // 任意 UTF-8 文本都能还原，特殊词按普通文本编码
+    let text = "héllo, 世界 <|endoftext|>\t\n";
+    let tokens = bpe.encode(text);
+    assert!(!tokens.contains(&263));
+    let decoded = tokens
+        .iter()
+        .flat_map(|&t| bpe.decode(t))
+        .copied()
+        .collect::<Vec<_>>();
+    assert_eq!(decoded, text.as_bytes());
+
+    let path = std::env::temp_dir().join(format!("llm-rs-bpe-{}.bin", std::process::id()));
+    bpe.save(&path).unwrap();
+    let loaded = Bpe::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.eos, bpe.eos);
+    assert_eq!(loaded.encode(text), tokens);
+
+    let err = |merges| Bpe::from_vocab(&vocab, merges).err().unwrap().to_string();
+    assert_eq!(
+        err("x <unk>\n"),
+        "invalid tokenizer: merge refers to unknown token \"<unk>\""
+    );
+    assert_eq!(
+        err("e l\n"),
+        "invalid tokenizer: result of merge 101 108 is not a token"
+    )
+}
@@ -1,8 +1,15 @@
-﻿use crate::{Blob, HashWeak, Tensor, nn::NeuralNetwork, optimizer::Optimizer};
+﻿use crate::{
+    Blob, HashWeak, Tensor,
+    nn::{NeuralNetwork, custom::CustomOp},
+    optimizer::Optimizer,
+};
 use digit_layout::DigitLayout;
+use rand::{SeedableRng, rngs::StdRng};
 use rw_rc::RwRc;
 use std::{
+    cell::{RefCell, RefMut},
     collections::{HashMap, HashSet},
+    fmt,
     rc::Rc,
     time::Instant,
 };
@@ -11,30 +18,185 @@ pub struct Context {
     path: String,
     weights: HashMap<HashWeak<Tensor<RwRc<Blob>>>, WeightInfo>,
     bench: bool,
+    training: bool,
+    batch_invariant: bool,
+    compensated_sum: bool,
+    rng: RefCell<StdRng>,
+    record: Option<Vec<(String, Tensor<Blob>)>>,
+    ops: HashMap<String, CustomOp>,
+    graph: RefCell<Option<GraphState>>,
+    dry_run: RefCell<Option<DryRun>>,
+}
+
+/// [`Context::dry_run`] 的结果，按前向完成的顺序列出各模块，子模块在父模块之前。
+#[derive(Clone, Default, Debug)]
+pub struct DryRun {
+    pub modules: Vec<ModuleInfo>,
+    /// 全部激活张量的字节数。激活不复用，这是实际前向占用的上界。
+    pub nbytes: usize,
+}
+
+/// 空跑中一个模块的前向。
+#[derive(Clone, Debug)]
+pub struct ModuleInfo {
+    pub path: String,
+    /// 各输出的数据类型和形状。
+    pub outputs: Vec<(DigitLayout, Vec<usize>)>,
+    /// 模块及其子模块声明的激活字节数。
+    pub nbytes: usize,
+}
+
+impl fmt::Display for DryRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mib = |n: usize| n as f64 / (1 << 20) as f64;
+        for ModuleInfo {
+            path,
+            outputs,
+            nbytes,
+        } in &self.modules
+        {
+            write!(f, "{path}:")?;
+            for (dt, shape) in outputs {
+                write!(f, " {dt}{shape:?}")?
+            }
+            writeln!(f, " ({:.2} MiB)", mib(*nbytes))?
+        }
+        write!(f, "activations: {:.2} MiB", mib(self.nbytes))
+    }
+}
+
+/// 捕获得到的计算图：按分配顺序记录的所有激活缓冲区。
+///
+/// 重放时 [`Context::tensor`] 依次返回这些缓冲区而不再分配，
+/// 要求重放的计算与捕获时形状完全一致，上一次重放的输出会被覆盖。
+#[derive(Clone)]
+pub struct Graph {
+    buffers: Rc<[Tensor<RwRc<Blob>>]>,
+}
+
+impl Graph {
+    /// 缓冲区数量。
+    pub fn len(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    /// 缓冲区总字节数。
+    pub fn nbytes(&self) -> usize {
+        self.buffers.iter().map(|t| t.get().read().len()).sum()
+    }
+}
+
+enum GraphState {
+    Capture(Vec<Tensor<RwRc<Blob>>>),
+    Replay(Graph, usize),
 }
 
 #[derive(Default)]
 struct WeightInfo {
     gradient: Option<Rc<Tensor<RwRc<Blob>>>>,
+    /// 稀疏梯度只保存这些行，`gradient` 的第 `i` 行是权重第 `rows[i]` 行的梯度。
+    rows: Option<Rc<[usize]>>,
     names: HashSet<String>,
 }
 
+impl WeightInfo {
+    /// 稀疏梯度展开为完整的梯度。
+    fn densify(&mut self, weight: &Tensor<RwRc<Blob>>) {
+        let Some(rows) = self.rows.take() else {
+            return;
+        };
+        let dense = Tensor::contiguous_of(weight)
+            .map(Blob::new_zeroed)
+            .map(RwRc::new);
+        if let Some(sparse) = self.gradient.take() {
+            let src = sparse.get().read();
+            let dst = dense.get().write();
+            let row = dst.len() / weight.shape()[0];
+            for (i, &r) in rows.iter().enumerate() {
+                dst[r * row..][..row].copy_from_slice(&src[i * row..][..row])
+            }
+            dense.get().release();
+            sparse.get().release()
+        }
+        self.gradient = Some(dense.share())
+    }
+}
+
 impl Context {
     pub fn new(bench: bool) -> Self {
         Self {
             path: "Ω".into(),
             weights: Default::default(),
             bench,
+            training: true,
+            batch_invariant: false,
+            compensated_sum: false,
+            rng: RefCell::new(StdRng::seed_from_u64(0)),
+            record: None,
+            ops: Default::default(),
+            graph: Default::default(),
+            dry_run: Default::default(),
         }
     }
 
+    /// 切换训练或评估模式，评估模式下 dropout 等随机模块不起作用。默认为训练模式。
+    pub fn set_training(&mut self, training: bool) {
+        self.training = training
+    }
+
+    pub fn is_training(&self) -> bool {
+        self.training
+    }
+
+    /// 设置 f32 矩阵乘是否使用批不变的实现，见 [`crate::op::linear::forward`]。
+    ///
+    /// gemm 按矩阵形状选择不同的分块和内核，同一行在不同的批大小下结果可能有末位差异。
+    /// 批不变的实现对每个输出按固定顺序累加，同一序列单独解码和在批中解码得到逐位相同的结果，
+    /// 但比 gemm 慢，用于可复现的评测，默认关闭。量化权重的矩阵乘总是批不变的，其他算子逐行计算，不受批影响。
+    pub fn set_batch_invariant(&mut self, enabled: bool) {
+        self.batch_invariant = enabled
+    }
+
+    pub fn is_batch_invariant(&self) -> bool {
+        self.batch_invariant
+    }
+
+    /// 设置 softmax 的指数和是否使用 Kahan 补偿求和，影响损失和注意力的 f32 算子。
+    ///
+    /// 补偿求和的结果与 f64 参考实现更接近，用于验证新算子和排查精度偏差，默认关闭。
+    pub fn set_compensated_sum(&mut self, enabled: bool) {
+        self.compensated_sum = enabled
+    }
+
+    pub fn is_compensated_sum(&self) -> bool {
+        self.compensated_sum
+    }
+
+    /// 重置随机模块使用的随机数生成器，默认种子为 0。
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = RefCell::new(StdRng::seed_from_u64(seed))
+    }
+
+    pub fn rng(&self) -> RefMut<'_, StdRng> {
+        self.rng.borrow_mut()
+    }
+
     pub fn trap<T>(&mut self, sub: impl AsRef<str>, f: impl FnOnce(&mut Self) -> T) -> T {
         let sub = sub.as_ref();
 
         self.path.push('.');
         self.path.push_str(sub);
+        let _span = tracing::trace_span!("module", name = sub).entered();
 
+        #[cfg(feature = "debug-borrow")]
+        let label = rw_rc::Label::push(&self.path);
         let ans = f(self);
+        #[cfg(feature = "debug-borrow")]
+        drop(label);
 
         assert!(self.path.ends_with(sub));
         self.path.truncate(self.path.len() - sub.len() - 1);
@@ -54,7 +216,8 @@ impl Context {
             .or_default();
         // 记录名字
         info.names.insert(format!("{}:{name}", self.path));
-        // 生成或取出梯度
+        // 生成或取出梯度，已有的稀疏梯度先展开
+        info.densify(weight);
         info.gradient
             .get_or_insert_with(|| {
                 Tensor::contiguous_of(weight)
@@ -65,9 +228,61 @@ impl Context {
             .clone()
     }
 
+    /// 只为权重的 `rows` 行分配梯度，用于大词表的嵌入表。
+    ///
+    /// 返回的梯度张量按行对应第二个返回值中的行号，它可能包含此前其他调用请求的行；
+    /// 权重已经有完整梯度时返回完整梯度，行号为 `None`。
+    #[allow(clippy::type_complexity)]
+    pub fn write_sparse_gradient(
+        &mut self,
+        name: &str,
+        weight: &Rc<Tensor<RwRc<Blob>>>,
+        rows: &[usize],
+    ) -> (Rc<Tensor<RwRc<Blob>>>, Option<Rc<[usize]>>) {
+        assert!(rows.iter().all(|&r| r < weight.shape()[0]));
+        let info = self
+            .weights
+            .entry(HashWeak(Rc::downgrade(weight)))
+            .or_default();
+        info.names.insert(format!("{}:{name}", self.path));
+
+        if info.gradient.is_some() && info.rows.is_none() {
+            return (info.gradient.clone().unwrap(), None);
+        }
+        let old = info.rows.take().unwrap_or_else(|| [].into());
+        let mut union = old.iter().chain(rows).copied().collect::<Vec<_>>();
+        union.sort_unstable();
+        union.dedup();
+        if union.len() != old.len() || info.gradient.is_none() {
+            // 行集合变大，重新分配并搬移已有的行
+            let mut shape = weight.shape().to_vec();
+            shape[0] = union.len();
+            let new = Tensor::new(weight.dt(), &shape)
+                .map(Blob::new_zeroed)
+                .map(RwRc::new);
+            if let Some(grad) = info.gradient.take() {
+                let src = grad.get().read();
+                let dst = new.get().write();
+                let row = dst.len() / union.len().max(1);
+                for (i, r) in old.iter().enumerate() {
+                    let j = union.binary_search(r).unwrap();
+                    dst[j * row..][..row].copy_from_slice(&src[i * row..][..row])
+                }
+                new.get().release();
+                grad.get().release()
+            }
+            info.gradient = Some(new.share());
+            info.rows = Some(union.into())
+        } else {
+            info.rows = Some(old)
+        }
+        (info.gradient.clone().unwrap(), info.rows.clone())
+    }
+
     pub fn zero_grad(&mut self) {
         for info in self.weights.values_mut() {
             let _ = info.gradient.take();
+            let _ = info.rows.take();
         }
     }
 
@@ -75,7 +290,10 @@ impl Context {
         for (weak, info) in &self.weights {
             let weight = weak.0.upgrade().unwrap();
             let gradient = info.gradient.clone().unwrap();
-            optimizer.update(weight, gradient)
+            match &info.rows {
+                Some(rows) => optimizer.update_sparse(weight, gradient, rows),
+                None => optimizer.update(weight, gradient),
+            }
         }
     }
 }
@@ -91,7 +309,30 @@ impl Context {
         nn: &mut NN,
         inputs: impl IntoIterator<Item = Rc<Tensor<RwRc<Blob>>>>,
     ) -> Vec<Rc<Tensor<RwRc<Blob>>>> {
-        self.trap(name, |ctx| nn.forward(inputs, ctx))
+        self.trap(name, |ctx| {
+            let before = ctx.dry_run.borrow().as_ref().map(|dry| dry.nbytes);
+            let outputs = nn.forward(inputs, ctx);
+            if let (Some(before), Some(dry)) = (before, &mut *ctx.dry_run.borrow_mut()) {
+                dry.modules.push(ModuleInfo {
+                    path: ctx.path.clone(),
+                    outputs: outputs
+                        .iter()
+                        .map(|y| (y.dt(), y.shape().to_vec()))
+                        .collect(),
+                    nbytes: dry.nbytes - before,
+                })
+            }
+            if let Some(record) = &mut ctx.record {
+                for (i, y) in outputs.iter().enumerate() {
+                    let path = match outputs.len() {
+                        1 => ctx.path.clone(),
+                        _ => format!("{}#{i}", ctx.path),
+                    };
+                    record.push((path, y.cloned().map(|b| b.read().clone())))
+                }
+            }
+            outputs
+        })
     }
 
     pub fn backward<NN: NeuralNetwork>(
@@ -103,19 +344,219 @@ impl Context {
         self.trap(name, |ctx| nn.backward(inputs, ctx))
     }
 
+    /// 分配激活张量。内存延迟到第一次读写时分配，没有执行到的分支不占用内存。
     pub fn tensor(&self, dt: DigitLayout, shape: &[usize]) -> Tensor<RwRc<Blob>> {
-        Tensor::new(dt, shape).map(Blob::new).map(RwRc::new)
+        self.alloc(dt, shape, false)
     }
 
     pub fn tensor_zeroed(&self, dt: DigitLayout, shape: &[usize]) -> Tensor<RwRc<Blob>> {
-        Tensor::new(dt, shape).map(Blob::new_zeroed).map(RwRc::new)
+        self.alloc(dt, shape, true)
+    }
+
+    /// 与 `t` 数据类型和形状相同的连续张量。
+    pub fn tensor_like(&self, t: &Tensor<RwRc<Blob>>) -> Tensor<RwRc<Blob>> {
+        self.alloc(t.dt(), &t.shape(), false)
+    }
+
+    pub fn tensor_zeroed_like(&self, t: &Tensor<RwRc<Blob>>) -> Tensor<RwRc<Blob>> {
+        self.alloc(t.dt(), &t.shape(), true)
+    }
+
+    fn alloc(&self, dt: DigitLayout, shape: &[usize], zeroed: bool) -> Tensor<RwRc<Blob>> {
+        if let Some(dry) = &mut *self.dry_run.borrow_mut() {
+            dry.nbytes += Tensor::new(dt, shape).take()
+        }
+        let new = || {
+            Tensor::new(dt, shape)
+                .map(|len| Blob::lazy(len, zeroed))
+                .map(RwRc::new)
+        };
+        match &mut *self.graph.borrow_mut() {
+            None => new(),
+            Some(GraphState::Capture(buffers)) => {
+                let ans = new();
+                buffers.push(ans.cloned());
+                ans
+            }
+            Some(GraphState::Replay(graph, i)) => {
+                let Some(buf) = graph.buffers.get(*i) else {
+                    panic!("replay allocates more tensors than captured")
+                };
+                *i += 1;
+                assert!(
+                    buf.dt() == dt && *buf.shape() == *shape,
+                    "replay shape mismatch"
+                );
+                if zeroed {
+                    buf.cloned().get().write().fill(0)
+                }
+                buf.cloned()
+            }
+        }
+    }
+
+    /// 在 `f` 中捕获激活缓冲区的分配，得到可以重放的 [`Graph`]。
+    pub fn capture<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> (T, Graph) {
+        let state = self.graph.replace(Some(GraphState::Capture(Vec::new())));
+        assert!(state.is_none(), "nested graph capture");
+        let ans = f(self);
+        let Some(GraphState::Capture(buffers)) = self.graph.take() else {
+            unreachable!()
+        };
+        let graph = Graph {
+            buffers: buffers.into(),
+        };
+        (ans, graph)
+    }
+
+    /// 在 `f` 中重放 `graph`，激活使用捕获时的缓冲区，不再分配。
+    pub fn replay<T>(&mut self, graph: &Graph, f: impl FnOnce(&mut Self) -> T) -> T {
+        let state = self
+            .graph
+            .replace(Some(GraphState::Replay(graph.clone(), 0)));
+        assert!(state.is_none(), "nested graph capture");
+        let ans = f(self);
+        let Some(GraphState::Replay(_, i)) = self.graph.take() else {
+            unreachable!()
+        };
+        assert_eq!(
+            i,
+            graph.len(),
+            "replay allocates fewer tensors than captured"
+        );
+        ans
+    }
+
+    /// 开始记录之后每个模块 forward 输出的副本。
+    pub fn start_record(&mut self) {
+        self.record = Some(Vec::new())
+    }
+
+    /// 停止记录，按 forward 顺序取出记录的路径和输出。
+    pub fn take_record(&mut self) -> Vec<(String, Tensor<Blob>)> {
+        self.record.take().unwrap_or_default()
     }
 
+    /// 注册自定义算子，之后可以用 [`crate::nn::custom::Custom`] 按名字初始化。
+    pub fn register_op(&mut self, name: impl Into<String>, op: CustomOp) {
+        let name = name.into();
+        assert!(
+            self.ops.insert(name.clone(), op).is_none(),
+            "custom op \"{name}\" already registered"
+        )
+    }
+
+    pub fn custom_op(&self, name: &str) -> Option<CustomOp> {
+        self.ops.get(name).cloned()
+    }
+
+    /// 空跑 `f`：模块照常检查输入形状、构造输出，但 [`Self::bench`] 中的算子不执行，
+    /// 激活张量只声明不分配，用于在实际计算之前发现配置错误和估计激活内存。
+    ///
+    /// 形状错误与实际前向一样 panic。只在算子内部检查的形状不会被检查。
+    pub fn dry_run<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> (T, DryRun) {
+        let state = self.dry_run.replace(Some(DryRun::default()));
+        assert!(state.is_none(), "nested dry run");
+        let ans = f(self);
+        (ans, self.dry_run.take().unwrap())
+    }
+
+    /// 执行算子，空跑时跳过。
     pub fn bench(&self, f: impl FnOnce()) {
+        if self.dry_run.borrow().is_some() {
+            return;
+        }
         let time = Instant::now();
         f();
         if self.bench {
-            println!("{}: {:?}", self.path, time.elapsed())
+            tracing::info!(path = self.path, elapsed = ?time.elapsed(), "bench")
         }
     }
 }
+
+#[test]
+fn test_graph_replay() {
+    use crate::{llmc, nn::gpt2::Gpt2};
+    use digit_layout::types;
+
+    let config = llmc::Gpt2Config {
+        nblk: 1,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut ctx = Context::new(false);
+    let gpt2 = llmc::Gpt2::random(config, &mut rng).map(RwRc::new);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", gpt2);
+
+    let tokens = |t: &[u16]| {
+        Tensor::new(types::U16, &[1, t.len()])
+            .map(|_| RwRc::new(t.into()))
+            .share()
+    };
+    let logits = |y: &Rc<Tensor<RwRc<Blob>>>| y.get().read().to_vec();
+
+    let a = [1, 2, 3, 4];
+    let b = [5, 6, 7, 8];
+    let (y, graph) = ctx.capture(|ctx| ctx.forward("gpt2", &mut gpt2, [tokens(&a)]));
+    let ptr = y[0].get().read().as_ptr();
+    let ya = logits(&y[0]);
+    drop(y);
+
+    let y = ctx.replay(&graph, |ctx| ctx.forward("gpt2", &mut gpt2, [tokens(&b)]));
+    assert_eq!(y[0].get().read().as_ptr(), ptr);
+    let yb = logits(&y[0]);
+    drop(y);
+
+    assert_eq!(ya, logits(&ctx.forward("gpt2", &mut gpt2, [tokens(&a)])[0]));
+    assert_eq!(yb, logits(&ctx.forward("gpt2", &mut gpt2, [tokens(&b)])[0]));
+    assert_ne!(ya, yb)
+}
+
+#[test]
+fn test_lazy_tensor() {
+    use digit_layout::types;
+
+    let ctx = Context::new(false);
+    let x = ctx.tensor(types::F32, &[2, 3]);
+    let y = ctx.tensor_zeroed_like(&x);
+    assert_eq!(&*y.shape(), [2, 3]);
+    // 只声明不访问的张量不分配内存
+    assert!(!x.get().read().is_allocated());
+    assert_eq!(x.get().read().len(), 24);
+    x.get().write().fill(1);
+    x.get().release();
+    assert!(x.get().read().is_allocated());
+    assert!(y.get().read().iter().all(|&b| b == 0));
+}
+
+#[test]
+fn test_dry_run() {
+    use crate::{llmc, nn::gpt2::Gpt2};
+    use digit_layout::types;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+
+    let config = llmc::Gpt2Config::tiny(64);
+    let n_ctx = config.n_seq;
+    let gpt2 = llmc::Gpt2::random(config, &mut StdRng::seed_from_u64(0)).map(RwRc::new);
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", gpt2);
+
+    let dry = gpt2.check(&mut ctx, 2, 16);
+    let (path, outputs) = dry.modules.last().map(|m| (&m.path, &m.outputs)).unwrap();
+    assert_eq!(path, "Ω.gpt2");
+    assert_eq!(outputs[0].1[..2], [2, 16]);
+    assert!(dry.modules.iter().any(|m| m.path == "Ω.gpt2.blk[0].attn"));
+
+    // 估计的激活内存与实际前向捕获的缓冲区一致
+    let tokens = Tensor::new(types::U16, &[2, 16])
+        .map(Blob::new_zeroed)
+        .map(RwRc::new)
+        .share();
+    let (_, graph) = ctx.capture(|ctx| ctx.forward("gpt2", &mut gpt2, [tokens]));
+    assert_eq!(dry.nbytes, graph.nbytes());
+
+    // 配置错误在空跑时就暴露出来
+    let err = catch_unwind(AssertUnwindSafe(|| gpt2.check(&mut ctx, 1, n_ctx + 1)));
+    assert!(err.is_err())
+}
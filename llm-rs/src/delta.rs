@@ -0,0 +1,485 @@
+//! 参数增量：只保存微调相对基础检查点改变了的张量，加载时叠加到基础模型上。
+//!
+//! 每个改变了的张量选择最小的表示：稠密差、稀疏差（只存改变的元素）或低秩差 `a·b`，
+//! 微调通常只改动少数张量或改动近似低秩，补丁文件远小于完整的检查点。
+//! 补丁保存为 safetensors，张量名加上后缀区分表示：`.dense`、`.sparse_idx`/`.sparse_val`、`.lora_a`/`.lora_b`。
+
+use crate::{
+    Blob, Tensor,
+    llmc::Gpt2,
+    quant::{Checkpoint, ImportError, RawView},
+};
+use digit_layout::types;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use safetensors::{Dtype, serialize_to_file};
+use std::{collections::HashMap, path::Path};
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeltaOptions {
+    /// 绝对值不超过此值的差视为没有改变。
+    pub tol: f32,
+    /// 低秩差的最大秩，0 表示不尝试低秩。
+    pub max_rank: usize,
+    /// 低秩近似允许的相对误差（Frobenius 范数）。
+    pub rank_tol: f32,
+}
+
+impl Default for DeltaOptions {
+    fn default() -> Self {
+        Self {
+            tol: 0.,
+            max_rank: 64,
+            rank_tol: 1e-3,
+        }
+    }
+}
+
+/// 一个张量的差。
+#[derive(Clone, PartialEq, Debug)]
+pub enum Patch {
+    Dense(Vec<f32>),
+    /// 改变的元素在展平后的下标和差值。
+    Sparse {
+        indices: Vec<u32>,
+        values: Vec<f32>,
+    },
+    /// `a·b`，`a` 形状为 `[rows, rank]`，`b` 形状为 `[rank, cols]`。
+    LowRank {
+        rows: usize,
+        cols: usize,
+        a: Vec<f32>,
+        b: Vec<f32>,
+    },
+}
+
+impl Patch {
+    /// 保存时占用的字节数。
+    pub fn nbytes(&self) -> usize {
+        match self {
+            Self::Dense(d) => d.len() * 4,
+            Self::Sparse { indices, values } => (indices.len() + values.len()) * 4,
+            Self::LowRank { a, b, .. } => (a.len() + b.len()) * 4,
+        }
+    }
+
+    fn add_to(&self, name: &str, shape: &[usize], dst: &mut [f32]) -> Result<(), ImportError> {
+        let bad = || ImportError::Shape(name.into(), shape.to_vec());
+        match self {
+            Self::Dense(d) => {
+                if d.len() != dst.len() {
+                    return Err(bad());
+                }
+                for (dst, d) in dst.iter_mut().zip(d) {
+                    *dst += d
+                }
+            }
+            Self::Sparse { indices, values } => {
+                if indices.iter().any(|&i| i as usize >= dst.len()) {
+                    return Err(bad());
+                }
+                for (&i, d) in indices.iter().zip(values) {
+                    dst[i as usize] += d
+                }
+            }
+            &Self::LowRank {
+                rows,
+                cols,
+                ref a,
+                ref b,
+            } => {
+                if shape != [rows, cols] {
+                    return Err(bad());
+                }
+                let rank = a.len() / rows;
+                for (dst, a) in dst.chunks_exact_mut(cols).zip(a.chunks_exact(rank)) {
+                    for (a, b) in a.iter().zip(b.chunks_exact(cols)) {
+                        for (dst, b) in dst.iter_mut().zip(b) {
+                            *dst += a * b
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 微调相对基础模型的参数增量，没有改变的张量不出现。
+#[derive(Clone, Default, Debug)]
+pub struct Delta {
+    pub patches: Vec<(String, Patch)>,
+}
+
+impl Delta {
+    /// 计算 `tuned` 相对 `base` 的增量，两者的张量都必须是 f32 且形状相同。
+    pub fn diff(base: &Gpt2<Blob>, tuned: &Gpt2<Blob>, options: &DeltaOptions) -> Self {
+        let mut tuned_tensors = Vec::new();
+        tuned.for_each(|_, t| tuned_tensors.push(t));
+        let mut tuned_tensors = tuned_tensors.into_iter();
+
+        let mut patches = Vec::new();
+        base.for_each(|name, base| {
+            let tuned = tuned_tensors.next().unwrap();
+            assert_eq!(base.shape(), tuned.shape(), "{name} has different shapes");
+            let d = f32s(tuned)
+                .iter()
+                .zip(f32s(base))
+                .map(|(t, b)| t - b)
+                .collect::<Vec<_>>();
+            if let Some(patch) = diff_tensor(&base.shape(), d, options) {
+                patches.push((name.into(), patch))
+            }
+        });
+        assert!(
+            tuned_tensors.next().is_none(),
+            "models have different tensors"
+        );
+        Self { patches }
+    }
+
+    /// 补丁的总字节数。
+    pub fn nbytes(&self) -> usize {
+        self.patches.iter().map(|(_, p)| p.nbytes()).sum()
+    }
+
+    /// 把增量叠加到 f32 的基础模型上。
+    pub fn apply(&self, base: Gpt2<Blob>) -> Result<Gpt2<Blob>, ImportError> {
+        let mut patches = self
+            .patches
+            .iter()
+            .map(|(name, patch)| (&**name, patch))
+            .collect::<HashMap<_, _>>();
+        let mut err = None;
+        let gpt2 = base.map_tensor(|name, mut tensor| {
+            if let Some(patch) = patches.remove(name) {
+                assert_eq!(tensor.dt(), types::F32, "{name} is not f32");
+                let shape = tensor.shape().to_vec();
+                let ([], dst, []) = (unsafe { tensor.get_mut().align_to_mut::<f32>() }) else {
+                    unreachable!()
+                };
+                if let Err(e) = patch.add_to(name, &shape, dst) {
+                    err.get_or_insert(e);
+                }
+            }
+            tensor
+        });
+        if let Some(e) = err {
+            return Err(e);
+        }
+        match patches.into_keys().next() {
+            Some(name) => Err(ImportError::Missing(name.into())),
+            None => Ok(gpt2),
+        }
+    }
+
+    /// 保存为 safetensors，形状和表示由张量名的后缀确定。
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ImportError> {
+        fn bytes<T>(data: &[T]) -> &[u8] {
+            unsafe { data.align_to::<u8>().1 }
+        }
+
+        let mut views = Vec::new();
+        let mut push =
+            |name: String, dtype, shape, data| views.push((name, RawView { dtype, shape, data }));
+        for (name, patch) in &self.patches {
+            match patch {
+                Patch::Dense(d) => {
+                    push(format!("{name}.dense"), Dtype::F32, vec![d.len()], bytes(d))
+                }
+                Patch::Sparse { indices, values } => {
+                    let n = vec![indices.len()];
+                    push(
+                        format!("{name}.sparse_idx"),
+                        Dtype::I32,
+                        n.clone(),
+                        bytes(indices),
+                    );
+                    push(format!("{name}.sparse_val"), Dtype::F32, n, bytes(values))
+                }
+                &Patch::LowRank {
+                    rows,
+                    cols,
+                    ref a,
+                    ref b,
+                } => {
+                    let rank = a.len() / rows;
+                    push(
+                        format!("{name}.lora_a"),
+                        Dtype::F32,
+                        vec![rows, rank],
+                        bytes(a),
+                    );
+                    push(
+                        format!("{name}.lora_b"),
+                        Dtype::F32,
+                        vec![rank, cols],
+                        bytes(b),
+                    )
+                }
+            }
+        }
+        Ok(serialize_to_file(views, &None, path.as_ref())?)
+    }
+
+    /// 读取由 [`Delta::save`] 保存的增量。
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ImportError> {
+        let ckpt = Checkpoint::open(path)?;
+        let mut names = ckpt.names().collect::<Vec<_>>();
+        names.sort();
+        let read = |name: &str, dt| -> Result<_, ImportError> {
+            let t = ckpt.load(name)?;
+            if t.dt() != dt {
+                return Err(ImportError::Shape(name.into(), t.shape().to_vec()));
+            }
+            Ok((t.shape().to_vec(), t.get().clone()))
+        };
+        let f32s = |blob: Blob| unsafe { blob.align_to::<f32>().1.to_vec() };
+
+        let mut patches = Vec::new();
+        for full in &names {
+            let bad = || ImportError::Missing(full.clone());
+            let (name, suffix) = full.rsplit_once('.').ok_or_else(bad)?;
+            let patch = match suffix {
+                "dense" => Patch::Dense(f32s(read(full, types::F32)?.1)),
+                "sparse_idx" => {
+                    let indices = read(full, types::I32)?.1;
+                    let indices = unsafe { indices.align_to::<u32>().1.to_vec() };
+                    let (_, values) = read(&format!("{name}.sparse_val"), types::F32)?;
+                    let values = f32s(values);
+                    if indices.len() != values.len() {
+                        return Err(ImportError::Shape(full.clone(), vec![indices.len()]));
+                    }
+                    Patch::Sparse { indices, values }
+                }
+                "lora_a" => {
+                    let b_name = format!("{name}.lora_b");
+                    let (a_shape, a) = read(full, types::F32)?;
+                    let (b_shape, b) = read(&b_name, types::F32)?;
+                    let (&[rows, rank], &[rank_, cols]) = (&*a_shape, &*b_shape) else {
+                        return Err(ImportError::Shape(b_name, b_shape));
+                    };
+                    if rank != rank_ {
+                        return Err(ImportError::Shape(b_name, b_shape));
+                    }
+                    Patch::LowRank {
+                        rows,
+                        cols,
+                        a: f32s(a),
+                        b: f32s(b),
+                    }
+                }
+                "sparse_val" | "lora_b" => continue,
+                _ => return Err(bad()),
+            };
+            patches.push((name.into(), patch))
+        }
+        Ok(Self { patches })
+    }
+}
+
+fn f32s(t: &Tensor<Blob>) -> &[f32] {
+    let ([], data, []) = (unsafe { t.get().align_to::<f32>() }) else {
+        unreachable!()
+    };
+    data
+}
+
+/// 选择最小的表示，没有改变时返回 `None`。
+fn diff_tensor(shape: &[usize], mut d: Vec<f32>, options: &DeltaOptions) -> Option<Patch> {
+    let changed = d.iter().filter(|x| x.abs() > options.tol).count();
+    if changed == 0 {
+        return None;
+    }
+    let mut best = if changed * 2 < d.len() {
+        let (indices, values) = d
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.abs() > options.tol)
+            .map(|(i, &x)| (i as u32, x))
+            .unzip();
+        Patch::Sparse { indices, values }
+    } else {
+        for x in &mut d {
+            if x.abs() <= options.tol {
+                *x = 0.
+            }
+        }
+        Patch::Dense(d.clone())
+    };
+    if let &[rows, cols] = shape
+        && let Some(patch) = low_rank(&d, rows, cols, options)
+        && patch.nbytes() < best.nbytes()
+    {
+        best = patch
+    }
+    Some(best)
+}
+
+/// 依次尝试 1、2、4… 直到 `max_rank` 的秩，返回第一个误差足够小的低秩近似。
+///
+/// 近似用随机化的子空间迭代求出，对确实低秩的差（如 LoRA 合并后的权重）是精确的。
+fn low_rank(d: &[f32], rows: usize, cols: usize, options: &DeltaOptions) -> Option<Patch> {
+    let norm = d.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let mut rank = 1;
+    while rank <= options.max_rank && rank * (rows + cols) < rows * cols {
+        let (a, b) = subspace_iteration(d, rows, cols, rank);
+        let k = a.len() / rows;
+        let mut err = 0.;
+        for (i, row) in d.chunks_exact(cols).enumerate() {
+            for (j, x) in row.iter().enumerate() {
+                let approx = (0..k).map(|r| a[i * k + r] * b[r * cols + j]).sum::<f32>();
+                err += (x - approx).powi(2)
+            }
+        }
+        if err.sqrt() <= options.rank_tol * norm {
+            return Some(Patch::LowRank { rows, cols, a, b });
+        }
+        rank *= 2
+    }
+    None
+}
+
+/// 求 `d ≈ a·b` 的秩至多 `rank` 的近似，`a` 的列正交，线性相关的方向被丢弃。
+fn subspace_iteration(d: &[f32], rows: usize, cols: usize, rank: usize) -> (Vec<f32>, Vec<f32>) {
+    // d·x 与 dᵀ·y
+    let mul = |x: &[f32]| {
+        d.chunks_exact(cols)
+            .map(|row| row.iter().zip(x).map(|(a, b)| a * b).sum())
+            .collect::<Vec<f32>>()
+    };
+    let mul_t = |y: &[f32]| {
+        let mut ans = vec![0.; cols];
+        for (row, y) in d.chunks_exact(cols).zip(y) {
+            for (ans, x) in ans.iter_mut().zip(row) {
+                *ans += y * x
+            }
+        }
+        ans
+    };
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let omega = (0..rank)
+        .map(|_| (0..cols).map(|_| rng.random::<f32>() * 2. - 1.).collect())
+        .collect::<Vec<Vec<f32>>>();
+    let mut q = orthonormalize(omega.iter().map(|x| mul(x)).collect());
+    for _ in 0..2 {
+        let z = orthonormalize(q.iter().map(|y| mul_t(y)).collect());
+        q = orthonormalize(z.iter().map(|x| mul(x)).collect())
+    }
+
+    let k = q.len();
+    let mut a = vec![0.; rows * k];
+    for (r, col) in q.iter().enumerate() {
+        for (i, x) in col.iter().enumerate() {
+            a[i * k + r] = *x
+        }
+    }
+    let b = q.iter().flat_map(|y| mul_t(y)).collect();
+    (a, b)
+}
+
+/// 修正的 Gram-Schmidt 正交化，丢弃与前面的向量线性相关的向量。
+fn orthonormalize(vectors: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+    let mut ans: Vec<Vec<f32>> = Vec::new();
+    for mut v in vectors {
+        let norm0 = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        // 做两遍减少舍入误差
+        for _ in 0..2 {
+            for u in &ans {
+                let dot = u.iter().zip(&v).map(|(a, b)| a * b).sum::<f32>();
+                for (v, u) in v.iter_mut().zip(u) {
+                    *v -= dot * u
+                }
+            }
+        }
+        let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 1e-4 * norm0 && norm > f32::MIN_POSITIVE {
+            v.iter_mut().for_each(|x| *x /= norm);
+            ans.push(v)
+        }
+    }
+    ans
+}
+
+#[test]
+fn test_delta() {
+    use crate::llmc::Gpt2Config;
+    use rand::Rng;
+
+    let mut rng = StdRng::seed_from_u64(7);
+    let base = Gpt2::random(Gpt2Config::tiny(64), &mut rng);
+    let d = base.config.d;
+
+    // 秩 2 的权重改动、少数偏置元素的改动和一个稠密改动
+    fn f32s_mut(t: &mut Tensor<Blob>) -> &mut [f32] {
+        let ([], data, []) = (unsafe { t.get_mut().align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        data
+    }
+    let mut tuned = base.clone();
+    let up = f32s_mut(&mut tuned.blks[0].ffn_up[0]);
+    let u = (0..2 * 4 * d)
+        .map(|_| rng.random::<f32>())
+        .collect::<Vec<_>>();
+    let v = (0..2 * d).map(|_| rng.random::<f32>()).collect::<Vec<_>>();
+    for (i, row) in up.chunks_exact_mut(d).enumerate() {
+        for (j, x) in row.iter_mut().enumerate() {
+            *x += 0.01 * (u[2 * i] * v[2 * j] + u[2 * i + 1] * v[2 * j + 1])
+        }
+    }
+    let bias = f32s_mut(&mut tuned.blks[1].attn_o[1]);
+    bias[3] += 0.5;
+    bias[17] -= 0.25;
+    let wpe = f32s_mut(&mut tuned.wpe);
+    for x in wpe.iter_mut() {
+        *x += rng.random::<f32>() - 0.5
+    }
+
+    let delta = Delta::diff(&base, &tuned, &DeltaOptions::default());
+    let kinds = delta
+        .patches
+        .iter()
+        .map(|(name, patch)| {
+            let kind = match patch {
+                Patch::Dense(_) => "dense",
+                Patch::Sparse { .. } => "sparse",
+                Patch::LowRank { a, .. } => {
+                    assert_eq!(a.len(), 4 * d * 2);
+                    "low_rank"
+                }
+            };
+            (&**name, kind)
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            ("wpe", "dense"),
+            ("blk.0.ffn_up.w", "low_rank"),
+            ("blk.1.attn_o.b", "sparse"),
+        ]
+    );
+
+    let path =
+        std::env::temp_dir().join(format!("llm-rs-delta-{}.safetensors", std::process::id()));
+    delta.save(&path).unwrap();
+    let loaded = Delta::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.nbytes(), delta.nbytes());
+
+    let patched = loaded.apply(base.clone()).unwrap();
+    let mut expected = Vec::new();
+    tuned.for_each(|_, t| expected.push(t));
+    let mut expected = expected.into_iter();
+    patched.for_each(|name, t| {
+        let expected = expected.next().unwrap();
+        for (a, b) in f32s(t).iter().zip(f32s(expected)) {
+            assert!((a - b).abs() < 1e-5, "{name}: {a} != {b}")
+        }
+    });
+
+    let mut missing = loaded;
+    missing.patches[0].0 = "blk.9.attn_o.b".into();
+    assert!(matches!(missing.apply(base), Err(ImportError::Missing(_))))
+}
@@ -0,0 +1,112 @@
+//! 分布式训练使用的集合通信。
+
+use std::{
+    iter::zip,
+    ops::Range,
+    sync::{Arc, Barrier, Mutex},
+};
+
+/// 规约操作。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ReduceOp {
+    Sum,
+    Max,
+}
+
+/// 集合通信器，同一组内的所有成员必须以相同的顺序调用集合操作。
+pub trait Communicator {
+    fn rank(&self) -> usize;
+    fn world_size(&self) -> usize;
+    /// 原地规约，完成后每个成员的 `buf` 都是规约结果。
+    fn all_reduce(&self, buf: &mut [f32], op: ReduceOp);
+}
+
+/// 按词表切分时本成员负责的词范围，`n_voc` 需要能被成员数整除。
+pub fn vocab_range(n_voc: usize, comm: &dyn Communicator) -> Range<usize> {
+    let world_size = comm.world_size();
+    assert!(n_voc.is_multiple_of(world_size));
+    let n = n_voc / world_size;
+    let start = comm.rank() * n;
+    start..start + n
+}
+
+/// 只有一个成员的通信器，所有集合操作都是空操作。
+pub struct Single;
+
+impl Communicator for Single {
+    fn rank(&self) -> usize {
+        0
+    }
+
+    fn world_size(&self) -> usize {
+        1
+    }
+
+    fn all_reduce(&self, _buf: &mut [f32], _op: ReduceOp) {}
+}
+
+/// 进程内以线程为成员的通信组。
+pub struct ThreadComm {
+    rank: usize,
+    world_size: usize,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    barrier: Barrier,
+    /// 累积的规约结果与已到达的成员数。
+    acc: Mutex<(Vec<f32>, usize)>,
+}
+
+impl ThreadComm {
+    /// 创建 `world_size` 个成员，分别移动到各自的线程中使用。
+    pub fn group(world_size: usize) -> Vec<Self> {
+        let shared = Arc::new(Shared {
+            barrier: Barrier::new(world_size),
+            acc: Mutex::new((Vec::new(), 0)),
+        });
+        (0..world_size)
+            .map(|rank| Self {
+                rank,
+                world_size,
+                shared: shared.clone(),
+            })
+            .collect()
+    }
+}
+
+impl Communicator for ThreadComm {
+    fn rank(&self) -> usize {
+        self.rank
+    }
+
+    fn world_size(&self) -> usize {
+        self.world_size
+    }
+
+    fn all_reduce(&self, buf: &mut [f32], op: ReduceOp) {
+        let Shared { barrier, acc } = &*self.shared;
+        // 等待上一次操作的所有成员取走结果
+        barrier.wait();
+        {
+            let (acc, count) = &mut *acc.lock().unwrap();
+            if *count == 0 {
+                acc.clear();
+                acc.extend_from_slice(buf)
+            } else {
+                assert_eq!(acc.len(), buf.len());
+                for (acc, x) in zip(acc, &*buf) {
+                    match op {
+                        ReduceOp::Sum => *acc += x,
+                        ReduceOp::Max => *acc = acc.max(*x),
+                    }
+                }
+            }
+            *count += 1
+        }
+        barrier.wait();
+        let (acc, count) = &mut *acc.lock().unwrap();
+        buf.copy_from_slice(acc);
+        *count -= 1
+    }
+}
@@ -0,0 +1,46 @@
+//! 编译时嵌入的模型和分词器，无需任何外部文件即可运行。
+//!
+//! 构建时以环境变量 `LLM_RS_EMBED_MODEL` 和 `LLM_RS_EMBED_TOKENIZER` 指定 llm.c 格式的文件，
+//! 由构建脚本复制到 `OUT_DIR` 后嵌入二进制。没有指定时嵌入空文件，读取时返回 [`NotEmbedded`]。
+
+use crate::llmc::{Gpt2, Tokenizer};
+use std::fmt;
+
+/// 按 64 字节对齐的数据，文件头可以直接按 `i32` 读取。
+#[repr(C, align(64))]
+struct Aligned<T: ?Sized>(T);
+
+static MODEL: &Aligned<[u8]> = &Aligned(*include_bytes!(concat!(env!("OUT_DIR"), "/model.bin")));
+static TOKENIZER: &Aligned<[u8]> =
+    &Aligned(*include_bytes!(concat!(env!("OUT_DIR"), "/tokenizer.bin")));
+
+/// 构建时没有指定要嵌入的文件。
+#[derive(Debug)]
+pub struct NotEmbedded(&'static str);
+
+impl fmt::Display for NotEmbedded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nothing was embedded, rebuild with {} set", self.0)
+    }
+}
+
+/// 嵌入的模型文件。
+pub fn model_bytes() -> Result<&'static [u8], NotEmbedded> {
+    match &MODEL.0 {
+        [] => Err(NotEmbedded("LLM_RS_EMBED_MODEL")),
+        bytes => Ok(bytes),
+    }
+}
+
+/// 嵌入的模型，张量直接引用二进制中的数据。
+pub fn model() -> Result<Gpt2<&'static [u8]>, NotEmbedded> {
+    model_bytes().map(Gpt2::new)
+}
+
+/// 嵌入的分词器。
+pub fn tokenizer() -> Result<Tokenizer, NotEmbedded> {
+    match &TOKENIZER.0 {
+        [] => Err(NotEmbedded("LLM_RS_EMBED_TOKENIZER")),
+        bytes => Ok(Tokenizer::from_bytes(bytes)),
+    }
+}
@@ -0,0 +1,549 @@
+//! 带提示缓存的推理引擎。
+
+use crate::{
+    Blob, Context,
+    generate::{GenerationConfig, GenerationResult, generate_with},
+    llmc,
+    nn::gpt2::Gpt2,
+    repro::{Determinism, ReproError},
+    sampler::{Sampler, SamplerConfig},
+    truncate::Truncation,
+};
+use digit_layout::types;
+use rw_rc::RwRc;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// 会话句柄，会话关闭或过期后失效。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct SessionId(u64);
+
+struct Session {
+    /// 在分页 KV 缓存中的序列号。
+    seq: usize,
+    /// 已预填充的系统提示。
+    prefix: Vec<u16>,
+    /// 开启记忆时保留的历史轮次，KV 缓存在系统提示之后接着缓存它们。
+    history: Vec<Turn>,
+    last_used: Instant,
+}
+
+/// 会话历史中的一轮。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Turn {
+    /// 用户输入和生成的词，或者摘要。
+    pub tokens: Vec<u16>,
+    /// 这一轮是由更早的轮次压缩得到的摘要。
+    pub summary: bool,
+}
+
+/// 多轮对话的记忆策略，决定上下文放不下时如何处理更早的轮次。
+#[derive(Default)]
+pub enum Memory {
+    /// 每轮互相独立，生成后回退到系统提示。
+    #[default]
+    Stateless,
+    /// 保留历史轮次，放不下时丢弃最早的轮次。
+    DropOldest,
+    /// 保留历史轮次，放不下时把最早的若干轮（包括之前的摘要）交给回调压缩为一轮不超过
+    /// `max_tokens` 个词的摘要，回调可以用 [`Summarizer`] 让模型自己生成摘要。
+    Summarize {
+        max_tokens: usize,
+        summarize: Box<SummarizeFn>,
+    },
+}
+
+/// 把最早的若干轮的词压缩为不超过给定长度的摘要，过长的摘要会被截断。
+pub type SummarizeFn = dyn FnMut(&mut Summarizer, &[u16], usize) -> Vec<u16>;
+
+/// 摘要回调中使用引擎的模型生成，使用临时的序列，不影响各会话的缓存。
+pub struct Summarizer<'a> {
+    ctx: &'a mut Context,
+    gpt2: &'a mut Gpt2,
+    config: &'a GenerationConfig,
+}
+
+impl Summarizer<'_> {
+    /// 从 `prompt` 开始生成最多 `max_tokens` 个词，`prompt` 过长时保留末尾。
+    pub fn generate<'a>(
+        &mut self,
+        prompt: &[u16],
+        max_tokens: usize,
+        sample: impl FnMut(&[f32]) -> u16,
+        decode: impl Fn(u16) -> &'a [u8],
+    ) -> GenerationResult {
+        let ctx = &mut *self.ctx;
+        let gpt2 = &mut *self.gpt2;
+        let config = GenerationConfig {
+            max_tokens,
+            echo: false,
+            ..self.config.clone()
+        };
+        let seq = gpt2.add_sequence();
+        gpt2.select_sequences(&[seq]);
+        let forward = |tokens: &[u16]| {
+            let reused = gpt2.sequence_len(seq);
+            let tokens = &tokens[reused..];
+            let tokens = crate::Tensor::new(types::U16, &[1, tokens.len()])
+                .map(|_| Blob::from(tokens))
+                .map(RwRc::new);
+            let logits = ctx.forward("gpt2", gpt2, [tokens.share()]);
+            (logits.into_iter().next().unwrap(), reused)
+        };
+        let ans = generate_with(prompt, &config, forward, sample, decode);
+        gpt2.remove_sequence(seq);
+        ans
+    }
+}
+
+/// 持有一个 GPT-2 和它的分页 KV 缓存的推理引擎。
+///
+/// [`Self::create_session`] 预填充系统提示并返回句柄，之后每轮对话只计算用户输入和生成的词，
+/// 结束后回退到系统提示，同一个会话可以服务任意多轮互相独立的请求。
+/// 设置 [`Memory`] 后会话保留历史轮次，上下文放不下时按策略丢弃或压缩更早的轮次。
+/// 超过 `ttl` 没有使用的会话在下次创建会话时被淘汰，归还它占用的块。
+/// 需要审计或复现输出时用 [`Self::deterministic`] 打开可复现模式。
+pub struct Engine {
+    ctx: Context,
+    gpt2: Gpt2,
+    config: GenerationConfig,
+    memory: Memory,
+    determinism: Option<Determinism>,
+    ttl: Duration,
+    sessions: HashMap<SessionId, Session>,
+    next_id: u64,
+}
+
+impl Engine {
+    /// 分页 KV 缓存共 `n_blocks` 个 `block_size` 行的块，由所有会话共享。
+    pub fn new(
+        model: llmc::Gpt2<RwRc<Blob>>,
+        block_size: usize,
+        n_blocks: usize,
+        ttl: Duration,
+    ) -> Self {
+        let config = GenerationConfig {
+            n_ctx: model.config.n_seq,
+            n_voc: model.config.n_voc,
+            max_tokens: 0,
+            eos: None,
+            truncation: Truncation::KeepTail,
+            echo: false,
+            stop: None,
+            cost: model.config.cost(),
+        };
+        let mut ctx = Context::new(false);
+        let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+        gpt2.paged_kv_cache(block_size, n_blocks);
+        Self {
+            ctx,
+            gpt2,
+            config,
+            memory: Memory::Stateless,
+            determinism: None,
+            ttl,
+            sessions: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// 生成此词时停止。
+    pub fn eos(&mut self, eos: Option<u16>) {
+        self.config.eos = eos
+    }
+
+    /// 生成时给出用户输入各词的对数概率，见 [`GenerationResult::prompt_logprobs`]，
+    /// 系统提示复用缓存，对应的位置为 NaN。
+    pub fn echo(&mut self, echo: bool) {
+        self.config.echo = echo
+    }
+
+    /// 多轮对话的记忆策略，只影响之后的轮次。
+    pub fn memory(&mut self, memory: Memory) {
+        self.memory = memory
+    }
+
+    /// 打开可复现模式，见 [`Determinism::enable`]，并让引擎的上下文使用批不变的内核，
+    /// 之后每次生成的结果带有复现清单。
+    ///
+    /// 用 [`Self::generate_seeded`] 由引擎采样时清单中记录采样参数和种子。
+    pub fn deterministic(&mut self, determinism: Determinism) -> Result<(), ReproError> {
+        determinism.enable()?;
+        self.ctx.set_batch_invariant(true);
+        self.determinism = Some(determinism);
+        Ok(())
+    }
+
+    /// 会话保留的历史轮次，会话不存在时返回 `None`。
+    pub fn history(&self, id: SessionId) -> Option<&[Turn]> {
+        self.sessions.get(&id).map(|s| &*s.history)
+    }
+
+    /// 存活的会话数。
+    pub fn n_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// 预填充 `system_prompt` 并创建会话，先淘汰过期的会话。
+    pub fn create_session(&mut self, system_prompt: &[u16]) -> SessionId {
+        assert!(
+            system_prompt.len() < self.config.n_ctx,
+            "system prompt leaves no room for the conversation"
+        );
+        self.evict_expired();
+
+        let seq = self.gpt2.add_sequence();
+        if !system_prompt.is_empty() {
+            self.gpt2.select_sequences(&[seq]);
+            let tokens = crate::Tensor::new(types::U16, &[1, system_prompt.len()])
+                .map(|_| Blob::from(system_prompt))
+                .map(RwRc::new);
+            let _ = self.ctx.forward("gpt2", &mut self.gpt2, [tokens.share()]);
+        }
+
+        let id = SessionId(self.next_id);
+        self.next_id += 1;
+        self.sessions.insert(
+            id,
+            Session {
+                seq,
+                prefix: system_prompt.to_vec(),
+                history: Vec::new(),
+                last_used: Instant::now(),
+            },
+        );
+        tracing::debug!(?id, n_prefix = system_prompt.len(), "session created");
+        id
+    }
+
+    /// 在会话的系统提示（和历史轮次）之后接上 `prompt` 生成，最多 `max_tokens` 个词，会话不存在时返回 `None`。
+    ///
+    /// 系统提示和历史的 KV 直接复用，`prompt` 过长时按 [`Truncation::KeepTail`] 截断以保留系统提示。
+    /// 开启记忆时，历史、`prompt` 和 `max_tokens` 个词放不下就先按策略处理更早的轮次，
+    /// 生成后这一轮加入历史。结果中的提示长度包括系统提示和历史。
+    pub fn generate<'a>(
+        &mut self,
+        id: SessionId,
+        prompt: &[u16],
+        max_tokens: usize,
+        sample: impl FnMut(&[f32]) -> u16,
+        decode: impl Fn(u16) -> &'a [u8],
+    ) -> Option<GenerationResult> {
+        let Self {
+            ctx,
+            gpt2,
+            config,
+            memory,
+            determinism,
+            sessions,
+            ..
+        } = self;
+        let session = sessions.get_mut(&id)?;
+        session.last_used = Instant::now();
+
+        let seq = session.seq;
+        let n_prefix = session.prefix.len();
+        let room = config.n_ctx - n_prefix;
+        let n_history = |history: &[Turn]| history.iter().map(|t| t.tokens.len()).sum::<usize>();
+        let need = (prompt.len() + max_tokens).min(room);
+        if n_history(&session.history) + need > room {
+            let before = session.history.len();
+            let mut summarizer = Summarizer {
+                ctx: &mut *ctx,
+                gpt2: &mut *gpt2,
+                config,
+            };
+            compress(memory, &mut summarizer, &mut session.history, room - need);
+            // 历史改变后位置随之改变，只有系统提示的缓存还有效
+            gpt2.truncate_sequence(seq, n_prefix);
+            tracing::debug!(
+                ?id,
+                before,
+                after = session.history.len(),
+                "history compressed"
+            )
+        }
+
+        let mut tokens = session.prefix.clone();
+        tokens.extend(session.history.iter().flat_map(|t| &t.tokens));
+        let n_context = tokens.len();
+        tokens.extend(Truncation::KeepTail.apply(prompt, config.n_ctx - n_context));
+        let config = GenerationConfig {
+            max_tokens,
+            ..config.clone()
+        };
+
+        gpt2.select_sequences(&[seq]);
+        let forward = |tokens: &[u16]| {
+            let reused = gpt2.sequence_len(seq);
+            let tokens = &tokens[reused..];
+            let tokens = crate::Tensor::new(types::U16, &[1, tokens.len()])
+                .map(|_| Blob::from(tokens))
+                .map(RwRc::new);
+            let logits = ctx.forward("gpt2", gpt2, [tokens.share()]);
+            (logits.into_iter().next().unwrap(), reused)
+        };
+        let mut ans = generate_with(&tokens, &config, forward, sample, decode);
+        if let Some(determinism) = determinism {
+            ans.manifest = Some(determinism.manifest(
+                ctx.is_batch_invariant(),
+                ctx.is_compensated_sum(),
+                tokens.clone(),
+                max_tokens,
+                config.eos,
+            ))
+        }
+        if let Memory::Stateless = memory {
+            // 回退到系统提示，下一轮从这里开始
+            gpt2.truncate_sequence(seq, n_prefix)
+        } else {
+            let mut turn = tokens[n_context..].to_vec();
+            turn.extend(&ans.tokens);
+            // 被停止序列截掉的词可能已经进入缓存
+            gpt2.truncate_sequence(seq, n_context + turn.len());
+            session.history.push(Turn {
+                tokens: turn,
+                summary: false,
+            })
+        }
+        // 空闲时间从生成结束算起
+        session.last_used = Instant::now();
+        Some(ans)
+    }
+
+    /// 与 [`Self::generate`] 相同，但由引擎按 `sampler` 和 `seed` 采样，并把它们记入复现清单。
+    pub fn generate_seeded<'a>(
+        &mut self,
+        id: SessionId,
+        prompt: &[u16],
+        max_tokens: usize,
+        sampler: SamplerConfig,
+        seed: u64,
+        decode: impl Fn(u16) -> &'a [u8],
+    ) -> Option<GenerationResult> {
+        let mut sample = Sampler::new(sampler, seed);
+        let mut ans = self.generate(id, prompt, max_tokens, |l| sample.sample(l), decode)?;
+        if let Some(manifest) = &mut ans.manifest {
+            manifest.sampler = Some(sampler);
+            manifest.seed = Some(seed)
+        }
+        Some(ans)
+    }
+
+    /// 关闭会话并归还它的块，会话不存在时返回 `false`。
+    pub fn close_session(&mut self, id: SessionId) -> bool {
+        let Some(session) = self.sessions.remove(&id) else {
+            return false;
+        };
+        self.gpt2.remove_sequence(session.seq);
+        true
+    }
+
+    /// 淘汰超过 `ttl` 没有使用的会话，返回淘汰的个数。
+    pub fn evict_expired(&mut self) -> usize {
+        let expired = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.last_used.elapsed() > self.ttl)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for &id in &expired {
+            self.close_session(id);
+        }
+        if !expired.is_empty() {
+            tracing::debug!(n = expired.len(), "sessions evicted")
+        }
+        expired.len()
+    }
+}
+
+/// 按策略处理最早的轮次，使历史不超过 `budget` 个词。
+fn compress(
+    memory: &mut Memory,
+    summarizer: &mut Summarizer,
+    history: &mut Vec<Turn>,
+    budget: usize,
+) {
+    let len = |history: &[Turn]| history.iter().map(|t| t.tokens.len()).sum::<usize>();
+    match memory {
+        Memory::Stateless | Memory::DropOldest => {}
+        &mut Memory::Summarize {
+            max_tokens,
+            ref mut summarize,
+        } => {
+            // 压缩尽量少的轮次，使剩下的轮次和摘要放得下
+            let mut rest = len(history);
+            let n = history
+                .iter()
+                .take_while(|t| {
+                    let over = rest + max_tokens > budget;
+                    rest -= t.tokens.len();
+                    over
+                })
+                .count();
+            let old = history
+                .drain(..n)
+                .flat_map(|t| t.tokens)
+                .collect::<Vec<_>>();
+            let mut summary = summarize(summarizer, &old, max_tokens);
+            summary.truncate(max_tokens);
+            if !summary.is_empty() {
+                history.insert(
+                    0,
+                    Turn {
+                        tokens: summary,
+                        summary: true,
+                    },
+                )
+            }
+        }
+    }
+    // 仍然放不下时丢弃最早的轮次
+    while len(history) > budget {
+        history.remove(0);
+    }
+}
+
+#[test]
+fn test_engine() {
+    use crate::{generate::generate_cached, op::topk};
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let model = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7))
+        .map(RwRc::new);
+    let mut engine = Engine::new(model.clone(), 8, 16, Duration::from_millis(100));
+    let argmax = |logits: &[f32]| topk::argmax(logits) as u16;
+    let decode = |_| &b"x"[..];
+
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+    let mut expected = |prompt: &[u16]| {
+        let config = GenerationConfig {
+            max_tokens: 6,
+            ..engine.config.clone()
+        };
+        generate_cached(&mut ctx, "gpt2", &mut gpt2, prompt, &config, argmax, decode).tokens
+    };
+    let a_turns = [expected(&[1, 2, 3, 4, 5]), expected(&[1, 2, 3, 6])];
+    let b_turn = expected(&[9, 8, 7]);
+
+    let a = engine.create_session(&[1, 2, 3]);
+    let b = engine.create_session(&[9, 8]);
+    // 两个会话交替使用，每轮都复用系统提示
+    for (i, prompt) in [&[4, 5][..], &[6]].into_iter().enumerate() {
+        let result = engine.generate(a, prompt, 6, argmax, decode).unwrap();
+        assert_eq!(result.tokens, a_turns[i]);
+        assert!(result.cache.reused >= 3);
+        let result = engine.generate(b, &[7], 6, argmax, decode).unwrap();
+        assert_eq!(result.tokens, b_turn)
+    }
+
+    // 用户输入的第一个词由系统提示的最后一个位置预测，这个位置复用了缓存
+    engine.echo(true);
+    let result = engine.generate(a, &[4, 5], 1, argmax, decode).unwrap();
+    let scores = result.prompt_logprobs.unwrap();
+    assert_eq!(scores.len(), 4);
+    assert!(scores[..3].iter().all(|x| x.is_nan()) && !scores[3].is_nan());
+    engine.echo(false);
+
+    // a 过期后在创建新会话时被淘汰
+    std::thread::sleep(Duration::from_millis(150));
+    let _ = engine.generate(b, &[7], 1, argmax, decode).unwrap();
+    let c = engine.create_session(&[]);
+    assert_eq!(engine.n_sessions(), 2);
+    assert!(engine.generate(a, &[4], 1, argmax, decode).is_none());
+    let result = engine.generate(c, &[9, 8, 7], 6, argmax, decode).unwrap();
+    assert_eq!(result.tokens, b_turn);
+    assert!(engine.close_session(b));
+    assert!(!engine.close_session(b));
+    assert_eq!(engine.evict_expired(), 0)
+}
+
+#[test]
+fn test_engine_memory() {
+    use crate::{generate::generate_cached, op::topk};
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::{cell::RefCell, rc::Rc};
+
+    let model = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7))
+        .map(RwRc::new);
+    let mut engine = Engine::new(model.clone(), 8, 32, Duration::from_secs(60));
+    let argmax = |logits: &[f32]| topk::argmax(logits) as u16;
+    let decode = |_| &b"x"[..];
+
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+    let config = engine.config.clone();
+    let mut expected = |prompt: &[u16], max_tokens| {
+        let config = GenerationConfig {
+            max_tokens,
+            ..config.clone()
+        };
+        generate_cached(&mut ctx, "gpt2", &mut gpt2, prompt, &config, argmax, decode).tokens
+    };
+
+    // 系统提示 3 个词，每轮 10 + 10 个词，第 4 轮放不下
+    let system = [1, 2, 3];
+    let prompts = (0..4u16)
+        .map(|i| (0..10).map(|j| 4 + i * 10 + j).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    engine.memory(Memory::DropOldest);
+    let a = engine.create_session(&system);
+    let mut context = system.to_vec();
+    for (i, prompt) in prompts.iter().enumerate() {
+        if i == 3 {
+            context.drain(3..23);
+        }
+        context.extend(prompt);
+        let result = engine.generate(a, prompt, 10, argmax, decode).unwrap();
+        assert_eq!(result.tokens, expected(&context, 10));
+        // 历史轮次复用缓存，只计算上一轮最后一个词、用户输入和生成的词（最后一个除外）；
+        // 丢弃历史后只有系统提示的缓存有效
+        let computed = match i {
+            0 => 19,
+            1 | 2 => 20,
+            _ => context.len() - 3 + 9,
+        };
+        assert_eq!(result.cache.computed, computed);
+        context.extend(&result.tokens);
+    }
+    let history = engine.history(a).unwrap();
+    assert_eq!(history.len(), 3);
+    assert_eq!(history[0].tokens, context[3..23]);
+
+    // 第 4 轮把第 1 轮压缩为摘要，摘要由模型续写第 1 轮得到
+    let calls = Rc::new(RefCell::new(Vec::new()));
+    let calls_ = calls.clone();
+    engine.memory(Memory::Summarize {
+        max_tokens: 1,
+        summarize: Box::new(move |summarizer, old, max_tokens| {
+            calls_.borrow_mut().push(old.to_vec());
+            summarizer
+                .generate(old, max_tokens + 1, argmax, decode)
+                .tokens
+        }),
+    });
+    let b = engine.create_session(&system);
+    let mut turns = Vec::new();
+    for prompt in &prompts {
+        let result = engine.generate(b, prompt, 10, argmax, decode).unwrap();
+        let mut turn = prompt.clone();
+        turn.extend(result.tokens);
+        turns.push(turn)
+    }
+    assert_eq!(*calls.borrow(), [turns[0].clone()]);
+    let summary = expected(&turns[0], 1);
+    let history = engine.history(b).unwrap();
+    assert_eq!(
+        history.iter().map(|t| t.summary).collect::<Vec<_>>(),
+        [true, false, false, false]
+    );
+    assert_eq!(history[0].tokens, summary);
+    let mut context = system.to_vec();
+    context.extend(&summary);
+    context.extend(turns[1..3].concat());
+    context.extend(&prompts[3]);
+    assert_eq!(turns[3][10..], expected(&context, 10))
+}
@@ -0,0 +1,92 @@
+use crate::{
+    Blob, Context, Tensor,
+    llmc::{DataLoader, Gpt2},
+    macros::dims,
+    nn::gpt2,
+    op::loss::softmax,
+};
+use digit_layout::types;
+use rw_rc::RwRc;
+use std::{fmt, iter::zip};
+
+/// 逐词元 KL 散度 `KL(P_a || P_b)` 的统计。
+pub struct KlStats {
+    pub n_tok: usize,
+    pub mean: f64,
+    pub median: f64,
+    pub p99: f64,
+    pub max: f64,
+    /// 两个模型 top-1 预测一致的比例。
+    pub top1_agree: f64,
+}
+
+/// 在数据集上比较两个检查点（例如 f32 与量化、基座与微调）逐词元的预测分布。
+///
+/// `n_batch` 必须大于 0。
+pub fn kl_compare(
+    a: &Gpt2<Blob>,
+    b: &Gpt2<Blob>,
+    loader: &mut DataLoader,
+    n_batch: usize,
+) -> KlStats {
+    assert!(n_batch > 0, "kl_compare needs at least one batch");
+    let n_voc = a.config.n_voc;
+    assert_eq!(n_voc, b.config.n_voc);
+
+    let mut models = [a, b].map(|gpt2| {
+        let mut ctx = Context::new(false);
+        let gpt2 = ctx.init::<gpt2::Gpt2>("gpt2", gpt2.clone().map(RwRc::new));
+        (ctx, gpt2)
+    });
+
+    let shape = loader.shape();
+    let mut kls = Vec::new();
+    let mut agree = 0;
+    for _ in 0..n_batch {
+        let [inputs, _] = loader.load();
+        let [pa, pb] = models.each_mut().map(|(ctx, gpt2)| {
+            let tokens = Tensor::new(types::U16, &shape).map(|_| RwRc::new(inputs.into()));
+            let logits = ctx.forward("gpt2", gpt2, [tokens.share()]);
+            let probs = ctx.tensor(logits[0].dt(), &logits[0].shape());
+            softmax(&probs, &logits[0], n_voc, ctx.is_compensated_sum());
+            probs
+        });
+
+        dims!([_, _, n_voc_padded] = pa);
+        let pa = pa.as_ref().map(|b| &**b.read()).merge(0, 3).vector::<f32>();
+        let pb = pb.as_ref().map(|b| &**b.read()).merge(0, 3).vector::<f32>();
+        for (pa, pb) in zip(pa.chunks_exact(n_voc_padded), pb.chunks_exact(n_voc_padded)) {
+            let (pa, pb) = (&pa[..n_voc], &pb[..n_voc]);
+            let kl = zip(pa, pb)
+                .filter(|&(&p, _)| p > 0.)
+                .map(|(&p, &q)| p as f64 * (p as f64 / (q as f64).max(f64::MIN_POSITIVE)).ln())
+                .sum::<f64>();
+            kls.push(kl.max(0.));
+            agree += (crate::op::topk::argmax(pa) == crate::op::topk::argmax(pb)) as usize
+        }
+    }
+
+    kls.sort_by(f64::total_cmp);
+    let n_tok = kls.len();
+    let quantile = |q: f64| kls[((n_tok - 1) as f64 * q).round() as usize];
+    KlStats {
+        n_tok,
+        mean: kls.iter().sum::<f64>() / n_tok as f64,
+        median: quantile(0.5),
+        p99: quantile(0.99),
+        max: kls[n_tok - 1],
+        top1_agree: agree as f64 / n_tok as f64,
+    }
+}
+
+impl fmt::Display for KlStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "tokens: {}", self.n_tok)?;
+        writeln!(
+            f,
+            "KL mean {:.4e}, median {:.4e}, p99 {:.4e}, max {:.4e}",
+            self.mean, self.median, self.p99, self.max
+        )?;
+        writeln!(f, "top-1 agreement: {:.2}%", self.top1_agree * 100.)
+    }
+}
@@ -0,0 +1,9 @@
+//! 模型评估工具。
+
+mod kl;
+mod needle;
+mod suite;
+
+pub use kl::{KlStats, kl_compare};
+pub use needle::{NeedleCell, NeedleConfig, NeedleReport, needle};
+pub use suite::{Item, Report, TaskScore, load_task, run_suite};
@@ -0,0 +1,261 @@
+use crate::{
+    Blob, Context,
+    generate::{GenerationConfig, generate_cached},
+    llmc::{Gpt2, Tokenizer},
+    nn::gpt2,
+    truncate::Truncation,
+};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use rw_rc::RwRc;
+use serde::Serialize;
+use std::{fmt, io};
+
+/// 填充上下文的句子，循环使用。
+const FILLER: &[&str] = &[
+    "The grass is green. ",
+    "The sky is blue. ",
+    "The sun is yellow. ",
+    "Here we go. ",
+    "There and back again. ",
+];
+const QUESTION: &str = "What is the pass key? The pass key is";
+
+/// 大海捞针评估的设置。
+#[derive(Clone, PartialEq, Debug)]
+pub struct NeedleConfig {
+    /// 测试的上下文长度，以词计，包括针和问题。
+    pub lengths: Vec<usize>,
+    /// 针在填充内容中的相对位置，`0` 为开头，`1` 为结尾。
+    pub depths: Vec<f32>,
+    /// 每个长度和深度组合的试验次数。
+    pub trials: usize,
+    /// 超出模型上下文时的截断策略。
+    pub truncation: Truncation,
+    pub seed: u64,
+}
+
+impl Default for NeedleConfig {
+    fn default() -> Self {
+        Self {
+            lengths: vec![128, 256, 512, 1024],
+            depths: vec![0., 0.25, 0.5, 0.75, 1.],
+            trials: 4,
+            truncation: Truncation::KeepTail,
+            seed: 0,
+        }
+    }
+}
+
+/// 一个长度和深度组合的结果。
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct NeedleCell {
+    pub len: usize,
+    pub depth: f32,
+    pub n_trials: usize,
+    pub n_correct: usize,
+}
+
+/// 大海捞针评估的报告，按长度、深度的顺序排列。
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct NeedleReport {
+    pub cells: Vec<NeedleCell>,
+}
+
+impl NeedleReport {
+    /// 某个上下文长度在所有深度上的检索准确率。
+    pub fn accuracy(&self, len: usize) -> f64 {
+        let (n, correct) = self
+            .cells
+            .iter()
+            .filter(|c| c.len == len)
+            .fold((0, 0), |(n, correct), c| {
+                (n + c.n_trials, correct + c.n_correct)
+            });
+        correct as f64 / n.max(1) as f64
+    }
+}
+
+/// 在长的填充上下文中随机深度处插入一个五位数口令，让模型在结尾复述，
+/// 统计各上下文长度下的检索准确率。
+pub fn needle(
+    gpt2: &Gpt2<Blob>,
+    tokenizer: &Tokenizer,
+    config: &NeedleConfig,
+) -> io::Result<NeedleReport> {
+    let encode = |text: &str| {
+        tokenizer.encode(text.as_bytes()).map_err(|pos| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cannot encode byte {pos} of {text:?}"),
+            )
+        })
+    };
+    let filler = FILLER
+        .iter()
+        .map(|s| encode(s))
+        .collect::<io::Result<Vec<_>>>()?;
+    let question = encode(QUESTION)?;
+
+    let generation = GenerationConfig {
+        n_ctx: gpt2.config.n_seq,
+        n_voc: gpt2.config.n_voc,
+        max_tokens: 8,
+        eos: Some(tokenizer.eos),
+        truncation: config.truncation,
+        echo: false,
+        stop: None,
+        cost: gpt2.config.cost(),
+    };
+    let mut ctx = Context::new(false);
+    let mut model = ctx.init::<gpt2::Gpt2>("gpt2", gpt2.clone().map(RwRc::new));
+    let mut rng = StdRng::seed_from_u64(config.seed);
+
+    let mut cells = Vec::with_capacity(config.lengths.len() * config.depths.len());
+    for &len in &config.lengths {
+        let _span = tracing::info_span!("needle", len).entered();
+        for &depth in &config.depths {
+            let mut n_correct = 0;
+            for _ in 0..config.trials {
+                let key = rng.random_range(10000..100000u32).to_string();
+                let needle = encode(&format!(
+                    "The pass key is {key}. Remember it. {key} is the pass key. "
+                ))?;
+                let prompt = haystack(&filler, &needle, &question, len, depth);
+                let result = generate_cached(
+                    &mut ctx,
+                    "gpt2",
+                    &mut model,
+                    &prompt,
+                    &generation,
+                    |logits| crate::op::topk::argmax(logits) as _,
+                    |t| tokenizer.decode(t),
+                );
+                n_correct += result.text.contains(&*key) as usize
+            }
+            tracing::info!(depth, n_correct, "evaluated");
+            cells.push(NeedleCell {
+                len,
+                depth,
+                n_trials: config.trials,
+                n_correct,
+            })
+        }
+    }
+    Ok(NeedleReport { cells })
+}
+
+/// 拼出总长 `len` 的提示：填充句子、在 `depth` 处的句子边界插入针，最后是问题。
+fn haystack(
+    filler: &[Vec<u16>],
+    needle: &[u16],
+    question: &[u16],
+    len: usize,
+    depth: f32,
+) -> Vec<u16> {
+    let n_filler = len.saturating_sub(needle.len() + question.len());
+    let at = (n_filler as f32 * depth.clamp(0., 1.)) as usize;
+
+    let mut ans = Vec::with_capacity(len.max(needle.len() + question.len()));
+    let mut inserted = false;
+    for sentence in filler.iter().cycle() {
+        let filled = ans.len() - if inserted { needle.len() } else { 0 };
+        if !inserted && filled + sentence.len() > at {
+            ans.extend_from_slice(needle);
+            inserted = true
+        }
+        let filled = ans.len() - if inserted { needle.len() } else { 0 };
+        if filled >= n_filler {
+            break;
+        }
+        let take = sentence.len().min(n_filler - filled);
+        ans.extend_from_slice(&sentence[..take])
+    }
+    ans.extend_from_slice(question);
+    ans
+}
+
+impl fmt::Display for NeedleReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut depths = Vec::new();
+        let mut lengths = Vec::new();
+        for c in &self.cells {
+            if !depths.contains(&c.depth) {
+                depths.push(c.depth)
+            }
+            if !lengths.contains(&c.len) {
+                lengths.push(c.len)
+            }
+        }
+
+        write!(f, "{:>8}", "len")?;
+        for depth in &depths {
+            write!(f, "  {:>6.0}%", depth * 100.)?
+        }
+        writeln!(f, "  {:>7}", "all")?;
+        for &len in &lengths {
+            write!(f, "{len:>8}")?;
+            for depth in &depths {
+                match self
+                    .cells
+                    .iter()
+                    .find(|c| c.len == len && c.depth == *depth)
+                {
+                    Some(c) => write!(f, "  {:>3}/{:<3}", c.n_correct, c.n_trials)?,
+                    None => write!(f, "  {:>7}", "-")?,
+                }
+            }
+            writeln!(f, "  {:6.2}%", self.accuracy(len) * 100.)?
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_needle() {
+    use crate::llmc::Gpt2Config;
+
+    // 字节级分词器：256 个单字节词和一个结束词
+    let mut tokenizer = vec![0i32; 256];
+    tokenizer[..4].copy_from_slice(&[20240328, 2, 257, 256]);
+    let mut tokenizer = tokenizer
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect::<Vec<_>>();
+    for b in 0..=255u8 {
+        tokenizer.extend([1, b])
+    }
+    tokenizer.extend([1, b'$']);
+    let mut aligned = vec![0u32; tokenizer.len().div_ceil(4)];
+    unsafe { aligned.align_to_mut::<u8>().1[..tokenizer.len()].copy_from_slice(&tokenizer) };
+    let tokenizer = Tokenizer::from_bytes(unsafe { aligned.align_to::<u8>().1 });
+
+    // 针在要求的深度处，总长度准确
+    let filler = [vec![1, 1, 1], vec![2, 2]];
+    let key = [9, 9];
+    let question = [7];
+    for (depth, at) in [(0., 0), (0.5, 5), (1., 10)] {
+        let prompt = haystack(&filler, &key, &question, 13, depth);
+        assert_eq!(prompt.len(), 13);
+        assert_eq!(prompt[at..][..2], key);
+        assert_eq!(prompt[12], 7)
+    }
+
+    let gpt2 = Gpt2::random(Gpt2Config::tiny(257), &mut StdRng::seed_from_u64(42));
+    let config = NeedleConfig {
+        lengths: vec![64, 128],
+        depths: vec![0., 1.],
+        trials: 2,
+        ..Default::default()
+    };
+    let report = needle(&gpt2, &tokenizer, &config).unwrap();
+    let cells = report
+        .cells
+        .iter()
+        .map(|c| (c.len, c.depth, c.n_trials))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        cells,
+        [(64, 0., 2), (64, 1., 2), (128, 0., 2), (128, 1., 2)]
+    );
+    assert!(report.to_string().lines().count() == 3)
+}
@@ -0,0 +1,296 @@
+use crate::{
+    Blob, Context, Tensor,
+    generate::{GenerationConfig, generate_cached},
+    llmc::{Gpt2, Tokenizer},
+    nn::gpt2,
+    truncate::Truncation,
+};
+use digit_layout::types;
+use regex::Regex;
+use rw_rc::RwRc;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+/// 任务文件中的一道题，每行一个 JSON 对象，以 `type` 区分题型。
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Item {
+    /// 多选题：每个选项接在提示后计算总对数概率，最大者为模型的选择。
+    Choice {
+        prompt: String,
+        choices: Vec<String>,
+        answer: usize,
+    },
+    /// 生成题：从提示贪心生成，生成的文本与正则匹配即为答对。
+    Generate {
+        prompt: String,
+        pattern: String,
+        #[serde(default = "default_max_tokens")]
+        max_tokens: usize,
+    },
+}
+
+fn default_max_tokens() -> usize {
+    32
+}
+
+/// 一个任务的得分。
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct TaskScore {
+    /// 任务文件名，不含扩展名。
+    pub name: String,
+    pub n_items: usize,
+    pub n_correct: usize,
+}
+
+impl TaskScore {
+    pub fn accuracy(&self) -> f64 {
+        self.n_correct as f64 / self.n_items.max(1) as f64
+    }
+}
+
+/// 整个评估集的得分报告，可以序列化为 JSON 以便跟踪。
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct Report {
+    pub tasks: Vec<TaskScore>,
+}
+
+impl Report {
+    /// 各任务准确率的平均值。
+    pub fn mean_accuracy(&self) -> f64 {
+        self.tasks.iter().map(TaskScore::accuracy).sum::<f64>() / self.tasks.len().max(1) as f64
+    }
+}
+
+/// 读取一个任务文件，跳过空行。
+pub fn load_task(path: impl AsRef<Path>) -> io::Result<Vec<Item>> {
+    let mut ans = Vec::new();
+    for line in BufReader::new(fs::File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            ans.push(serde_json::from_str(&line)?)
+        }
+    }
+    Ok(ans)
+}
+
+/// 运行目录中所有 `*.jsonl` 任务，按文件名排序。
+pub fn run_suite(
+    dir: impl AsRef<Path>,
+    gpt2: &Gpt2<Blob>,
+    tokenizer: &Tokenizer,
+) -> io::Result<Report> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.retain(|p| p.extension().is_some_and(|ext| ext == "jsonl"));
+    paths.sort();
+
+    let mut evaluator = Evaluator::new(gpt2, tokenizer);
+    let mut tasks = Vec::with_capacity(paths.len());
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let _span = tracing::info_span!("task", name).entered();
+
+        let items = load_task(&path)?;
+        let mut n_correct = 0;
+        for item in &items {
+            n_correct += evaluator.grade(item)? as usize
+        }
+        let score = TaskScore {
+            name,
+            n_items: items.len(),
+            n_correct,
+        };
+        tracing::info!(score.n_items, score.n_correct, "evaluated");
+        tasks.push(score)
+    }
+    Ok(Report { tasks })
+}
+
+struct Evaluator<'a> {
+    ctx: Context,
+    gpt2: gpt2::Gpt2,
+    tokenizer: &'a Tokenizer,
+    config: GenerationConfig,
+}
+
+impl<'a> Evaluator<'a> {
+    fn new(gpt2: &Gpt2<Blob>, tokenizer: &'a Tokenizer) -> Self {
+        let config = GenerationConfig {
+            n_ctx: gpt2.config.n_seq,
+            n_voc: gpt2.config.n_voc,
+            max_tokens: 0,
+            eos: Some(tokenizer.eos),
+            truncation: Truncation::KeepTail,
+            echo: false,
+            stop: None,
+            cost: gpt2.config.cost(),
+        };
+        let mut ctx = Context::new(false);
+        let gpt2 = ctx.init::<gpt2::Gpt2>("gpt2", gpt2.clone().map(RwRc::new));
+        Self {
+            ctx,
+            gpt2,
+            tokenizer,
+            config,
+        }
+    }
+
+    fn encode(&self, text: &str) -> io::Result<Vec<u16>> {
+        self.tokenizer.encode(text.as_bytes()).map_err(|pos| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("cannot encode byte {pos} of {text:?}"),
+            )
+        })
+    }
+
+    fn grade(&mut self, item: &Item) -> io::Result<bool> {
+        match item {
+            Item::Choice {
+                prompt,
+                choices,
+                answer,
+            } => {
+                let prompt = self.encode(prompt)?;
+                let mut best = (f32::NEG_INFINITY, usize::MAX);
+                for (i, choice) in choices.iter().enumerate() {
+                    let logprob = self.logprob(&prompt, &self.encode(choice)?);
+                    if logprob > best.0 {
+                        best = (logprob, i)
+                    }
+                }
+                Ok(best.1 == *answer)
+            }
+            Item::Generate {
+                prompt,
+                pattern,
+                max_tokens,
+            } => {
+                let pattern = Regex::new(pattern)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let prompt = self.encode(prompt)?;
+                let config = GenerationConfig {
+                    max_tokens: *max_tokens,
+                    ..self.config.clone()
+                };
+                let Self {
+                    ctx,
+                    gpt2,
+                    tokenizer,
+                    ..
+                } = self;
+                let result = generate_cached(ctx, "gpt2", gpt2, &prompt, &config, argmax, |t| {
+                    tokenizer.decode(t)
+                });
+                Ok(pattern.is_match(&result.text))
+            }
+        }
+    }
+
+    /// `continuation` 接在 `prompt` 之后的总对数概率，超出上下文的部分截去。
+    fn logprob(&mut self, prompt: &[u16], continuation: &[u16]) -> f32 {
+        let mut tokens = [prompt, continuation].concat();
+        tokens.truncate(self.config.n_ctx);
+        let len = tokens.len();
+
+        let tokens_ = Tensor::new(types::U16, &[1, len])
+            .map(|_| Blob::from(&*tokens))
+            .map(RwRc::new);
+        let logits = self.ctx.forward("gpt2", &mut self.gpt2, [tokens_.share()]);
+        let logits = logits[0].cloned();
+        let buf = logits.get().read();
+        let ([], buf, []) = (unsafe { buf.align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let n_voc_padded = buf.len() / len;
+
+        (prompt.len().max(1)..len)
+            .map(|pos| {
+                let logits = &buf[(pos - 1) * n_voc_padded..][..self.config.n_voc];
+                let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                let sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>();
+                logits[tokens[pos] as usize] - max - sum.ln()
+            })
+            .sum()
+    }
+}
+
+fn argmax(logits: &[f32]) -> u16 {
+    crate::op::topk::argmax(logits) as _
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.tasks.iter().map(|t| t.name.len()).max().unwrap_or(0);
+        for task in &self.tasks {
+            writeln!(
+                f,
+                "{:width$}  {:>4}/{:<4}  {:6.2}%",
+                task.name,
+                task.n_correct,
+                task.n_items,
+                task.accuracy() * 100.
+            )?
+        }
+        writeln!(f, "mean accuracy: {:.2}%", self.mean_accuracy() * 100.)
+    }
+}
+
+#[test]
+fn test_run_suite() {
+    use crate::llmc::Gpt2Config;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    // 字节级分词器：256 个单字节词和一个结束词
+    let mut tokenizer = vec![0i32; 256];
+    tokenizer[..4].copy_from_slice(&[20240328, 2, 257, 256]);
+    let mut tokenizer = tokenizer
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect::<Vec<_>>();
+    for b in 0..=255u8 {
+        tokenizer.extend([1, b])
+    }
+    tokenizer.extend([1, b'$']);
+    // `from_bytes` 要求 4 字节对齐
+    let mut aligned = vec![0u32; tokenizer.len().div_ceil(4)];
+    unsafe { aligned.align_to_mut::<u8>().1[..tokenizer.len()].copy_from_slice(&tokenizer) };
+    let tokenizer = Tokenizer::from_bytes(unsafe { aligned.align_to::<u8>().1 });
+
+    let gpt2 = Gpt2::random(Gpt2Config::tiny(257), &mut StdRng::seed_from_u64(42));
+
+    let dir = std::env::temp_dir().join(format!("llm-rs-eval-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // 空选项的对数概率是 0，总是被选中
+    fs::write(
+        dir.join("choice.jsonl"),
+        r#"{"type":"choice","prompt":"ab","choices":["x",""],"answer":1}
+{"type":"choice","prompt":"ab","choices":["x",""],"answer":0}
+"#,
+    )
+    .unwrap();
+    fs::write(
+        dir.join("gen.jsonl"),
+        r#"{"type":"generate","prompt":"hello","pattern":"^.*$","max_tokens":2}
+
+{"type":"generate","prompt":"hello","pattern":"^$","max_tokens":2}
+"#,
+    )
+    .unwrap();
+    fs::write(dir.join("notes.txt"), "not a task").unwrap();
+
+    let report = run_suite(&dir, &gpt2, &tokenizer).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let names = report.tasks.iter().map(|t| &*t.name).collect::<Vec<_>>();
+    assert_eq!(names, ["choice", "gen"]);
+    assert_eq!((report.tasks[0].n_items, report.tasks[0].n_correct), (2, 1));
+    assert_eq!(report.tasks[1].n_items, 2);
+    assert!(report.tasks[1].n_correct >= 1)
+}
@@ -0,0 +1,1052 @@
+//! 自回归生成。
+
+use crate::{
+    Blob, Context, Tensor,
+    nn::{NeuralNetwork, gpt2::Gpt2},
+    repro::Manifest,
+    stop::StopCriteria,
+    truncate::Truncation,
+};
+use digit_layout::types;
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator},
+    slice::ParallelSlice,
+};
+use rw_rc::RwRc;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// 生成参数。
+#[derive(Clone, Debug)]
+pub struct GenerationConfig {
+    /// 模型支持的最大序列长度。
+    pub n_ctx: usize,
+    /// 有效词表大小，logits 中超出的部分是填充。
+    pub n_voc: usize,
+    /// 最多生成的词数。
+    pub max_tokens: usize,
+    /// 生成此词时停止，它不计入结果。
+    pub eos: Option<u16>,
+    /// 提示过长时的截断策略。
+    pub truncation: Truncation,
+    /// 预填充时顺便给出提示中各词的对数概率，见 [`GenerationResult::prompt_logprobs`]。
+    pub echo: bool,
+    /// 每生成一个词检查的停止条件，多个条件用 [`crate::stop::AnyOf`] 组合，
+    /// 停止序列用 [`crate::stop::StopSequences`]。
+    pub stop: Option<Arc<dyn StopCriteria + Send + Sync>>,
+    /// 模型前向的计算量，用于统计每次生成的浮点运算数。
+    pub cost: Cost,
+}
+
+/// 前向计算量模型：长为 `len` 的序列消耗 `per_token * len + per_pair * len * (len + 1) / 2` 次浮点运算，
+/// 后一项是因果注意力中每对可见位置的开销。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize)]
+pub struct Cost {
+    pub per_token: u64,
+    pub per_pair: u64,
+}
+
+impl Cost {
+    /// 长为 `len` 的序列一次前向的浮点运算数。
+    pub fn flops(&self, len: usize) -> u64 {
+        let len = len as u64;
+        self.per_token * len + self.per_pair * len * (len + 1) / 2
+    }
+}
+
+/// 停止生成的原因。
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// 生成了结束词。
+    Stop,
+    /// 达到 `max_tokens` 或上下文长度。
+    Length,
+    /// 满足了 [`GenerationConfig::stop`]。
+    Criteria,
+}
+
+/// 各阶段耗时，包含采样。
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct Timing {
+    /// 处理提示并产生第一个词。
+    pub prefill: Duration,
+    /// 产生其余的词。
+    pub decode: Duration,
+}
+
+/// 缓存统计。
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct CacheStats {
+    /// 复用缓存的位置数。
+    pub reused: usize,
+    /// 实际计算的位置数。
+    pub computed: usize,
+}
+
+/// 一次生成消耗的计算。
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct Compute {
+    /// 按 [`Cost`] 估计的浮点运算数。
+    pub flops: u64,
+    /// 前向计算的耗时，不含采样。
+    pub time: Duration,
+}
+
+/// 一次生成的结果。
+#[derive(Clone, Debug, Serialize)]
+pub struct GenerationResult {
+    /// 截断后实际使用的提示长度。
+    pub n_prompt: usize,
+    /// 生成的词。
+    pub tokens: Vec<u16>,
+    /// 生成的词解码得到的文本，非法 UTF-8 会被替换。
+    pub text: String,
+    /// 每个生成的词的对数概率。
+    pub logprobs: Vec<f32>,
+    /// 开启 `echo` 时截断后提示中第 2 个词起各词的对数概率，由预填充的 logits 得到，不需要额外的前向。
+    /// 复用缓存而没有重新计算的位置为 NaN。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_logprobs: Option<Vec<f32>>,
+    pub finish_reason: FinishReason,
+    pub timing: Timing,
+    pub cache: CacheStats,
+    pub compute: Compute,
+    /// 可复现模式下的复现清单，见 [`crate::repro`]。
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<Manifest>,
+}
+
+/// 多次生成的累计用量，用于计费或预算。
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct Usage {
+    pub requests: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub flops: u64,
+    pub compute_time: Duration,
+}
+
+impl GenerationResult {
+    /// 提示的困惑度，没有开启 `echo` 或没有可用的对数概率时为 `None`。
+    pub fn prompt_perplexity(&self) -> Option<f32> {
+        let logprobs = self.prompt_logprobs.as_ref()?;
+        let (n, sum) = logprobs
+            .iter()
+            .filter(|x| !x.is_nan())
+            .fold((0, 0.), |(n, sum), x| (n + 1, sum + x));
+        (n > 0).then(|| (-sum / n as f32).exp())
+    }
+}
+
+/// 按第 `i` 行 logits 预测 `targets[i]` 计算对数概率，`rows` 每行 `n_voc_padded` 个值，只考虑前 `n_voc` 个。
+///
+/// 逐行并行，只求 logsumexp 而不写出 softmax，用于在预填充中顺便为提示打分。
+pub fn score_tokens(rows: &[f32], n_voc_padded: usize, n_voc: usize, targets: &[u16]) -> Vec<f32> {
+    rows.par_chunks_exact(n_voc_padded)
+        .zip(targets.par_iter())
+        .map(|(logits, &target)| {
+            let logits = &logits[..n_voc];
+            let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>();
+            logits[target as usize] - max - sum.ln()
+        })
+        .collect()
+}
+
+impl Usage {
+    /// 计入一次生成。
+    pub fn add(&mut self, result: &GenerationResult) {
+        self.requests += 1;
+        self.prompt_tokens += result.n_prompt;
+        self.completion_tokens += result.tokens.len();
+        self.flops += result.compute.flops;
+        self.compute_time += result.compute.time
+    }
+}
+
+/// 从 `prompt` 开始生成，`sample` 从有效词表的 logits 中选出下一个词，`decode` 将词转为字节。
+///
+/// 每步都对整个序列重新计算前向。
+pub fn generate<'a>(
+    ctx: &mut Context,
+    name: &str,
+    model: &mut impl NeuralNetwork,
+    prompt: &[u16],
+    config: &GenerationConfig,
+    sample: impl FnMut(&[f32]) -> u16,
+    decode: impl Fn(u16) -> &'a [u8],
+) -> GenerationResult {
+    let forward = |tokens: &[u16]| {
+        let tokens = Tensor::new(types::U16, &[1, tokens.len()])
+            .map(|_| Blob::from(tokens))
+            .map(RwRc::new);
+        let logits = ctx.forward(name, model, [tokens.share()]);
+        (logits.into_iter().next().unwrap(), 0)
+    };
+    generate_with(prompt, config, forward, sample, decode)
+}
+
+/// 与 [`generate`] 相同，但开启 GPT-2 的 KV 缓存（见 [`Gpt2::kv_cache`]）增量解码，
+/// 每步只计算新词，生成的总计算量随长度线性增长。
+///
+/// 生成前清空缓存，模型原本没有开启缓存时生成后关闭。
+pub fn generate_cached<'a>(
+    ctx: &mut Context,
+    name: &str,
+    model: &mut Gpt2,
+    prompt: &[u16],
+    config: &GenerationConfig,
+    sample: impl FnMut(&[f32]) -> u16,
+    decode: impl Fn(u16) -> &'a [u8],
+) -> GenerationResult {
+    generate_stream(ctx, name, model, prompt, config, sample, decode).finish()
+}
+
+/// 与 [`generate_cached`] 相同，但返回逐段产生生成文本的 [`Stream`]，
+/// 迭代结束后可以用 [`Stream::finish`] 取得完整的结果。
+pub fn generate_stream<'m, 'a, S, D>(
+    ctx: &'m mut Context,
+    name: &'m str,
+    model: &'m mut Gpt2,
+    prompt: &[u16],
+    config: &GenerationConfig,
+    sample: S,
+    decode: D,
+) -> Stream<'m, S, D>
+where
+    S: FnMut(&[f32]) -> u16,
+    D: Fn(u16) -> &'a [u8],
+{
+    let enabled = model.cached_len().is_some();
+    if enabled {
+        model.clear_kv_cache()
+    } else {
+        model.kv_cache(true)
+    }
+    Stream {
+        ctx,
+        name,
+        model,
+        enabled,
+        generation: Some(Generation::new(prompt, config, sample, decode)),
+        emitted: 0,
+    }
+}
+
+/// 逐段产生生成文本的迭代器，由 [`generate_stream`] 创建。
+///
+/// 每生成一个词产生新增的文本，不完整的 UTF-8 字符和可能被停止条件截掉的文本
+/// （见 [`StopCriteria::safe_len`]）留在缓冲中，所有片段拼接起来等于结果的 `text`。
+/// 模型原本没有开启 KV 缓存时在析构时关闭。
+pub struct Stream<'m, S, D> {
+    ctx: &'m mut Context,
+    name: &'m str,
+    model: &'m mut Gpt2,
+    /// 模型原本开启了 KV 缓存。
+    enabled: bool,
+    generation: Option<Generation<S, D>>,
+    /// 已经产生的文本字节数。
+    emitted: usize,
+}
+
+impl<'a, S, D> Stream<'_, S, D>
+where
+    S: FnMut(&[f32]) -> u16,
+    D: Fn(u16) -> &'a [u8],
+{
+    fn step(&mut self) -> Option<FinishReason> {
+        let Self {
+            ctx,
+            name,
+            model,
+            generation,
+            ..
+        } = self;
+        let forward = |tokens: &[u16]| forward_cached(ctx, name, model, tokens);
+        generation.as_mut().unwrap().step(forward)
+    }
+
+    /// 生成到结束，返回完整的结果。
+    pub fn finish(mut self) -> GenerationResult {
+        while self.step().is_none() {}
+        self.generation.take().unwrap().finish()
+    }
+}
+
+impl<'a, S, D> Iterator for Stream<'_, S, D>
+where
+    S: FnMut(&[f32]) -> u16,
+    D: Fn(u16) -> &'a [u8],
+{
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            let finished = self.step().is_some();
+            let generation = self.generation.as_ref().unwrap();
+            let text = &generation.text;
+            let end = if finished {
+                text.len()
+            } else {
+                let safe = match &generation.config.stop {
+                    Some(stop) => {
+                        let tokens = &generation.tokens[generation.n_prompt..];
+                        stop.safe_len(tokens, text, &generation.decode)
+                    }
+                    None => text.len(),
+                };
+                utf8_safe_len(&text[..safe])
+            };
+            if end > self.emitted {
+                let chunk = String::from_utf8_lossy(&text[self.emitted..end]).into_owned();
+                self.emitted = end;
+                return Some(chunk);
+            }
+            if finished {
+                return None;
+            }
+        }
+    }
+}
+
+impl<S, D> Drop for Stream<'_, S, D> {
+    fn drop(&mut self) {
+        if !self.enabled {
+            self.model.kv_cache(false)
+        }
+    }
+}
+
+/// `bytes` 中不以不完整的 UTF-8 字符结尾的最长前缀，中间的非法字节不影响。
+fn utf8_safe_len(bytes: &[u8]) -> usize {
+    let mut start = 0;
+    loop {
+        match std::str::from_utf8(&bytes[start..]) {
+            Ok(_) => return bytes.len(),
+            Err(e) => match e.error_len() {
+                Some(n) => start += e.valid_up_to() + n,
+                None => return start + e.valid_up_to(),
+            },
+        }
+    }
+}
+
+/// 用 KV 缓存前向，只计算缓存之后的词，返回 logits 和复用的位置数。
+fn forward_cached(
+    ctx: &mut Context,
+    name: &str,
+    model: &mut Gpt2,
+    tokens: &[u16],
+) -> (Rc<Tensor<RwRc<Blob>>>, usize) {
+    let reused = model.cached_len().unwrap();
+    let tokens = &tokens[reused..];
+    let tokens = Tensor::new(types::U16, &[1, tokens.len()])
+        .map(|_| Blob::from(tokens))
+        .map(RwRc::new);
+    let logits = ctx.forward(name, model, [tokens.share()]);
+    (logits.into_iter().next().unwrap(), reused)
+}
+
+/// 生成的主循环，`forward` 对当前的全部词做前向，返回 logits 和复用缓存而跳过的位置数，
+/// logits 的最后一行是下一个词的分布。
+pub(crate) fn generate_with<'a>(
+    prompt: &[u16],
+    config: &GenerationConfig,
+    mut forward: impl FnMut(&[u16]) -> (Rc<Tensor<RwRc<Blob>>>, usize),
+    sample: impl FnMut(&[f32]) -> u16,
+    decode: impl Fn(u16) -> &'a [u8],
+) -> GenerationResult {
+    let mut generation = Generation::new(prompt, config, sample, decode);
+    while generation.step(&mut forward).is_none() {}
+    generation.finish()
+}
+
+/// 生成的状态，每次 [`Generation::step`] 做一次前向并生成一个词。
+struct Generation<S, D> {
+    config: GenerationConfig,
+    sample: S,
+    decode: D,
+    span: tracing::Span,
+    /// 截断后的提示和生成的词。
+    tokens: Vec<u16>,
+    n_prompt: usize,
+    logprobs: Vec<f32>,
+    prompt_logprobs: Option<Vec<f32>>,
+    text: Vec<u8>,
+    timing: Timing,
+    cache: CacheStats,
+    compute: Compute,
+    finish_reason: Option<FinishReason>,
+}
+
+impl<'a, S, D> Generation<S, D>
+where
+    S: FnMut(&[f32]) -> u16,
+    D: Fn(u16) -> &'a [u8],
+{
+    fn new(prompt: &[u16], config: &GenerationConfig, sample: S, decode: D) -> Self {
+        assert!(!prompt.is_empty() && config.n_ctx > 0);
+
+        // 每次生成一个请求号，用于关联日志
+        static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+        let request = NEXT_REQUEST.fetch_add(1, Ordering::Relaxed);
+        let span = tracing::info_span!("generate", request);
+
+        let tokens = config.truncation.apply(prompt, config.n_ctx);
+        Self {
+            config: config.clone(),
+            sample,
+            decode,
+            span,
+            n_prompt: tokens.len(),
+            tokens,
+            logprobs: Vec::new(),
+            prompt_logprobs: None,
+            text: Vec::new(),
+            timing: Timing::default(),
+            cache: CacheStats::default(),
+            compute: Compute::default(),
+            finish_reason: None,
+        }
+    }
+
+    /// 生成一个词，结束时返回停止的原因。
+    fn step(
+        &mut self,
+        mut forward: impl FnMut(&[u16]) -> (Rc<Tensor<RwRc<Blob>>>, usize),
+    ) -> Option<FinishReason> {
+        if self.finish_reason.is_some() {
+            return self.finish_reason;
+        }
+        let span = self.span.clone();
+        let _span = span.enter();
+        let &GenerationConfig {
+            n_ctx,
+            n_voc,
+            max_tokens,
+            eos,
+            echo,
+            ref stop,
+            cost,
+            ..
+        } = &self.config;
+        let Self {
+            tokens,
+            n_prompt,
+            logprobs,
+            text,
+            timing,
+            cache,
+            compute,
+            ..
+        } = self;
+        let n_prompt = *n_prompt;
+
+        // 最后一个位置的输出仍然可以作为新词，只是不能再输入模型
+        if logprobs.len() == max_tokens || tokens.len() > n_ctx {
+            self.finish_reason = Some(FinishReason::Length);
+            return self.finish_reason;
+        }
+        let time = Instant::now();
+
+        let len = tokens.len();
+        let (logits, reused) = forward(tokens);
+        cache.reused += reused;
+        cache.computed += len - reused;
+        compute.flops += cost.flops(len) - cost.flops(reused);
+        compute.time += time.elapsed();
+
+        if echo && self.prompt_logprobs.is_none() {
+            // 第 j 行预测第 reused + j + 1 个词，复用缓存的位置没有 logits
+            let buf = logits.get().read();
+            let ([], buf, []) = (unsafe { buf.align_to::<f32>() }) else {
+                unreachable!()
+            };
+            let n_voc_padded = buf.len() / (len - reused);
+            let mut scores = vec![f32::NAN; reused.min(n_prompt - 1)];
+            let targets = tokens.get(reused + 1..n_prompt).unwrap_or(&[]);
+            scores.extend(score_tokens(buf, n_voc_padded, n_voc, targets));
+            self.prompt_logprobs = Some(scores)
+        }
+
+        let logits = logits.cloned().index(&[0, len - reused - 1]);
+        let logits = &logits.as_ref().map(|b| &**b.read()).vector::<f32>()[..n_voc];
+        let next = (self.sample)(logits);
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>();
+
+        *if logprobs.is_empty() {
+            &mut timing.prefill
+        } else {
+            &mut timing.decode
+        } += time.elapsed();
+
+        if Some(next) == eos {
+            self.finish_reason = Some(FinishReason::Stop);
+            return self.finish_reason;
+        }
+        logprobs.push(logits[next as usize] - max - sum.ln());
+        tokens.push(next);
+        text.extend_from_slice((self.decode)(next));
+        if check_stop(stop, &tokens[n_prompt..], logprobs, text, &self.decode) {
+            tokens.truncate(n_prompt + logprobs.len());
+            self.finish_reason = Some(FinishReason::Criteria);
+        }
+        self.finish_reason
+    }
+
+    fn finish(mut self) -> GenerationResult {
+        let _span = self.span.enter();
+        let n_prompt = self.n_prompt;
+        let finish_reason = self.finish_reason.unwrap();
+        let tokens = self.tokens.split_off(n_prompt);
+        tracing::debug!(
+            n_prompt,
+            n_tokens = tokens.len(),
+            ?finish_reason,
+            flops = self.compute.flops,
+            elapsed = ?(self.timing.prefill + self.timing.decode),
+            "generated"
+        );
+        GenerationResult {
+            n_prompt,
+            text: String::from_utf8_lossy(&self.text).into_owned(),
+            tokens,
+            logprobs: self.logprobs,
+            prompt_logprobs: self.prompt_logprobs,
+            finish_reason,
+            timing: self.timing,
+            cache: self.cache,
+            compute: self.compute,
+            manifest: None,
+        }
+    }
+}
+
+/// 检查停止条件，满足时按 [`StopCriteria::cut`] 截断对数概率和文本，调用者相应截断生成的词 `tokens`。
+pub(crate) fn check_stop<'a>(
+    stop: &Option<Arc<dyn StopCriteria + Send + Sync>>,
+    tokens: &[u16],
+    logprobs: &mut Vec<f32>,
+    text: &mut Vec<u8>,
+    decode: impl Fn(u16) -> &'a [u8],
+) -> bool {
+    let Some(stop) = stop.as_ref().filter(|stop| stop.should_stop(tokens, text)) else {
+        return false;
+    };
+    if let Some(cut) = stop.cut(tokens, text) {
+        let (n, len) = cut.resolve(tokens, decode);
+        logprobs.truncate(n);
+        text.truncate(len)
+    }
+    true
+}
+
+/// 从同一个 `prompt` 批量生成 `n` 个候选，每步对 `[n, len]` 的批做一次前向。
+///
+/// 每个候选独立调用 `sample`，已结束的候选以 0 填充直到所有候选结束。
+/// 各阶段耗时是整批共享的，计算耗时均摊到每个候选，浮点运算数只计入候选自身未结束的步。
+#[allow(clippy::too_many_arguments)]
+pub fn generate_n<'a>(
+    ctx: &mut Context,
+    name: &str,
+    model: &mut impl NeuralNetwork,
+    prompt: &[u16],
+    config: &GenerationConfig,
+    n: usize,
+    mut sample: impl FnMut(&[f32]) -> u16,
+    decode: impl Fn(u16) -> &'a [u8],
+) -> Vec<GenerationResult> {
+    let &GenerationConfig {
+        n_ctx,
+        n_voc,
+        max_tokens,
+        eos,
+        truncation,
+        echo,
+        ref stop,
+        cost,
+    } = config;
+    assert!(!prompt.is_empty() && n_ctx > 0 && n > 0);
+
+    let tokens = truncation.apply(prompt, n_ctx);
+    let n_prompt = tokens.len();
+    let mut rows = vec![tokens; n];
+    let mut logprobs = vec![Vec::new(); n];
+    let mut texts = vec![Vec::new(); n];
+    let mut finish_reasons = vec![None; n];
+    let mut timing = Timing::default();
+    let mut caches = vec![CacheStats::default(); n];
+    let mut computes = vec![Compute::default(); n];
+    let mut time = Duration::ZERO;
+    let mut prompt_logprobs = None;
+
+    for step in 0.. {
+        let len = n_prompt + step;
+        if step == max_tokens || len > n_ctx {
+            break;
+        }
+        if finish_reasons.iter().all(Option::is_some) {
+            break;
+        }
+        let start = Instant::now();
+
+        let batch = rows.concat();
+        let tokens_ = Tensor::new(types::U16, &[n, len])
+            .map(|_| Blob::from(&*batch))
+            .map(RwRc::new);
+        let logits = ctx.forward(name, model, [tokens_.share()]);
+        time += start.elapsed();
+
+        // 各行的提示相同，取第一行打分
+        if echo && step == 0 {
+            let buf = logits[0].get().read();
+            let ([], buf, []) = (unsafe { buf.align_to::<f32>() }) else {
+                unreachable!()
+            };
+            let n_voc_padded = buf.len() / (n * len);
+            prompt_logprobs = Some(score_tokens(buf, n_voc_padded, n_voc, &rows[0][1..]))
+        }
+
+        for i in 0..n {
+            if finish_reasons[i].is_some() {
+                rows[i].push(0);
+                continue;
+            }
+            caches[i].computed += len;
+            computes[i].flops += cost.flops(len);
+
+            let logits = logits[0].cloned().index(&[i, len - 1]);
+            let logits = &logits.as_ref().map(|b| &**b.read()).vector::<f32>()[..n_voc];
+            let next = sample(logits);
+            if Some(next) == eos {
+                finish_reasons[i] = Some(FinishReason::Stop);
+                rows[i].push(0);
+                continue;
+            }
+            let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>();
+            logprobs[i].push(logits[next as usize] - max - sum.ln());
+            rows[i].push(next);
+            texts[i].extend_from_slice(decode(next));
+            // 行中的词不截断，结果只取对数概率对应的部分
+            let generated = &rows[i][n_prompt..];
+            if check_stop(stop, generated, &mut logprobs[i], &mut texts[i], &decode) {
+                finish_reasons[i] = Some(FinishReason::Criteria)
+            }
+        }
+
+        *if step == 0 {
+            &mut timing.prefill
+        } else {
+            &mut timing.decode
+        } += start.elapsed();
+    }
+
+    let ans = rows
+        .into_iter()
+        .zip(logprobs)
+        .zip(texts)
+        .zip(finish_reasons)
+        .zip(caches)
+        .zip(computes)
+        .map(
+            |(((((row, logprobs), text), finish_reason), cache), compute)| {
+                let tokens = row[n_prompt..][..logprobs.len()].to_vec();
+                GenerationResult {
+                    n_prompt,
+                    text: String::from_utf8_lossy(&text).into_owned(),
+                    cache,
+                    tokens,
+                    logprobs,
+                    prompt_logprobs: prompt_logprobs.clone(),
+                    finish_reason: finish_reason.unwrap_or(FinishReason::Length),
+                    timing,
+                    compute: Compute {
+                        time: time / n as u32,
+                        ..compute
+                    },
+                    manifest: None,
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+    tracing::debug!(n, n_prompt, elapsed = ?(timing.prefill + timing.decode), "generated batch");
+    ans
+}
+
+/// 生成的总对数概率，用作 [`best_of`] 的默认评分。
+pub fn sum_logprob(result: &GenerationResult) -> f32 {
+    result.logprobs.iter().sum()
+}
+
+/// 评分最高的候选，评分可以是 [`sum_logprob`] 或外部奖励模型。并列时取靠前的。
+pub fn best_of(
+    results: &[GenerationResult],
+    mut score: impl FnMut(&GenerationResult) -> f32,
+) -> Option<&GenerationResult> {
+    results
+        .iter()
+        .map(|r| (score(r), r))
+        .fold(None, |best: Option<(f32, _)>, (s, r)| match best {
+            Some((best_s, _)) if best_s >= s => best,
+            _ => Some((s, r)),
+        })
+        .map(|(_, r)| r)
+}
+
+/// 自洽性投票：从每个候选中提取答案，返回票数最多的答案及票数。
+/// 提取不到答案的候选不参与投票，并列时取最先出现的答案。
+pub fn majority_vote<T: Eq + Hash>(
+    results: &[GenerationResult],
+    mut extract: impl FnMut(&GenerationResult) -> Option<T>,
+) -> Option<(T, usize)> {
+    let mut votes = HashMap::<T, (usize, usize)>::new();
+    for (i, answer) in results.iter().filter_map(&mut extract).enumerate() {
+        votes.entry(answer).or_insert((0, i)).0 += 1
+    }
+    votes
+        .into_iter()
+        .max_by(|(_, (a, i)), (_, (b, j))| a.cmp(b).then(j.cmp(i)))
+        .map(|(answer, (count, _))| (answer, count))
+}
+
+#[test]
+fn test_generate_n() {
+    use crate::{llmc, nn::gpt2::Gpt2, op::topk};
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::iter::zip;
+
+    let gpt2 = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7));
+    let config = GenerationConfig {
+        n_ctx: gpt2.config.n_seq,
+        n_voc: gpt2.config.n_voc,
+        max_tokens: 5,
+        eos: None,
+        truncation: Truncation::KeepTail,
+        echo: false,
+        stop: None,
+        cost: gpt2.config.cost(),
+    };
+    let mut ctx = Context::new(false);
+    let mut model = ctx.init::<Gpt2>("gpt2", gpt2.map(RwRc::new));
+    let argmax = |logits: &[f32]| topk::argmax(logits) as u16;
+    let decode = |_| &b"x"[..];
+
+    // 贪心解码时批量生成的每个候选都与单独生成一致
+    let prompt = [1, 2, 3];
+    let single = generate(
+        &mut ctx, "gpt2", &mut model, &prompt, &config, argmax, decode,
+    );
+    let batch = generate_n(
+        &mut ctx, "gpt2", &mut model, &prompt, &config, 3, argmax, decode,
+    );
+    for r in &batch {
+        assert_eq!(r.tokens, single.tokens);
+        assert_eq!(r.finish_reason, FinishReason::Length);
+        for (a, b) in zip(&r.logprobs, &single.logprobs) {
+            assert!((a - b).abs() < 1e-4)
+        }
+    }
+
+    let best = best_of(&batch, sum_logprob).unwrap();
+    assert!(std::ptr::eq(best, &batch[0]));
+    let lens = |r: &GenerationResult| Some(r.tokens.len() % 2);
+    assert_eq!(majority_vote(&batch, lens), Some((1, 3)));
+    assert_eq!(majority_vote(&batch, |_| None::<()>), None)
+}
+
+#[test]
+fn test_generate_cached() {
+    use crate::{llmc, op::topk};
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::iter::zip;
+
+    let gpt2 = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7));
+    let config = GenerationConfig {
+        n_ctx: gpt2.config.n_seq,
+        n_voc: gpt2.config.n_voc,
+        max_tokens: 8,
+        eos: None,
+        truncation: Truncation::KeepTail,
+        echo: false,
+        stop: None,
+        cost: gpt2.config.cost(),
+    };
+    let mut ctx = Context::new(false);
+    let mut model = ctx.init::<Gpt2>("gpt2", gpt2.map(RwRc::new));
+    let argmax = |logits: &[f32]| topk::argmax(logits) as u16;
+    let decode = |_| &b"x"[..];
+
+    let prompt = [1, 2, 3, 4, 5];
+    let full = generate(
+        &mut ctx, "gpt2", &mut model, &prompt, &config, argmax, decode,
+    );
+    // 连续两次生成，第二次复用已分配的缓存
+    for _ in 0..2 {
+        let cached = generate_cached(
+            &mut ctx, "gpt2", &mut model, &prompt, &config, argmax, decode,
+        );
+        assert_eq!(cached.tokens, full.tokens);
+        for (a, b) in zip(&cached.logprobs, &full.logprobs) {
+            assert!((a - b).abs() < 1e-4, "{a} vs {b}")
+        }
+        // 每个位置只计算一次，最后生成的词没有输入模型
+        let n = prompt.len() + full.tokens.len() - 1;
+        assert_eq!(cached.cache.computed, n);
+        assert_eq!(cached.compute.flops, config.cost.flops(n));
+    }
+    assert_eq!(model.cached_len(), None)
+}
+
+#[test]
+fn test_echo() {
+    use crate::{llmc, op::topk};
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::iter::zip;
+
+    let gpt2 = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7));
+    let config = GenerationConfig {
+        n_ctx: gpt2.config.n_seq,
+        n_voc: gpt2.config.n_voc,
+        max_tokens: 6,
+        eos: None,
+        truncation: Truncation::KeepTail,
+        echo: true,
+        stop: None,
+        cost: gpt2.config.cost(),
+    };
+    let mut ctx = Context::new(false);
+    let mut model = ctx.init::<Gpt2>("gpt2", gpt2.map(RwRc::new));
+    let argmax = |logits: &[f32]| topk::argmax(logits) as u16;
+    let decode = |_| &b"x"[..];
+
+    let prompt = [1, 2, 3, 4, 5];
+    let first = generate_cached(
+        &mut ctx, "gpt2", &mut model, &prompt, &config, argmax, decode,
+    );
+    assert_eq!(first.prompt_logprobs.as_ref().unwrap().len(), 4);
+
+    // 把生成的词接在提示后作为新的提示，预填充给出的对数概率与生成时相同
+    let echoed = [&prompt[..], &first.tokens].concat();
+    let check = |result: &GenerationResult| {
+        let scores = result.prompt_logprobs.as_ref().unwrap();
+        assert_eq!(scores.len(), echoed.len() - 1);
+        for (a, b) in zip(&scores[..4], first.prompt_logprobs.as_ref().unwrap()) {
+            assert!((a - b).abs() < 1e-4, "{a} vs {b}")
+        }
+        for (a, b) in zip(&scores[4..], &first.logprobs) {
+            assert!((a - b).abs() < 1e-4, "{a} vs {b}")
+        }
+        let ppl = result.prompt_perplexity().unwrap();
+        assert!(ppl >= 1. && ppl.is_finite())
+    };
+    check(&generate_cached(
+        &mut ctx, "gpt2", &mut model, &echoed, &config, argmax, decode,
+    ));
+    check(&generate(
+        &mut ctx, "gpt2", &mut model, &echoed, &config, argmax, decode,
+    ));
+    for result in generate_n(
+        &mut ctx, "gpt2", &mut model, &echoed, &config, 2, argmax, decode,
+    ) {
+        check(&result)
+    }
+
+    let quiet = GenerationConfig {
+        echo: false,
+        ..config
+    };
+    let result = generate(
+        &mut ctx, "gpt2", &mut model, &prompt, &quiet, argmax, decode,
+    );
+    assert!(result.prompt_logprobs.is_none() && result.prompt_perplexity().is_none())
+}
+
+#[test]
+fn test_generate_stop() {
+    use crate::{
+        llmc,
+        op::topk,
+        stop::{AnyOf, Cancellation, MaxTokens, StopSequences, StopStrings},
+    };
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let gpt2 = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7));
+    let base = GenerationConfig {
+        n_ctx: gpt2.config.n_seq,
+        n_voc: gpt2.config.n_voc,
+        max_tokens: 8,
+        eos: None,
+        truncation: Truncation::KeepTail,
+        echo: false,
+        stop: None,
+        cost: gpt2.config.cost(),
+    };
+    let mut ctx = Context::new(false);
+    let mut model = ctx.init::<Gpt2>("gpt2", gpt2.map(RwRc::new));
+    let argmax = |logits: &[f32]| topk::argmax(logits) as u16;
+    // 每个词解码为两个字节，停止字符串跨越词的边界
+    let decode = |_| &b"ab"[..];
+
+    let cancel = Cancellation::new();
+    cancel.cancel();
+    for (stop, n, reason) in [
+        (
+            AnyOf(vec![Arc::new(MaxTokens(3))]),
+            3,
+            FinishReason::Criteria,
+        ),
+        (
+            AnyOf(vec![Arc::new(StopStrings(vec!["baba".into()]))]),
+            3,
+            FinishReason::Criteria,
+        ),
+        (
+            AnyOf(vec![Arc::new(cancel.clone())]),
+            1,
+            FinishReason::Criteria,
+        ),
+        (
+            AnyOf(vec![Arc::new(StopStrings(vec!["c".into()]))]),
+            8,
+            FinishReason::Length,
+        ),
+    ] {
+        let config = GenerationConfig {
+            stop: Some(Arc::new(stop)),
+            ..base.clone()
+        };
+        let prompt = [1, 2, 3];
+        let cached = generate_cached(
+            &mut ctx, "gpt2", &mut model, &prompt, &config, argmax, decode,
+        );
+        assert_eq!((cached.tokens.len(), cached.finish_reason), (n, reason));
+        assert_eq!(cached.text.len(), 2 * n);
+        let batch = generate_n(
+            &mut ctx, "gpt2", &mut model, &prompt, &config, 2, argmax, decode,
+        );
+        for result in batch {
+            assert_eq!(
+                (result.tokens, result.finish_reason),
+                (cached.tokens.clone(), reason)
+            )
+        }
+    }
+    // 停止序列本身不出现在结果中
+    let free = generate_cached(
+        &mut ctx,
+        "gpt2",
+        &mut model,
+        &[1, 2, 3],
+        &base,
+        argmax,
+        decode,
+    );
+    let seq = free.tokens[2..4].to_vec();
+    let n = (1..).find(|&i| free.tokens[i - 1..=i] == seq[..]).unwrap() - 1;
+    for (stop, n, text) in [
+        (
+            StopSequences {
+                strings: vec!["ba".into()],
+                ..Default::default()
+            },
+            0,
+            "a",
+        ),
+        (
+            StopSequences {
+                tokens: vec![seq],
+                ..Default::default()
+            },
+            n,
+            &"ab".repeat(n)[..],
+        ),
+    ] {
+        let config = GenerationConfig {
+            stop: Some(Arc::new(AnyOf(vec![Arc::new(stop)]))),
+            ..base.clone()
+        };
+        let cached = generate_cached(
+            &mut ctx,
+            "gpt2",
+            &mut model,
+            &[1, 2, 3],
+            &config,
+            argmax,
+            decode,
+        );
+        assert_eq!(cached.finish_reason, FinishReason::Criteria);
+        assert_eq!(cached.tokens, free.tokens[..n]);
+        assert_eq!(cached.logprobs.len(), n);
+        assert_eq!(cached.text, text);
+        let batch = generate_n(
+            &mut ctx,
+            "gpt2",
+            &mut model,
+            &[1, 2, 3],
+            &config,
+            2,
+            argmax,
+            decode,
+        );
+        for result in batch {
+            assert_eq!(
+                (result.tokens, result.text),
+                (cached.tokens.clone(), cached.text.clone())
+            )
+        }
+    }
+}
+
+#[test]
+fn test_generate_stream() {
+    use crate::{llmc, stop::StopSequences};
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let gpt2 = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7));
+    let config = GenerationConfig {
+        n_ctx: gpt2.config.n_seq,
+        n_voc: gpt2.config.n_voc,
+        max_tokens: 16,
+        eos: None,
+        truncation: Truncation::KeepTail,
+        echo: false,
+        stop: Some(Arc::new(StopSequences {
+            strings: vec!["END".into()],
+            ..Default::default()
+        })),
+        cost: gpt2.config.cost(),
+    };
+    let mut ctx = Context::new(false);
+    let mut model = ctx.init::<Gpt2>("gpt2", gpt2.map(RwRc::new));
+
+    // "é" 的两个字节分在两个词中，"EN" 是停止字符串的开头
+    let vocab: [&[u8]; 7] = [b"a", &[0xc3], &[0xa9], b"E", b"N", b"D", b"x"];
+    let decode = |t: u16| vocab[t as usize];
+    let script = [0, 1, 2, 3, 4, 6, 1, 2, 3, 4, 5, 0];
+    let mut i = 0;
+    let sample = |_: &[f32]| {
+        i += 1;
+        script[i - 1]
+    };
+
+    let mut stream = generate_stream(&mut ctx, "gpt2", &mut model, &[1], &config, sample, decode);
+    let chunks = (&mut stream).collect::<Vec<_>>();
+    assert_eq!(chunks, ["a", "é", "ENx", "é"]);
+    let result = stream.finish();
+    assert_eq!(result.text, chunks.concat());
+    assert_eq!(result.tokens, [0, 1, 2, 3, 4, 6, 1, 2]);
+    assert_eq!(result.finish_reason, FinishReason::Criteria);
+    assert!(model.cached_len().is_none())
+}
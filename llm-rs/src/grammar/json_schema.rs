@@ -0,0 +1,265 @@
+//! 把 JSON Schema 转为 GBNF 语法。
+//!
+//! 支持 `type`（含类型数组）、对象的 `properties`、数组的 `items`、`enum`、`const`、`anyOf` 和 `oneOf`。
+//! 对象的所有属性都按键的顺序输出，视为必需；`$ref` 等其他关键字报错。
+
+use super::{Grammar, GrammarError};
+use serde_json::Value;
+
+/// 通用 JSON 值的规则，Schema 没有约束的部分用它们匹配。空白最多一个，避免模型不停地输出空白。
+const PRIMITIVES: &str = r#"
+value   ::= object | array | string | number | boolean | null
+object  ::= "{" ws ( string ws ":" ws value ( ws "," ws string ws ":" ws value )* )? ws "}"
+array   ::= "[" ws ( value ( ws "," ws value )* )? ws "]"
+string  ::= "\"" ( [^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" hex hex hex hex ) )* "\""
+hex     ::= [0-9a-fA-F]
+number  ::= integer ( "." [0-9]+ )? ( [eE] [-+]? [0-9]+ )?
+integer ::= "-"? ( "0" | [1-9] [0-9]* )
+boolean ::= "true" | "false"
+null    ::= "null"
+ws      ::= [ \t\n]?
+"#;
+
+impl Grammar {
+    /// 只接受符合 `schema` 的 JSON 文本的语法。
+    pub fn from_json_schema(schema: &Value) -> Result<Self, GrammarError> {
+        Self::parse(&json_schema_to_gbnf(schema)?)
+    }
+}
+
+/// 把 JSON Schema 转为 GBNF 语法文本，根规则匹配符合 `schema` 的 JSON 值。
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String, GrammarError> {
+    let mut converter = Converter { rules: Vec::new() };
+    let root = converter.expr(schema)?;
+    let mut gbnf = format!("root ::= {root}\n");
+    for (name, body) in converter.rules {
+        gbnf += &format!("{name} ::= {body}\n")
+    }
+    gbnf += PRIMITIVES;
+    Ok(gbnf)
+}
+
+/// 匹配一次工具调用 `{"name": 工具名, "arguments": 参数}` 的语法文本，参数符合对应工具的 Schema。
+pub fn tool_call_gbnf<'a>(
+    tools: impl IntoIterator<Item = (&'a str, &'a Value)>,
+) -> Result<String, GrammarError> {
+    let mut converter = Converter { rules: Vec::new() };
+    let mut alts = Vec::new();
+    for (name, parameters) in tools {
+        let name = literal(&Value::String(name.into()));
+        let arguments = converter.expr(parameters)?;
+        alts.push(format!(
+            r#""{{" ws "\"name\"" ws ":" ws {name} ws "," ws "\"arguments\"" ws ":" ws {arguments} ws "}}""#
+        ))
+    }
+    if alts.is_empty() {
+        return Err(GrammarError::Schema("no tool to call".into()));
+    }
+    let mut gbnf = format!("root ::= {}\n", alts.join(" | "));
+    for (name, body) in converter.rules {
+        gbnf += &format!("{name} ::= {body}\n")
+    }
+    gbnf += PRIMITIVES;
+    Ok(gbnf)
+}
+
+struct Converter {
+    rules: Vec<(String, String)>,
+}
+
+impl Converter {
+    fn rule(&mut self, kind: &str, body: String) -> String {
+        let name = format!("{kind}-{}", self.rules.len());
+        self.rules.push((name.clone(), body));
+        name
+    }
+
+    fn expr(&mut self, schema: &Value) -> Result<String, GrammarError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".into()),
+            Value::Object(schema) => schema,
+            _ => return Err(GrammarError::Schema(format!("invalid schema {schema}"))),
+        };
+        if schema.contains_key("$ref") {
+            return Err(GrammarError::Schema("$ref is not supported".into()));
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let Value::Array(values) = values else {
+                return Err(GrammarError::Schema("enum must be an array".into()));
+            };
+            let alts = values.iter().map(literal).collect::<Vec<_>>();
+            return Ok(format!("( {} )", alts.join(" | ")));
+        }
+        if let Some(schemas) = schema.get("anyOf").or_else(|| schema.get("oneOf")) {
+            let Value::Array(schemas) = schemas else {
+                return Err(GrammarError::Schema("anyOf must be an array".into()));
+            };
+            let alts = schemas
+                .iter()
+                .map(|s| self.expr(s))
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(format!("( {} )", alts.join(" | ")));
+        }
+
+        let ty = match schema.get("type") {
+            Some(Value::String(ty)) => ty.as_str(),
+            Some(Value::Array(types)) => {
+                let mut alts = Vec::new();
+                for ty in types {
+                    let mut schema = schema.clone();
+                    schema.insert("type".into(), ty.clone());
+                    alts.push(self.expr(&Value::Object(schema))?)
+                }
+                return Ok(format!("( {} )", alts.join(" | ")));
+            }
+            Some(ty) => return Err(GrammarError::Schema(format!("invalid type {ty}"))),
+            None if schema.contains_key("properties") => "object",
+            None => return Ok("value".into()),
+        };
+        match ty {
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(ty.into()),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => self.expr(items)?,
+                    None => "value".into(),
+                };
+                let body = format!(r#""[" ws ( {item} ( ws "," ws {item} )* )? ws "]""#);
+                Ok(self.rule("array", body))
+            }
+            "object" => {
+                let Some(properties) = schema.get("properties") else {
+                    return Ok("object".into());
+                };
+                let Value::Object(properties) = properties else {
+                    return Err(GrammarError::Schema("properties must be an object".into()));
+                };
+                let mut fields = Vec::new();
+                for (key, value) in properties {
+                    let key = literal(&Value::String(key.clone()));
+                    fields.push(format!(r#"{key} ws ":" ws {}"#, self.expr(value)?))
+                }
+                let body = if fields.is_empty() {
+                    r#""{" ws "}""#.into()
+                } else {
+                    format!(r#""{{" ws {} ws "}}""#, fields.join(r#" ws "," ws "#))
+                };
+                Ok(self.rule("object", body))
+            }
+            _ => Err(GrammarError::Schema(format!("unknown type {ty}"))),
+        }
+    }
+}
+
+/// 匹配 `value` 序列化文本的 GBNF 字面量。
+fn literal(value: &Value) -> String {
+    let text = value.to_string();
+    let mut ans = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => ans += "\\\"",
+            '\\' => ans += "\\\\",
+            '\n' => ans += "\\n",
+            c => ans.push(c),
+        }
+    }
+    ans.push('"');
+    ans
+}
+
+#[test]
+fn test_json_schema() {
+    use crate::{
+        Context,
+        generate::{FinishReason, GenerationConfig, generate_cached},
+        llmc,
+        nn::gpt2::Gpt2,
+        truncate::Truncation,
+    };
+    use rand::{SeedableRng, rngs::StdRng};
+    use rw_rc::RwRc;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "ok": { "type": "boolean" },
+            "tag": { "enum": ["a", "b\"c"] },
+            "n": { "anyOf": [{ "type": "integer" }, { "const": null }] },
+            "xs": { "type": "array", "items": { "type": ["string", "null"] } },
+        },
+    });
+    let grammar = Arc::new(Grammar::from_json_schema(&schema).unwrap());
+    let matches = |text: &str| {
+        let mut m = super::Matcher::new(grammar.clone());
+        m.accept_bytes(text.as_bytes()) && m.is_complete()
+    };
+    assert!(matches(
+        r#"{"n":-12,"ok":true,"tag":"a","xs":["x\n",null]}"#
+    ));
+    assert!(matches(
+        r#"{ "n": null, "ok": false, "tag": "b\"c", "xs": [] }"#
+    ));
+    assert!(!matches(r#"{"n":1.5,"ok":true,"tag":"a","xs":[]}"#));
+    assert!(!matches(r#"{"n":1,"ok":true,"tag":"c","xs":[]}"#));
+    assert!(!matches(r#"{"ok":true,"tag":"a","xs":[]}"#));
+    assert!(matches!(
+        Grammar::from_json_schema(&json!({ "$ref": "#/x" })),
+        Err(GrammarError::Schema(_))
+    ));
+
+    // 用随机模型约束生成，输出总是符合 Schema 的 JSON
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "ok": { "type": "boolean" },
+            "tag": { "enum": ["x", "yz"] },
+        },
+    });
+    let grammar = Arc::new(Grammar::from_json_schema(&schema).unwrap());
+    let vocab = "\0{}[]:,\" \ntruefalsnxyzokag"
+        .bytes()
+        .map(|b| vec![b])
+        .chain(["true", "false", "\"ok\"", "\"tag\""].map(|s| s.as_bytes().to_vec()))
+        .collect::<Vec<_>>();
+    let gpt2 = llmc::Gpt2::random(
+        llmc::Gpt2Config::tiny(vocab.len()),
+        &mut StdRng::seed_from_u64(7),
+    );
+    let config = GenerationConfig {
+        n_ctx: gpt2.config.n_seq,
+        n_voc: gpt2.config.n_voc,
+        max_tokens: 48,
+        eos: Some(0),
+        truncation: Truncation::KeepTail,
+        echo: false,
+        stop: None,
+        cost: gpt2.config.cost(),
+    };
+    let mut ctx = Context::new(false);
+    let mut model = ctx.init::<Gpt2>("gpt2", gpt2.map(RwRc::new));
+    let decode = |t: u16| &vocab[t as usize][..];
+    for seed in 0..3 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut matcher = super::Matcher::new(grammar.clone());
+        let sample = |logits: &[f32]| {
+            matcher
+                .sample(logits, decode, Some(0), |logits| {
+                    // 在允许的词中随机选择
+                    let allowed = (0..logits.len())
+                        .filter(|&i| logits[i] != f32::NEG_INFINITY)
+                        .collect::<Vec<_>>();
+                    allowed[rand::Rng::random_range(&mut rng, 0..allowed.len())] as u16
+                })
+                .unwrap()
+        };
+        let result = generate_cached(&mut ctx, "gpt2", &mut model, &[1], &config, sample, decode);
+        assert_eq!(result.finish_reason, FinishReason::Stop);
+        let value = serde_json::from_str::<Value>(&result.text).unwrap();
+        assert!(value["ok"].is_boolean());
+        assert!(value["tag"] == "x" || value["tag"] == "yz");
+    }
+}
@@ -0,0 +1,419 @@
+//! 语法约束解码：按 GBNF 风格的语法或 JSON Schema 屏蔽 logits，只允许与语法相容的词。
+//!
+//! [`Grammar`] 是编译后的语法，[`Matcher`] 保存一个请求的解析状态：每步用 [`Matcher::mask`]
+//! 把会使解析失败的词的 logits 置为负无穷，采样后用 [`Matcher::accept`] 推进。
+//! 解析状态是下推自动机的一组栈，按 Unicode 码点匹配，跨越词边界的 UTF-8 字符留在缓冲中。
+
+mod json_schema;
+mod parse;
+
+pub use json_schema::{json_schema_to_gbnf, tool_call_gbnf};
+pub use parse::GrammarError;
+
+use std::{collections::HashSet, sync::Arc};
+
+/// 编译后的语法，每条规则是若干备选，每个备选是元素序列。
+#[derive(Clone, Debug)]
+pub struct Grammar {
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    root: usize,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+enum Element {
+    /// 匹配一个码点，`negated` 时匹配不在范围内的码点。
+    Char {
+        ranges: Vec<(u32, u32)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: u32) -> bool {
+        let Self::Char { ranges, negated } = self else {
+            unreachable!()
+        };
+        ranges.iter().any(|&(a, b)| a <= c && c <= b) != *negated
+    }
+
+    /// `[lo, hi]` 中是否有码点可以匹配。
+    fn may_match(&self, lo: u32, hi: u32) -> bool {
+        let Self::Char { ranges, negated } = self else {
+            unreachable!()
+        };
+        if !negated {
+            return ranges.iter().any(|&(a, b)| a <= hi && lo <= b);
+        }
+        // 范围没有完全覆盖 [lo, hi] 时可以匹配
+        let mut cur = lo;
+        while let Some(&(_, b)) = ranges.iter().find(|&&(a, b)| a <= cur && cur <= b) {
+            if b >= hi {
+                return false;
+            }
+            cur = b + 1
+        }
+        true
+    }
+}
+
+impl Grammar {
+    /// 规则名，匿名规则的名字由所在规则派生。
+    pub fn rule_names(&self) -> &[String] {
+        &self.names
+    }
+
+    fn element(&self, pos: Pos) -> Option<&Element> {
+        self.rules[pos.rule as usize][pos.alt as usize].get(pos.idx as usize)
+    }
+
+    /// 展开栈，直到栈顶是字符元素或栈为空（语法已完整匹配），结果加入 `out`。
+    ///
+    /// 左递归的语法在解析时已被拒绝，不消耗字符的展开不会无限加深栈。
+    fn expand(&self, stack: Stack, out: &mut Vec<Stack>) {
+        let mut seen = HashSet::new();
+        let mut work = vec![stack];
+        while let Some(mut stack) = work.pop() {
+            if !seen.insert(stack.clone()) {
+                continue;
+            }
+            let Some(&top) = stack.last() else {
+                out.push(stack);
+                continue;
+            };
+            match self.element(top) {
+                None => {
+                    stack.pop();
+                    work.push(stack)
+                }
+                Some(Element::Char { .. }) => out.push(stack),
+                Some(&Element::Rule(rule)) => {
+                    // 规则是序列的最后一个元素时不保留已结束的位置，右递归不会加深栈
+                    let next = Pos {
+                        idx: top.idx + 1,
+                        ..top
+                    };
+                    if self.element(next).is_some() {
+                        *stack.last_mut().unwrap() = next
+                    } else {
+                        stack.pop();
+                    }
+                    for alt in 0..self.rules[rule].len() {
+                        let mut stack = stack.clone();
+                        stack.push(Pos {
+                            rule: rule as _,
+                            alt: alt as _,
+                            idx: 0,
+                        });
+                        work.push(stack)
+                    }
+                }
+            }
+        }
+    }
+
+    /// 各栈匹配码点 `c` 后的栈。
+    fn step(&self, stacks: &[Stack], c: u32) -> Vec<Stack> {
+        let mut out = Vec::new();
+        for stack in stacks {
+            let Some(&top) = stack.last() else {
+                continue;
+            };
+            if self.element(top).unwrap().matches(c) {
+                let mut stack = stack.clone();
+                stack.last_mut().unwrap().idx += 1;
+                self.expand(stack, &mut out)
+            }
+        }
+        out.sort_unstable();
+        out.dedup();
+        out
+    }
+}
+
+/// 语法中的位置：第 `rule` 条规则第 `alt` 个备选的第 `idx` 个元素。
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+struct Pos {
+    rule: u32,
+    alt: u32,
+    idx: u32,
+}
+
+type Stack = Vec<Pos>;
+
+/// 一个请求的语法解析状态。
+#[derive(Clone, Debug)]
+pub struct Matcher {
+    grammar: Arc<Grammar>,
+    stacks: Vec<Stack>,
+    /// 尚未组成完整码点的 UTF-8 字节。
+    partial: Vec<u8>,
+}
+
+impl Matcher {
+    pub fn new(grammar: Arc<Grammar>) -> Self {
+        let mut stacks = Vec::new();
+        for alt in 0..grammar.rules[grammar.root].len() {
+            let pos = Pos {
+                rule: grammar.root as _,
+                alt: alt as _,
+                idx: 0,
+            };
+            grammar.expand(vec![pos], &mut stacks)
+        }
+        stacks.sort_unstable();
+        stacks.dedup();
+        Self {
+            grammar,
+            stacks,
+            partial: Vec::new(),
+        }
+    }
+
+    /// 已输入的文本是语法的一个完整匹配，此时可以结束生成。
+    pub fn is_complete(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().any(Vec::is_empty)
+    }
+
+    /// 已完整匹配且不能再接受任何字符。
+    pub fn is_finished(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().all(Vec::is_empty)
+    }
+
+    /// 在已输入的文本之后接上 `bytes` 是否仍可能匹配语法。
+    pub fn accepts(&self, bytes: &[u8]) -> bool {
+        self.advance(bytes).is_some()
+    }
+
+    /// 接上 `bytes`，不能匹配时返回 `false` 且状态不变。
+    pub fn accept_bytes(&mut self, bytes: &[u8]) -> bool {
+        match self.advance(bytes) {
+            Some((stacks, partial)) => {
+                self.stacks = stacks;
+                self.partial = partial;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 接受生成的词，结束词只在完整匹配时被接受。
+    pub fn accept<'a>(
+        &mut self,
+        token: u16,
+        decode: impl Fn(u16) -> &'a [u8],
+        eos: Option<u16>,
+    ) -> bool {
+        if Some(token) == eos {
+            self.is_complete()
+        } else {
+            self.accept_bytes(decode(token))
+        }
+    }
+
+    /// 把不能接在已输入的文本之后的词的 logits 置为负无穷。
+    ///
+    /// 结束词只在完整匹配时保留，解码为空的词总是被屏蔽。已经是负无穷的词不再检查。
+    pub fn mask<'a>(&self, logits: &mut [f32], decode: impl Fn(u16) -> &'a [u8], eos: Option<u16>) {
+        let complete = self.is_complete();
+        for (token, x) in logits.iter_mut().enumerate() {
+            if *x == f32::NEG_INFINITY {
+                continue;
+            }
+            let token = token as u16;
+            let allowed = if Some(token) == eos {
+                complete
+            } else {
+                let bytes = decode(token);
+                !bytes.is_empty() && self.accepts(bytes)
+            };
+            if !allowed {
+                *x = f32::NEG_INFINITY
+            }
+        }
+    }
+
+    /// 屏蔽后用 `sample` 选出下一个词并接受它，可以包装任何采样函数作为生成函数的 `sample` 参数。
+    ///
+    /// 没有词可以接上（例如 `logits` 已被其他限制屏蔽，与语法没有交集），或 `sample` 选中了被屏蔽的词时
+    /// 返回 `None` 且状态不变，调用者应结束生成。
+    pub fn sample<'a>(
+        &mut self,
+        logits: &[f32],
+        decode: impl Fn(u16) -> &'a [u8],
+        eos: Option<u16>,
+        mut sample: impl FnMut(&[f32]) -> u16,
+    ) -> Option<u16> {
+        let mut logits = logits.to_vec();
+        self.mask(&mut logits, &decode, eos);
+        if logits.iter().all(|&x| x == f32::NEG_INFINITY) {
+            return None;
+        }
+        let token = sample(&logits);
+        self.accept(token, decode, eos).then_some(token)
+    }
+
+    fn advance(&self, bytes: &[u8]) -> Option<(Vec<Stack>, Vec<u8>)> {
+        let mut stacks = None;
+        let mut partial = self.partial.clone();
+        for &b in bytes {
+            partial.push(b);
+            match utf8(&partial) {
+                Utf8::Complete(c) => {
+                    let next = self
+                        .grammar
+                        .step(stacks.as_deref().unwrap_or(&self.stacks), c);
+                    if next.is_empty() {
+                        return None;
+                    }
+                    stacks = Some(next);
+                    partial.clear()
+                }
+                Utf8::Incomplete => {}
+                Utf8::Invalid => return None,
+            }
+        }
+        let stacks = stacks.unwrap_or_else(|| self.stacks.clone());
+        // 不完整的字符需要有栈可能接受以这些字节开头的码点
+        if !partial.is_empty() {
+            let (lo, hi) = utf8_range(&partial)?;
+            let possible = stacks.iter().any(|stack| {
+                stack
+                    .last()
+                    .is_some_and(|&top| self.grammar.element(top).unwrap().may_match(lo, hi))
+            });
+            if !possible {
+                return None;
+            }
+        }
+        Some((stacks, partial))
+    }
+}
+
+enum Utf8 {
+    Complete(u32),
+    Incomplete,
+    Invalid,
+}
+
+fn utf8_len(first: u8) -> Option<usize> {
+    match first {
+        0x00..0x80 => Some(1),
+        0xc0..0xe0 => Some(2),
+        0xe0..0xf0 => Some(3),
+        0xf0..0xf8 => Some(4),
+        _ => None,
+    }
+}
+
+fn utf8(bytes: &[u8]) -> Utf8 {
+    let Some(len) = utf8_len(bytes[0]) else {
+        return Utf8::Invalid;
+    };
+    if bytes[1..].iter().any(|b| b & 0xc0 != 0x80) {
+        return Utf8::Invalid;
+    }
+    if bytes.len() < len {
+        return Utf8::Incomplete;
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(s) => Utf8::Complete(s.chars().next().unwrap() as _),
+        Err(_) => Utf8::Invalid,
+    }
+}
+
+/// 以不完整的 UTF-8 字节开头的合法码点范围，超长编码、代理区和超出 Unicode 的前缀返回 `None`。
+fn utf8_range(partial: &[u8]) -> Option<(u32, u32)> {
+    let len = utf8_len(partial[0]).unwrap();
+    let decode = |fill: u8| {
+        let mut bytes = partial.to_vec();
+        bytes.resize(len, fill);
+        let mask = [0x7f, 0x1f, 0x0f, 0x07][len - 1];
+        bytes[1..].iter().fold((bytes[0] & mask) as u32, |c, b| {
+            (c << 6) | (b & 0x3f) as u32
+        })
+    };
+    let lo = decode(0x80).max([0, 0x80, 0x800, 0x10000][len - 1]);
+    let hi = decode(0xbf).min(char::MAX as _);
+    let surrogate = 0xd800 <= lo && hi <= 0xdfff;
+    (lo <= hi && !surrogate).then_some((lo, hi))
+}
+
+#[test]
+fn test_matcher() {
+    let grammar = Grammar::parse(
+        r#"
+        # 问候语后接一个或多个数字，或者一个带引号的词
+        root   ::= greeting " " (digits | quoted)
+        greeting ::= "hi" | "héllo"
+        digits ::= [0-9]+
+        quoted ::= "\"" [^"]* "\""
+        "#,
+    )
+    .unwrap();
+    let grammar = Arc::new(grammar);
+    let matcher = Matcher::new(grammar.clone());
+    assert!(!matcher.is_complete());
+
+    let run = |text: &[u8]| {
+        let mut m = matcher.clone();
+        m.accept_bytes(text).then_some(m)
+    };
+    assert!(run(b"hi 123").unwrap().is_complete());
+    assert!(!run(b"hi ").unwrap().is_complete());
+    assert!(run(b"hi x").is_none());
+    assert!(run(b"hey").is_none());
+    assert!(run("héllo \"wörld\"".as_bytes()).unwrap().is_finished());
+    assert!(run("hi \"wörld".as_bytes()).is_some_and(|m| !m.is_complete()));
+
+    // 跨越词边界的多字节字符
+    let e = "é".as_bytes();
+    let mut m = run(b"h").unwrap();
+    assert!(m.accept_bytes(&e[..1]));
+    assert!(!m.accepts(b"l"));
+    assert!(m.accept_bytes(&e[1..]));
+    assert!(m.accept_bytes(b"llo 7"));
+    // 首字节 0xc3 开头的码点都不是 'i'
+    assert!(run(b"h").unwrap().accepts(&e[..1]));
+    assert!(!run(b"hi").unwrap().accepts(&e[..1]));
+    // 超长编码和代理区的前缀不能补全为合法字符
+    let quoted = run(b"hi \"").unwrap();
+    assert!(quoted.accepts(&[0xe0, 0xa0]) && quoted.accepts(&[0xed]));
+    for prefix in [&[0xc1][..], &[0xe0, 0x80], &[0xed, 0xa0], &[0xf4, 0x90]] {
+        assert!(!quoted.accepts(prefix), "{prefix:x?}")
+    }
+
+    // 屏蔽：词表中 0 是结束词
+    let vocab: [&[u8]; 6] = [b"", b"hi", b" 1", b"2", b"x", b"hi 3"];
+    let decode = |t: u16| vocab[t as usize];
+    let mut m = matcher.clone();
+    let mut logits = [0.; 6];
+    m.mask(&mut logits, decode, Some(0));
+    let allowed = |logits: &[f32]| {
+        (0..logits.len())
+            .filter(|&i| logits[i] != f32::NEG_INFINITY)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(allowed(&logits), [1, 5]);
+
+    let greedy = |logits: &[f32]| crate::op::topk::argmax(logits) as u16;
+    assert_eq!(
+        m.sample(&[0., 1., 0., 0., 0., 0.], decode, Some(0), greedy),
+        Some(1)
+    );
+    // 与语法没有交集时不采样
+    let masked = [0., f32::NEG_INFINITY, 0., 0., 0., f32::NEG_INFINITY];
+    assert_eq!(
+        matcher.clone().sample(&masked, decode, Some(0), greedy),
+        None
+    );
+    let mut logits = [0.; 6];
+    m.mask(&mut logits, decode, Some(0));
+    assert_eq!(allowed(&logits), [2]);
+    assert!(m.accept(2, decode, Some(0)));
+    let mut logits = [0.; 6];
+    m.mask(&mut logits, decode, Some(0));
+    assert_eq!(allowed(&logits), [0, 3]);
+    assert!(m.accept(0, decode, Some(0)))
+}
@@ -0,0 +1,445 @@
+//! GBNF 风格的语法文本解析。
+//!
+//! 规则写作 `name ::= 备选 | 备选`，`#` 开始注释，根规则名为 `root`。元素可以是字面量 `"..."`、
+//! 字符类 `[a-z]`、`[^"]`、任意字符 `.`、规则名和分组 `( ... )`，后缀 `*`、`+`、`?` 表示重复。
+//! 重复和分组编译为匿名规则，如 `x*` 变为 `R ::= x R |`。
+
+use super::{Element, Grammar};
+use std::{collections::HashMap, fmt};
+
+#[derive(Debug)]
+pub enum GrammarError {
+    Syntax {
+        line: usize,
+        message: String,
+    },
+    Undefined(String),
+    Redefined(String),
+    MissingRoot,
+    /// 规则不消耗字符就能展开到自身，如 `expr ::= expr "+" term`。
+    LeftRecursive(String),
+    /// JSON Schema 中不支持的部分。
+    Schema(String),
+}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Syntax { line, message } => write!(f, "syntax error at line {line}: {message}"),
+            Self::Undefined(name) => write!(f, "rule {name} is not defined"),
+            Self::Redefined(name) => write!(f, "rule {name} is defined twice"),
+            Self::MissingRoot => write!(f, "grammar has no root rule"),
+            Self::LeftRecursive(name) => write!(f, "rule {name} is left-recursive"),
+            Self::Schema(message) => write!(f, "unsupported json schema: {message}"),
+        }
+    }
+}
+
+impl Grammar {
+    pub fn parse(src: &str) -> Result<Self, GrammarError> {
+        let mut parser = Parser {
+            chars: src.chars().collect(),
+            pos: 0,
+            line: 1,
+            names: Vec::new(),
+            ids: HashMap::new(),
+            rules: Vec::new(),
+        };
+        loop {
+            parser.skip_space();
+            if parser.peek().is_none() {
+                break;
+            }
+            let name = parser.ident()?;
+            parser.skip_space();
+            parser.expect("::=")?;
+            let id = parser.id(&name);
+            if parser.rules[id].is_some() {
+                return Err(GrammarError::Redefined(name));
+            }
+            let alts = parser.alternatives(&name, false)?;
+            parser.rules[id] = Some(alts)
+        }
+
+        let Parser {
+            names, ids, rules, ..
+        } = parser;
+        let root = *ids.get("root").ok_or(GrammarError::MissingRoot)?;
+        let rules = rules
+            .into_iter()
+            .zip(&names)
+            .map(|(rule, name)| rule.ok_or_else(|| GrammarError::Undefined(name.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(i) = left_recursive(&rules) {
+            return Err(GrammarError::LeftRecursive(names[i].clone()));
+        }
+        Ok(Self { rules, names, root })
+    }
+}
+
+/// 找出一条左递归的规则，匹配器展开这样的规则时栈会无限加深。
+fn left_recursive(rules: &[Vec<Vec<Element>>]) -> Option<usize> {
+    // 能匹配空串的规则，反复传播直到不再变化
+    let mut nullable = vec![false; rules.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, alts) in rules.iter().enumerate() {
+            let empty = |alt: &Vec<Element>| {
+                alt.iter()
+                    .all(|e| matches!(*e, Element::Rule(r) if nullable[r]))
+            };
+            if !nullable[i] && alts.iter().any(empty) {
+                nullable[i] = true;
+                changed = true
+            }
+        }
+    }
+
+    // 每条规则不消耗字符就能展开到的规则
+    let edges = rules
+        .iter()
+        .map(|alts| {
+            let mut next = Vec::new();
+            for alt in alts {
+                for e in alt {
+                    let &Element::Rule(r) = e else { break };
+                    next.push(r);
+                    if !nullable[r] {
+                        break;
+                    }
+                }
+            }
+            next
+        })
+        .collect::<Vec<_>>();
+
+    // 深度优先搜索，回到搜索路径上的规则即有环；0 未访问，1 在路径上，2 已完成
+    fn visit(i: usize, edges: &[Vec<usize>], state: &mut [u8]) -> Option<usize> {
+        match state[i] {
+            1 => return Some(i),
+            2 => return None,
+            _ => {}
+        }
+        state[i] = 1;
+        for &j in &edges[i] {
+            if let Some(r) = visit(j, edges, state) {
+                return Some(r);
+            }
+        }
+        state[i] = 2;
+        None
+    }
+    let mut state = vec![0; rules.len()];
+    (0..rules.len()).find_map(|i| visit(i, &edges, &mut state))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    names: Vec<String>,
+    ids: HashMap<String, usize>,
+    /// 引用了但还没定义的规则为 `None`。
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1
+        }
+        Some(c)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, GrammarError> {
+        Err(GrammarError::Syntax {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    /// 跳过空白和注释，包括换行。
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.next();
+                }
+            } else if c.is_whitespace() {
+                self.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), GrammarError> {
+        for expected in s.chars() {
+            if self.next() != Some(expected) {
+                return self.error(format!("expected `{s}`"));
+            }
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String, GrammarError> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_ident) {
+            self.next();
+        }
+        if self.pos == start {
+            return self.error("expected rule name");
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// 当前位置是否是下一条规则的开始 `name ::=`。
+    fn at_rule_start(&self) -> bool {
+        let mut i = self.pos;
+        while self.chars.get(i).is_some_and(|&c| is_ident(c)) {
+            i += 1
+        }
+        if i == self.pos {
+            return false;
+        }
+        while self.chars.get(i).is_some_and(|c| c.is_whitespace()) {
+            i += 1
+        }
+        self.chars[i..].starts_with(&[':', ':', '='])
+    }
+
+    fn id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        self.add(name.into(), None)
+    }
+
+    fn add(&mut self, name: String, rule: Option<Vec<Vec<Element>>>) -> usize {
+        let id = self.rules.len();
+        self.ids.insert(name.clone(), id);
+        self.names.push(name);
+        self.rules.push(rule);
+        id
+    }
+
+    /// 为 `parent` 规则中的分组或重复新建匿名规则。
+    fn anonymous(&mut self, parent: &str, alts: Vec<Vec<Element>>) -> Element {
+        let name = format!("{parent}-{}", self.rules.len());
+        Element::Rule(self.add(name, Some(alts)))
+    }
+
+    fn alternatives(
+        &mut self,
+        rule: &str,
+        nested: bool,
+    ) -> Result<Vec<Vec<Element>>, GrammarError> {
+        let mut alts = vec![self.sequence(rule, nested)?];
+        while self.peek() == Some('|') {
+            self.next();
+            alts.push(self.sequence(rule, nested)?)
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self, rule: &str, nested: bool) -> Result<Vec<Element>, GrammarError> {
+        let mut seq = Vec::new();
+        loop {
+            self.skip_space();
+            let atom = match self.peek() {
+                None | Some('|') => break,
+                Some(')') if nested => break,
+                Some(_) if !nested && self.at_rule_start() => break,
+                Some('"') => {
+                    self.next();
+                    let mut atom = Vec::new();
+                    loop {
+                        match self.next() {
+                            None => return self.error("unterminated literal"),
+                            Some('"') => break,
+                            Some('\\') => {
+                                let c = self.escape()?;
+                                atom.push(single(c))
+                            }
+                            Some(c) => atom.push(single(c as _)),
+                        }
+                    }
+                    atom
+                }
+                Some('[') => {
+                    self.next();
+                    vec![self.class()?]
+                }
+                Some('.') => {
+                    self.next();
+                    vec![Element::Char {
+                        ranges: Vec::new(),
+                        negated: true,
+                    }]
+                }
+                Some('(') => {
+                    self.next();
+                    let alts = self.alternatives(rule, true)?;
+                    self.skip_space();
+                    self.expect(")")?;
+                    vec![self.anonymous(rule, alts)]
+                }
+                Some(c) if is_ident(c) => {
+                    let name = self.ident()?;
+                    vec![Element::Rule(self.id(&name))]
+                }
+                Some(c) => return self.error(format!("unexpected `{c}`")),
+            };
+
+            let repeat = match self.peek() {
+                Some(c @ ('*' | '+' | '?')) => {
+                    self.next();
+                    c
+                }
+                _ => {
+                    seq.extend(atom);
+                    continue;
+                }
+            };
+            let item = if atom.len() == 1 {
+                atom.into_iter().next().unwrap()
+            } else {
+                self.anonymous(rule, vec![atom])
+            };
+            // 先建规则再填备选，递归引用自身
+            let Element::Rule(id) = self.anonymous(rule, Vec::new()) else {
+                unreachable!()
+            };
+            let this = Element::Rule(id);
+            self.rules[id] = Some(match repeat {
+                '*' => vec![vec![item, this.clone()], vec![]],
+                '+' => vec![vec![item.clone(), this.clone()], vec![item]],
+                '?' => vec![vec![item], vec![]],
+                _ => unreachable!(),
+            });
+            seq.push(this)
+        }
+        Ok(seq)
+    }
+
+    /// 解析 `[` 之后的字符类。
+    fn class(&mut self) -> Result<Element, GrammarError> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.next();
+        }
+        let mut ranges = Vec::new();
+        loop {
+            let lo = match self.next() {
+                None => return self.error("unterminated character class"),
+                Some(']') => break,
+                Some('\\') => self.escape()?,
+                Some(c) => c as u32,
+            };
+            let hi = if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.next();
+                match self.next() {
+                    None => return self.error("unterminated character class"),
+                    Some('\\') => self.escape()?,
+                    Some(c) => c as u32,
+                }
+            } else {
+                lo
+            };
+            if hi < lo {
+                return self.error("invalid character range");
+            }
+            ranges.push((lo, hi))
+        }
+        Ok(Element::Char { ranges, negated })
+    }
+
+    /// 解析 `\` 之后的转义字符。
+    fn escape(&mut self) -> Result<u32, GrammarError> {
+        let hex = |p: &mut Self, n: usize| {
+            let mut c = 0;
+            for _ in 0..n {
+                match p.next().and_then(|d| d.to_digit(16)) {
+                    Some(d) => c = c * 16 + d,
+                    None => return p.error("invalid hex escape"),
+                }
+            }
+            Ok(c)
+        };
+        match self.next() {
+            Some('n') => Ok('\n' as _),
+            Some('r') => Ok('\r' as _),
+            Some('t') => Ok('\t' as _),
+            Some('x') => hex(self, 2),
+            Some('u') => hex(self, 4),
+            Some('U') => hex(self, 8),
+            Some(c @ ('\\' | '"' | '\'' | '[' | ']' | '-' | '^' | '/')) => Ok(c as _),
+            _ => self.error("invalid escape"),
+        }
+    }
+}
+
+fn single(c: u32) -> Element {
+    Element::Char {
+        ranges: vec![(c, c)],
+        negated: false,
+    }
+}
+
+#[test]
+fn test_parse() {
+    let grammar = Grammar::parse(
+        r#"root ::= item ("," item)*
+           item ::= [a-c\x41] | "é"? "z"
+        "#,
+    )
+    .unwrap();
+    let item = grammar.names.iter().position(|n| n == "item").unwrap();
+    assert_eq!(grammar.root, 0);
+    assert_eq!(grammar.rules[item].len(), 2);
+    assert_eq!(
+        grammar.rules[item][0],
+        [Element::Char {
+            ranges: vec![('a' as _, 'c' as _), (0x41, 0x41)],
+            negated: false,
+        }]
+    );
+    assert!(grammar.names.iter().any(|n| n.starts_with("root-")));
+
+    let err = |src: &str| Grammar::parse(src).unwrap_err().to_string();
+    assert_eq!(err("root ::= a"), "rule a is not defined");
+    assert_eq!(err("a ::= \"x\""), "grammar has no root rule");
+    assert_eq!(
+        err("root ::= \"x\"\nroot ::= \"y\""),
+        "rule root is defined twice"
+    );
+    assert_eq!(
+        err("root ::= \"x\"\n  ::= \"y\""),
+        "syntax error at line 2: unexpected `:`"
+    );
+    assert_eq!(
+        err("root ::= [a-"),
+        "syntax error at line 1: unterminated character class"
+    );
+    assert_eq!(
+        err("root ::= expr\nexpr ::= expr \"+\" [0-9] | [0-9]"),
+        "rule expr is left-recursive"
+    );
+    // 可空的前缀之后的自引用也是左递归，非首位的自引用不是
+    assert_eq!(
+        err("root ::= \" \"* root \"x\" | \"y\""),
+        "rule root is left-recursive"
+    );
+    assert!(Grammar::parse("root ::= \"(\" root \")\" | \"x\"").is_ok());
+}
@@ -0,0 +1,210 @@
+//! 只追加的训练日志。
+//!
+//! 每行一条 JSON 记录，包含步数、损失、数据位置和此步保存的检查点。崩溃后用 [`recover`]
+//! 找到最后一个文件完好的检查点及其对应的数据位置，从那里继续训练。
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// 一条日志。
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub step: usize,
+    pub loss: f32,
+    /// 此步之后数据加载器的位置，见 [`crate::llmc::DataLoader::cursor`]。
+    pub cursor: usize,
+    /// 此步保存的检查点。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint: Option<Checkpoint>,
+}
+
+/// 检查点文件及其内容的哈希。
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub path: PathBuf,
+    pub hash: u64,
+}
+
+impl Checkpoint {
+    /// 记录已经写完的检查点文件。
+    pub fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let hash = hash_file(&path)?;
+        Ok(Self { path, hash })
+    }
+
+    /// 文件存在且内容与记录一致。
+    pub fn verify(&self) -> bool {
+        hash_file(&self.path).is_ok_and(|hash| hash == self.hash)
+    }
+}
+
+/// 日志写入器。
+pub struct Journal {
+    file: File,
+    sync_every: usize,
+    unsynced: usize,
+}
+
+impl Journal {
+    /// 以追加方式打开日志，每 `sync_every` 条同步一次到磁盘。
+    ///
+    /// 崩溃时写了一半的最后一行在打开时截掉，否则新的日志会接在它后面，使它不再是最后一行。
+    pub fn open(path: impl AsRef<Path>, sync_every: usize) -> io::Result<Self> {
+        assert!(sync_every > 0);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let len = data.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        if len < data.len() {
+            file.set_len(len as _)?;
+            file.sync_data()?
+        }
+        Ok(Self {
+            file,
+            sync_every,
+            unsynced: 0,
+        })
+    }
+
+    /// 追加一条日志，带检查点的日志立即同步。
+    pub fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.unsynced += 1;
+        if entry.checkpoint.is_some() || self.unsynced >= self.sync_every {
+            self.sync()?
+        }
+        Ok(())
+    }
+
+    /// 把已写入的日志同步到磁盘。
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.unsynced = 0;
+        self.file.sync_data()
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+/// 读出全部日志。崩溃时写了一半的最后一行被忽略。
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<Entry>> {
+    let mut ans = Vec::new();
+    let mut lines = BufReader::new(File::open(path)?).lines().peekable();
+    while let Some(line) = lines.next() {
+        match serde_json::from_str(&line?) {
+            Ok(entry) => ans.push(entry),
+            Err(_) if lines.peek().is_none() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(ans)
+}
+
+/// 找到最后一条检查点文件完好的日志，从它的 `step` 和 `cursor` 继续训练。
+pub fn recover(path: impl AsRef<Path>) -> io::Result<Option<Entry>> {
+    Ok(read(path)?
+        .into_iter()
+        .rev()
+        .find(|entry| entry.checkpoint.as_ref().is_some_and(Checkpoint::verify)))
+}
+
+/// 文件内容的 64 位 FNV-1a 哈希，不随编译器版本变化。
+pub fn hash_file(path: impl AsRef<Path>) -> io::Result<u64> {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    let data = std::fs::read(path)?;
+    Ok(data
+        .iter()
+        .fold(OFFSET, |hash, &b| (hash ^ b as u64).wrapping_mul(PRIME)))
+}
+
+#[test]
+fn test_recover() {
+    let dir = std::env::temp_dir().join(format!("llm-rs-journal-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("journal.jsonl");
+    let _ = std::fs::remove_file(&log);
+
+    let entry = |step, checkpoint| Entry {
+        step,
+        loss: 1. / step as f32,
+        cursor: step * 2,
+        checkpoint,
+    };
+    let ckpt = |step: usize, data: &[u8]| {
+        let path = dir.join(format!("step{step}.bin"));
+        std::fs::write(&path, data).unwrap();
+        Some(Checkpoint::new(path).unwrap())
+    };
+
+    {
+        let mut journal = Journal::open(&log, 4).unwrap();
+        journal.append(&entry(1, None)).unwrap();
+        journal.append(&entry(2, ckpt(2, b"two"))).unwrap();
+        journal.append(&entry(3, None)).unwrap();
+        journal.append(&entry(4, ckpt(4, b"four"))).unwrap();
+    }
+    // 模拟崩溃：最后一行只写了一半，最新的检查点也没有写完
+    OpenOptions::new()
+        .append(true)
+        .open(&log)
+        .unwrap()
+        .write_all(br#"{"step":5,"lo"#)
+        .unwrap();
+    std::fs::write(dir.join("step4.bin"), b"fo").unwrap();
+
+    assert_eq!(read(&log).unwrap().len(), 4);
+    assert_eq!(recover(&log).unwrap(), Some(entry(2, ckpt(2, b"two"))));
+    std::fs::remove_dir_all(&dir).unwrap()
+}
+
+#[test]
+fn test_resume_twice() {
+    let dir = std::env::temp_dir().join(format!("llm-rs-journal-resume-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("journal.jsonl");
+    let _ = std::fs::remove_file(&log);
+
+    let entry = |step: usize| Entry {
+        step,
+        loss: 1. / step as f32,
+        cursor: step * 2,
+        checkpoint: None,
+    };
+    let crash = |partial: &[u8]| {
+        OpenOptions::new()
+            .append(true)
+            .open(&log)
+            .unwrap()
+            .write_all(partial)
+            .unwrap()
+    };
+
+    Journal::open(&log, 1).unwrap().append(&entry(1)).unwrap();
+    // 两次崩溃后恢复，每次都在写一半的行后面继续追加
+    for (step, partial) in [(2, br#"{"step":2,"lo"#), (3, br#"{"step":3,"cu"#)] {
+        crash(partial);
+        Journal::open(&log, 1)
+            .unwrap()
+            .append(&entry(step))
+            .unwrap();
+        let entries = read(&log).unwrap();
+        assert_eq!(entries, (1..=step).map(entry).collect::<Vec<_>>())
+    }
+    std::fs::remove_dir_all(&dir).unwrap()
+}
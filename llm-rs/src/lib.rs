@@ -0,0 +1,94 @@
+mod blob;
+pub mod bpe;
+mod context;
+pub mod delta;
+pub mod dist;
+#[cfg(feature = "embedded")]
+pub mod embedded;
+pub mod engine;
+pub mod eval;
+pub mod generate;
+pub mod grammar;
+pub mod journal;
+pub mod llama;
+pub mod llmc;
+pub mod log;
+pub mod metadata;
+pub mod nn;
+pub mod op;
+pub mod optimizer;
+pub mod pipeline;
+pub mod prefetch;
+pub mod prune;
+pub mod quant;
+pub mod repro;
+pub mod reward;
+pub mod sampler;
+pub mod scheduler;
+pub mod seq_warmup;
+pub mod session;
+pub mod snapshot;
+pub mod speculative;
+pub mod stop;
+pub mod synthetic;
+#[cfg(feature = "log")]
+pub mod trace;
+pub mod train;
+pub mod truncate;
+
+use std::{hash::Hash, rc::Weak};
+
+pub use blob::Blob;
+pub use context::{Context, DryRun, Graph, ModuleInfo};
+
+pub type Tensor<T> = tensor::Tensor<T, 4>;
+
+struct HashWeak<T>(Weak<T>);
+
+impl<T> PartialEq for HashWeak<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Weak::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<T> Eq for HashWeak<T> {}
+
+impl<T> Hash for HashWeak<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.as_ptr().hash(state)
+    }
+}
+
+mod macros {
+    macro_rules! dims {
+        ($pat:pat = $tensor:expr) => {
+            let &$pat = &*$tensor.shape() else {
+                panic!("Ndim mismatch ( = {})", $tensor.shape().len())
+            };
+        };
+    }
+
+    macro_rules! strides {
+        ($pat:pat = $tensor:expr) => {
+            let &$pat = &*$tensor.layout().strides() else {
+                panic!("Ndim mismatch ( = {})", $tensor.layout().strides().len())
+            };
+        };
+    }
+
+    macro_rules! destruct {
+        ([$( $name:ident ),+] = $iter:expr) => {
+            let mut iter = $iter.into_iter();
+            $( let $name = iter.next().unwrap(); )+
+            assert!(iter.next().is_none());
+        };
+    }
+
+    macro_rules! clone_tensor {
+        ($( $tensor:ident )+) => {
+            $( let $tensor = $tensor.cloned(); )+
+        };
+    }
+
+    pub(super) use {clone_tensor, destruct, dims, strides};
+}
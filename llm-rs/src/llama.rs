@@ -0,0 +1,268 @@
+//! LLaMA 结构的模型权重：RMSNorm、旋转位置编码、SwiGLU 前馈和分组查询注意力。
+
+use crate::{Blob, Tensor, generate::Cost};
+use digit_layout::types;
+use rand::Rng;
+use rw_rc::RwRc;
+use std::f32::consts::PI;
+
+/// LLaMA 模型配置
+#[derive(Clone, Debug)]
+pub struct LlamaConfig {
+    pub n_seq: usize, // 最大序列长度，例如 4096
+    pub n_voc: usize, // 词表大小，例如 32000
+    pub nblk: usize,  // 层数，例如 32
+    pub nh: usize,    // 查询头数，例如 32
+    pub nkvh: usize,  // K、V 头数，等于 nh 时为多头注意力，为 1 时为多查询注意力
+    pub d: usize,     // 通道数，例如 4096
+    pub d_ffn: usize, // 前馈网络的中间维度，例如 11008
+    pub theta: f32,   // 旋转位置编码的底数，例如 10000
+    pub epsilon: f32, // RMSNorm 的 epsilon，例如 1e-5
+}
+
+impl LlamaConfig {
+    /// 用于测试和演示的小模型：2 层、64 维、4 个查询头共享 2 组 K、V、最长 64 词。
+    pub fn tiny(n_voc: usize) -> Self {
+        Self {
+            n_seq: 64,
+            n_voc,
+            nblk: 2,
+            nh: 4,
+            nkvh: 2,
+            d: 64,
+            d_ffn: 172,
+            theta: 1e4,
+            epsilon: 1e-5,
+        }
+    }
+
+    /// 每个头的维度。
+    pub fn dh(&self) -> usize {
+        self.d / self.nh
+    }
+
+    /// 前向的计算量，参见 [`crate::llmc::Gpt2Config::cost`]。
+    pub fn cost(&self) -> Cost {
+        let &Self {
+            n_voc,
+            nblk,
+            nkvh,
+            d,
+            d_ffn,
+            ..
+        } = self;
+        let dkv = (nkvh * self.dh()) as u64;
+        let (nblk, d, d_ffn, n_voc) = (nblk as u64, d as u64, d_ffn as u64, n_voc as u64);
+        Cost {
+            per_token: 2 * (nblk * (2 * d * d + 2 * d * dkv + 3 * d * d_ffn) + d * n_voc),
+            per_pair: nblk * 4 * d,
+        }
+    }
+
+    /// 按名字计算张量形状，名字与 [`Llama::from_fn`] 一致。
+    pub fn shape(&self, name: &str) -> Vec<usize> {
+        let &Self {
+            n_voc,
+            nkvh,
+            d,
+            d_ffn,
+            ..
+        } = self;
+        let dkv = nkvh * self.dh();
+        match name {
+            "wte" | "lm_head.w" => vec![n_voc, d],
+            _ => match name.rsplitn(3, '.').collect::<Vec<_>>()[..] {
+                ["w", "attn_q" | "attn_o", _] => vec![d, d],
+                ["w", "attn_k" | "attn_v", _] => vec![dkv, d],
+                ["w", "ffn_gate" | "ffn_up", _] => vec![d_ffn, d],
+                ["w", "ffn_down", _] => vec![d, d_ffn],
+                _ => vec![d],
+            },
+        }
+    }
+}
+
+/// HF transformers 检查点中对应的张量名，用于从 safetensors 加载。
+///
+/// HF 的 Q、K 投影已经按前后两半配对的旋转方式排列，与 [`crate::op::rope`] 一致。
+pub fn hf_name(name: &str) -> String {
+    match name {
+        "wte" => "model.embed_tokens.weight".into(),
+        "output_norm.w" => "model.norm.weight".into(),
+        "lm_head.w" => "lm_head.weight".into(),
+        _ => {
+            let [blk, i, module, "w"] = name.split('.').collect::<Vec<_>>()[..] else {
+                panic!("unknown tensor {name}")
+            };
+            assert_eq!(blk, "blk");
+            let module = match module {
+                "attn_norm" => "input_layernorm",
+                "attn_q" => "self_attn.q_proj",
+                "attn_k" => "self_attn.k_proj",
+                "attn_v" => "self_attn.v_proj",
+                "attn_o" => "self_attn.o_proj",
+                "ffn_norm" => "post_attention_layernorm",
+                "ffn_gate" => "mlp.gate_proj",
+                "ffn_up" => "mlp.up_proj",
+                "ffn_down" => "mlp.down_proj",
+                _ => panic!("unknown tensor {name}"),
+            };
+            format!("model.layers.{i}.{module}.weight")
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Llama<T> {
+    pub config: LlamaConfig,
+    pub wte: Tensor<T>,
+    pub blks: Box<[LlamaBlk<T>]>,
+    pub output_norm: Tensor<T>,
+    pub lm_head: Tensor<T>,
+}
+
+/// 各投影都没有偏置，归一化只有缩放参数。
+#[derive(Clone)]
+pub struct LlamaBlk<T> {
+    pub attn_norm: Tensor<T>,
+    pub attn_q: Tensor<T>,
+    pub attn_k: Tensor<T>,
+    pub attn_v: Tensor<T>,
+    pub attn_o: Tensor<T>,
+    pub ffn_norm: Tensor<T>,
+    pub ffn_gate: Tensor<T>,
+    pub ffn_up: Tensor<T>,
+    pub ffn_down: Tensor<T>,
+}
+
+macro_rules! blk_tensors {
+    ($mac:ident) => {
+        $mac! {
+            attn_norm
+            attn_q
+            attn_k
+            attn_v
+            attn_o
+            ffn_norm
+            ffn_gate
+            ffn_up
+            ffn_down
+        }
+    };
+}
+
+impl<T> Llama<T> {
+    /// 按名字构造各个张量，名字形如 `wte`、`blk.0.attn_q.w`、`lm_head.w`。
+    pub fn from_fn(config: LlamaConfig, mut f: impl FnMut(&str) -> Tensor<T>) -> Self {
+        Self {
+            wte: f("wte"),
+            blks: (0..config.nblk)
+                .map(|i| LlamaBlk::from_fn(&format!("blk.{i}"), &mut f))
+                .collect(),
+            output_norm: f("output_norm.w"),
+            lm_head: f("lm_head.w"),
+            config,
+        }
+    }
+
+    /// 按名字遍历各个张量。
+    pub fn for_each<'a>(&'a self, mut f: impl FnMut(&str, &'a Tensor<T>)) {
+        f("wte", &self.wte);
+        for (i, blk) in self.blks.iter().enumerate() {
+            blk.for_each(&format!("blk.{i}"), &mut f)
+        }
+        f("output_norm.w", &self.output_norm);
+        f("lm_head.w", &self.lm_head)
+    }
+
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> Llama<U> {
+        Llama {
+            config: self.config,
+            wte: self.wte.map(&mut f),
+            blks: self.blks.into_iter().map(|blk| blk.map(&mut f)).collect(),
+            output_norm: self.output_norm.map(&mut f),
+            lm_head: self.lm_head.map(&mut f),
+        }
+    }
+}
+
+impl Llama<RwRc<Blob>> {
+    /// 复制出独立的模型，参见 [`crate::llmc::Gpt2::fork`]。
+    pub fn fork(&self) -> Self {
+        self.clone().map(|b| RwRc::new(b.read().clone()))
+    }
+}
+
+impl Llama<Blob> {
+    /// 随机初始化：权重服从 N(0, 0.02)，残差输出投影再缩小 `sqrt(2 * nblk)` 倍，归一化的缩放为 1。
+    pub fn random(config: LlamaConfig, rng: &mut impl Rng) -> Self {
+        let residual_std = 0.02 / ((2 * config.nblk) as f32).sqrt();
+        let config_ = config.clone();
+        Self::from_fn(config, |name| {
+            let mut tensor = Tensor::new(types::F32, &config_.shape(name)).map(Blob::new_zeroed);
+            let ([], data, []) = (unsafe { tensor.get_mut().align_to_mut::<f32>() }) else {
+                unreachable!()
+            };
+            if name.ends_with("norm.w") {
+                data.fill(1.)
+            } else {
+                let std = if name.ends_with("attn_o.w") || name.ends_with("ffn_down.w") {
+                    residual_std
+                } else {
+                    0.02
+                };
+                // Box-Muller
+                for x in data {
+                    let u = 1. - rng.random::<f32>();
+                    let v = rng.random::<f32>();
+                    *x = std * (-2. * u.ln()).sqrt() * (2. * PI * v).cos()
+                }
+            }
+            tensor
+        })
+    }
+}
+
+impl<T> LlamaBlk<T> {
+    fn from_fn(prefix: &str, mut f: impl FnMut(&str) -> Tensor<T>) -> Self {
+        macro_rules! build {
+            ($( $id:ident )+) => {
+                LlamaBlk { $( $id: f(&format!("{prefix}.{}.w", stringify!($id))), )+ }
+            };
+        }
+        blk_tensors!(build)
+    }
+
+    fn for_each<'a>(&'a self, prefix: &str, mut f: impl FnMut(&str, &'a Tensor<T>)) {
+        macro_rules! visit {
+            ($( $id:ident )+) => {
+                { $( f(&format!("{prefix}.{}.w", stringify!($id)), &self.$id); )+ }
+            };
+        }
+        blk_tensors!(visit)
+    }
+
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> LlamaBlk<U> {
+        macro_rules! map {
+            ($( $id:ident )+) => {
+                LlamaBlk { $( $id: self.$id.map(&mut f), )+ }
+            };
+        }
+        blk_tensors!(map)
+    }
+}
+
+#[test]
+fn test_hf_name() {
+    let config = LlamaConfig::tiny(100);
+    let mut names = Vec::new();
+    Llama::from_fn(config, |name| {
+        names.push(hf_name(name));
+        Tensor::new(types::F32, &[1])
+    });
+    assert_eq!(names.len(), 1 + 2 * 9 + 2);
+    assert_eq!(names[0], "model.embed_tokens.weight");
+    assert_eq!(names[2], "model.layers.0.self_attn.q_proj.weight");
+    assert_eq!(names[18], "model.layers.1.mlp.down_proj.weight");
+    assert_eq!(names[19], "model.norm.weight");
+}
@@ -2,7 +2,7 @@ use super::BinHeader;
 use globset::Glob;
 use memmap2::Mmap;
 use rand::seq::SliceRandom;
-use std::{fs::File, path::Path};
+use std::{fs::File, ops::Deref, path::Path};
 
 pub struct DataLoader {
     shards: Vec<Shard>,
@@ -13,11 +13,35 @@ pub struct DataLoader {
 }
 
 struct Shard {
-    tokens: Vec<u16>,
+    tokens: Tokens,
     indices: Vec<usize>,
     sample_idx: usize,
 }
 
+/// 分片的词序列，文件中的分片直接映射，不复制到内存。
+enum Tokens {
+    Owned(Vec<u16>),
+    Mapped(Mmap),
+}
+
+impl Deref for Tokens {
+    type Target = [u16];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Owned(tokens) => tokens,
+            Self::Mapped(mmap) => {
+                let ([], tokens, []) =
+                    (unsafe { mmap[size_of::<BinHeader>()..].align_to::<u16>() })
+                else {
+                    unreachable!()
+                };
+                tokens
+            }
+        }
+    }
+}
+
 impl DataLoader {
     pub fn new(
         path: impl AsRef<Path>,
@@ -32,10 +56,11 @@ impl DataLoader {
             if glob.is_match(path) {
                 let tokens = load_shard(path);
                 let samples = tokens.len() / (batch_size * seq_len);
-                println!(
-                    "loaded {} tokens ({samples} samples) from {}",
-                    tokens.len(),
-                    path.display()
+                tracing::info!(
+                    tokens = tokens.len(),
+                    samples,
+                    path = %path.display(),
+                    "loaded shard"
                 );
                 shards.push(Shard {
                     tokens,
@@ -54,6 +79,63 @@ impl DataLoader {
         }
     }
 
+    /// 从内存中的词序列构造，用于合成数据。
+    pub fn from_tokens(
+        tokens: Vec<u16>,
+        batch_size: usize,
+        seq_len: usize,
+        should_shuffle: bool,
+    ) -> Self {
+        let samples = n_samples(tokens.len(), batch_size * seq_len);
+        Self {
+            shards: vec![Shard {
+                tokens: Tokens::Owned(tokens),
+                indices: (0..samples).collect(),
+                sample_idx: 0,
+            }],
+            batch_size,
+            seq_len,
+            should_shuffle,
+            rng: rand::rng(),
+        }
+    }
+
+    /// 每批数据的形状 `[batch_size, seq_len]`。
+    pub fn shape(&self) -> [usize; 2] {
+        [self.batch_size, self.seq_len]
+    }
+
+    /// 已经加载的批数在当前轮中的位置，配合 [`Self::seek`] 从中断处继续。
+    pub fn cursor(&self) -> usize {
+        self.shards[0].sample_idx
+    }
+
+    /// 跳到 [`Self::cursor`] 返回的位置。
+    pub fn seek(&mut self, cursor: usize) {
+        let shard = &mut self.shards[0];
+        assert!(cursor < shard.indices.len());
+        shard.sample_idx = cursor
+    }
+
+    /// 改变之后每批的形状，从词序列中当前位置之后的第一个完整批继续读取。
+    ///
+    /// 形状改变后 [`Self::cursor`] 以新的批大小计数。
+    pub fn reshape(&mut self, batch_size: usize, seq_len: usize) {
+        let n_old = self.batch_size * self.seq_len;
+        let n_new = batch_size * seq_len;
+        for shard in &mut self.shards {
+            let samples = n_samples(shard.tokens.len(), n_new);
+            shard.indices = (0..samples).collect();
+            shard.sample_idx = (shard.sample_idx * n_old).div_ceil(n_new);
+            if shard.sample_idx >= samples {
+                shard.sample_idx = 0
+            }
+        }
+        self.batch_size = batch_size;
+        self.seq_len = seq_len;
+        self.rand()
+    }
+
     pub fn rand(&mut self) {
         if self.should_shuffle {
             for Shard { indices, .. } in &mut self.shards {
@@ -74,20 +156,30 @@ impl DataLoader {
     }
 }
 
-fn load_shard(path: impl AsRef<Path>) -> Vec<u16> {
+/// 词序列能提供的完整批数，目标右移一位，每批需要 `n_tok + 1` 个词。
+fn n_samples(len: usize, n_tok: usize) -> usize {
+    assert!(n_tok > 0, "batch size and sequence length must be positive");
+    let samples = len.saturating_sub(1) / n_tok;
+    assert!(
+        samples > 0,
+        "{len} tokens are not enough for a batch of {n_tok} tokens, at least {} are needed",
+        n_tok + 1
+    );
+    samples
+}
+
+fn load_shard(path: impl AsRef<Path>) -> Tokens {
     let file = File::open(path).unwrap();
     let mmap = unsafe { Mmap::map(&file).unwrap() };
-    let (header, body) = mmap.split_at(size_of::<BinHeader>()); // 读取 GPT-2 模型的头部信息
-    let header = unsafe { header.as_ptr().cast::<BinHeader>().as_ref().unwrap() };
+    let header = unsafe { mmap.as_ptr().cast::<BinHeader>().as_ref().unwrap() }; // 读取数据集的头部信息
     if header.0[0] != 20240520 || header.0[1] != 1 {
         panic!("header is not correct ");
     }
-    let ntok = header.0[2] as usize;
-    let ([], tokens, []) = (unsafe { body.align_to::<u16>() }) else {
-        unreachable!()
-    };
+    // 词数以 u32 记录，超过 2^31 个词（4 GiB）的分片不会变成负数
+    let ntok = header.0[2] as u32 as usize;
+    let tokens = Tokens::Mapped(mmap);
     assert_eq!(tokens.len(), ntok);
-    tokens.to_vec()
+    tokens
 }
 
 fn for_files(path: impl AsRef<Path>, f: &mut impl FnMut(&Path)) {
@@ -109,8 +201,64 @@ fn for_files(path: impl AsRef<Path>, f: &mut impl FnMut(&Path)) {
 fn test_glob() {
     let matcher = globset::Glob::new("./src/*.rs").unwrap().compile_matcher();
     for_files(".", &mut |file| {
-        if matcher.is_match(file.to_str().unwrap()) {
+        if matcher.is_match(file) {
             println!("{}", file.display());
         }
     })
 }
+
+#[test]
+fn test_non_utf8_path() {
+    use std::{ffi::OsStr, fs};
+
+    // 非 UTF-8 的目录名，Windows 上以非 ASCII 字符代替
+    #[cfg(unix)]
+    let name = std::os::unix::ffi::OsStrExt::from_bytes(b"llm-rs-\xff");
+    #[cfg(not(unix))]
+    let name = OsStr::new("llm-rs-数据");
+    let name: &OsStr = name;
+    let dir = std::env::temp_dir()
+        .join(name)
+        .join(std::process::id().to_string());
+    fs::create_dir_all(&dir).unwrap();
+
+    let ntok = 20;
+    let mut header = [0i32; 256];
+    header[..3].copy_from_slice(&[20240520, 1, ntok as _]);
+    let bytes = header
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .chain((0..ntok as u16).flat_map(|x| x.to_le_bytes()))
+        .collect::<Vec<_>>();
+    fs::write(dir.join("train.bin"), bytes).unwrap();
+
+    let mut loader = DataLoader::new(&dir, "*/train.bin", 2, 3, false);
+    let [x, y] = loader.load();
+    assert_eq!(x, [0, 1, 2, 3, 4, 5]);
+    assert_eq!(y, [1, 2, 3, 4, 5, 6]);
+    assert_eq!(loader.cursor(), 1);
+    fs::remove_dir_all(std::env::temp_dir().join(name)).unwrap()
+}
+
+#[test]
+fn test_too_few_tokens() {
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+
+    let message = |f: &mut dyn FnMut()| {
+        let err = catch_unwind(AssertUnwindSafe(f)).unwrap_err();
+        err.downcast_ref::<String>().unwrap().clone()
+    };
+    assert_eq!(
+        message(&mut || {
+            DataLoader::from_tokens(vec![], 2, 3, false);
+        }),
+        "0 tokens are not enough for a batch of 6 tokens, at least 7 are needed"
+    );
+    // 正好一批时可以读取，改为更大的批时同样报告
+    let mut loader = DataLoader::from_tokens((0..7).collect(), 2, 3, false);
+    assert_eq!(loader.load()[1], [1, 2, 3, 4, 5, 6]);
+    assert_eq!(
+        message(&mut || loader.reshape(2, 4)),
+        "7 tokens are not enough for a batch of 8 tokens, at least 9 are needed"
+    );
+}
@@ -1,8 +1,12 @@
 ﻿mod data_loader;
 mod tokenizer;
 
-use crate::Tensor;
+use crate::{Blob, Tensor, generate::Cost};
 use digit_layout::types;
+use memmap2::Mmap;
+use rand::Rng;
+use rw_rc::RwRc;
+use std::{f32::consts::PI, sync::Arc};
 
 pub use data_loader::DataLoader;
 pub use tokenizer::{Tokenizer, safe_print};
@@ -18,16 +22,75 @@ pub struct Gpt2Config {
     pub nblk: usize,              // 层数，例如 12
     pub nh: usize,                // 注意力头数，例如 12
     pub d: usize,                 // 通道数，例如 768
+    pub n_mtp: usize,             // 多词预测辅助头数，例如 0
 }
 
+impl Gpt2Config {
+    /// 用于测试和演示的小模型：2 层、128 维、4 头、最长 64 词。
+    pub fn tiny(n_voc: usize) -> Self {
+        Self {
+            n_seq: 64,
+            n_voc,
+            padded_vocab_size: n_voc.next_multiple_of(64),
+            nblk: 2,
+            nh: 4,
+            d: 128,
+            n_mtp: 0,
+        }
+    }
+
+    /// 前向的计算量：每层的 4 个矩阵乘共 `12d²` 个参数，加上输出层，每个乘加计 2 次运算；
+    /// 注意力中每对位置的 QK 和 AV 各 `2d`。不计多词预测头和逐元素运算。
+    pub fn cost(&self) -> Cost {
+        let &Self {
+            padded_vocab_size,
+            nblk,
+            d,
+            ..
+        } = self;
+        let (nblk, d, n_voc) = (nblk as u64, d as u64, padded_vocab_size as u64);
+        Cost {
+            per_token: 2 * (nblk * 12 * d * d + d * n_voc),
+            per_pair: nblk * 4 * d,
+        }
+    }
+
+    /// 按名字计算张量形状，名字与 [`Gpt2::from_fn`] 一致。
+    pub fn shape(&self, name: &str) -> Vec<usize> {
+        let &Self {
+            n_seq,
+            padded_vocab_size,
+            d,
+            ..
+        } = self;
+        match name {
+            "wte" => vec![padded_vocab_size, d],
+            "wpe" => vec![n_seq, d],
+            _ => match name.rsplitn(3, '.').collect::<Vec<_>>()[..] {
+                ["w", "attn_qkv", _] => vec![3 * d, d],
+                ["b", "attn_qkv", _] => vec![3 * d],
+                ["w", "attn_o", _] => vec![d, d],
+                ["w", "ffn_up", _] => vec![4 * d, d],
+                ["b", "ffn_up", _] => vec![4 * d],
+                ["w", "ffn_down", _] => vec![d, 4 * d],
+                _ => vec![d],
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Gpt2<T> {
     pub config: Gpt2Config,
     pub wte: Tensor<T>,
     pub wpe: Tensor<T>,
     pub blks: Box<[Gpt2Blk<T>]>,
     pub output_norm: [Tensor<T>; 2],
+    /// 多词预测辅助头，第 `i` 个头预测之后第 `i + 2` 个词。
+    pub mtp: Box<[Gpt2Blk<T>]>,
 }
 
+#[derive(Clone)]
 pub struct Gpt2Blk<T> {
     pub attn_norm: [Tensor<T>; 2],
     pub attn_qkv: [Tensor<T>; 2],
@@ -53,10 +116,11 @@ impl<'a> Gpt2<&'a [u8]> {
             nblk: header.0[4] as _,
             nh: header.0[5] as _,
             d: header.0[6] as _,
+            n_mtp: 0,
         };
 
-        // 打印模型配置信息
-        println!("{config:#?}");
+        // 记录模型配置信息
+        tracing::info!(?config, "loaded GPT-2 checkpoint");
 
         let Gpt2Config {
             n_seq,
@@ -122,11 +186,72 @@ impl<'a> Gpt2<&'a [u8]> {
                 })
                 .collect(),
             output_norm: [output_norm_w, output_norm_b],
+            mtp: Box::new([]),
         }
     }
 }
 
+impl Gpt2<Blob> {
+    /// 直接引用内存映射的 llm.c 检查点，见 [`Blob::mapped`]，各层的权重可以按需预取。
+    pub fn mapped(mmap: &Arc<Mmap>) -> Self {
+        Gpt2::new(mmap).map(|data| Blob::mapped(mmap, data))
+    }
+}
+
 impl<T> Gpt2<T> {
+    /// 按名字构造各个张量，名字形如 `wte`、`blk.0.attn_qkv.w`、`mtp.0.ffn_up.b`。
+    pub fn from_fn(config: Gpt2Config, mut f: impl FnMut(&str) -> Tensor<T>) -> Self {
+        Self {
+            wte: f("wte"),
+            wpe: f("wpe"),
+            blks: (0..config.nblk)
+                .map(|i| Gpt2Blk::from_fn(&format!("blk.{i}"), &mut f))
+                .collect(),
+            output_norm: [f("output_norm.w"), f("output_norm.b")],
+            mtp: (0..config.n_mtp)
+                .map(|i| Gpt2Blk::from_fn(&format!("mtp.{i}"), &mut f))
+                .collect(),
+            config,
+        }
+    }
+
+    /// 按名字遍历各个张量。
+    pub fn for_each<'a>(&'a self, mut f: impl FnMut(&str, &'a Tensor<T>)) {
+        f("wte", &self.wte);
+        f("wpe", &self.wpe);
+        for (i, blk) in self.blks.iter().enumerate() {
+            blk.for_each(&format!("blk.{i}"), &mut f)
+        }
+        f("output_norm.w", &self.output_norm[0]);
+        f("output_norm.b", &self.output_norm[1]);
+        for (i, blk) in self.mtp.iter().enumerate() {
+            blk.for_each(&format!("mtp.{i}"), &mut f)
+        }
+    }
+
+    /// 按名字变换各个张量。
+    pub fn map_tensor<U>(self, mut f: impl FnMut(&str, Tensor<T>) -> Tensor<U>) -> Gpt2<U> {
+        let [w, b] = self.output_norm;
+        Gpt2 {
+            config: self.config,
+            wte: f("wte", self.wte),
+            wpe: f("wpe", self.wpe),
+            blks: self
+                .blks
+                .into_iter()
+                .enumerate()
+                .map(|(i, blk)| blk.map_tensor(&format!("blk.{i}"), &mut f))
+                .collect(),
+            output_norm: [f("output_norm.w", w), f("output_norm.b", b)],
+            mtp: self
+                .mtp
+                .into_iter()
+                .enumerate()
+                .map(|(i, blk)| blk.map_tensor(&format!("mtp.{i}"), &mut f))
+                .collect(),
+        }
+    }
+
     pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> Gpt2<U> {
         Gpt2 {
             config: self.config,
@@ -134,11 +259,117 @@ impl<T> Gpt2<T> {
             wpe: self.wpe.map(&mut f),
             blks: self.blks.into_iter().map(|blk| blk.map(&mut f)).collect(),
             output_norm: self.output_norm.map(|t| t.map(&mut f)),
+            mtp: self.mtp.into_iter().map(|blk| blk.map(&mut f)).collect(),
         }
     }
 }
 
+impl Gpt2<RwRc<Blob>> {
+    /// 复制出独立的模型，例如 EMA 副本或另一个推理实例。
+    ///
+    /// 直接克隆会与原模型共享同一组可写权重；分叉得到的权重只共享内存，任何一方修改某个张量时才复制它。
+    pub fn fork(&self) -> Self {
+        self.clone().map(|b| RwRc::new(b.read().clone()))
+    }
+}
+
+impl Gpt2<Blob> {
+    /// 按 GPT-2 的方式随机初始化：权重服从 N(0, 0.02)，残差输出投影再缩小 `sqrt(2 * nblk)` 倍，
+    /// 归一化的缩放为 1，偏置为 0。
+    pub fn random(config: Gpt2Config, rng: &mut impl Rng) -> Self {
+        let residual_std = 0.02 / ((2 * config.nblk) as f32).sqrt();
+        let config_ = config.clone();
+        Self::from_fn(config, |name| {
+            let mut tensor = Tensor::new(types::F32, &config_.shape(name)).map(Blob::new_zeroed);
+            let ([], data, []) = (unsafe { tensor.get_mut().align_to_mut::<f32>() }) else {
+                unreachable!()
+            };
+            let std = if name.ends_with("attn_o.w") || name.ends_with("ffn_down.w") {
+                residual_std
+            } else {
+                0.02
+            };
+            if name.ends_with("norm.w") {
+                data.fill(1.)
+            } else if name == "wte" || name == "wpe" || name.ends_with(".w") {
+                // Box-Muller
+                for x in data {
+                    let u = 1. - rng.random::<f32>();
+                    let v = rng.random::<f32>();
+                    *x = std * (-2. * u.ln()).sqrt() * (2. * PI * v).cos()
+                }
+            }
+            tensor
+        })
+    }
+}
+
 impl<T> Gpt2Blk<T> {
+    fn from_fn(prefix: &str, mut f: impl FnMut(&str) -> Tensor<T>) -> Self {
+        macro_rules! build {
+            ($( $id:ident )+) => {
+                Gpt2Blk { $( $id: ["w", "b"].map(|wb| f(&format!("{prefix}.{}.{wb}", stringify!($id)))), )+ }
+            };
+        }
+
+        build! {
+            attn_norm
+            attn_qkv
+            attn_o
+            ffn_norm
+            ffn_up
+            ffn_down
+        }
+    }
+
+    fn for_each<'a>(&'a self, prefix: &str, mut f: impl FnMut(&str, &'a Tensor<T>)) {
+        macro_rules! visit {
+            ($( $id:ident )+) => {
+                $(
+                    let [w, b] = &self.$id;
+                    f(&format!("{prefix}.{}.w", stringify!($id)), w);
+                    f(&format!("{prefix}.{}.b", stringify!($id)), b);
+                )+
+            };
+        }
+
+        visit! {
+            attn_norm
+            attn_qkv
+            attn_o
+            ffn_norm
+            ffn_up
+            ffn_down
+        }
+    }
+
+    fn map_tensor<U>(
+        self,
+        prefix: &str,
+        mut f: impl FnMut(&str, Tensor<T>) -> Tensor<U>,
+    ) -> Gpt2Blk<U> {
+        macro_rules! map {
+            ($( $id:ident )+) => {
+                Gpt2Blk { $( $id: {
+                    let [w, b] = self.$id;
+                    [
+                        f(&format!("{prefix}.{}.w", stringify!($id)), w),
+                        f(&format!("{prefix}.{}.b", stringify!($id)), b),
+                    ]
+                }, )+ }
+            };
+        }
+
+        map! {
+            attn_norm
+            attn_qkv
+            attn_o
+            ffn_norm
+            ffn_up
+            ffn_down
+        }
+    }
+
     pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> Gpt2Blk<U> {
         macro_rules! map {
             ($( $id:ident )+) => {
@@ -1,19 +1,28 @@
 use super::BinHeader;
 use memmap2::Mmap;
-use std::{fs::File, io::Write, path::Path};
+use std::{collections::HashMap, fs::File, io::Write, path::Path};
 
 // 定义分词器结构体
 pub struct Tokenizer {
     token_table: Vec<Vec<u8>>,
+    // 编码用的反查表及最长词的字节数
+    index: HashMap<Vec<u8>, u16>,
+    max_len: usize,
     pub eos: u16, // <|endoftext|> token id
 }
 
 impl Tokenizer {
     // 初始化分词器
     pub fn new(path: impl AsRef<Path>) -> Result<Tokenizer, std::io::Error> {
-        let file = File::open(path).unwrap();
-        let mmap = unsafe { Mmap::map(&file).unwrap() };
-        let (header, mut body) = mmap.split_at(size_of::<BinHeader>());
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file) }?;
+        Ok(Self::from_bytes(&mmap))
+    }
+
+    /// 从内存中的分词器文件构造，`data` 需要按 4 字节对齐。
+    pub fn from_bytes(data: &[u8]) -> Self {
+        assert!(data.as_ptr().cast::<BinHeader>().is_aligned());
+        let (header, mut body) = data.split_at(size_of::<BinHeader>());
         let header = unsafe { header.as_ptr().cast::<BinHeader>().as_ref().unwrap() };
         if header.0[0] != 20240328 {
             panic!("header is not correct ");
@@ -44,7 +53,100 @@ impl Tokenizer {
             body = tail;
         }
 
-        Ok(Tokenizer { token_table, eos })
+        // 重复的词保留编号最小的
+        let mut index = HashMap::with_capacity(n_voc);
+        for (i, token) in token_table.iter().enumerate().rev() {
+            index.insert(token.clone(), i as u16);
+        }
+        let max_len = token_table.iter().map(Vec::len).max().unwrap_or(0);
+
+        Tokenizer {
+            token_table,
+            index,
+            max_len,
+            eos,
+        }
+    }
+
+    // 词表大小
+    pub fn n_voc(&self) -> usize {
+        self.token_table.len()
+    }
+
+    // 贪心最长匹配编码，不是 GPT-2 的 BPE 合并顺序，但解码后能还原输入；
+    // 遇到词表无法覆盖的字节时返回其偏移
+    pub fn encode(&self, text: &[u8]) -> Result<Vec<u16>, usize> {
+        let mut ans = Vec::new();
+        let mut i = 0;
+        while i < text.len() {
+            let (len, id) = self.longest(text, i).ok_or(i)?;
+            ans.push(id);
+            i += len
+        }
+        Ok(ans)
+    }
+
+    /// 与 [`Self::encode`] 结果相同，但把 `text` 切成约 `chunk_size` 字节的块并行编码。
+    ///
+    /// 每块从块首开始贪心匹配，直到越过下一块的块首。贪心匹配只取决于起点，
+    /// 前一块的匹配一旦落在后一块的某个词边界上，之后两者完全相同，从这里拼接；
+    /// 没有落在边界上时逐词向后匹配，直到对齐。
+    pub fn encode_parallel(&self, text: &[u8], chunk_size: usize) -> Result<Vec<u16>, usize> {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+        assert!(chunk_size > 0);
+        let starts = (0..text.len()).step_by(chunk_size).collect::<Vec<_>>();
+        // 每块的词和每个词的结束位置
+        let parts = (0..starts.len())
+            .into_par_iter()
+            .map(|k| {
+                let stop = starts.get(k + 1).copied().unwrap_or(text.len());
+                let mut tokens = Vec::new();
+                let mut ends = Vec::new();
+                let mut i = starts[k];
+                while i < stop {
+                    let (len, id) = self.longest(text, i)?;
+                    tokens.push(id);
+                    i += len;
+                    ends.push(i)
+                }
+                Some((tokens, ends))
+            })
+            .collect::<Option<Vec<_>>>();
+        // 某块的起点可能落在一个词的中间，按块首匹配失败不代表整体失败，由顺序编码给出准确的位置
+        let Some(parts) = parts else {
+            return self.encode(text);
+        };
+
+        let mut ans = Vec::with_capacity(parts.iter().map(|(t, _)| t.len()).sum());
+        let mut i = 0;
+        for (&start, (tokens, ends)) in starts.iter().zip(&parts) {
+            let end = ends.last().copied().unwrap_or(start);
+            while i < end {
+                if i == start {
+                    ans.extend(tokens);
+                    i = end;
+                    break;
+                }
+                if let Ok(j) = ends.binary_search(&i) {
+                    ans.extend(&tokens[j + 1..]);
+                    i = end;
+                    break;
+                }
+                let (len, id) = self.longest(text, i).ok_or(i)?;
+                ans.push(id);
+                i += len
+            }
+        }
+        Ok(ans)
+    }
+
+    /// 从 `text[i..]` 开头匹配的最长的词及其字节数。
+    fn longest(&self, text: &[u8], i: usize) -> Option<(usize, u16)> {
+        let rest = &text[i..];
+        (1..=self.max_len.min(rest.len()))
+            .rev()
+            .find_map(|len| self.index.get(&rest[..len]).map(|&id| (len, id)))
     }
 
     // 解码token id
@@ -62,3 +164,40 @@ pub fn safe_print(piece: &[u8]) {
     }
     std::io::stdout().flush().unwrap()
 }
+
+#[test]
+fn test_encode_parallel() {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    // 单字节词加上一些相互重叠的多字节词，贪心匹配的结果依赖起点
+    let pieces = [&b"ab"[..], b"abc", b"bca", b"cab", b"aa", b"aaa", b"bcabc"];
+    let mut data = vec![0i32; 256];
+    data[..4].copy_from_slice(&[20240328, 2, 256 + pieces.len() as i32, 0]);
+    let mut data = data
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect::<Vec<_>>();
+    for b in 0..=255u8 {
+        data.extend([1, b])
+    }
+    for piece in pieces {
+        data.push(piece.len() as u8);
+        data.extend(piece)
+    }
+    let mut aligned = vec![0u32; data.len().div_ceil(4)];
+    unsafe { aligned.align_to_mut::<u8>().1[..data.len()].copy_from_slice(&data) };
+    let tokenizer = Tokenizer::from_bytes(unsafe { aligned.align_to::<u8>().1 });
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let text = (0..2000)
+        .map(|_| b"abc"[rng.random_range(0..3)])
+        .collect::<Vec<_>>();
+    let expected = tokenizer.encode(&text).unwrap();
+    for chunk_size in [1, 2, 3, 7, 64, 5000] {
+        assert_eq!(
+            tokenizer.encode_parallel(&text, chunk_size).unwrap(),
+            expected
+        )
+    }
+    assert!(tokenizer.encode_parallel(&[], 4).unwrap().is_empty())
+}
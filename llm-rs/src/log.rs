@@ -0,0 +1,49 @@
+//! 日志输出。
+//!
+//! 库中以 [`tracing`] 记录 span 和事件：模块（`module`）、生成请求（`generate`）等；
+//! 二进制程序调用 [`init`] 把它们输出到标准错误，也可以导出为跟踪文件，见 [`crate::trace`]。
+
+/// [`init`] 返回的守卫，析构时写完跟踪文件，二进制程序应持有到退出。
+#[must_use]
+pub struct Guard {
+    #[cfg(feature = "log")]
+    _flush: Option<crate::trace::FlushGuard>,
+}
+
+/// 安装输出到标准错误的订阅者，级别由 `RUST_LOG` 控制，缺省为 `info`。
+///
+/// 设置了环境变量 `LLM_RS_TRACE` 时，还把所有级别的 span 计时写到它指定的文件，
+/// 格式见 [`crate::trace`]，不受 `RUST_LOG` 影响。
+///
+/// 未启用 `log` 特性时什么也不做，使用者可以安装自己的订阅者。
+pub fn init() -> Guard {
+    #[cfg(feature = "log")]
+    {
+        use crate::trace::{self, TRACE_ENV};
+        use tracing_subscriber::{
+            EnvFilter, Layer, fmt, layer::SubscriberExt, util::SubscriberInitExt,
+        };
+
+        let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+        let path = std::env::var_os(TRACE_ENV);
+        let (chrome, guard) = match path.as_ref().map(trace::file).transpose() {
+            Ok(Some((layer, guard))) => (Some(layer), Some(guard)),
+            Ok(None) => (None, None),
+            Err(e) => {
+                eprintln!("failed to create {TRACE_ENV} file: {e}");
+                (None, None)
+            }
+        };
+        let _ = tracing_subscriber::registry()
+            .with(
+                fmt::layer()
+                    .with_writer(std::io::stderr)
+                    .with_filter(filter),
+            )
+            .with(chrome)
+            .try_init();
+        Guard { _flush: guard }
+    }
+    #[cfg(not(feature = "log"))]
+    Guard {}
+}
@@ -1,37 +1,36 @@
-mod blob;
-mod context;
-mod llmc;
-mod nn;
-mod op;
-mod optimizer;
-
-use std::{hash::Hash, rc::Weak};
-
-use blob::Blob;
-use context::Context;
+use llm_rs::{Context, Tensor, llmc, nn};
 use rw_rc::RwRc;
 
-type Tensor<T> = tensor::Tensor<T, 4>;
-
 fn main() {
     use digit_layout::types;
+    use llm_rs::{
+        generate::{GenerationConfig, generate_cached},
+        log,
+        optimizer::AdamW,
+        sampler::{Sampler, SamplerConfig},
+        seq_warmup::SeqLenWarmup,
+        truncate::Truncation,
+    };
     use llmc::{DataLoader, Tokenizer, safe_print};
     use memmap2::Mmap;
-    use optimizer::AdamW;
-    use std::fs::File;
-    use std::{env::args, path::PathBuf, time::Instant};
+    use std::{env::args_os, path::PathBuf, time::Instant};
+    use std::{fs::File, sync::Arc};
 
-    let bin_path = PathBuf::from(args().nth(1).unwrap());
+    let _trace = log::init();
+    let bin_path = PathBuf::from(args_os().nth(1).unwrap());
     let batch_size = 4;
     let seq_len = 64;
+    // 前 20 步从 16 词的短序列逐步增长到完整长度，每步的词数不变
+    let warmup = SeqLenWarmup {
+        tokens_per_step: batch_size * seq_len,
+        min_seq_len: 16,
+        max_seq_len: seq_len,
+        warmup_steps: 20,
+        multiple_of: 16,
+    };
+    let [b, t] = warmup.shape(0);
 
-    let mut train_loader = DataLoader::new(
-        &bin_path,
-        "*/tiny_shakespeare_train.bin",
-        batch_size,
-        seq_len,
-        true,
-    );
+    let mut train_loader = DataLoader::new(&bin_path, "*/tiny_shakespeare_train.bin", b, t, true);
     let mut val_loader = DataLoader::new(
         &bin_path,
         "*/tiny_shakespeare_val.bin",
@@ -43,16 +42,20 @@ fn main() {
     assert_eq!(tokenizer.decode(tokenizer.eos), br"<|endoftext|>");
 
     let file = File::open(bin_path.join("gpt2_124M.bin")).unwrap();
-    let mmap = unsafe { Mmap::map(&file) }.unwrap();
-    let gpt2 = llmc::Gpt2::new(&mmap);
+    let mmap = Arc::new(unsafe { Mmap::map(&file) }.unwrap());
+    let gpt2 = llmc::Gpt2::mapped(&mmap);
     let n_voc = gpt2.config.n_voc;
+    let cost = gpt2.config.cost();
 
     let mut ctx = Context::new(false);
-    let mut gpt2 = ctx.init::<nn::gpt2::Gpt2>("gpt2", gpt2.map(Blob::from).map(RwRc::new));
+    let mut gpt2 = ctx.init::<nn::gpt2::Gpt2>("gpt2", gpt2.map(RwRc::new));
     let mut loss = ctx.init::<nn::loss::Loss>("loss", n_voc);
-    let mut adamw = AdamW::new(1e-4, 0.9, 0.999, 1e-8, 0.);
+    let learning_rate = 1e-4;
+    let mut adamw = AdamW::new(learning_rate, 0.9, 0.999, 1e-8, 0.);
+    let mut sampler = Sampler::new(SamplerConfig::default(), rand::random());
 
     for step in 0..=40 {
+        let _span = tracing::info_span!("step", step).entered();
         if step % 10 == 0 {
             val_loader.rand();
             let mut val_loss = 0.;
@@ -69,32 +72,46 @@ fn main() {
                 val_loss += loss_sum(losses[0].cloned().as_ref().map(|b| &**b.read()))
             }
             val_loss /= 5.;
-            println!("val_loss: {val_loss}")
+            tracing::info!(val_loss, "validated")
         }
 
         if step > 0 && step % 20 == 0 {
             println!("-----------");
-            let mut tokens = vec![tokenizer.eos; batch_size * seq_len];
-            for t in 1..64 {
-                let tokens_ = Tensor::new(types::U16, &[batch_size, seq_len])
-                    .map(|_| Blob::from(&*tokens))
-                    .map(RwRc::new);
-                let logits = ctx.forward("gpt2", &mut gpt2, [tokens_.share()]);
-                let logits = logits[0].cloned().index(&[0, t - 1]);
-                let logits = logits.as_ref().map(|b| &**b.read()).vector();
-                let next = sample(&logits[..n_voc], rand::random());
-                tokens[t] = next as u16;
-                safe_print(tokenizer.decode(next as u16))
-            }
+            let config = GenerationConfig {
+                n_ctx: seq_len,
+                n_voc,
+                max_tokens: 63,
+                eos: None,
+                truncation: Truncation::KeepTail,
+                echo: false,
+                stop: None,
+                cost,
+            };
+            let result = generate_cached(
+                &mut ctx,
+                "gpt2",
+                &mut gpt2,
+                &[tokenizer.eos],
+                &config,
+                |logits| sampler.sample(logits),
+                |t| tokenizer.decode(t),
+            );
+            safe_print(result.text.as_bytes());
             println!();
+            println!("{}", serde_json::to_string(&result).unwrap());
             println!("-----------")
         }
 
         let time = Instant::now();
 
+        let shape = warmup.shape(step);
+        if shape != train_loader.shape() {
+            let [b, t] = shape;
+            train_loader.reshape(b, t)
+        }
+        adamw.set_learning_rate(learning_rate * warmup.lr_scale(step));
         let [inputs, targets] = train_loader.load();
 
-        let shape = [batch_size, seq_len];
         let tokens = Tensor::new(types::U16, &shape).map(|_| RwRc::new(inputs.into()));
         let targets = Tensor::new(types::U16, &shape).map(|_| RwRc::new(targets.into()));
 
@@ -103,9 +120,9 @@ fn main() {
         let train_loss = loss_sum(losses[0].cloned().as_ref().map(|b| &**b.read()));
         ctx.zero_grad();
 
-        let dloss_mean = 1. / (batch_size * seq_len) as f32;
+        let dloss_mean = 1. / warmup.tokens(step) as f32;
         let loss_ = &losses[0];
-        let dlosses = ctx.tensor(loss_.dt(), &loss_.shape());
+        let dlosses = ctx.tensor_like(loss_);
         dlosses
             .cloned()
             .merge(0, 2)
@@ -119,10 +136,8 @@ fn main() {
         ctx.update(&mut adamw);
         adamw.next();
 
-        println!(
-            "step {step}: train loss {train_loss} (took {:?})",
-            time.elapsed()
-        )
+        let tokens = warmup.tokens_before(step + 1);
+        tracing::info!(train_loss, ?shape, tokens, elapsed = ?time.elapsed(), "trained")
     }
 }
 
@@ -130,77 +145,3 @@ fn loss_sum(losses: Tensor<&[u8]>) -> f32 {
     let losses = losses.merge(0, 2).vector::<f32>();
     losses.iter().sum::<f32>() / losses.len() as f32
 }
-
-fn sample(logits: &[f32], coin: f32) -> u16 {
-    let mut pairs = logits.iter().copied().enumerate().collect::<Vec<_>>();
-    pairs.sort_by(|(_, a), (_, b)| f32::total_cmp(a, b).reverse());
-
-    let max = pairs[0].1;
-    pairs[0].1 = 1.;
-
-    for i in 1..pairs.len() {
-        pairs[i].1 = pairs[i - 1].1 + (pairs[i].1 - max).exp()
-    }
-
-    let &[.., (_, sum)] = &*pairs else {
-        unreachable!()
-    };
-
-    let plimit = sum * coin;
-    for (i, acc) in pairs {
-        if acc >= plimit {
-            return i as _;
-        }
-    }
-    unreachable!()
-}
-
-struct HashWeak<T>(Weak<T>);
-
-impl<T> PartialEq for HashWeak<T> {
-    fn eq(&self, other: &Self) -> bool {
-        Weak::ptr_eq(&self.0, &other.0)
-    }
-}
-
-impl<T> Eq for HashWeak<T> {}
-
-impl<T> Hash for HashWeak<T> {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.as_ptr().hash(state)
-    }
-}
-
-mod macros {
-    macro_rules! dims {
-        ($pat:pat = $tensor:expr) => {
-            let &$pat = &*$tensor.shape() else {
-                panic!("Ndim mismatch ( = {})", $tensor.shape().len())
-            };
-        };
-    }
-
-    macro_rules! strides {
-        ($pat:pat = $tensor:expr) => {
-            let &$pat = &*$tensor.layout().strides() else {
-                panic!("Ndim mismatch ( = {})", $tensor.layout().strides().len())
-            };
-        };
-    }
-
-    macro_rules! destruct {
-        ([$( $name:ident ),+] = $iter:expr) => {
-            let mut iter = $iter.into_iter();
-            $( let $name = iter.next().unwrap(); )+
-            assert!(iter.next().is_none());
-        };
-    }
-
-    macro_rules! clone_tensor {
-        ($( $tensor:ident )+) => {
-            $( let $tensor = $tensor.cloned(); )+
-        };
-    }
-
-    pub(super) use {clone_tensor, destruct, dims, strides};
-}
@@ -0,0 +1,178 @@
+//! 模型的元数据：架构、参数量、上下文长度、量化方案、词表和来源。
+
+use crate::{
+    bpe::Bpe,
+    journal,
+    llmc::{self, Gpt2},
+    quant::{Checkpoint, ImportError, Scheme},
+};
+use memmap2::Mmap;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+/// 模型卡片，由 [`Gpt2::metadata`] 或 [`ModelMetadata::open`] 得到。
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct ModelMetadata {
+    pub architecture: String,
+    /// 逻辑参数量，量化张量按反量化后的元素数计。
+    pub n_params: usize,
+    /// 权重占用的字节数。
+    pub nbytes: usize,
+    /// 上下文长度。
+    pub n_ctx: usize,
+    pub n_voc: usize,
+    pub nblk: usize,
+    pub nh: usize,
+    pub d: usize,
+    /// 各存储类型的张量数，如 `{"f32": 26, "q4g32": 8}`。
+    pub quantization: BTreeMap<String, usize>,
+    pub tokenizer: Option<TokenizerInfo>,
+    pub source: Option<Source>,
+    /// 检查点 `__metadata__` 中的 `license`。
+    pub license: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct TokenizerInfo {
+    /// `"llm.c"` 或 `"bpe"`。
+    pub kind: String,
+    pub n_voc: usize,
+    pub eos: Option<u16>,
+}
+
+/// 模型的来源文件。
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct Source {
+    pub path: PathBuf,
+    /// `"llm.c"` 或 `"safetensors"`。
+    pub format: String,
+    /// 文件内容的 [`journal::hash_file`]。
+    pub hash: u64,
+}
+
+impl<T> Gpt2<T> {
+    /// 由模型本身得到的元数据，词表、来源和许可证为空。
+    pub fn metadata(&self) -> ModelMetadata {
+        let mut n_params = 0;
+        let mut nbytes = 0;
+        let mut quantization = BTreeMap::new();
+        self.for_each(|_, tensor| {
+            let dt = tensor.dt();
+            let n = tensor.shape().iter().product::<usize>();
+            n_params += n;
+            nbytes += n / dt.group_size() * dt.nbytes();
+            let scheme = Scheme::from_dt(dt).map_or_else(|| format!("{dt}"), |s| s.to_string());
+            *quantization.entry(scheme).or_default() += 1
+        });
+        let config = &self.config;
+        ModelMetadata {
+            architecture: "gpt2".into(),
+            n_params,
+            nbytes,
+            n_ctx: config.n_seq,
+            n_voc: config.n_voc,
+            nblk: config.nblk,
+            nh: config.nh,
+            d: config.d,
+            quantization,
+            tokenizer: None,
+            source: None,
+            license: None,
+        }
+    }
+}
+
+impl ModelMetadata {
+    /// 读取 llm.c 或 safetensors（按扩展名区分）检查点的元数据并计算文件哈希。
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ImportError> {
+        let path = path.as_ref();
+        let (mut ans, format) = if path.extension().is_some_and(|e| e == "safetensors") {
+            let checkpoint = Checkpoint::open(path)?;
+            let mut ans = checkpoint.gpt2()?.metadata();
+            ans.license = checkpoint
+                .metadata()
+                .and_then(|m| m.get("license"))
+                .cloned();
+            (ans, "safetensors")
+        } else {
+            let mmap = unsafe { Mmap::map(&File::open(path)?) }?;
+            (Gpt2::new(&mmap).metadata(), "llm.c")
+        };
+        ans.source = Some(Source {
+            path: path.into(),
+            format: format.into(),
+            hash: journal::hash_file(path)?,
+        });
+        Ok(ans)
+    }
+
+    pub fn with_tokenizer(mut self, tokenizer: impl Into<TokenizerInfo>) -> Self {
+        self.tokenizer = Some(tokenizer.into());
+        self
+    }
+}
+
+impl From<&llmc::Tokenizer> for TokenizerInfo {
+    fn from(value: &llmc::Tokenizer) -> Self {
+        Self {
+            kind: "llm.c".into(),
+            n_voc: value.n_voc(),
+            eos: Some(value.eos),
+        }
+    }
+}
+
+impl From<&Bpe> for TokenizerInfo {
+    fn from(value: &Bpe) -> Self {
+        Self {
+            kind: "bpe".into(),
+            n_voc: value.n_voc(),
+            eos: value.eos,
+        }
+    }
+}
+
+#[test]
+fn test_metadata() {
+    use crate::quant::{Recipe, quantize_gpt2, save_gpt2};
+    use llmc::Gpt2Config;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let gpt2 = Gpt2::random(Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7));
+    let meta = gpt2.metadata();
+    let mut n_params = 0;
+    gpt2.for_each(|_, t| n_params += t.shape().iter().product::<usize>());
+    assert_eq!(meta.n_params, n_params);
+    assert_eq!(meta.nbytes, n_params * 4);
+    assert_eq!(meta.n_ctx, 64);
+    assert_eq!(meta.quantization.keys().collect::<Vec<_>>(), ["f32"]);
+
+    // 量化后参数量不变，字节数减少
+    let recipe = Recipe::default().rule("*", Scheme::Q8_0);
+    let (gpt2, _) = quantize_gpt2(gpt2, &recipe);
+    let quantized = gpt2.metadata();
+    assert_eq!(quantized.n_params, n_params);
+    assert!(quantized.nbytes < meta.nbytes);
+    assert!(quantized.quantization["q8_0"] > 0);
+
+    // 保存后从文件读出相同的元数据和来源
+    let path = std::env::temp_dir().join(format!("llm-rs-meta-{}.safetensors", std::process::id()));
+    save_gpt2(&path, &gpt2).unwrap();
+    let opened = ModelMetadata::open(&path).unwrap();
+    let source = opened.source.clone().unwrap();
+    assert_eq!(source.format, "safetensors");
+    assert_eq!(source.hash, journal::hash_file(&path).unwrap());
+    assert_eq!(opened.license, None);
+    assert_eq!(
+        ModelMetadata {
+            source: None,
+            ..opened
+        },
+        quantized
+    );
+    std::fs::remove_file(path).unwrap()
+}
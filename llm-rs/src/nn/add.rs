@@ -0,0 +1,90 @@
+use super::{NeuralNetwork, Tensor};
+use crate::{Context, macros::*, op::add::add};
+use std::rc::Rc;
+
+/// 残差连接 `y = x + residual`，反向把同一个梯度分别传给两个分支。
+pub struct Add;
+
+impl NeuralNetwork for Add {
+    type Init = ();
+
+    fn init(_init: Self::Init, _ctx: &mut Context) -> Self {
+        Self
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([x, residual] = inputs);
+
+        let y = ctx.tensor_zeroed(x.dt(), &x.shape());
+        ctx.bench(|| {
+            add(&y, &x);
+            add(&y, &residual)
+        });
+
+        vec![y.share()]
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([dy] = inputs);
+
+        // 两个分支的梯度可能被各自原地累加，不能共享存储
+        let dresidual = ctx.tensor_zeroed(dy.dt(), &dy.shape());
+        ctx.bench(|| add(&dresidual, &dy));
+
+        vec![dy, dresidual.share()]
+    }
+}
+
+#[test]
+fn test_add() {
+    use crate::Blob;
+    use digit_layout::types;
+    use rw_rc::RwRc;
+
+    let tensor = |data: &[f32]| {
+        crate::Tensor::new(types::F32, &[1, 2, 3])
+            .map(|_| Blob::from(data))
+            .map(RwRc::new)
+            .share()
+    };
+    let values = |t: &Rc<Tensor>| {
+        let t = t.cloned();
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+
+    let xs = [1f32, 2., 3., 4., 5., 6.];
+    let rs = [0.5f32, -1., 0., 2., -3., 1.];
+    let x = tensor(&xs);
+    let residual = tensor(&rs);
+
+    let mut ctx = Context::new(false);
+    let mut add = ctx.init::<Add>("add", ());
+    let y = ctx.forward("add", &mut add, [x.clone(), residual.clone()]);
+    let expected = std::iter::zip(xs, rs)
+        .map(|(x, r)| x + r)
+        .collect::<Vec<_>>();
+    assert_eq!(values(&y[0]), expected);
+    // 输入保持不变
+    assert_eq!(values(&x), xs);
+
+    // 两个分支得到相同的梯度，存储相互独立
+    let dys = [1f32, -1., 2., 0., 3., -2.];
+    let dy = tensor(&dys);
+    destruct!([dx, dresidual] = ctx.backward("add", &mut add, [dy]));
+    assert_eq!(values(&dx), dys);
+    assert_eq!(values(&dresidual), dys);
+    crate::op::add::add(&dresidual, &dx);
+    assert_eq!(values(&dx), dys);
+    assert_eq!(values(&dresidual), dys.map(|dy| 2. * dy))
+}
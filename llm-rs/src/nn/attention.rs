@@ -1,15 +1,556 @@
 ﻿use super::{NeuralNetwork, Tensor};
 use crate::{
-    Context,
+    Blob, Context,
     macros::*,
-    op::attention::{backward, forward},
+    op::{
+        attention::{
+            AttentionConfig, Paged, RelativeBias, SparsePattern, backward, decode, forward,
+            prefill, quant_heads,
+        },
+        flash_attention,
+        rearrange::rearrange,
+    },
 };
-use std::rc::Rc;
+use digit_layout::{DigitLayout, types};
+use rw_rc::RwRc;
+use std::{
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+/// 一层注意力的分页 KV 缓存，多个序列共享一块预先分配的缓冲区。
+///
+/// 开启缓存后每次前向只输入新的位置，新位置的 K、V 追加到各自序列的缓存末尾，查询对序列缓存的全部位置做注意力。
+/// 缓冲区分为 `n_blocks` 个 `block_size` 行的块，K、V 形状为 `[n_blocks, block_size, nkvh, dh]`，首次前向时分配。
+/// 每个序列有自己的块表，追加位置时按需取空闲块，移除序列时归还，长度不同的序列共用缓冲区而不产生碎片。
+///
+/// 前向输入的第 `i` 行属于 [`Self::select`] 选中的第 `i` 个序列；没有选中的序列时为每行新建一个序列。
+pub struct KvCache {
+    n_ctx: usize,
+    block_size: usize,
+    n_blocks: Option<usize>,
+    kv: Option<[Tensor; 2]>,
+    int8: bool,
+    scales: Option<[Tensor; 2]>,
+    free: Vec<usize>,
+    seqs: Vec<Option<Sequence>>,
+    active: Vec<usize>,
+}
+
+/// 一个序列的块表和已缓存的位置数。
+#[derive(Default)]
+struct Sequence {
+    table: Vec<usize>,
+    len: usize,
+}
+
+impl KvCache {
+    /// 每个序列独占一个 `n_ctx` 行的块，块数为首次前向的批大小。
+    pub fn new(n_ctx: usize) -> Self {
+        Self {
+            n_ctx,
+            block_size: n_ctx,
+            n_blocks: None,
+            kv: None,
+            int8: false,
+            scales: None,
+            free: Vec::new(),
+            seqs: Vec::new(),
+            active: Vec::new(),
+        }
+    }
+
+    /// 共 `n_blocks` 个 `block_size` 行的块，每个序列最长 `n_ctx`。
+    pub fn paged(n_ctx: usize, block_size: usize, n_blocks: usize) -> Self {
+        assert!(block_size > 0 && n_blocks > 0);
+        Self {
+            block_size,
+            n_blocks: Some(n_blocks),
+            free: (0..n_blocks).rev().collect(),
+            ..Self::new(n_ctx)
+        }
+    }
+
+    /// 以 int8 存储 K、V，每个位置的每个头一个比例，缓存约为 f32 的四分之一，解码时在注意力内核中反量化。
+    ///
+    /// 须在首次前向分配缓冲区之前设置。
+    pub fn int8(&mut self, enabled: bool) {
+        assert!(self.kv.is_none(), "KV cache is already allocated");
+        self.int8 = enabled
+    }
+
+    /// K、V 缓冲区及比例的总字节数，首次前向之前为 0。
+    pub fn nbytes(&self) -> usize {
+        let nbytes = |t: &Option<[Tensor; 2]>| {
+            t.iter()
+                .flatten()
+                .map(|t| t.shape().iter().product::<usize>() * t.dt().nbytes())
+                .sum::<usize>()
+        };
+        nbytes(&self.kv) + nbytes(&self.scales)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// 空闲的块数，首次前向之前按批大小分配的缓存为 0。
+    pub fn free_blocks(&self) -> usize {
+        self.free.len()
+    }
+
+    /// 新建一个空序列，返回序列号，移除的序列号会被复用。
+    pub fn add_sequence(&mut self) -> usize {
+        match self.seqs.iter().position(Option::is_none) {
+            Some(id) => {
+                self.seqs[id] = Some(Sequence::default());
+                id
+            }
+            None => {
+                self.seqs.push(Some(Sequence::default()));
+                self.seqs.len() - 1
+            }
+        }
+    }
+
+    /// 移除序列并归还它占用的块，它也不再被选中。
+    pub fn remove_sequence(&mut self, id: usize) {
+        let seq = self.seqs[id].take().expect("sequence does not exist");
+        self.free.extend(seq.table.into_iter().rev());
+        self.active.retain(|&i| i != id)
+    }
+
+    /// 选择之后的前向中各行对应的序列。
+    pub fn select(&mut self, ids: &[usize]) {
+        for (i, &id) in ids.iter().enumerate() {
+            assert!(
+                self.seqs.get(id).is_some_and(Option::is_some),
+                "sequence {id} does not exist"
+            );
+            assert!(!ids[..i].contains(&id), "sequence {id} selected twice")
+        }
+        self.active = ids.to_vec()
+    }
+
+    /// 序列 `id` 已缓存的位置数。
+    pub fn seq_len(&self, id: usize) -> usize {
+        self.seqs[id].as_ref().expect("sequence does not exist").len
+    }
+
+    /// 把序列 `id` 回退到前 `len` 个位置，之后的位置作废，不再需要的块归还给空闲块。
+    ///
+    /// 用于投机解码等需要撤销已缓存的词的场合。
+    pub fn truncate(&mut self, id: usize, len: usize) {
+        let block_size = self.block_size;
+        let seq = self.seqs[id].as_mut().expect("sequence does not exist");
+        if len >= seq.len {
+            return;
+        }
+        seq.len = len;
+        let n_blocks = len.div_ceil(block_size);
+        self.free.extend(seq.table.drain(n_blocks..).rev())
+    }
+
+    /// 选中的各序列已缓存的位置数。
+    pub fn lens(&self) -> Vec<usize> {
+        self.active.iter().map(|&id| self.seq_len(id)).collect()
+    }
+
+    /// 第一个选中的序列已缓存的位置数，没有选中的序列时为 0。
+    pub fn len(&self) -> usize {
+        self.active.first().map_or(0, |&id| self.seq_len(id))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 把序列 `id` 的 K、V 和位置数写入 `w`，只写入它占用的位置，与块大小和块表无关。
+    pub fn save(&self, id: usize, w: &mut impl Write) -> io::Result<()> {
+        let len = self.seq_len(id);
+        let Some([k, v]) = &self.kv else {
+            return write_u64s(w, &[0; 4]);
+        };
+        dims!([_, _, nkvh, dh] = k);
+        let tag = CACHE_DTS.iter().position(|&dt| dt == k.dt()).unwrap();
+        write_u64s(w, &[tag as _, len as _, nkvh as _, dh as _])?;
+
+        let table = &self.seqs[id].as_ref().unwrap().table;
+        for t in [k, v].into_iter().chain(self.scales.iter().flatten()) {
+            let buf = t.get().read();
+            let row = buf.len() / (t.shape()[0] * self.block_size);
+            for pos in 0..len {
+                let block = table[pos / self.block_size];
+                let offset = (block * self.block_size + pos % self.block_size) * row;
+                w.write_all(&buf[offset..][..row])?
+            }
+            t.get().release()
+        }
+        Ok(())
+    }
+
+    /// 从 `r` 读取 [`Self::save`] 保存的序列，存为一个新序列，返回序列号。
+    ///
+    /// 缓冲区尚未分配时按保存的格式分配，否则格式和形状须与已有的缓冲区一致。
+    /// 空闲块不足时返回 [`io::ErrorKind::OutOfMemory`]，读取失败时缓存保持调用前的状态。
+    pub fn load(&mut self, r: &mut impl Read) -> io::Result<usize> {
+        let [tag, len, nkvh, dh] = read_u64s(r)?.map(|x| x as usize);
+        if len == 0 {
+            return Ok(self.add_sequence());
+        }
+        let dt = *CACHE_DTS
+            .get(tag)
+            .ok_or_else(|| invalid(format!("unknown dtype tag {tag}")))?;
+        if len > self.n_ctx {
+            return Err(invalid(format!(
+                "sequence length {len} exceeds n_ctx {}",
+                self.n_ctx
+            )));
+        }
+        if let Some([k, _]) = &self.kv
+            && (k.dt() != dt || k.shape()[2..] != [nkvh, dh])
+        {
+            return Err(invalid(format!(
+                "saved cache is {dt:?} [{nkvh}, {dh}], expected {:?} {:?}",
+                k.dt(),
+                &k.shape()[2..]
+            )));
+        }
+        // 按批大小分配的缓存在首次使用时只有一块
+        let n_free = if self.n_blocks.is_some() {
+            self.free.len()
+        } else {
+            1
+        };
+        let n_needed = len.div_ceil(self.block_size);
+        if n_needed > n_free {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!("KV cache has {n_free} free blocks, session needs {n_needed}"),
+            ));
+        }
+
+        let allocated = self.kv.is_none();
+        let (n_blocks, int8) = (self.n_blocks, self.int8);
+        if allocated {
+            self.int8 = dt == types::I8
+        }
+        self.alloc(dt, nkvh, dh, 1);
+        let id = self.add_sequence();
+        self.reserve(id, len);
+        match self.read_sequence(id, len, r) {
+            Ok(()) => {
+                self.seqs[id].as_mut().unwrap().len = len;
+                Ok(id)
+            }
+            Err(e) => {
+                self.remove_sequence(id);
+                if allocated {
+                    self.kv = None;
+                    self.scales = None;
+                    self.int8 = int8;
+                    if n_blocks.is_none() {
+                        self.n_blocks = None;
+                        self.free.clear()
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// 把 `r` 中的 `len` 个位置读入序列 `id` 已取得的块。
+    fn read_sequence(&self, id: usize, len: usize, r: &mut impl Read) -> io::Result<()> {
+        let table = &self.seqs[id].as_ref().unwrap().table;
+        let [k, v] = self.kv.as_ref().unwrap();
+        for t in [k, v].into_iter().chain(self.scales.iter().flatten()) {
+            let buf = t.get().write();
+            let row = buf.len() / (t.shape()[0] * self.block_size);
+            let ans = (0..len).try_for_each(|pos| {
+                let block = table[pos / self.block_size];
+                let offset = (block * self.block_size + pos % self.block_size) * row;
+                r.read_exact(&mut buf[offset..][..row])
+            });
+            t.get().release();
+            ans?
+        }
+        Ok(())
+    }
+
+    /// 首次使用时分配缓冲区，按批大小分配时每行一块。
+    fn alloc(&mut self, dt: DigitLayout, nkvh: usize, dh: usize, batch_size: usize) {
+        if self.kv.is_some() {
+            return;
+        }
+        let n_blocks = *self.n_blocks.get_or_insert_with(|| {
+            self.free.extend((0..batch_size).rev());
+            batch_size
+        });
+        let tensor = |dt, shape: &[usize]| {
+            crate::Tensor::new(dt, shape)
+                .map(Blob::new_zeroed)
+                .map(RwRc::new)
+        };
+        let shape = [n_blocks, self.block_size, nkvh, dh];
+        self.kv = Some(if self.int8 {
+            self.scales = Some([(); 2].map(|_| tensor(types::F32, &shape[..3])));
+            [(); 2].map(|_| tensor(types::I8, &shape))
+        } else {
+            [(); 2].map(|_| tensor(dt, &shape))
+        })
+    }
+
+    /// 从空闲块中为序列 `id` 取块，直到能容纳 `len` 个位置。
+    fn reserve(&mut self, id: usize, len: usize) {
+        let n_ctx = self.n_ctx;
+        assert!(len <= n_ctx, "sequence length {len} exceeds n_ctx {n_ctx}");
+        let seq = self.seqs[id].as_mut().expect("sequence does not exist");
+        while seq.table.len() * self.block_size < len {
+            seq.table
+                .push(self.free.pop().expect("KV cache is out of blocks"))
+        }
+    }
+
+    /// 移除所有序列，保留已分配的缓冲区。
+    pub fn clear(&mut self) {
+        self.seqs.clear();
+        self.active.clear();
+        if let Some(n_blocks) = self.n_blocks {
+            self.free = (0..n_blocks).rev().collect()
+        }
+    }
+}
+
+/// 保存的 KV 缓存的数据类型，以序号记录。
+const CACHE_DTS: [DigitLayout; 4] = [types::F32, types::F16, types::BF16, types::I8];
+
+fn write_u64s(w: &mut impl Write, vals: &[u64]) -> io::Result<()> {
+    for val in vals {
+        w.write_all(&val.to_le_bytes())?
+    }
+    Ok(())
+}
+
+fn read_u64s<const N: usize>(r: &mut impl Read) -> io::Result<[u64; N]> {
+    let mut ans = [0; N];
+    for val in &mut ans {
+        let mut buf = [0; 8];
+        r.read_exact(&mut buf)?;
+        *val = u64::from_le_bytes(buf)
+    }
+    Ok(ans)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
 
 pub struct Attention {
     nh: usize,
+    rel_bias: Option<(Rc<Tensor>, usize)>,
+    alibi: Option<Box<[f32]>>,
+    sparse: Option<SparsePattern>,
+    prefix: usize,
+    nkvh: Option<usize>,
+    lens: Option<Box<[usize]>>,
+    mask: Option<Rc<Tensor>>,
+    flash: bool,
+    cache: Option<KvCache>,
     x: Option<Rc<Tensor>>,
+    kv: Option<Rc<Tensor>>,
     att: Option<Tensor>,
+    // 分块实现保存输出和 log-sum-exp，代替 att
+    y: Option<Rc<Tensor>>,
+    lse: Option<Tensor>,
+}
+
+impl Attention {
+    /// 使用可学习的 T5 相对位置偏置，`table` 形状为 `[nh, n_buckets]`。
+    pub fn relative_bias(&mut self, table: Rc<Tensor>, max_distance: usize) {
+        dims!([nh, _] = table);
+        assert_eq!(nh, self.nh);
+        self.rel_bias = Some((table, max_distance))
+    }
+
+    /// 使用 ALiBi 线性偏置，`slopes` 为每个头的斜率，通常取 [`alibi_slopes`](crate::op::attention::alibi_slopes)。
+    pub fn alibi(&mut self, slopes: Vec<f32>) {
+        assert_eq!(slopes.len(), self.nh);
+        self.alibi = Some(slopes.into())
+    }
+
+    /// 使用稀疏注意力模式。
+    pub fn sparse(&mut self, pattern: SparsePattern) {
+        assert!(pattern.window > 0);
+        self.sparse = Some(pattern)
+    }
+
+    /// 前 `n_prefix` 个位置双向可见（Prefix-LM），0 表示完全因果。
+    pub fn prefix_lm(&mut self, n_prefix: usize) {
+        self.prefix = n_prefix
+    }
+
+    /// 分组查询注意力，`nkvh` 个 K、V 头由查询头分组共享，输入的 qkv 宽度为 `(nh + 2 * nkvh) * dh`。
+    pub fn kv_heads(&mut self, nkvh: usize) {
+        assert_eq!(self.nh % nkvh, 0);
+        self.nkvh = Some(nkvh)
+    }
+
+    /// 批中每个序列的有效长度，`None` 表示没有填充，对之后的前向和反向生效。
+    pub fn padding(&mut self, lens: Option<Vec<usize>>) {
+        self.lens = lens.map(Into::into)
+    }
+
+    /// 形状为 `[batch, n_seq, n_seq]` 的布尔掩码，参见 [`AttentionConfig::mask`]，对之后的前向和反向生效。
+    pub fn mask(&mut self, mask: Option<Rc<Tensor>>) {
+        self.mask = mask
+    }
+
+    /// 使用分块的单趟实现，不构造 `[batch, nh, n_seq, n_seq]` 的 preatt 和 att，
+    /// 反向时重算注意力权重，参见 [`flash_attention::forward`]。
+    pub fn flash(&mut self, enabled: bool) {
+        self.flash = enabled
+    }
+
+    /// 设置 KV 缓存，`None` 表示不缓存。缓存只用于推理，开启时不能反向，
+    /// 只支持因果注意力，可以使用 ALiBi 和分组查询。
+    pub fn kv_cache(&mut self, cache: Option<KvCache>) {
+        self.cache = cache
+    }
+
+    /// 当前的 KV 缓存。
+    pub fn cache(&self) -> Option<&KvCache> {
+        self.cache.as_ref()
+    }
+
+    pub fn cache_mut(&mut self) -> Option<&mut KvCache> {
+        self.cache.as_mut()
+    }
+
+    pub fn take_cache(&mut self) -> Option<KvCache> {
+        self.cache.take()
+    }
+
+    /// 新位置的 K、V 追加到各行序列的缓存，每个查询对它所在序列中它之前的所有位置做注意力。
+    ///
+    /// 有第二个输入时这是共享 KV 的层，缓存借自源层，源层已经追加了新位置，只读不写。
+    fn forward_cached(&mut self, ctx: &mut Context) -> Rc<Tensor> {
+        let Self {
+            nh,
+            nkvh,
+            alibi,
+            cache,
+            x,
+            kv,
+            ..
+        } = self;
+        assert!(
+            self.rel_bias.is_none()
+                && self.sparse.is_none()
+                && self.prefix == 0
+                && self.lens.is_none()
+                && self.mask.is_none(),
+            "KV cache only supports causal attention with ALiBi"
+        );
+        let shared = kv.is_some();
+        let compensated = ctx.is_compensated_sum();
+        let cache = cache.as_mut().unwrap();
+        let x = x.as_ref().unwrap();
+        dims!([batch_size, n_seq, d3] = x);
+        let nkvh = nkvh.unwrap_or(*nh);
+        let d = d3 * *nh / (*nh + 2 * nkvh);
+        let dh = d / *nh;
+        let d_kv = nkvh * dh;
+
+        if cache.active.is_empty() {
+            cache.active = (0..batch_size).map(|_| cache.add_sequence()).collect()
+        }
+        assert_eq!(
+            cache.active.len(),
+            batch_size,
+            "batch size does not match the selected sequences"
+        );
+        // 共享的缓存中已有新位置
+        let n_new = if shared { 0 } else { n_seq };
+        cache.alloc(x.dt(), nkvh, dh, batch_size);
+        // 为新位置分配块
+        for i in 0..batch_size {
+            let id = cache.active[i];
+            cache.reserve(id, cache.seq_len(id) + n_new)
+        }
+        let KvCache {
+            block_size,
+            kv,
+            scales,
+            seqs,
+            active,
+            ..
+        } = cache;
+        let block_size = *block_size;
+        let [k, v] = kv.as_ref().unwrap();
+        let scales = scales.as_ref().map(|[k, v]| [k, v]);
+        let seqs = active
+            .iter()
+            .map(|&id| seqs[id].as_ref().unwrap())
+            .collect::<Vec<_>>();
+
+        let y = ctx.tensor(x.dt(), &[batch_size, n_seq, d]);
+        ctx.bench(|| {
+            // 追加新位置的 K、V，跨越块边界时分段写入
+            for (b, seq) in seqs.iter().enumerate() {
+                let mut t = 0;
+                while t < n_new {
+                    let pos = seq.len + t;
+                    let (block, row) = (seq.table[pos / block_size], pos % block_size);
+                    let n = (block_size - row).min(n_new - t);
+                    for (i, (cache, start)) in [(&*k, d), (&*v, d + d_kv)].into_iter().enumerate() {
+                        let dst = cache.cloned().index(&[block]).slice(0, row, n);
+                        let src = x
+                            .cloned()
+                            .index(&[b])
+                            .slice(0, t, n)
+                            .slice(1, start, d_kv)
+                            .tile(1, &[nkvh, dh]);
+                        match scales {
+                            Some(scales) => {
+                                let scale = scales[i].cloned().index(&[block]).slice(0, row, n);
+                                quant_heads(&dst, &scale, &src)
+                            }
+                            None => rearrange(&dst, &src),
+                        }
+                    }
+                    t += n
+                }
+            }
+            // 单个新词走解码路径，多个新词一次预填充
+            let q = x.cloned().slice(2, 0, d).tile(2, &[*nh, dh]);
+            let y = y.cloned().tile(2, &[*nh, dh]);
+            let pages = seqs
+                .iter()
+                .map(|seq| Paged {
+                    k,
+                    v,
+                    scales,
+                    table: &seq.table,
+                    len: seq.len + n_new,
+                })
+                .collect::<Vec<_>>();
+            if n_seq == 1 {
+                let [q, y] = [q, y].map(|t| t.merge(1, 2));
+                decode(&y, &q, &pages, alibi.as_deref(), compensated)
+            } else {
+                prefill(&y, &q, &pages, alibi.as_deref(), compensated)
+            }
+        });
+        for &id in &*active {
+            cache.seqs[id].as_mut().unwrap().len += n_new
+        }
+        y.share()
+    }
+
+    /// 最近一次前向的 qkv 输入，供后续层共享 K、V。
+    pub fn qkv(&self) -> Option<&Rc<Tensor>> {
+        self.x.as_ref()
+    }
 }
 
 impl NeuralNetwork for Attention {
@@ -18,8 +559,20 @@ impl NeuralNetwork for Attention {
     fn init(init: Self::Init, _ctx: &mut Context) -> Self {
         Self {
             nh: init,
+            rel_bias: None,
+            alibi: None,
+            sparse: None,
+            prefix: 0,
+            nkvh: None,
+            lens: None,
+            mask: None,
+            flash: false,
+            cache: None,
             x: None,
+            kv: None,
             att: None,
+            y: None,
+            lse: None,
         }
     }
 
@@ -28,19 +581,61 @@ impl NeuralNetwork for Attention {
         inputs: impl IntoIterator<Item = Rc<Tensor>>,
         ctx: &mut Context,
     ) -> Vec<Rc<Tensor>> {
-        destruct!([x] = inputs);
-        self.x.replace(x);
-        let Self { nh, x, .. } = self;
+        // 可选的第二个输入是其他层的 qkv，使用其中的 K、V
+        let mut inputs = inputs.into_iter();
+        self.x = inputs.next();
+        self.kv = inputs.next();
+        assert!(inputs.next().is_none());
+        if self.cache.is_some() {
+            return vec![self.forward_cached(ctx)];
+        }
+        let Self {
+            nh,
+            rel_bias,
+            alibi,
+            sparse,
+            prefix,
+            nkvh,
+            lens,
+            mask,
+            flash,
+            x,
+            kv,
+            ..
+        } = self;
 
         let x = x.as_ref().unwrap();
         dims!([batch_size, n_seq, d3] = x);
 
-        let d = d3 / 3;
+        // qkv 的宽度为 (nh + 2 * nkvh) * dh
+        let d = d3 * *nh / (*nh + 2 * nkvh.unwrap_or(*nh));
         let y = ctx.tensor_zeroed(x.dt(), &[batch_size, n_seq, d]);
+
+        let config = AttentionConfig {
+            bias: rel_bias.as_ref().map(|(table, max_distance)| RelativeBias {
+                table,
+                max_distance: *max_distance,
+            }),
+            alibi: alibi.as_deref(),
+            sparse: *sparse,
+            prefix: *prefix,
+            nkvh: *nkvh,
+            lens: lens.as_deref(),
+            mask: mask.as_deref(),
+            compensated: ctx.is_compensated_sum(),
+        };
+        if *flash {
+            let lse = ctx.tensor(types::F32, &[batch_size, *nh, n_seq]);
+            ctx.bench(|| flash_attention::forward(&y, &lse, x, kv.as_deref(), &config));
+            let y = y.share();
+            self.y.replace(y.clone());
+            self.lse.replace(lse);
+            return vec![y];
+        }
+
         let preatt = ctx.tensor_zeroed(x.dt(), &[batch_size, *nh, n_seq, n_seq]);
         let att = ctx.tensor_zeroed(x.dt(), &[batch_size, *nh, n_seq, n_seq]);
-
-        ctx.bench(|| forward(&y, &preatt, &att, x));
+        ctx.bench(|| forward(&y, &preatt, &att, x, kv.as_deref(), &config));
 
         self.att.replace(att);
 
@@ -53,17 +648,84 @@ impl NeuralNetwork for Attention {
         ctx: &mut Context,
     ) -> Vec<Rc<Tensor>> {
         destruct!([dy] = inputs);
-        let Self { x, att, .. } = self;
+        assert!(self.cache.is_none(), "KV cache is for inference only");
+        let Self {
+            rel_bias,
+            alibi,
+            sparse,
+            prefix,
+            nkvh,
+            lens,
+            mask,
+            x,
+            kv,
+            att,
+            y,
+            lse,
+            ..
+        } = self;
 
         let x = x.take().unwrap();
-        let dx = ctx.tensor_zeroed(x.dt(), &x.shape());
+        let dx = ctx.tensor_zeroed_like(&x);
+
+        let kv = kv.take();
+        let dkv = kv.as_ref().map(|kv| ctx.tensor_zeroed_like(kv));
+
+        // 分块实现重算权重，需要与前向相同的配置，偏置表的梯度单独传入
+        if let Some(lse) = lse.take() {
+            let y = y.take().unwrap();
+            let dtable = rel_bias
+                .as_ref()
+                .map(|(table, _)| ctx.write_gradient("rel_bias", table));
+            let config = AttentionConfig {
+                bias: rel_bias.as_ref().map(|(table, max_distance)| RelativeBias {
+                    table,
+                    max_distance: *max_distance,
+                }),
+                alibi: alibi.as_deref(),
+                sparse: *sparse,
+                prefix: *prefix,
+                nkvh: *nkvh,
+                lens: lens.as_deref(),
+                mask: mask.as_deref(),
+                compensated: ctx.is_compensated_sum(),
+            };
+            let kv = kv.as_deref().zip(dkv.as_ref());
+            ctx.bench(|| {
+                flash_attention::backward(&dx, &dy, &x, kv, &y, &lse, &config, dtable.as_deref())
+            });
+            return [Some(dx), dkv]
+                .into_iter()
+                .flatten()
+                .map(Tensor::share)
+                .collect();
+        }
 
         let att = att.take().unwrap();
-        let dpreatt = ctx.tensor_zeroed(att.dt(), &att.shape());
-        let datt = ctx.tensor_zeroed(att.dt(), &att.shape());
+        let dpreatt = ctx.tensor_zeroed_like(&att);
+        let datt = ctx.tensor_zeroed_like(&att);
 
-        ctx.bench(|| backward(&dx, &dpreatt, &datt, &dy, &x, &att));
+        let dtable = rel_bias
+            .as_ref()
+            .map(|(table, max_distance)| (ctx.write_gradient("rel_bias", table), *max_distance));
+        let config = AttentionConfig {
+            bias: dtable.as_ref().map(|(table, max_distance)| RelativeBias {
+                table,
+                max_distance: *max_distance,
+            }),
+            prefix: *prefix,
+            nkvh: *nkvh,
+            lens: lens.as_deref(),
+            ..Default::default()
+        };
+        let kv = kv.as_deref().zip(dkv.as_ref());
+        ctx.bench(|| backward(&dx, &dpreatt, &datt, &dy, &x, kv, &att, &config));
 
-        vec![dx.share()]
+        // 共享 K、V 时额外返回其梯度
+        [Some(dx), dkv]
+            .into_iter()
+            .flatten()
+            .map(Tensor::share)
+            .collect()
     }
 }
@@ -0,0 +1,65 @@
+use super::{NeuralNetwork, Tensor};
+use crate::{
+    Context,
+    macros::*,
+    op::conv1d::{Conv1dArgs, backward, forward},
+};
+use std::rc::Rc;
+
+/// 序列维的一维卷积，输入输出都是 `[batch, n_seq, channels]`。
+pub struct Conv1d {
+    w: Rc<Tensor>,
+    b: Option<Rc<Tensor>>,
+    args: Conv1dArgs,
+    x: Option<Rc<Tensor>>,
+}
+
+impl NeuralNetwork for Conv1d {
+    type Init = (Rc<Tensor>, Option<Rc<Tensor>>, Conv1dArgs);
+
+    fn init(init: Self::Init, _ctx: &mut Context) -> Self {
+        let (weight, bias, args) = init;
+        Self {
+            w: weight,
+            b: bias,
+            args,
+            x: None,
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([x] = inputs);
+        self.x.replace(x);
+        let Self { w, b, args, x } = self;
+
+        let x = x.as_deref().unwrap();
+        dims!([batch_size, n_seq, _] = x);
+        dims!([c_out, _, kernel] = w);
+        let y = ctx.tensor(x.dt(), &[batch_size, args.out_len(n_seq, kernel), c_out]);
+
+        ctx.bench(|| forward(&y, x, w, b.as_deref(), *args));
+
+        vec![y.share()]
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([dy] = inputs);
+        let Self { w, b, args, x } = self;
+
+        let x = x.take().unwrap();
+        let dw = ctx.write_gradient("w", w);
+        let dx = ctx.tensor_zeroed_like(&x);
+        let db = b.as_ref().map(|b| ctx.write_gradient("b", b));
+        ctx.bench(|| backward(&dx, &dw, db.as_deref(), &dy, &x, w, *args));
+
+        vec![dx.share()]
+    }
+}
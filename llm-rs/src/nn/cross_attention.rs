@@ -0,0 +1,68 @@
+use super::{NeuralNetwork, Tensor};
+use crate::{
+    Context,
+    macros::*,
+    op::cross_attention::{backward, forward},
+};
+use std::rc::Rc;
+
+/// 交叉注意力，输入为查询 `q` 和上下文的 `kv`，参见 [`crate::op::cross_attention::forward`]。
+///
+/// 反向依次返回 `dq` 和 `dkv`，`dkv` 交给产生上下文的编码器或检索模块继续反向。
+pub struct CrossAttention {
+    nh: usize,
+    q: Option<Rc<Tensor>>,
+    kv: Option<Rc<Tensor>>,
+    att: Option<Tensor>,
+}
+
+impl NeuralNetwork for CrossAttention {
+    type Init = usize;
+
+    fn init(init: Self::Init, _ctx: &mut Context) -> Self {
+        Self {
+            nh: init,
+            q: None,
+            kv: None,
+            att: None,
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([q, kv] = inputs);
+        dims!([batch_size, n_q, _] = q);
+        dims!([_, n_kv, _] = kv);
+
+        let y = ctx.tensor_like(&q);
+        let att = ctx.tensor(q.dt(), &[batch_size, self.nh, n_q, n_kv]);
+        let compensated = ctx.is_compensated_sum();
+        ctx.bench(|| forward(&y, &att, &q, &kv, compensated));
+
+        self.q.replace(q);
+        self.kv.replace(kv);
+        self.att.replace(att);
+
+        vec![y.share()]
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([dy] = inputs);
+        let q = self.q.take().unwrap();
+        let kv = self.kv.take().unwrap();
+        let att = self.att.take().unwrap();
+
+        let dq = ctx.tensor_zeroed_like(&q);
+        let dkv = ctx.tensor_zeroed_like(&kv);
+        ctx.bench(|| backward(&dq, &dkv, &dy, &q, &kv, &att));
+
+        vec![dq.share(), dkv.share()]
+    }
+}
@@ -0,0 +1,124 @@
+use super::{NeuralNetwork, Tensor};
+use crate::Context;
+use std::rc::Rc;
+
+/// 自定义算子的前向：输入为本次的输入，返回输出。
+pub type CustomForward = dyn Fn(&[Rc<Tensor>], &mut Context) -> Vec<Rc<Tensor>>;
+/// 自定义算子的反向：输入为前向时的输入和输出的梯度，返回输入的梯度。
+///
+/// 算子持有的权重可以通过 [`Context::write_gradient`] 累加梯度。
+pub type CustomBackward = dyn Fn(&[Rc<Tensor>], &[Rc<Tensor>], &mut Context) -> Vec<Rc<Tensor>>;
+
+/// 注册在 [`Context`] 中的自定义算子。
+#[derive(Clone)]
+pub struct CustomOp {
+    pub forward: Rc<CustomForward>,
+    pub backward: Rc<CustomBackward>,
+}
+
+impl CustomOp {
+    pub fn new(
+        forward: impl Fn(&[Rc<Tensor>], &mut Context) -> Vec<Rc<Tensor>> + 'static,
+        backward: impl Fn(&[Rc<Tensor>], &[Rc<Tensor>], &mut Context) -> Vec<Rc<Tensor>> + 'static,
+    ) -> Self {
+        Self {
+            forward: Rc::new(forward),
+            backward: Rc::new(backward),
+        }
+    }
+}
+
+/// 按名字调用 [`Context::register_op`] 注册的算子，像内置模块一样参与前向和反向。
+pub struct Custom {
+    op: CustomOp,
+    inputs: Option<Vec<Rc<Tensor>>>,
+}
+
+impl NeuralNetwork for Custom {
+    type Init = String;
+
+    fn init(init: Self::Init, ctx: &mut Context) -> Self {
+        let Some(op) = ctx.custom_op(&init) else {
+            panic!("custom op \"{init}\" is not registered")
+        };
+        Self { op, inputs: None }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let inputs = self.inputs.insert(inputs.into_iter().collect());
+        (self.op.forward)(inputs, ctx)
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let dy = inputs.into_iter().collect::<Vec<_>>();
+        let x = self.inputs.take().unwrap();
+        (self.op.backward)(&x, &dy, ctx)
+    }
+}
+
+#[test]
+fn test_custom_op() {
+    use crate::Blob;
+    use digit_layout::types;
+    use rw_rc::RwRc;
+
+    // 长度为 1 的张量不能 merge，直接读写整个缓冲区
+    let vector = |t: &Tensor| {
+        let t = t.cloned();
+        let ([], data, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        data.to_vec()
+    };
+    let tensor = |data: &[f32]| {
+        let t = crate::Tensor::new(types::F32, &[data.len()])
+            .map(Blob::new)
+            .map(RwRc::new);
+        let t_ = t.cloned();
+        let ([], buf, []) = (unsafe { t_.get().write().align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        buf.copy_from_slice(data);
+        t.share()
+    };
+
+    // y = s * x，s 是算子持有的权重
+    let s = tensor(&[3.]);
+    let s_ = s.clone();
+    let s__ = s.clone();
+    let op = CustomOp::new(
+        move |x, _ctx| {
+            let s = vector(&s_)[0];
+            let y = vector(&x[0]).iter().map(|x| s * x).collect::<Vec<_>>();
+            vec![tensor(&y)]
+        },
+        move |x, dy, ctx| {
+            let s = vector(&s__)[0];
+            let (x, dy) = (vector(&x[0]), vector(&dy[0]));
+            let ds = ctx.write_gradient("s", &s__).cloned();
+            let ([], ds, []) = (unsafe { ds.get().write().align_to_mut::<f32>() }) else {
+                unreachable!()
+            };
+            ds[0] += x.iter().zip(&dy).map(|(x, dy)| x * dy).sum::<f32>();
+            vec![tensor(&dy.iter().map(|dy| s * dy).collect::<Vec<_>>())]
+        },
+    );
+
+    let mut ctx = Context::new(false);
+    ctx.register_op("scale", op);
+    let mut scale = ctx.init::<Custom>("scale", "scale".into());
+    let y = ctx.forward("scale", &mut scale, [tensor(&[1., 2.])]);
+    assert_eq!(vector(&y[0]), [3., 6.]);
+    let dx = ctx.backward("scale", &mut scale, [tensor(&[1., 1.])]);
+    assert_eq!(vector(&dx[0]), [3., 3.]);
+    let ds = ctx.write_gradient("s", &s);
+    assert_eq!(vector(&ds), [3.])
+}
@@ -0,0 +1,106 @@
+use super::{NeuralNetwork, Tensor};
+use crate::{
+    Context,
+    macros::*,
+    op::dropout::{backward, forward},
+};
+use std::rc::Rc;
+
+/// 训练时以概率 `p` 随机置 0，随机数来自 [`Context::rng`]；评估模式下原样输出。
+pub struct Dropout {
+    p: f32,
+    mask: Option<Tensor>,
+}
+
+impl NeuralNetwork for Dropout {
+    type Init = f32;
+
+    fn init(init: Self::Init, _ctx: &mut Context) -> Self {
+        assert!((0. ..1.).contains(&init));
+        Self {
+            p: init,
+            mask: None,
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([x] = inputs);
+        let p = self.p;
+        if !ctx.is_training() || p == 0. {
+            self.mask = None;
+            return vec![x];
+        }
+
+        let y = ctx.tensor_like(&x);
+        let mask = ctx.tensor_like(&x);
+        ctx.bench(|| forward(&y, &mask, &x, p, &mut *ctx.rng()));
+
+        self.mask.replace(mask);
+        vec![y.share()]
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([dy] = inputs);
+        // 前向没有生效时梯度原样传回
+        let Some(mask) = self.mask.take() else {
+            return vec![dy];
+        };
+
+        let dx = ctx.tensor_zeroed_like(&dy);
+        ctx.bench(|| backward(&dx, &dy, &mask));
+
+        vec![dx.share()]
+    }
+}
+
+#[test]
+fn test_dropout() {
+    use crate::Blob;
+    use digit_layout::types;
+    use rw_rc::RwRc;
+
+    let n = 1000;
+    let x = crate::Tensor::new(types::F32, &[1, n])
+        .map(|_| Blob::from(&*vec![1f32; n]))
+        .map(RwRc::new)
+        .share();
+    let values = |t: &Rc<Tensor>| {
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+
+    let mut ctx = Context::new(false);
+    let mut dropout = ctx.init::<Dropout>("dropout", 0.25);
+    let run = |ctx: &mut Context, dropout: &mut Dropout| {
+        let y = ctx.forward("dropout", dropout, [x.clone()]);
+        let y = values(&y[0]);
+        let dx = ctx.backward("dropout", dropout, [x.clone()]);
+        assert_eq!(values(&dx[0]), y);
+        y
+    };
+
+    let y = run(&mut ctx, &mut dropout);
+    let dropped = y.iter().filter(|&&y| y == 0.).count();
+    assert!((150..350).contains(&dropped), "{dropped}");
+    assert!(y.iter().all(|&y| y == 0. || y == 1. / 0.75));
+
+    // 同一种子得到相同的掩码
+    ctx.seed(0);
+    assert_eq!(run(&mut ctx, &mut dropout), y);
+
+    ctx.set_training(false);
+    assert!(Rc::ptr_eq(
+        &ctx.forward("dropout", &mut dropout, [x.clone()])[0],
+        &x
+    ))
+}
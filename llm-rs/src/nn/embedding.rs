@@ -2,25 +2,80 @@ use super::{NeuralNetwork, Tensor};
 use crate::{
     Context,
     macros::*,
-    op::embedding::{BatchIter, backward, build_pos, forward},
+    op::embedding::{BatchIter, backward, build_pos, forward, pos_dt, read_indices},
 };
 use digit_layout::types;
+use rw_rc::RwRc;
 use std::rc::Rc;
 
+/// 词嵌入加可选的可学习位置嵌入，使用旋转位置编码的模型没有位置嵌入表。
 pub struct Embedding {
     te: Rc<Tensor>,
-    pe: Rc<Tensor>,
+    pe: Option<Rc<Tensor>>,
+    sparse: bool,
+    offsets: Box<[usize]>,
     tokens: Option<Rc<Tensor>>,
 }
 
+impl Embedding {
+    /// 词嵌入表只为本批出现的词分配梯度，见 [`Context::write_sparse_gradient`]。
+    ///
+    /// 词嵌入表与输出头共享时输出头已经写入完整梯度，这一设置不起作用。
+    pub fn sparse_gradient(&mut self, sparse: bool) {
+        self.sparse = sparse
+    }
+
+    /// 输入的第一个词的位置号，增量解码时为已缓存的词数，对之后的前向和反向生效。
+    pub fn position_offset(&mut self, offset: usize) {
+        self.offsets = Box::new([offset])
+    }
+
+    /// 每行各自的第一个词的位置号，长度为批大小，用于批中的序列已缓存的词数不同时。
+    pub fn position_offsets(&mut self, offsets: &[usize]) {
+        self.offsets = offsets.into()
+    }
+
+    /// 第 `b` 行的位置偏移。
+    fn offset(offsets: &[usize], b: usize) -> usize {
+        match offsets {
+            [] => 0,
+            [offset] => *offset,
+            _ => offsets[b],
+        }
+    }
+
+    /// 每个词的位置号。
+    fn positions(
+        pe: &Tensor,
+        batch_size: usize,
+        n_seq: usize,
+        offsets: &[usize],
+        ctx: &Context,
+    ) -> Tensor {
+        dims!([n_ctx, _] = pe);
+        let mut pos = ctx.tensor(pos_dt(n_ctx), &[batch_size * n_seq]);
+        build_pos(
+            pos.get_mut().clone().write(),
+            pos.dt(),
+            BatchIter::new(batch_size, n_seq)
+                .enumerate()
+                .map(|(i, t)| t + Self::offset(offsets, i / n_seq)),
+        );
+        pos
+    }
+}
+
 impl NeuralNetwork for Embedding {
-    type Init = [Rc<Tensor>; 2];
+    /// 词嵌入表和可选的位置嵌入表。
+    type Init = (Rc<Tensor>, Option<Rc<Tensor>>);
 
     fn init(init: Self::Init, _ctx: &mut Context) -> Self {
-        let [te, pe] = init;
+        let (te, pe) = init;
         Self {
             te,
             pe,
+            sparse: false,
+            offsets: Box::new([]),
             tokens: None,
         }
     }
@@ -32,22 +87,37 @@ impl NeuralNetwork for Embedding {
     ) -> Vec<Rc<Tensor>> {
         destruct!([tokens] = inputs);
         self.tokens.replace(tokens);
-        let Self { te, pe, tokens } = self;
+        let Self {
+            te,
+            pe,
+            offsets,
+            tokens,
+            ..
+        } = self;
         let tokens = tokens.as_ref().unwrap();
 
         dims!([batch_size, n_seq] = tokens);
+        assert!(offsets.len() <= 1 || offsets.len() == batch_size);
+        if let Some(pe) = pe {
+            dims!([n_ctx, _] = pe);
+            let offset = offsets.iter().copied().max().unwrap_or(0);
+            assert!(
+                offset + n_seq <= n_ctx,
+                "sequence length {} exceeds n_ctx {n_ctx}, truncate the input first",
+                offset + n_seq
+            )
+        }
 
         dims!([_, d] = te);
         let y = ctx.tensor(te.dt(), &[batch_size, n_seq, d]);
 
         let i1 = tokens.cloned().merge(0, 2);
-        let mut i2 = ctx.tensor(types::U16, &[batch_size * n_seq]);
-        build_pos(
-            i2.get_mut().clone().write(),
-            BatchIter::new(batch_size, n_seq),
-        );
+        let i2 = pe
+            .as_ref()
+            .map(|pe| Self::positions(pe, batch_size, n_seq, offsets, ctx));
+        let pos = i2.as_ref().zip(pe.as_deref());
 
-        ctx.bench(|| forward::embedding(&y.clone().merge(0, 2), &i1, &i2, te, pe));
+        ctx.bench(|| forward::embedding(&y.clone().merge(0, 2), &i1, te, pos));
 
         vec![y.share()]
     }
@@ -58,29 +128,111 @@ impl NeuralNetwork for Embedding {
         ctx: &mut Context,
     ) -> Vec<Rc<Tensor>> {
         destruct!([dy] = inputs);
-        let Self { te, pe, tokens } = self;
-
-        let dtable1 = ctx.write_gradient("wte", te);
-        let dtable2 = ctx.write_gradient("wpe", pe);
+        let Self {
+            te,
+            pe,
+            sparse,
+            offsets,
+            tokens,
+        } = self;
 
-        let i1 = tokens.take().unwrap();
+        let mut i1 = tokens.take().unwrap();
+        let dtable1 = if *sparse {
+            let tokens = read_indices(&i1);
+            let mut rows = tokens.clone();
+            rows.sort_unstable();
+            rows.dedup();
+            let (dtable, rows) = ctx.write_sparse_gradient("wte", te, &rows);
+            // 梯度只含出现过的行，词号换成在这些行中的位置
+            if let Some(rows) = rows {
+                let pos = tokens
+                    .iter()
+                    .map(|t| rows.binary_search(t).unwrap() as u32)
+                    .collect::<Vec<_>>();
+                i1 = Rc::new(
+                    crate::Tensor::new(types::U32, &i1.shape()).map(|_| RwRc::new((&*pos).into())),
+                )
+            }
+            dtable
+        } else {
+            ctx.write_gradient("wte", te)
+        };
         dims!([batch_size, n_seq] = i1);
-        let mut i2 = ctx.tensor(types::U16, &[batch_size * n_seq]);
-        build_pos(
-            i2.get_mut().clone().write(),
-            BatchIter::new(batch_size, n_seq),
-        );
+        let pos = pe.as_ref().map(|pe| {
+            (
+                Self::positions(pe, batch_size, n_seq, offsets, ctx),
+                ctx.write_gradient("wpe", pe),
+            )
+        });
 
         ctx.bench(|| {
             backward::embedding(
                 &dtable1,
-                &dtable2,
                 &dy.cloned().merge(0, 2),
                 &i1.cloned().merge(0, 2),
-                &i2,
+                pos.as_ref().map(|(i2, dtable2)| (i2, &**dtable2)),
             )
         });
 
         vec![]
     }
 }
+
+#[test]
+fn test_sparse_gradient() {
+    use crate::{Blob, optimizer::AdamW};
+
+    let [n_voc, n_ctx, d] = [16, 4, 3];
+    let tensor = |shape: &[usize], f: fn(usize) -> f32| {
+        let len = shape.iter().product::<usize>();
+        let data = (0..len).map(f).collect::<Vec<_>>();
+        crate::Tensor::new(types::F32, shape)
+            .map(|_| Blob::from(&*data))
+            .map(RwRc::new)
+            .share()
+    };
+    let tokens = |data: &[u16]| {
+        crate::Tensor::new(types::U16, &[1, data.len()])
+            .map(|_| Blob::from(data))
+            .map(RwRc::new)
+            .share()
+    };
+    let values = |t: &Rc<Tensor>| {
+        let t = t.cloned();
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+
+    let run = |sparse: bool| {
+        let te = tensor(&[n_voc, d], |i| (i % 7) as f32 / 7.);
+        let pe = tensor(&[n_ctx, d], |i| i as f32 / 10.);
+        let mut ctx = Context::new(false);
+        let mut embedding = ctx.init::<Embedding>("embedding", (te.clone(), Some(pe.clone())));
+        embedding.sparse_gradient(sparse);
+        // 两个微批次累积梯度，第二批引入新的行
+        for batch in [[3, 5, 3, 9], [9, 1, 5, 12]] {
+            let tokens = tokens(&batch);
+            ctx.forward("embedding", &mut embedding, [tokens]);
+            let dy = tensor(&[1, 4, d], |i| i as f32 - 5.);
+            ctx.backward("embedding", &mut embedding, [dy]);
+        }
+        if sparse {
+            let (grad, rows) = ctx.write_sparse_gradient("wte", &te, &[]);
+            assert_eq!(&*rows.unwrap(), [1, 3, 5, 9, 12]);
+            assert_eq!(&*grad.shape(), [5, d]);
+        }
+        let mut adamw = AdamW::new(1e-2, 0.9, 0.999, 1e-8, 0.1);
+        ctx.update(&mut adamw);
+        ctx.update(&mut adamw);
+        (values(&te), values(&pe))
+    };
+
+    let (te_dense, pe_dense) = run(false);
+    let (te_sparse, pe_sparse) = run(true);
+    assert_eq!(pe_sparse, pe_dense);
+    for (i, (s, d)) in te_sparse.iter().zip(&te_dense).enumerate() {
+        assert!((s - d).abs() < 1e-6, "te[{i}]: {s} vs {d}")
+    }
+}
@@ -2,19 +2,23 @@ use super::{NeuralNetwork, Tensor};
 use crate::{
     Context,
     macros::*,
-    op::gelu::{backward, forward},
+    op::gelu::{Approx, backward, forward},
 };
 use std::rc::Rc;
 
 pub struct Gelu {
+    approx: Approx,
     x: Option<Rc<Tensor>>,
 }
 
 impl NeuralNetwork for Gelu {
-    type Init = ();
+    type Init = Approx;
 
-    fn init(_init: Self::Init, _ctx: &mut Context) -> Self {
-        Self { x: None }
+    fn init(init: Self::Init, _ctx: &mut Context) -> Self {
+        Self {
+            approx: init,
+            x: None,
+        }
     }
 
     fn forward(
@@ -24,12 +28,12 @@ impl NeuralNetwork for Gelu {
     ) -> Vec<Rc<Tensor>> {
         destruct!([x] = inputs);
         self.x.replace(x);
-        let Self { x } = self;
+        let Self { approx, x } = self;
 
         let x = x.as_ref().unwrap();
-        let y = ctx.tensor(x.dt(), &x.shape());
+        let y = ctx.tensor_like(x);
 
-        ctx.bench(|| forward::gelu(&y.clone().merge(0, 2), &x.cloned().merge(0, 2)));
+        ctx.bench(|| forward::gelu(&y.clone().merge(0, 2), &x.cloned().merge(0, 2), *approx));
 
         vec![y.share()]
     }
@@ -40,16 +44,17 @@ impl NeuralNetwork for Gelu {
         ctx: &mut Context,
     ) -> Vec<Rc<Tensor>> {
         destruct!([dy] = inputs);
-        let Self { x } = self;
+        let Self { approx, x } = self;
 
         let x = x.take().unwrap();
-        let dx = ctx.tensor_zeroed(x.dt(), &x.shape());
+        let dx = ctx.tensor_zeroed_like(&x);
 
         ctx.bench(|| {
             backward::gelu(
                 &dx.clone().merge(0, 2),
                 &x.cloned().merge(0, 2),
                 &dy.cloned().merge(0, 2),
+                *approx,
             )
         });
 
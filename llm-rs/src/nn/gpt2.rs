@@ -1,10 +1,30 @@
 ﻿use super::{
-    NeuralNetwork, Tensor, embedding::Embedding, gpt2_blk::Gpt2Blk, layer_norm::LayerNorm,
-    linear::Linear,
+    NeuralNetwork, Tensor, attention::KvCache, embedding::Embedding, gpt2_blk::Gpt2Blk,
+    layer_norm::LayerNorm, linear::Linear,
 };
-use crate::{Blob, Context, llmc};
+use crate::{
+    Blob, Context, DryRun, llmc,
+    macros::*,
+    op::{
+        add::{add, axpby},
+        attention::SparsePattern,
+    },
+    prefetch::Region,
+};
+use digit_layout::types;
+use rand::Rng;
 use rw_rc::RwRc;
-use std::rc::Rc;
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    iter::zip,
+    path::Path,
+    rc::Rc,
+};
+
+/// KV 缓存会话文件的开头。
+const SESSION_MAGIC: &[u8; 8] = b"llmrs-kv";
 
 const EMBEDDING: &str = "embedding";
 
@@ -16,41 +36,327 @@ fn BLK(i: usize) -> String {
 const OUTPUT_NORM: &str = "output_norm";
 const LM_HEAD: &str = "lm_head";
 
+#[allow(non_snake_case)]
+fn MTP(i: usize) -> String {
+    format!("mtp[{i}]")
+}
+
 pub struct Gpt2 {
     embedding: Embedding,
     blks: Box<[Gpt2Blk]>,
+    kv_share: Box<[Option<usize>]>,
+    /// 随机深度中各层的保留概率，为空时不启用。
+    survival: Box<[f32]>,
+    /// 最近一次前向中各层的处理方式，供反向使用。
+    depth: Box<[Depth]>,
+    output_norm: LayerNorm,
+    lm_head: Linear,
+    mtp: Box<[MtpHead]>,
+    hidden: bool,
+    n_ctx: usize,
+    /// 各层内存映射的权重，计算一层之前预取下一层。
+    prefetch: Box<[Box<[Region]>]>,
+}
+
+/// 随机深度中一层在前向时的处理方式。
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Depth {
+    /// 正常计算。
+    Kept,
+    /// 整层跳过，输出即输入。
+    Skipped,
+    /// 输出的变化按保留概率缩放。
+    Scaled(f32),
+}
+
+/// 多词预测辅助头：独立的 Transformer 块，与主干共享输出归一化和输出头的权重。
+struct MtpHead {
+    blk: Gpt2Blk,
     output_norm: LayerNorm,
     lm_head: Linear,
 }
 
+impl Gpt2 {
+    /// 所有层使用稀疏注意力模式。
+    pub fn sparse_attention(&mut self, pattern: SparsePattern) {
+        for blk in &mut self.blks {
+            blk.sparse_attention(pattern)
+        }
+    }
+
+    /// Prefix-LM：所有层（包括多词预测头）的前 `n_prefix` 个位置双向可见，
+    /// 配合 [`super::loss::Loss::prefix`] 使前缀不计入损失。
+    pub fn prefix_lm(&mut self, n_prefix: usize) {
+        for blk in &mut self.blks {
+            blk.prefix_lm(n_prefix)
+        }
+        for head in &mut self.mtp {
+            head.blk.prefix_lm(n_prefix)
+        }
+    }
+
+    /// 词嵌入只为本批出现的词计算梯度，见 [`Embedding::sparse_gradient`]。
+    ///
+    /// GPT-2 的输出头与词嵌入共享权重，反向时输出头先写入完整梯度，词嵌入随之退回稠密梯度；
+    /// 只在不共享权重的模型中减少显存。
+    pub fn sparse_embedding_gradient(&mut self, sparse: bool) {
+        self.embedding.sparse_gradient(sparse)
+    }
+
+    /// 空跑一次 `[batch, n_seq]` 的前向，检查各层的形状并估计激活内存，不计算也不分配激活。
+    ///
+    /// 形状不匹配时与实际前向一样 panic，参见 [`Context::dry_run`]。
+    pub fn check(&mut self, ctx: &mut Context, batch: usize, n_seq: usize) -> DryRun {
+        let tokens = crate::Tensor::new(types::U16, &[batch, n_seq])
+            .map(|len| Blob::lazy(len, true))
+            .map(RwRc::new)
+            .share();
+        ctx.dry_run(|ctx| ctx.forward("gpt2", self, [tokens])).1
+    }
+
+    /// 前向输出归一化后的最后一层隐藏状态 `[batch, n_seq, d]`，跳过输出头和多词预测头，
+    /// 供价值头等自定义的输出层使用，反向的输入相应为隐藏状态的梯度。
+    pub fn hidden_states(&mut self, enabled: bool) {
+        self.hidden = enabled
+    }
+
+    /// 开启或关闭各层的 KV 缓存，见 [`KvCache`]，重新开启会丢弃已有的缓存。
+    ///
+    /// 开启后每次前向只输入新词，位置接在已缓存的词之后，只输出主干的 logits，不能反向。
+    /// 共享 KV 的层没有自己的缓存，前向时读取源层的缓存。
+    pub fn kv_cache(&mut self, enabled: bool) {
+        for (blk, src) in zip(&mut self.blks, &self.kv_share) {
+            blk.kv_cache((enabled && src.is_none()).then(|| KvCache::new(self.n_ctx)))
+        }
+        self.embedding.position_offset(0)
+    }
+
+    /// 开启各层的分页 KV 缓存，共 `n_blocks` 个 `block_size` 行的块，见 [`KvCache::paged`]。
+    ///
+    /// 用 [`Self::add_sequence`] 新建序列，前向前用 [`Self::select_sequences`] 指定各行所属的序列，
+    /// 每行的位置接在各自序列已缓存的词之后。
+    pub fn paged_kv_cache(&mut self, block_size: usize, n_blocks: usize) {
+        self.kv_cache(true);
+        for blk in &mut self.blks {
+            if blk.cache().is_some() {
+                blk.kv_cache(Some(KvCache::paged(self.n_ctx, block_size, n_blocks)))
+            }
+        }
+    }
+
+    /// 各层的 KV 缓存以 int8 存储，见 [`KvCache::int8`]，须在开启缓存之后、首次前向之前设置。
+    pub fn int8_kv_cache(&mut self, enabled: bool) {
+        self.caches().for_each(|cache| cache.int8(enabled))
+    }
+
+    /// 各层 KV 缓存的总字节数。
+    pub fn kv_cache_nbytes(&self) -> usize {
+        self.blks
+            .iter()
+            .filter_map(Gpt2Blk::cache)
+            .map(KvCache::nbytes)
+            .sum()
+    }
+
+    /// 在各层的 KV 缓存中新建一个序列，返回序列号。
+    pub fn add_sequence(&mut self) -> usize {
+        let ids = self.caches().map(KvCache::add_sequence).collect::<Vec<_>>();
+        let id = ids.first().copied().expect("KV cache is not enabled");
+        assert!(ids.iter().all(|&i| i == id));
+        id
+    }
+
+    /// 从各层的 KV 缓存中移除序列并归还它的块。
+    pub fn remove_sequence(&mut self, id: usize) {
+        self.caches().for_each(|cache| cache.remove_sequence(id))
+    }
+
+    /// 选择之后的前向中各行对应的序列，见 [`KvCache::select`]。
+    pub fn select_sequences(&mut self, ids: &[usize]) {
+        self.caches().for_each(|cache| cache.select(ids))
+    }
+
+    /// 序列 `id` 已缓存的词数。
+    pub fn sequence_len(&self, id: usize) -> usize {
+        self.blks[0]
+            .cache()
+            .expect("KV cache is not enabled")
+            .seq_len(id)
+    }
+
+    /// 把序列 `id` 在各层的 KV 缓存回退到前 `len` 个词，见 [`KvCache::truncate`]。
+    pub fn truncate_sequence(&mut self, id: usize, len: usize) {
+        self.caches().for_each(|cache| cache.truncate(id, len))
+    }
+
+    /// 把序列 `id` 在各层的 KV 缓存保存到文件，之后可以用 [`Self::load_session`] 恢复，不必重新预填充。
+    pub fn save_session(&self, id: usize, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(SESSION_MAGIC)?;
+        let caches = self.blks.iter().filter_map(Gpt2Blk::cache);
+        w.write_all(&(caches.clone().count() as u64).to_le_bytes())?;
+        for cache in caches {
+            cache.save(id, &mut w)?
+        }
+        w.flush()
+    }
+
+    /// 从 [`Self::save_session`] 保存的文件恢复一个序列，返回它在各层缓存中的序列号。
+    ///
+    /// 文件损坏或空闲块不足时返回错误，各层缓存保持调用前的状态。
+    pub fn load_session(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0; SESSION_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        let mut n_blks = [0; 8];
+        r.read_exact(&mut n_blks)?;
+        if magic != *SESSION_MAGIC || u64::from_le_bytes(n_blks) != self.caches().count() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a KV cache session of this model",
+            ));
+        }
+        let mut caches = self.caches().collect::<Vec<_>>();
+        let mut ids = Vec::with_capacity(caches.len());
+        for i in 0..caches.len() {
+            match caches[i].load(&mut r) {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    // 撤销已经恢复的层，各层的序列保持一致
+                    for (cache, &id) in caches.iter_mut().zip(&ids) {
+                        cache.remove_sequence(id)
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        let id = *ids.first().expect("KV cache is not enabled");
+        assert!(ids.iter().all(|&i| i == id));
+        Ok(id)
+    }
+
+    fn caches(&mut self) -> impl Iterator<Item = &mut KvCache> {
+        self.blks.iter_mut().filter_map(Gpt2Blk::cache_mut)
+    }
+
+    /// 清空各层的 KV 缓存以开始新的序列，保留已分配的缓冲区。
+    pub fn clear_kv_cache(&mut self) {
+        for blk in &mut self.blks {
+            if let Some(cache) = blk.cache_mut() {
+                cache.clear()
+            }
+        }
+    }
+
+    /// 已缓存的词数，没有开启缓存时为 `None`。
+    pub fn cached_len(&self) -> Option<usize> {
+        self.blks.first()?.cache().map(KvCache::len)
+    }
+
+    /// 随机深度：训练时第 `i` 层以概率 `survival[i]` 保留，否则整层跳过，前向和反向都直接传递残差；
+    /// 评估模式或开启 KV 缓存时每层都计算，输出相对输入的变化乘以保留概率，`x + p·(blk(x) - x)`。
+    ///
+    /// 随机数来自 [`Context::rng`]，保留概率常取 [`Self::linear_survival`]，传入空切片关闭。
+    /// 不能与跨层 KV 共享同时使用。
+    pub fn stochastic_depth(&mut self, survival: &[f32]) {
+        assert!(survival.is_empty() || survival.len() == self.blks.len());
+        assert!(survival.iter().all(|p| 0. < *p && *p <= 1.));
+        assert!(
+            survival.is_empty() || self.kv_share.iter().all(Option::is_none),
+            "stochastic depth does not support shared KV"
+        );
+        self.survival = survival.into()
+    }
+
+    /// 随深度线性下降的保留概率：第一层之前为 1，最后一层为 `p_last`。
+    pub fn linear_survival(nblk: usize, p_last: f32) -> Vec<f32> {
+        (1..=nblk)
+            .map(|i| 1. - i as f32 / nblk as f32 * (1. - p_last))
+            .collect()
+    }
+
+    /// YOCO 风格的跨层 KV 共享：第 `blk` 层使用第 `src` 层的 K、V。
+    ///
+    /// 共享层自身 qkv 投影中 K、V 部分的输出不再使用，其梯度为零。
+    pub fn share_kv(&mut self, blk: usize, src: usize) {
+        assert!(src < blk && blk < self.blks.len());
+        assert!(
+            self.survival.is_empty(),
+            "shared KV does not support stochastic depth"
+        );
+        assert!(
+            self.kv_share[src].is_none(),
+            "blk[{src}] does not own its KV"
+        );
+        assert!(
+            !self.kv_share.contains(&Some(blk)),
+            "blk[{blk}] is already shared by other blocks"
+        );
+        self.kv_share[blk] = Some(src);
+        // 开启缓存时共享层改用源层的缓存
+        self.blks[blk].kv_cache(None)
+    }
+}
+
 impl NeuralNetwork for Gpt2 {
     type Init = llmc::Gpt2<RwRc<Blob>>;
 
     fn init(init: Self::Init, ctx: &mut Context) -> Self {
+        let mut prefetch = vec![Vec::new(); init.config.nblk];
+        init.for_each(|name, t| {
+            let blk = name
+                .strip_prefix("blk.")
+                .and_then(|tail| tail.split('.').next())
+                .and_then(|i| i.parse::<usize>().ok());
+            if let Some(i) = blk
+                && let Some(region) = region(t)
+            {
+                prefetch[i].push(region)
+            }
+        });
         let Self::Init {
             config,
             wte,
             wpe,
             blks,
             output_norm,
+            mtp,
         } = init;
 
         let wte = wte.share();
+        let output_norm = output_norm.map(Tensor::share);
 
-        let embedding = ctx.init(EMBEDDING, [wte.clone(), wpe.share()]);
+        let embedding = ctx.init(EMBEDDING, (wte.clone(), Some(wpe.share())));
         let blks = blks
             .into_iter()
             .enumerate()
             .map(|(i, blk)| ctx.init(BLK(i), (blk, config.nh)))
+            .collect::<Box<[Gpt2Blk]>>();
+        let kv_share = vec![None; blks.len()].into();
+        let depth = vec![Depth::Kept; blks.len()].into();
+        let mtp = mtp
+            .into_iter()
+            .enumerate()
+            .map(|(i, blk)| {
+                let init = (blk, config.nh, output_norm.clone(), wte.clone());
+                ctx.init(MTP(i), init)
+            })
             .collect();
-        let output_norm = ctx.init(OUTPUT_NORM, output_norm.map(Tensor::share));
+        let output_norm = ctx.init(OUTPUT_NORM, output_norm);
         let lm_head = ctx.init(LM_HEAD, (wte, None));
 
         Self {
             embedding,
             blks,
+            kv_share,
+            survival: Box::new([]),
+            depth,
             output_norm,
             lm_head,
+            mtp,
+            hidden: false,
+            n_ctx: config.n_seq,
+            prefetch: prefetch.into_iter().map(Into::into).collect(),
         }
     }
 
@@ -62,19 +368,86 @@ impl NeuralNetwork for Gpt2 {
         let Self {
             embedding,
             blks,
+            kv_share,
+            survival,
+            depth,
             output_norm,
             lm_head,
+            mtp,
+            hidden,
+            prefetch,
+            ..
         } = self;
 
-        let x = ctx.forward(EMBEDDING, embedding, inputs);
+        let will_need = |i: usize| {
+            prefetch
+                .get(i)
+                .into_iter()
+                .flatten()
+                .for_each(Region::will_need)
+        };
+        will_need(0);
+        // 使用缓存时新词的位置接在已缓存的词之后
+        let cached = blks.first().and_then(|blk| blk.cache().map(KvCache::lens));
+        if let Some(lens) = &cached {
+            embedding.position_offsets(lens)
+        }
+        let mut x = ctx.forward(EMBEDDING, embedding, inputs);
 
-        let x = blks
-            .iter_mut()
-            .enumerate()
-            .fold(x, |x, (i, blk)| ctx.forward(BLK(i), blk, x));
+        // 只在训练且不使用缓存时跳层，否则缓存会缺少被跳过的词
+        let skip = ctx.is_training() && cached.is_none();
+        for (i, src) in kv_share.iter().enumerate() {
+            will_need(i + 1);
+            depth[i] = match survival.get(i) {
+                Some(&p) if p < 1. && skip => {
+                    if ctx.rng().random::<f32>() < p {
+                        Depth::Kept
+                    } else {
+                        Depth::Skipped
+                    }
+                }
+                Some(&p) if p < 1. => Depth::Scaled(p),
+                _ => Depth::Kept,
+            };
+            match depth[i] {
+                Depth::Kept => match *src {
+                    Some(src) => {
+                        // 使用缓存时借用源层的缓存，前向之后归还
+                        x.push(blks[src].kv());
+                        let cache = blks[src].take_cache();
+                        let lent = cache.is_some();
+                        blks[i].kv_cache(cache);
+                        x = ctx.forward(BLK(i), &mut blks[i], x);
+                        if lent {
+                            let cache = blks[i].take_cache();
+                            blks[src].kv_cache(cache)
+                        }
+                    }
+                    None => x = ctx.forward(BLK(i), &mut blks[i], x),
+                },
+                Depth::Skipped => {}
+                Depth::Scaled(p) => {
+                    let residual = x[0].clone();
+                    x = ctx.forward(BLK(i), &mut blks[i], x);
+                    ctx.bench(|| axpby(&x[0], &residual, p, 1. - p))
+                }
+            }
+        }
 
-        let x = ctx.forward(OUTPUT_NORM, output_norm, x);
-        ctx.forward(LM_HEAD, lm_head, x)
+        // 主干输出之后是各辅助头的输出
+        destruct!([x] = x);
+        let y = ctx.forward(OUTPUT_NORM, output_norm, [x.clone()]);
+        if *hidden {
+            return y;
+        }
+        let mut y = ctx.forward(LM_HEAD, lm_head, y);
+        if cached.is_some() {
+            return y;
+        }
+        for (i, head) in mtp.iter_mut().enumerate() {
+            y.extend(ctx.forward(MTP(i), head, [x.clone()]))
+        }
+        y
     }
 
     fn backward(
@@ -85,19 +458,557 @@ impl NeuralNetwork for Gpt2 {
         let Self {
             embedding,
             blks,
+            kv_share,
+            depth,
+            output_norm,
+            lm_head,
+            mtp,
+            hidden,
+            ..
+        } = self;
+
+        let mut inputs = inputs.into_iter();
+        let d = if *hidden {
+            vec![inputs.next().unwrap()]
+        } else {
+            ctx.backward(LM_HEAD, lm_head, [inputs.next().unwrap()])
+        };
+        let mut d = ctx.backward(OUTPUT_NORM, output_norm, d);
+        // 只输出隐藏状态时多词预测头不参与前向
+        let mtp = if *hidden { &mut [][..] } else { &mut mtp[..] };
+        for (i, head) in mtp.iter_mut().enumerate() {
+            destruct!([dx] = ctx.backward(MTP(i), head, [inputs.next().unwrap()]));
+            add(&d[0], &dx)
+        }
+        assert!(inputs.next().is_none());
+
+        // 共享层产生的 K、V 梯度累积到源层
+        let mut dkv = HashMap::<usize, Rc<Tensor>>::new();
+        for (i, src) in kv_share.iter().enumerate().rev() {
+            match depth[i] {
+                Depth::Kept => {}
+                Depth::Skipped => continue,
+                Depth::Scaled(p) => {
+                    // 块内得到 p·dy，残差直接得到 (1 - p)·dy
+                    let dy = d[0].clone();
+                    let dblk = ctx.tensor_zeroed_like(&dy).share();
+                    axpby(&dblk, &dy, 0., p);
+                    d = ctx.backward(BLK(i), &mut blks[i], [dblk]);
+                    axpby(&d[0], &dy, 1., 1. - p);
+                    continue;
+                }
+            }
+            d.extend(dkv.remove(&i));
+            d = ctx.backward(BLK(i), &mut blks[i], d);
+            if let Some(src) = src {
+                let dkv_ = d.pop().unwrap();
+                match dkv.entry(*src) {
+                    Entry::Occupied(entry) => add(entry.get(), &dkv_),
+                    Entry::Vacant(entry) => {
+                        entry.insert(dkv_);
+                    }
+                }
+            }
+        }
+
+        ctx.backward(EMBEDDING, embedding, d)
+    }
+}
+
+impl NeuralNetwork for MtpHead {
+    type Init = (
+        llmc::Gpt2Blk<RwRc<Blob>>,
+        usize,
+        [Rc<Tensor>; 2],
+        Rc<Tensor>,
+    );
+
+    fn init(init: Self::Init, ctx: &mut Context) -> Self {
+        let (blk, nh, output_norm, wte) = init;
+        Self {
+            blk: ctx.init("blk", (blk, nh)),
+            output_norm: ctx.init(OUTPUT_NORM, output_norm),
+            lm_head: ctx.init(LM_HEAD, (wte, None)),
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let Self {
+            blk,
+            output_norm,
+            lm_head,
+        } = self;
+
+        let x = ctx.forward("blk", blk, inputs);
+        let x = ctx.forward(OUTPUT_NORM, output_norm, x);
+        ctx.forward(LM_HEAD, lm_head, x)
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let Self {
+            blk,
             output_norm,
             lm_head,
         } = self;
 
         let d = ctx.backward(LM_HEAD, lm_head, inputs);
         let d = ctx.backward(OUTPUT_NORM, output_norm, d);
+        ctx.backward("blk", blk, d)
+    }
+}
 
-        let d = blks
-            .iter_mut()
-            .enumerate()
-            .rev()
-            .fold(d, |d, (i, blk)| ctx.backward(BLK(i), blk, d));
+/// 内存映射的张量在文件中占用的范围。
+fn region(t: &Tensor) -> Option<Region> {
+    let nbytes = crate::Tensor::contiguous_of(t).take();
+    let ans = t
+        .get()
+        .read()
+        .region()
+        .map(|r| r.slice(t.layout().offset() as _, nbytes));
+    t.get().release();
+    ans
+}
 
-        ctx.backward(EMBEDDING, embedding, d)
+#[test]
+fn test_paged_kv_cache() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let config = llmc::Gpt2Config {
+        nblk: 2,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let model = llmc::Gpt2::random(config, &mut StdRng::seed_from_u64(0)).map(RwRc::new);
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+
+    let tokens = |rows: &[&[u16]]| {
+        let n_seq = rows[0].len();
+        let data = rows.concat();
+        crate::Tensor::new(types::U16, &[rows.len(), n_seq])
+            .map(|_| RwRc::new((&*data).into()))
+            .share()
+    };
+    // 每行最后一个位置的 logits
+    let last = |y: &Tensor| {
+        dims!([batch_size, n_seq, n_voc] = y);
+        let ([], buf, []) = (unsafe { y.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let ans = (0..batch_size)
+            .map(|b| buf[((b + 1) * n_seq - 1) * n_voc..][..64].to_vec())
+            .collect::<Vec<_>>();
+        y.get().release();
+        ans
+    };
+    let full = |ctx: &mut Context, gpt2: &mut Gpt2, seq: &[u16]| {
+        let y = ctx.forward("gpt2", gpt2, [tokens(&[seq])]);
+        last(&y[0]).pop().unwrap()
+    };
+    let check = |a: &[f32], b: &[f32]| {
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-4, "{a} vs {b}")
+        }
+    };
+    let seq_a = [1, 2, 3, 4, 5, 6];
+    let seq_b = [7, 8, 9];
+    let seq_c = [10, 11, 12, 13, 14, 15, 16, 17, 18];
+    let expected = [&seq_a[..], &seq_b, &seq_c].map(|seq| full(&mut ctx, &mut gpt2, seq));
+
+    gpt2.paged_kv_cache(4, 6);
+    let a = gpt2.add_sequence();
+    let b = gpt2.add_sequence();
+    // 两个序列分别预填充，再在同一批中各解码一个词
+    gpt2.select_sequences(&[a]);
+    let _ = ctx.forward("gpt2", &mut gpt2, [tokens(&[&seq_a[..5]])]);
+    gpt2.select_sequences(&[b]);
+    let _ = ctx.forward("gpt2", &mut gpt2, [tokens(&[&seq_b[..2]])]);
+    gpt2.select_sequences(&[b, a]);
+    let y = ctx.forward("gpt2", &mut gpt2, [tokens(&[&seq_b[2..], &seq_a[5..]])]);
+    let y = last(&y[0]);
+    check(&y[0], &expected[1]);
+    check(&y[1], &expected[0]);
+    assert_eq!([gpt2.sequence_len(a), gpt2.sequence_len(b)], [6, 3]);
+    assert_eq!(gpt2.blks[0].cache().unwrap().free_blocks(), 3);
+
+    // 移除的序列归还块，新序列复用它们
+    gpt2.remove_sequence(a);
+    assert_eq!(gpt2.blks[0].cache().unwrap().free_blocks(), 5);
+    let c = gpt2.add_sequence();
+    assert_eq!(c, a);
+    gpt2.select_sequences(&[c]);
+    let y = ctx.forward("gpt2", &mut gpt2, [tokens(&[&seq_c])]);
+    check(&last(&y[0])[0], &expected[2]);
+    assert_eq!(gpt2.blks[0].cache().unwrap().free_blocks(), 2);
+    // 回退后归还多余的块，重新输入被撤销的词得到相同的结果
+    gpt2.truncate_sequence(c, 5);
+    assert_eq!(gpt2.sequence_len(c), 5);
+    assert_eq!(gpt2.blks[0].cache().unwrap().free_blocks(), 3);
+    let y = ctx.forward("gpt2", &mut gpt2, [tokens(&[&seq_c[5..]])]);
+    check(&last(&y[0])[0], &expected[2])
+}
+
+#[test]
+fn test_int8_kv_cache() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let config = llmc::Gpt2Config {
+        nblk: 2,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let model = llmc::Gpt2::random(config, &mut StdRng::seed_from_u64(0)).map(RwRc::new);
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+
+    let seq = [3u16, 1, 4, 1, 5, 9, 2, 6];
+    let tokens = |seq: &[u16]| {
+        crate::Tensor::new(types::U16, &[1, seq.len()])
+            .map(|_| RwRc::new(seq.into()))
+            .share()
+    };
+    let last = |y: &Tensor| {
+        dims!([_, n_seq, n_voc] = y);
+        let ([], buf, []) = (unsafe { y.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let ans = buf[(n_seq - 1) * n_voc..][..64].to_vec();
+        y.get().release();
+        ans
+    };
+
+    let mut nbytes = Vec::new();
+    let mut logits = Vec::new();
+    for int8 in [false, true] {
+        gpt2.kv_cache(true);
+        gpt2.int8_kv_cache(int8);
+        let mut y = Vec::new();
+        for t in 0..seq.len() {
+            let y_ = ctx.forward("gpt2", &mut gpt2, [tokens(&seq[t..][..1])]);
+            y = last(&y_[0])
+        }
+        nbytes.push(gpt2.kv_cache_nbytes());
+        logits.push(y)
+    }
+    gpt2.kv_cache(false);
+    // dh = 8：每个位置每个头的 K 或 V 由 32 字节变为 8 字节加 4 字节的比例
+    assert_eq!(nbytes[1] * 32, nbytes[0] * (8 + 4));
+    let max = logits[0].iter().fold(0f32, |m, x| m.max(x.abs()));
+    for (a, b) in logits[0].iter().zip(&logits[1]) {
+        assert!((a - b).abs() < 2e-2 * max, "{a} vs {b}")
+    }
+}
+
+#[test]
+fn test_kv_session() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let config = llmc::Gpt2Config {
+        nblk: 2,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let model = llmc::Gpt2::random(config, &mut StdRng::seed_from_u64(0)).map(RwRc::new);
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+
+    let tokens = |seq: &[u16]| {
+        crate::Tensor::new(types::U16, &[1, seq.len()])
+            .map(|_| RwRc::new(seq.into()))
+            .share()
+    };
+    let last = |y: &Tensor| {
+        dims!([_, n_seq, n_voc] = y);
+        let ([], buf, []) = (unsafe { y.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let ans = buf[(n_seq - 1) * n_voc..][..64].to_vec();
+        y.get().release();
+        ans
+    };
+    let path = std::env::temp_dir().join(format!("llm-rs-kv-{}.bin", std::process::id()));
+
+    let seq = [5u16, 8, 13, 21, 34, 55, 2];
+    for int8 in [false, true] {
+        // 预填充后保存，再接着解码作为参照
+        gpt2.paged_kv_cache(4, 4);
+        gpt2.int8_kv_cache(int8);
+        let a = gpt2.add_sequence();
+        gpt2.select_sequences(&[a]);
+        let _ = ctx.forward("gpt2", &mut gpt2, [tokens(&seq[..6])]);
+        gpt2.save_session(a, &path).unwrap();
+        let expected = last(&ctx.forward("gpt2", &mut gpt2, [tokens(&seq[6..])])[0]);
+
+        // 在块大小不同的新缓存中恢复，接着解码
+        gpt2.paged_kv_cache(3, 4);
+        let _ = gpt2.add_sequence();
+        let id = gpt2.load_session(&path).unwrap();
+        assert_eq!(gpt2.sequence_len(id), 6);
+        gpt2.select_sequences(&[id]);
+        let y = last(&ctx.forward("gpt2", &mut gpt2, [tokens(&seq[6..])])[0]);
+        for (a, b) in y.iter().zip(&expected) {
+            assert!((a - b).abs() < 1e-5, "{a} vs {b}")
+        }
+    }
+    gpt2.kv_cache(false);
+    std::fs::remove_file(path).unwrap()
+}
+
+#[test]
+fn test_kv_session_errors() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let config = llmc::Gpt2Config {
+        nblk: 2,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let model = llmc::Gpt2::random(config, &mut StdRng::seed_from_u64(0)).map(RwRc::new);
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+    let path = std::env::temp_dir().join(format!("llm-rs-kv-err-{}.bin", std::process::id()));
+
+    gpt2.paged_kv_cache(4, 4);
+    let a = gpt2.add_sequence();
+    gpt2.select_sequences(&[a]);
+    let tokens = crate::Tensor::new(types::U16, &[1, 6])
+        .map(|_| RwRc::new([5u16, 8, 13, 21, 34, 55][..].into()))
+        .share();
+    let _ = ctx.forward("gpt2", &mut gpt2, [tokens]);
+    gpt2.save_session(a, &path).unwrap();
+
+    // 截断的文件：第一层恢复成功、第二层失败，第一层被撤销
+    let data = std::fs::read(&path).unwrap();
+    let truncated = path.with_extension("truncated");
+    std::fs::write(&truncated, &data[..data.len() - 8]).unwrap();
+    gpt2.paged_kv_cache(4, 4);
+    let err = gpt2.load_session(&truncated).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(gpt2.add_sequence(), 0);
+    assert!(gpt2.caches().all(|cache| cache.free_blocks() == 4));
+
+    // 6 个位置占 2 块，第三次恢复时块不够
+    assert!(gpt2.load_session(&path).is_ok());
+    assert!(gpt2.load_session(&path).is_ok());
+    let err = gpt2.load_session(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+
+    // 按批大小分配的缓存只有一块，第二次恢复返回错误，第一个序列不受影响
+    gpt2.kv_cache(true);
+    let id = gpt2.load_session(&path).unwrap();
+    let err = gpt2.load_session(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+    assert_eq!(gpt2.sequence_len(id), 6);
+
+    gpt2.kv_cache(false);
+    std::fs::remove_file(truncated).unwrap();
+    std::fs::remove_file(path).unwrap()
+}
+
+#[test]
+fn test_kv_cache_shared() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let config = llmc::Gpt2Config {
+        nblk: 3,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let model = llmc::Gpt2::random(config, &mut StdRng::seed_from_u64(0)).map(RwRc::new);
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+    gpt2.share_kv(2, 1);
+
+    let tokens = |seq: &[u16]| {
+        crate::Tensor::new(types::U16, &[1, seq.len()])
+            .map(|_| RwRc::new(seq.into()))
+            .share()
+    };
+    let last = |y: &Tensor| {
+        dims!([_, n_seq, n_voc] = y);
+        let ([], buf, []) = (unsafe { y.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let ans = buf[(n_seq - 1) * n_voc..][..64].to_vec();
+        y.get().release();
+        ans
+    };
+
+    let seq = [5u16, 8, 13, 21, 34, 55, 2];
+    let expected = (4..=seq.len())
+        .map(|len| last(&ctx.forward("gpt2", &mut gpt2, [tokens(&seq[..len])])[0]))
+        .collect::<Vec<_>>();
+
+    // 共享层没有自己的缓存
+    gpt2.kv_cache(true);
+    let _ = ctx.forward("gpt2", &mut gpt2, [tokens(&seq[..1])]);
+    let per_blk = gpt2.blks[0].cache().unwrap().nbytes();
+    assert!(gpt2.blks[2].cache().is_none());
+    assert_eq!(gpt2.kv_cache_nbytes(), 2 * per_blk);
+
+    // 预填充后逐词解码，与不使用缓存的前向一致
+    for paged in [false, true] {
+        if paged {
+            gpt2.paged_kv_cache(4, 4)
+        } else {
+            gpt2.kv_cache(true)
+        }
+        let mut y = last(&ctx.forward("gpt2", &mut gpt2, [tokens(&seq[..4])])[0]);
+        for (i, expected) in expected.iter().enumerate() {
+            for (a, b) in y.iter().zip(expected) {
+                assert!((a - b).abs() < 1e-4, "{a} vs {b}")
+            }
+            if let Some(&t) = seq.get(4 + i) {
+                y = last(&ctx.forward("gpt2", &mut gpt2, [tokens(&[t])])[0])
+            }
+        }
+    }
+    gpt2.kv_cache(false)
+}
+
+#[test]
+fn test_mapped_weights() {
+    use memmap2::Mmap;
+    use std::sync::Arc;
+
+    // 写出 llm.c 格式的小模型，权重为确定的伪随机数
+    let [n_seq, n_voc, nblk, nh, d] = [16, 64, 2, 4, 32];
+    let mut header = [0i32; 256];
+    header[..8].copy_from_slice(&[20240326, 3, n_seq, n_voc, nblk, nh, d, n_voc]);
+    let [n_seq, n_voc, nblk, d] = [n_seq, n_voc, nblk, d].map(|x| x as usize);
+    let n_params = n_voc * d + n_seq * d + nblk * (12 * d * d + 13 * d) + 2 * d;
+    let mut data = header
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect::<Vec<_>>();
+    for i in 0..n_params {
+        let x = ((i * 7919 % 1009) as f32 / 1009. - 0.5) * 0.1;
+        data.extend(x.to_le_bytes())
+    }
+    let path = std::env::temp_dir().join(format!("llm-rs-mapped-{}.bin", std::process::id()));
+    std::fs::write(&path, &data).unwrap();
+    let mmap = Arc::new(unsafe { Mmap::map(&File::open(&path).unwrap()) }.unwrap());
+
+    // 映射的权重与复制出的权重得到相同的 logits
+    let tokens = crate::Tensor::new(types::U16, &[1, 5])
+        .map(|_| RwRc::new([3u16, 1, 4, 1, 5][..].into()))
+        .share();
+    let mut logits = Vec::new();
+    for mapped in [false, true] {
+        let model = if mapped {
+            llmc::Gpt2::mapped(&mmap)
+        } else {
+            llmc::Gpt2::new(&mmap).map(Blob::from)
+        };
+        let mut ctx = Context::new(false);
+        let mut gpt2 = ctx.init::<Gpt2>("gpt2", model.map(RwRc::new));
+        // 每层 12 个张量，各自落在文件中不同的位置
+        let n_regions = gpt2
+            .prefetch
+            .iter()
+            .map(|blk| blk.len())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            n_regions,
+            if mapped {
+                vec![12; nblk]
+            } else {
+                vec![0; nblk]
+            }
+        );
+        if mapped {
+            let mut ranges = gpt2
+                .prefetch
+                .iter()
+                .flatten()
+                .map(Region::range)
+                .collect::<Vec<_>>();
+            ranges.sort_by_key(|r| r.start);
+            assert!(ranges.windows(2).all(|w| w[0].end <= w[1].start));
+            assert!(ranges.iter().all(|r| r.end <= data.len()))
+        }
+        let y = ctx.forward("gpt2", &mut gpt2, [tokens.clone()]);
+        let y = y[0].cloned();
+        logits.push(y.get().read().to_vec())
+    }
+    assert_eq!(logits[0], logits[1]);
+
+    // 修改映射的权重时复制出来，文件不变
+    let mut blob = Blob::mapped(&mmap, &mmap[1024..][..8]);
+    assert!(blob.region().is_some());
+    blob.fill(0);
+    assert!(blob.region().is_none());
+    assert_eq!(&mmap[1024..][..8], &data[1024..][..8]);
+    std::fs::remove_file(path).unwrap()
+}
+
+#[test]
+fn test_stochastic_depth() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    assert_eq!(Gpt2::linear_survival(4, 0.5), [0.875, 0.75, 0.625, 0.5]);
+
+    let config = llmc::Gpt2Config {
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let model = llmc::Gpt2::random(config, &mut StdRng::seed_from_u64(0)).map(RwRc::new);
+    // 去掉最后一层的模型作为跳层的参照
+    let shallow = llmc::Gpt2 {
+        blks: model.blks[..1].into(),
+        ..model.clone()
+    };
+
+    let tokens = crate::Tensor::new(types::U16, &[1, 5])
+        .map(|_| RwRc::new((&[1u16, 2, 3, 4, 5][..]).into()))
+        .share();
+    let logits = |ctx: &mut Context, gpt2: &mut Gpt2| {
+        let y = ctx.forward("gpt2", gpt2, [tokens.clone()]);
+        let ([], buf, []) = (unsafe { y[0].get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        (y[0].clone(), buf.to_vec())
+    };
+    let close = |a: &[f32], b: &[f32], eps: f32| a.iter().zip(b).all(|(a, b)| (a - b).abs() < eps);
+
+    let mut ctx = Context::new(false);
+    let mut full = ctx.init::<Gpt2>("gpt2", model);
+    let mut shallow = ctx.init::<Gpt2>("gpt2", shallow);
+    let (_, expected_full) = logits(&mut ctx, &mut full);
+    let (_, expected_shallow) = logits(&mut ctx, &mut shallow);
+    assert!(!close(&expected_full, &expected_shallow, 1e-3));
+
+    // 训练时最后一层以一半的概率被跳过，跳过时与浅模型相同，反向直接穿过
+    full.stochastic_depth(&[1., 0.5]);
+    let mut skipped = 0;
+    for _ in 0..20 {
+        let (y, y_) = logits(&mut ctx, &mut full);
+        if close(&y_, &expected_shallow, 1e-5) {
+            skipped += 1
+        } else {
+            assert!(close(&y_, &expected_full, 1e-5))
+        }
+        let dy = ctx.tensor_zeroed_like(&y).share();
+        let _ = ctx.backward("gpt2", &mut full, [dy]);
     }
+    assert!((3..=17).contains(&skipped), "{skipped}");
+
+    // 评估时按保留概率缩放，保留概率趋于 0 时接近浅模型
+    ctx.set_training(false);
+    full.stochastic_depth(&[1., 1e-6]);
+    let (y, y_) = logits(&mut ctx, &mut full);
+    assert!(close(&y_, &expected_shallow, 1e-3));
+    let dy = ctx.tensor_zeroed_like(&y).share();
+    let _ = ctx.backward("gpt2", &mut full, [dy]);
+
+    full.stochastic_depth(&[]);
+    assert!(close(&logits(&mut ctx, &mut full).1, &expected_full, 1e-5))
 }
@@ -1,7 +1,15 @@
 use super::{
-    NeuralNetwork, Tensor, attention::Attention, gelu::Gelu, layer_norm::LayerNorm, linear::Linear,
+    NeuralNetwork, Tensor,
+    attention::{Attention, KvCache},
+    gelu::Gelu,
+    layer_norm::LayerNorm,
+    linear::Linear,
+};
+use crate::{
+    Blob, Context, llmc,
+    macros::*,
+    op::{add::add, attention::SparsePattern, gelu::Approx},
 };
-use crate::{Blob, Context, llmc, macros::*, op::add::add};
 use rw_rc::RwRc;
 use std::rc::Rc;
 
@@ -25,6 +33,38 @@ pub struct Gpt2Blk {
     ffn_down: Linear,
 }
 
+impl Gpt2Blk {
+    pub fn sparse_attention(&mut self, pattern: SparsePattern) {
+        self.attn.sparse(pattern)
+    }
+
+    pub fn prefix_lm(&mut self, n_prefix: usize) {
+        self.attn.prefix_lm(n_prefix)
+    }
+
+    /// 设置注意力层的 KV 缓存，见 [`Attention::kv_cache`]。
+    pub fn kv_cache(&mut self, cache: Option<KvCache>) {
+        self.attn.kv_cache(cache)
+    }
+
+    pub fn cache(&self) -> Option<&KvCache> {
+        self.attn.cache()
+    }
+
+    pub fn cache_mut(&mut self) -> Option<&mut KvCache> {
+        self.attn.cache_mut()
+    }
+
+    pub fn take_cache(&mut self) -> Option<KvCache> {
+        self.attn.take_cache()
+    }
+
+    /// 最近一次前向计算的 qkv，供共享 KV 的后续层使用。
+    pub fn kv(&self) -> Rc<Tensor> {
+        self.attn.qkv().unwrap().clone()
+    }
+}
+
 impl NeuralNetwork for Gpt2Blk {
     type Init = (llmc::Gpt2Blk<RwRc<Blob>>, usize);
 
@@ -57,7 +97,7 @@ impl NeuralNetwork for Gpt2Blk {
         let [w, b] = share(ffn_up);
         let ffn_up = ctx.init(FFN_UP, (w, Some(b)));
 
-        let ffn_act = ctx.init(FFN_ACT, ());
+        let ffn_act = ctx.init(FFN_ACT, Approx::Tanh);
 
         let [w, b] = share(ffn_down);
         let ffn_down = ctx.init(FFN_DOWN, (w, Some(b)));
@@ -90,16 +130,21 @@ impl NeuralNetwork for Gpt2Blk {
             ffn_down,
         } = self;
 
-        destruct!([residual] = inputs);
+        // 可选的第二个输入是共享的 qkv
+        let mut inputs = inputs.into_iter();
+        let residual = inputs.next().unwrap();
+        let kv = inputs.next();
+        assert!(inputs.next().is_none());
 
         let x = [residual.clone()];
         let x = ctx.forward(ATTN_NORM, attn_norm, x);
-        let x = ctx.forward(ATTN_QKV, attn_qkv, x);
+        let mut x = ctx.forward(ATTN_QKV, attn_qkv, x);
+        x.extend(kv);
         let x = ctx.forward(ATTN, attn, x);
         let x = ctx.forward(ATTN_O, attn_o, x);
 
         destruct!([x] = x);
-        add(&x, &residual);
+        ctx.bench(|| add(&x, &residual));
         let residual = x;
 
         let x = [residual.clone()];
@@ -109,7 +154,7 @@ impl NeuralNetwork for Gpt2Blk {
         let x = ctx.forward(FFN_DOWN, ffn_down, x);
 
         destruct!([x] = x);
-        add(&x, &residual);
+        ctx.bench(|| add(&x, &residual));
 
         vec![x]
     }
@@ -130,8 +175,11 @@ impl NeuralNetwork for Gpt2Blk {
             ffn_down,
         } = self;
 
-        destruct!([dresidual] = inputs);
-        let dresidual = dresidual;
+        // 可选的第二个输入是共享本层 KV 的后续层累积的 qkv 梯度
+        let mut inputs = inputs.into_iter();
+        let dresidual = inputs.next().unwrap();
+        let dqkv = inputs.next();
+        assert!(inputs.next().is_none());
 
         let d = [dresidual.clone()];
         let d = ctx.backward(FFN_DOWN, ffn_down, d);
@@ -145,13 +193,18 @@ impl NeuralNetwork for Gpt2Blk {
 
         let d = [dresidual.clone()];
         let d = ctx.backward(ATTN_O, attn_o, d);
-        let d = ctx.backward(ATTN, attn, d);
-        let d = ctx.backward(ATTN_QKV, attn_qkv, d);
+        let mut d = ctx.backward(ATTN, attn, d).into_iter();
+        let (d, dkv) = (d.next().unwrap(), d.next());
+        if let Some(dqkv) = dqkv {
+            add(&d, &dqkv)
+        }
+        let d = ctx.backward(ATTN_QKV, attn_qkv, [d]);
         let d = ctx.backward(ATTN_NORM, attn_norm, d);
 
         destruct!([d] = d);
         add(&d, &dresidual);
 
-        vec![d]
+        // 使用共享 KV 时额外返回共享 qkv 的梯度
+        [Some(d), dkv].into_iter().flatten().collect()
     }
 }
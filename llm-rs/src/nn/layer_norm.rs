@@ -77,7 +77,7 @@ impl NeuralNetwork for LayerNorm {
         } = self;
 
         let x = x.take().unwrap();
-        let dx = ctx.tensor_zeroed(x.dt(), &x.shape());
+        let dx = ctx.tensor_zeroed_like(&x);
 
         let dw = ctx.write_gradient("w", w);
         let db = ctx.write_gradient("b", b);
@@ -34,16 +34,19 @@ impl NeuralNetwork for Linear {
         let Self { w, b, x } = self;
 
         let x = x.as_deref().unwrap();
-        dims!([batch_size, seq_len, _] = x);
-        dims!([d, _] = w);
+        dims!([batch_size, seq_len, d_in] = x);
+        dims!([d, d_in_] = w);
+        assert_eq!(d_in, d_in_, "input width mismatches weight {:?}", w.shape());
         let y = ctx.tensor(x.dt(), &[batch_size, seq_len, d]);
 
+        let batch_invariant = ctx.is_batch_invariant();
         ctx.bench(|| {
             forward(
                 &y.clone().merge(0, 2),
                 &x.clone().merge(0, 2),
                 w,
                 b.as_deref(),
+                batch_invariant,
             )
         });
 
@@ -60,7 +63,7 @@ impl NeuralNetwork for Linear {
 
         let x = x.take().unwrap();
         let dw = ctx.write_gradient("w", w);
-        let dx = ctx.tensor_zeroed(x.dt(), &x.shape());
+        let dx = ctx.tensor_zeroed_like(&x);
         let db = b.as_ref().map(|b| ctx.write_gradient("b", b));
         ctx.bench(|| {
             backward(
@@ -0,0 +1,387 @@
+use super::{
+    NeuralNetwork, Tensor, attention::Attention, embedding::Embedding, linear::Linear,
+    rms_norm::RmsNorm, swiglu::SwiGlu,
+};
+use crate::{
+    Blob, Context, DryRun,
+    llama::{self, LlamaConfig},
+    macros::*,
+    op::{add::add, rearrange::rearrange, rope},
+};
+use digit_layout::types;
+use rw_rc::RwRc;
+use std::rc::Rc;
+
+const EMBEDDING: &str = "embedding";
+
+#[allow(non_snake_case)]
+fn BLK(i: usize) -> String {
+    format!("blk[{i}]")
+}
+
+const OUTPUT_NORM: &str = "output_norm";
+const LM_HEAD: &str = "lm_head";
+
+const ATTN_NORM: &str = "attn_norm";
+const ATTN_Q: &str = "attn_q";
+const ATTN_K: &str = "attn_k";
+const ATTN_V: &str = "attn_v";
+const ATTN: &str = "attn";
+const ATTN_O: &str = "attn_o";
+const FFN_NORM: &str = "ffn_norm";
+const FFN: &str = "ffn";
+
+pub struct Llama {
+    embedding: Embedding,
+    blks: Box<[LlamaBlk]>,
+    output_norm: RmsNorm,
+    lm_head: Linear,
+}
+
+pub struct LlamaBlk {
+    attn_norm: RmsNorm,
+    attn_q: Linear,
+    attn_k: Linear,
+    attn_v: Linear,
+    attn: Attention,
+    attn_o: Linear,
+    ffn_norm: RmsNorm,
+    ffn: SwiGlu,
+    nh: usize,
+    nkvh: usize,
+    theta: f32,
+}
+
+impl Llama {
+    /// 空跑一次 `[batch, n_seq]` 的前向，检查各层的形状并估计激活内存，不计算也不分配激活。
+    ///
+    /// 形状不匹配时与实际前向一样 panic，参见 [`Context::dry_run`]。
+    pub fn check(&mut self, ctx: &mut Context, batch: usize, n_seq: usize) -> DryRun {
+        let tokens = crate::Tensor::new(types::U16, &[batch, n_seq])
+            .map(|len| Blob::lazy(len, true))
+            .map(RwRc::new)
+            .share();
+        ctx.dry_run(|ctx| ctx.forward("llama", self, [tokens])).1
+    }
+}
+
+impl NeuralNetwork for Llama {
+    type Init = llama::Llama<RwRc<Blob>>;
+
+    fn init(init: Self::Init, ctx: &mut Context) -> Self {
+        let Self::Init {
+            config,
+            wte,
+            blks,
+            output_norm,
+            lm_head,
+        } = init;
+
+        let embedding = ctx.init(EMBEDDING, (wte.share(), None));
+        let blks = blks
+            .into_iter()
+            .enumerate()
+            .map(|(i, blk)| ctx.init(BLK(i), (blk, config.clone())))
+            .collect();
+        let output_norm = ctx.init(OUTPUT_NORM, (output_norm.share(), config.epsilon));
+        let lm_head = ctx.init(LM_HEAD, (lm_head.share(), None));
+
+        Self {
+            embedding,
+            blks,
+            output_norm,
+            lm_head,
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let Self {
+            embedding,
+            blks,
+            output_norm,
+            lm_head,
+        } = self;
+
+        let mut x = ctx.forward(EMBEDDING, embedding, inputs);
+        for (i, blk) in blks.iter_mut().enumerate() {
+            x = ctx.forward(BLK(i), blk, x)
+        }
+        let x = ctx.forward(OUTPUT_NORM, output_norm, x);
+        ctx.forward(LM_HEAD, lm_head, x)
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let Self {
+            embedding,
+            blks,
+            output_norm,
+            lm_head,
+        } = self;
+
+        let d = ctx.backward(LM_HEAD, lm_head, inputs);
+        let mut d = ctx.backward(OUTPUT_NORM, output_norm, d);
+        for (i, blk) in blks.iter_mut().enumerate().rev() {
+            d = ctx.backward(BLK(i), blk, d)
+        }
+        ctx.backward(EMBEDDING, embedding, d)
+    }
+}
+
+impl LlamaBlk {
+    /// `[batch, n_seq, n * dh]` 的投影结果按头拆分为 `[batch, n_seq, n, dh]`。
+    fn heads(x: &Tensor, n: usize) -> Tensor {
+        dims!([_, _, d] = x);
+        x.cloned().tile(2, &[n, d / n])
+    }
+}
+
+impl NeuralNetwork for LlamaBlk {
+    type Init = (llama::LlamaBlk<RwRc<Blob>>, LlamaConfig);
+
+    fn init(init: Self::Init, ctx: &mut Context) -> Self {
+        let (
+            llama::LlamaBlk {
+                attn_norm,
+                attn_q,
+                attn_k,
+                attn_v,
+                attn_o,
+                ffn_norm,
+                ffn_gate,
+                ffn_up,
+                ffn_down,
+            },
+            config,
+        ) = init;
+        let LlamaConfig {
+            nh,
+            nkvh,
+            theta,
+            epsilon,
+            ..
+        } = config;
+        assert_eq!(nh % nkvh, 0);
+
+        Self {
+            attn_norm: ctx.init(ATTN_NORM, (attn_norm.share(), epsilon)),
+            attn_q: ctx.init(ATTN_Q, (attn_q.share(), None)),
+            attn_k: ctx.init(ATTN_K, (attn_k.share(), None)),
+            attn_v: ctx.init(ATTN_V, (attn_v.share(), None)),
+            attn: {
+                let mut attn = ctx.init::<Attention>(ATTN, nh);
+                attn.kv_heads(nkvh);
+                attn
+            },
+            attn_o: ctx.init(ATTN_O, (attn_o.share(), None)),
+            ffn_norm: ctx.init(FFN_NORM, (ffn_norm.share(), epsilon)),
+            ffn: ctx.init(FFN, [ffn_gate, ffn_up, ffn_down].map(Tensor::share)),
+            nh,
+            nkvh,
+            theta,
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let Self {
+            attn_norm,
+            attn_q,
+            attn_k,
+            attn_v,
+            attn,
+            attn_o,
+            ffn_norm,
+            ffn,
+            nh,
+            nkvh,
+            theta,
+        } = self;
+
+        destruct!([residual] = inputs);
+        destruct!([x] = ctx.forward(ATTN_NORM, attn_norm, [residual.clone()]));
+        destruct!([q] = ctx.forward(ATTN_Q, attn_q, [x.clone()]));
+        destruct!([k] = ctx.forward(ATTN_K, attn_k, [x.clone()]));
+        destruct!([v] = ctx.forward(ATTN_V, attn_v, [x]));
+
+        // Q、K 原地旋转后拼接为注意力的输入 [q | k | v]
+        dims!([batch_size, n_seq, d] = q);
+        dims!([_, _, dkv] = k);
+        let qkv = ctx.tensor(q.dt(), &[batch_size, n_seq, d + 2 * dkv]);
+        ctx.bench(|| {
+            rope::forward(&Self::heads(&q, *nh), *theta);
+            rope::forward(&Self::heads(&k, *nkvh), *theta);
+            for (t, start) in [(&q, 0), (&k, d), (&v, d + dkv)] {
+                rearrange(&qkv.cloned().slice(2, start, t.shape()[2]), t)
+            }
+        });
+
+        let x = ctx.forward(ATTN, attn, [qkv.share()]);
+        destruct!([x] = ctx.forward(ATTN_O, attn_o, x));
+        ctx.bench(|| add(&x, &residual));
+        let residual = x;
+
+        let x = ctx.forward(FFN_NORM, ffn_norm, [residual.clone()]);
+        destruct!([x] = ctx.forward(FFN, ffn, x));
+        ctx.bench(|| add(&x, &residual));
+
+        vec![x]
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let Self {
+            attn_norm,
+            attn_q,
+            attn_k,
+            attn_v,
+            attn,
+            attn_o,
+            ffn_norm,
+            ffn,
+            nh,
+            nkvh,
+            theta,
+        } = self;
+
+        destruct!([dresidual] = inputs);
+        let d = ctx.backward(FFN, ffn, [dresidual.clone()]);
+        destruct!([d] = ctx.backward(FFN_NORM, ffn_norm, d));
+        add(&d, &dresidual);
+        let dresidual = d;
+
+        let d = ctx.backward(ATTN_O, attn_o, [dresidual.clone()]);
+        destruct!([dqkv] = ctx.backward(ATTN, attn, d));
+
+        dims!([batch_size, n_seq, d3] = dqkv);
+        let dkv = d3 / (*nh + 2 * *nkvh) * *nkvh;
+        let d = d3 - 2 * dkv;
+        let dq = ctx.tensor(dqkv.dt(), &[batch_size, n_seq, d]);
+        let dk = ctx.tensor(dqkv.dt(), &[batch_size, n_seq, dkv]);
+        let dv = ctx.tensor(dqkv.dt(), &[batch_size, n_seq, dkv]);
+        ctx.bench(|| {
+            for (t, start) in [(&dq, 0), (&dk, d), (&dv, d + dkv)] {
+                rearrange(t, &dqkv.cloned().slice(2, start, t.shape()[2]))
+            }
+            rope::backward(&Self::heads(&dq, *nh), *theta);
+            rope::backward(&Self::heads(&dk, *nkvh), *theta)
+        });
+
+        // 三个投影共享输入，梯度相加
+        destruct!([dx] = ctx.backward(ATTN_Q, attn_q, [dq.share()]));
+        for (name, linear, d) in [(ATTN_K, attn_k, dk), (ATTN_V, attn_v, dv)] {
+            destruct!([dx_] = ctx.backward(name, linear, [d.share()]));
+            add(&dx, &dx_)
+        }
+        destruct!([d] = ctx.backward(ATTN_NORM, attn_norm, [dx]));
+        add(&d, &dresidual);
+
+        vec![d]
+    }
+}
+
+#[test]
+fn test_llama() {
+    use crate::optimizer::Optimizer;
+    use digit_layout::types;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let config = LlamaConfig::tiny(50);
+    let n_voc = config.n_voc;
+    let weights = llama::Llama::random(config, &mut StdRng::seed_from_u64(7)).map(RwRc::new);
+    let mut ctx = Context::new(false);
+    let mut model = ctx.init::<Llama>("llama", weights);
+
+    let values = |t: &Rc<Tensor>| {
+        let t = t.cloned();
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+    let run = |ctx: &mut Context, model: &mut Llama, tokens: &[u16]| {
+        let tokens = crate::Tensor::new(types::U16, &[1, tokens.len()])
+            .map(|_| Blob::from(tokens))
+            .map(RwRc::new)
+            .share();
+        destruct!([logits] = ctx.forward("llama", model, [tokens]));
+        logits
+    };
+
+    // 因果性：改变最后一个词不影响之前位置的输出
+    let tokens = [3, 14, 15, 9, 26, 5];
+    let logits = values(&run(&mut ctx, &mut model, &tokens));
+    let mut tokens_ = tokens;
+    tokens_[5] = 35;
+    let logits_ = values(&run(&mut ctx, &mut model, &tokens_));
+    let n = 5 * n_voc;
+    assert_eq!(logits[..n], logits_[..n]);
+    assert_ne!(logits[n..], logits_[n..]);
+
+    // 反向与数值梯度一致，loss = sum(logits * r)
+    let r = (0..tokens.len() * n_voc)
+        .map(|i| ((i * 7 % 13) as f32 - 6.) / 6.)
+        .collect::<Vec<_>>();
+    let loss = |ctx: &mut Context, model: &mut Llama| {
+        let logits = values(&run(ctx, model, &tokens));
+        logits.iter().zip(&r).map(|(y, r)| y * r).sum::<f32>()
+    };
+    run(&mut ctx, &mut model, &tokens);
+    let dy = crate::Tensor::new(types::F32, &[1, tokens.len(), n_voc])
+        .map(|_| Blob::from(&*r))
+        .map(RwRc::new)
+        .share();
+    ctx.backward("llama", &mut model, [dy]);
+
+    struct Record(Vec<(Rc<Tensor>, Vec<f32>)>);
+    impl Optimizer for Record {
+        fn update(&mut self, weight: Rc<Tensor>, gradient: Rc<Tensor>) {
+            let gradient = gradient.cloned();
+            let ([], grad, []) = (unsafe { gradient.get().read().align_to::<f32>() }) else {
+                unreachable!()
+            };
+            self.0.push((weight, grad.to_vec()))
+        }
+    }
+    let mut record = Record(Vec::new());
+    ctx.update(&mut record);
+    assert_eq!(record.0.len(), 1 + 2 * 9 + 2);
+
+    let h = 1e-2;
+    for (weight, grad) in record.0 {
+        let add = |i: usize, h: f32| {
+            let weight = weight.cloned();
+            let ([], buf, []) = (unsafe { weight.get().write().align_to_mut::<f32>() }) else {
+                unreachable!()
+            };
+            buf[i] += h
+        };
+        for i in (0..grad.len()).step_by(grad.len() / 3 + 1) {
+            add(i, h);
+            let plus = loss(&mut ctx, &mut model);
+            add(i, -2. * h);
+            let minus = loss(&mut ctx, &mut model);
+            add(i, h);
+            let numeric = (plus - minus) / (2. * h);
+            assert!(
+                (grad[i] - numeric).abs() < 1e-2 * numeric.abs().max(1.),
+                "{:?}[{i}]: {} vs {numeric}",
+                weight.shape(),
+                grad[i]
+            )
+        }
+    }
+}
@@ -1,15 +1,53 @@
-use super::{NeuralNetwork, Tensor};
+use super::{NeuralNetwork, Tensor, custom::Custom};
 use crate::{
     Context,
+    dist::Communicator,
     macros::*,
-    op::loss::{backward, crossentropy, softmax},
+    op::add::add,
+    op::loss::{
+        accumulate, backward, backward_shard, crossentropy, online_softmax, shift_targets, softcap,
+        softcap_backward, softmax, vocab_parallel_crossentropy, z_loss, z_loss_backward,
+        zero_prefix,
+    },
 };
-use std::rc::Rc;
+use digit_layout::types;
+use std::{iter::zip, rc::Rc};
 
 pub struct Loss {
     n_voc: usize,
+    prefix: usize,
+    online: bool,
+    z_loss: f32,
+    softcap: Option<f32>,
     targets: Option<Rc<Tensor>>,
     probs: Option<Tensor>,
+    logz: Option<Tensor>,
+    capped: Option<Tensor>,
+}
+
+impl Loss {
+    /// Prefix-LM：每个序列的前 `n_prefix` 个位置不计损失，输出的损失和回传的梯度都为 0。
+    pub fn prefix(&mut self, n_prefix: usize) {
+        self.prefix = n_prefix
+    }
+
+    /// 使用单趟求指数和的在线 softmax，参见 [`online_softmax`]。
+    pub fn online_softmax(&mut self, enabled: bool) {
+        self.online = enabled
+    }
+
+    /// 每个位置的损失加上 `coef * log²Z`，见 [`crate::op::loss::z_loss`]，`coef` 为 0 时关闭，常用 1e-4。
+    pub fn z_loss(&mut self, coef: f32) {
+        self.z_loss = coef
+    }
+
+    /// 计算损失前把 logits 软截断到 `(-cap, cap)`，见 [`crate::op::loss::softcap`]，`None` 时关闭。
+    pub fn logit_softcap(&mut self, cap: Option<f32>) {
+        if let Some(cap) = cap {
+            assert!(cap > 0.)
+        }
+        self.softcap = cap
+    }
 }
 
 impl NeuralNetwork for Loss {
@@ -18,8 +56,14 @@ impl NeuralNetwork for Loss {
     fn init(init: Self::Init, _ctx: &mut Context) -> Self {
         Self {
             n_voc: init,
+            prefix: 0,
+            online: false,
+            z_loss: 0.,
+            softcap: None,
             targets: None,
             probs: None,
+            logz: None,
+            capped: None,
         }
     }
 
@@ -32,19 +76,43 @@ impl NeuralNetwork for Loss {
         self.targets.replace(targets);
         let Self {
             n_voc: nvoc,
+            online,
+            z_loss: coef,
+            softcap: cap,
             targets,
             ..
         } = self;
 
         let targets = targets.as_ref().unwrap();
 
-        let probs = ctx.tensor(logits.dt(), &logits.shape());
-        softmax(&probs, &logits, *nvoc);
-
+        let probs = ctx.tensor_like(&logits);
         let losses = ctx.tensor(probs.dt(), &targets.shape());
-        crossentropy(&losses, &probs, targets);
+        let capped = cap.map(|_| ctx.tensor_like(&logits));
+        let logz = (*coef != 0.).then(|| ctx.tensor(types::F32, &targets.shape()));
+        let compensated = ctx.is_compensated_sum();
+        ctx.bench(|| {
+            let logits = match (&capped, *cap) {
+                (Some(capped), Some(cap)) => {
+                    softcap(capped, &logits, cap);
+                    capped
+                }
+                _ => &*logits,
+            };
+            if *online {
+                online_softmax(&probs, logits, *nvoc, compensated)
+            } else {
+                softmax(&probs, logits, *nvoc, compensated)
+            }
+            crossentropy(&losses, &probs, targets);
+            if let Some(logz) = &logz {
+                z_loss(&losses, logz, &probs, logits, *coef)
+            }
+            zero_prefix(&losses, self.prefix)
+        });
 
         self.probs.replace(probs);
+        self.logz = logz;
+        self.capped = capped;
         vec![losses.share()]
     }
 
@@ -54,13 +122,586 @@ impl NeuralNetwork for Loss {
         ctx: &mut Context,
     ) -> Vec<Rc<Tensor>> {
         destruct!([dlosses] = inputs);
-        let Self { targets, probs, .. } = self;
+        let Self {
+            prefix,
+            z_loss: coef,
+            softcap: cap,
+            targets,
+            probs,
+            logz,
+            capped,
+            ..
+        } = self;
 
         let probs = probs.take().unwrap();
-        let dlogits = ctx.tensor_zeroed(probs.dt(), &probs.shape());
+        let dlogits = ctx.tensor_zeroed_like(&probs);
+
+        let dlosses = if *prefix > 0 {
+            dims!([_, n_seq] = dlosses);
+            let dlosses_ = ctx.tensor_zeroed_like(&dlosses);
+            accumulate(&dlosses_, &dlosses, 1., n_seq);
+            zero_prefix(&dlosses_, *prefix);
+            dlosses_.share()
+        } else {
+            dlosses
+        };
 
         backward(&dlogits, &dlosses, &probs, &targets.take().unwrap());
+        if let Some(logz) = logz.take() {
+            z_loss_backward(&dlogits, &dlosses, &probs, &logz, *coef)
+        }
+        if let (Some(capped), Some(cap)) = (capped.take(), *cap) {
+            softcap_backward(&dlogits, &capped, cap)
+        }
 
         vec![dlogits.share()]
     }
 }
+
+/// 多词预测的组合损失：主损失加上各辅助头损失的平均值乘以 `weight`。
+///
+/// 输入为 `[logits, mtp_logits.., targets]`，第 `i` 个辅助头的目标是 `targets` 左移 `i` 位，
+/// 序列末尾没有目标的位置不计入损失，序列短到没有任何目标的头直接跳过。输出逐词的组合损失。
+pub struct MtpLoss {
+    heads: Box<[Loss]>,
+    weight: f32,
+    /// 前向跳过的头的 logits，反向为它们返回全 0 梯度。
+    skipped: Box<[Option<Rc<Tensor>>]>,
+}
+
+#[allow(non_snake_case)]
+fn HEAD(i: usize) -> String {
+    format!("head[{i}]")
+}
+
+impl MtpLoss {
+    /// 所有头的前 `n_prefix` 个位置不计损失，见 [`Loss::prefix`]。
+    pub fn prefix(&mut self, n_prefix: usize) {
+        for head in &mut self.heads {
+            head.prefix(n_prefix)
+        }
+    }
+
+    fn scale(&self, i: usize) -> f32 {
+        match i {
+            0 => 1.,
+            _ => self.weight / (self.heads.len() - 1) as f32,
+        }
+    }
+}
+
+impl NeuralNetwork for MtpLoss {
+    /// `(n_voc, n_mtp, weight)`
+    type Init = (usize, usize, f32);
+
+    fn init(init: Self::Init, ctx: &mut Context) -> Self {
+        let (n_voc, n_mtp, weight) = init;
+        Self {
+            heads: (0..=n_mtp).map(|i| ctx.init(HEAD(i), n_voc)).collect(),
+            weight,
+            skipped: vec![None; n_mtp + 1].into(),
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let mut logits = inputs.into_iter().collect::<Vec<_>>();
+        let targets = logits.pop().unwrap();
+        assert_eq!(logits.len(), self.heads.len());
+        dims!([batch_size, n_seq] = targets);
+
+        let losses = ctx.tensor_zeroed(types::F32, &[batch_size, n_seq]);
+        for (i, logits) in logits.into_iter().enumerate() {
+            let n_valid = n_seq.saturating_sub(i);
+            if n_valid == 0 {
+                self.skipped[i] = Some(logits);
+                continue;
+            }
+            self.skipped[i] = None;
+
+            let targets_ = ctx.tensor_zeroed_like(&targets);
+            shift_targets(&targets_, &targets, i);
+
+            let scale = self.scale(i);
+            let losses_ = ctx.forward(HEAD(i), &mut self.heads[i], [logits, targets_.share()]);
+            accumulate(&losses, &losses_[0], scale, n_valid)
+        }
+        vec![losses.share()]
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([dlosses] = inputs);
+        dims!([_, n_seq] = dlosses);
+
+        let mut dlogits = Vec::with_capacity(self.heads.len());
+        for i in 0..self.heads.len() {
+            if let Some(logits) = self.skipped[i].take() {
+                dlogits.push(ctx.tensor_zeroed_like(&logits).share());
+                continue;
+            }
+            let dlosses_ = ctx.tensor_zeroed_like(&dlosses);
+            accumulate(&dlosses_, &dlosses, self.scale(i), n_seq - i);
+            dlogits.extend(ctx.backward(HEAD(i), &mut self.heads[i], [dlosses_.share()]))
+        }
+        dlogits
+    }
+}
+
+/// 损失项的权重随训练步数的变化。
+#[derive(Clone, Copy, Debug)]
+pub enum Schedule {
+    /// 固定权重。
+    Constant(f32),
+    /// 前 `steps` 步从 `from` 线性变化到 `to`，之后保持 `to`。
+    Linear { from: f32, to: f32, steps: usize },
+    /// 第 `start` 步之前为 0，之后为 `weight`。
+    After { start: usize, weight: f32 },
+}
+
+impl Schedule {
+    /// 第 `step` 步的权重。
+    pub fn weight(&self, step: usize) -> f32 {
+        match *self {
+            Self::Constant(weight) => weight,
+            Self::Linear { from, to, steps } => {
+                if step >= steps {
+                    to
+                } else {
+                    from + (to - from) * step as f32 / steps as f32
+                }
+            }
+            Self::After { start, weight } => {
+                if step >= start {
+                    weight
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+}
+
+/// [`MixedLoss`] 的一项。
+#[derive(Clone, Debug)]
+pub enum LossTerm {
+    /// 交叉熵，即 [`Loss`]。
+    CrossEntropy,
+    /// [`Context::register_op`] 注册的算子，前向输入 `[logits, targets]` 输出逐词的损失 `[batch, n_seq]`，
+    /// 反向输入损失的梯度，返回 logits 的梯度。
+    Custom(String),
+}
+
+enum Term {
+    CrossEntropy(Box<Loss>),
+    Custom(Custom),
+}
+
+/// 多个损失项的加权和，每项的权重按 [`Schedule`] 随步数变化。
+///
+/// 输入为 `[logits, targets]`，输出逐词的组合损失，之后依次是各项未加权的逐词损失，便于分别记录。
+/// 权重为 0 的项不计算，输出的损失为 0。
+pub struct MixedLoss {
+    terms: Box<[(Term, Schedule)]>,
+    step: usize,
+    active: Vec<f32>,
+    logits: Option<Rc<Tensor>>,
+}
+
+#[allow(non_snake_case)]
+fn TERM(i: usize) -> String {
+    format!("term[{i}]")
+}
+
+impl MixedLoss {
+    /// 设置当前的训练步数，决定之后前向和反向使用的权重。
+    pub fn step(&mut self, step: usize) {
+        self.step = step
+    }
+
+    /// 第 `step` 步各项的权重。
+    pub fn weights(&self, step: usize) -> Vec<f32> {
+        self.terms.iter().map(|(_, s)| s.weight(step)).collect()
+    }
+
+    /// 所有交叉熵项的前 `n_prefix` 个位置不计损失，见 [`Loss::prefix`]。
+    pub fn prefix(&mut self, n_prefix: usize) {
+        for (term, _) in &mut self.terms {
+            if let Term::CrossEntropy(loss) = term {
+                loss.prefix(n_prefix)
+            }
+        }
+    }
+}
+
+impl NeuralNetwork for MixedLoss {
+    /// `(n_voc, terms)`
+    type Init = (usize, Vec<(LossTerm, Schedule)>);
+
+    fn init(init: Self::Init, ctx: &mut Context) -> Self {
+        let (n_voc, terms) = init;
+        assert!(!terms.is_empty());
+        let terms = terms
+            .into_iter()
+            .enumerate()
+            .map(|(i, (term, schedule))| {
+                let term = match term {
+                    LossTerm::CrossEntropy => {
+                        Term::CrossEntropy(Box::new(ctx.init(TERM(i), n_voc)))
+                    }
+                    LossTerm::Custom(op) => Term::Custom(ctx.init(TERM(i), op)),
+                };
+                (term, schedule)
+            })
+            .collect();
+        Self {
+            terms,
+            step: 0,
+            active: Vec::new(),
+            logits: None,
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([logits, targets] = inputs);
+        dims!([batch_size, n_seq] = targets);
+        self.active = self.weights(self.step);
+
+        let losses = ctx.tensor_zeroed(types::F32, &[batch_size, n_seq]).share();
+        let mut ans = vec![losses.clone()];
+        for (i, ((term, _), &weight)) in zip(&mut self.terms, &self.active).enumerate() {
+            if weight == 0. {
+                ans.push(ctx.tensor_zeroed(types::F32, &[batch_size, n_seq]).share());
+                continue;
+            }
+            let inputs = [logits.clone(), targets.clone()];
+            let losses_ = match term {
+                Term::CrossEntropy(loss) => ctx.forward(TERM(i), &mut **loss, inputs),
+                Term::Custom(op) => ctx.forward(TERM(i), op, inputs),
+            };
+            accumulate(&losses, &losses_[0], weight, n_seq);
+            ans.push(losses_[0].clone())
+        }
+        self.logits.replace(logits);
+        ans
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([dlosses] = inputs);
+        dims!([_, n_seq] = dlosses);
+
+        let dlogits = ctx.tensor_zeroed_like(&self.logits.take().unwrap());
+        for (i, ((term, _), &weight)) in zip(&mut self.terms, &self.active).enumerate() {
+            if weight == 0. {
+                continue;
+            }
+            let dlosses_ = ctx.tensor_zeroed_like(&dlosses);
+            accumulate(&dlosses_, &dlosses, weight, n_seq);
+            let inputs = [dlosses_.share()];
+            let dlogits_ = match term {
+                Term::CrossEntropy(loss) => ctx.backward(TERM(i), &mut **loss, inputs),
+                Term::Custom(op) => ctx.backward(TERM(i), op, inputs),
+            };
+            add(&dlogits, &dlogits_[0])
+        }
+        vec![dlogits.share()]
+    }
+}
+
+/// 按词表切分的交叉熵损失，输入为本分片的 logits 与完整的目标。
+///
+/// 配合只持有 `wte[range]` 的输出头使用，`range` 由 [`crate::dist::vocab_range`] 给出。
+pub struct VocabParallelLoss {
+    n_voc: usize,
+    start: usize,
+    comm: Rc<dyn Communicator>,
+    targets: Option<Rc<Tensor>>,
+    probs: Option<Tensor>,
+}
+
+impl NeuralNetwork for VocabParallelLoss {
+    /// `(n_voc, start, comm)`
+    type Init = (usize, usize, Rc<dyn Communicator>);
+
+    fn init(init: Self::Init, _ctx: &mut Context) -> Self {
+        let (n_voc, start, comm) = init;
+        Self {
+            n_voc,
+            start,
+            comm,
+            targets: None,
+            probs: None,
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([logits, targets] = inputs);
+        let Self {
+            n_voc, start, comm, ..
+        } = self;
+
+        let probs = ctx.tensor_like(&logits);
+        let losses = ctx.tensor(probs.dt(), &targets.shape());
+        let compensated = ctx.is_compensated_sum();
+        ctx.bench(|| {
+            vocab_parallel_crossentropy(
+                &losses,
+                &probs,
+                &logits,
+                &targets,
+                *start,
+                *n_voc,
+                &**comm,
+                compensated,
+            )
+        });
+
+        self.targets.replace(targets);
+        self.probs.replace(probs);
+        vec![losses.share()]
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([dlosses] = inputs);
+        let Self {
+            start,
+            targets,
+            probs,
+            ..
+        } = self;
+
+        let probs = probs.take().unwrap();
+        let dlogits = ctx.tensor_zeroed_like(&probs);
+
+        let targets = targets.take().unwrap();
+        backward_shard(&dlogits, &dlosses, &probs, &targets, *start);
+
+        vec![dlogits.share()]
+    }
+}
+
+#[test]
+fn test_mixed_loss() {
+    use super::custom::CustomOp;
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    const SHAPE: [usize; 2] = [2, 3];
+    const N_VOC: usize = 8;
+    let logits = (0..SHAPE[0] * SHAPE[1] * N_VOC)
+        .map(|i| ((i * 37 % 23) as f32 - 11.) / 4.)
+        .collect::<Vec<_>>();
+    let targets = [0u16, 3, 6, 4, 5, 1];
+
+    fn tensor<T: Copy>(data: &[T], shape: &[usize], dt: digit_layout::DigitLayout) -> Rc<Tensor> {
+        let data: &[u8] =
+            unsafe { std::slice::from_raw_parts(data.as_ptr().cast(), size_of_val(data)) };
+        crate::Tensor::new(dt, shape)
+            .map(|_| RwRc::new(Blob::from(data)))
+            .share()
+    }
+    fn f32s(t: &Tensor) -> Vec<f32> {
+        let ([], data, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let ans = data.to_vec();
+        t.get().release();
+        ans
+    }
+    let logits = tensor(&logits, &[SHAPE[0], SHAPE[1], N_VOC], types::F32);
+    let targets = tensor(&targets, &SHAPE, types::U16);
+    let dlosses = tensor(&[1f32; 6], &SHAPE, types::F32);
+
+    // 每个位置的损失恒为 1，梯度为 0
+    let mut ctx = Context::new(false);
+    ctx.register_op(
+        "one",
+        CustomOp::new(
+            |x, ctx| {
+                let losses = ctx.tensor(types::F32, &x[1].shape());
+                let ([], data, []) = (unsafe { losses.get().write().align_to_mut::<f32>() }) else {
+                    unreachable!()
+                };
+                data.fill(1.);
+                losses.get().release();
+                vec![losses.share()]
+            },
+            |x, _, ctx| vec![ctx.tensor_zeroed_like(&x[0]).share()],
+        ),
+    );
+
+    let mut ce = ctx.init::<Loss>("ce", N_VOC);
+    let ce_losses = f32s(&ctx.forward("ce", &mut ce, [logits.clone(), targets.clone()])[0]);
+    let ce_dlogits = f32s(&ctx.backward("ce", &mut ce, [dlosses.clone()])[0]);
+
+    let mut mixed = ctx.init::<MixedLoss>(
+        "mixed",
+        (
+            N_VOC,
+            vec![
+                (LossTerm::CrossEntropy, Schedule::Constant(1.)),
+                (
+                    LossTerm::CrossEntropy,
+                    Schedule::Linear {
+                        from: 0.,
+                        to: 1.,
+                        steps: 4,
+                    },
+                ),
+                (
+                    LossTerm::Custom("one".into()),
+                    Schedule::After {
+                        start: 2,
+                        weight: 0.5,
+                    },
+                ),
+            ],
+        ),
+    );
+    for (step, [w_ce, w_one]) in [(0, [1., 0.]), (2, [1.5, 0.5]), (8, [2., 0.5])] {
+        mixed.step(step);
+        let losses = ctx.forward("mixed", &mut mixed, [logits.clone(), targets.clone()]);
+        assert_eq!(losses.len(), 4);
+        for (a, b) in zip(f32s(&losses[0]), &ce_losses) {
+            assert!((a - (w_ce * b + w_one)).abs() < 1e-5)
+        }
+        let dlogits = ctx.backward("mixed", &mut mixed, [dlosses.clone()]);
+        for (a, b) in zip(f32s(&dlogits[0]), &ce_dlogits) {
+            assert!((a - w_ce * b).abs() < 1e-5)
+        }
+    }
+}
+
+#[test]
+fn test_z_loss_softcap() {
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    const SHAPE: [usize; 3] = [1, 2, 8];
+    const N_VOC: usize = 6;
+    let logits = (0..SHAPE.iter().product())
+        .map(|i| ((i * 37 % 23) as f32 - 11.) / 2.)
+        .collect::<Vec<_>>();
+    let targets = [1u16, 4];
+
+    fn tensor<T: Copy>(data: &[T], shape: &[usize], dt: digit_layout::DigitLayout) -> Rc<Tensor> {
+        let data: &[u8] =
+            unsafe { std::slice::from_raw_parts(data.as_ptr().cast(), size_of_val(data)) };
+        crate::Tensor::new(dt, shape)
+            .map(|_| RwRc::new(Blob::from(data)))
+            .share()
+    }
+    fn f32s(t: &Tensor) -> Vec<f32> {
+        let ([], data, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let ans = data.to_vec();
+        t.get().release();
+        ans
+    }
+
+    let mut ctx = Context::new(false);
+    let mut loss = ctx.init::<Loss>("loss", N_VOC);
+    loss.z_loss(0.1);
+    loss.logit_softcap(Some(3.));
+    let targets = tensor(&targets, &SHAPE[..2], types::U16);
+    let total = |ctx: &mut Context, loss: &mut Loss, logits: &[f32]| {
+        let losses = ctx.forward(
+            "loss",
+            loss,
+            [tensor(logits, &SHAPE, types::F32), targets.clone()],
+        );
+        f32s(&losses[0]).iter().map(|&l| l as f64).sum::<f64>()
+    };
+
+    // 数值微分检查反向
+    let _ = total(&mut ctx, &mut loss, &logits);
+    let dlosses = tensor(&[1f32; 2], &SHAPE[..2], types::F32);
+    let dlogits = f32s(&ctx.backward("loss", &mut loss, [dlosses])[0]);
+    let eps = 1e-2;
+    for (i, dx) in dlogits.iter().enumerate() {
+        let mut x = logits.clone();
+        x[i] += eps;
+        let plus = total(&mut ctx, &mut loss, &x);
+        x[i] -= 2. * eps;
+        let minus = total(&mut ctx, &mut loss, &x);
+        let expected = (plus - minus) / (2. * eps as f64);
+        assert!((*dx as f64 - expected).abs() < 1e-2, "{dx} vs {expected}")
+    }
+
+    // 不截断时 z-loss 恰为 `coef * log²Z`
+    loss.logit_softcap(None);
+    let with = total(&mut ctx, &mut loss, &logits);
+    loss.z_loss(0.);
+    let without = total(&mut ctx, &mut loss, &logits);
+    let logz = logits
+        .chunks(8)
+        .map(|x| {
+            x[..N_VOC]
+                .iter()
+                .map(|&x| (x as f64).exp())
+                .sum::<f64>()
+                .ln()
+        })
+        .collect::<Vec<_>>();
+    let expected = logz.iter().map(|z| 0.1 * z * z).sum::<f64>();
+    assert!((with - without - expected).abs() < 1e-3)
+}
+
+#[test]
+fn test_mtp_short_sequence() {
+    use crate::op::fixture::{from_f32, tensor, values};
+
+    // 序列长 2、3 个辅助头：头 1 只有 1 个目标，头 2、3 没有目标
+    const N_VOC: usize = 8;
+    let [n_seq, n_mtp] = [2, 3];
+    let logits = (0..n_seq * N_VOC)
+        .map(|i| ((i * 37 % 23) as f32 - 11.) / 4.)
+        .collect::<Vec<_>>();
+    let logits = || from_f32(&[1, n_seq, N_VOC], &logits).share();
+    let targets = tensor(types::U16, &[1, n_seq], &[3u16, 5]).share();
+
+    let mut ctx = Context::new(false);
+    let mut mtp = ctx.init::<MtpLoss>("mtp", (N_VOC, n_mtp, 0.5));
+    let inputs = (0..=n_mtp).map(|_| logits()).chain([targets.clone()]);
+    let losses = values(&ctx.forward("mtp", &mut mtp, inputs)[0]);
+
+    // 与只有主损失和头 1 的组合一致，头 1 在位置 0 的目标是下下个词
+    let mut ce = ctx.init::<Loss>("ce", N_VOC);
+    let shifted = tensor(types::U16, &[1, n_seq], &[5u16, 0]).share();
+    let ce_shifted = values(&ctx.forward("ce", &mut ce, [logits(), shifted])[0]);
+    let ce = values(&ctx.forward("ce", &mut ce, [logits(), targets])[0]);
+    let expected = [ce[0] + 0.5 / 3. * ce_shifted[0], ce[1]];
+    for (a, b) in zip(losses, expected) {
+        assert!((a - b).abs() < 1e-5, "{a} vs {b}")
+    }
+
+    let dlosses = from_f32(&[1, n_seq], &[1.; 2]).share();
+    let dlogits = ctx.backward("mtp", &mut mtp, [dlosses]);
+    assert_eq!(dlogits.len(), n_mtp + 1);
+    for dlogits in &dlogits[2..] {
+        assert!(values(dlogits).iter().all(|&x| x == 0.))
+    }
+}
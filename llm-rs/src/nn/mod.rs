@@ -1,11 +1,20 @@
-﻿pub mod attention;
+﻿pub mod add;
+pub mod attention;
+pub mod conv1d;
+pub mod cross_attention;
+pub mod custom;
+pub mod dropout;
 pub mod embedding;
 pub mod gelu;
 pub mod gpt2;
 pub mod gpt2_blk;
 pub mod layer_norm;
 pub mod linear;
+pub mod llama;
 pub mod loss;
+pub mod rms_norm;
+pub mod rope;
+pub mod swiglu;
 
 use crate::{blob::Blob, context::Context};
 use std::rc::Rc;
@@ -0,0 +1,87 @@
+use super::{NeuralNetwork, Tensor};
+use crate::{
+    Context,
+    macros::*,
+    op::rms_norm::{backward, forward},
+};
+use digit_layout::types;
+use std::rc::Rc;
+
+/// 只有缩放参数的 RMS 归一化，用于 LLaMA 系列模型。
+pub struct RmsNorm {
+    w: Rc<Tensor>,
+    epsilon: f32,
+    x: Option<Rc<Tensor>>,
+    rstd: Option<Tensor>,
+}
+
+impl NeuralNetwork for RmsNorm {
+    /// 缩放参数和加在均方上的 epsilon，LLaMA 使用 1e-5 或 1e-6。
+    type Init = (Rc<Tensor>, f32);
+
+    fn init(init: Self::Init, _ctx: &mut Context) -> Self {
+        let (w, epsilon) = init;
+        Self {
+            w,
+            epsilon,
+            x: None,
+            rstd: None,
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([x] = inputs);
+        self.x.replace(x);
+        let Self { w, epsilon, x, .. } = self;
+
+        let x = x.as_ref().unwrap();
+        dims!([batch_size, n_seq, d] = x);
+
+        let y = ctx.tensor(x.dt(), &[batch_size, n_seq, d]);
+        let rstd = ctx.tensor(types::F32, &[batch_size, n_seq]);
+
+        ctx.bench(|| {
+            forward::rms_norm(
+                &y.cloned().merge(0, 2),
+                &rstd.cloned().merge(0, 2),
+                &x.cloned().merge(0, 2),
+                w,
+                *epsilon,
+            )
+        });
+
+        self.rstd.replace(rstd);
+
+        vec![y.share()]
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([dy] = inputs);
+        let Self { w, x, rstd, .. } = self;
+
+        let x = x.take().unwrap();
+        let dx = ctx.tensor_zeroed_like(&x);
+
+        let dw = ctx.write_gradient("w", w);
+        ctx.bench(|| {
+            backward::rms_norm(
+                &dx.cloned().merge(0, 2),
+                &dw,
+                &dy.cloned().merge(0, 2),
+                &x.cloned().merge(0, 2),
+                w,
+                &rstd.take().unwrap().merge(0, 2),
+            )
+        });
+
+        vec![dx.share()]
+    }
+}
@@ -0,0 +1,59 @@
+use super::{NeuralNetwork, Tensor};
+use crate::{
+    Context,
+    macros::*,
+    op::rope::{backward, forward},
+};
+use std::rc::Rc;
+
+/// 旋转位置编码，输入形状为 `[batch, n_seq, nh * dh]` 的 Q 或 K。
+pub struct Rope {
+    nh: usize,
+    theta: f32,
+}
+
+impl Rope {
+    /// 复制一份再按头拆开，原地旋转副本。
+    fn rotate(&self, x: &Tensor, ctx: &Context, f: fn(&Tensor, f32)) -> Tensor {
+        dims!([_, _, d] = x);
+        assert_eq!(d % self.nh, 0);
+        assert!(x.is_contiguous());
+        let y = ctx.tensor_like(x);
+        ctx.bench(|| {
+            y.get().write().copy_from_slice(x.get().read());
+            y.get().release();
+            f(&y.cloned().tile(2, &[self.nh, d / self.nh]), self.theta)
+        });
+        y
+    }
+}
+
+impl NeuralNetwork for Rope {
+    /// 头数和旋转的底数，头的维度由通道数除以头数得到。
+    type Init = (usize, f32);
+
+    fn init(init: Self::Init, _ctx: &mut Context) -> Self {
+        let (nh, theta) = init;
+        Self { nh, theta }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([x] = inputs);
+        let y = self.rotate(&x, ctx, forward);
+        vec![y.share()]
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([dy] = inputs);
+        let dx = self.rotate(&dy, ctx, backward);
+        vec![dx.share()]
+    }
+}
@@ -0,0 +1,92 @@
+use super::{NeuralNetwork, Tensor, linear::Linear};
+use crate::{
+    Context,
+    macros::*,
+    op::{
+        add::add,
+        swiglu::{backward, forward},
+    },
+};
+use std::rc::Rc;
+
+const GATE: &str = "gate";
+const UP: &str = "up";
+const DOWN: &str = "down";
+
+/// LLaMA 风格的前馈网络：`down(silu(gate(x)) * up(x))`，三个投影都没有偏置。
+pub struct SwiGlu {
+    gate: Linear,
+    up: Linear,
+    down: Linear,
+    g: Option<Rc<Tensor>>,
+    u: Option<Rc<Tensor>>,
+}
+
+impl NeuralNetwork for SwiGlu {
+    /// `[gate, up, down]` 的权重，形状为 `[d_ffn, d]`、`[d_ffn, d]`、`[d, d_ffn]`。
+    type Init = [Rc<Tensor>; 3];
+
+    fn init(init: Self::Init, ctx: &mut Context) -> Self {
+        let [gate, up, down] = init;
+        Self {
+            gate: ctx.init(GATE, (gate, None)),
+            up: ctx.init(UP, (up, None)),
+            down: ctx.init(DOWN, (down, None)),
+            g: None,
+            u: None,
+        }
+    }
+
+    fn forward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        destruct!([x] = inputs);
+        let Self {
+            gate,
+            up,
+            down,
+            g,
+            u,
+        } = self;
+
+        destruct!([g_] = ctx.forward(GATE, gate, [x.clone()]));
+        destruct!([u_] = ctx.forward(UP, up, [x]));
+
+        let h = ctx.tensor_like(&g_);
+        ctx.bench(|| forward(&h, &g_, &u_));
+
+        g.replace(g_);
+        u.replace(u_);
+        ctx.forward(DOWN, down, [h.share()])
+    }
+
+    fn backward(
+        &mut self,
+        inputs: impl IntoIterator<Item = Rc<Tensor>>,
+        ctx: &mut Context,
+    ) -> Vec<Rc<Tensor>> {
+        let Self {
+            gate,
+            up,
+            down,
+            g,
+            u,
+        } = self;
+
+        destruct!([dh] = ctx.backward(DOWN, down, inputs));
+        let g = g.take().unwrap();
+        let u = u.take().unwrap();
+        let dg = ctx.tensor_zeroed_like(&g);
+        let du = ctx.tensor_zeroed_like(&u);
+        ctx.bench(|| backward(&dg, &du, &dh, &g, &u));
+
+        // 两个分支共享输入，梯度相加
+        destruct!([dx] = ctx.backward(GATE, gate, [dg.share()]));
+        destruct!([dx_up] = ctx.backward(UP, up, [du.share()]));
+        add(&dx, &dx_up);
+
+        vec![dx]
+    }
+}
@@ -5,6 +5,10 @@ pub fn add(y: &Tensor, x: &Tensor) {
     clone_tensor!(y x);
 
     assert_eq!(y.shape(), x.shape());
+    #[cfg(feature = "reference")]
+    if y.dt() == digit_layout::types::F64 {
+        return super::reference::add(&y, &x);
+    }
     let ndim = y.layout().ndim();
     let y = y.as_ref().merge(0, ndim);
     let x = x.as_ref().merge(0, ndim);
@@ -15,3 +19,19 @@ pub fn add(y: &Tensor, x: &Tensor) {
         *y += x
     }
 }
+
+/// `y = a·y + b·x`
+pub fn axpby(y: &Tensor, x: &Tensor, a: f32, b: f32) {
+    clone_tensor!(y x);
+
+    assert_eq!(y.shape(), x.shape());
+    let ndim = y.layout().ndim();
+    let y = y.as_ref().merge(0, ndim);
+    let x = x.as_ref().merge(0, ndim);
+    for (y, x) in std::iter::zip(
+        y.map(|t| &mut **t.write()).vector_mut::<f32>(),
+        x.map(|t| &**t.write()).vector::<f32>(),
+    ) {
+        *y = a * *y + b * x
+    }
+}
@@ -1,13 +1,319 @@
-﻿use super::{Tensor, unique};
+﻿use super::{Tensor, expsum, is_half, store_f32, to_f32, unique};
 use crate::macros::*;
 use digit_layout::types;
 use itertools::izip;
-use std::{iter::zip, slice::from_raw_parts_mut};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{
+    iter::zip,
+    slice::{from_raw_parts, from_raw_parts_mut},
+};
 
-pub fn forward(y: &Tensor, preatt: &Tensor, att: &Tensor, x: &Tensor) {
+/// T5 风格的相对位置偏置，`table` 形状为 `[nh, n_buckets]`。
+pub struct RelativeBias<'a> {
+    pub table: &'a Tensor,
+    pub max_distance: usize,
+}
+
+impl RelativeBias<'_> {
+    pub(super) fn check(&self, nh: usize) -> (Tensor, usize) {
+        let table = self.table.cloned();
+        assert_eq!(table.dt(), types::F32);
+        assert!(table.is_contiguous());
+        dims!([nh_, n_buckets] = table);
+        assert_eq!(nh, nh_);
+        (table.merge(0, 2), n_buckets)
+    }
+}
+
+/// 稀疏注意力模式（BigBird 风格）：局部窗口、步长全局位置和开头的全局词。
+///
+/// 查询位置 `q` 可见键位置 `k` 当且仅当 `k <= q` 且满足以下之一：
+///
+/// - `q - k < window`；
+/// - `k < n_global`；
+/// - `stride > 0` 且 `k % stride == stride - 1`。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SparsePattern {
+    pub window: usize,
+    pub stride: usize,
+    pub n_global: usize,
+}
+
+impl SparsePattern {
+    #[inline]
+    pub fn attend(&self, q: usize, k: usize) -> bool {
+        k <= q
+            && (q - k < self.window
+                || k < self.n_global
+                || (self.stride > 0 && k % self.stride == self.stride - 1))
+    }
+}
+
+/// T5 的相对位置分桶：近处每个距离一个桶，远处按对数共享桶。
+///
+/// `relative` 为键位置减查询位置，因果注意力中总是非正数。
+pub fn relative_bucket(relative: isize, n_buckets: usize, max_distance: usize) -> usize {
+    let n = (-relative).max(0) as usize;
+    let max_exact = n_buckets / 2;
+    if n < max_exact {
+        n
+    } else {
+        let ratio =
+            (n as f32 / max_exact as f32).ln() / (max_distance as f32 / max_exact as f32).ln();
+        (max_exact + (ratio * (n_buckets - max_exact) as f32) as usize).min(n_buckets - 1)
+    }
+}
+
+/// ALiBi 各头的斜率：头数为 2 的幂 `n` 时第 `i` 个头为 `2^(-8(i+1)/n)`，
+/// 否则取最近的 2 的幂的斜率，再从两倍头数的斜率中隔一个补足（与 BLOOM 一致）。
+pub fn alibi_slopes(nh: usize) -> Vec<f32> {
+    let slopes = |n: usize| (1..=n).map(move |i| 2f32.powf(-8. * i as f32 / n as f32));
+    let n = 1 << nh.ilog2();
+    slopes(n)
+        .chain(slopes(2 * n).step_by(2).take(nh - n))
+        .collect()
+}
+
+/// 注意力的可选项，缺省为普通的因果注意力。
+#[derive(Default)]
+pub struct AttentionConfig<'a> {
+    /// T5 相对位置偏置，反向时 `table` 为累加梯度的张量。
+    pub bias: Option<RelativeBias<'a>>,
+    /// ALiBi 每个头的斜率，查询 `t` 对键 `t_` 的得分加上 `-slope * |t - t_|`。
+    ///
+    /// 偏置是常数，反向无需处理。
+    pub alibi: Option<&'a [f32]>,
+    /// 不可见的位置注意力权重为 0，反向无需处理。
+    pub sparse: Option<SparsePattern>,
+    /// 前 `prefix` 个位置是双向的前缀（Prefix-LM），任何查询都可见全部前缀，其后仍为因果注意力。
+    /// 反向须与前向一致。
+    pub prefix: usize,
+    /// K、V 的头数，缺省与查询头数相同。少于查询头数时为分组查询注意力（GQA），为 1 时为多查询注意力（MQA），
+    /// 每个 K、V 头由连续的 `nh / nkvh` 个查询头共享，qkv 打包为 `[q | k | v]`，宽度为 `(nh + 2 * nkvh) * dh`。
+    /// 反向须与前向一致。
+    pub nkvh: Option<usize>,
+    /// 批中每个序列的有效长度，之后的位置是填充：填充的键不可见，填充的查询输出为 0，反向时跳过。
+    pub lens: Option<&'a [usize]>,
+    /// 形状为 `[batch, n_seq, n_seq]` 的 `Bool` 掩码，`[b, t, t_]` 为 false 时查询 `t` 不可见键 `t_`，
+    /// 与因果、前缀和稀疏规则同时生效，用于左填充、多个文档拼接等任意形状的填充。
+    /// 没有可见位置的查询输出为 0。不可见位置的权重为 0，反向无需处理。
+    pub mask: Option<&'a Tensor>,
+    /// softmax 的指数和使用 Kahan 补偿求和，见 [`crate::Context::set_compensated_sum`]。
+    pub compensated: bool,
+}
+
+impl AttentionConfig<'_> {
+    /// 是否为 f64 参考实现支持的普通因果注意力。
+    #[cfg(feature = "reference")]
+    fn is_plain(&self) -> bool {
+        self.bias.is_none()
+            && self.alibi.is_none()
+            && self.sparse.is_none()
+            && self.prefix == 0
+            && self.nkvh.is_none()
+            && self.lens.is_none()
+            && self.mask.is_none()
+    }
+
+    fn check_lens(&self, batch_size: usize, n_seq: usize) -> Option<&[usize]> {
+        self.lens.inspect(|lens| {
+            assert_eq!(lens.len(), batch_size);
+            assert!(lens.iter().all(|&len| len <= n_seq))
+        })
+    }
+
+    fn check_mask(&self, batch_size: usize, n_seq: usize) -> Option<Tensor> {
+        self.mask.map(|mask| {
+            assert_eq!(mask.dt(), types::Bool);
+            assert_eq!(&*mask.shape(), [batch_size, n_seq, n_seq]);
+            assert!(mask.is_contiguous());
+            mask.cloned().merge(0, 3)
+        })
+    }
+
+    /// 检查 qkv 的宽度，返回 K、V 的头数。
+    pub(super) fn kv_heads(&self, nh: usize, d: usize, d3: usize) -> usize {
+        let nkvh = self.nkvh.unwrap_or(nh);
+        assert_eq!(nh % nkvh, 0);
+        assert_eq!(d3, d + 2 * d / nh * nkvh);
+        nkvh
+    }
+
+    /// 检查并读出偏置表和掩码，在 `f` 中使用解析得到的 [`Rules`]。
+    pub(super) fn with_rules<T>(
+        &self,
+        batch_size: usize,
+        nh: usize,
+        n_seq: usize,
+        f: impl FnOnce(&Rules) -> T,
+    ) -> T {
+        if let Some(alibi) = self.alibi {
+            assert_eq!(alibi.len(), nh)
+        }
+        let lens = self.check_lens(batch_size, n_seq);
+        let mask = self.check_mask(batch_size, n_seq);
+        let bias = self
+            .bias
+            .as_ref()
+            .map(|bias| (bias.check(nh), bias.max_distance));
+        f(&Rules {
+            n_seq,
+            prefix: self.prefix,
+            bias: bias.as_ref().map(|((table, n_buckets), max_distance)| {
+                let table = table.as_ref().map(|b| &**b.read()).vector::<f32>();
+                (table, *n_buckets, *max_distance)
+            }),
+            alibi: self.alibi,
+            sparse: self.sparse,
+            lens,
+            mask: mask
+                .as_ref()
+                .map(|mask| mask.as_ref().map(|b| &**b.read()).vector::<bool>()),
+        })
+    }
+}
+
+/// 由 [`AttentionConfig`] 解析出的可见性和偏置规则，朴素实现和分块实现共用。
+pub(super) struct Rules<'a> {
+    n_seq: usize,
+    prefix: usize,
+    bias: Option<(&'a [f32], usize, usize)>,
+    alibi: Option<&'a [f32]>,
+    sparse: Option<SparsePattern>,
+    lens: Option<&'a [usize]>,
+    mask: Option<&'a [bool]>,
+}
+
+impl Rules<'_> {
+    /// 查询 `t` 在因果和前缀规则下可能看到的键的数量。
+    pub fn n_vis(&self, t: usize) -> usize {
+        (t + 1).max(self.prefix).min(self.n_seq)
+    }
+
+    /// 序列 `b` 的有效长度。
+    pub fn len(&self, b: usize) -> usize {
+        self.lens.map_or(self.n_seq, |lens| lens[b])
+    }
+
+    pub fn has_bias(&self) -> bool {
+        self.bias.is_some() || self.alibi.is_some()
+    }
+
+    pub fn has_mask(&self) -> bool {
+        self.sparse.is_some() || self.lens.is_some() || self.mask.is_some()
+    }
+
+    /// 头 `h` 的查询 `t` 对键 `t_` 的加性偏置。
+    pub fn bias(&self, h: usize, t: usize, t_: usize) -> f32 {
+        let rel = self.bias.map_or(0., |(table, n_buckets, max_distance)| {
+            let bucket = relative_bucket(t_ as isize - t as isize, n_buckets, max_distance);
+            table[h * n_buckets + bucket]
+        });
+        rel - self
+            .alibi
+            .map_or(0., |alibi| alibi[h] * t.abs_diff(t_) as f32)
+    }
+
+    /// 在 `n_vis` 范围内，序列 `b` 的查询 `t` 是否可见键 `t_`。
+    ///
+    /// 填充的查询不可见任何位置，填充的键对所有查询不可见。
+    pub fn visible(&self, b: usize, t: usize, t_: usize) -> bool {
+        let len = self.len(b);
+        t < len
+            && t_ < len
+            && self
+                .sparse
+                .is_none_or(|p| t_ < self.prefix || p.attend(t, t_))
+            && self
+                .mask
+                .is_none_or(|m| m[(b * self.n_seq + t) * self.n_seq + t_])
+    }
+}
+
+/// 融合的缩放、偏置、掩码和 softmax，计算一行注意力权重。
+///
+/// `att` 输入未缩放的 `q·k`，原地输出 `softmax(scale * att + bias)`；
+/// `bias` 为加性偏置（ALiBi、相对位置偏置），`mask` 中为 false 的位置和 `len` 之后的位置权重为 0，
+/// 全部位置都不可见时权重全为 0。
+/// `preatt` 非空时写入 softmax 之前的得分，不可见的位置为负无穷。
+/// `compensated` 同 [`AttentionConfig::compensated`]。
+pub fn scale_mask_softmax(
+    att: &mut [f32],
+    mut preatt: Option<&mut [f32]>,
+    scale: f32,
+    bias: Option<&[f32]>,
+    mask: Option<&[bool]>,
+    len: usize,
+    compensated: bool,
+) {
+    let (att, tail) = att.split_at_mut(len);
+
+    // pass 1: scale, add bias, apply mask and find maxval
+    let mut max = f32::NEG_INFINITY;
+    for (i, val) in att.iter_mut().enumerate() {
+        *val = if mask.is_none_or(|m| m[i]) {
+            *val * scale + bias.map_or(0., |b| b[i])
+        } else {
+            f32::NEG_INFINITY
+        };
+        if let Some(preatt) = &mut preatt {
+            preatt[i] = *val
+        }
+        max = max.max(*val)
+    }
+
+    // 没有可见位置时权重全为 0
+    if max == f32::NEG_INFINITY {
+        att.fill(0.);
+        tail.fill(0.);
+        return;
+    }
+
+    // pass 2: calculate the exp and sum them up
+    for val in &mut *att {
+        *val = (*val - max).exp()
+    }
+    let expsum_inv = 1. / expsum(att, compensated);
+
+    // pass 3: normalize to get the softmax
+    for val in att {
+        *val *= expsum_inv
+    }
+    tail.fill(0.)
+}
+
+/// `kv` 为提供 K、V 的 qkv 张量，用于跨层共享 KV，缺省时使用 `x` 自身的 K、V。
+pub fn forward(
+    y: &Tensor,
+    preatt: &Tensor,
+    att: &Tensor,
+    x: &Tensor,
+    kv: Option<&Tensor>,
+    config: &AttentionConfig,
+) {
     clone_tensor!(y preatt att x);
+    let kv = kv.map(Tensor::cloned);
+    if let Some(kv) = &kv {
+        assert_eq!(kv.dt(), x.dt());
+        assert_eq!(kv.shape(), x.shape());
+    }
 
     let dt = unique(&[y.dt(), preatt.dt(), att.dt(), x.dt()]).unwrap();
+    if is_half(dt) {
+        // 以 f32 计算，输出再转换回半精度
+        let [y_, preatt_, att_, x_] = [&y, &preatt, &att, &x].map(to_f32);
+        let kv_ = kv.as_ref().map(to_f32);
+        forward(&y_, &preatt_, &att_, &x_, kv_.as_ref(), config);
+        for (dst, src) in [(&y, &y_), (&preatt, &preatt_), (&att, &att_)] {
+            store_f32(dst, src)
+        }
+        return;
+    }
+    #[cfg(feature = "reference")]
+    if dt == types::F64 {
+        assert!(kv.is_none() && config.is_plain());
+        return super::reference::attention(&y, &preatt, &att, &x);
+    }
     assert_eq!(dt, types::F32);
 
     dims!([batch_size_0, n_seq_0, d] = y);
@@ -18,97 +324,123 @@ pub fn forward(y: &Tensor, preatt: &Tensor, att: &Tensor, x: &Tensor) {
     let batch_size = unique(&[batch_size_0, batch_size_1, batch_size_2, batch_size_3]).unwrap();
     let n_seq = unique(&[n_seq_0, n_seq_1, n_seq_2, n_seq_3, n_seq_4, n_seq_5]).unwrap();
     let nh = unique(&[nh_0, nh_1]).unwrap();
-    let d = unique(&[d, d3 / 3]).unwrap();
     let dh = d / nh;
+    let d_kv = config.kv_heads(nh, d, d3) * dh;
+    let group = d / d_kv;
     let scale = (dh as f32).powf(-0.5);
+    let compensated = config.compensated;
 
-    for b in 0..batch_size {
-        let qkv = x.as_ref().index(&[b]);
-        for t in 0..n_seq {
-            let q = qkv
-                .as_deref()
-                .index(&[t])
-                .map(|b| &**b.read())
-                .vector::<f32>();
-            let y = y
-                .as_ref()
-                .index(&[b, t])
-                .map(|b| &mut **b.write())
-                .vector_mut::<f32>();
-
-            for h in 0..nh {
-                let y = &mut y[h * dh..][..dh];
-                let q = &q[h * dh..][..dh];
-
-                let preatt = preatt
-                    .as_ref()
-                    .index(&[b, h, t])
-                    .map(|b| &mut **b.write())
-                    .vector_mut::<f32>();
-                let att = att
-                    .as_ref()
-                    .index(&[b, h, t])
-                    .map(|b| &mut **b.write())
-                    .vector_mut::<f32>();
-                let (preatt, _) = preatt.split_at_mut(t + 1);
-                let (att, tail) = att.split_at_mut(t + 1);
-
-                // pass 1: calculate query dot key and maxval
-                let mut max = f32::NEG_INFINITY;
-                for (t_, val) in preatt.iter_mut().enumerate() {
-                    let k = &qkv
-                        .as_deref()
-                        .index(&[t_])
-                        .map(|b| &**b.read())
-                        .vector::<f32>()[d..][h * dh..][..dh];
-                    *val = zip(q, k).map(|(&q, &k)| q * k).sum::<f32>() * scale;
-                    if *val > max {
-                        max = *val
-                    }
-                }
+    // 每个 (批, 头, 查询位置) 相互独立，并行计算
+    assert!(x.is_contiguous() && y.is_contiguous());
+    assert!(preatt.is_contiguous() && att.is_contiguous());
+    let kv = kv.as_ref().unwrap_or(&x);
+    assert!(kv.is_contiguous());
+    let x = x.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let kv = kv.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+    let preatt = preatt.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+    let att = att.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
 
-                // pass 2: calculate the exp and keep track of sum
-                let mut expsum = 0.;
-                for (att, preatt) in zip(&mut *att, preatt) {
-                    *att = (*preatt - max).exp();
-                    expsum += *att
-                }
-                let expsum_inv = 1. / expsum;
+    config.with_rules(batch_size, nh, n_seq, |rules| {
+        (0..batch_size * nh * n_seq).into_par_iter().for_each(|i| {
+            let (b, h, t) = (i / (nh * n_seq), i / n_seq % nh, i % n_seq);
+            let n_vis = rules.n_vis(t);
+            let row = |base: usize, t: usize| unsafe {
+                from_raw_parts((base as *const f32).add((b * n_seq + t) * d3), d3)
+            };
+            let q = &row(x, t)[h * dh..][..dh];
+            let y = unsafe {
+                from_raw_parts_mut((y as *mut f32).add((b * n_seq + t) * d + h * dh), dh)
+            };
+            let preatt = unsafe { from_raw_parts_mut((preatt as *mut f32).add(i * n_seq), n_vis) };
+            let att = unsafe { from_raw_parts_mut((att as *mut f32).add(i * n_seq), n_seq) };
 
-                // pass 3: normalize to get the softmax
-                for val in &mut *att {
-                    *val *= expsum_inv
-                }
-                tail.fill(0.);
+            // 偏置和掩码按需逐行构造，再与缩放、softmax 融合计算
+            for (t_, val) in att[..n_vis].iter_mut().enumerate() {
+                let k = &row(kv, t_)[d + h / group * dh..][..dh];
+                *val = zip(q, k).map(|(&q, &k)| q * k).sum::<f32>()
+            }
+            let row_bias = rules.has_bias().then(|| {
+                (0..n_vis)
+                    .map(|t_| rules.bias(h, t, t_))
+                    .collect::<Vec<_>>()
+            });
+            let row_mask = rules.has_mask().then(|| {
+                (0..n_vis)
+                    .map(|t_| rules.visible(b, t, t_))
+                    .collect::<Vec<_>>()
+            });
+            scale_mask_softmax(
+                att,
+                Some(preatt),
+                scale,
+                row_bias.as_deref(),
+                row_mask.as_deref(),
+                n_vis,
+                compensated,
+            );
 
-                // pass 4: accumulate weighted values into the output of attention
-                y.fill(0.);
-                for (t_, val) in att.iter_mut().enumerate() {
-                    let v = &qkv
-                        .as_ref()
-                        .index(&[t_])
-                        .map(|b| &**b.read())
-                        .vector::<f32>()[d * 2..][h * dh..][..dh];
-                    for (y, v) in zip(&mut *y, v) {
-                        *y += *val * v
-                    }
+            // pass 4: accumulate weighted values into the output of attention
+            y.fill(0.);
+            for (t_, val) in att[..n_vis].iter().enumerate() {
+                let v = &row(kv, t_)[d + d_kv + h / group * dh..][..dh];
+                for (y, v) in zip(&mut *y, v) {
+                    *y += *val * v
                 }
             }
-        }
-    }
+        })
+    })
 }
 
+/// `kv` 为前向时共享的 `(kv, dkv)`，K、V 的梯度累加到 `dkv`，缺省时累加到 `dx`。
+/// `config` 的 `prefix` 须与前向一致，相对位置偏置的 `table` 换成梯度，其余各项不影响反向。
+#[allow(clippy::too_many_arguments)]
 pub fn backward(
     dx: &Tensor,
     dpreatt: &Tensor,
     datt: &Tensor,
     dy: &Tensor,
     x: &Tensor,
+    kv: Option<(&Tensor, &Tensor)>,
     att: &Tensor,
+    config: &AttentionConfig,
 ) {
     clone_tensor!(dx dpreatt datt dy x att);
+    let kv = kv.map(|(kv, dkv)| (kv.cloned(), dkv.cloned()));
+    if let Some((kv, dkv)) = &kv {
+        assert_eq!(kv.shape(), x.shape());
+        assert_eq!(dkv.shape(), dx.shape());
+    }
 
     let dt = unique(&[dx.dt(), dpreatt.dt(), datt.dt(), dy.dt(), x.dt(), att.dt()]).unwrap();
+    if is_half(dt) {
+        // 梯度以 f32 累加，完成后转换回半精度
+        let [dx_, dpreatt_, datt_, dy_, x_, att_] =
+            [&dx, &dpreatt, &datt, &dy, &x, &att].map(to_f32);
+        let kv_ = kv.as_ref().map(|(kv, dkv)| (to_f32(kv), to_f32(dkv)));
+        backward(
+            &dx_,
+            &dpreatt_,
+            &datt_,
+            &dy_,
+            &x_,
+            kv_.as_ref().map(|(kv, dkv)| (kv, dkv)),
+            &att_,
+            config,
+        );
+        for (dst, src) in [(&dx, &dx_), (&dpreatt, &dpreatt_), (&datt, &datt_)] {
+            store_f32(dst, src)
+        }
+        if let (Some((_, dkv)), Some((_, dkv_))) = (&kv, &kv_) {
+            store_f32(dkv, dkv_)
+        }
+        return;
+    }
+    #[cfg(feature = "reference")]
+    if dt == types::F64 {
+        assert!(kv.is_none() && config.is_plain());
+        return super::reference::attention_backward(&dx, &dpreatt, &datt, &dy, &x, &att);
+    }
     assert_eq!(dt, types::F32);
 
     dims!([batch_size_0, n_seq_0, d3_0] = dx);
@@ -132,16 +464,36 @@ pub fn backward(
     ])
     .unwrap();
     let nh = unique(&[nh_0, nh_1, nh_2]).unwrap();
-    let d = unique(&[d3_0 / 3, d3_1 / 3, d_0]).unwrap();
+    let (d, d3) = (d_0, unique(&[d3_0, d3_1]).unwrap());
 
     let dh = d / nh;
+    let d_kv = config.kv_heads(nh, d, d3) * dh;
+    let group = d / d_kv;
     let scale = (dh as f32).powf(-0.5);
+    let prefix = config.prefix;
+    let lens = config.check_lens(batch_size, n_seq);
+    let dbias = config
+        .bias
+        .as_ref()
+        .map(|dbias| (dbias.check(nh), dbias.max_distance));
+    let mut dbias = dbias.as_ref().map(|((dtable, n_buckets), max_distance)| {
+        let dtable = dtable
+            .as_ref()
+            .map(|b| &mut **b.write())
+            .vector_mut::<f32>();
+        (dtable, *n_buckets, *max_distance)
+    });
 
     for b in 0..batch_size {
-        for t in 0..n_seq {
+        // 填充的查询没有梯度，填充的键权重为 0，都跳过
+        let len = lens.map_or(n_seq, |lens| lens[b]);
+        for t in 0..len {
+            let n_vis = (t + 1).max(prefix).min(len);
             for h in 0..nh {
-                let dx = dx.as_ref().index(&[b]);
-                let x = x.as_ref().index(&[b]);
+                // 同组的查询头共享一个 K、V 头，梯度累加到共享的头上
+                let hk = h / group;
+                let dkv = kv.as_ref().map_or(&dx, |(_, dkv)| dkv).as_ref().index(&[b]);
+                let kv = kv.as_ref().map_or(&x, |(kv, _)| kv).as_ref().index(&[b]);
 
                 let dpreatt = dpreatt
                     .as_ref()
@@ -164,16 +516,20 @@ pub fn backward(
                     .map(|b| &**b.read())
                     .vector::<f32>();
 
-                for t_ in 0..=t {
-                    let dqkv = dx
+                for t_ in 0..n_vis {
+                    let dkv = dkv
                         .as_ref()
                         .index(&[t_])
                         .map(|b| &mut **b.write())
                         .vector_mut::<f32>();
-                    let qkv = x.as_ref().index(&[t_]).map(|b| &**b.read()).vector::<f32>();
+                    let kv = kv
+                        .as_ref()
+                        .index(&[t_])
+                        .map(|b| &**b.read())
+                        .vector::<f32>();
 
-                    let dv = &mut dqkv[2 * d..][h * dh..][..dh];
-                    let v = &qkv[2 * d..][h * dh..][..dh];
+                    let dv = &mut dkv[d + d_kv + hk * dh..][..dh];
+                    let v = &kv[d + d_kv + hk * dh..][..dh];
                     let datt = &mut datt[t_];
                     let att = att[t_];
 
@@ -182,26 +538,43 @@ pub fn backward(
                         *dv += att * dy;
                     }
                 }
-                for t_ in 0..=t {
-                    for t__ in 0..=t {
+                for t_ in 0..n_vis {
+                    for t__ in 0..n_vis {
                         let indicator = if t_ == t__ { 1. } else { 0. };
                         dpreatt[t__] += att[t_] * (indicator - att[t__]) * datt[t_];
                     }
                 }
+                if let Some((dtable, n_buckets, max_distance)) = &mut dbias {
+                    for (t_, dpreatt) in dpreatt[..n_vis].iter().enumerate() {
+                        let bucket =
+                            relative_bucket(t_ as isize - t as isize, *n_buckets, *max_distance);
+                        dtable[h * *n_buckets + bucket] += dpreatt
+                    }
+                }
 
                 let dqkv = dx
                     .as_ref()
+                    .index(&[b])
                     .merge(0, 2)
                     .map(|b| &mut **b.write())
                     .vector_mut::<f32>();
-                let qkv = x.as_ref().merge(0, 2).map(|b| &**b.read()).vector::<f32>();
-
-                let dq =
-                    unsafe { from_raw_parts_mut(dqkv[t * 3 * d..][h * dh..].as_mut_ptr(), dh) };
-                let q = &qkv[t * 3 * d..][h * dh..][..dh];
-                for t in 0..=t {
-                    let dk = &mut dqkv[(t * 3 + 1) * d..][h * dh..][..dh];
-                    let k = &qkv[(t * 3 + 1) * d..][h * dh..][..dh];
+                let qkv = x
+                    .as_ref()
+                    .index(&[b])
+                    .merge(0, 2)
+                    .map(|b| &**b.read())
+                    .vector::<f32>();
+                let dkv = dkv
+                    .merge(0, 2)
+                    .map(|b| &mut **b.write())
+                    .vector_mut::<f32>();
+                let kv = kv.merge(0, 2).map(|b| &**b.read()).vector::<f32>();
+
+                let dq = unsafe { from_raw_parts_mut(dqkv[t * d3 + h * dh..].as_mut_ptr(), dh) };
+                let q = &qkv[t * d3 + h * dh..][..dh];
+                for t in 0..n_vis {
+                    let dk = &mut dkv[t * d3 + d + hk * dh..][..dh];
+                    let k = &kv[t * d3 + d + hk * dh..][..dh];
                     let dpreatt = dpreatt[t];
 
                     for (dq, q, dk, k) in izip!(&mut *dq, q, dk, k) {
@@ -213,3 +586,650 @@ pub fn backward(
         }
     }
 }
+
+/// 分块存储的一个序列的 K、V。
+///
+/// `k`、`v` 形状为 `[n_blocks, block_size, nkvh, dh]`，可以是任意步长的视图，
+/// `nkvh` 少于查询头数时同组的查询头共享 K、V 头，见 [`AttentionConfig::nkvh`]；
+/// 序列的第 `t` 个位置在第 `table[t / block_size]` 块的第 `t % block_size` 行。
+/// `k`、`v` 也可以是 int8，此时 `scales` 为两者每个位置每个头的反量化比例 `[n_blocks, block_size, nkvh]`，
+/// 由 [`quant_heads`] 写入。
+pub struct Paged<'a> {
+    pub k: &'a Tensor,
+    pub v: &'a Tensor,
+    pub scales: Option<[&'a Tensor; 2]>,
+    pub table: &'a [usize],
+    pub len: usize,
+}
+
+/// 单步解码：每个序列的一个查询对其全部 `len` 个缓存位置做注意力。
+///
+/// `y`、`q` 形状为 `[batch, nh, dh]`，`kv` 每个序列一个。K、V 按块表直接从各块中读取，
+/// 不拼接、不复制，也不构造 preatt 和 att。查询位于序列末尾，`alibi` 同 [`AttentionConfig::alibi`]，
+/// `compensated` 同 [`AttentionConfig::compensated`]。
+pub fn decode(y: &Tensor, q: &Tensor, kv: &[Paged], alibi: Option<&[f32]>, compensated: bool) {
+    let [y, q] = [y, q].map(|t| {
+        let batch_size = t.shape()[0];
+        t.cloned().tile(0, &[batch_size, 1])
+    });
+    prefill(&y, &q, kv, alibi, compensated)
+}
+
+/// 预填充：每个序列的 `n_seq` 个新查询对缓存做因果注意力，新位置的 K、V 须已写入缓存。
+///
+/// `y`、`q` 形状为 `[batch, n_seq, nh, dh]`，第 `t` 个查询位于缓存的第 `len - n_seq + t` 个位置，
+/// 只看到它和它之前的位置。所有序列、位置和头的查询在一次并行中完成，其余同 [`decode`]。
+/// K、V 的行在 `dh` 维连续时直接以切片做点积，否则先收集到连续的缓冲区。
+pub fn prefill(y: &Tensor, q: &Tensor, kv: &[Paged], alibi: Option<&[f32]>, compensated: bool) {
+    clone_tensor!(y q);
+    assert_eq!(unique(&[y.dt(), q.dt()]), Some(types::F32));
+
+    dims!([batch_size_0, n_seq_0, nh_0, dh_0] = y);
+    dims!([batch_size_1, n_seq_1, nh_1, dh_1] = q);
+    let batch_size = unique(&[batch_size_0, batch_size_1, kv.len()]).unwrap();
+    let n_seq = unique(&[n_seq_0, n_seq_1]).unwrap();
+    let nh = unique(&[nh_0, nh_1]).unwrap();
+    let dh = unique(&[dh_0, dh_1]).unwrap();
+    let scale = (dh as f32).powf(-0.5);
+    if let Some(alibi) = alibi {
+        assert_eq!(alibi.len(), nh)
+    }
+
+    strides!([sby, sty, shy, sdy] = y);
+    strides!([sbq, stq, shq, sdq] = q);
+    let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<u8>() as usize;
+    let q = q.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+
+    // 每个序列的 K、V 基址和步长，int8 时另有比例的基址和步长
+    struct Pages<'a> {
+        k: usize,
+        v: usize,
+        sk: [isize; 4],
+        sv: [isize; 4],
+        scales: Option<[(usize, [isize; 3]); 2]>,
+        block_size: usize,
+        group: usize,
+        table: &'a [usize],
+        len: usize,
+    }
+    let kv = kv
+        .iter()
+        .map(
+            |&Paged {
+                 k,
+                 v,
+                 scales,
+                 table,
+                 len,
+             }| {
+                let (k, v) = (k.cloned(), v.cloned());
+                let dt = if scales.is_some() {
+                    types::I8
+                } else {
+                    types::F32
+                };
+                assert_eq!(unique(&[k.dt(), v.dt()]), Some(dt));
+                let scales = scales.map(|scales| {
+                    scales.map(|scale| {
+                        let scale = scale.cloned();
+                        assert_eq!(scale.dt(), types::F32);
+                        assert_eq!(scale.shape()[..], k.shape()[..3]);
+                        strides!([s0, s1, s2] = scale);
+                        let ptr = scale.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+                        (ptr, [s0, s1, s2])
+                    })
+                });
+                dims!([n_blocks_0, block_size_0, nh_2, dh_2] = k);
+                dims!([n_blocks_1, block_size_1, nh_3, dh_3] = v);
+                let n_blocks = unique(&[n_blocks_0, n_blocks_1]).unwrap();
+                let block_size = unique(&[block_size_0, block_size_1]).unwrap();
+                let nkvh = unique(&[nh_2, nh_3]).unwrap();
+                assert_eq!(nh % nkvh, 0);
+                assert_eq!(unique(&[dh, dh_2, dh_3]), Some(dh));
+                assert!(len >= n_seq && len > 0 && len <= table.len() * block_size);
+                assert!(table.iter().all(|&i| i < n_blocks));
+
+                strides!([s0, s1, s2, s3] = k);
+                let sk = [s0, s1, s2, s3];
+                strides!([s0, s1, s2, s3] = v);
+                let sv = [s0, s1, s2, s3];
+                Pages {
+                    k: k.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize,
+                    v: v.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize,
+                    sk,
+                    sv,
+                    scales,
+                    block_size,
+                    group: nh / nkvh,
+                    table,
+                    len,
+                }
+            },
+        )
+        .collect::<Vec<_>>();
+
+    (0..batch_size * n_seq * nh).into_par_iter().for_each_init(
+        || vec![0f32; dh],
+        |buf, i| {
+            let (b, t, h) = (i / (n_seq * nh), i / nh % n_seq, i % nh);
+            let pages = &kv[b];
+            let n_vis = pages.len - n_seq + t + 1;
+            let h_kv = (h / pages.group) as isize;
+            let int8 = pages.scales.is_some();
+            // 第 `pos` 个位置的行在块中的偏移
+            let offset = |[sb, st, sh, _]: [isize; 4], pos: usize| {
+                let block = pages.table[pos / pages.block_size] as isize;
+                block * sb + (pos % pages.block_size) as isize * st + h_kv * sh
+            };
+            // 第 `pos` 个位置的反量化比例，`i` 为 0 取 K 的、为 1 取 V 的
+            let scale_at = |i: usize, pos: usize| match pages.scales {
+                Some(scales) => unsafe {
+                    let (base, [sb, st, sh]) = scales[i];
+                    let offset = offset([sb, st, sh, 0], pos);
+                    *(base as *const u8).byte_offset(offset).cast::<f32>()
+                },
+                None => 1.,
+            };
+
+            let q = (0..dh)
+                .map(|j| unsafe {
+                    *(q as *const u8)
+                        .byte_offset(
+                            b as isize * sbq
+                                + t as isize * stq
+                                + h as isize * shq
+                                + j as isize * sdq,
+                        )
+                        .cast::<f32>()
+                })
+                .collect::<Vec<_>>();
+
+            let mut att = (0..n_vis)
+                .map(|pos| {
+                    let row = unsafe {
+                        Row::new(pages.k, offset(pages.sk, pos), pages.sk[3], dh, int8, buf)
+                    };
+                    row.dot(&q) * scale_at(0, pos)
+                })
+                .collect::<Vec<_>>();
+            let bias = alibi.map(|alibi| {
+                (0..n_vis)
+                    .map(|pos| -alibi[h] * (n_vis - 1 - pos) as f32)
+                    .collect::<Vec<_>>()
+            });
+            scale_mask_softmax(
+                &mut att,
+                None,
+                scale,
+                bias.as_deref(),
+                None,
+                n_vis,
+                compensated,
+            );
+
+            // V 的比例并入注意力权重
+            let mut out = vec![0f32; dh];
+            for (pos, &w) in att.iter().enumerate() {
+                let w = w * scale_at(1, pos);
+                let row =
+                    unsafe { Row::new(pages.v, offset(pages.sv, pos), pages.sv[3], dh, int8, buf) };
+                row.axpy(&mut out, w)
+            }
+            for (j, val) in out.into_iter().enumerate() {
+                unsafe {
+                    *(y as *mut u8)
+                        .byte_offset(
+                            b as isize * sby
+                                + t as isize * sty
+                                + h as isize * shy
+                                + j as isize * sdy,
+                        )
+                        .cast::<f32>() = val
+                }
+            }
+        },
+    )
+}
+
+/// 缓存中一个位置一个头的 K 或 V。
+enum Row<'a> {
+    F32(&'a [f32]),
+    I8(&'a [i8]),
+}
+
+impl<'a> Row<'a> {
+    /// `base + offset` 处步长为 `sd` 的 `dh` 个元素，连续时直接引用，否则收集到 `buf`。
+    ///
+    /// # Safety
+    ///
+    /// 地址须指向有效的 f32 或 int8 元素。
+    unsafe fn new(
+        base: usize,
+        offset: isize,
+        sd: isize,
+        dh: usize,
+        int8: bool,
+        buf: &'a mut [f32],
+    ) -> Self {
+        let ptr = unsafe { (base as *const u8).byte_offset(offset) };
+        let size = if int8 { 1 } else { size_of::<f32>() as isize };
+        match (int8, sd == size) {
+            (false, true) => Self::F32(unsafe { from_raw_parts(ptr.cast(), dh) }),
+            (true, true) => Self::I8(unsafe { from_raw_parts(ptr.cast(), dh) }),
+            _ => {
+                for (j, val) in buf.iter_mut().enumerate() {
+                    let ptr = unsafe { ptr.byte_offset(j as isize * sd) };
+                    *val = unsafe {
+                        if int8 {
+                            *ptr.cast::<i8>() as f32
+                        } else {
+                            *ptr.cast::<f32>()
+                        }
+                    }
+                }
+                Self::F32(buf)
+            }
+        }
+    }
+
+    /// 与 `q` 的点积，分 8 路累加以便向量化。
+    fn dot(&self, q: &[f32]) -> f32 {
+        fn dot<T: Copy>(q: &[f32], x: &[T], f: impl Fn(T) -> f32) -> f32 {
+            let (q8, q_tail) = q.as_chunks::<8>();
+            let (x8, x_tail) = x.as_chunks::<8>();
+            let mut acc = [0f32; 8];
+            for (q, x) in zip(q8, x8) {
+                for i in 0..8 {
+                    acc[i] += q[i] * f(x[i])
+                }
+            }
+            acc.iter().sum::<f32>() + zip(q_tail, x_tail).map(|(q, &x)| q * f(x)).sum::<f32>()
+        }
+        match self {
+            Self::F32(x) => dot(q, x, |x| x),
+            Self::I8(x) => dot(q, x, |x| x as f32),
+        }
+    }
+
+    /// `y += w * self`。
+    fn axpy(&self, y: &mut [f32], w: f32) {
+        match self {
+            Self::F32(x) => zip(y, *x).for_each(|(y, x)| *y += w * x),
+            Self::I8(x) => zip(y, *x).for_each(|(y, &x)| *y += w * x as f32),
+        }
+    }
+}
+
+/// 把 `x` 按最后一维对称量化为 int8 存入 `y`，每个向量的比例 `max|x| / 127` 存入 `scale`。
+///
+/// `y`、`x` 形状为 `[n, nkvh, dh]`，`scale` 形状为 `[n, nkvh]`，都可以是任意步长的视图。
+pub fn quant_heads(y: &Tensor, scale: &Tensor, x: &Tensor) {
+    clone_tensor!(y scale x);
+    assert_eq!(y.dt(), types::I8);
+    assert_eq!(unique(&[scale.dt(), x.dt()]), Some(types::F32));
+    dims!([n, nkvh, dh] = x);
+    assert_eq!(y.shape()[..], [n, nkvh, dh]);
+    assert_eq!(scale.shape()[..], [n, nkvh]);
+
+    strides!([sny, shy, sdy] = y);
+    strides!([sns, shs] = scale);
+    strides!([snx, shx, sdx] = x);
+    let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<u8>();
+    let scale = scale.as_ref().map(|b| &mut **b.write()).mut_ptr::<u8>();
+    let x = x.as_ref().map(|b| &**b.read()).ptr::<u8>();
+    for i in 0..n as isize {
+        for h in 0..nkvh as isize {
+            unsafe {
+                let x = |j: usize| {
+                    *x.byte_offset(i * snx + h * shx + j as isize * sdx)
+                        .cast::<f32>()
+                };
+                let max = (0..dh).map(|j| x(j).abs()).fold(0., f32::max);
+                let s = max / 127.;
+                let inv = if s > 0. { 1. / s } else { 0. };
+                *scale.byte_offset(i * sns + h * shs).cast::<f32>() = s;
+                for j in 0..dh {
+                    *y.byte_offset(i * sny + h * shy + j as isize * sdy)
+                        .cast::<i8>() = (x(j) * inv).round() as i8
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sparse_pattern() {
+    let pattern = SparsePattern {
+        window: 2,
+        stride: 4,
+        n_global: 1,
+    };
+    let visible = (0..8).filter(|&k| pattern.attend(7, k)).collect::<Vec<_>>();
+    assert_eq!(visible, [0, 3, 6, 7]);
+    assert!(!pattern.attend(2, 3));
+}
+
+#[test]
+fn test_prefix_lm() {
+    use super::fixture::{from_f32, values, zeros};
+
+    let [n_seq, d, nh, prefix] = [4, 4, 2, 2];
+    let xs = (0..n_seq * 3 * d)
+        .map(|i| ((i * 7 % 13) as f32 - 6.) / 5.)
+        .collect::<Vec<_>>();
+    let dys = (0..n_seq * d)
+        .map(|i| ((i * 5 % 11) as f32 - 5.) / 3.)
+        .collect::<Vec<_>>();
+    let att_shape = [1, nh, n_seq, n_seq];
+    let run = |xs: &[f32]| {
+        let y = zeros(types::F32, &[1, n_seq, d]);
+        let x = from_f32(&[1, n_seq, 3 * d], xs);
+        let (preatt, att) = (zeros(types::F32, &att_shape), zeros(types::F32, &att_shape));
+        let config = AttentionConfig {
+            prefix,
+            ..Default::default()
+        };
+        forward(&y, &preatt, &att, &x, None, &config);
+        (values(&y), x, att)
+    };
+
+    // 前缀内的查询能看到后面的前缀位置，看不到前缀之后的位置
+    let (y, x, att) = run(&xs);
+    let perturb = |t: usize| {
+        let mut xs = xs.clone();
+        xs[t * 3 * d + d] += 1.;
+        run(&xs).0
+    };
+    let changed = |y_: &[f32], t: usize| y[t * d..][..d] != y_[t * d..][..d];
+    assert!(changed(&perturb(prefix - 1), 0));
+    let y_ = perturb(prefix);
+    assert!(!changed(&y_, 0) && !changed(&y_, prefix - 1) && changed(&y_, prefix));
+
+    // 反向与数值梯度一致
+    let dx = zeros(types::F32, &[1, n_seq, 3 * d]);
+    let (dpreatt, datt) = (zeros(types::F32, &att_shape), zeros(types::F32, &att_shape));
+    let dy = from_f32(&[1, n_seq, d], &dys);
+    let config = AttentionConfig {
+        prefix,
+        ..Default::default()
+    };
+    backward(&dx, &dpreatt, &datt, &dy, &x, None, &att, &config);
+    let loss = |xs: &[f32]| zip(run(xs).0, &dys).map(|(y, dy)| y * dy).sum::<f32>();
+    for (i, dx) in values(&dx).into_iter().enumerate() {
+        let h = 1e-2;
+        let mut xs_ = xs.clone();
+        xs_[i] += h;
+        let plus = loss(&xs_);
+        xs_[i] -= 2. * h;
+        let numeric = (plus - loss(&xs_)) / (2. * h);
+        assert!((dx - numeric).abs() < 1e-2, "dx[{i}]: {dx} vs {numeric}")
+    }
+}
+
+#[test]
+fn test_decode() {
+    use super::fixture::{from_f32, values, zeros};
+
+    let [n_seq, nh, dh, block_size] = [5, 2, 3, 2];
+    let d = nh * dh;
+    let xs = (0..n_seq * 3 * d)
+        .map(|i| ((i * 7 % 13) as f32 - 6.) / 5.)
+        .collect::<Vec<_>>();
+
+    // 完整的因果注意力作为参照
+    let x = from_f32(&[1, n_seq, 3 * d], &xs);
+    let expected = |alibi| {
+        let y = zeros(types::F32, &[1, n_seq, d]);
+        let att_shape = [1, nh, n_seq, n_seq];
+        let (preatt, att) = (zeros(types::F32, &att_shape), zeros(types::F32, &att_shape));
+        let config = AttentionConfig {
+            alibi,
+            ..Default::default()
+        };
+        forward(&y, &preatt, &att, &x, None, &config);
+        values(&y)
+    };
+
+    // 把各位置的 K、V 打散存入块中，块内 K、V 交错存放
+    let table = [3, 0, 2];
+    let mut pool = vec![0.; 4 * block_size * 2 * d];
+    for t in 0..n_seq {
+        let row = (table[t / block_size] * block_size + t % block_size) * 2 * d;
+        pool[row..][..2 * d].copy_from_slice(&xs[t * 3 * d + d..][..2 * d])
+    }
+    let pool = from_f32(&[4, block_size, 2 * d], &pool);
+    let k = pool.cloned().slice(2, 0, d).tile(2, &[nh, dh]);
+    let v = pool.cloned().slice(2, d, d).tile(2, &[nh, dh]);
+    // 查询直接取 qkv 中最后一个位置的 Q
+    let q = x
+        .cloned()
+        .index(&[0])
+        .slice(0, n_seq - 1, 1)
+        .slice(1, 0, d)
+        .tile(1, &[nh, dh]);
+    for alibi in [None, Some(&[0.5, 0.125][..])] {
+        let y = zeros(types::F32, &[1, nh, dh]);
+        let kv = Paged {
+            k: &k,
+            v: &v,
+            scales: None,
+            table: &table,
+            len: n_seq,
+        };
+        decode(&y, &q, &[kv], alibi, false);
+
+        for (y, expected) in zip(values(&y), &expected(alibi)[(n_seq - 1) * d..]) {
+            assert!((y - expected).abs() < 1e-6, "{y} vs {expected}")
+        }
+    }
+
+    // 预填充最后 3 个位置，每个查询只看到它之前的位置
+    let n_new = 3;
+    let q = x
+        .cloned()
+        .slice(1, n_seq - n_new, n_new)
+        .slice(2, 0, d)
+        .tile(2, &[nh, dh]);
+    for alibi in [None, Some(&[0.5, 0.125][..])] {
+        let y = zeros(types::F32, &[1, n_new, nh, dh]);
+        let kv = Paged {
+            k: &k,
+            v: &v,
+            scales: None,
+            table: &table,
+            len: n_seq,
+        };
+        prefill(&y, &q, &[kv], alibi, false);
+
+        for (y, expected) in zip(values(&y), &expected(alibi)[(n_seq - n_new) * d..]) {
+            assert!((y - expected).abs() < 1e-6, "{y} vs {expected}")
+        }
+    }
+}
+
+#[test]
+fn test_alibi() {
+    use super::fixture::{values, zeros};
+
+    assert_eq!(alibi_slopes(4), [0.25, 0.0625, 0.015625, 0.00390625]);
+    assert_eq!(alibi_slopes(6)[4..], [0.5, 0.125]);
+
+    // Q 全为 0 时得分只剩偏置，注意力权重随距离按斜率指数衰减
+    let [n_seq, nh, d] = [4, 2, 4];
+    let (y, x) = (
+        zeros(types::F32, &[1, n_seq, d]),
+        zeros(types::F32, &[1, n_seq, 3 * d]),
+    );
+    let (preatt, att) = (
+        zeros(types::F32, &[1, nh, n_seq, n_seq]),
+        zeros(types::F32, &[1, nh, n_seq, n_seq]),
+    );
+    let slopes = [0.5, 2.];
+    let config = AttentionConfig {
+        alibi: Some(&slopes),
+        ..Default::default()
+    };
+    forward(&y, &preatt, &att, &x, None, &config);
+
+    let att = values(&att);
+    for (h, slope) in slopes.into_iter().enumerate() {
+        let t = n_seq - 1;
+        let row = &att[(h * n_seq + t) * n_seq..][..n_seq];
+        let weights = (0..n_seq).map(|t_| (-slope * (t - t_) as f32).exp());
+        let sum = weights.clone().sum::<f32>();
+        for (att, w) in zip(row, weights) {
+            assert!((att - w / sum).abs() < 1e-6)
+        }
+    }
+}
+
+#[test]
+fn test_gqa() {
+    use super::fixture::{from_f32, values, zeros};
+
+    let [n_seq, nh, nkvh, dh] = [4, 4, 2, 2];
+    let [d, d_kv] = [nh * dh, nkvh * dh];
+    let group = nh / nkvh;
+    let run = |xs: &[f32], nkvh: Option<usize>| {
+        let d3 = xs.len() / n_seq;
+        let x = from_f32(&[1, n_seq, d3], xs);
+        let y = zeros(types::F32, &[1, n_seq, d]);
+        let att_shape = [1, nh, n_seq, n_seq];
+        let (preatt, att) = (zeros(types::F32, &att_shape), zeros(types::F32, &att_shape));
+        let config = AttentionConfig {
+            nkvh,
+            ..Default::default()
+        };
+        forward(&y, &preatt, &att, &x, None, &config);
+
+        let dys = (0..n_seq * d)
+            .map(|i| (i % 5) as f32 - 2.)
+            .collect::<Vec<_>>();
+        let dx = zeros(types::F32, &[1, n_seq, d3]);
+        let (dpreatt, datt) = (zeros(types::F32, &att_shape), zeros(types::F32, &att_shape));
+        let dy = from_f32(&[1, n_seq, d], &dys);
+        backward(&dx, &dpreatt, &datt, &dy, &x, None, &att, &config);
+        (values(&y), values(&dx))
+    };
+
+    // 每个 K、V 头复制给同组的查询头，得到等价的多头注意力输入
+    let gqa = (0..n_seq * (d + 2 * d_kv))
+        .map(|i| ((i * 7 % 13) as f32 - 6.) / 5.)
+        .collect::<Vec<_>>();
+    let mha = gqa
+        .chunks(d + 2 * d_kv)
+        .flat_map(|row| {
+            let (q, kv) = row.split_at(d);
+            let repeat = |x: &[f32]| {
+                (0..nh)
+                    .flat_map(|h| x[h / group * dh..][..dh].to_vec())
+                    .collect::<Vec<_>>()
+            };
+            [q.to_vec(), repeat(&kv[..d_kv]), repeat(&kv[d_kv..])].concat()
+        })
+        .collect::<Vec<_>>();
+
+    let (y_gqa, dx_gqa) = run(&gqa, Some(nkvh));
+    let (y_mha, dx_mha) = run(&mha, None);
+    assert_eq!(y_gqa, y_mha);
+    // 共享头的梯度是同组各头梯度之和
+    for (row_gqa, row_mha) in zip(dx_gqa.chunks(d + 2 * d_kv), dx_mha.chunks(3 * d)) {
+        assert_eq!(row_gqa[..d], row_mha[..d]);
+        for (i, dkv) in row_gqa[d..].iter().enumerate() {
+            let (part, hk, j) = (i / d_kv, i % d_kv / dh, i % dh);
+            let sum = (0..group)
+                .map(|g| row_mha[d + part * d + (hk * group + g) * dh + j])
+                .sum::<f32>();
+            assert!((dkv - sum).abs() < 1e-5, "{dkv} vs {sum}")
+        }
+    }
+}
+
+#[test]
+fn test_padding() {
+    use super::fixture::{from_f32, tensor, values, zeros};
+
+    let [n_seq, nh, d, n_valid] = [5, 2, 4, 3];
+    // 返回 y 和 dx
+    let run = |xs: &[f32], dys: &[f32], config: &AttentionConfig| {
+        let n = xs.len() / (3 * d);
+        let att_shape = [1, nh, n, n];
+        let x = from_f32(&[1, n, 3 * d], xs);
+        let y = zeros(types::F32, &[1, n, d]);
+        let (preatt, att) = (zeros(types::F32, &att_shape), zeros(types::F32, &att_shape));
+        forward(&y, &preatt, &att, &x, None, config);
+        let dx = zeros(types::F32, &[1, n, 3 * d]);
+        let (dpreatt, datt) = (zeros(types::F32, &att_shape), zeros(types::F32, &att_shape));
+        let dy = from_f32(&[1, n, d], dys);
+        backward(&dx, &dpreatt, &datt, &dy, &x, None, &att, config);
+        (values(&y), values(&dx))
+    };
+
+    let xs = (0..n_seq * 3 * d)
+        .map(|i| ((i * 7 % 13) as f32 - 6.) / 5.)
+        .collect::<Vec<_>>();
+    let dys = (0..n_seq * d)
+        .map(|i| ((i * 5 % 11) as f32 - 5.) / 3.)
+        .collect::<Vec<_>>();
+    let pad_x = vec![9.; (n_seq - n_valid) * 3 * d];
+    let pad_dy = vec![1.; (n_seq - n_valid) * d];
+    let (valid_x, valid_dy) = (&xs[..n_valid * 3 * d], &dys[..n_valid * d]);
+    let assert_close = |a: &[f32], b: &[f32]| {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in zip(a, b) {
+            assert!((a - b).abs() < 1e-6, "{a} vs {b}")
+        }
+    };
+
+    // 右填充：前缀覆盖整个序列时填充的键本可见，有效长度把它们屏蔽
+    let (y, dx) = run(
+        valid_x,
+        valid_dy,
+        &AttentionConfig {
+            prefix: n_valid,
+            ..Default::default()
+        },
+    );
+    let lens = [n_valid];
+    let (y_, dx_) = run(
+        &[valid_x, &pad_x].concat(),
+        &[valid_dy, &pad_dy].concat(),
+        &AttentionConfig {
+            prefix: n_seq,
+            lens: Some(&lens),
+            ..Default::default()
+        },
+    );
+    assert_close(&y_[..n_valid * d], &y);
+    assert_close(&dx_[..n_valid * 3 * d], &dx);
+    assert!(
+        y_[n_valid * d..]
+            .iter()
+            .chain(&dx_[n_valid * 3 * d..])
+            .all(|&v| v == 0.)
+    );
+
+    // 左填充：用完整的掩码屏蔽开头的填充
+    let (y, dx) = run(valid_x, valid_dy, &AttentionConfig::default());
+    let n_pad = n_seq - n_valid;
+    let mask = (0..n_seq * n_seq)
+        .map(|i| (i / n_seq >= n_pad && i % n_seq >= n_pad) as u8)
+        .collect::<Vec<_>>();
+    let mask = tensor(types::Bool, &[1, n_seq, n_seq], &mask);
+    let (y_, dx_) = run(
+        &[&pad_x, valid_x].concat(),
+        &[&pad_dy, valid_dy].concat(),
+        &AttentionConfig {
+            mask: Some(&mask),
+            ..Default::default()
+        },
+    );
+    assert_close(&y_[n_pad * d..], &y);
+    assert_close(&dx_[n_pad * 3 * d..], &dx);
+    assert!(
+        y_[..n_pad * d]
+            .iter()
+            .chain(&dx_[..n_pad * 3 * d])
+            .all(|&v| v == 0.)
+    );
+}
@@ -0,0 +1,265 @@
+use super::Tensor;
+use crate::macros::*;
+use digit_layout::{DigitLayout, types};
+use half::{bf16, f16};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+/// 把 `x` 转换为 `y` 的数据类型写入 `y`，两者形状相同，可以是任意步长的视图。
+///
+/// 支持 f64、f32、f16、bf16 和 8 到 64 位有符号、无符号整数之间的任意转换。
+/// 浮点转整数时四舍五入并饱和到整数的范围，NaN 转为 0；整数之间的转换同样饱和。
+pub fn forward(y: &Tensor, x: &Tensor) {
+    clone_tensor!(y x);
+    assert_eq!(y.shape(), x.shape());
+    if y.dt() == x.dt() {
+        return super::rearrange::rearrange(&y, &x);
+    }
+    let y_dt = y.dt();
+    dispatch(x.dt(), Src { y: &y, x: &x, y_dt })
+}
+
+/// 类型转换的梯度取恒等映射（直通估计），`dx += cast(dy)`。
+///
+/// 整数没有梯度，`dx` 必须是浮点类型。
+pub fn backward(dx: &Tensor, dy: &Tensor) {
+    clone_tensor!(dx dy);
+    assert_eq!(dx.shape(), dy.shape());
+    assert!(is_float(dx.dt()), "{} is not differentiable", dx.dt());
+    assert!(is_float(dy.dt()), "{} is not differentiable", dy.dt());
+    let dx_dt = dx.dt();
+    dispatch(
+        dy.dt(),
+        Grad {
+            dx: &dx,
+            dy: &dy,
+            dx_dt,
+        },
+    )
+}
+
+fn is_float(dt: DigitLayout) -> bool {
+    matches!(dt, types::F64 | types::F32 | types::F16 | types::BF16)
+}
+
+/// 参与转换的标量类型，浮点之间经 f64 转换，整数之间经 i128 转换。
+trait Scalar: Copy + Send + Sync + 'static {
+    const FLOAT: bool;
+    fn to_f64(self) -> f64;
+    fn from_f64(val: f64) -> Self;
+    fn to_i128(self) -> i128;
+    fn from_i128(val: i128) -> Self;
+}
+
+macro_rules! float {
+    ($( $t:ty: $to:expr, $from:expr; )+) => {
+        $(
+            impl Scalar for $t {
+                const FLOAT: bool = true;
+                fn to_f64(self) -> f64 {
+                    $to(self)
+                }
+                fn from_f64(val: f64) -> Self {
+                    $from(val)
+                }
+                fn to_i128(self) -> i128 {
+                    self.to_f64() as _
+                }
+                fn from_i128(val: i128) -> Self {
+                    Self::from_f64(val as _)
+                }
+            }
+        )+
+    };
+}
+
+float! {
+    f64: |x| x, |x| x;
+    f32: |x: f32| x as f64, |x| x as f32;
+    f16: f16::to_f64, f16::from_f64;
+    bf16: bf16::to_f64, bf16::from_f64;
+}
+
+macro_rules! int {
+    ($( $t:ty )+) => {
+        $(
+            impl Scalar for $t {
+                const FLOAT: bool = false;
+                fn to_f64(self) -> f64 {
+                    self as _
+                }
+                fn from_f64(val: f64) -> Self {
+                    // `as` 已经饱和且把 NaN 转为 0
+                    val.round() as _
+                }
+                fn to_i128(self) -> i128 {
+                    self as _
+                }
+                fn from_i128(val: i128) -> Self {
+                    val.clamp(<$t>::MIN as _, <$t>::MAX as _) as _
+                }
+            }
+        )+
+    };
+}
+
+int!(u8 u16 u32 u64 i8 i16 i32 i64);
+
+/// 先按源类型分派，再按目标类型分派。
+trait Visit {
+    fn visit<T: Scalar>(self);
+}
+
+fn dispatch(dt: DigitLayout, v: impl Visit) {
+    match dt {
+        types::F64 => v.visit::<f64>(),
+        types::F32 => v.visit::<f32>(),
+        types::F16 => v.visit::<f16>(),
+        types::BF16 => v.visit::<bf16>(),
+        types::U8 => v.visit::<u8>(),
+        types::U16 => v.visit::<u16>(),
+        types::U32 => v.visit::<u32>(),
+        types::U64 => v.visit::<u64>(),
+        types::I8 => v.visit::<i8>(),
+        types::I16 => v.visit::<i16>(),
+        types::I32 => v.visit::<i32>(),
+        types::I64 => v.visit::<i64>(),
+        dt => panic!("cannot cast {dt}"),
+    }
+}
+
+struct Src<'a> {
+    y: &'a Tensor,
+    x: &'a Tensor,
+    y_dt: DigitLayout,
+}
+
+impl Visit for Src<'_> {
+    fn visit<T: Scalar>(self) {
+        struct Dst<'a, T>(&'a Tensor, &'a Tensor, std::marker::PhantomData<T>);
+        impl<T: Scalar> Visit for Dst<'_, T> {
+            fn visit<U: Scalar>(self) {
+                for_each(self.0, self.1, |y: &mut U, x: T| {
+                    *y = if T::FLOAT || U::FLOAT {
+                        U::from_f64(x.to_f64())
+                    } else {
+                        U::from_i128(x.to_i128())
+                    }
+                })
+            }
+        }
+        dispatch(self.y_dt, Dst::<T>(self.y, self.x, Default::default()))
+    }
+}
+
+struct Grad<'a> {
+    dx: &'a Tensor,
+    dy: &'a Tensor,
+    dx_dt: DigitLayout,
+}
+
+impl Visit for Grad<'_> {
+    fn visit<U: Scalar>(self) {
+        macro_rules! accumulate {
+            ($t:ty) => {
+                for_each(self.dx, self.dy, |dx: &mut $t, dy: U| {
+                    *dx = <$t>::from_f64(dx.to_f64() + dy.to_f64())
+                })
+            };
+        }
+        match self.dx_dt {
+            types::F64 => accumulate!(f64),
+            types::F32 => accumulate!(f32),
+            types::F16 => accumulate!(f16),
+            types::BF16 => accumulate!(bf16),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// 按形状逐元素访问两个任意步长的张量。
+fn for_each<T: Scalar, U: Scalar>(y: &Tensor, x: &Tensor, f: impl Fn(&mut T, U) + Sync) {
+    let shape = y.shape().to_vec();
+    let (sy, sx) = (y.layout().strides().to_vec(), x.layout().strides().to_vec());
+    let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<u8>() as usize;
+    let x = x.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+    let len = shape.iter().product::<usize>();
+    (0..len).into_par_iter().for_each(|mut i| {
+        let (mut oy, mut ox) = (0, 0);
+        for (d, &n) in shape.iter().enumerate().rev() {
+            let j = (i % n) as isize;
+            i /= n;
+            oy += j * sy[d];
+            ox += j * sx[d]
+        }
+        unsafe {
+            let y = &mut *(y as *mut u8).byte_offset(oy).cast::<T>();
+            f(
+                y,
+                (x as *const u8)
+                    .byte_offset(ox)
+                    .cast::<U>()
+                    .read_unaligned(),
+            )
+        }
+    })
+}
+
+#[test]
+fn test_cast() {
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    let tensor = |dt: DigitLayout, shape: &[usize]| {
+        crate::Tensor::new(dt, shape)
+            .map(Blob::new_zeroed)
+            .map(RwRc::new)
+    };
+    let values = |t: &Tensor| {
+        let t = t.cloned();
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+
+    let data = [-300.7f32, -1.5, -0.4, 0.5, 2.49, 1e3, 7., f32::NAN];
+    let x = tensor(types::F32, &[2, 4]);
+    x.get()
+        .write()
+        .copy_from_slice(unsafe { data.align_to::<u8>().1 });
+    x.get().release();
+
+    // 浮点转整数四舍五入且饱和
+    let y = tensor(types::I8, &[2, 4]);
+    forward(&y, &x);
+    assert_eq!(
+        &**y.get().read(),
+        [-128i8, -2, 0, 1, 2, 127, 7, 0].map(|x| x as u8)
+    );
+    y.get().release();
+    // 整数之间饱和
+    let z = tensor(types::U8, &[2, 4]);
+    forward(&z, &y);
+    assert_eq!(&**z.get().read(), [0, 0, 0, 1, 2, 127, 7, 0]);
+    z.get().release();
+
+    // f32 -> bf16 -> f16 -> f32，源是每行取中间两列的视图
+    let x = x.slice(1, 1, 2);
+    let bf = tensor(types::BF16, &[2, 2]);
+    let h = tensor(types::F16, &[2, 2]);
+    let y = tensor(types::F32, &[2, 2]);
+    forward(&bf, &x);
+    forward(&h, &bf);
+    forward(&y, &h);
+    for (i, y) in values(&y).into_iter().enumerate() {
+        assert_eq!(y, bf16::from_f32(data[i / 2 * 4 + 1 + i % 2]).to_f32())
+    }
+
+    // 反向把梯度累加到原类型上
+    let dx = tensor(types::F32, &[2, 2]);
+    backward(&dx, &h);
+    backward(&dx, &h);
+    for (dx, y) in std::iter::zip(values(&dx), values(&y)) {
+        assert_eq!(dx, 2. * y)
+    }
+}
@@ -0,0 +1,218 @@
+use super::{Tensor, unique};
+use crate::macros::*;
+use digit_layout::types;
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+
+/// 卷积的步长和两端填充。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Conv1dArgs {
+    pub stride: usize,
+    pub padding: usize,
+}
+
+impl Conv1dArgs {
+    /// 输入长度为 `n_seq`、卷积核长度为 `kernel` 时的输出长度。
+    pub fn out_len(&self, n_seq: usize, kernel: usize) -> usize {
+        assert!(self.stride > 0);
+        let len = n_seq + 2 * self.padding;
+        assert!(len >= kernel, "conv1d input too short");
+        (len - kernel) / self.stride + 1
+    }
+
+    /// 输出位置 `t` 与卷积核位置 `k` 对应的输入位置，落在填充区时为 `None`。
+    fn input_pos(&self, t: usize, k: usize, n_seq: usize) -> Option<usize> {
+        (t * self.stride + k)
+            .checked_sub(self.padding)
+            .filter(|&i| i < n_seq)
+    }
+}
+
+/// 序列维卷积，`x` 为 `[batch, n_seq, c_in]`，`w` 为 `[c_out, c_in, kernel]`，
+/// `y` 为 `[batch, out_len, c_out]`。
+pub fn forward(y: &Tensor, x: &Tensor, w: &Tensor, b: Option<&Tensor>, args: Conv1dArgs) {
+    clone_tensor!(y x w);
+
+    let dt = unique(&[y.dt(), x.dt(), w.dt()]).unwrap();
+    assert_eq!(dt, types::F32);
+
+    dims!([batch_size, n_out, c_out] = y);
+    dims!([batch_size_, n_seq, c_in] = x);
+    dims!([c_out_, c_in_, kernel] = w);
+    assert_eq!(batch_size, batch_size_);
+    assert_eq!(c_out, c_out_);
+    assert_eq!(c_in, c_in_);
+    assert_eq!(n_out, args.out_len(n_seq, kernel));
+
+    let b = b.map(|b| {
+        assert_eq!(b.dt(), dt);
+        dims!([c_out__] = b);
+        assert_eq!(c_out, c_out__);
+        b.cloned()
+    });
+    let b = b
+        .as_ref()
+        .map(|b| b.as_ref().map(|b| &**b.read()).vector::<f32>());
+
+    let x = x.merge(0, 3);
+    let x = x.as_ref().map(|b| &**b.read()).vector::<f32>();
+    let w = w.merge(0, 3);
+    let w = w.as_ref().map(|b| &**b.read()).vector::<f32>();
+    let y = y.merge(0, 3);
+    let y = y.as_ref().map(|b| &mut **b.write()).vector_mut::<f32>();
+
+    y.par_chunks_mut(c_out).enumerate().for_each(|(i, y)| {
+        let (n, t) = (i / n_out, i % n_out);
+        for (co, y) in y.iter_mut().enumerate() {
+            let mut sum = b.map_or(0., |b| b[co]);
+            for k in 0..kernel {
+                let Some(t_in) = args.input_pos(t, k, n_seq) else {
+                    continue;
+                };
+                let x = &x[(n * n_seq + t_in) * c_in..][..c_in];
+                for (ci, x) in x.iter().enumerate() {
+                    sum += w[(co * c_in + ci) * kernel + k] * x
+                }
+            }
+            *y = sum
+        }
+    })
+}
+
+/// 反向传播，梯度累加到 `dx`、`dw`、`db` 上。
+#[allow(clippy::too_many_arguments)]
+pub fn backward(
+    dx: &Tensor,
+    dw: &Tensor,
+    db: Option<&Tensor>,
+    dy: &Tensor,
+    x: &Tensor,
+    w: &Tensor,
+    args: Conv1dArgs,
+) {
+    clone_tensor!(dx dw dy x w);
+
+    let dt = unique(&[dx.dt(), dw.dt(), dy.dt(), x.dt(), w.dt()]).unwrap();
+    assert_eq!(dt, types::F32);
+
+    dims!([batch_size, n_out, c_out] = dy);
+    dims!([batch_size_, n_seq, c_in] = x);
+    dims!([c_out_, c_in_, kernel] = w);
+    assert_eq!(batch_size, batch_size_);
+    assert_eq!(c_out, c_out_);
+    assert_eq!(c_in, c_in_);
+    assert_eq!(n_out, args.out_len(n_seq, kernel));
+    assert_eq!(dx.shape(), x.shape());
+    assert_eq!(dw.shape(), w.shape());
+
+    let dy = dy.merge(0, 3);
+    let dy = dy.as_ref().map(|b| &**b.read()).vector::<f32>();
+    let x = x.merge(0, 3);
+    let x = x.as_ref().map(|b| &**b.read()).vector::<f32>();
+    let w = w.merge(0, 3);
+    let w = w.as_ref().map(|b| &**b.read()).vector::<f32>();
+    let dx = dx.merge(0, 3);
+    let dx = dx.as_ref().map(|b| &mut **b.write()).vector_mut::<f32>();
+    let dw = dw.merge(0, 3);
+    let dw = dw.as_ref().map(|b| &mut **b.write()).vector_mut::<f32>();
+
+    for n in 0..batch_size {
+        for t in 0..n_out {
+            let dy = &dy[(n * n_out + t) * c_out..][..c_out];
+            for k in 0..kernel {
+                let Some(t_in) = args.input_pos(t, k, n_seq) else {
+                    continue;
+                };
+                let x = &x[(n * n_seq + t_in) * c_in..][..c_in];
+                let dx = &mut dx[(n * n_seq + t_in) * c_in..][..c_in];
+                for (co, dy) in dy.iter().enumerate() {
+                    for ci in 0..c_in {
+                        let i = (co * c_in + ci) * kernel + k;
+                        dx[ci] += w[i] * dy;
+                        dw[i] += x[ci] * dy
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(db) = db {
+        clone_tensor!(db);
+
+        assert_eq!(db.dt(), dt);
+        dims!([c_out_] = db);
+        assert_eq!(c_out, c_out_);
+
+        let db = db.as_ref().map(|b| &mut **b.write()).vector_mut::<f32>();
+        for dy in dy.chunks(c_out) {
+            for (db, dy) in db.iter_mut().zip(dy) {
+                *db += dy
+            }
+        }
+    }
+}
+
+#[test]
+fn test_conv1d_grad() {
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    let tensor = |shape: &[usize], f: &dyn Fn(usize) -> f32| {
+        let t = crate::Tensor::new(types::F32, shape)
+            .map(Blob::new)
+            .map(RwRc::new);
+        let data = t.cloned().merge(0, shape.len());
+        let data = data.as_ref().map(|b| &mut **b.write()).vector_mut::<f32>();
+        for (i, x) in data.iter_mut().enumerate() {
+            *x = f(i)
+        }
+        t
+    };
+    let data = |t: &Tensor| {
+        let t = t.cloned().merge(0, t.layout().ndim());
+        t.as_ref().map(|b| &**b.read()).vector::<f32>().to_vec()
+    };
+    let add = |t: &Tensor, i: usize, delta: f32| {
+        let t = t.cloned().merge(0, t.layout().ndim());
+        t.as_ref().map(|b| &mut **b.write()).vector_mut::<f32>()[i] += delta
+    };
+
+    let args = Conv1dArgs {
+        stride: 2,
+        padding: 1,
+    };
+    let x = tensor(&[2, 5, 2], &|i| (i as f32 * 0.7).sin());
+    let w = tensor(&[3, 2, 3], &|i| (i as f32 * 1.3).cos());
+    let b = tensor(&[3], &|i| i as f32);
+    let y = tensor(&[2, args.out_len(5, 3), 3], &|_| 0.);
+    // 损失为 y 与 dy 的内积
+    let dy = tensor(&y.shape(), &|i| (i as f32 * 0.3).sin());
+    let loss = |x: &Tensor, w: &Tensor| {
+        forward(&y, x, w, Some(&b), args);
+        let y = y.cloned().merge(0, 3);
+        let y = y.as_ref().map(|b| &**b.read()).vector::<f32>();
+        y.iter().zip(data(&dy)).map(|(y, dy)| y * dy).sum::<f32>()
+    };
+
+    let dx = tensor(&x.shape(), &|_| 0.);
+    let dw = tensor(&w.shape(), &|_| 0.);
+    let db = tensor(&[3], &|_| 0.);
+    backward(&dx, &dw, Some(&db), &dy, &x, &w, args);
+
+    for (t, dt) in [(&x, &dx), (&w, &dw)] {
+        for i in 0..data(t).len() {
+            let eps = 1e-2;
+            add(t, i, eps);
+            let l1 = loss(&x, &w);
+            add(t, i, -2. * eps);
+            let l0 = loss(&x, &w);
+            add(t, i, eps);
+            let num = (l1 - l0) / (2. * eps);
+            assert!((num - data(dt)[i]).abs() < 1e-2, "{num} {}", data(dt)[i])
+        }
+    }
+    let sum = |c: usize| data(&dy).chunks(3).map(|dy| dy[c]).sum::<f32>();
+    assert_eq!(data(&db), [sum(0), sum(1), sum(2)])
+}
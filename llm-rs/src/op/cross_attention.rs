@@ -0,0 +1,274 @@
+use super::{Tensor, attention::scale_mask_softmax, is_half, store_f32, to_f32, unique};
+use crate::macros::*;
+use digit_layout::types;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{
+    iter::zip,
+    slice::{from_raw_parts, from_raw_parts_mut},
+};
+
+/// 交叉注意力：查询来自 `q`，K、V 来自另一个序列（编码器输出、检索到的片段）的 `kv`。
+///
+/// `y`、`q` 形状为 `[batch, n_q, d]`，`kv` 形状为 `[batch, n_kv, 2 * d_kv]`，每行先 K 后 V；
+/// `d_kv` 小于 `d` 时同组的查询头共享 K、V 头，见 [`AttentionConfig::nkvh`](super::attention::AttentionConfig::nkvh)。
+/// 每个查询可见全部 `n_kv` 个位置，`att` 形状为 `[batch, nh, n_q, n_kv]`，保存权重供反向使用。
+/// `compensated` 同 [`AttentionConfig::compensated`](super::attention::AttentionConfig::compensated)。
+pub fn forward(y: &Tensor, att: &Tensor, q: &Tensor, kv: &Tensor, compensated: bool) {
+    clone_tensor!(y att q kv);
+
+    let dt = unique(&[y.dt(), att.dt(), q.dt(), kv.dt()]).unwrap();
+    if is_half(dt) {
+        let [y_, att_, q_, kv_] = [&y, &att, &q, &kv].map(to_f32);
+        forward(&y_, &att_, &q_, &kv_, compensated);
+        for (dst, src) in [(&y, &y_), (&att, &att_)] {
+            store_f32(dst, src)
+        }
+        return;
+    }
+    assert_eq!(dt, types::F32);
+
+    let Shape {
+        batch_size,
+        n_q,
+        n_kv,
+        nh,
+        dh,
+        group,
+    } = Shape::new(&y, &att, &q, &kv);
+    let (d, d_kv) = (nh * dh, nh / group * dh);
+    let scale = (dh as f32).powf(-0.5);
+
+    assert!(y.is_contiguous() && att.is_contiguous());
+    assert!(q.is_contiguous() && kv.is_contiguous());
+    let q = q.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let kv = kv.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+    let att = att.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+
+    (0..batch_size * nh * n_q).into_par_iter().for_each(|i| {
+        let (b, h, t) = (i / (nh * n_q), i / n_q % nh, i % n_q);
+        let hk = h / group;
+        let kv_row = |t_: usize| unsafe {
+            from_raw_parts((kv as *const f32).add((b * n_kv + t_) * 2 * d_kv), 2 * d_kv)
+        };
+        let q = unsafe { from_raw_parts((q as *const f32).add((b * n_q + t) * d + h * dh), dh) };
+        let y = unsafe { from_raw_parts_mut((y as *mut f32).add((b * n_q + t) * d + h * dh), dh) };
+        let att = unsafe { from_raw_parts_mut((att as *mut f32).add(i * n_kv), n_kv) };
+
+        for (t_, val) in att.iter_mut().enumerate() {
+            let k = &kv_row(t_)[hk * dh..][..dh];
+            *val = zip(q, k).map(|(q, k)| q * k).sum()
+        }
+        scale_mask_softmax(att, None, scale, None, None, n_kv, compensated);
+
+        y.fill(0.);
+        for (t_, &a) in att.iter().enumerate() {
+            let v = &kv_row(t_)[d_kv + hk * dh..][..dh];
+            for (y, v) in zip(&mut *y, v) {
+                *y += a * v
+            }
+        }
+    })
+}
+
+/// 反向传播，梯度累加到 `dq`、`dkv` 上。
+///
+/// 每个 K、V 头的梯度只来自同组的查询头，按 `(批, K、V 头)` 并行，互不冲突。
+pub fn backward(dq: &Tensor, dkv: &Tensor, dy: &Tensor, q: &Tensor, kv: &Tensor, att: &Tensor) {
+    clone_tensor!(dq dkv dy q kv att);
+    assert_eq!(dq.shape(), q.shape());
+    assert_eq!(dkv.shape(), kv.shape());
+
+    let dt = unique(&[dq.dt(), dkv.dt(), dy.dt(), q.dt(), kv.dt(), att.dt()]).unwrap();
+    if is_half(dt) {
+        let [dq_, dkv_, dy_, q_, kv_, att_] = [&dq, &dkv, &dy, &q, &kv, &att].map(to_f32);
+        backward(&dq_, &dkv_, &dy_, &q_, &kv_, &att_);
+        for (dst, src) in [(&dq, &dq_), (&dkv, &dkv_)] {
+            store_f32(dst, src)
+        }
+        return;
+    }
+    assert_eq!(dt, types::F32);
+
+    let Shape {
+        batch_size,
+        n_q,
+        n_kv,
+        nh,
+        dh,
+        group,
+    } = Shape::new(&dy, &att, &q, &kv);
+    let (d, d_kv) = (nh * dh, nh / group * dh);
+    let nkvh = nh / group;
+    let scale = (dh as f32).powf(-0.5);
+
+    for t in [&dq, &dkv, &dy, &q, &kv, &att] {
+        assert!(t.is_contiguous())
+    }
+    let q = q.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let kv = kv.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let dy = dy.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let att = att.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let dq = dq.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+    let dkv = dkv.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+
+    (0..batch_size * nkvh).into_par_iter().for_each(|i| {
+        let (b, hk) = (i / nkvh, i % nkvh);
+        let at = |base: usize, offset: usize| unsafe { (base as *mut f32).add(offset) };
+        let kv_at = |base: usize, t_: usize, j: usize| at(base, (b * n_kv + t_) * 2 * d_kv + j);
+        let q_at = |base: usize, t: usize, h: usize| at(base, (b * n_q + t) * d + h * dh);
+
+        let mut datt = vec![0.; n_kv];
+        for h in hk * group..(hk + 1) * group {
+            for t in 0..n_q {
+                let att = unsafe { from_raw_parts(at(att, ((b * nh + h) * n_q + t) * n_kv), n_kv) };
+                let dy = unsafe { from_raw_parts(q_at(dy, t, h), dh) };
+                let q = unsafe { from_raw_parts(q_at(q, t, h), dh) };
+                let dq = unsafe { from_raw_parts_mut(q_at(dq, t, h), dh) };
+
+                // y = att · V
+                for (t_, (datt, &a)) in zip(&mut datt, att).enumerate() {
+                    let v = unsafe { from_raw_parts(kv_at(kv, t_, d_kv + hk * dh), dh) };
+                    let dv = unsafe { from_raw_parts_mut(kv_at(dkv, t_, d_kv + hk * dh), dh) };
+                    *datt = 0.;
+                    for ((dv, v), dy) in zip(zip(dv, v), dy) {
+                        *datt += v * dy;
+                        *dv += a * dy
+                    }
+                }
+                // softmax 的反向：dpreatt = att * (datt - Σ att * datt)
+                let dot = zip(att, &datt).map(|(a, da)| a * da).sum::<f32>();
+                for (t_, (&a, &da)) in zip(att, &datt).enumerate() {
+                    let dpreatt = a * (da - dot) * scale;
+                    let k = unsafe { from_raw_parts(kv_at(kv, t_, hk * dh), dh) };
+                    let dk = unsafe { from_raw_parts_mut(kv_at(dkv, t_, hk * dh), dh) };
+                    for ((dq, q), (dk, k)) in zip(zip(&mut *dq, q), zip(dk, k)) {
+                        *dq += k * dpreatt;
+                        *dk += q * dpreatt
+                    }
+                }
+            }
+        }
+    })
+}
+
+struct Shape {
+    batch_size: usize,
+    n_q: usize,
+    n_kv: usize,
+    nh: usize,
+    dh: usize,
+    group: usize,
+}
+
+impl Shape {
+    fn new(y: &Tensor, att: &Tensor, q: &Tensor, kv: &Tensor) -> Self {
+        dims!([batch_size_0, n_q_0, d_0] = y);
+        dims!([batch_size_1, nh, n_q_1, n_kv_0] = att);
+        dims!([batch_size_2, n_q_2, d_1] = q);
+        dims!([batch_size_3, n_kv_1, d_kv2] = kv);
+
+        let batch_size = unique(&[batch_size_0, batch_size_1, batch_size_2, batch_size_3]).unwrap();
+        let n_q = unique(&[n_q_0, n_q_1, n_q_2]).unwrap();
+        let n_kv = unique(&[n_kv_0, n_kv_1]).unwrap();
+        let d = unique(&[d_0, d_1]).unwrap();
+        let dh = d / nh;
+        assert_eq!(d_kv2 % (2 * dh), 0);
+        let nkvh = d_kv2 / (2 * dh);
+        assert_eq!(nh % nkvh, 0);
+        Self {
+            batch_size,
+            n_q,
+            n_kv,
+            nh,
+            dh,
+            group: nh / nkvh,
+        }
+    }
+}
+
+#[test]
+fn test_cross_attention() {
+    use super::attention::{self, AttentionConfig};
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    // K、V 与 Q 等长且全部可见时，与 prefix 覆盖整个序列的自注意力一致
+    let [batch_size, n_seq, nh, nkvh, dh] = [2, 5, 4, 2, 3];
+    let [d, d_kv] = [nh * dh, nkvh * dh];
+    let d3 = d + 2 * d_kv;
+    let tensor = |shape: &[usize], data: &[f32]| {
+        let mut t = crate::Tensor::new(types::F32, shape)
+            .map(Blob::new_zeroed)
+            .map(RwRc::new);
+        let buf = t.get_mut().write();
+        let ([], buf, []) = (unsafe { buf.align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        buf[..data.len()].copy_from_slice(data);
+        t.get().release();
+        t
+    };
+    let values = |t: &Tensor| {
+        let t = t.cloned();
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+    let assert_close = |a: &[f32], b: &[f32]| {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in zip(a, b) {
+            assert!((a - b).abs() < 1e-5, "{a} vs {b}")
+        }
+    };
+
+    let xs = (0..batch_size * n_seq * d3)
+        .map(|i| ((i * 7 % 17) as f32 - 8.) / 6.)
+        .collect::<Vec<_>>();
+    let dys = (0..batch_size * n_seq * d)
+        .map(|i| (i % 5) as f32 - 2.)
+        .collect::<Vec<_>>();
+    let (qs, kvs): (Vec<_>, Vec<_>) = xs.chunks(d3).map(|row| row.split_at(d)).unzip();
+    let (qs, kvs) = (qs.concat(), kvs.concat());
+
+    let att_shape = [batch_size, nh, n_seq, n_seq];
+    let x = tensor(&[batch_size, n_seq, d3], &xs);
+    let (y, preatt, att) = (
+        tensor(&[batch_size, n_seq, d], &[]),
+        tensor(&att_shape, &[]),
+        tensor(&att_shape, &[]),
+    );
+    let config = AttentionConfig {
+        prefix: n_seq,
+        nkvh: Some(nkvh),
+        ..Default::default()
+    };
+    attention::forward(&y, &preatt, &att, &x, None, &config);
+    let dx = tensor(&[batch_size, n_seq, d3], &[]);
+    let (dpreatt, datt) = (tensor(&att_shape, &[]), tensor(&att_shape, &[]));
+    let dy = tensor(&[batch_size, n_seq, d], &dys);
+    attention::backward(&dx, &dpreatt, &datt, &dy, &x, None, &att, &config);
+
+    let q = tensor(&[batch_size, n_seq, d], &qs);
+    let kv = tensor(&[batch_size, n_seq, 2 * d_kv], &kvs);
+    let (y_, att_) = (
+        tensor(&[batch_size, n_seq, d], &[]),
+        tensor(&att_shape, &[]),
+    );
+    forward(&y_, &att_, &q, &kv, false);
+    assert_close(&values(&y_), &values(&y));
+    assert_close(&values(&att_), &values(&att));
+
+    let (dq, dkv) = (
+        tensor(&[batch_size, n_seq, d], &[]),
+        tensor(&[batch_size, n_seq, 2 * d_kv], &[]),
+    );
+    backward(&dq, &dkv, &dy, &q, &kv, &att_);
+    let (dqs, dkvs): (Vec<_>, Vec<_>) = values(&dx)
+        .chunks(d3)
+        .map(|row| (row[..d].to_vec(), row[d..].to_vec()))
+        .unzip();
+    assert_close(&values(&dq), &dqs.concat());
+    assert_close(&values(&dkv), &dkvs.concat());
+}
@@ -0,0 +1,47 @@
+use super::{Tensor, unique};
+use crate::macros::*;
+use digit_layout::types;
+use rand::Rng;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+/// 以概率 `p` 将元素置 0，其余放大 `1 / (1 - p)`，保留的缩放系数写入 `mask` 供反向使用。
+pub fn forward(y: &Tensor, mask: &Tensor, x: &Tensor, p: f32, rng: &mut impl Rng) {
+    clone_tensor!(y mask x);
+    assert!((0. ..1.).contains(&p));
+    assert_eq!(unique(&[y.dt(), mask.dt(), x.dt()]).unwrap(), types::F32);
+    assert!(y.shape() == x.shape() && mask.shape() == x.shape());
+    assert!(y.is_contiguous() && mask.is_contiguous() && x.is_contiguous());
+    let n = x.shape().iter().product();
+
+    // 掩码串行生成，保证同一种子的结果与线程数无关
+    let scale = 1. / (1. - p);
+    let mask =
+        unsafe { from_raw_parts_mut(mask.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>(), n) };
+    for m in &mut *mask {
+        *m = if rng.random::<f32>() < p { 0. } else { scale }
+    }
+
+    let y = unsafe { from_raw_parts_mut(y.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>(), n) };
+    let x = unsafe { from_raw_parts(x.as_ref().map(|b| &**b.read()).ptr::<f32>(), n) };
+    (y, x, &*mask)
+        .into_par_iter()
+        .for_each(|(y, x, m)| *y = x * m)
+}
+
+/// `dx += dy * mask`
+pub fn backward(dx: &Tensor, dy: &Tensor, mask: &Tensor) {
+    clone_tensor!(dx dy mask);
+    assert_eq!(unique(&[dx.dt(), dy.dt(), mask.dt()]).unwrap(), types::F32);
+    assert!(dx.shape() == mask.shape() && dy.shape() == mask.shape());
+    assert!(dx.is_contiguous() && dy.is_contiguous() && mask.is_contiguous());
+    let n = mask.shape().iter().product();
+
+    let dx =
+        unsafe { from_raw_parts_mut(dx.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>(), n) };
+    let dy = unsafe { from_raw_parts(dy.as_ref().map(|b| &**b.read()).ptr::<f32>(), n) };
+    let mask = unsafe { from_raw_parts(mask.as_ref().map(|b| &**b.read()).ptr::<f32>(), n) };
+    (dx, dy, mask)
+        .into_par_iter()
+        .for_each(|(dx, dy, m)| *dx += dy * m)
+}
@@ -0,0 +1,197 @@
+use super::{Tensor, unique};
+use crate::macros::*;
+use digit_layout::types;
+use gemm::{Parallelism::Rayon, gemm};
+
+/// 两个操作数的爱因斯坦求和 `y = einsum(spec, a, b)`，覆盖 `y` 原有的值。
+///
+/// `spec` 形如 `bhqd,bhkd->bhqk`，每个字母是一个维度，不出现在输出中的维度被求和。
+/// 三个张量都有的维度作为批，其余每类（只在 `a` 和 `y`、只在 `b` 和 `y`、只在 `a` 和 `b`）
+/// 至多一个维度时按批调用矩阵乘，否则逐元素计算。
+pub fn einsum(spec: &str, y: &Tensor, a: &Tensor, b: &Tensor) {
+    clone_tensor!(y a b);
+
+    let dt = unique(&[y.dt(), a.dt(), b.dt()]).unwrap();
+    assert_eq!(dt, types::F32);
+
+    let axes = Axis::parse(spec, [&a, &b, &y]);
+
+    let mut batch = Vec::new();
+    let mut m = Vec::new();
+    let mut n = Vec::new();
+    let mut k = Vec::new();
+    let mut reduce = Vec::new();
+    for axis in axes {
+        match axis.present {
+            [true, true, true] => batch.push(axis),
+            [true, false, true] => m.push(axis),
+            [false, true, true] => n.push(axis),
+            [true, true, false] => k.push(axis),
+            [true, false, false] | [false, true, false] => reduce.push(axis),
+            [false, false, _] => unreachable!(),
+        }
+    }
+
+    let a = a.as_ref().map(|b| &**b.read()).ptr::<f32>();
+    let b = b.as_ref().map(|b| &**b.read()).ptr::<f32>();
+    let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>();
+
+    if m.len() <= 1 && n.len() <= 1 && k.len() <= 1 && reduce.is_empty() {
+        let [m, n, k] = [m, n, k].map(|g| g.into_iter().next().unwrap_or(Axis::ONE));
+        for_each_offset(&batch, [0; 3], &mut |[oa, ob, oy]| unsafe {
+            gemm::<f32>(
+                m.len,
+                n.len,
+                k.len,
+                y.offset(oy),
+                n.strides[2],
+                m.strides[2],
+                false,
+                a.offset(oa),
+                k.strides[0],
+                m.strides[0],
+                b.offset(ob),
+                n.strides[1],
+                k.strides[1],
+                0.,
+                1.,
+                false,
+                false,
+                false,
+                Rayon(0),
+            )
+        })
+    } else {
+        let reduce = [k, reduce].concat();
+        let outer = [batch, m, n].concat();
+        for_each_offset(&outer, [0; 3], &mut |[oa, ob, oy]| {
+            let mut sum = 0.;
+            for_each_offset(&reduce, [oa, ob, 0], &mut |[oa, ob, _]| unsafe {
+                sum += *a.offset(oa) * *b.offset(ob)
+            });
+            unsafe { *y.offset(oy) = sum }
+        })
+    }
+}
+
+/// 一个下标：长度、是否出现在 `a`、`b`、`y` 中以及在其中的元素步长。
+#[derive(Clone, Copy, Debug)]
+struct Axis {
+    name: u8,
+    len: usize,
+    present: [bool; 3],
+    strides: [isize; 3],
+}
+
+impl Axis {
+    const ONE: Self = Self {
+        name: 0,
+        len: 1,
+        present: [true; 3],
+        strides: [0; 3],
+    };
+
+    fn parse(spec: &str, tensors: [&Tensor; 3]) -> Vec<Self> {
+        let spec = spec.replace(' ', "");
+        let Some((inputs, output)) = spec.split_once("->") else {
+            panic!("einsum spec \"{spec}\" has no \"->\"")
+        };
+        let Some((ia, ib)) = inputs.split_once(',') else {
+            panic!("einsum spec \"{spec}\" needs two operands")
+        };
+
+        let mut axes = Vec::<Self>::new();
+        for (i, (names, t)) in [ia, ib, output].into_iter().zip(tensors).enumerate() {
+            let shape = t.shape();
+            let strides = t.layout().strides();
+            assert_eq!(
+                names.len(),
+                shape.len(),
+                "einsum spec \"{spec}\" ndim mismatch"
+            );
+            for (j, name) in names.bytes().enumerate() {
+                assert!(name.is_ascii_lowercase(), "invalid einsum index {name}");
+                let axis = match axes.iter_mut().find(|a| a.name == name) {
+                    Some(axis) => {
+                        assert_eq!(
+                            axis.len, shape[j],
+                            "einsum index {} len mismatch",
+                            name as char
+                        );
+                        axis
+                    }
+                    None => {
+                        assert!(i < 2, "einsum output index {} not in inputs", name as char);
+                        axes.push(Self {
+                            name,
+                            len: shape[j],
+                            present: [false; 3],
+                            strides: [0; 3],
+                        });
+                        axes.last_mut().unwrap()
+                    }
+                };
+                assert!(!axis.present[i], "einsum index {} repeated", name as char);
+                axis.present[i] = true;
+                axis.strides[i] = strides[j] / size_of::<f32>() as isize
+            }
+        }
+        axes
+    }
+}
+
+/// 遍历 `axes` 张成的所有位置，以三个张量中的元素偏移调用 `f`。
+fn for_each_offset(axes: &[Axis], base: [isize; 3], f: &mut impl FnMut([isize; 3])) {
+    match axes {
+        [] => f(base),
+        [axis, tail @ ..] => {
+            for i in 0..axis.len as isize {
+                let offset = [0, 1, 2].map(|j| base[j] + i * axis.strides[j]);
+                for_each_offset(tail, offset, f)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_einsum() {
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    let tensor = |shape: &[usize], data: &[f32]| {
+        let t = crate::Tensor::new(types::F32, shape)
+            .map(Blob::new)
+            .map(RwRc::new);
+        if !data.is_empty() {
+            t.cloned()
+                .merge(0, shape.len())
+                .as_ref()
+                .map(|b| &mut **b.write())
+                .vector_mut::<f32>()
+                .copy_from_slice(data)
+        }
+        t
+    };
+    let read = |t: &Tensor| {
+        let t = t.cloned().merge(0, t.layout().ndim());
+        t.as_ref().map(|b| &**b.read()).vector::<f32>().to_vec()
+    };
+
+    // 批矩阵乘，转置的右操作数
+    let a = tensor(
+        &[2, 2, 3],
+        &[1., 2., 3., 4., 5., 6., 1., 0., 0., 0., 1., 0.],
+    );
+    let b = tensor(
+        &[2, 2, 3],
+        &[1., 0., 1., 0., 1., 0., 1., 2., 3., 4., 5., 6.],
+    );
+    let y = tensor(&[2, 2, 2], &[]);
+    einsum("bik,bjk->bij", &y, &a, &b);
+    assert_eq!(read(&y), [4., 2., 10., 5., 1., 4., 2., 5.]);
+
+    // 逐元素计算的路径：a 独有的 i 被求和
+    let y = tensor(&[2, 3], &[]);
+    einsum("bij,bkj->bj", &y, &a, &b);
+    assert_eq!(read(&y), [5., 7., 9., 5., 7., 0.])
+}
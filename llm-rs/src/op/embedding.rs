@@ -1,10 +1,58 @@
-﻿trait Index: Copy + Sync {
+﻿use digit_layout::{DigitLayout, types};
+
+trait Index: Copy + Sync {
     fn as_usize(self) -> usize;
 }
 
-impl<T: Copy + Sync + Into<usize>> Index for T {
+impl Index for u16 {
+    fn as_usize(self) -> usize {
+        self as _
+    }
+}
+
+impl Index for u32 {
+    fn as_usize(self) -> usize {
+        self as _
+    }
+}
+
+impl Index for i32 {
     fn as_usize(self) -> usize {
-        self.into()
+        debug_assert!(self >= 0);
+        self as _
+    }
+}
+
+/// 存储类型，以 f32 计算。
+trait Float: Copy + Send + Sync {
+    fn to_f32(self) -> f32;
+    fn from_f32(val: f32) -> Self;
+}
+
+impl Float for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+    fn from_f32(val: f32) -> Self {
+        val
+    }
+}
+
+impl Float for half::bf16 {
+    fn to_f32(self) -> f32 {
+        self.to_f32()
+    }
+    fn from_f32(val: f32) -> Self {
+        Self::from_f32(val)
+    }
+}
+
+impl Float for half::f16 {
+    fn to_f32(self) -> f32 {
+        self.to_f32()
+    }
+    fn from_f32(val: f32) -> Self {
+        Self::from_f32(val)
     }
 }
 
@@ -42,52 +90,103 @@ impl Iterator for BatchIter {
     }
 }
 
-pub fn build_pos(buf: &mut [u8], nseqs: impl IntoIterator<Item = usize>) {
-    let ([], mut slice, []) = (unsafe { buf.align_to_mut::<u16>() }) else {
-        unreachable!()
-    };
-    for i in nseqs {
-        slice[0] = i as _;
-        slice = &mut slice[1..]
+/// 位置号的类型，`n_ctx` 超过 65536 时使用 u32。
+pub fn pos_dt(n_ctx: usize) -> DigitLayout {
+    if n_ctx <= 1 << 16 {
+        types::U16
+    } else {
+        types::U32
+    }
+}
+
+pub fn build_pos(buf: &mut [u8], dt: DigitLayout, nseqs: impl IntoIterator<Item = usize>) {
+    fn fill<T: TryFrom<usize>>(buf: &mut [u8], nseqs: impl IntoIterator<Item = usize>) {
+        let ([], slice, []) = (unsafe { buf.align_to_mut::<T>() }) else {
+            unreachable!()
+        };
+        for (dst, i) in slice.iter_mut().zip(nseqs) {
+            *dst = T::try_from(i).ok().unwrap()
+        }
+    }
+    match dt {
+        types::U16 => fill::<u16>(buf, nseqs),
+        types::U32 => fill::<u32>(buf, nseqs),
+        _ => todo!(),
+    }
+}
+
+/// 读出连续存储的索引张量。
+pub fn read_indices(indices: &super::Tensor) -> Vec<usize> {
+    fn read<T: Index>(indices: &super::Tensor) -> Vec<usize> {
+        let indices = indices.cloned();
+        assert!(indices.is_contiguous());
+        let len = indices.shape().iter().product();
+        let ptr = indices.as_ref().map(|b| &**b.read()).ptr::<T>();
+        unsafe { std::slice::from_raw_parts(ptr, len) }
+            .iter()
+            .map(|&i| i.as_usize())
+            .collect()
+    }
+    match indices.dt() {
+        types::U16 => read::<u16>(indices),
+        types::U32 => read::<u32>(indices),
+        types::I32 => read::<i32>(indices),
+        dt => panic!("unsupported index type {dt}"),
     }
 }
 
 pub mod forward {
-    use super::Index;
+    use super::{Float, Index};
     use crate::{
         macros::*,
         op::{Tensor, unique},
     };
-    use digit_layout::types;
-    use std::{iter::zip, ops::Add};
+    use digit_layout::{DigitLayout, types};
+    use half::{bf16, f16};
+    use rayon::iter::{IntoParallelIterator, ParallelIterator};
+    use std::ptr::null;
 
+    /// `pos` 为位置号和位置嵌入表，缺省时只查词嵌入表（使用旋转位置编码的模型）。
     pub(crate) fn embedding(
         y: &Tensor,
         i1: &Tensor,
-        i2: &Tensor,
         table1: &Tensor,
-        table2: &Tensor,
+        pos: Option<(&Tensor, &Tensor)>,
     ) {
-        clone_tensor!(y i1 i2 table1 table2);
+        clone_tensor!(y i1 table1);
+        let pos = pos.map(|(i2, table2)| (i2.cloned(), table2.cloned()));
 
         dims!([n0, d0] = y);
         dims!([n1] = i1);
-        dims!([n2] = i2);
         dims!([_nt1, d1] = table1);
-        dims!([_nt2, d2] = table2);
 
-        let n = unique(&[n0, n1, n2]).unwrap();
-        let d = unique(&[d0, d1, d2]).unwrap();
+        let n = unique(&[n0, n1]).unwrap();
+        let d = unique(&[d0, d1]).unwrap();
 
         strides!([nsy, dsy] = y);
         strides!([ns1] = i1);
-        strides!([ns2] = i2);
 
         assert_eq!(dsy, y.dt().nbytes() as isize);
         assert_eq!(ns1, i1.dt().nbytes() as isize);
-        assert_eq!(ns2, i2.dt().nbytes() as isize);
         assert!(table1.is_contiguous());
-        assert!(table2.is_contiguous());
+
+        let (i2_dt, i2, table2) = match &pos {
+            Some((i2, table2)) => {
+                dims!([n2] = i2);
+                dims!([_nt2, d2] = table2);
+                assert_eq!(n2, n);
+                assert_eq!(d2, d);
+                strides!([ns2] = i2);
+                assert_eq!(ns2, i2.dt().nbytes() as isize);
+                assert!(table2.is_contiguous());
+                (
+                    i2.dt(),
+                    i2.as_ref().map(|b| &**b.read()).ptr(),
+                    table2.as_ref().map(|b| &**b.read()).ptr(),
+                )
+            }
+            None => (types::U16, null(), null()),
+        };
 
         let scheme = Scheme {
             n,
@@ -95,14 +194,21 @@ pub mod forward {
             nsy,
             y: y.as_ref().map(|b| &mut **b.write()).mut_ptr(),
             i1: i1.as_ref().map(|b| &**b.read()).ptr(),
-            i2: i2.as_ref().map(|b| &**b.read()).ptr(),
+            i2,
             table1: table1.as_ref().map(|b| &**b.read()).ptr(),
-            table2: table2.as_ref().map(|b| &**b.read()).ptr(),
+            table2,
         };
 
-        match (y.dt(), i1.dt(), i2.dt()) {
-            (types::F32, types::U16, types::U16) => scheme.compute::<f32, u16, u16>(),
-            (_, _, _) => todo!(),
+        match y.dt() {
+            types::F32 => scheme.dispatch::<f32>(i1.dt(), i2_dt),
+            types::BF16 => scheme.dispatch::<bf16>(i1.dt(), i2_dt),
+            types::F16 => scheme.dispatch::<f16>(i1.dt(), i2_dt),
+            #[cfg(feature = "reference")]
+            types::F64 => {
+                let pos = pos.as_ref().map(|(i2, table2)| (i2, table2));
+                crate::op::reference::embedding(&y, &i1, &table1, pos)
+            }
+            _ => todo!(),
         }
     }
 
@@ -118,7 +224,20 @@ pub mod forward {
     }
 
     impl Scheme {
-        fn compute<T: Add<Output = T>, I1: Index, I2: Index>(&self) {
+        /// 词号可以是 u16、u32 或 i32，位置号可以是 u16 或 u32。
+        fn dispatch<T: Float>(&self, i1: DigitLayout, i2: DigitLayout) {
+            match (i1, i2) {
+                (types::U16, types::U16) => self.compute::<T, u16, u16>(),
+                (types::U32, types::U16) => self.compute::<T, u32, u16>(),
+                (types::I32, types::U16) => self.compute::<T, i32, u16>(),
+                (types::U16, types::U32) => self.compute::<T, u16, u32>(),
+                (types::U32, types::U32) => self.compute::<T, u32, u32>(),
+                (types::I32, types::U32) => self.compute::<T, i32, u32>(),
+                (_, _) => todo!(),
+            }
+        }
+
+        fn compute<T: Float, I1: Index, I2: Index>(&self) {
             let &Self {
                 n,
                 d,
@@ -130,70 +249,125 @@ pub mod forward {
                 table2,
             } = self;
             let i1 = unsafe { std::slice::from_raw_parts(i1.cast::<I1>(), n) };
-            let i2 = unsafe { std::slice::from_raw_parts(i2.cast::<I2>(), n) };
-            for (i, (i1, i2)) in zip(i1, i2).enumerate() {
-                let y = unsafe { y.byte_offset(nsy * i as isize) }.cast::<T>();
-                let x1 = unsafe { table1.byte_add(i1.as_usize() * d * size_of::<T>()) }.cast::<T>();
-                let x2 = unsafe { table2.byte_add(i2.as_usize() * d * size_of::<T>()) }.cast::<T>();
+            let i2 =
+                (!i2.is_null()).then(|| unsafe { std::slice::from_raw_parts(i2.cast::<I2>(), n) });
+            let y = y as usize;
+            let table1 = table1 as usize;
+            let table2 = table2 as usize;
+            (0..n).into_par_iter().for_each(|i| {
+                let y = (y as *mut u8)
+                    .wrapping_byte_offset(nsy * i as isize)
+                    .cast::<T>();
+                let x1 = (table1 as *const u8)
+                    .wrapping_byte_add(i1[i].as_usize() * d * size_of::<T>())
+                    .cast::<T>();
+                let x2 = i2.map(|i2| {
+                    (table2 as *const u8)
+                        .wrapping_byte_add(i2[i].as_usize() * d * size_of::<T>())
+                        .cast::<T>()
+                });
                 for i in 0..d {
-                    unsafe { y.add(i).write(x1.add(i).read() + x2.add(i).read()) }
+                    let mut val = unsafe { x1.add(i).read().to_f32() };
+                    if let Some(x2) = x2 {
+                        val += unsafe { x2.add(i).read().to_f32() }
+                    }
+                    unsafe { y.add(i).write(T::from_f32(val)) }
                 }
-            }
+            })
         }
     }
 }
 
 pub mod backward {
-    use super::Index;
+    use super::{Float, Index};
     use crate::{
         macros::*,
-        op::{Tensor, unique},
+        op::{Tensor, is_half, store_f32, to_f32, unique},
     };
-    use digit_layout::types;
-    use std::{iter::zip, ops::AddAssign};
+    use digit_layout::{DigitLayout, types};
+    use std::ptr::{null, null_mut};
 
+    /// `pos` 为位置号和位置嵌入表的梯度，与前向一致。
     pub(crate) fn embedding(
         dtable1: &Tensor,
-        dtable2: &Tensor,
         dy: &Tensor,
         i1: &Tensor,
-        i2: &Tensor,
+        pos: Option<(&Tensor, &Tensor)>,
     ) {
-        clone_tensor!(dtable1 dtable2 dy i1 i2);
+        clone_tensor!(dtable1 dy i1);
+        let pos = pos.map(|(i2, dtable2)| (i2.cloned(), dtable2.cloned()));
+
+        // 半精度在 f32 副本上累加全部行，最后只舍入一次
+        if is_half(dtable1.dt()) {
+            let dtable1_ = to_f32(&dtable1);
+            let pos_ = pos
+                .as_ref()
+                .map(|(i2, dtable2)| (i2.cloned(), to_f32(dtable2)));
+            let dy_ = to_f32(&dy);
+            embedding(
+                &dtable1_,
+                &dy_,
+                &i1,
+                pos_.as_ref().map(|(i2, dtable2)| (i2, dtable2)),
+            );
+            store_f32(&dtable1, &dtable1_);
+            if let (Some((_, dtable2)), Some((_, dtable2_))) = (&pos, &pos_) {
+                store_f32(dtable2, dtable2_)
+            }
+            return;
+        }
 
         dims!([_nt1, d1] = dtable1);
-        dims!([_nt2, d2] = dtable2);
         dims!([n0, d0] = dy);
         dims!([n1] = i1);
-        dims!([n2] = i2);
 
-        let n = unique(&[n0, n1, n2]).unwrap();
-        let d = unique(&[d0, d1, d2]).unwrap();
+        let n = unique(&[n0, n1]).unwrap();
+        let d = unique(&[d0, d1]).unwrap();
 
         strides!([nsy, dsy] = dy);
         strides!([ns1] = i1);
-        strides!([ns2] = i2);
 
         assert!(dtable1.is_contiguous());
-        assert!(dtable2.is_contiguous());
         assert_eq!(dsy, dy.dt().nbytes() as isize);
         assert_eq!(ns1, i1.dt().nbytes() as isize);
-        assert_eq!(ns2, i2.dt().nbytes() as isize);
+
+        let (i2_dt, i2, dtable2) = match &pos {
+            Some((i2, dtable2)) => {
+                dims!([n2] = i2);
+                dims!([_nt2, d2] = dtable2);
+                assert_eq!(n2, n);
+                assert_eq!(d2, d);
+                strides!([ns2] = i2);
+                assert_eq!(ns2, i2.dt().nbytes() as isize);
+                assert!(dtable2.is_contiguous());
+                (
+                    i2.dt(),
+                    i2.as_ref().map(|b| &**b.read()).ptr(),
+                    dtable2.as_ref().map(|b| &mut **b.write()).mut_ptr(),
+                )
+            }
+            None => (types::U16, null(), null_mut()),
+        };
 
         let scheme = Scheme {
             n,
             d,
             nsy,
             dtable1: dtable1.as_ref().map(|b| &mut **b.write()).mut_ptr(),
-            dtable2: dtable2.as_ref().map(|b| &mut **b.write()).mut_ptr(),
+            dtable2,
             dy: dy.as_ref().map(|b| &**b.read()).ptr(),
             i1: i1.as_ref().map(|b| &**b.read()).ptr(),
-            i2: i2.as_ref().map(|b| &**b.read()).ptr(),
+            i2,
         };
 
-        match (dy.dt(), i1.dt(), i2.dt()) {
-            (types::F32, types::U16, types::U16) => scheme.compute::<f32, u16, u16>(),
-            (_, _, _) => todo!(),
+        match dy.dt() {
+            types::F32 => scheme.dispatch::<f32>(i1.dt(), i2_dt),
+            #[cfg(feature = "reference")]
+            types::F64 => {
+                let pos = pos.as_ref().map(|(i2, dtable2)| (i2, dtable2));
+                crate::op::reference::embedding_backward(&dtable1, &dy, &i1, pos)
+            }
+            _ => todo!(),
         }
     }
 
@@ -209,7 +383,20 @@ pub mod backward {
     }
 
     impl Scheme {
-        fn compute<T: AddAssign + Copy, I1: Index, I2: Index>(&self) {
+        /// 词号可以是 u16、u32 或 i32，位置号可以是 u16 或 u32。
+        fn dispatch<T: Float>(&self, i1: DigitLayout, i2: DigitLayout) {
+            match (i1, i2) {
+                (types::U16, types::U16) => self.compute::<T, u16, u16>(),
+                (types::U32, types::U16) => self.compute::<T, u32, u16>(),
+                (types::I32, types::U16) => self.compute::<T, i32, u16>(),
+                (types::U16, types::U32) => self.compute::<T, u16, u32>(),
+                (types::U32, types::U32) => self.compute::<T, u32, u32>(),
+                (types::I32, types::U32) => self.compute::<T, i32, u32>(),
+                (_, _) => todo!(),
+            }
+        }
+
+        fn compute<T: Float, I1: Index, I2: Index>(&self) {
             let &Self {
                 n,
                 d,
@@ -221,18 +408,95 @@ pub mod backward {
                 i2,
             } = self;
             let i1 = unsafe { std::slice::from_raw_parts(i1.cast::<I1>(), n) };
-            let i2 = unsafe { std::slice::from_raw_parts(i2.cast::<I2>(), n) };
-            for (i, (i1, i2)) in zip(i1, i2).enumerate() {
+            let i2 =
+                (!i2.is_null()).then(|| unsafe { std::slice::from_raw_parts(i2.cast::<I2>(), n) });
+            for (i, i1) in i1.iter().enumerate() {
                 let dy = unsafe { dy.byte_offset(nsy * i as isize) }.cast::<T>();
                 let x1 =
                     unsafe { dtable1.byte_add(i1.as_usize() * d * size_of::<T>()) }.cast::<T>();
-                let x2 =
-                    unsafe { dtable2.byte_add(i2.as_usize() * d * size_of::<T>()) }.cast::<T>();
+                let x2 = i2.map(|i2| {
+                    unsafe { dtable2.byte_add(i2[i].as_usize() * d * size_of::<T>()) }.cast::<T>()
+                });
                 for i in 0..d {
-                    unsafe { *x1.add(i) += *dy.add(i) }
-                    unsafe { *x2.add(i) += *dy.add(i) }
+                    let dy = unsafe { dy.add(i).read() }.to_f32();
+                    unsafe { *x1.add(i) = T::from_f32((*x1.add(i)).to_f32() + dy) }
+                    if let Some(x2) = x2 {
+                        unsafe { *x2.add(i) = T::from_f32((*x2.add(i)).to_f32() + dy) }
+                    }
                 }
             }
         }
     }
 }
+
+#[test]
+fn test_index_types() {
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    let [n_voc, n_ctx, d] = [70000, 4, 3];
+    let tensor = |dt, shape: &[usize], data: &[u8]| {
+        let t = crate::Tensor::new(dt, shape).map(Blob::new_zeroed);
+        let mut t = t.map(RwRc::new);
+        t.get_mut().write()[..data.len()].copy_from_slice(data);
+        t.get().release();
+        t
+    };
+    let te = (0..n_voc * d)
+        .flat_map(|i| (i as f32).to_ne_bytes())
+        .collect::<Vec<_>>();
+    let pe = (0..n_ctx * d)
+        .flat_map(|i| (i as f32 * 0.5).to_ne_bytes())
+        .collect::<Vec<_>>();
+    let te = tensor(types::F32, &[n_voc, d], &te);
+    let pe = tensor(types::F32, &[n_ctx, d], &pe);
+
+    // 超出 u16 范围的词号
+    let tokens = [3usize, 65536, 69999];
+    let mut pos = vec![0; tokens.len() * 2];
+    build_pos(&mut pos, types::U16, 0..tokens.len());
+    let pos = tensor(types::U16, &[tokens.len()], &pos);
+
+    let run = |i1: &crate::Tensor<RwRc<Blob>>| {
+        let y = tensor(types::F32, &[tokens.len(), d], &[]);
+        forward::embedding(&y, i1, &te, Some((&pos, &pe)));
+        let y = y.cloned().merge(0, 2);
+        y.as_ref().map(|b| &**b.read()).vector::<f32>().to_vec()
+    };
+    let u32s = tokens
+        .iter()
+        .flat_map(|&t| (t as u32).to_ne_bytes())
+        .collect::<Vec<_>>();
+    let i32s = tokens
+        .iter()
+        .flat_map(|&t| (t as i32).to_ne_bytes())
+        .collect::<Vec<_>>();
+    let y = run(&tensor(types::U32, &[tokens.len()], &u32s));
+    assert_eq!(y, run(&tensor(types::I32, &[tokens.len()], &i32s)));
+    for (i, &t) in tokens.iter().enumerate() {
+        for j in 0..d {
+            assert_eq!(y[i * d + j], (t * d + j) as f32 + (i * d + j) as f32 * 0.5)
+        }
+    }
+}
+
+#[test]
+fn test_half_accumulate() {
+    use super::{
+        cast,
+        fixture::{from_f32, tensor, values, zeros},
+    };
+
+    // 同一个词在同一个位置重复多次，逐行舍入时 bf16 停在 256、f16 停在 2048
+    let [n, d] = [4096, 2];
+    let i1 = tensor(types::U16, &[n], &vec![1u16; n]);
+    let i2 = tensor(types::U16, &[n], &vec![0u16; n]);
+    for dt in [types::BF16, types::F16] {
+        let dy = zeros(dt, &[n, d]);
+        cast::forward(&dy, &from_f32(&[n, d], &vec![1.; n * d]));
+        let (dte, dpe) = (zeros(dt, &[2, d]), zeros(dt, &[1, d]));
+        backward::embedding(&dte, &dy, &i1, Some((&i2, &dpe)));
+        assert_eq!(values(&dte), [0., 0., n as f32, n as f32]);
+        assert_eq!(values(&dpe), [n as f32; 2])
+    }
+}
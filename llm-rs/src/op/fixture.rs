@@ -0,0 +1,38 @@
+//! 测试共用的张量构造和读取。
+
+use super::Tensor;
+use crate::Blob;
+use digit_layout::{DigitLayout, types};
+use rw_rc::RwRc;
+
+/// 以 `data` 为内容的张量，`data` 的元素数必须与形状一致。
+pub(crate) fn tensor<T: Copy>(dt: DigitLayout, shape: &[usize], data: &[T]) -> Tensor {
+    assert_eq!(data.len(), shape.iter().product::<usize>());
+    let t = zeros(dt, shape);
+    let data = unsafe { std::slice::from_raw_parts(data.as_ptr().cast::<u8>(), size_of_val(data)) };
+    t.get().write()[..data.len()].copy_from_slice(data);
+    t.get().release();
+    t
+}
+
+/// 以 `data` 为内容的 f32 张量。
+pub(crate) fn from_f32(shape: &[usize], data: &[f32]) -> Tensor {
+    tensor(types::F32, shape, data)
+}
+
+pub(crate) fn zeros(dt: DigitLayout, shape: &[usize]) -> Tensor {
+    crate::Tensor::new(dt, shape)
+        .map(Blob::new_zeroed)
+        .map(RwRc::new)
+}
+
+/// 按逻辑顺序读出全部元素，半精度先转换为 f32。
+pub(crate) fn values(t: &Tensor) -> Vec<f32> {
+    let t = super::to_f32(t);
+    let ndim = t.layout().ndim();
+    t.merge(0, ndim)
+        .as_ref()
+        .map(|b| &**b.read())
+        .vector::<f32>()
+        .to_vec()
+}
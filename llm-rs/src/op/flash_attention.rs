@@ -0,0 +1,451 @@
+use super::{
+    Tensor,
+    attention::{AttentionConfig, relative_bucket},
+    is_half, store_f32, to_f32, unique,
+};
+use crate::macros::*;
+use digit_layout::types;
+use itertools::izip;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{
+    iter::zip,
+    slice::{from_raw_parts, from_raw_parts_mut},
+};
+
+/// 查询分块的行数。
+const BLOCK_Q: usize = 16;
+/// 键分块的行数。
+const BLOCK_K: usize = 64;
+
+/// 分块的单趟注意力前向，结果与 [`attention::forward`](super::attention::forward) 相同，但不构造 preatt 和 att。
+///
+/// 每个 (批, 头, 查询块) 逐个键块计算得分，维护每行的运行最大值、指数和与加权累加，
+/// 键块处理完后归一化。`lse` 形状为 `[batch, nh, n_seq]`，总是 f32，
+/// 写入每行得分的 log-sum-exp 供反向重算权重，没有可见位置的行为负无穷，输出为 0。
+/// `kv` 和 `config` 的含义与朴素实现相同。
+pub fn forward(
+    y: &Tensor,
+    lse: &Tensor,
+    x: &Tensor,
+    kv: Option<&Tensor>,
+    config: &AttentionConfig,
+) {
+    clone_tensor!(y lse x);
+    let kv = kv.map(Tensor::cloned);
+    if let Some(kv) = &kv {
+        assert_eq!(kv.dt(), x.dt());
+        assert_eq!(kv.shape(), x.shape());
+    }
+    assert_eq!(lse.dt(), types::F32);
+
+    let dt = unique(&[y.dt(), x.dt()]).unwrap();
+    if is_half(dt) {
+        let [y_, x_] = [&y, &x].map(to_f32);
+        let kv_ = kv.as_ref().map(to_f32);
+        forward(&y_, &lse, &x_, kv_.as_ref(), config);
+        store_f32(&y, &y_);
+        return;
+    }
+    assert_eq!(dt, types::F32);
+
+    dims!([batch_size_0, n_seq_0, d] = y);
+    dims!([batch_size_1, n_seq_1, d3] = x);
+    dims!([batch_size_2, nh, n_seq_2] = lse);
+
+    let batch_size = unique(&[batch_size_0, batch_size_1, batch_size_2]).unwrap();
+    let n_seq = unique(&[n_seq_0, n_seq_1, n_seq_2]).unwrap();
+    let dh = d / nh;
+    let d_kv = config.kv_heads(nh, d, d3) * dh;
+    let group = d / d_kv;
+    let scale = (dh as f32).powf(-0.5);
+
+    assert!(x.is_contiguous() && y.is_contiguous() && lse.is_contiguous());
+    let kv = kv.as_ref().unwrap_or(&x);
+    assert!(kv.is_contiguous());
+    let x = x.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let kv = kv.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+    let lse = lse.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+
+    let n_blocks = n_seq.div_ceil(BLOCK_Q);
+    config.with_rules(batch_size, nh, n_seq, |rules| {
+        (0..batch_size * nh * n_blocks)
+            .into_par_iter()
+            .for_each(|i| {
+                let (b, h, qb) = (i / (nh * n_blocks), i / n_blocks % nh, i % n_blocks);
+                let hk = h / group;
+                let row = |base: usize, t: usize| unsafe {
+                    from_raw_parts((base as *const f32).add((b * n_seq + t) * d3), d3)
+                };
+                let queries = qb * BLOCK_Q..((qb + 1) * BLOCK_Q).min(n_seq);
+                let n_rows = queries.len();
+
+                let mut max = [f32::NEG_INFINITY; BLOCK_Q];
+                let mut sum = [0.; BLOCK_Q];
+                let mut acc = vec![0.; n_rows * dh];
+                let mut scores = [0.; BLOCK_K];
+
+                // 查询块中最后一行可见的键最多
+                let n_keys = rules.n_vis(queries.end - 1);
+                for kb in (0..n_keys).step_by(BLOCK_K) {
+                    let keys = kb..(kb + BLOCK_K).min(n_keys);
+                    for (r, t) in queries.clone().enumerate() {
+                        let keys = keys.start..keys.end.min(rules.n_vis(t));
+                        if keys.is_empty() {
+                            continue;
+                        }
+                        let q = &row(x, t)[h * dh..][..dh];
+                        let scores = &mut scores[..keys.len()];
+
+                        let mut block_max = f32::NEG_INFINITY;
+                        for (t_, s) in zip(keys.clone(), &mut *scores) {
+                            *s = if rules.visible(b, t, t_) {
+                                let k = &row(kv, t_)[d + hk * dh..][..dh];
+                                let dot = zip(q, k).map(|(&q, &k)| q * k).sum::<f32>();
+                                dot * scale + rules.bias(h, t, t_)
+                            } else {
+                                f32::NEG_INFINITY
+                            };
+                            block_max = block_max.max(*s)
+                        }
+                        if block_max == f32::NEG_INFINITY {
+                            continue;
+                        }
+
+                        // 最大值变大时，之前的指数和与累加按比例缩小
+                        let max_ = max[r].max(block_max);
+                        let correction = (max[r] - max_).exp();
+                        max[r] = max_;
+                        sum[r] *= correction;
+                        let acc = &mut acc[r * dh..][..dh];
+                        for a in &mut *acc {
+                            *a *= correction
+                        }
+                        for (t_, &s) in zip(keys.clone(), &*scores) {
+                            let p = (s - max_).exp();
+                            sum[r] += p;
+                            let v = &row(kv, t_)[d + d_kv + hk * dh..][..dh];
+                            for (a, v) in zip(&mut *acc, v) {
+                                *a += p * v
+                            }
+                        }
+                    }
+                }
+
+                for (r, t) in queries.enumerate() {
+                    let y = unsafe {
+                        from_raw_parts_mut((y as *mut f32).add((b * n_seq + t) * d + h * dh), dh)
+                    };
+                    let lse = unsafe { &mut *(lse as *mut f32).add((b * nh + h) * n_seq + t) };
+                    if sum[r] == 0. {
+                        y.fill(0.);
+                        *lse = f32::NEG_INFINITY;
+                        continue;
+                    }
+                    let sum_inv = 1. / sum[r];
+                    for (y, a) in zip(y, &acc[r * dh..][..dh]) {
+                        *y = a * sum_inv
+                    }
+                    *lse = max[r] + sum[r].ln()
+                }
+            })
+    })
+}
+
+/// 分块前向的反向，用 `y` 和 `lse` 逐块重算注意力权重，不读取 preatt 和 att。
+///
+/// `config` 须与前向完全一致（包括相对位置偏置的 `table`），相对位置偏置的梯度累加到 `dtable`。
+/// 其余参数与 [`attention::backward`](super::attention::backward) 相同，梯度累加到 `dx` 和 `dkv`。
+#[allow(clippy::too_many_arguments)]
+pub fn backward(
+    dx: &Tensor,
+    dy: &Tensor,
+    x: &Tensor,
+    kv: Option<(&Tensor, &Tensor)>,
+    y: &Tensor,
+    lse: &Tensor,
+    config: &AttentionConfig,
+    dtable: Option<&Tensor>,
+) {
+    clone_tensor!(dx dy x y lse);
+    let kv = kv.map(|(kv, dkv)| (kv.cloned(), dkv.cloned()));
+    if let Some((kv, dkv)) = &kv {
+        assert_eq!(kv.shape(), x.shape());
+        assert_eq!(dkv.shape(), dx.shape());
+    }
+    assert_eq!(lse.dt(), types::F32);
+
+    let dt = unique(&[dx.dt(), dy.dt(), x.dt(), y.dt()]).unwrap();
+    if is_half(dt) {
+        // 梯度以 f32 累加，完成后转换回半精度
+        let [dx_, dy_, x_, y_] = [&dx, &dy, &x, &y].map(to_f32);
+        let kv_ = kv.as_ref().map(|(kv, dkv)| (to_f32(kv), to_f32(dkv)));
+        backward(
+            &dx_,
+            &dy_,
+            &x_,
+            kv_.as_ref().map(|(kv, dkv)| (kv, dkv)),
+            &y_,
+            &lse,
+            config,
+            dtable,
+        );
+        store_f32(&dx, &dx_);
+        if let (Some((_, dkv)), Some((_, dkv_))) = (&kv, &kv_) {
+            store_f32(dkv, dkv_)
+        }
+        return;
+    }
+    assert_eq!(dt, types::F32);
+
+    dims!([batch_size_0, n_seq_0, d3_0] = dx);
+    dims!([batch_size_1, n_seq_1, d_0] = dy);
+    dims!([batch_size_2, n_seq_2, d3_1] = x);
+    dims!([batch_size_3, n_seq_3, d_1] = y);
+    dims!([batch_size_4, nh, n_seq_4] = lse);
+
+    let batch_size = unique(&[
+        batch_size_0,
+        batch_size_1,
+        batch_size_2,
+        batch_size_3,
+        batch_size_4,
+    ])
+    .unwrap();
+    let n_seq = unique(&[n_seq_0, n_seq_1, n_seq_2, n_seq_3, n_seq_4]).unwrap();
+    let (d, d3) = (unique(&[d_0, d_1]).unwrap(), unique(&[d3_0, d3_1]).unwrap());
+    let dh = d / nh;
+    let nkvh = config.kv_heads(nh, d, d3);
+    let d_kv = nkvh * dh;
+    let group = d / d_kv;
+    let scale = (dh as f32).powf(-0.5);
+
+    let dtable = dtable.map(|dtable| {
+        let dtable = dtable.cloned();
+        let (_, n_buckets) = config.bias.as_ref().unwrap().check(nh);
+        assert_eq!(dtable.dt(), types::F32);
+        assert_eq!(&*dtable.shape(), [nh, n_buckets]);
+        assert!(dtable.is_contiguous());
+        (dtable, n_buckets)
+    });
+    let dtable_ = dtable.as_ref().map(|(dtable, n_buckets)| {
+        (
+            dtable.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize,
+            *n_buckets,
+        )
+    });
+    let max_distance = config.bias.as_ref().map_or(0, |bias| bias.max_distance);
+
+    for t in [&dx, &dy, &x, &y, &lse] {
+        assert!(t.is_contiguous())
+    }
+    let (kv, dkv) = kv.as_ref().map_or((&x, &dx), |(kv, dkv)| (kv, dkv));
+    assert!(kv.is_contiguous() && dkv.is_contiguous());
+    let [x, kv, y, dy, lse] =
+        [&x, kv, &y, &dy, &lse].map(|t| t.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize);
+    // 共享 K、V 时 dx 与 dkv 是同一块内存，写入的列互不相交
+    let [dx, dkv] =
+        [&dx, dkv].map(|t| t.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize);
+
+    // 每个 K、V 头及同组的查询头只写自己的列和偏置表中自己的行，按 K、V 头并行
+    config.with_rules(batch_size, nh, n_seq, |rules| {
+        (0..nkvh).into_par_iter().for_each(|hk| {
+            let row = |base: usize, width: usize, b: usize, t: usize| unsafe {
+                from_raw_parts((base as *const f32).add((b * n_seq + t) * width), width)
+            };
+            // 只取出要写的 dh 列，dx 与 dkv 相同时各切片也不重叠
+            let cols_mut = |base: usize, b: usize, t: usize, offset: usize| unsafe {
+                from_raw_parts_mut((base as *mut f32).add((b * n_seq + t) * d3 + offset), dh)
+            };
+            let mut delta = vec![0.; n_seq];
+            for b in 0..batch_size {
+                for h in hk * group..(hk + 1) * group {
+                    let lse =
+                        |t: usize| unsafe { *(lse as *const f32).add((b * nh + h) * n_seq + t) };
+                    // D = dy·y，等于每行 Σ p·dp
+                    for (t, delta) in delta.iter_mut().enumerate() {
+                        let dy = &row(dy, d, b, t)[h * dh..][..dh];
+                        let y = &row(y, d, b, t)[h * dh..][..dh];
+                        *delta = zip(dy, y).map(|(dy, y)| dy * y).sum()
+                    }
+
+                    // 键块在外层，块内的 dk、dv 在查询间复用
+                    for kb in (0..n_seq).step_by(BLOCK_K) {
+                        let keys = kb..(kb + BLOCK_K).min(n_seq);
+                        for (t, &delta) in delta.iter().enumerate() {
+                            let keys = keys.start..keys.end.min(rules.n_vis(t));
+                            let lse = lse(t);
+                            if keys.is_empty() || lse == f32::NEG_INFINITY {
+                                continue;
+                            }
+                            let q = &row(x, d3, b, t)[h * dh..][..dh];
+                            let dq = cols_mut(dx, b, t, h * dh);
+                            let dy = &row(dy, d, b, t)[h * dh..][..dh];
+                            for t_ in keys {
+                                if !rules.visible(b, t, t_) {
+                                    continue;
+                                }
+                                let kv = row(kv, d3, b, t_);
+                                let k = &kv[d + hk * dh..][..dh];
+                                let v = &kv[d + d_kv + hk * dh..][..dh];
+
+                                let dot = zip(q, k).map(|(&q, &k)| q * k).sum::<f32>();
+                                let p = (dot * scale + rules.bias(h, t, t_) - lse).exp();
+                                let dp = zip(dy, v).map(|(dy, v)| dy * v).sum::<f32>();
+                                let ds = p * (dp - delta);
+
+                                for (dv, dy) in zip(cols_mut(dkv, b, t_, d + d_kv + hk * dh), dy) {
+                                    *dv += p * dy
+                                }
+                                let dk = cols_mut(dkv, b, t_, d + hk * dh);
+                                for (dq, dk, q, k) in izip!(&mut *dq, dk, q, k) {
+                                    *dq += ds * scale * k;
+                                    *dk += ds * scale * q
+                                }
+                                if let Some((dtable, n_buckets)) = dtable_ {
+                                    let bucket = relative_bucket(
+                                        t_ as isize - t as isize,
+                                        n_buckets,
+                                        max_distance,
+                                    );
+                                    unsafe {
+                                        *(dtable as *mut f32).add(h * n_buckets + bucket) += ds
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    })
+}
+
+#[test]
+fn test_flash_attention() {
+    use super::attention::{self, RelativeBias, SparsePattern, alibi_slopes};
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    let [n_seq, nh, dh] = [90, 4, 4];
+    let d = nh * dh;
+    let tensor = |dt, shape: &[usize], data: &[u8]| {
+        let mut t = crate::Tensor::new(dt, shape)
+            .map(Blob::new_zeroed)
+            .map(RwRc::new);
+        t.get_mut().write()[..data.len()].copy_from_slice(data);
+        t.get().release();
+        t
+    };
+    let f32s = |shape: &[usize], data: &[f32]| {
+        tensor(types::F32, shape, unsafe { data.align_to::<u8>().1 })
+    };
+    let values = |t: &Tensor| {
+        let t = t.cloned();
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+    let assert_close = |a: &[f32], b: &[f32]| {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in zip(a, b) {
+            assert!((a - b).abs() < 1e-4, "{a} vs {b}")
+        }
+    };
+
+    let table = (0..nh * 8)
+        .map(|i| ((i * 3 % 7) as f32 - 3.) / 4.)
+        .collect::<Vec<_>>();
+    let table = f32s(&[nh, 8], &table);
+    let slopes = alibi_slopes(nh);
+    let lens = [n_seq, 70];
+    let mask = (0..2 * n_seq * n_seq)
+        .map(|i| (i % 3 != 0 || i % n_seq == 0) as u8)
+        .collect::<Vec<_>>();
+    let mask = tensor(types::Bool, &[2, n_seq, n_seq], &mask);
+    let configs = [
+        (None, AttentionConfig::default()),
+        (
+            Some(2),
+            AttentionConfig {
+                alibi: Some(&slopes),
+                prefix: 20,
+                ..Default::default()
+            },
+        ),
+        (
+            Some(1),
+            AttentionConfig {
+                bias: Some(RelativeBias {
+                    table: &table,
+                    max_distance: 32,
+                }),
+                sparse: Some(SparsePattern {
+                    window: 10,
+                    stride: 16,
+                    n_global: 2,
+                }),
+                ..Default::default()
+            },
+        ),
+        (
+            None,
+            AttentionConfig {
+                prefix: n_seq,
+                lens: Some(&lens),
+                mask: Some(&mask),
+                ..Default::default()
+            },
+        ),
+    ];
+
+    for (nkvh, config) in configs {
+        let config = AttentionConfig { nkvh, ..config };
+        let d3 = d + 2 * nkvh.unwrap_or(nh) * dh;
+        let xs = (0..2 * n_seq * d3)
+            .map(|i| ((i * 7 % 13) as f32 - 6.) / 5.)
+            .collect::<Vec<_>>();
+        let dys = (0..2 * n_seq * d)
+            .map(|i| ((i * 5 % 11) as f32 - 5.) / 3.)
+            .collect::<Vec<_>>();
+        let x = f32s(&[2, n_seq, d3], &xs);
+        let dy = f32s(&[2, n_seq, d], &dys);
+        let att_shape = [2, nh, n_seq, n_seq];
+        let dtable = || f32s(&[nh, 8], &[]);
+
+        // 朴素实现，反向的 table 换成梯度
+        let y = f32s(&[2, n_seq, d], &[]);
+        let (preatt, att) = (f32s(&att_shape, &[]), f32s(&att_shape, &[]));
+        attention::forward(&y, &preatt, &att, &x, None, &config);
+        let dx = f32s(&[2, n_seq, d3], &[]);
+        let (dpreatt, datt) = (f32s(&att_shape, &[]), f32s(&att_shape, &[]));
+        let dtable_naive = dtable();
+        let config_ = AttentionConfig {
+            bias: config.bias.as_ref().map(|bias| RelativeBias {
+                table: &dtable_naive,
+                max_distance: bias.max_distance,
+            }),
+            alibi: config.alibi,
+            sparse: config.sparse,
+            prefix: config.prefix,
+            nkvh,
+            lens: config.lens,
+            mask: config.mask,
+            compensated: config.compensated,
+        };
+        attention::backward(&dx, &dpreatt, &datt, &dy, &x, None, &att, &config_);
+
+        let y_ = f32s(&[2, n_seq, d], &[]);
+        let lse = f32s(&[2, nh, n_seq], &[]);
+        forward(&y_, &lse, &x, None, &config);
+        let dx_ = f32s(&[2, n_seq, d3], &[]);
+        let dtable_flash = dtable();
+        let dtable_ = config.bias.is_some().then_some(&dtable_flash);
+        backward(&dx_, &dy, &x, None, &y_, &lse, &config, dtable_);
+
+        assert_close(&values(&y_), &values(&y));
+        assert_close(&values(&dx_), &values(&dx));
+        assert_close(&values(&dtable_flash), &values(&dtable_naive));
+    }
+}
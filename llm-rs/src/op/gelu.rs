@@ -3,42 +3,119 @@ use crate::macros::*;
 use digit_layout::types;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::{
-    f32::consts::PI,
+    f64::consts::{FRAC_1_SQRT_2, PI},
     ops::{AddAssign, Mul},
     sync::LazyLock,
 };
 
+/// GELU 的计算方式。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Approx {
+    /// `x * Φ(x)`，用 erf 精确计算。
+    Exact,
+    /// GPT-2 和 llm.c 使用的 tanh 近似。
+    #[default]
+    Tanh,
+}
+
 const GELU_MAGIC: f32 = 0.044715;
-static GELU_FACTOR: LazyLock<f32> = LazyLock::new(|| (2. / PI).sqrt());
+static GELU_FACTOR: LazyLock<f32> = LazyLock::new(|| (2. / PI as f32).sqrt());
 
 trait GeluData: Copy + AddAssign + Mul<Output = Self> {
-    fn compute(self) -> Self;
-    fn grad(self) -> Self;
+    fn compute(self, approx: Approx) -> Self;
+    fn grad(self, approx: Approx) -> Self;
 }
 
+// tanh 近似保持单精度，与 llm.c 的结果一致；精确版本在双精度下计算 erf
 impl GeluData for f32 {
-    fn compute(self) -> Self {
-        let x3 = GELU_MAGIC * self.powi(3);
-        let tanh = *GELU_FACTOR * (self + x3);
+    fn compute(self, approx: Approx) -> Self {
+        match approx {
+            Approx::Exact => {
+                let x = self as f64;
+                (0.5 * x * (1. + erf(x * FRAC_1_SQRT_2))) as _
+            }
+            Approx::Tanh => {
+                let x3 = GELU_MAGIC * self.powi(3);
+                let tanh = *GELU_FACTOR * (self + x3);
 
-        0.5 * self * (1. + tanh.tanh())
+                0.5 * self * (1. + tanh.tanh())
+            }
+        }
     }
 
-    fn grad(self) -> Self {
-        let x3 = GELU_MAGIC * self.powi(3);
-        let tanh = *GELU_FACTOR * (self + x3);
+    fn grad(self, approx: Approx) -> Self {
+        match approx {
+            Approx::Exact => {
+                let x = self as f64;
+                let pdf = (-0.5 * x * x).exp() / (2. * PI).sqrt();
+                (0.5 * (1. + erf(x * FRAC_1_SQRT_2)) + x * pdf) as _
+            }
+            Approx::Tanh => {
+                let x3 = GELU_MAGIC * self.powi(3);
+                let tanh = *GELU_FACTOR * (self + x3);
+
+                let dx3 = 3. * GELU_MAGIC * self.powi(2);
+                let dtanh = *GELU_FACTOR * (1. + dx3);
+
+                0.5 * (1. + tanh.tanh()) + 0.5 * self * tanh.cosh().powi(-2) * dtanh
+            }
+        }
+    }
+}
 
-        let dx3 = 3. * GELU_MAGIC * self.powi(2);
-        let dtanh = *GELU_FACTOR * (1. + dx3);
+/// 参考实现，两种方式都在双精度下计算。
+#[cfg(feature = "reference")]
+impl GeluData for f64 {
+    fn compute(self, approx: Approx) -> Self {
+        match approx {
+            Approx::Exact => 0.5 * self * (1. + erf(self * FRAC_1_SQRT_2)),
+            Approx::Tanh => {
+                let tanh = (2. / PI).sqrt() * (self + GELU_MAGIC as f64 * self.powi(3));
+                0.5 * self * (1. + tanh.tanh())
+            }
+        }
+    }
 
-        0.5 * (1. + tanh.tanh()) + 0.5 * self * tanh.cosh().powi(-2) * dtanh
+    fn grad(self, approx: Approx) -> Self {
+        match approx {
+            Approx::Exact => {
+                let pdf = (-0.5 * self * self).exp() / (2. * PI).sqrt();
+                0.5 * (1. + erf(self * FRAC_1_SQRT_2)) + self * pdf
+            }
+            Approx::Tanh => {
+                let magic = GELU_MAGIC as f64;
+                let tanh = (2. / PI).sqrt() * (self + magic * self.powi(3));
+                let dtanh = (2. / PI).sqrt() * (1. + 3. * magic * self.powi(2));
+                0.5 * (1. + tanh.tanh()) + 0.5 * self * tanh.cosh().powi(-2) * dtanh
+            }
+        }
     }
 }
 
+/// 误差函数：`|x| < 2` 时用泰勒级数，否则用 erfc 的连分式，双精度下误差约 1e-15。
+fn erf(x: f64) -> f64 {
+    let z = x.abs();
+    let ans = if z < 2. {
+        // erf(z) = 2/√π Σ (-1)^n z^(2n+1) / (n! (2n+1))
+        let mut term = z;
+        let mut sum = z;
+        for n in 1..60 {
+            term *= -z * z / n as f64;
+            sum += term / (2 * n + 1) as f64;
+        }
+        sum * 2. / PI.sqrt()
+    } else {
+        // erfc(z) = e^(-z²) / √π / (z + 1/2 / (z + 1 / (z + 3/2 / (z + ...))))
+        let t = (1..60).rev().fold(z, |t, n| z + n as f64 / 2. / t);
+        1. - (-z * z).exp() / PI.sqrt() / t
+    };
+    ans.copysign(x)
+}
+
 pub mod forward {
     use super::*;
 
-    pub(crate) fn gelu(y: &Tensor, x: &Tensor) {
+    pub(crate) fn gelu(y: &Tensor, x: &Tensor, approx: Approx) {
         clone_tensor!(y x);
 
         dims!([n, d] = y);
@@ -48,7 +125,7 @@ pub mod forward {
         assert_eq!(d, d_);
 
         strides!([nsy, dsy] = y);
-        strides!([nsx, dsx] = y);
+        strides!([nsx, dsx] = x);
 
         let dt = unique(&[y.dt(), x.dt()]).unwrap();
 
@@ -59,10 +136,13 @@ pub mod forward {
             sx: [nsx, dsx],
             y: y.as_ref().map(|b| &mut **b.write()).mut_ptr(),
             x: x.as_ref().map(|b| &**b.read()).ptr(),
+            approx,
         };
 
         match dt {
             types::F32 => scheme.compute::<f32>(),
+            #[cfg(feature = "reference")]
+            types::F64 => scheme.compute::<f64>(),
             _ => todo!(),
         }
     }
@@ -74,11 +154,20 @@ pub mod forward {
         sx: [isize; 2],
         y: *mut u8,
         x: *const u8,
+        approx: Approx,
     }
 
     impl Scheme {
         fn compute<T: GeluData>(&self) {
-            let &Self { n, d, sy, sx, y, x } = self;
+            let &Self {
+                n,
+                d,
+                sy,
+                sx,
+                y,
+                x,
+                approx,
+            } = self;
             let y = y as usize;
             let x = x as usize;
             (0..n * d).into_par_iter().for_each(|i| {
@@ -88,7 +177,7 @@ pub mod forward {
                 let y = unsafe { (y as *mut T).byte_offset(i * si + j * sj) };
                 let [si, sj] = sx;
                 let x = unsafe { (x as *const T).byte_offset(i * si + j * sj) };
-                unsafe { *y = (*x).compute() }
+                unsafe { *y = (*x).compute(approx) }
             });
         }
     }
@@ -97,7 +186,7 @@ pub mod forward {
 pub mod backward {
     use super::*;
 
-    pub(crate) fn gelu(dx: &Tensor, x: &Tensor, dy: &Tensor) {
+    pub(crate) fn gelu(dx: &Tensor, x: &Tensor, dy: &Tensor, approx: Approx) {
         clone_tensor!(dx x dy);
 
         dims!([n0, d0] = dx);
@@ -121,10 +210,13 @@ pub mod backward {
             dx: dx.as_ref().map(|b| &mut **b.write()).mut_ptr(),
             x: x.as_ref().map(|b| &**b.read()).ptr(),
             dy: dy.as_ref().map(|b| &**b.read()).ptr(),
+            approx,
         };
 
         match dt {
             types::F32 => scheme.compute::<f32>(),
+            #[cfg(feature = "reference")]
+            types::F64 => scheme.compute::<f64>(),
             _ => todo!(),
         }
     }
@@ -138,6 +230,7 @@ pub mod backward {
         dx: *mut u8,
         x: *const u8,
         dy: *const u8,
+        approx: Approx,
     }
 
     impl Scheme {
@@ -151,6 +244,7 @@ pub mod backward {
                 dx,
                 x,
                 dy,
+                approx,
             } = self;
             let dx = dx as usize;
             let x = x as usize;
@@ -164,8 +258,38 @@ pub mod backward {
                 let x = unsafe { (x as *const T).byte_offset(i * si + j * sj) };
                 let [si, sj] = sdy;
                 let dy = unsafe { (dy as *const T).byte_offset(i * si + j * sj) };
-                unsafe { *dx += *dy * (*x).grad() }
+                unsafe { *dx += *dy * (*x).grad(approx) }
             });
         }
     }
 }
+
+#[test]
+fn test_gelu() {
+    // Python math.erf
+    for (x, erf_x) in [
+        (0.1, 0.1124629160182849),
+        (1., 0.8427007929497149),
+        (1.9, 0.9927904292352575),
+        (2.1, 0.997020533343667),
+        (-3.5, -0.9999992569016276),
+    ] {
+        assert!((erf(x) - erf_x).abs() < 1e-14, "erf({x}) = {}", erf(x))
+    }
+    // 由 Python math.erf 和 math.tanh 计算
+    for (x, exact, tanh) in [
+        (-2f32, -0.04550026, -0.04540231),
+        (0.5, 0.34573123, 0.345714),
+        (3., 2.99595, 2.996363),
+    ] {
+        assert!((x.compute(Approx::Exact) - exact).abs() < 1e-6);
+        assert!((x.compute(Approx::Tanh) - tanh).abs() < 1e-6);
+    }
+    for approx in [Approx::Exact, Approx::Tanh] {
+        for x in [-3f32, -0.7, 0., 0.4, 2.5] {
+            let h = 1e-3;
+            let numeric = ((x + h).compute(approx) - (x - h).compute(approx)) / (2. * h);
+            assert!((x.grad(approx) - numeric).abs() < 1e-3, "{approx:?} {x}")
+        }
+    }
+}
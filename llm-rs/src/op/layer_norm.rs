@@ -20,6 +20,10 @@ pub mod forward {
         clone_tensor!(y mean rstd x scalar bias);
 
         let dt = unique(&[y.dt(), mean.dt(), rstd.dt(), x.dt(), scalar.dt(), bias.dt()]).unwrap();
+        #[cfg(feature = "reference")]
+        if dt == types::F64 {
+            return crate::op::reference::layer_norm(&y, &mean, &rstd, &x, &scalar, &bias);
+        }
         assert_eq!(dt, types::F32);
 
         dims!([n, d_0] = y);
@@ -204,6 +208,12 @@ pub mod backward {
             rstd.dt(),
         ])
         .unwrap();
+        #[cfg(feature = "reference")]
+        if dt == types::F64 {
+            return crate::op::reference::layer_norm_backward(
+                &dx, &dw, &db, &dy, &x, &w, &mean, &rstd,
+            );
+        }
         assert_eq!(dt, types::F32);
 
         dims!([n, d_0] = dx);
@@ -3,12 +3,28 @@ use crate::macros::*;
 use digit_layout::types;
 use gemm::{Parallelism::Rayon, gemm};
 use mem_rearrange::Rearranging;
-use std::iter::zip;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{iter::zip, slice::from_raw_parts};
+
+/// `y = x w^T (+ b)`，`batch_invariant` 时 f32 权重不用 gemm，见 [`crate::Context::set_batch_invariant`]。
+pub fn forward(
+    y: &Tensor,
+    x: &Tensor,
+    weight: &Tensor,
+    bias: Option<&Tensor>,
+    batch_invariant: bool,
+) {
+    if crate::quant::is_quantized(weight.dt()) {
+        return super::quant::linear(y, x, weight, bias);
+    }
 
-pub fn forward(y: &Tensor, x: &Tensor, weight: &Tensor, bias: Option<&Tensor>) {
     clone_tensor!(y x weight);
 
     let dt = unique(&[y.dt(), x.dt(), weight.dt()]).unwrap();
+    #[cfg(feature = "reference")]
+    if dt == types::F64 {
+        return super::reference::linear(&y, &x, &weight, bias.map(Tensor::cloned).as_ref());
+    }
     assert_eq!(dt, types::F32);
 
     dims!([m, n] = y);
@@ -37,6 +53,9 @@ pub fn forward(y: &Tensor, x: &Tensor, weight: &Tensor, bias: Option<&Tensor>) {
         }
     }
 
+    if batch_invariant {
+        return rowwise(&y, &x, &weight, bias.is_some());
+    }
     unsafe {
         gemm::<f32>(
             m,
@@ -62,6 +81,26 @@ pub fn forward(y: &Tensor, x: &Tensor, weight: &Tensor, bias: Option<&Tensor>) {
     }
 }
 
+/// 批不变的 `y = x w^T (+ y)`：按输出列并行，每个元素顺序累加，结果与批中的其他行无关。
+fn rowwise(y: &Tensor, x: &Tensor, w: &Tensor, read_dst: bool) {
+    dims!([m, n] = y);
+    dims!([_, k] = x);
+    assert!(y.is_contiguous() && x.is_contiguous() && w.is_contiguous());
+
+    let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+    let x = x.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let w = w.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    (0..n).into_par_iter().for_each(|j| {
+        let w = unsafe { from_raw_parts((w as *const f32).add(j * k), k) };
+        for i in 0..m {
+            let x = unsafe { from_raw_parts((x as *const f32).add(i * k), k) };
+            let y = unsafe { &mut *(y as *mut f32).add(i * n + j) };
+            let b = if read_dst { *y } else { 0. };
+            *y = zip(w, x).map(|(w, x)| w * x).sum::<f32>() + b
+        }
+    })
+}
+
 pub fn backward(
     dx: &Tensor,
     dw: &Tensor,
@@ -73,6 +112,11 @@ pub fn backward(
     clone_tensor!(dx dw dy x w);
 
     let dt = unique(&[dx.dt(), dw.dt(), dy.dt(), x.dt(), w.dt()]).unwrap();
+    #[cfg(feature = "reference")]
+    if dt == types::F64 {
+        let db = db.map(Tensor::cloned);
+        return super::reference::linear_backward(&dx, &dw, db.as_ref(), &dy, &x, &w);
+    }
     assert_eq!(dt, types::F32);
 
     dims!([m, n] = dx);
@@ -1,12 +1,45 @@
-use super::{Tensor, unique};
-use crate::macros::*;
+use super::{Tensor, expsum, is_half, store_f32, to_f32, unique};
+use crate::{
+    dist::{Communicator, ReduceOp},
+    macros::*,
+};
 use digit_layout::types;
 use std::iter::zip;
 
-pub fn softmax(y: &Tensor, x: &Tensor, mask: usize) {
+/// `compensated` 时指数和使用 Kahan 补偿求和，见 [`crate::Context::set_compensated_sum`]。
+pub fn softmax(y: &Tensor, x: &Tensor, mask: usize, compensated: bool) {
+    softmax_rows(y, x, mask, compensated, three_pass)
+}
+
+/// 与 [`softmax`] 相同，但单趟在线计算最大值和指数和。
+///
+/// 逐个元素更新当前最大值，最大值变大时已有的指数和按 `exp(旧最大值 - 新最大值)` 缩小，
+/// 一趟读完 `x` 即得到全局最大值和指数和，再一趟写出归一化的 `y`。
+/// 比三趟的实现少遍历一趟、不回读 `y`，但每个元素求两次指数，结果有末位差异。
+pub fn online_softmax(y: &Tensor, x: &Tensor, mask: usize, compensated: bool) {
+    softmax_rows(y, x, mask, compensated, online)
+}
+
+fn softmax_rows(
+    y: &Tensor,
+    x: &Tensor,
+    mask: usize,
+    compensated: bool,
+    row: fn(&mut [f32], &[f32], bool),
+) {
     clone_tensor!(y x);
 
     let dt = unique(&[y.dt(), x.dt()]).unwrap();
+    if is_half(dt) {
+        let (y_, x_) = (to_f32(&y), to_f32(&x));
+        softmax_rows(&y_, &x_, mask, compensated, row);
+        store_f32(&y, &y_);
+        return;
+    }
+    #[cfg(feature = "reference")]
+    if dt == types::F64 {
+        return super::reference::softmax(&y, &x, mask);
+    }
     assert_eq!(dt, types::F32);
 
     dims!([batch_size, n_seq, n_voc] = y);
@@ -29,21 +62,51 @@ pub fn softmax(y: &Tensor, x: &Tensor, mask: usize) {
                 .vector::<f32>();
 
             let (y, tail) = y.split_at_mut(mask);
-            let x = &x[..mask];
+            row(y, &x[..mask], compensated);
+            tail.fill(0.)
+        }
+    }
+}
 
-            let max = x.iter().max_by(|a, b| f32::total_cmp(a, b)).unwrap();
-            let mut expsum = 0.;
-            for (y, &x) in zip(&mut *y, x) {
-                *y = (x - max).exp();
-                expsum += *y
-            }
+fn three_pass(y: &mut [f32], x: &[f32], compensated: bool) {
+    let max = x.iter().max_by(|a, b| f32::total_cmp(a, b)).unwrap();
+    for (y, &x) in zip(&mut *y, x) {
+        *y = (x - max).exp()
+    }
+    let expsum = expsum(y, compensated);
 
-            for y in y {
-                *y /= expsum
-            }
-            tail.fill(0.)
+    for y in y {
+        *y /= expsum
+    }
+}
+
+fn online(y: &mut [f32], x: &[f32], compensated: bool) {
+    let mut max = f32::NEG_INFINITY;
+    let mut sum = 0f32;
+    // Kahan 补偿，与 `sum` 一起缩放
+    let mut c = 0f32;
+    for &x in x {
+        if x > max {
+            let scale = (max - x).exp();
+            sum *= scale;
+            c *= scale;
+            max = x
+        }
+        let val = (x - max).exp();
+        if compensated {
+            let y = val - c;
+            let t = sum + y;
+            c = (t - sum) - y;
+            sum = t
+        } else {
+            sum += val
         }
     }
+
+    let sum_inv = 1. / sum;
+    for (y, &x) in zip(y, x) {
+        *y = (x - max).exp() * sum_inv
+    }
 }
 
 pub fn crossentropy(losses: &Tensor, probs: &Tensor, targets: &Tensor) {
@@ -52,6 +115,16 @@ pub fn crossentropy(losses: &Tensor, probs: &Tensor, targets: &Tensor) {
         probs
     }
 
+    if [losses.dt(), probs.dt()].into_iter().any(is_half) {
+        let (losses_, probs_) = (to_f32(&losses), to_f32(&probs));
+        crossentropy(&losses_, &probs_, targets);
+        store_f32(&losses, &losses_);
+        return;
+    }
+    #[cfg(feature = "reference")]
+    if unique(&[losses.dt(), probs.dt()]) == Some(types::F64) {
+        return super::reference::crossentropy(&losses, &probs, targets);
+    }
     assert_eq!(unique(&[losses.dt(), probs.dt()]).unwrap(), types::F32);
     assert_eq!(targets.dt(), types::U16);
 
@@ -84,7 +157,283 @@ pub fn crossentropy(losses: &Tensor, probs: &Tensor, targets: &Tensor) {
     }
 }
 
+/// z-loss：`losses += coef * log²Z`，`Z` 为 softmax 的配分函数，抑制 logits 整体漂移。
+///
+/// `log Z` 由概率最大的词恢复，`log Z = x_i - ln p_i`，不需要 softmax 另外输出；
+/// 每个位置的 `log Z` 写入 `logz`，供 [`z_loss_backward`] 使用。
+pub fn z_loss(losses: &Tensor, logz: &Tensor, probs: &Tensor, logits: &Tensor, coef: f32) {
+    clone_tensor!(losses logz probs logits);
+
+    if [losses.dt(), probs.dt(), logits.dt()]
+        .into_iter()
+        .any(is_half)
+    {
+        let [losses_, probs_, logits_] = [&losses, &probs, &logits].map(to_f32);
+        z_loss(&losses_, &logz, &probs_, &logits_, coef);
+        store_f32(&losses, &losses_);
+        return;
+    }
+    let dt = unique(&[losses.dt(), logz.dt(), probs.dt(), logits.dt()]).unwrap();
+    assert_eq!(dt, types::F32);
+
+    dims!([batch_size, n_seq] = losses);
+    assert_eq!(logz.shape(), losses.shape());
+    assert_eq!(probs.shape(), logits.shape());
+
+    for b in 0..batch_size {
+        for t in 0..n_seq {
+            let probs = probs
+                .as_ref()
+                .index(&[b, t])
+                .map(|b| &**b.read())
+                .vector::<f32>();
+            let logits = logits
+                .as_ref()
+                .index(&[b, t])
+                .map(|b| &**b.read())
+                .vector::<f32>();
+            let (i, p) = probs
+                .iter()
+                .enumerate()
+                .max_by(|(_, a), (_, b)| f32::total_cmp(a, b))
+                .unwrap();
+            let logz_ = logits[i] - p.ln();
+            *logz
+                .as_ref()
+                .index(&[b, t])
+                .map(|b| &mut **b.write())
+                .scalar_mut::<f32>() = logz_;
+            *losses
+                .as_ref()
+                .index(&[b, t])
+                .map(|b| &mut **b.write())
+                .scalar_mut::<f32>() += coef * logz_ * logz_
+        }
+    }
+}
+
+/// [`z_loss`] 的反向：`dlogits += dlosses * 2 coef log Z * probs`。
+pub fn z_loss_backward(
+    dlogits: &Tensor,
+    dlosses: &Tensor,
+    probs: &Tensor,
+    logz: &Tensor,
+    coef: f32,
+) {
+    clone_tensor!(dlogits dlosses probs logz);
+
+    if [dlogits.dt(), dlosses.dt(), probs.dt()]
+        .into_iter()
+        .any(is_half)
+    {
+        let [dlogits_, dlosses_, probs_] = [&dlogits, &dlosses, &probs].map(to_f32);
+        z_loss_backward(&dlogits_, &dlosses_, &probs_, &logz, coef);
+        store_f32(&dlogits, &dlogits_);
+        return;
+    }
+    let dt = unique(&[dlogits.dt(), dlosses.dt(), probs.dt(), logz.dt()]).unwrap();
+    assert_eq!(dt, types::F32);
+
+    dims!([batch_size, n_seq] = dlosses);
+    assert_eq!(logz.shape(), dlosses.shape());
+    assert_eq!(dlogits.shape(), probs.shape());
+
+    for b in 0..batch_size {
+        for t in 0..n_seq {
+            let dlogits = dlogits
+                .as_ref()
+                .index(&[b, t])
+                .map(|b| &mut **b.write())
+                .vector_mut::<f32>();
+            let probs = probs
+                .as_ref()
+                .index(&[b, t])
+                .map(|b| &**b.read())
+                .vector::<f32>();
+            let dloss = *dlosses
+                .as_ref()
+                .index(&[b, t])
+                .map(|b| &**b.read())
+                .scalar::<f32>();
+            let logz = *logz
+                .as_ref()
+                .index(&[b, t])
+                .map(|b| &**b.read())
+                .scalar::<f32>();
+            let k = dloss * 2. * coef * logz;
+            for (dx, p) in zip(dlogits, probs) {
+                *dx += k * p
+            }
+        }
+    }
+}
+
+/// 软截断 logits：`y = cap * tanh(x / cap)`，输出的绝对值小于 `cap`，`|x|` 远小于 `cap` 时近似不变。
+pub fn softcap(y: &Tensor, x: &Tensor, cap: f32) {
+    clone_tensor!(y x);
+
+    let dt = unique(&[y.dt(), x.dt()]).unwrap();
+    if is_half(dt) {
+        let (y_, x_) = (to_f32(&y), to_f32(&x));
+        softcap(&y_, &x_, cap);
+        store_f32(&y, &y_);
+        return;
+    }
+    assert_eq!(dt, types::F32);
+    assert_eq!(y.shape(), x.shape());
+
+    let ndim = y.layout().ndim();
+    let y = y.merge(0, ndim);
+    let x = x.merge(0, ndim);
+    for (y, x) in zip(
+        y.as_ref().map(|b| &mut **b.write()).vector_mut::<f32>(),
+        x.as_ref().map(|b| &**b.read()).vector::<f32>(),
+    ) {
+        *y = cap * (x / cap).tanh()
+    }
+}
+
+/// [`softcap`] 的反向，原地修改：`dx *= 1 - (y / cap)²`，`y` 为截断后的 logits。
+pub fn softcap_backward(dx: &Tensor, y: &Tensor, cap: f32) {
+    clone_tensor!(dx y);
+
+    let dt = unique(&[dx.dt(), y.dt()]).unwrap();
+    if is_half(dt) {
+        let (dx_, y_) = (to_f32(&dx), to_f32(&y));
+        softcap_backward(&dx_, &y_, cap);
+        store_f32(&dx, &dx_);
+        return;
+    }
+    assert_eq!(dt, types::F32);
+    assert_eq!(dx.shape(), y.shape());
+
+    let ndim = dx.layout().ndim();
+    let dx = dx.merge(0, ndim);
+    let y = y.merge(0, ndim);
+    for (dx, y) in zip(
+        dx.as_ref().map(|b| &mut **b.write()).vector_mut::<f32>(),
+        y.as_ref().map(|b| &**b.read()).vector::<f32>(),
+    ) {
+        let y = y / cap;
+        *dx *= 1. - y * y
+    }
+}
+
 pub fn backward(dlogits: &Tensor, dlosses: &Tensor, probs: &Tensor, targets: &Tensor) {
+    backward_shard(dlogits, dlosses, probs, targets, 0)
+}
+
+/// 按词表切分的 softmax 与交叉熵。
+///
+/// `logits` 与 `probs` 只包含词 `[start, start + n)` 的部分，`n_voc` 之后的填充词不参与计算。
+/// 各分片通过 `comm` 规约最大值、指数和与目标词的损失，完成后所有分片的 `losses` 相同。
+/// `compensated` 同 [`softmax`]。
+#[allow(clippy::too_many_arguments)]
+pub fn vocab_parallel_crossentropy(
+    losses: &Tensor,
+    probs: &Tensor,
+    logits: &Tensor,
+    targets: &Tensor,
+    start: usize,
+    n_voc: usize,
+    comm: &dyn Communicator,
+    compensated: bool,
+) {
+    clone_tensor!(losses probs logits targets);
+
+    let dt = unique(&[losses.dt(), probs.dt(), logits.dt()]).unwrap();
+    assert_eq!(dt, types::F32);
+    assert_eq!(targets.dt(), types::U16);
+
+    dims!([batch_size_0, n_seq_0] = losses);
+    dims!([batch_size_1, n_seq_1, n_0] = probs);
+    dims!([batch_size_2, n_seq_2, n_1] = logits);
+    dims!([batch_size_3, n_seq_3] = targets);
+
+    let batch_size = unique(&[batch_size_0, batch_size_1, batch_size_2, batch_size_3]).unwrap();
+    let n_seq = unique(&[n_seq_0, n_seq_1, n_seq_2, n_seq_3]).unwrap();
+    let n = unique(&[n_0, n_1]).unwrap();
+    let valid = n_voc.saturating_sub(start).min(n);
+
+    let rows = (0..batch_size).flat_map(|b| (0..n_seq).map(move |t| [b, t]));
+    let row = |i: [usize; 2]| {
+        logits
+            .as_ref()
+            .index(&i)
+            .map(|b| &**b.read())
+            .vector::<f32>()
+    };
+
+    // 全局最大值
+    let mut max = rows
+        .clone()
+        .map(|i| {
+            row(i)[..valid]
+                .iter()
+                .fold(f32::NEG_INFINITY, |a, &b| a.max(b))
+        })
+        .collect::<Vec<_>>();
+    comm.all_reduce(&mut max, ReduceOp::Max);
+
+    // 全局指数和
+    let mut expsum = zip(rows.clone(), &max)
+        .map(|(i, max)| {
+            let y = probs
+                .as_ref()
+                .index(&i)
+                .map(|b| &mut **b.write())
+                .vector_mut::<f32>();
+            let (y, tail) = y.split_at_mut(valid);
+            for (y, x) in zip(&mut *y, row(i)) {
+                *y = (x - max).exp()
+            }
+            tail.fill(0.);
+            expsum(y, compensated)
+        })
+        .collect::<Vec<_>>();
+    comm.all_reduce(&mut expsum, ReduceOp::Sum);
+
+    // 只有持有目标词的分片产生损失
+    let mut loss = zip(rows.clone(), &expsum)
+        .map(|(i, expsum)| {
+            let y = probs
+                .as_ref()
+                .index(&i)
+                .map(|b| &mut **b.write())
+                .vector_mut::<f32>();
+            for y in &mut *y {
+                *y /= expsum
+            }
+            let target = *targets
+                .as_ref()
+                .index(&i)
+                .map(|b| &**b.read())
+                .scalar::<u16>() as usize;
+            match target.checked_sub(start) {
+                Some(j) if j < valid => -y[j].ln(),
+                _ => 0.,
+            }
+        })
+        .collect::<Vec<_>>();
+    comm.all_reduce(&mut loss, ReduceOp::Sum);
+
+    for (i, loss) in zip(rows, loss) {
+        *losses
+            .as_ref()
+            .index(&i)
+            .map(|b| &mut **b.write())
+            .scalar_mut::<f32>() = loss
+    }
+}
+
+/// [`vocab_parallel_crossentropy`] 的反向，`dlogits` 只包含词 `[start, start + n)` 的部分，无需通信。
+pub fn backward_shard(
+    dlogits: &Tensor,
+    dlosses: &Tensor,
+    probs: &Tensor,
+    targets: &Tensor,
+    start: usize,
+) {
     clone_tensor! {
         dlogits
         dlosses
@@ -92,6 +441,22 @@ pub fn backward(dlogits: &Tensor, dlosses: &Tensor, probs: &Tensor, targets: &Te
         targets
     }
 
+    if [dlogits.dt(), dlosses.dt(), probs.dt()]
+        .into_iter()
+        .any(is_half)
+    {
+        // 梯度以 f32 累加，完成后转换回半精度
+        let [dlogits_, dlosses_, probs_] = [&dlogits, &dlosses, &probs].map(to_f32);
+        backward_shard(&dlogits_, &dlosses_, &probs_, &targets, start);
+        store_f32(&dlogits, &dlogits_);
+        return;
+    }
+    #[cfg(feature = "reference")]
+    if unique(&[dlogits.dt(), dlosses.dt(), probs.dt()]) == Some(types::F64) {
+        return super::reference::crossentropy_backward(
+            &dlogits, &dlosses, &probs, &targets, start,
+        );
+    }
     let dt = unique(&[dlogits.dt(), dlosses.dt(), probs.dt()]).unwrap();
     assert_eq!(dt, types::F32);
     assert_eq!(targets.dt(), types::U16);
@@ -128,9 +493,213 @@ pub fn backward(dlogits: &Tensor, dlosses: &Tensor, probs: &Tensor, targets: &Te
                 .map(|b| &**b.read())
                 .scalar::<u16>() as usize;
             for (i, (dlogit, prob)) in zip(dlogits, probs).enumerate() {
-                let indicator = if i == ix { 1. } else { 0. };
+                let indicator = if start + i == ix { 1. } else { 0. };
                 *dlogit += (prob - indicator) * dloss
             }
         }
     }
 }
+
+/// `y[b, t] = x[b, t + k]`，没有对应位置的保持不变。
+pub fn shift_targets(y: &Tensor, x: &Tensor, k: usize) {
+    clone_tensor!(y x);
+    assert_eq!(unique(&[y.dt(), x.dt()]).unwrap(), types::U16);
+    dims!([_, n_seq] = y);
+
+    let y = y
+        .as_ref()
+        .merge(0, 2)
+        .map(|b| &mut **b.write())
+        .vector_mut::<u16>();
+    let x = x.as_ref().merge(0, 2).map(|b| &**b.read()).vector::<u16>();
+    for (y, x) in zip(y.chunks_exact_mut(n_seq), x.chunks_exact(n_seq)) {
+        y[..n_seq.saturating_sub(k)].copy_from_slice(&x[k.min(n_seq)..])
+    }
+}
+
+/// `y[b, t] += x[b, t] * scale`，只处理 `t < n_valid` 的位置。
+pub fn accumulate(y: &Tensor, x: &Tensor, scale: f32, n_valid: usize) {
+    clone_tensor!(y x);
+    assert_eq!(unique(&[y.dt(), x.dt()]).unwrap(), types::F32);
+    dims!([_, n_seq] = y);
+
+    let y = y
+        .as_ref()
+        .merge(0, 2)
+        .map(|b| &mut **b.write())
+        .vector_mut::<f32>();
+    let x = x.as_ref().merge(0, 2).map(|b| &**b.read()).vector::<f32>();
+    for (y, x) in zip(y.chunks_exact_mut(n_seq), x.chunks_exact(n_seq)) {
+        for (y, x) in zip(&mut y[..n_valid], x) {
+            *y += x * scale
+        }
+    }
+}
+
+/// `x[b, t] = 0`，只处理 `t < prefix` 的位置。
+pub fn zero_prefix(x: &Tensor, prefix: usize) {
+    if prefix == 0 {
+        return;
+    }
+    clone_tensor!(x);
+    assert_eq!(x.dt(), types::F32);
+    dims!([_, n_seq] = x);
+
+    let x = x
+        .as_ref()
+        .merge(0, 2)
+        .map(|b| &mut **b.write())
+        .vector_mut::<f32>();
+    for x in x.chunks_exact_mut(n_seq) {
+        x[..prefix.min(n_seq)].fill(0.)
+    }
+}
+
+#[test]
+fn test_vocab_parallel() {
+    use super::fixture::{tensor, values, zeros};
+    use crate::dist::ThreadComm;
+
+    const SHAPE: [usize; 2] = [2, 3];
+    const N_VOC: usize = 7;
+    const PADDED: usize = 8;
+    let logits = (0..SHAPE[0] * SHAPE[1] * PADDED)
+        .map(|i| ((i * 37 % 23) as f32 - 11.) / 4.)
+        .collect::<Vec<_>>();
+    let targets = [0u16, 3, 6, 4, 5, 1];
+
+    // 不切分的参考结果
+    let [b, t] = SHAPE;
+    let x = tensor(types::F32, &[b, t, PADDED], &logits);
+    let probs = zeros(types::F32, &[b, t, PADDED]);
+    let losses = zeros(types::F32, &SHAPE);
+    let targets_ = tensor(types::U16, &SHAPE, &targets);
+    softmax(&probs, &x, N_VOC, false);
+    crossentropy(&losses, &probs, &targets_);
+    let dlosses = tensor(types::F32, &SHAPE, &[1f32; 6]);
+    let dlogits = zeros(types::F32, &[b, t, PADDED]);
+    backward(&dlogits, &dlosses, &probs, &targets_);
+    let (losses, dlogits) = (values(&losses), values(&dlogits));
+
+    let results = std::thread::scope(|s| {
+        ThreadComm::group(2)
+            .into_iter()
+            .map(|comm| {
+                let logits = &logits;
+                s.spawn(move || {
+                    let range = crate::dist::vocab_range(PADDED, &comm);
+                    let shard = logits
+                        .chunks_exact(PADDED)
+                        .flat_map(|row| &row[range.clone()])
+                        .copied()
+                        .collect::<Vec<_>>();
+                    let n = range.len();
+                    let x = tensor(types::F32, &[b, t, n], &shard);
+                    let probs = zeros(types::F32, &[b, t, n]);
+                    let losses = zeros(types::F32, &SHAPE);
+                    let targets = tensor(types::U16, &SHAPE, &targets);
+                    vocab_parallel_crossentropy(
+                        &losses,
+                        &probs,
+                        &x,
+                        &targets,
+                        range.start,
+                        N_VOC,
+                        &comm,
+                        false,
+                    );
+                    let dlosses = tensor(types::F32, &SHAPE, &[1f32; 6]);
+                    let dlogits = zeros(types::F32, &[b, t, n]);
+                    backward_shard(&dlogits, &dlosses, &probs, &targets, range.start);
+                    (range, values(&losses), values(&dlogits))
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    for (range, losses_, dlogits_) in results {
+        for (a, b) in zip(&losses, &losses_) {
+            assert!((a - b).abs() < 1e-5, "{a} vs {b}")
+        }
+        let n = range.len();
+        for (row, row_) in zip(dlogits.chunks_exact(PADDED), dlogits_.chunks_exact(n)) {
+            for (a, b) in zip(&row[range.clone()], row_) {
+                assert!((a - b).abs() < 1e-5, "{a} vs {b}")
+            }
+        }
+    }
+}
+
+#[test]
+fn test_half() {
+    use super::fixture::{tensor, values, zeros};
+    use half::{bf16, f16};
+
+    const SHAPE: [usize; 3] = [2, 3, 8];
+    let logits = (0..SHAPE.iter().product())
+        .map(|i| ((i * 37 % 23) as f32 - 11.) / 4.)
+        .collect::<Vec<_>>();
+    let targets = [0u16, 3, 6, 4, 5, 1];
+
+    let [b, t, n] = SHAPE;
+    let targets = tensor(types::U16, &[b, t], &targets);
+    let run = |x: Tensor| {
+        let dt = x.dt();
+        let probs = zeros(dt, &SHAPE);
+        let losses = zeros(dt, &[b, t]);
+        softmax(&probs, &x, n, false);
+        crossentropy(&losses, &probs, &targets);
+        let dlosses = tensor(types::F32, &[b, t], &[1f32; 6]);
+        let dlogits = zeros(dt, &SHAPE);
+        backward(&dlogits, &dlosses, &probs, &targets);
+        (values(&losses), values(&dlogits))
+    };
+
+    let (losses, dlogits) = run(tensor(types::F32, &SHAPE, &logits));
+    let bf16s = logits
+        .iter()
+        .map(|&x| bf16::from_f32(x))
+        .collect::<Vec<_>>();
+    let f16s = logits.iter().map(|&x| f16::from_f32(x)).collect::<Vec<_>>();
+    for (losses_, dlogits_) in [
+        run(tensor(types::BF16, &SHAPE, &bf16s)),
+        run(tensor(types::F16, &SHAPE, &f16s)),
+    ] {
+        for (a, b) in zip(
+            losses.iter().chain(&dlogits),
+            losses_.iter().chain(&dlogits_),
+        ) {
+            assert!((a - b).abs() < 2e-2, "{a} vs {b}")
+        }
+    }
+}
+
+#[test]
+fn test_online_softmax() {
+    use super::fixture::{from_f32, values, zeros};
+
+    // 最大值多次变大，出现在行的后部，末尾有掩码
+    const N: usize = 3172;
+    const MASK: usize = N - 37;
+    let logits = (0..2 * N)
+        .map(|i| ((i * 37 % 101) as f32 - 50.) / 8. + (i % N) as f32 / 200.)
+        .collect::<Vec<_>>();
+    let x = from_f32(&[1, 2, N], &logits);
+
+    for compensated in [false, true] {
+        let (y, y_) = (zeros(types::F32, &[1, 2, N]), zeros(types::F32, &[1, 2, N]));
+        softmax(&y, &x, MASK, compensated);
+        online_softmax(&y_, &x, MASK, compensated);
+        let (y, y_) = (values(&y), values(&y_));
+        for (row, row_) in zip(y.chunks(N), y_.chunks(N)) {
+            assert!(row_[MASK..].iter().all(|&y| y == 0.));
+            assert!((row_.iter().sum::<f32>() - 1.).abs() < 1e-5);
+            for (a, b) in zip(row, row_) {
+                assert!((a - b).abs() <= 1e-5 * a.max(1e-30), "{a} vs {b}")
+            }
+        }
+    }
+}
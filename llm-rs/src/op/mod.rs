@@ -1,11 +1,27 @@
 pub mod add;
 pub mod attention;
+pub mod cast;
+pub mod conv1d;
+pub mod cross_attention;
+pub mod dropout;
+pub mod einsum;
 pub mod embedding;
+#[cfg(test)]
+pub(crate) mod fixture;
+pub mod flash_attention;
 pub mod gelu;
 pub mod gemm;
 pub mod layer_norm;
 pub mod linear;
 pub mod loss;
+pub mod quant;
+pub mod rearrange;
+#[cfg(feature = "reference")]
+pub mod reference;
+pub mod rms_norm;
+pub mod rope;
+pub mod swiglu;
+pub mod topk;
 
 type Tensor = crate::Tensor<rw_rc::RwRc<crate::Blob>>;
 
@@ -20,3 +36,67 @@ fn unique<T: Copy + Eq>(vals: &[T]) -> Option<T> {
     }
     Some(*val)
 }
+
+/// 按顺序累加 softmax 的指数，顺序固定因此结果确定。
+///
+/// `compensated` 时使用 Kahan 补偿求和，见 [`crate::Context::set_compensated_sum`]。
+fn expsum(vals: &[f32], compensated: bool) -> f32 {
+    if !compensated {
+        return vals.iter().sum();
+    }
+    let mut sum = 0f32;
+    let mut c = 0f32;
+    for &val in vals {
+        let y = val - c;
+        let t = sum + y;
+        // 记下这次加法丢失的低位，下一次加回来
+        c = (t - sum) - y;
+        sum = t
+    }
+    sum
+}
+
+/// 是否为以 f32 计算的半精度类型。
+fn is_half(dt: digit_layout::DigitLayout) -> bool {
+    use digit_layout::types;
+    matches!(dt, types::BF16 | types::F16)
+}
+
+/// 把半精度张量转换为连续的 f32 副本，f32 张量直接共享存储。
+///
+/// 低精度的算子借此以 f32 计算和累加，结果再用 [`store_f32`] 写回。
+fn to_f32(t: &Tensor) -> Tensor {
+    use crate::Blob;
+    use digit_layout::types;
+    use rw_rc::RwRc;
+
+    if t.dt() == types::F32 {
+        return t.cloned();
+    }
+    let ans = crate::Tensor::new(types::F32, &t.shape())
+        .map(Blob::new)
+        .map(RwRc::new);
+    cast::forward(&ans, t);
+    ans
+}
+
+/// 把 [`to_f32`] 得到的副本写回 `dst`，f32 张量与副本共享存储，无需写回。
+fn store_f32(dst: &Tensor, src: &Tensor) {
+    use digit_layout::types;
+
+    assert_eq!(src.dt(), types::F32);
+    assert_eq!(dst.shape(), src.shape());
+    if dst.dt() != types::F32 {
+        cast::forward(dst, src)
+    }
+}
+
+#[test]
+fn test_expsum() {
+    // 一个大数之后跟许多小数，普通求和会把小数全部舍掉
+    let mut vals = vec![1f32];
+    vals.extend(std::iter::repeat_n(1e-8, 1 << 20));
+    let exact = 1. + 1e-8 * (1 << 20) as f64;
+    assert_eq!(expsum(&vals, false), 1.);
+    assert!((expsum(&vals, true) as f64 - exact).abs() < 1e-6);
+}
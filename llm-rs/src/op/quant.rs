@@ -0,0 +1,73 @@
+use super::Tensor;
+use crate::{macros::*, quant::dequant_row};
+use digit_layout::types;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::iter::zip;
+
+/// 将量化张量 `x` 反量化为 f32 张量 `y`。
+pub fn dequant(y: &Tensor, x: &Tensor) {
+    clone_tensor!(y x);
+
+    assert_eq!(y.dt(), types::F32);
+    assert_eq!(y.shape(), x.shape());
+    assert!(y.is_contiguous());
+    assert!(x.is_contiguous());
+
+    let ndim = y.layout().ndim();
+    let y = y.merge(0, ndim);
+    let x = x.merge(0, ndim);
+    dequant_row(
+        x.dt(),
+        x.as_ref().map(|b| &**b.read()).get(),
+        y.as_ref().map(|b| &mut **b.write()).vector_mut::<f32>(),
+    )
+}
+
+/// 量化权重的矩阵乘：`y = x w^T + b`，每个线程每次反量化 `w` 的一行。
+pub fn linear(y: &Tensor, x: &Tensor, w: &Tensor, bias: Option<&Tensor>) {
+    clone_tensor!(y x w);
+
+    assert_eq!(y.dt(), types::F32);
+    assert_eq!(x.dt(), types::F32);
+
+    dims!([m, n] = y);
+    dims!([m_, k] = x);
+    dims!([n_, k_] = w);
+
+    assert_eq!(m, m_);
+    assert_eq!(k, k_);
+    assert_eq!(n, n_);
+    assert!(y.is_contiguous());
+    assert!(x.is_contiguous());
+    assert!(w.is_contiguous());
+
+    let bias = bias.map(|bias| {
+        assert_eq!(bias.dt(), types::F32);
+        bias.cloned()
+    });
+    let bias = bias
+        .as_ref()
+        .map(|b| b.as_ref().map(|b| &**b.read()).vector::<f32>());
+
+    let dt = w.dt();
+    let row_size = k / dt.group_size() * dt.nbytes();
+
+    let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>() as usize;
+    let x = x.as_ref().map(|b| &**b.read()).ptr::<f32>() as usize;
+    let w = w.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+    (0..n).into_par_iter().for_each_init(
+        || vec![0.; k],
+        |row, j| {
+            let w =
+                unsafe { std::slice::from_raw_parts((w as *const u8).add(j * row_size), row_size) };
+            dequant_row(dt, w, row);
+
+            let b = bias.map_or(0., |b| b[j]);
+            for i in 0..m {
+                let x = unsafe { std::slice::from_raw_parts((x as *const f32).add(i * k), k) };
+                let y = unsafe { &mut *(y as *mut f32).add(i * n + j) };
+                *y = zip(&*row, x).map(|(w, x)| w * x).sum::<f32>() + b
+            }
+        },
+    )
+}
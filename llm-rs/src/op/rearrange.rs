@@ -0,0 +1,16 @@
+use super::Tensor;
+use crate::macros::*;
+use mem_rearrange::Rearranging;
+
+/// 把 `x` 复制到形状相同的 `y`，两者都可以是任意步长的视图，例如把 Q、K、V 拼接为 qkv 的各段。
+pub fn rearrange(y: &Tensor, x: &Tensor) {
+    clone_tensor!(y x);
+    assert_eq!(y.dt(), x.dt());
+    assert_eq!(y.shape(), x.shape());
+
+    unsafe {
+        Rearranging::new(y.layout(), x.layout(), y.dt().nbytes())
+            .unwrap()
+            .launch(y.get().write().as_mut_ptr(), x.get().read().as_ptr())
+    }
+}
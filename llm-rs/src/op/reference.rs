@@ -0,0 +1,714 @@
+//! f64 参考实现，用于验证其他实现的数值精度。
+//!
+//! 开启 `reference` 特性时，算子遇到 f64 张量分派到这里。
+//! 参考实现只支持连续张量，不支持注意力的相对位置偏置、稀疏模式和跨层共享 KV；
+//! 丢弃、卷积、einsum 和量化算子没有参考实现。
+//! [`compare`] 以 f32 和 f64 分别执行同一段计算，报告各输出的最大误差。
+
+use super::Tensor;
+use crate::Blob;
+use digit_layout::types;
+use itertools::izip;
+use rw_rc::RwRc;
+use std::{
+    iter::zip,
+    slice::{from_raw_parts, from_raw_parts_mut},
+};
+
+/// 连续张量的全部元素。
+fn data<T>(t: &Tensor) -> &[T] {
+    assert!(t.is_contiguous());
+    assert_eq!(t.dt().nbytes(), size_of::<T>());
+    let len = t.shape().iter().product();
+    unsafe { from_raw_parts(t.as_ref().map(|b| &**b.read()).ptr::<T>(), len) }
+}
+
+/// 词号或位置号。
+fn indices(t: &Tensor) -> Vec<usize> {
+    match t.dt() {
+        types::U16 => data::<u16>(t).iter().map(|&i| i as _).collect(),
+        types::U32 => data::<u32>(t).iter().map(|&i| i as _).collect(),
+        types::I32 => data::<i32>(t).iter().map(|&i| i as _).collect(),
+        dt => panic!("unsupported index type {dt}"),
+    }
+}
+
+/// 连续张量的全部元素，可写。
+#[allow(clippy::mut_from_ref)]
+fn data_mut<T>(t: &Tensor) -> &mut [T] {
+    assert!(t.is_contiguous());
+    assert_eq!(t.dt().nbytes(), size_of::<T>());
+    let len = t.shape().iter().product();
+    unsafe { from_raw_parts_mut(t.as_ref().map(|b| &mut **b.write()).mut_ptr::<T>(), len) }
+}
+
+pub(super) fn embedding(y: &Tensor, i1: &Tensor, table1: &Tensor, pos: Option<(&Tensor, &Tensor)>) {
+    let d = y.shape()[1];
+    let y = data_mut::<f64>(y);
+    let table1 = data::<f64>(table1);
+    for (y, i1) in zip(y.chunks_exact_mut(d), indices(i1)) {
+        y.copy_from_slice(&table1[i1 * d..][..d])
+    }
+    if let Some((i2, table2)) = pos {
+        let table2 = data::<f64>(table2);
+        for (y, i2) in zip(y.chunks_exact_mut(d), indices(i2)) {
+            for (y, x2) in zip(y, &table2[i2 * d..][..d]) {
+                *y += x2
+            }
+        }
+    }
+}
+
+pub(super) fn embedding_backward(
+    dtable1: &Tensor,
+    dy: &Tensor,
+    i1: &Tensor,
+    pos: Option<(&Tensor, &Tensor)>,
+) {
+    let d = dy.shape()[1];
+    let dy = data::<f64>(dy);
+    let dtable1 = data_mut::<f64>(dtable1);
+    for (dy, i1) in zip(dy.chunks_exact(d), indices(i1)) {
+        for (i, dy) in dy.iter().enumerate() {
+            dtable1[i1 * d + i] += dy
+        }
+    }
+    if let Some((i2, dtable2)) = pos {
+        let dtable2 = data_mut::<f64>(dtable2);
+        for (dy, i2) in zip(dy.chunks_exact(d), indices(i2)) {
+            for (i, dy) in dy.iter().enumerate() {
+                dtable2[i2 * d + i] += dy
+            }
+        }
+    }
+}
+
+pub(super) fn softmax(y: &Tensor, x: &Tensor, mask: usize) {
+    let n = *y.shape().last().unwrap();
+    for (y, x) in zip(
+        data_mut::<f64>(y).chunks_exact_mut(n),
+        data::<f64>(x).chunks_exact(n),
+    ) {
+        let (y, tail) = y.split_at_mut(mask);
+        let max = x[..mask].iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        for (y, x) in zip(&mut *y, x) {
+            *y = (x - max).exp()
+        }
+        let sum = y.iter().sum::<f64>();
+        for y in y {
+            *y /= sum
+        }
+        tail.fill(0.)
+    }
+}
+
+pub(super) fn crossentropy(losses: &Tensor, probs: &Tensor, targets: &Tensor) {
+    let n = *probs.shape().last().unwrap();
+    for (loss, (probs, &target)) in zip(
+        data_mut::<f64>(losses),
+        zip(data::<f64>(probs).chunks_exact(n), data::<u16>(targets)),
+    ) {
+        *loss = -probs[target as usize].ln()
+    }
+}
+
+pub(super) fn crossentropy_backward(
+    dlogits: &Tensor,
+    dlosses: &Tensor,
+    probs: &Tensor,
+    targets: &Tensor,
+    start: usize,
+) {
+    let n = *probs.shape().last().unwrap();
+    for ((dlogits, probs), (dloss, &target)) in zip(
+        zip(
+            data_mut::<f64>(dlogits).chunks_exact_mut(n),
+            data::<f64>(probs).chunks_exact(n),
+        ),
+        zip(data::<f64>(dlosses), data::<u16>(targets)),
+    ) {
+        for (i, (dlogit, prob)) in zip(dlogits, probs).enumerate() {
+            let indicator = if start + i == target as usize { 1. } else { 0. };
+            *dlogit += (prob - indicator) * dloss
+        }
+    }
+}
+
+pub(super) fn attention(y: &Tensor, preatt: &Tensor, att: &Tensor, x: &Tensor) {
+    let &[batch_size, nh, n_seq, _] = &*att.shape() else {
+        unreachable!()
+    };
+    let d = y.shape()[2];
+    let dh = d / nh;
+    let scale = (dh as f64).powf(-0.5);
+    let (y, x) = (data_mut::<f64>(y), data::<f64>(x));
+    let (preatt, att) = (data_mut::<f64>(preatt), data_mut::<f64>(att));
+
+    for b in 0..batch_size {
+        for h in 0..nh {
+            for t in 0..n_seq {
+                let qkv = |t: usize, i: usize| &x[((b * n_seq + t) * 3 + i) * d + h * dh..][..dh];
+                let row = ((b * nh + h) * n_seq + t) * n_seq;
+                let preatt = &mut preatt[row..][..=t];
+                let att = &mut att[row..][..n_seq];
+
+                for (t_, val) in preatt.iter_mut().enumerate() {
+                    *val = zip(qkv(t, 0), qkv(t_, 1)).map(|(q, k)| q * k).sum::<f64>() * scale
+                }
+                let max = preatt.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                for (att, preatt) in zip(&mut *att, &*preatt) {
+                    *att = (preatt - max).exp()
+                }
+                let sum = att[..=t].iter().sum::<f64>();
+                for att in &mut att[..=t] {
+                    *att /= sum
+                }
+                att[t + 1..].fill(0.);
+
+                let y = &mut y[(b * n_seq + t) * d + h * dh..][..dh];
+                y.fill(0.);
+                for (t_, att) in att[..=t].iter().enumerate() {
+                    for (y, v) in zip(&mut *y, qkv(t_, 2)) {
+                        *y += att * v
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub(super) fn attention_backward(
+    dx: &Tensor,
+    dpreatt: &Tensor,
+    datt: &Tensor,
+    dy: &Tensor,
+    x: &Tensor,
+    att: &Tensor,
+) {
+    let &[batch_size, nh, n_seq, _] = &*att.shape() else {
+        unreachable!()
+    };
+    let d = dy.shape()[2];
+    let dh = d / nh;
+    let scale = (dh as f64).powf(-0.5);
+    let (dx, x) = (data_mut::<f64>(dx), data::<f64>(x));
+    let (dpreatt, datt) = (data_mut::<f64>(dpreatt), data_mut::<f64>(datt));
+    let (dy, att) = (data::<f64>(dy), data::<f64>(att));
+
+    for b in 0..batch_size {
+        for h in 0..nh {
+            for t in 0..n_seq {
+                let idx = |t: usize, i: usize| ((b * n_seq + t) * 3 + i) * d + h * dh;
+                let row = ((b * nh + h) * n_seq + t) * n_seq;
+                let att = &att[row..][..=t];
+                let datt = &mut datt[row..][..=t];
+                let dpreatt = &mut dpreatt[row..][..=t];
+                let dy = &dy[(b * n_seq + t) * d + h * dh..][..dh];
+
+                for t_ in 0..=t {
+                    for i in 0..dh {
+                        datt[t_] += x[idx(t_, 2) + i] * dy[i];
+                        dx[idx(t_, 2) + i] += att[t_] * dy[i]
+                    }
+                }
+                for t_ in 0..=t {
+                    for t__ in 0..=t {
+                        let indicator = if t_ == t__ { 1. } else { 0. };
+                        dpreatt[t__] += att[t_] * (indicator - att[t__]) * datt[t_]
+                    }
+                }
+                for t_ in 0..=t {
+                    for i in 0..dh {
+                        dx[idx(t, 0) + i] += x[idx(t_, 1) + i] * dpreatt[t_] * scale;
+                        dx[idx(t_, 1) + i] += x[idx(t, 0) + i] * dpreatt[t_] * scale
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub(super) fn add(y: &Tensor, x: &Tensor) {
+    for (y, x) in zip(data_mut::<f64>(y), data::<f64>(x)) {
+        *y += x
+    }
+}
+
+/// 与 f32 实现一致，epsilon 固定为 1e-5。
+pub(super) fn layer_norm(
+    y: &Tensor,
+    mean: &Tensor,
+    rstd: &Tensor,
+    x: &Tensor,
+    w: &Tensor,
+    b: &Tensor,
+) {
+    let d = *x.shape().last().unwrap();
+    let (w, b) = (data::<f64>(w), data::<f64>(b));
+    for (y, x, mean, rstd) in izip!(
+        data_mut::<f64>(y).chunks_exact_mut(d),
+        data::<f64>(x).chunks_exact(d),
+        data_mut::<f64>(mean),
+        data_mut::<f64>(rstd),
+    ) {
+        *mean = x.iter().sum::<f64>() / d as f64;
+        let var = x.iter().map(|x| (x - *mean).powi(2)).sum::<f64>() / d as f64;
+        *rstd = (var + 1e-5).powf(-0.5);
+        for (y, x, w, b) in izip!(y, x, w, b) {
+            *y = (x - *mean) * *rstd * w + b
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(super) fn layer_norm_backward(
+    dx: &Tensor,
+    dw: &Tensor,
+    db: &Tensor,
+    dy: &Tensor,
+    x: &Tensor,
+    w: &Tensor,
+    mean: &Tensor,
+    rstd: &Tensor,
+) {
+    let d = *x.shape().last().unwrap();
+    let (dw, db, w) = (data_mut::<f64>(dw), data_mut::<f64>(db), data::<f64>(w));
+    for (dx, dy, x, mean, rstd) in izip!(
+        data_mut::<f64>(dx).chunks_exact_mut(d),
+        data::<f64>(dy).chunks_exact(d),
+        data::<f64>(x).chunks_exact(d),
+        data::<f64>(mean),
+        data::<f64>(rstd),
+    ) {
+        let norm = x.iter().map(|x| (x - mean) * rstd).collect::<Vec<_>>();
+        let dnorm = zip(w, dy).map(|(w, dy)| w * dy).collect::<Vec<_>>();
+        let dnorm_mean = dnorm.iter().sum::<f64>() / d as f64;
+        let dnorm_norm_mean = zip(&dnorm, &norm).map(|(a, b)| a * b).sum::<f64>() / d as f64;
+        for (j, dx) in dx.iter_mut().enumerate() {
+            db[j] += dy[j];
+            dw[j] += norm[j] * dy[j];
+            *dx += rstd * (dnorm[j] - dnorm_mean - norm[j] * dnorm_norm_mean)
+        }
+    }
+}
+
+pub(super) fn rms_norm(y: &Tensor, rstd: &Tensor, x: &Tensor, w: &Tensor, epsilon: f64) {
+    let d = *x.shape().last().unwrap();
+    let w = data::<f64>(w);
+    for (y, x, rstd) in izip!(
+        data_mut::<f64>(y).chunks_exact_mut(d),
+        data::<f64>(x).chunks_exact(d),
+        data_mut::<f64>(rstd),
+    ) {
+        *rstd = (x.iter().map(|x| x * x).sum::<f64>() / d as f64 + epsilon).powf(-0.5);
+        for (y, x, w) in izip!(y, x, w) {
+            *y = x * *rstd * w
+        }
+    }
+}
+
+pub(super) fn rms_norm_backward(
+    dx: &Tensor,
+    dw: &Tensor,
+    dy: &Tensor,
+    x: &Tensor,
+    w: &Tensor,
+    rstd: &Tensor,
+) {
+    let d = *x.shape().last().unwrap();
+    let (dw, w) = (data_mut::<f64>(dw), data::<f64>(w));
+    for (dx, dy, x, rstd) in izip!(
+        data_mut::<f64>(dx).chunks_exact_mut(d),
+        data::<f64>(dy).chunks_exact(d),
+        data::<f64>(x).chunks_exact(d),
+        data::<f64>(rstd),
+    ) {
+        let dnorm_norm_mean = izip!(w, dy, x)
+            .map(|(w, dy, x)| w * dy * x * rstd)
+            .sum::<f64>()
+            / d as f64;
+        for (j, dx) in dx.iter_mut().enumerate() {
+            let norm = x[j] * rstd;
+            dw[j] += norm * dy[j];
+            *dx += rstd * (w[j] * dy[j] - norm * dnorm_norm_mean)
+        }
+    }
+}
+
+/// `y = x w^T + b`，`w` 的形状为 `[n_out, n_in]`。
+pub(super) fn linear(y: &Tensor, x: &Tensor, w: &Tensor, b: Option<&Tensor>) {
+    let &[n_out, n_in] = &*w.shape() else {
+        unreachable!()
+    };
+    let (x, w) = (data::<f64>(x), data::<f64>(w));
+    let b = b.map(data::<f64>);
+    for (y, x) in zip(
+        data_mut::<f64>(y).chunks_exact_mut(n_out),
+        x.chunks_exact(n_in),
+    ) {
+        for (i, y) in y.iter_mut().enumerate() {
+            *y = zip(x, &w[i * n_in..][..n_in])
+                .map(|(x, w)| x * w)
+                .sum::<f64>()
+                + b.map_or(0., |b| b[i])
+        }
+    }
+}
+
+pub(super) fn linear_backward(
+    dx: &Tensor,
+    dw: &Tensor,
+    db: Option<&Tensor>,
+    dy: &Tensor,
+    x: &Tensor,
+    w: &Tensor,
+) {
+    let &[n_out, n_in] = &*w.shape() else {
+        unreachable!()
+    };
+    let (dw, w) = (data_mut::<f64>(dw), data::<f64>(w));
+    let mut db = db.map(data_mut::<f64>);
+    for (dx, dy, x) in izip!(
+        data_mut::<f64>(dx).chunks_exact_mut(n_in),
+        data::<f64>(dy).chunks_exact(n_out),
+        data::<f64>(x).chunks_exact(n_in),
+    ) {
+        for (i, dy) in dy.iter().enumerate() {
+            for j in 0..n_in {
+                dx[j] += dy * w[i * n_in + j];
+                dw[i * n_in + j] += dy * x[j]
+            }
+            if let Some(db) = &mut db {
+                db[i] += dy
+            }
+        }
+    }
+}
+
+/// `h = silu(gate) * up`
+pub(super) fn swiglu(h: &Tensor, gate: &Tensor, up: &Tensor) {
+    for (h, g, u) in izip!(data_mut::<f64>(h), data::<f64>(gate), data::<f64>(up)) {
+        *h = g / (1. + (-g).exp()) * u
+    }
+}
+
+pub(super) fn swiglu_backward(
+    dgate: &Tensor,
+    dup: &Tensor,
+    dh: &Tensor,
+    gate: &Tensor,
+    up: &Tensor,
+) {
+    for (dg, du, dh, g, u) in izip!(
+        data_mut::<f64>(dgate),
+        data_mut::<f64>(dup),
+        data::<f64>(dh),
+        data::<f64>(gate),
+        data::<f64>(up),
+    ) {
+        let sigmoid = 1. / (1. + (-g).exp());
+        *dg += dh * u * sigmoid * (1. + g * (1. - sigmoid));
+        *du += dh * g * sigmoid
+    }
+}
+
+/// 原地旋转形状为 `[batch, n_seq, nh, dh]` 的张量，`sign` 为 -1 时即反向。
+pub(super) fn rope(x: &Tensor, theta: f64, sign: f64) {
+    let &[_, n_seq, nh, dh] = &*x.shape() else {
+        unreachable!()
+    };
+    let half = dh / 2;
+    for (i, x) in data_mut::<f64>(x).chunks_exact_mut(dh).enumerate() {
+        let t = (i / nh % n_seq) as f64;
+        let (x0, x1) = x.split_at_mut(half);
+        for (j, (x0, x1)) in zip(x0, x1).enumerate() {
+            let (sin, cos) = (sign * t * theta.powf(-2. * j as f64 / dh as f64)).sin_cos();
+            (*x0, *x1) = (*x0 * cos - *x1 * sin, *x0 * sin + *x1 * cos)
+        }
+    }
+}
+
+/// 最大误差。
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Error {
+    /// 最大绝对误差。
+    pub abs: f64,
+    /// 最大相对误差，参考值为 0 的元素不计入。
+    pub rel: f64,
+}
+
+/// 以 f32 和 f64 分别执行 `run`，返回各输出相对 f64 结果的最大误差。
+///
+/// `inputs` 中的 f32 张量复制为 f64 张量后再执行一次，其他类型（例如词号）原样传入；
+/// `run` 按输入的数据类型分配输出并调用算子。
+pub fn compare(inputs: &[Tensor], run: impl Fn(&[Tensor]) -> Vec<Tensor>) -> Vec<Error> {
+    let inputs_ = inputs
+        .iter()
+        .map(|t| match t.dt() {
+            types::F32 => {
+                let ans = crate::Tensor::new(types::F64, &t.shape())
+                    .map(Blob::new)
+                    .map(RwRc::new);
+                let (src, dst) = (t.cloned(), ans.cloned());
+                for (dst, &src) in zip(data_mut::<f64>(&dst), data::<f32>(&src)) {
+                    *dst = src as _
+                }
+                ans
+            }
+            _ => t.cloned(),
+        })
+        .collect::<Vec<_>>();
+
+    let values = |t: &Tensor| {
+        let t = t.cloned();
+        match t.dt() {
+            types::F32 => data::<f32>(&t).iter().map(|&x| x as f64).collect(),
+            types::F64 => data::<f64>(&t).to_vec(),
+            dt => panic!("unsupported output type {dt}"),
+        }
+    };
+
+    let outputs = run(inputs);
+    let outputs_ = run(&inputs_);
+    assert_eq!(outputs.len(), outputs_.len());
+    zip(outputs, outputs_)
+        .map(|(y, y_)| {
+            let (y, y_): (Vec<_>, Vec<_>) = (values(&y), values(&y_));
+            zip(y, y_).fold(Error::default(), |Error { abs, rel }, (a, b)| {
+                let err = (a - b).abs();
+                Error {
+                    abs: abs.max(err),
+                    rel: if b == 0. { rel } else { rel.max(err / b.abs()) },
+                }
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_compare() {
+    use super::{attention, loss};
+
+    let [b, t, nh, d] = [2, 5, 2, 8];
+    let n = 7;
+    let tensor = |dt, shape: &[usize]| {
+        crate::Tensor::new(dt, shape)
+            .map(Blob::new_zeroed)
+            .map(RwRc::new)
+    };
+    let x = tensor(types::F32, &[b, t, 3 * d]);
+    for (i, x) in data_mut::<f32>(&x.cloned()).iter_mut().enumerate() {
+        *x = ((i * 37 % 23) as f32 - 11.) / 8.
+    }
+    let targets = tensor(types::U16, &[b, t]);
+    for (i, t) in data_mut::<u16>(&targets.cloned()).iter_mut().enumerate() {
+        *t = (i * 5 % n) as _
+    }
+
+    // 注意力输出的前 n 个通道作为 logits，覆盖注意力、softmax 和交叉熵的前向与反向
+    let errors = compare(&[x, targets], |inputs| {
+        let [x, targets] = inputs else { unreachable!() };
+        let dt = x.dt();
+        let y = tensor(dt, &[b, t, d]);
+        let preatt = tensor(dt, &[b, nh, t, t]);
+        let att = tensor(dt, &[b, nh, t, t]);
+        attention::forward(&y, &preatt, &att, x, None, &Default::default());
+
+        let probs = tensor(dt, &[b, t, d]);
+        let losses = tensor(dt, &[b, t]);
+        loss::softmax(&probs, &y, n, false);
+        loss::crossentropy(&losses, &probs, targets);
+
+        let dlosses = tensor(dt, &[b, t]);
+        match dt {
+            types::F32 => data_mut::<f32>(&dlosses.cloned()).fill(1.),
+            _ => data_mut::<f64>(&dlosses.cloned()).fill(1.),
+        }
+        let dy = tensor(dt, &[b, t, d]);
+        loss::backward(&dy, &dlosses, &probs, targets);
+        let dx = tensor(dt, &[b, t, 3 * d]);
+        let dpreatt = tensor(dt, &[b, nh, t, t]);
+        let datt = tensor(dt, &[b, nh, t, t]);
+        attention::backward(
+            &dx,
+            &dpreatt,
+            &datt,
+            &dy,
+            x,
+            None,
+            &att,
+            &Default::default(),
+        );
+        vec![y, losses, dx]
+    });
+    assert_eq!(errors.len(), 3);
+    for Error { abs, .. } in errors {
+        assert!(abs < 1e-5, "{abs}")
+    }
+}
+
+#[test]
+fn test_compare_ops() {
+    use super::{
+        add::add,
+        gelu::{self, Approx},
+        layer_norm, linear, rms_norm, rope, swiglu,
+    };
+
+    let [n, d, d_out] = [6, 8, 5];
+    let tensor = |dt, shape: &[usize]| {
+        crate::Tensor::new(dt, shape)
+            .map(Blob::new_zeroed)
+            .map(RwRc::new)
+    };
+    let input = |shape: &[usize], seed: usize| {
+        let t = tensor(types::F32, shape);
+        for (i, x) in data_mut::<f32>(&t.cloned()).iter_mut().enumerate() {
+            *x = (((i + seed) * 37 % 23) as f32 - 11.) / 8.
+        }
+        t
+    };
+    let inputs = [
+        input(&[n, d], 0),
+        input(&[d], 1),
+        input(&[d], 2),
+        input(&[d_out, d], 3),
+        input(&[d_out], 4),
+        input(&[n, d_out], 5),
+    ];
+
+    // 依次经过 LayerNorm、RMSNorm、GELU、SwiGLU、RoPE 和线性层，再反向传回
+    let errors = compare(&inputs, |inputs| {
+        let [x, w, b, wl, bl, dy] = inputs else {
+            unreachable!()
+        };
+        let dt = x.dt();
+        let heads = |t: &Tensor| t.cloned().tile(0, &[1, n]).tile(2, &[2, d / 2]);
+
+        let [h1, h2, h3, h4] = [(); 4].map(|_| tensor(dt, &[n, d]));
+        let [mean, rstd1, rstd2] = [(); 3].map(|_| tensor(dt, &[n]));
+        layer_norm::forward::layer_norm(&h1, &mean, &rstd1, x, w, b);
+        rms_norm::forward::rms_norm(&h2, &rstd2, &h1, w, 1e-5);
+        gelu::forward::gelu(&h3, &h2, Approx::Exact);
+        swiglu::forward(&h4, &h3, &h2);
+        rope::forward(&heads(&h4), 1e4);
+        let y = tensor(dt, &[n, d_out]);
+        linear::forward(&y, &h4, wl, Some(bl), false);
+
+        let [dh1, dh2, dh3, dh4, dup, dx] = [(); 6].map(|_| tensor(dt, &[n, d]));
+        let [dw, db, dw2] = [(); 3].map(|_| tensor(dt, &[d]));
+        let (dwl, dbl) = (tensor(dt, &[d_out, d]), tensor(dt, &[d_out]));
+        linear::backward(&dh4, &dwl, Some(&dbl), dy, &h4, wl);
+        rope::backward(&heads(&dh4), 1e4);
+        swiglu::backward(&dh3, &dup, &dh4, &h3, &h2);
+        gelu::backward::gelu(&dh2, &h2, &dh3, Approx::Exact);
+        add(&dh2, &dup);
+        rms_norm::backward::rms_norm(&dh1, &dw2, &dh2, &h1, w, &rstd2);
+        layer_norm::backward::layer_norm(&dx, &dw, &db, &dh1, x, w, &mean, &rstd1);
+        vec![y, dx, dw, db, dw2, dwl, dbl]
+    });
+    assert_eq!(errors.len(), 7);
+    for (i, Error { abs, .. }) in errors.into_iter().enumerate() {
+        assert!(abs < 1e-4, "output {i}: {abs}")
+    }
+}
+
+#[test]
+fn test_compensated() {
+    use super::{
+        attention::{self, Paged, decode},
+        loss,
+    };
+    use crate::dist::Single;
+
+    let tensor = |dt, shape: &[usize]| {
+        crate::Tensor::new(dt, shape)
+            .map(Blob::new_zeroed)
+            .map(RwRc::new)
+    };
+
+    // 大词表，概率分散，逐个累加的指数和误差明显
+    let n = 1 << 17;
+    let x = tensor(types::F32, &[1, 2, n]);
+    for (i, x) in data_mut::<f32>(&x.cloned()).iter_mut().enumerate() {
+        *x = ((i * 37 % 101) as f32 - 50.) / 16.
+    }
+    let targets = tensor(types::U16, &[1, 2]);
+    data_mut::<u16>(&targets.cloned()).copy_from_slice(&[3, 500]);
+    let loss_error = |compensated| {
+        let errors = compare(&[x.clone(), targets.clone()], |inputs| {
+            let [x, targets] = inputs else { unreachable!() };
+            let dt = x.dt();
+            let probs = tensor(dt, &[1, 2, n]);
+            let losses = tensor(dt, &[1, 2]);
+            if dt == types::F64 {
+                loss::softmax(&probs, x, n, false);
+                loss::crossentropy(&losses, &probs, targets)
+            } else {
+                loss::vocab_parallel_crossentropy(
+                    &losses,
+                    &probs,
+                    x,
+                    targets,
+                    0,
+                    n,
+                    &Single,
+                    compensated,
+                )
+            }
+            vec![losses, probs]
+        });
+        errors[0].abs
+    };
+    let (plain, compensated) = (loss_error(false), loss_error(true));
+    assert!(compensated < 1e-6, "{compensated}");
+    assert!(compensated < plain, "{compensated} vs {plain}");
+
+    // 长序列的解码，K、V 直接取自 qkv，整个序列在一个块中
+    let [n_seq, nh, dh] = [1024, 2, 4];
+    let d = nh * dh;
+    let x = tensor(types::F32, &[1, n_seq, 3 * d]);
+    for (i, x) in data_mut::<f32>(&x.cloned()).iter_mut().enumerate() {
+        *x = ((i * 7 % 13) as f32 - 6.) / 5.
+    }
+    let errors = compare(&[x], |inputs| {
+        let [x] = inputs else { unreachable!() };
+        let dt = x.dt();
+        let y = tensor(dt, &[1, nh, dh]);
+        if dt == types::F64 {
+            // 完整的因果注意力，取最后一个位置
+            let y_ = tensor(dt, &[1, n_seq, d]);
+            let att_shape = [1, nh, n_seq, n_seq];
+            let (preatt, att) = (tensor(dt, &att_shape), tensor(dt, &att_shape));
+            attention::forward(&y_, &preatt, &att, x, None, &Default::default());
+            data_mut::<f64>(&y.cloned())
+                .copy_from_slice(&data::<f64>(&y_.cloned())[(n_seq - 1) * d..])
+        } else {
+            let qkv = x.cloned().index(&[0]);
+            let q = qkv
+                .clone()
+                .slice(0, n_seq - 1, 1)
+                .slice(1, 0, d)
+                .tile(1, &[nh, dh]);
+            let k = qkv
+                .clone()
+                .slice(1, d, d)
+                .tile(1, &[nh, dh])
+                .tile(0, &[1, n_seq]);
+            let v = qkv
+                .slice(1, 2 * d, d)
+                .tile(1, &[nh, dh])
+                .tile(0, &[1, n_seq]);
+            let kv = Paged {
+                k: &k,
+                v: &v,
+                scales: None,
+                table: &[0],
+                len: n_seq,
+            };
+            decode(&y, &q, &[kv], None, true)
+        }
+        vec![y]
+    });
+    assert!(errors[0].abs < 1e-6, "{}", errors[0].abs)
+}
@@ -0,0 +1,214 @@
+use crate::{
+    macros::*,
+    op::{Tensor, unique},
+};
+use digit_layout::types;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+pub mod forward {
+
+    use super::*;
+
+    /// `y = x * rstd * scalar`，`rstd = (mean(x²) + epsilon)^-0.5` 保存用于反向。
+    pub(crate) fn rms_norm(y: &Tensor, rstd: &Tensor, x: &Tensor, scalar: &Tensor, epsilon: f32) {
+        clone_tensor!(y rstd x scalar);
+
+        let dt = unique(&[y.dt(), rstd.dt(), x.dt(), scalar.dt()]).unwrap();
+        #[cfg(feature = "reference")]
+        if dt == types::F64 {
+            return crate::op::reference::rms_norm(&y, &rstd, &x, &scalar, epsilon as _);
+        }
+        assert_eq!(dt, types::F32);
+
+        dims!([n_0, d_0] = y);
+        dims!([n_1, d_1] = x);
+        dims!([n_2] = rstd);
+        dims!([d_2] = scalar);
+
+        let n = unique(&[n_0, n_1, n_2]).unwrap();
+        let d = unique(&[d_0, d_1, d_2]).unwrap();
+
+        strides!([nsy, dsy] = y);
+        strides!([nsx, dsx] = x);
+        strides!([nsr] = rstd);
+        strides!([sw] = scalar);
+
+        let y = y.as_ref().map(|b| &mut **b.write()).mut_ptr::<u8>() as usize;
+        let x = x.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+        let rstd = rstd.as_ref().map(|b| &mut **b.write()).mut_ptr::<u8>() as usize;
+        let scalar = scalar.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+
+        // 处理每个 batch 序列
+        (0..n as isize).into_par_iter().for_each(|i| {
+            let x = |j: isize| unsafe {
+                *(x as *const u8)
+                    .byte_offset(i * nsx + j * dsx)
+                    .cast::<f32>()
+            };
+            let w = |j: isize| unsafe { *(scalar as *const u8).byte_offset(j * sw).cast::<f32>() };
+
+            let sum2 = (0..d as isize).map(|j| x(j) * x(j)).sum::<f32>();
+            let rstd_val = (sum2 / d as f32 + epsilon).powf(-0.5);
+            unsafe { *(rstd as *mut u8).byte_offset(i * nsr).cast::<f32>() = rstd_val }
+
+            for j in 0..d as isize {
+                let y = unsafe { (y as *mut u8).byte_offset(i * nsy + j * dsy).cast::<f32>() };
+                unsafe { *y = x(j) * rstd_val * w(j) }
+            }
+        })
+    }
+}
+
+pub mod backward {
+    use super::*;
+
+    pub(crate) fn rms_norm(
+        dx: &Tensor,
+        dw: &Tensor,
+        dy: &Tensor,
+        x: &Tensor,
+        w: &Tensor,
+        rstd: &Tensor,
+    ) {
+        clone_tensor!(dx dw dy x w rstd);
+
+        let dt = unique(&[dx.dt(), dw.dt(), dy.dt(), x.dt(), w.dt(), rstd.dt()]).unwrap();
+        #[cfg(feature = "reference")]
+        if dt == types::F64 {
+            return crate::op::reference::rms_norm_backward(&dx, &dw, &dy, &x, &w, &rstd);
+        }
+        assert_eq!(dt, types::F32);
+
+        dims!([n_0, d_0] = dx);
+        dims!([n_1, d_1] = dy);
+        dims!([n_2, d_2] = x);
+        dims!([d_3] = dw);
+        dims!([d_4] = w);
+        dims!([n_3] = rstd);
+
+        let n = unique(&[n_0, n_1, n_2, n_3]).unwrap();
+        let d = unique(&[d_0, d_1, d_2, d_3, d_4]).unwrap();
+
+        strides!([nsdx, dsdx] = dx);
+        strides!([nsdy, dsdy] = dy);
+        strides!([nsx, dsx] = x);
+        strides!([sdw] = dw);
+        strides!([sw] = w);
+        strides!([nsr] = rstd);
+
+        let dx = dx.as_ref().map(|b| &mut **b.write()).mut_ptr::<u8>() as usize;
+        let dy = dy.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+        let x = x.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+        let dw = dw.as_ref().map(|b| &mut **b.write()).mut_ptr::<u8>() as usize;
+        let w = w.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+        let rstd = rstd.as_ref().map(|b| &**b.read()).ptr::<u8>() as usize;
+
+        let dy = |i: isize, j: isize| unsafe {
+            *(dy as *const u8)
+                .byte_offset(i * nsdy + j * dsdy)
+                .cast::<f32>()
+        };
+        let x = |i: isize, j: isize| unsafe {
+            *(x as *const u8)
+                .byte_offset(i * nsx + j * dsx)
+                .cast::<f32>()
+        };
+        let w = |j: isize| unsafe { *(w as *const u8).byte_offset(j * sw).cast::<f32>() };
+        let rstd = |i: isize| unsafe { *(rstd as *const u8).byte_offset(i * nsr).cast::<f32>() };
+
+        // 各行的 dx 相互独立，并行计算
+        (0..n as isize).into_par_iter().for_each(|i| {
+            let rstd = rstd(i);
+            let dnorm_norm_mean = (0..d as isize)
+                .map(|j| w(j) * dy(i, j) * x(i, j) * rstd)
+                .sum::<f32>()
+                / d as f32;
+            for j in 0..d as isize {
+                let norm = x(i, j) * rstd;
+                let dx = unsafe {
+                    (dx as *mut u8)
+                        .byte_offset(i * nsdx + j * dsdx)
+                        .cast::<f32>()
+                };
+                unsafe { *dx += rstd * (w(j) * dy(i, j) - norm * dnorm_norm_mean) }
+            }
+        });
+
+        // dw 在各行间累加，按列并行
+        (0..d as isize).into_par_iter().for_each(|j| {
+            let sum = (0..n as isize)
+                .map(|i| x(i, j) * rstd(i) * dy(i, j))
+                .sum::<f32>();
+            unsafe { *(dw as *mut u8).byte_offset(j * sdw).cast::<f32>() += sum }
+        })
+    }
+}
+
+#[test]
+fn test_rms_norm_grad() {
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    let [n, d] = [3, 5];
+    let tensor = |shape: &[usize], data: &[f32]| {
+        let mut t = crate::Tensor::new(types::F32, shape)
+            .map(Blob::new_zeroed)
+            .map(RwRc::new);
+        let buf = t.get_mut().write();
+        let ([], buf, []) = (unsafe { buf.align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        buf[..data.len()].copy_from_slice(data);
+        t.get().release();
+        t
+    };
+    let values = |t: &Tensor| {
+        let t = t.cloned();
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+    let xs = (0..n * d)
+        .map(|i| ((i * 7 % 11) as f32 - 5.) / 4.)
+        .collect::<Vec<_>>();
+    let ws = (0..d).map(|i| 0.5 + i as f32 / 4.).collect::<Vec<_>>();
+    let dys = (0..n * d)
+        .map(|i| ((i * 5 % 7) as f32 - 3.) / 2.)
+        .collect::<Vec<_>>();
+
+    // loss = sum(y * dy)
+    let loss = |xs: &[f32], ws: &[f32]| {
+        let y = tensor(&[n, d], &[]);
+        let rstd = tensor(&[n], &[]);
+        forward::rms_norm(&y, &rstd, &tensor(&[n, d], xs), &tensor(&[d], ws), 1e-5);
+        let y = values(&y);
+        y.iter().zip(&dys).map(|(y, dy)| y * dy).sum::<f32>()
+    };
+
+    let x = tensor(&[n, d], &xs);
+    let w = tensor(&[d], &ws);
+    let (y, rstd) = (tensor(&[n, d], &[]), tensor(&[n], &[]));
+    forward::rms_norm(&y, &rstd, &x, &w, 1e-5);
+    let (dx, dw) = (tensor(&[n, d], &[]), tensor(&[d], &[]));
+    backward::rms_norm(&dx, &dw, &tensor(&[n, d], &dys), &x, &w, &rstd);
+
+    let h = 1e-2;
+    let grad = |f: &dyn Fn(f32) -> f32| (f(h) - f(-h)) / (2. * h);
+    for (i, dx) in values(&dx).into_iter().enumerate() {
+        let numeric = grad(&|h| {
+            let mut xs = xs.clone();
+            xs[i] += h;
+            loss(&xs, &ws)
+        });
+        assert!((dx - numeric).abs() < 1e-2, "dx[{i}]: {dx} vs {numeric}")
+    }
+    for (j, dw) in values(&dw).into_iter().enumerate() {
+        let numeric = grad(&|h| {
+            let mut ws = ws.clone();
+            ws[j] += h;
+            loss(&xs, &ws)
+        });
+        assert!((dw - numeric).abs() < 1e-2, "dw[{j}]: {dw} vs {numeric}")
+    }
+}
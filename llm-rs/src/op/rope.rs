@@ -0,0 +1,104 @@
+use super::Tensor;
+use crate::macros::*;
+use digit_layout::types;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+/// 旋转位置编码，原地旋转形状为 `[batch, n_seq, nh, dh]` 的 Q 或 K，可以是任意步长的视图。
+///
+/// 每个头的第 `i` 维和第 `i + dh / 2` 维组成一对（GPT-NeoX、HF LLaMA 的排列），
+/// 第 `t` 个位置转过 `t * theta^(-2i / dh)`。
+/// 只旋转每个头的一部分维度时，传入用 `slice` 截取的视图。
+pub fn forward(x: &Tensor, theta: f32) {
+    rotate(x, theta, 1.)
+}
+
+/// 旋转是正交变换，反向即按相反的角度旋转梯度。
+pub fn backward(dx: &Tensor, theta: f32) {
+    rotate(dx, theta, -1.)
+}
+
+fn rotate(x: &Tensor, theta: f32, sign: f64) {
+    clone_tensor!(x);
+    #[cfg(feature = "reference")]
+    if x.dt() == types::F64 {
+        return super::reference::rope(&x, theta as _, sign);
+    }
+    assert_eq!(x.dt(), types::F32);
+
+    dims!([batch_size, n_seq, nh, dh] = x);
+    strides!([sb, st, sh, sd] = x);
+    assert_eq!(dh % 2, 0);
+    let half = dh / 2;
+
+    // 各频率的转角只与位置有关，先算出每个位置的正弦和余弦
+    let freqs = (0..half)
+        .map(|i| (theta as f64).powf(-2. * i as f64 / dh as f64))
+        .collect::<Vec<_>>();
+    let sin_cos = (0..n_seq)
+        .flat_map(|t| freqs.iter().map(move |f| (sign * t as f64 * f).sin_cos()))
+        .map(|(sin, cos)| (sin as f32, cos as f32))
+        .collect::<Vec<_>>();
+
+    let x = x.as_ref().map(|b| &mut **b.write()).mut_ptr::<u8>() as usize;
+    (0..batch_size * n_seq * nh).into_par_iter().for_each(|i| {
+        let (b, t, h) = (i / (n_seq * nh), i / nh % n_seq, i % nh);
+        let base = b as isize * sb + t as isize * st + h as isize * sh;
+        let at = |j: usize| unsafe {
+            (x as *mut u8)
+                .byte_offset(base + j as isize * sd)
+                .cast::<f32>()
+        };
+        for (j, &(sin, cos)) in sin_cos[t * half..][..half].iter().enumerate() {
+            let (p0, p1) = (at(j), at(j + half));
+            unsafe {
+                let (x0, x1) = (*p0, *p1);
+                *p0 = x0 * cos - x1 * sin;
+                *p1 = x0 * sin + x1 * cos
+            }
+        }
+    })
+}
+
+#[test]
+fn test_rope() {
+    use crate::Blob;
+    use rw_rc::RwRc;
+    use std::iter::zip;
+
+    let [n_seq, nh, dh] = [6, 2, 8];
+    // 每个位置的 Q 相同，旋转后的内积只取决于相对位置
+    let data = (0..n_seq)
+        .flat_map(|_| (0..nh * dh).map(|i| (i as f32 * 0.37).sin()))
+        .collect::<Vec<_>>();
+    let x =
+        crate::Tensor::new(types::F32, &[1, n_seq, nh, dh]).map(|_| RwRc::new(Blob::from(&*data)));
+    let values = |x: &Tensor| {
+        let ([], buf, []) = (unsafe { x.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let ans = buf.to_vec();
+        x.get().release();
+        ans
+    };
+    let head = |buf: &[f32], t: usize, h: usize| buf[(t * nh + h) * dh..][..dh].to_vec();
+    let dot = |a: &[f32], b: &[f32]| zip(a, b).map(|(a, b)| a * b).sum::<f32>();
+
+    forward(&x, 1e4);
+    let y = values(&x);
+    for h in 0..nh {
+        let q = head(&data, 0, h);
+        for t in 0..n_seq {
+            let y_ = head(&y, t, h);
+            assert!((dot(&y_, &y_) - dot(&q, &q)).abs() < 1e-4);
+            for s in 0..t {
+                let expected = dot(&head(&y, t - s, h), &head(&y, 0, h));
+                assert!((dot(&y_, &head(&y, s, h)) - expected).abs() < 1e-4)
+            }
+        }
+    }
+
+    backward(&x, 1e4);
+    for (a, b) in zip(values(&x), &data) {
+        assert!((a - b).abs() < 1e-5)
+    }
+}
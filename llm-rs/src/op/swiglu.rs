@@ -0,0 +1,110 @@
+use super::{Tensor, unique};
+use crate::macros::*;
+use digit_layout::{DigitLayout, types};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+
+fn silu(x: f32) -> f32 {
+    x / (1. + (-x).exp())
+}
+
+/// 张量形状相同且连续，返回数据类型和元素数。
+fn check(tensors: &[&Tensor]) -> (DigitLayout, usize) {
+    let dt = unique(&tensors.iter().map(|t| t.dt()).collect::<Vec<_>>()).unwrap();
+    let shape = tensors[0].shape();
+    for t in tensors {
+        assert_eq!(t.shape(), shape);
+        assert!(t.is_contiguous())
+    }
+    (dt, shape.iter().product())
+}
+
+/// `h = silu(gate) * up`
+pub fn forward(h: &Tensor, gate: &Tensor, up: &Tensor) {
+    clone_tensor!(h gate up);
+    let (dt, n) = check(&[&h, &gate, &up]);
+    #[cfg(feature = "reference")]
+    if dt == types::F64 {
+        return super::reference::swiglu(&h, &gate, &up);
+    }
+    assert_eq!(dt, types::F32);
+
+    let h = unsafe { from_raw_parts_mut(h.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>(), n) };
+    let gate = unsafe { from_raw_parts(gate.as_ref().map(|b| &**b.read()).ptr::<f32>(), n) };
+    let up = unsafe { from_raw_parts(up.as_ref().map(|b| &**b.read()).ptr::<f32>(), n) };
+    (h, gate, up)
+        .into_par_iter()
+        .for_each(|(h, &g, &u)| *h = silu(g) * u)
+}
+
+/// `dgate += dh * up * silu'(gate)`，`dup += dh * silu(gate)`
+pub fn backward(dgate: &Tensor, dup: &Tensor, dh: &Tensor, gate: &Tensor, up: &Tensor) {
+    clone_tensor!(dgate dup dh gate up);
+    let (dt, n) = check(&[&dgate, &dup, &dh, &gate, &up]);
+    #[cfg(feature = "reference")]
+    if dt == types::F64 {
+        return super::reference::swiglu_backward(&dgate, &dup, &dh, &gate, &up);
+    }
+    assert_eq!(dt, types::F32);
+
+    let dgate =
+        unsafe { from_raw_parts_mut(dgate.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>(), n) };
+    let dup =
+        unsafe { from_raw_parts_mut(dup.as_ref().map(|b| &mut **b.write()).mut_ptr::<f32>(), n) };
+    let dh = unsafe { from_raw_parts(dh.as_ref().map(|b| &**b.read()).ptr::<f32>(), n) };
+    let gate = unsafe { from_raw_parts(gate.as_ref().map(|b| &**b.read()).ptr::<f32>(), n) };
+    let up = unsafe { from_raw_parts(up.as_ref().map(|b| &**b.read()).ptr::<f32>(), n) };
+    (dgate, dup, dh, gate)
+        .into_par_iter()
+        .zip(up)
+        .for_each(|((dg, du, &dh, &g), &u)| {
+            let sigmoid = 1. / (1. + (-g).exp());
+            // silu'(g) = σ(g) (1 + g (1 - σ(g)))
+            *dg += dh * u * sigmoid * (1. + g * (1. - sigmoid));
+            *du += dh * g * sigmoid
+        })
+}
+
+#[test]
+fn test_swiglu() {
+    use crate::Blob;
+    use rw_rc::RwRc;
+
+    let tensor = |data: &[f32]| {
+        let mut t = crate::Tensor::new(types::F32, &[1, data.len()])
+            .map(Blob::new_zeroed)
+            .map(RwRc::new);
+        let buf = t.get_mut().write();
+        let ([], buf, []) = (unsafe { buf.align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        buf.copy_from_slice(data);
+        t.get().release();
+        t
+    };
+    let values = |t: &Tensor| {
+        let t = t.cloned();
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+
+    let gs = [-3f32, -0.5, 0., 0.7, 2.];
+    let us = [0.5f32, -1., 2., 1.5, -0.25];
+    let h = tensor(&[0.; 5]);
+    let (gate, up) = (tensor(&gs), tensor(&us));
+    forward(&h, &gate, &up);
+    for ((h, g), u) in values(&h).into_iter().zip(gs).zip(us) {
+        assert!((h - silu(g) * u).abs() < 1e-6)
+    }
+
+    let (dgate, dup) = (tensor(&[0.; 5]), tensor(&[0.; 5]));
+    backward(&dgate, &dup, &tensor(&[1.; 5]), &gate, &up);
+    let eps = 1e-3;
+    for (i, (dg, du)) in values(&dgate).into_iter().zip(values(&dup)).enumerate() {
+        let numeric = (silu(gs[i] + eps) - silu(gs[i] - eps)) / (2. * eps) * us[i];
+        assert!((dg - numeric).abs() < 1e-3, "dgate[{i}]: {dg} vs {numeric}");
+        assert!((du - silu(gs[i])).abs() < 1e-6)
+    }
+}
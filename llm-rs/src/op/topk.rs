@@ -0,0 +1,99 @@
+use super::Tensor;
+use crate::macros::*;
+use digit_layout::types;
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::{ParallelSlice, ParallelSliceMut};
+use std::cmp::Ordering;
+
+/// 最大值的位置，有多个最大值时取第一个。
+pub fn argmax(x: &[f32]) -> usize {
+    let mut ans = 0;
+    for (i, v) in x.iter().enumerate().skip(1) {
+        if v.total_cmp(&x[ans]).is_gt() {
+            ans = i
+        }
+    }
+    ans
+}
+
+/// 最大的 `k` 个值及其位置，按值从大到小排列，值相同时位置小的在前。
+///
+/// 只保留不超过 `2k` 个候选，满了就用快速选择丢掉较小的一半，
+/// 之后小于等于第 `k` 大候选的值直接跳过，总体是线性时间，不对整个词表排序。
+pub fn top_k(x: &[f32], k: usize) -> Vec<(usize, f32)> {
+    let k = k.min(x.len());
+    if k == 0 {
+        return vec![];
+    }
+
+    let mut buf = Vec::with_capacity(2 * k);
+    let mut threshold = None;
+    for (i, &v) in x.iter().enumerate() {
+        if threshold.is_some_and(|t: f32| v.total_cmp(&t).is_le()) {
+            continue;
+        }
+        buf.push((i, v));
+        if buf.len() == 2 * k {
+            buf.select_nth_unstable_by(k - 1, desc);
+            buf.truncate(k);
+            threshold = Some(buf[k - 1].1)
+        }
+    }
+    if buf.len() > k {
+        buf.select_nth_unstable_by(k - 1, desc);
+        buf.truncate(k)
+    }
+    buf.sort_unstable_by(desc);
+    buf
+}
+
+fn desc(a: &(usize, f32), b: &(usize, f32)) -> Ordering {
+    b.1.total_cmp(&a.1).then(a.0.cmp(&b.0))
+}
+
+/// 对 `[n, n_voc]` 的 logits 逐行取 [`top_k`]，写入 `[n, k]` 的 `values` 和 `indices`（u32）。
+pub fn forward(values: &Tensor, indices: &Tensor, logits: &Tensor) {
+    clone_tensor!(values indices logits);
+    assert_eq!(logits.dt(), types::F32);
+    assert_eq!(values.dt(), types::F32);
+    assert_eq!(indices.dt(), types::U32);
+    assert!(logits.is_contiguous() && values.is_contiguous() && indices.is_contiguous());
+
+    dims!([n, n_voc] = logits);
+    dims!([n_, k] = values);
+    assert_eq!(n_, n);
+    assert_eq!(indices.shape(), values.shape());
+
+    let logits = logits.as_ref().map(|b| &**b.read()).merge(0, 2);
+    let mut values = values.as_ref().map(|b| &mut **b.write()).merge(0, 2);
+    let mut indices = indices.as_ref().map(|b| &mut **b.write()).merge(0, 2);
+    logits
+        .vector::<f32>()
+        .par_chunks(n_voc)
+        .zip(values.vector_mut::<f32>().par_chunks_mut(k))
+        .zip(indices.vector_mut::<u32>().par_chunks_mut(k))
+        .for_each(|((x, values), indices)| {
+            for (j, (i, v)) in top_k(x, k).into_iter().enumerate() {
+                values[j] = v;
+                indices[j] = i as _
+            }
+        })
+}
+
+#[test]
+fn test_top_k() {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::seed_from_u64(42);
+    // 取值很少，有大量相同的值
+    let x = (0..1000)
+        .map(|_| rng.random_range(0..50) as f32)
+        .collect::<Vec<_>>();
+    let mut sorted = x.iter().copied().enumerate().collect::<Vec<_>>();
+    sorted.sort_by(desc);
+
+    assert_eq!(argmax(&x), sorted[0].0);
+    for k in [0, 1, 3, 17, 999, 1000, 2000] {
+        assert_eq!(top_k(&x, k), sorted[..k.min(x.len())])
+    }
+}
@@ -6,6 +6,31 @@ use std::{collections::HashMap, rc::Rc};
 
 pub trait Optimizer {
     fn update(&mut self, weight: Rc<Tensor<RwRc<Blob>>>, gradient: Rc<Tensor<RwRc<Blob>>>);
+
+    /// 稀疏梯度：`gradient` 的第 `i` 行是 `weight` 第 `rows[i]` 行的梯度，其余行梯度为 0。
+    ///
+    /// 默认展开为完整的梯度再更新。
+    fn update_sparse(
+        &mut self,
+        weight: Rc<Tensor<RwRc<Blob>>>,
+        gradient: Rc<Tensor<RwRc<Blob>>>,
+        rows: &[usize],
+    ) {
+        let dense = Tensor::contiguous_of(&*weight)
+            .map(Blob::new_zeroed)
+            .map(RwRc::new);
+        {
+            let gradient = gradient.cloned();
+            let src = gradient.get().read();
+            let dst = dense.get().write();
+            let row = dst.len() / weight.shape()[0];
+            for (i, &r) in rows.iter().enumerate() {
+                dst[r * row..][..row].copy_from_slice(&src[i * row..][..row])
+            }
+            dense.get().release()
+        }
+        self.update(weight, dense.share())
+    }
 }
 
 pub struct AdamW {
@@ -15,12 +40,15 @@ pub struct AdamW {
     beta2: f32,
     epsilon: f32,
     weight_decay: f32,
+    lazy: bool,
     t: i32,
 }
 
 struct State {
     m: Blob,
     v: Blob,
+    /// 惰性更新时每行的更新次数，用于逐行的偏差修正。
+    steps: Vec<i32>,
 }
 
 impl Optimizer for AdamW {
@@ -33,14 +61,16 @@ impl Optimizer for AdamW {
             epsilon,
             weight_decay,
             t,
+            ..
         } = self;
-        let State { m, v } = weights
+        let State { m, v, .. } = weights
             .entry(HashWeak(Rc::downgrade(&weight)))
             .or_insert_with(|| {
                 let len = Tensor::contiguous_of(&*weight).take();
                 State {
                     m: Blob::new_zeroed(len),
                     v: Blob::new_zeroed(len),
+                    steps: Vec::new(),
                 }
             });
 
@@ -71,6 +101,95 @@ impl Optimizer for AdamW {
             *w -= learning_rate * (*m * hat1 / ((*v * hat2).sqrt() + epsilon) + weight_decay * *w)
         }
     }
+
+    /// 默认与完整梯度的结果相同：没有梯度的行按梯度为 0 更新动量，但不需要分配完整的梯度。
+    ///
+    /// 惰性模式（见 [`AdamW::set_lazy`]）只更新有梯度的行。
+    fn update_sparse(
+        &mut self,
+        weight: Rc<Tensor<RwRc<Blob>>>,
+        gradient: Rc<Tensor<RwRc<Blob>>>,
+        rows: &[usize],
+    ) {
+        let &mut Self {
+            ref mut weights,
+            learning_rate,
+            beta1,
+            beta2,
+            epsilon,
+            weight_decay,
+            lazy,
+            t,
+        } = self;
+        let State { m, v, steps } = weights
+            .entry(HashWeak(Rc::downgrade(&weight)))
+            .or_insert_with(|| {
+                let len = Tensor::contiguous_of(&*weight).take();
+                State {
+                    m: Blob::new_zeroed(len),
+                    v: Blob::new_zeroed(len),
+                    steps: Vec::new(),
+                }
+            });
+
+        assert_eq!(weight.dt(), types::F32);
+        assert_eq!(gradient.dt(), types::F32);
+        assert!(weight.is_contiguous() && gradient.is_contiguous());
+
+        let n_rows = weight.shape()[0];
+        let weight = weight.cloned();
+        let weight = weight.get().write();
+        let ([], weight, []) = (unsafe { weight.align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        let row = weight.len() / n_rows;
+        let gradient = gradient.cloned();
+        let grad = gradient.get().read();
+        let ([], grad, []) = (unsafe { grad.align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let ([], m, []) = (unsafe { m.align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        let ([], v, []) = (unsafe { v.align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+
+        let mut grads = vec![None; n_rows];
+        for (i, &r) in rows.iter().enumerate() {
+            grads[r] = Some(&grad[i * row..][..row])
+        }
+        let zeros = vec![0.; row];
+        if lazy && steps.is_empty() {
+            *steps = vec![0; n_rows]
+        }
+
+        for (r, g) in grads.into_iter().enumerate() {
+            let (g, t) = match g {
+                // 惰性模式跳过没有梯度的行，行内的步数只计有梯度的更新
+                None if lazy => continue,
+                Some(g) if lazy => {
+                    steps[r] += 1;
+                    (g, steps[r])
+                }
+                g => (g.unwrap_or(&zeros), t),
+            };
+            let hat1 = 1. / (1. - beta1.powi(t));
+            let hat2 = 1. / (1. - beta2.powi(t));
+            let range = r * row..(r + 1) * row;
+            for (w, g, m, v) in izip!(
+                &mut weight[range.clone()],
+                g,
+                &mut m[range.clone()],
+                &mut v[range]
+            ) {
+                *m = beta1 * *m + (1. - beta1) * g;
+                *v = beta2 * *v + (1. - beta2) * g * g;
+                *w -=
+                    learning_rate * (*m * hat1 / ((*v * hat2).sqrt() + epsilon) + weight_decay * *w)
+            }
+        }
+    }
 }
 
 impl AdamW {
@@ -88,11 +207,77 @@ impl AdamW {
             beta2,
             epsilon,
             weight_decay,
+            lazy: false,
             t: 1,
         }
     }
 
+    /// 稀疏梯度只更新出现的行（Lazy Adam）。
+    ///
+    /// 没有梯度的行不衰减动量也不衰减权重，每行按自己被更新的次数做偏差修正。
+    /// 小批量微调大词表时更新量与批内词数而不是词表大小成正比，结果与完整更新不再相同。
+    pub fn set_lazy(&mut self, lazy: bool) {
+        self.lazy = lazy
+    }
+
+    pub fn learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+
+    /// 修改之后使用的学习率，用于学习率调度。
+    pub fn set_learning_rate(&mut self, learning_rate: f32) {
+        self.learning_rate = learning_rate
+    }
+
     pub fn next(&mut self) {
         self.t += 1
     }
 }
+
+#[test]
+fn test_lazy_update() {
+    let [n_rows, row] = [4, 2];
+    let tensor = |shape: &[usize], data: &[f32]| {
+        Tensor::new(types::F32, shape)
+            .map(|_| Blob::from(data))
+            .map(RwRc::new)
+            .share()
+    };
+    let values = |t: &Rc<Tensor<RwRc<Blob>>>| {
+        let t = t.cloned();
+        let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+
+    let init = [1f32; 8];
+    let weight = tensor(&[n_rows, row], &init);
+    let mut adamw = AdamW::new(0.1, 0.9, 0.999, 1e-8, 0.);
+    adamw.set_lazy(true);
+    // 第 1 步只有第 0、2 行，第 2 步只有第 2、3 行
+    adamw.update_sparse(
+        weight.clone(),
+        tensor(&[2, row], &[1., -1., 2., 2.]),
+        &[0, 2],
+    );
+    adamw.next();
+    let first = values(&weight);
+    adamw.update_sparse(
+        weight.clone(),
+        tensor(&[2, row], &[2., 2., 3., -3.]),
+        &[2, 3],
+    );
+    let second = values(&weight);
+
+    // 偏差修正后第一次更新的步长都是学习率
+    for (w, expect) in first.iter().zip([0.9, 1.1, 1., 1., 0.9, 0.9, 1., 1.]) {
+        assert!((w - expect).abs() < 1e-6, "{first:?}")
+    }
+    // 没有梯度的行不变
+    assert_eq!(&second[..4], &first[..4]);
+    // 各行按自己的步数修正：第 2 行梯度不变、第 3 行首次更新，步长都是学习率
+    for (w, expect) in second[4..].iter().zip([0.8, 0.8, 0.9, 1.1]) {
+        assert!((w - expect).abs() < 1e-6, "{second:?}")
+    }
+}
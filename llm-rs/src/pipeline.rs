@@ -0,0 +1,522 @@
+//! 高层推理接口：把模型、分词器、KV 缓存和采样组合在一起，直接按文本生成和对话。
+//!
+//! [`crate::session::InferenceSession`] 是形状固定的前向重放，这里的 [`Pipeline`] 是面向文本的一层封装，
+//! 每次调用在内部完成编码、截断、采样和解码。
+//!
+//! [`Pipeline::chat_tools`] 支持工具调用：把工具列表写进系统提示，回复是 JSON 形式的调用时解析为
+//! [`ToolCall`]，[`ToolChoice::Required`] 时用语法约束保证输出是合法的调用。
+
+use crate::{
+    Blob, Context,
+    generate::{GenerationConfig, GenerationResult, Stream, generate_stream},
+    grammar::{Grammar, GrammarError, Matcher, tool_call_gbnf},
+    llmc::{self, Tokenizer},
+    nn::gpt2::Gpt2,
+    quant::{Checkpoint, ImportError},
+    sampler::{Penalties, SamplerChain, SamplerConfig, SamplingState, TokenMask},
+    stop::StopSequences,
+    truncate::Truncation,
+};
+use memmap2::Mmap;
+use rw_rc::RwRc;
+use serde_json::Value;
+use std::{fs::File, path::Path, sync::Arc};
+
+/// 一次生成的参数。
+#[derive(Clone, Debug)]
+pub struct GenerateParams {
+    pub max_tokens: usize,
+    pub sampler: SamplerConfig,
+    /// 提示中的词也计入惩罚。
+    pub penalties: Penalties,
+    pub seed: u64,
+    /// 停止字符串，不出现在结果中。
+    pub stop: Vec<String>,
+    /// 只在这些词中采样，见 [`TokenMask`]，结束词不在其中时只能生成到 `max_tokens`。
+    pub allowed: Option<TokenMask>,
+}
+
+impl Default for GenerateParams {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            sampler: SamplerConfig::default(),
+            penalties: Penalties::default(),
+            seed: 0,
+            stop: Vec::new(),
+            allowed: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    /// 工具调用的结果。
+    Tool,
+}
+
+/// 对话中的一条消息。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+
+    /// 助手发起工具调用的消息，用于把之前的调用放回对话。
+    pub fn tool_call(call: &ToolCall) -> Self {
+        Self::new(Role::Assistant, call.to_json())
+    }
+}
+
+/// 可供模型调用的工具。
+#[derive(Clone, PartialEq, Debug)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    /// 参数的 JSON Schema，支持的关键字见 [`crate::grammar::json_schema_to_gbnf`]。
+    pub parameters: Value,
+}
+
+/// 模型发起的一次工具调用。
+#[derive(Clone, PartialEq, Debug)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: Value,
+}
+
+impl ToolCall {
+    /// 从回复文本中解析对 `tools` 中某个工具的调用，回复不是这样的 JSON 对象时返回 `None`。
+    pub fn parse(text: &str, tools: &[Tool]) -> Option<Self> {
+        let Value::Object(mut call) = serde_json::from_str(text.trim()).ok()? else {
+            return None;
+        };
+        let Some(Value::String(name)) = call.remove("name") else {
+            return None;
+        };
+        let arguments = call.remove("arguments")?;
+        (call.is_empty() && tools.iter().any(|t| t.name == name))
+            .then_some(Self { name, arguments })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::json!({ "name": self.name, "arguments": self.arguments }).to_string()
+    }
+}
+
+/// 是否必须调用工具。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ToolChoice {
+    /// 模型自行决定回复文本还是调用工具。
+    #[default]
+    Auto,
+    /// 约束输出为对某个工具的调用。
+    Required,
+}
+
+/// 带工具的对话回复，`tool_call` 为 `None` 时 `result.text` 是普通的回复。
+#[derive(Debug)]
+pub struct ChatResponse {
+    pub result: GenerationResult,
+    pub tool_call: Option<ToolCall>,
+}
+
+/// 持有模型、分词器和 KV 缓存的文本生成管线。
+pub struct Pipeline {
+    ctx: Context,
+    gpt2: Gpt2,
+    tokenizer: Tokenizer,
+    config: GenerationConfig,
+}
+
+impl Pipeline {
+    pub fn new(model: llmc::Gpt2<RwRc<Blob>>, tokenizer: Tokenizer) -> Self {
+        let config = GenerationConfig {
+            n_ctx: model.config.n_seq,
+            n_voc: model.config.n_voc,
+            max_tokens: 0,
+            eos: Some(tokenizer.eos),
+            truncation: Truncation::KeepTail,
+            echo: false,
+            stop: None,
+            cost: model.config.cost(),
+        };
+        let mut ctx = Context::new(false);
+        let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+        gpt2.kv_cache(true);
+        Self {
+            ctx,
+            gpt2,
+            tokenizer,
+            config,
+        }
+    }
+
+    /// 从 llm.c 的 `.bin` 或 safetensors 检查点和 llm.c 的分词器文件构造。
+    pub fn open(model: impl AsRef<Path>, tokenizer: impl AsRef<Path>) -> Result<Self, ImportError> {
+        let model = model.as_ref();
+        let gpt2 = if model.extension().is_some_and(|ext| ext == "safetensors") {
+            Checkpoint::open(model)?.gpt2()?
+        } else {
+            let mmap = unsafe { Mmap::map(&File::open(model)?) }?;
+            llmc::Gpt2::mapped(&Arc::new(mmap))
+        };
+        let tokenizer = Tokenizer::new(tokenizer)?;
+        Ok(Self::new(gpt2.map(RwRc::new), tokenizer))
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// 续写 `prompt`，生成结束词、满足停止字符串或达到 `max_tokens` 时停止。
+    pub fn generate(&mut self, prompt: &str, params: &GenerateParams) -> GenerationResult {
+        self.stream(prompt, params).finish()
+    }
+
+    /// 与 [`Self::generate`] 相同，但逐段产生生成的文本，见 [`Stream`]。
+    pub fn stream<'s>(
+        &'s mut self,
+        prompt: &str,
+        params: &GenerateParams,
+    ) -> Stream<'s, impl FnMut(&[f32]) -> u16 + use<'s>, impl Fn(u16) -> &'s [u8] + use<'s>> {
+        let prompt = self.encode(prompt);
+        self.start(&[&prompt], params, &[], None)
+    }
+
+    /// 按消息生成助手的下一条回复。
+    ///
+    /// 消息按纯文本模板拼接：系统提示单独成段，其余每条为一行 `User: ...` 或 `Assistant: ...`，
+    /// 最后以 `Assistant:` 引出回复，生成到下一个 `User:` 之前。
+    /// 上下文放不下时按 [`Truncation::apply_chat`] 优先保留系统提示和最新的消息。
+    pub fn chat(&mut self, messages: &[Message], params: &GenerateParams) -> GenerationResult {
+        self.reply(messages, String::new(), params, None)
+    }
+
+    /// 可以调用 `tools` 的对话，模板同 [`Self::chat`]，工具结果以 `Tool: ...` 一行给出。
+    ///
+    /// 工具的说明和参数 Schema 追加在系统提示之后，要求模型以 `{"name": ..., "arguments": ...}` 调用。
+    /// 参数 Schema 不受支持时返回错误。
+    pub fn chat_tools(
+        &mut self,
+        messages: &[Message],
+        tools: &[Tool],
+        choice: ToolChoice,
+        params: &GenerateParams,
+    ) -> Result<ChatResponse, GrammarError> {
+        let grammar = Grammar::parse(&tool_call_gbnf(
+            tools.iter().map(|t| (&*t.name, &t.parameters)),
+        )?)?;
+        let grammar = (choice == ToolChoice::Required).then(|| Arc::new(grammar));
+
+        let mut system = String::from(
+            "To call a tool, reply with only a JSON object {\"name\": <tool>, \"arguments\": <arguments>}.\nTools:\n",
+        );
+        for tool in tools {
+            system += &format!(
+                "- {}: {} Arguments: {}\n",
+                tool.name, tool.description, tool.parameters
+            )
+        }
+        system += "\n";
+
+        let result = self.reply(messages, system, params, grammar);
+        let tool_call = ToolCall::parse(&result.text, tools);
+        Ok(ChatResponse { result, tool_call })
+    }
+
+    /// 按模板拼接消息，`tools` 追加在系统提示之后，生成助手的回复。
+    fn reply(
+        &mut self,
+        messages: &[Message],
+        tools: String,
+        params: &GenerateParams,
+        grammar: Option<Arc<Grammar>>,
+    ) -> GenerationResult {
+        let mut system = String::new();
+        let mut turns = Vec::new();
+        for msg in messages {
+            match msg.role {
+                Role::System => system += &format!("{}\n\n", msg.content),
+                Role::User => turns.push(self.encode(&format!("User: {}\n", msg.content))),
+                Role::Assistant => {
+                    turns.push(self.encode(&format!("Assistant: {}\n", msg.content)))
+                }
+                Role::Tool => turns.push(self.encode(&format!("Tool: {}\n", msg.content))),
+            }
+        }
+        turns.push(self.encode("Assistant:"));
+
+        let system = self.encode(&(system + &tools));
+        let mut parts = vec![&*system];
+        parts.extend(turns.iter().map(Vec::as_slice));
+        let mut result = self.start(&parts, params, &["\nUser:"], grammar).finish();
+        result.text = result.text.trim().into();
+        result
+    }
+
+    fn encode(&self, text: &str) -> Vec<u16> {
+        self.tokenizer
+            .encode(text.as_bytes())
+            .unwrap_or_else(|i| panic!("byte {i} of the prompt is not in the vocabulary"))
+    }
+
+    /// 截断并拼接提示的各部分（第一部分为系统提示），开始生成。
+    fn start<'s>(
+        &'s mut self,
+        parts: &[&[u16]],
+        params: &GenerateParams,
+        stop: &[&str],
+        grammar: Option<Arc<Grammar>>,
+    ) -> Stream<'s, impl FnMut(&[f32]) -> u16 + use<'s>, impl Fn(u16) -> &'s [u8] + use<'s>> {
+        let Self {
+            ctx,
+            gpt2,
+            tokenizer,
+            config,
+        } = self;
+        // 给生成留出空间，空的提示以结束词开始
+        let room = config.n_ctx.saturating_sub(params.max_tokens).max(1);
+        let mut prompt = if parts.len() == 1 {
+            Truncation::KeepTail.apply(parts[0], room)
+        } else {
+            Truncation::KeepTail.apply_chat(parts, room)
+        };
+        if prompt.is_empty() {
+            prompt.push(tokenizer.eos)
+        }
+
+        let strings = params
+            .stop
+            .iter()
+            .cloned()
+            .chain(stop.iter().map(|&s| s.into()))
+            .collect::<Vec<_>>();
+        let config = GenerationConfig {
+            max_tokens: params.max_tokens,
+            stop: (!strings.is_empty()).then(|| {
+                Arc::new(StopSequences {
+                    strings,
+                    ..Default::default()
+                }) as _
+            }),
+            ..config.clone()
+        };
+
+        let chain = SamplerChain::new(params.sampler.steps(), params.seed);
+        let mut state = SamplingState::new(chain, params.penalties);
+        prompt.iter().for_each(|&t| state.accept(t));
+        if let Some(mask) = &params.allowed {
+            state.allowed(mask.clone())
+        }
+        let tokenizer = &*tokenizer;
+        let mut matcher = grammar.map(Matcher::new);
+        let allowed = params.allowed.clone();
+        let eos = Some(tokenizer.eos);
+        generate_stream(
+            ctx,
+            "gpt2",
+            gpt2,
+            &prompt,
+            &config,
+            move |logits| match &mut matcher {
+                Some(m) => {
+                    // 先屏蔽不允许的词，与语法没有交集时以结束词结束生成
+                    let mut logits = logits.to_vec();
+                    if let Some(mask) = &allowed {
+                        mask.apply(&mut logits)
+                    }
+                    m.sample(&logits, |t| tokenizer.decode(t), eos, |l| state.sample(l))
+                        .unwrap_or(tokenizer.eos)
+                }
+                None => state.sample(logits),
+            },
+            move |t| tokenizer.decode(t),
+        )
+    }
+}
+
+#[test]
+fn test_pipeline() {
+    use crate::{generate::FinishReason, llmc::Gpt2Config};
+    use rand::{SeedableRng, rngs::StdRng};
+    use serde_json::json;
+
+    // 字节级分词器：256 个单字节词和一个结束词
+    let mut tokenizer = vec![0i32; 256];
+    tokenizer[..4].copy_from_slice(&[20240328, 2, 257, 256]);
+    let mut tokenizer = tokenizer
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect::<Vec<_>>();
+    for b in 0..=255u8 {
+        tokenizer.extend([1, b])
+    }
+    tokenizer.extend([1, b'$']);
+    let mut aligned = vec![0u32; tokenizer.len().div_ceil(4)];
+    unsafe { aligned.align_to_mut::<u8>().1[..tokenizer.len()].copy_from_slice(&tokenizer) };
+    let tokenizer = Tokenizer::from_bytes(unsafe { aligned.align_to::<u8>().1 });
+
+    let gpt2 = llmc::Gpt2::random(Gpt2Config::tiny(257), &mut StdRng::seed_from_u64(7));
+    let mut pipeline = Pipeline::new(gpt2.map(RwRc::new), tokenizer);
+
+    let params = GenerateParams {
+        max_tokens: 8,
+        ..Default::default()
+    };
+    let a = pipeline.generate("hello", &params);
+    assert_eq!(a.n_prompt, 5);
+    // 相同的种子得到相同的结果，流式输出拼接起来与一次生成相同
+    let mut stream = pipeline.stream("hello", &params);
+    let text = (&mut stream).collect::<String>();
+    let b = stream.finish();
+    assert_eq!(a.tokens, b.tokens);
+    assert_eq!(text, b.text);
+
+    // 空的提示以结束词开始，过长的提示保留末尾并给生成留出空间
+    assert_eq!(pipeline.generate("", &params).n_prompt, 1);
+    let long = "x".repeat(100);
+    assert_eq!(pipeline.generate(&long, &params).n_prompt, 64 - 8);
+
+    // 停止字符串来自每个词都解码为同一个字节的贪心解码
+    let greedy = GenerateParams {
+        sampler: SamplerConfig {
+            temperature: 0.,
+            ..Default::default()
+        },
+        ..params.clone()
+    };
+    let free = pipeline.generate("ab", &greedy);
+    if free.finish_reason == FinishReason::Length && free.text.len() >= 3 {
+        let stop = free.text.as_bytes()[1..3].to_vec();
+        let stop = String::from_utf8_lossy(&stop).into_owned();
+        let stopped = pipeline.generate(
+            "ab",
+            &GenerateParams {
+                stop: vec![stop.clone()],
+                ..greedy.clone()
+            },
+        );
+        assert!(!stopped.text.contains(&*stop));
+    }
+
+    // 只允许数字和结束词
+    let tokenizer = pipeline.tokenizer();
+    let mut digits = TokenMask::from_decoded(
+        257,
+        |t| tokenizer.decode(t),
+        |t| t.iter().all(u8::is_ascii_digit),
+    );
+    digits.insert(tokenizer.eos);
+    let number = pipeline.generate(
+        "n=",
+        &GenerateParams {
+            allowed: Some(digits.clone()),
+            ..params.clone()
+        },
+    );
+    assert!(number.text.bytes().all(|b| b.is_ascii_digit()));
+
+    let messages = [
+        Message::new(Role::System, "Hi."),
+        Message::new(Role::User, "a"),
+        Message::new(Role::Assistant, "b"),
+        Message::new(Role::User, "c"),
+    ];
+    let reply = pipeline.chat(&messages, &params);
+    assert_eq!(
+        reply.n_prompt,
+        "Hi.\n\nUser: a\nAssistant: b\nUser: c\nAssistant:".len()
+    );
+    assert!(!reply.text.contains("\nUser:"));
+    // 放不下时截断最早的消息，提示正好占满留给它的空间
+    let params = GenerateParams {
+        max_tokens: 24,
+        ..params
+    };
+    assert_eq!(pipeline.chat(&messages, &params).n_prompt, 64 - 24);
+
+    // 工具调用，参数只有枚举，约束生成的长度有限
+    let tools = [
+        Tool {
+            name: "time".into(),
+            description: "Current time.".into(),
+            parameters: json!({ "type": "object", "properties": {} }),
+        },
+        Tool {
+            name: "weather".into(),
+            description: "Weather forecast.".into(),
+            parameters: json!({ "properties": { "unit": { "enum": ["c", "f"] } } }),
+        },
+    ];
+    let mut config = Gpt2Config::tiny(257);
+    config.n_seq = 512;
+    let gpt2 = llmc::Gpt2::random(config, &mut StdRng::seed_from_u64(7));
+    let tokenizer = Tokenizer::from_bytes(unsafe { aligned.align_to::<u8>().1 });
+    let mut pipeline = Pipeline::new(gpt2.map(RwRc::new), tokenizer);
+    let messages = [Message::new(Role::User, "Weather?")];
+    for seed in 0..3 {
+        let params = GenerateParams {
+            max_tokens: 96,
+            seed,
+            ..Default::default()
+        };
+        let response = pipeline
+            .chat_tools(&messages, &tools, ToolChoice::Required, &params)
+            .unwrap();
+        let call = response.tool_call.unwrap();
+        match &*call.name {
+            "time" => assert_eq!(call.arguments, json!({})),
+            _ => assert!(call.arguments["unit"] == "c" || call.arguments["unit"] == "f"),
+        }
+    }
+    // 只允许数字时与工具调用的语法没有交集，直接结束生成
+    let params = GenerateParams {
+        max_tokens: 8,
+        allowed: Some(digits),
+        ..Default::default()
+    };
+    let response = pipeline
+        .chat_tools(&messages, &tools, ToolChoice::Required, &params)
+        .unwrap();
+    assert!(response.tool_call.is_none());
+    assert!(response.result.text.is_empty());
+
+    // 调用和结果放回对话
+    let call = ToolCall {
+        name: "weather".into(),
+        arguments: json!({ "unit": "c" }),
+    };
+    assert_eq!(ToolCall::parse(&call.to_json(), &tools), Some(call.clone()));
+    assert_eq!(
+        ToolCall::parse(r#"{"name": "search", "arguments": {}}"#, &tools),
+        None
+    );
+    let messages = [
+        Message::new(Role::User, "Weather?"),
+        Message::tool_call(&call),
+        Message::new(Role::Tool, "12"),
+    ];
+    let params = GenerateParams {
+        max_tokens: 8,
+        ..Default::default()
+    };
+    let response = pipeline
+        .chat_tools(&messages, &tools, ToolChoice::Auto, &params)
+        .unwrap();
+    assert!(response.result.n_prompt > 0);
+    assert!(matches!(
+        pipeline.chat_tools(&messages, &[], ToolChoice::Auto, &params),
+        Err(GrammarError::Schema(_))
+    ))
+}
@@ -0,0 +1,48 @@
+//! 内存映射权重的预取，见 [`crate::Blob::mapped`]。
+
+use memmap2::Mmap;
+use std::{ops::Range, sync::Arc};
+
+/// 通知内核即将读取 `range`，立即返回，内核在后台读入。
+pub fn will_need(mmap: &Mmap, range: Range<usize>) {
+    #[cfg(unix)]
+    {
+        // 只是提示，失败不影响正确性
+        let _ = mmap.advise_range(memmap2::Advice::WillNeed, range.start, range.len());
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (mmap, range);
+    }
+}
+
+/// 内存映射文件中的一段，由 [`crate::Blob::region`] 得到。
+#[derive(Clone)]
+pub struct Region {
+    mmap: Arc<Mmap>,
+    range: Range<usize>,
+}
+
+impl Region {
+    pub(crate) fn new(mmap: Arc<Mmap>, range: Range<usize>) -> Self {
+        assert!(range.end <= mmap.len());
+        Self { mmap, range }
+    }
+
+    /// 在文件中的字节范围。
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    /// 其中从 `start` 开始的 `len` 字节。
+    pub fn slice(&self, start: usize, len: usize) -> Self {
+        assert!(start + len <= self.range.len());
+        let start = self.range.start + start;
+        Self::new(self.mmap.clone(), start..start + len)
+    }
+
+    /// 见 [`will_need`]。
+    pub fn will_need(&self) {
+        will_need(&self.mmap, self.range())
+    }
+}
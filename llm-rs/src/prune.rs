@@ -0,0 +1,311 @@
+//! 结构化剪枝：按权重幅度给注意力头和 FFN 通道打分，删去不重要的部分得到更小的模型，
+//! 再用 [`recover`] 做短暂的微调恢复精度。
+//!
+//! 剪枝后的模型可以用 [`crate::quant::save_gpt2`] 保存，读取时形状取自张量本身；
+//! [`Gpt2Config::cost`](crate::llmc::Gpt2Config::cost) 和 [`Gpt2Config::shape`](crate::llmc::Gpt2Config::shape) 仍按未剪枝的尺寸计算。只支持 f32 权重。
+
+use crate::{
+    Blob, Context, Tensor,
+    llmc::{DataLoader, Gpt2, Gpt2Blk},
+    nn,
+    op::topk,
+    optimizer::AdamW,
+};
+use digit_layout::types;
+use rw_rc::RwRc;
+
+/// 各注意力头的重要性：头在 qkv 投影中的行（含偏置）与在输出投影中的列的权重平方和。
+pub fn head_importance(blk: &Gpt2Blk<Blob>, nh: usize) -> Vec<f32> {
+    let [w, b] = &blk.attn_qkv;
+    let &[d3, d] = &*w.shape() else {
+        unreachable!()
+    };
+    let d_attn = d3 / 3;
+    let dh = d_attn / nh;
+    let (w, b, o) = (f32s(w), f32s(b), f32s(&blk.attn_o[0]));
+    (0..nh)
+        .map(|h| {
+            let qkv = head_rows(h, d_attn, dh)
+                .map(|r| sum_sq(&w[r * d..][..d]) + b[r] * b[r])
+                .sum::<f32>();
+            let out = o
+                .chunks_exact(d_attn)
+                .map(|row| sum_sq(&row[h * dh..][..dh]))
+                .sum::<f32>();
+            qkv + out
+        })
+        .collect()
+}
+
+/// FFN 各通道的重要性：通道在升维投影中的行（含偏置）与在降维投影中的列的权重平方和。
+pub fn ffn_importance(blk: &Gpt2Blk<Blob>) -> Vec<f32> {
+    let [w, b] = &blk.ffn_up;
+    let &[d_ffn, d] = &*w.shape() else {
+        unreachable!()
+    };
+    let (w, b, down) = (f32s(w), f32s(b), f32s(&blk.ffn_down[0]));
+    let mut scores = (0..d_ffn)
+        .map(|j| sum_sq(&w[j * d..][..d]) + b[j] * b[j])
+        .collect::<Vec<_>>();
+    for row in down.chunks_exact(d_ffn) {
+        for (s, x) in scores.iter_mut().zip(row) {
+            *s += x * x
+        }
+    }
+    scores
+}
+
+/// 每层（包括多词预测头）只保留重要性最高的 `n_heads` 个注意力头，`config.nh` 相应减小，头维度不变。
+pub fn prune_heads(gpt2: Gpt2<Blob>, n_heads: usize) -> Gpt2<Blob> {
+    let nh = gpt2.config.nh;
+    assert!(0 < n_heads && n_heads <= nh);
+    let prune = |blk: Gpt2Blk<Blob>| {
+        let keep = keep(&head_importance(&blk, nh), n_heads);
+        let Gpt2Blk {
+            attn_qkv: [w, b],
+            attn_o: [o, o_b],
+            ..
+        } = blk;
+        let d_attn = w.shape()[0] / 3;
+        let dh = d_attn / nh;
+        let rows = (0..3)
+            .flat_map(|part| {
+                keep.iter()
+                    .flat_map(move |&h| head_rows_in(part, h, d_attn, dh))
+            })
+            .collect::<Vec<_>>();
+        let cols = keep
+            .iter()
+            .flat_map(|&h| h * dh..(h + 1) * dh)
+            .collect::<Vec<_>>();
+        Gpt2Blk {
+            attn_qkv: [select_rows(&w, &rows), select_rows(&b, &rows)],
+            attn_o: [select_cols(&o, &cols), o_b],
+            ..blk
+        }
+    };
+    let mut gpt2 = Gpt2 {
+        blks: gpt2.blks.into_iter().map(prune).collect(),
+        mtp: gpt2.mtp.into_iter().map(prune).collect(),
+        ..gpt2
+    };
+    gpt2.config.nh = n_heads;
+    gpt2
+}
+
+/// 每层（包括多词预测头）只保留重要性最高的 `n_channels` 个 FFN 通道。
+pub fn prune_ffn(gpt2: Gpt2<Blob>, n_channels: usize) -> Gpt2<Blob> {
+    assert!(n_channels > 0);
+    let prune = |blk: Gpt2Blk<Blob>| {
+        let keep = keep(&ffn_importance(&blk), n_channels);
+        let Gpt2Blk {
+            ffn_up: [w, b],
+            ffn_down: [down, down_b],
+            ..
+        } = blk;
+        Gpt2Blk {
+            ffn_up: [select_rows(&w, &keep), select_rows(&b, &keep)],
+            ffn_down: [select_cols(&down, &keep), down_b],
+            ..blk
+        }
+    };
+    Gpt2 {
+        blks: gpt2.blks.into_iter().map(prune).collect(),
+        mtp: gpt2.mtp.into_iter().map(prune).collect(),
+        ..gpt2
+    }
+}
+
+/// 剪枝后的恢复微调：在 `loader` 上以 AdamW 训练 `steps` 步，返回新的权重和每步的平均损失。
+pub fn recover(
+    gpt2: Gpt2<Blob>,
+    loader: &mut DataLoader,
+    steps: usize,
+    learning_rate: f32,
+) -> (Gpt2<Blob>, Vec<f32>) {
+    let n_voc = gpt2.config.n_voc;
+    let weights = gpt2.map(RwRc::new);
+
+    let mut ctx = Context::new(false);
+    let mut model = ctx.init::<nn::gpt2::Gpt2>("gpt2", weights.clone());
+    let mut loss = ctx.init::<nn::loss::Loss>("loss", n_voc);
+    let mut adamw = AdamW::new(learning_rate, 0.9, 0.999, 1e-8, 0.);
+
+    let mut history = Vec::with_capacity(steps);
+    for step in 0..steps {
+        let _span = tracing::debug_span!("recover", step).entered();
+        let shape = loader.shape();
+        let [inputs, targets] = loader.load();
+        let tokens = Tensor::new(types::U16, &shape).map(|_| RwRc::new(inputs.into()));
+        let targets = Tensor::new(types::U16, &shape).map(|_| RwRc::new(targets.into()));
+
+        let logits = ctx.forward("gpt2", &mut model, [tokens.share()]);
+        let losses = ctx.forward("loss", &mut loss, [logits[0].clone(), targets.share()]);
+        let losses_ = losses[0].cloned().merge(0, 2);
+        let losses_ = losses_.as_ref().map(|b| &**b.read()).vector::<f32>();
+        let n = losses_.len();
+        history.push(losses_.iter().sum::<f32>() / n as f32);
+
+        ctx.zero_grad();
+        let dlosses = ctx.tensor_like(&losses[0]);
+        dlosses
+            .cloned()
+            .merge(0, 2)
+            .as_ref()
+            .map(|b| &mut **b.write())
+            .vector_mut::<f32>()
+            .fill(1. / n as f32);
+        let dlogits = ctx.backward("loss", &mut loss, [dlosses.share()]);
+        let _ = ctx.backward("gpt2", &mut model, dlogits);
+        ctx.update(&mut adamw);
+        adamw.next();
+        tracing::debug!(loss = history[step], "recovered")
+    }
+
+    drop((model, ctx));
+    (weights.map(|b| b.read().clone()), history)
+}
+
+/// 重要性最高的 `n` 个位置，按原顺序排列。
+fn keep(scores: &[f32], n: usize) -> Vec<usize> {
+    assert!(n <= scores.len());
+    let mut keep = topk::top_k(scores, n)
+        .into_iter()
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    keep.sort_unstable();
+    keep
+}
+
+/// 第 `h` 个头在 q、k、v 三部分中的行。
+fn head_rows(h: usize, d_attn: usize, dh: usize) -> impl Iterator<Item = usize> {
+    (0..3).flat_map(move |part| head_rows_in(part, h, d_attn, dh))
+}
+
+fn head_rows_in(part: usize, h: usize, d_attn: usize, dh: usize) -> std::ops::Range<usize> {
+    let start = part * d_attn + h * dh;
+    start..start + dh
+}
+
+fn sum_sq(x: &[f32]) -> f32 {
+    x.iter().map(|x| x * x).sum()
+}
+
+fn f32s(t: &Tensor<Blob>) -> &[f32] {
+    assert_eq!(t.dt(), types::F32, "pruning supports f32 weights only");
+    let ([], data, []) = (unsafe { t.get().align_to::<f32>() }) else {
+        unreachable!()
+    };
+    data
+}
+
+/// 取出张量沿第一维的部分行。
+fn select_rows(t: &Tensor<Blob>, rows: &[usize]) -> Tensor<Blob> {
+    let width = t.shape()[1..].iter().product::<usize>();
+    let data = f32s(t);
+    let ans = rows
+        .iter()
+        .flat_map(|&r| &data[r * width..][..width])
+        .copied()
+        .collect::<Vec<_>>();
+    let mut shape = t.shape().to_vec();
+    shape[0] = rows.len();
+    Tensor::new(types::F32, &shape).map(|_| Blob::from(&*ans))
+}
+
+/// 取出矩阵的部分列。
+fn select_cols(t: &Tensor<Blob>, cols: &[usize]) -> Tensor<Blob> {
+    let &[n_rows, width] = &*t.shape() else {
+        unreachable!()
+    };
+    let data = f32s(t);
+    let ans = data
+        .chunks_exact(width)
+        .flat_map(|row| cols.iter().map(|&c| row[c]))
+        .collect::<Vec<_>>();
+    Tensor::new(types::F32, &[n_rows, cols.len()]).map(|_| Blob::from(&*ans))
+}
+
+#[test]
+fn test_prune() {
+    use crate::{
+        llmc::Gpt2Config,
+        synthetic::{N_VOC, Task},
+    };
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut gpt2 = Gpt2::random(Gpt2Config::tiny(N_VOC), &mut rng);
+    let Gpt2Config { nh, d, .. } = gpt2.config;
+    let dh = d / nh;
+
+    // 把每层的第 1 个头和第 3 个 FFN 通道置零，它们对输出没有贡献，最不重要
+    let zero_rows = |t: &mut Tensor<Blob>, rows: &mut dyn Iterator<Item = usize>| {
+        let width = t.shape()[1..].iter().product::<usize>();
+        let ([], data, []) = (unsafe { t.get_mut().align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        for r in rows {
+            data[r * width..][..width].fill(0.)
+        }
+    };
+    let zero_col = |t: &mut Tensor<Blob>, cols: std::ops::Range<usize>| {
+        let width = t.shape()[1];
+        let ([], data, []) = (unsafe { t.get_mut().align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        for row in data.chunks_exact_mut(width) {
+            row[cols.clone()].fill(0.)
+        }
+    };
+    for blk in &mut gpt2.blks {
+        for t in &mut blk.attn_qkv {
+            zero_rows(t, &mut head_rows(1, d, dh))
+        }
+        zero_col(&mut blk.attn_o[0], dh..2 * dh);
+        for t in &mut blk.ffn_up {
+            zero_rows(t, &mut (3..4))
+        }
+        zero_col(&mut blk.ffn_down[0], 3..4)
+    }
+    let scores = head_importance(&gpt2.blks[0], nh);
+    assert_eq!(scores.len(), nh);
+    assert_eq!(
+        topk::argmax(&scores.iter().map(|x| -x).collect::<Vec<_>>()),
+        1
+    );
+    assert_eq!(ffn_importance(&gpt2.blks[0])[3], 0.);
+
+    let logits = |gpt2: &Gpt2<Blob>| {
+        let mut ctx = Context::new(false);
+        let mut model = ctx.init::<nn::gpt2::Gpt2>("gpt2", gpt2.clone().map(RwRc::new));
+        let tokens = Tensor::new(types::U16, &[1, 6])
+            .map(|_| RwRc::new(Blob::from(&[1u16, 5, 9, 2, 7, 3][..])))
+            .share();
+        let y = ctx.forward("gpt2", &mut model, [tokens]);
+        let ([], buf, []) = (unsafe { y[0].get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        buf.to_vec()
+    };
+    let expected = logits(&gpt2);
+
+    // 删去没有贡献的头和通道不改变输出
+    let pruned = prune_ffn(prune_heads(gpt2, nh - 1), 4 * d - 1);
+    assert_eq!(pruned.config.nh, nh - 1);
+    let blk = &pruned.blks[1];
+    assert_eq!(&*blk.attn_qkv[0].shape(), [3 * (nh - 1) * dh, d]);
+    assert_eq!(&*blk.attn_o[0].shape(), [d, (nh - 1) * dh]);
+    assert_eq!(&*blk.ffn_up[1].shape(), [4 * d - 1]);
+    assert_eq!(&*blk.ffn_down[0].shape(), [d, 4 * d - 1]);
+    for (a, b) in logits(&pruned).iter().zip(&expected) {
+        assert!((a - b).abs() < 1e-4, "{a} vs {b}")
+    }
+
+    // 进一步剪枝后的恢复微调降低损失
+    let pruned = prune_ffn(prune_heads(pruned, 2), d);
+    let tokens = Task::Copy.tokens(16, 8, &mut rng);
+    let mut loader = DataLoader::from_tokens(tokens, 2, 16, false);
+    let (_, history) = recover(pruned, &mut loader, 10, 1e-2);
+    assert!(history[9] < history[0], "{history:?}")
+}
@@ -0,0 +1,112 @@
+use super::{Recipe, quantize_gpt2};
+use crate::{
+    Blob, Context, Tensor,
+    llmc::{DataLoader, Gpt2},
+    nn::{gpt2, loss::Loss},
+};
+use digit_layout::types;
+use rw_rc::RwRc;
+use std::{fmt, iter::zip};
+
+/// 量化误差报告。
+pub struct Report {
+    /// 每个模块输出的 `(路径, 均方误差, 相对误差)`，相对误差为误差能量与参考输出能量之比。
+    pub layers: Vec<(String, f64, f64)>,
+    /// f32 模型的困惑度。
+    pub ppl_ref: f64,
+    /// 量化模型的困惑度。
+    pub ppl_quant: f64,
+}
+
+/// 用校准集分别运行 f32 模型和按配方量化的模型，统计逐层输出误差和端到端困惑度。
+pub fn calibrate(
+    gpt2: &Gpt2<Blob>,
+    recipe: &Recipe,
+    loader: &mut DataLoader,
+    n_batch: usize,
+) -> Report {
+    let n_voc = gpt2.config.n_voc;
+    let (quant, _) = quantize_gpt2(gpt2.clone(), recipe);
+
+    let mut models = [gpt2.clone(), quant].map(|gpt2| {
+        let mut ctx = Context::new(false);
+        let gpt2 = ctx.init::<gpt2::Gpt2>("gpt2", gpt2.map(RwRc::new));
+        let loss = ctx.init::<Loss>("loss", n_voc);
+        (ctx, gpt2, loss)
+    });
+
+    let shape = loader.shape();
+    let mut errors = Vec::<(String, f64, f64, usize)>::new();
+    let mut losses = [0.; 2];
+    for _ in 0..n_batch {
+        let [inputs, targets] = loader.load();
+        let records = models.each_mut().map(|(ctx, gpt2, loss)| {
+            let tokens = Tensor::new(types::U16, &shape).map(|_| RwRc::new(inputs.into()));
+            let targets = Tensor::new(types::U16, &shape).map(|_| RwRc::new(targets.into()));
+            ctx.start_record();
+            let logits = ctx.forward("gpt2", gpt2, [tokens.share()]);
+            ctx.forward("loss", loss, [logits[0].clone(), targets.share()]);
+            ctx.take_record()
+        });
+
+        let [ref_, quant] = records;
+        if errors.is_empty() {
+            errors = ref_
+                .iter()
+                .map(|(path, _)| (path.clone(), 0., 0., 0))
+                .collect()
+        }
+        for ((path, a), (path_, b), (_, err, energy, n)) in
+            itertools::izip!(&ref_, &quant, &mut errors)
+        {
+            assert_eq!(path, path_);
+            let (a, b) = (f32s(a), f32s(b));
+            if path.ends_with(".loss") {
+                losses[0] += a.iter().map(|&x| x as f64).sum::<f64>();
+                losses[1] += b.iter().map(|&x| x as f64).sum::<f64>();
+            }
+            for (a, b) in zip(a, b) {
+                *err += ((a - b) as f64).powi(2);
+                *energy += (*a as f64).powi(2);
+            }
+            *n += a.len()
+        }
+    }
+
+    let n_tok = (n_batch * shape[0] * shape[1]) as f64;
+    Report {
+        layers: errors
+            .into_iter()
+            .map(|(path, err, energy, n)| {
+                let path = path.trim_start_matches("Ω.").to_string();
+                (path, err / n as f64, err / energy.max(f64::MIN_POSITIVE))
+            })
+            .collect(),
+        ppl_ref: (losses[0] / n_tok).exp(),
+        ppl_quant: (losses[1] / n_tok).exp(),
+    }
+}
+
+fn f32s(t: &Tensor<Blob>) -> &[f32] {
+    let ([], data, []) = (unsafe { t.get().align_to::<f32>() }) else {
+        unreachable!()
+    };
+    data
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.layers.iter().map(|(p, ..)| p.len()).max().unwrap_or(0);
+        writeln!(f, "{:<width$}  {:>10}  {:>10}", "layer", "mse", "rel")?;
+        for (path, mse, rel) in &self.layers {
+            writeln!(f, "{path:<width$}  {mse:>10.3e}  {rel:>10.3e}")?
+        }
+        writeln!(
+            f,
+            "perplexity: {:.4} (f32) -> {:.4} (quant), delta {:+.4}",
+            self.ppl_ref,
+            self.ppl_quant,
+            self.ppl_quant - self.ppl_ref
+        )
+    }
+}
@@ -0,0 +1,136 @@
+use super::{ImportError, Recipe, Scheme, quant_row};
+use crate::{Blob, Tensor, llmc::Gpt2};
+use digit_layout::types;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use safetensors::{Dtype, View, serialize_to_file};
+use std::{borrow::Cow, collections::HashMap, path::Path, sync::LazyLock};
+
+/// 可以量化的张量，即参与矩阵乘的权重。
+///
+/// 词嵌入与输出头共享 `wte`，归一化与偏置都保持 f32。
+pub const QUANTIZABLE: [&str; 4] = [
+    "blk.*.attn_qkv.w",
+    "blk.*.attn_o.w",
+    "blk.*.ffn_up.w",
+    "blk.*.ffn_down.w",
+];
+
+static QUANTIZABLE_SET: LazyLock<GlobSet> = LazyLock::new(|| {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in QUANTIZABLE {
+        builder.add(Glob::new(pattern).unwrap());
+    }
+    builder.build().unwrap()
+});
+
+/// 按配方量化模型，返回新模型与每个张量采用的方案。
+pub fn quantize_gpt2(gpt2: Gpt2<Blob>, recipe: &Recipe) -> (Gpt2<Blob>, Vec<(String, Scheme)>) {
+    let mut schemes = Vec::new();
+    let gpt2 = gpt2.map_tensor(|name, tensor| {
+        let scheme = if QUANTIZABLE_SET.is_match(name) {
+            recipe.scheme(name)
+        } else {
+            Scheme::F32
+        };
+        schemes.push((name.into(), scheme));
+        if scheme == Scheme::F32 {
+            return tensor;
+        }
+
+        assert_eq!(tensor.dt(), types::F32);
+        let dt = scheme.dt();
+        let &[.., k] = &*tensor.shape() else {
+            unreachable!()
+        };
+        let mut ans = Tensor::new(dt, &tensor.shape()).map(Blob::new);
+        let ([], src, []) = (unsafe { tensor.get().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let row_size = k / dt.group_size() * dt.nbytes();
+        for (src, dst) in src
+            .chunks_exact(k)
+            .zip(ans.get_mut().chunks_exact_mut(row_size))
+        {
+            quant_row(dt, src, dst)
+        }
+        ans
+    });
+    (gpt2, schemes)
+}
+
+/// 将（可能量化的）模型保存为 safetensors。
+///
+/// 量化张量以 `u8` 存储，其方案与逻辑形状记录在元数据中，由 [`super::Checkpoint::gpt2`] 还原。
+pub fn save_gpt2(path: impl AsRef<Path>, gpt2: &Gpt2<Blob>) -> Result<(), ImportError> {
+    let config = &gpt2.config;
+    let mut meta = HashMap::from([
+        ("n_seq".into(), config.n_seq.to_string()),
+        ("n_voc".into(), config.n_voc.to_string()),
+        (
+            "padded_vocab_size".into(),
+            config.padded_vocab_size.to_string(),
+        ),
+        ("nblk".into(), config.nblk.to_string()),
+        ("nh".into(), config.nh.to_string()),
+        ("d".into(), config.d.to_string()),
+        ("n_mtp".into(), config.n_mtp.to_string()),
+    ]);
+
+    let mut views = Vec::new();
+    gpt2.for_each(|name, tensor| {
+        let dtype = match tensor.dt() {
+            types::F32 => Dtype::F32,
+            types::F16 => Dtype::F16,
+            types::BF16 => Dtype::BF16,
+            dt => {
+                let scheme = Scheme::from_dt(dt).unwrap();
+                let shape = tensor
+                    .shape()
+                    .iter()
+                    .map(usize::to_string)
+                    .collect::<Vec<_>>();
+                meta.insert(name.into(), format!("{scheme};{}", shape.join(",")));
+                Dtype::U8
+            }
+        };
+        let shape = match dtype {
+            Dtype::U8 => vec![tensor.get().len()],
+            _ => tensor.shape().to_vec(),
+        };
+        views.push((
+            name.to_string(),
+            RawView {
+                dtype,
+                shape,
+                data: tensor.get(),
+            },
+        ))
+    });
+
+    Ok(serialize_to_file(views, &Some(meta), path.as_ref())?)
+}
+
+/// 借用原始字节的 safetensors 视图。
+pub(crate) struct RawView<'a> {
+    pub dtype: Dtype,
+    pub shape: Vec<usize>,
+    pub data: &'a [u8],
+}
+
+impl View for RawView<'_> {
+    fn dtype(&self) -> Dtype {
+        self.dtype
+    }
+
+    fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        self.data.into()
+    }
+
+    fn data_len(&self) -> usize {
+        self.data.len()
+    }
+}
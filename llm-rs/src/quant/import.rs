@@ -0,0 +1,304 @@
+use super::{Scheme, q4g, write_q4g};
+use crate::{
+    Blob, Tensor,
+    llmc::{Gpt2, Gpt2Config},
+    prefetch,
+};
+use digit_layout::types;
+use half::{bf16, f16};
+use memmap2::Mmap;
+use safetensors::{Dtype, SafeTensorError, SafeTensors, tensor::Metadata};
+use std::{collections::HashMap, fmt, fs::File, io, path::Path};
+
+/// 社区常见的 int4 按组量化格式。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// AutoGPTQ：`qweight` 沿输入维度打包，零点存储为 `zero - 1`。
+    Gptq,
+    /// AutoAWQ（GEMM）：`qweight` 沿输出维度按 `[0, 2, 4, 6, 1, 3, 5, 7]` 交错打包。
+    Awq,
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    SafeTensors(SafeTensorError),
+    Missing(String),
+    Dtype(String, Dtype),
+    Shape(String, Vec<usize>),
+    /// GPTQ 的 act-order（`desc_act`）使组不再连续，无法表示为 [`q4g`]。
+    ActOrder(String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "io error: {e}"),
+            Self::SafeTensors(e) => write!(f, "safetensors error: {e:?}"),
+            Self::Missing(name) => write!(f, "tensor {name} not found"),
+            Self::Dtype(name, dt) => write!(f, "tensor {name} has unexpected dtype {dt:?}"),
+            Self::Shape(name, shape) => write!(f, "tensor {name} has unexpected shape {shape:?}"),
+            Self::ActOrder(name) => write!(f, "{name} uses act-order, which is not supported"),
+        }
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<SafeTensorError> for ImportError {
+    fn from(value: SafeTensorError) -> Self {
+        Self::SafeTensors(value)
+    }
+}
+
+/// 内存映射的 safetensors 检查点。
+pub struct Checkpoint {
+    mmap: Mmap,
+    offset: usize,
+    meta: Metadata,
+}
+
+impl Checkpoint {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ImportError> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file) }?;
+        let (n, meta) = SafeTensors::read_metadata(&mmap)?;
+        Ok(Self {
+            mmap,
+            offset: n + 8,
+            meta,
+        })
+    }
+
+    /// 通知内核即将读取这些张量，不存在的名字被忽略。
+    pub fn prefetch<'a>(&self, names: impl IntoIterator<Item = &'a str>) {
+        for name in names {
+            if let Some(info) = self.meta.info(name) {
+                let (start, end) = info.data_offsets;
+                prefetch::will_need(&self.mmap, self.offset + start..self.offset + end)
+            }
+        }
+    }
+
+    /// safetensors 头部的 `__metadata__`。
+    pub fn metadata(&self) -> Option<&HashMap<String, String>> {
+        self.meta.metadata().as_ref()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = String> {
+        self.meta.tensors().into_keys()
+    }
+
+    /// 取出原始张量。
+    pub fn tensor(&self, name: &str) -> Result<(Dtype, Vec<usize>, &[u8]), ImportError> {
+        let info = self
+            .meta
+            .info(name)
+            .ok_or_else(|| ImportError::Missing(name.into()))?;
+        let (start, end) = info.data_offsets;
+        Ok((
+            info.dtype,
+            info.shape.clone(),
+            &self.mmap[self.offset + start..self.offset + end],
+        ))
+    }
+
+    /// 读取张量，保持存储类型；由 [`super::save_gpt2`] 保存的量化张量还原为量化类型。
+    pub fn load(&self, name: &str) -> Result<Tensor<Blob>, ImportError> {
+        let (dtype, shape, data) = self.tensor(name)?;
+        let (dt, shape) = match self.meta.metadata().as_ref().and_then(|m| m.get(name)) {
+            Some(desc) => {
+                let bad = || ImportError::Shape(name.into(), shape.clone());
+                let (scheme, shape) = desc.split_once(';').ok_or_else(bad)?;
+                let scheme = scheme.parse::<Scheme>().map_err(|_| bad())?;
+                let shape = shape
+                    .split(',')
+                    .map(|d| d.parse().map_err(|_| bad()))
+                    .collect::<Result<Vec<usize>, _>>()?;
+                (scheme.dt(), shape)
+            }
+            None => {
+                let dt = match dtype {
+                    Dtype::F32 => types::F32,
+                    Dtype::F16 => types::F16,
+                    Dtype::BF16 => types::BF16,
+                    Dtype::I32 => types::I32,
+                    Dtype::U16 => types::U16,
+                    Dtype::U8 => types::U8,
+                    dt => return Err(ImportError::Dtype(name.into(), dt)),
+                };
+                (dt, shape)
+            }
+        };
+        let mut ans = Tensor::new(dt, &shape).map(Blob::new);
+        if ans.get().len() != data.len() {
+            return Err(ImportError::Shape(name.into(), shape));
+        }
+        ans.get_mut().copy_from_slice(data);
+        Ok(ans)
+    }
+
+    /// 读取由 [`super::save_gpt2`] 保存的模型。
+    pub fn gpt2(&self) -> Result<Gpt2<Blob>, ImportError> {
+        let meta = self.meta.metadata().as_ref();
+        let get = |key: &str| {
+            meta.and_then(|m| m.get(key))
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| ImportError::Missing(format!("__metadata__.{key}")))
+        };
+        let config = Gpt2Config {
+            n_seq: get("n_seq")?,
+            n_voc: get("n_voc")?,
+            padded_vocab_size: get("padded_vocab_size")?,
+            nblk: get("nblk")?,
+            nh: get("nh")?,
+            d: get("d")?,
+            n_mtp: get("n_mtp").unwrap_or(0),
+        };
+
+        // 开始读取一层时预取下一层
+        let names = self.names().collect::<Vec<_>>();
+        let mut prefetched = None;
+        let mut err = None;
+        let gpt2 = Gpt2::from_fn(config, |name| {
+            let blk = name
+                .strip_prefix("blk.")
+                .and_then(|tail| tail.split('.').next());
+            if let Some(i) = blk.and_then(|i| i.parse::<usize>().ok())
+                && prefetched != Some(i)
+            {
+                prefetched = Some(i);
+                let next = format!("blk.{}.", i + 1);
+                self.prefetch(names.iter().filter(|n| n.starts_with(&next)).map(|n| &**n))
+            }
+            // 出错后用不分配内存的空张量占位，结果会被丢弃
+            self.load(name).unwrap_or_else(|e| {
+                err.get_or_insert(e);
+                Tensor::new(types::U8, &[0]).map(|len| Blob::lazy(len, false))
+            })
+        });
+        match err {
+            Some(e) => Err(e),
+            None => Ok(gpt2),
+        }
+    }
+
+    /// 读取浮点张量并转换为 f32。
+    pub fn f32(&self, name: &str) -> Result<Tensor<Blob>, ImportError> {
+        let (dtype, shape, data) = self.tensor(name)?;
+        let mut ans = Tensor::new(types::F32, &shape).map(Blob::new);
+        let dst = ans.get_mut();
+        let ([], dst, []) = (unsafe { dst.align_to_mut::<f32>() }) else {
+            unreachable!()
+        };
+        match dtype {
+            Dtype::F32 => {
+                for (dst, src) in dst.iter_mut().zip(data.chunks_exact(4)) {
+                    *dst = f32::from_le_bytes(src.try_into().unwrap())
+                }
+            }
+            Dtype::F16 => {
+                for (dst, src) in dst.iter_mut().zip(data.chunks_exact(2)) {
+                    *dst = f16::from_le_bytes([src[0], src[1]]).to_f32()
+                }
+            }
+            Dtype::BF16 => {
+                for (dst, src) in dst.iter_mut().zip(data.chunks_exact(2)) {
+                    *dst = bf16::from_le_bytes([src[0], src[1]]).to_f32()
+                }
+            }
+            dt => return Err(ImportError::Dtype(name.into(), dt)),
+        }
+        Ok(ans)
+    }
+
+    /// 导入 `prefix` 下的量化线性层权重，转换为形状 `[n_out, n_in]` 的 [`q4g`] 张量。
+    pub fn quantized_linear(
+        &self,
+        prefix: &str,
+        format: Format,
+    ) -> Result<Tensor<Blob>, ImportError> {
+        let name = |s: &str| format!("{prefix}.{s}");
+
+        let (dt, qweight_shape, qweight) = self.tensor(&name("qweight"))?;
+        expect_dtype(&name("qweight"), dt, Dtype::I32)?;
+        let (dt, qzeros_shape, qzeros) = self.tensor(&name("qzeros"))?;
+        expect_dtype(&name("qzeros"), dt, Dtype::I32)?;
+        let (dt, scales_shape, scales) = self.tensor(&name("scales"))?;
+        expect_dtype(&name("scales"), dt, Dtype::F16)?;
+
+        let &[n_group, n] = &*scales_shape else {
+            return Err(ImportError::Shape(name("scales"), scales_shape));
+        };
+        let k = match (format, &*qweight_shape) {
+            (Format::Gptq, &[k8, n_]) if n_ == n => k8 * 8,
+            (Format::Awq, &[k, n8]) if n8 * 8 == n => k,
+            _ => return Err(ImportError::Shape(name("qweight"), qweight_shape)),
+        };
+        if qzeros_shape != [n_group, n / 8] || k % n_group != 0 {
+            return Err(ImportError::Shape(name("qzeros"), qzeros_shape));
+        }
+        let group = k / n_group;
+
+        if format == Format::Gptq
+            && let Ok((dt, _, g_idx)) = self.tensor(&name("g_idx"))
+        {
+            expect_dtype(&name("g_idx"), dt, Dtype::I32)?;
+            if (0..k).any(|i| read_i32(g_idx, i) as usize != i / group) {
+                return Err(ImportError::ActOrder(prefix.into()));
+            }
+        }
+
+        let dt = q4g(group);
+        let mut ans = Tensor::new(dt, &[n, k]).map(Blob::new);
+        let blocks = ans.get_mut().chunks_exact_mut(dt.nbytes());
+        for (i, block) in blocks.enumerate() {
+            let (j, g) = (i / n_group, i % n_group);
+            let scale = f16::from_le_bytes([scales[2 * (g * n + j)], scales[2 * (g * n + j) + 1]]);
+            let zero = match format {
+                Format::Gptq => unpack(read_i32(qzeros, g * n / 8 + j / 8), j % 8) + 1,
+                Format::Awq => unpack(read_i32(qzeros, g * n / 8 + j / 8), AWQ_SHIFT[j % 8]),
+            };
+            write_q4g(block, scale, f16::from_f32(zero as _), |i| {
+                let k = g * group + i;
+                match format {
+                    Format::Gptq => unpack(read_i32(qweight, k / 8 * n + j), k % 8),
+                    Format::Awq => unpack(read_i32(qweight, k * n / 8 + j / 8), AWQ_SHIFT[j % 8]),
+                }
+            })
+        }
+        Ok(ans)
+    }
+}
+
+/// AWQ 打包顺序 `[0, 2, 4, 6, 1, 3, 5, 7]` 的逆序。
+const AWQ_SHIFT: [usize; 8] = [0, 4, 1, 5, 2, 6, 3, 7];
+
+fn expect_dtype(name: &str, dt: Dtype, expected: Dtype) -> Result<(), ImportError> {
+    if dt == expected {
+        Ok(())
+    } else {
+        Err(ImportError::Dtype(name.into(), dt))
+    }
+}
+
+fn read_i32(data: &[u8], i: usize) -> i32 {
+    i32::from_le_bytes(data[4 * i..][..4].try_into().unwrap())
+}
+
+fn unpack(packed: i32, i: usize) -> u8 {
+    ((packed as u32 >> (4 * i)) & 0xf) as _
+}
+
+#[test]
+fn test_awq_order() {
+    const ORDER: [usize; 8] = [0, 2, 4, 6, 1, 3, 5, 7];
+    let packed = (0..8).fold(0u32, |acc, i| acc | ((ORDER[i] as u32) << (4 * i))) as i32;
+    for (col, &shift) in AWQ_SHIFT.iter().enumerate() {
+        assert_eq!(unpack(packed, shift) as usize, col)
+    }
+}
@@ -0,0 +1,150 @@
+//! 量化权重的数据类型、导入、导出与误差评估。
+
+mod calibrate;
+mod export;
+mod import;
+mod recipe;
+
+pub use calibrate::{Report, calibrate};
+pub(crate) use export::RawView;
+pub use export::{QUANTIZABLE, quantize_gpt2, save_gpt2};
+pub use import::{Checkpoint, Format, ImportError};
+pub use recipe::{Recipe, Scheme};
+
+use digit_layout::{DigitLayout, types};
+use half::{bf16, f16};
+
+/// 按块 int8 对称量化，每块内存布局为 `[scale: f16, q: [i8; 32]]`。
+pub const Q8_0: DigitLayout = DigitLayout::named("q8_0", 32, 34);
+
+/// 按组 int4 量化（带零点），每组内存布局为 `[scale: f16, zero: f16, q: [u4; group]]`。
+///
+/// 反量化为 `(q - zero) * scale`，低 4 位在前。
+pub const fn q4g(group: usize) -> DigitLayout {
+    DigitLayout::named("q4g", group as _, (4 + group / 2) as _)
+}
+
+/// 判断是否为需要反量化才能参与 f32 计算的类型。
+pub fn is_quantized(dt: DigitLayout) -> bool {
+    let group = dt.group_size();
+    matches!(dt, types::F16 | types::BF16 | Q8_0) || (group > 1 && dt == q4g(group))
+}
+
+/// 将一行量化数据反量化为 f32。
+pub fn dequant_row(dt: DigitLayout, src: &[u8], dst: &mut [f32]) {
+    let group = dt.group_size();
+    assert_eq!(src.len(), dst.len() / group * dt.nbytes());
+
+    let blocks = src
+        .chunks_exact(dt.nbytes())
+        .zip(dst.chunks_exact_mut(group));
+    match dt {
+        types::F16 => blocks.for_each(|(x, y)| y[0] = f16::from_le_bytes([x[0], x[1]]).to_f32()),
+        types::BF16 => blocks.for_each(|(x, y)| y[0] = bf16::from_le_bytes([x[0], x[1]]).to_f32()),
+        Q8_0 => blocks.for_each(|(x, y)| read_q8_0(x, y)),
+        _ if dt == q4g(group) => blocks.for_each(|(x, y)| read_q4g(x, y)),
+        _ => todo!("Unsupported data type {dt}"),
+    }
+}
+
+/// 将一行 f32 数据量化。
+pub fn quant_row(dt: DigitLayout, src: &[f32], dst: &mut [u8]) {
+    let group = dt.group_size();
+    assert_eq!(dst.len(), src.len() / group * dt.nbytes());
+
+    let blocks = src
+        .chunks_exact(group)
+        .zip(dst.chunks_exact_mut(dt.nbytes()));
+    match dt {
+        types::F32 => blocks.for_each(|(x, y)| y.copy_from_slice(&x[0].to_le_bytes())),
+        types::F16 => {
+            blocks.for_each(|(x, y)| y.copy_from_slice(&f16::from_f32(x[0]).to_le_bytes()))
+        }
+        types::BF16 => {
+            blocks.for_each(|(x, y)| y.copy_from_slice(&bf16::from_f32(x[0]).to_le_bytes()))
+        }
+        Q8_0 => blocks.for_each(|(x, y)| write_q8_0(y, x)),
+        _ if dt == q4g(group) => blocks.for_each(|(x, y)| {
+            let min = x.iter().copied().fold(0., f32::min);
+            let max = x.iter().copied().fold(0., f32::max);
+            let scale = (max - min) / 15.;
+            let zero = if scale == 0. {
+                0.
+            } else {
+                (-min / scale).round()
+            };
+            let rscale = if scale == 0. { 0. } else { scale.recip() };
+            write_q4g(y, f16::from_f32(scale), f16::from_f32(zero), |i| {
+                (x[i] * rscale + zero).round().clamp(0., 15.) as _
+            })
+        }),
+        _ => todo!("Unsupported data type {dt}"),
+    }
+}
+
+fn read_q8_0(block: &[u8], dst: &mut [f32]) {
+    let [s0, s1, q @ ..] = block else {
+        unreachable!()
+    };
+    let scale = f16::from_le_bytes([*s0, *s1]).to_f32();
+    for (q, dst) in q.iter().zip(dst) {
+        *dst = *q as i8 as f32 * scale
+    }
+}
+
+fn write_q8_0(block: &mut [u8], src: &[f32]) {
+    let [s0, s1, body @ ..] = block else {
+        unreachable!()
+    };
+    let amax = src.iter().fold(0., |acc: f32, x| acc.max(x.abs()));
+    let scale = amax / 127.;
+    let rscale = if scale == 0. { 0. } else { scale.recip() };
+    [*s0, *s1] = f16::from_f32(scale).to_le_bytes();
+    for (q, x) in body.iter_mut().zip(src) {
+        *q = (x * rscale).round() as i8 as u8
+    }
+}
+
+fn read_q4g(block: &[u8], dst: &mut [f32]) {
+    let [s0, s1, z0, z1, q @ ..] = block else {
+        unreachable!()
+    };
+    let scale = f16::from_le_bytes([*s0, *s1]).to_f32();
+    let zero = f16::from_le_bytes([*z0, *z1]).to_f32();
+    for (q, dst) in q.iter().zip(dst.chunks_exact_mut(2)) {
+        dst[0] = ((q & 0xf) as f32 - zero) * scale;
+        dst[1] = ((q >> 4) as f32 - zero) * scale;
+    }
+}
+
+fn write_q4g(block: &mut [u8], scale: f16, zero: f16, mut q: impl FnMut(usize) -> u8) {
+    let [s0, s1, z0, z1, body @ ..] = block else {
+        unreachable!()
+    };
+    [*s0, *s1] = scale.to_le_bytes();
+    [*z0, *z1] = zero.to_le_bytes();
+    for (i, byte) in body.iter_mut().enumerate() {
+        *byte = q(2 * i) | (q(2 * i + 1) << 4)
+    }
+}
+
+#[test]
+fn test_quant_row() {
+    let src = (0..256)
+        .map(|i| ((i * 37 % 101) as f32 - 50.) / 25.)
+        .collect::<Vec<_>>();
+    for (scheme, tol) in [
+        (Scheme::F16, 1e-3),
+        (Scheme::Q8_0, 2e-2),
+        (Scheme::Q4g(64), 2e-1),
+    ] {
+        let dt = scheme.dt();
+        let mut buf = vec![0; src.len() / dt.group_size() * dt.nbytes()];
+        let mut dst = vec![0.; src.len()];
+        quant_row(dt, &src, &mut buf);
+        dequant_row(dt, &buf, &mut dst);
+        for (a, b) in src.iter().zip(&dst) {
+            assert!((a - b).abs() < tol, "{scheme}: {a} vs {b}")
+        }
+    }
+}
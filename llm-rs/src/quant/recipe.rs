@@ -0,0 +1,109 @@
+use super::{Q8_0, q4g};
+use digit_layout::{DigitLayout, types};
+use globset::{Glob, GlobMatcher};
+use std::{fmt, str::FromStr};
+
+/// 单个张量的量化方案。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scheme {
+    F32,
+    F16,
+    Q8_0,
+    Q4g(usize),
+}
+
+impl Scheme {
+    pub fn dt(self) -> DigitLayout {
+        match self {
+            Self::F32 => types::F32,
+            Self::F16 => types::F16,
+            Self::Q8_0 => Q8_0,
+            Self::Q4g(group) => q4g(group),
+        }
+    }
+
+    pub fn from_dt(dt: DigitLayout) -> Option<Self> {
+        [Self::F32, Self::F16, Self::Q8_0, Self::Q4g(dt.group_size())]
+            .into_iter()
+            .find(|s| s.dt() == dt)
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "f32" => Ok(Self::F32),
+            "f16" => Ok(Self::F16),
+            "q8_0" | "q8" => Ok(Self::Q8_0),
+            s => s
+                .strip_prefix("q4g")
+                .and_then(|g| g.parse().ok())
+                .filter(|g: &usize| *g >= 2 && g.is_multiple_of(2))
+                .map(Self::Q4g)
+                .ok_or_else(|| format!("unknown quantization scheme \"{s}\"")),
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::F32 => write!(f, "f32"),
+            Self::F16 => write!(f, "f16"),
+            Self::Q8_0 => write!(f, "q8_0"),
+            Self::Q4g(group) => write!(f, "q4g{group}"),
+        }
+    }
+}
+
+/// 按张量名字选择量化方案的配方。
+///
+/// 规则按添加顺序匹配，第一个匹配的规则生效；没有规则匹配的张量保持 f32。
+/// 文本格式每行一条 `<glob> = <scheme>`，`#` 开始注释，例如：
+///
+/// ```text
+/// blk.0.*        = q8_0
+/// blk.11.*       = q8_0
+/// *.attn_o.w     = f16
+/// *              = q4g128
+/// ```
+#[derive(Clone, Default)]
+pub struct Recipe {
+    rules: Vec<(GlobMatcher, Scheme)>,
+}
+
+impl Recipe {
+    pub fn rule(mut self, pattern: &str, scheme: Scheme) -> Self {
+        let glob = Glob::new(pattern).unwrap().compile_matcher();
+        self.rules.push((glob, scheme));
+        self
+    }
+
+    pub fn scheme(&self, name: &str) -> Scheme {
+        self.rules
+            .iter()
+            .find(|(glob, _)| glob.is_match(name))
+            .map_or(Scheme::F32, |&(_, scheme)| scheme)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut ans = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((pattern, scheme)) = line.split_once('=') else {
+                return Err(format!("line {}: expect `<glob> = <scheme>`", i + 1));
+            };
+            let glob = Glob::new(pattern.trim())
+                .map_err(|e| format!("line {}: {e}", i + 1))?
+                .compile_matcher();
+            let scheme = scheme.parse().map_err(|e| format!("line {}: {e}", i + 1))?;
+            ans.rules.push((glob, scheme))
+        }
+        Ok(ans)
+    }
+}
@@ -0,0 +1,143 @@
+//! 可复现模式：固定线程数、改用批不变的内核，并为每次生成记录复现清单。
+//!
+//! 打开后同一台机器上相同的清单总是得到逐位相同的输出，审计时按清单中的模型、词序列、
+//! 采样参数和种子重新生成即可核对。
+
+use crate::sampler::SamplerConfig;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// 可复现模式的设置，见 [`crate::engine::Engine::deterministic`]。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Determinism {
+    /// rayon 全局线程池的线程数，gemm 的分块随线程数变化。
+    pub threads: usize,
+    /// 模型的标识，如检查点文件的 [`crate::journal::hash_file`]，原样记入清单。
+    pub model: String,
+}
+
+#[derive(Debug)]
+pub enum ReproError {
+    /// 全局线程池已经以其他线程数建立。
+    Threads { requested: usize, actual: usize },
+}
+
+impl fmt::Display for ReproError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Threads { requested, actual } => write!(
+                f,
+                "thread pool already has {actual} threads, cannot pin it to {requested}"
+            ),
+        }
+    }
+}
+
+impl Determinism {
+    /// 固定全局线程池的线程数，影响进程中的所有模型。
+    ///
+    /// 批不变的内核按上下文打开，见 [`crate::Context::set_batch_invariant`]。
+    ///
+    /// rayon 的全局线程池只能建立一次，已经建立时线程数必须与设置相同，所以应在任何计算之前调用。
+    pub fn enable(&self) -> Result<(), ReproError> {
+        let requested = self.threads;
+        let pinned = rayon::ThreadPoolBuilder::new()
+            .num_threads(requested)
+            .build_global()
+            .is_ok();
+        let actual = rayon::current_num_threads();
+        if !pinned && actual != requested {
+            return Err(ReproError::Threads { requested, actual });
+        }
+        Ok(())
+    }
+
+    /// 当前环境下一次生成的清单，`batch_invariant` 和 `compensated_sum` 取自生成所用的上下文，
+    /// 采样参数和种子由调用者填写。
+    pub fn manifest(
+        &self,
+        batch_invariant: bool,
+        compensated_sum: bool,
+        prompt: Vec<u16>,
+        max_tokens: usize,
+        eos: Option<u16>,
+    ) -> Manifest {
+        Manifest {
+            version: env!("CARGO_PKG_VERSION").into(),
+            target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            threads: rayon::current_num_threads(),
+            batch_invariant,
+            compensated_sum,
+            model: self.model.clone(),
+            prompt,
+            max_tokens,
+            eos,
+            sampler: None,
+            seed: None,
+        }
+    }
+}
+
+/// 复现一次生成所需的全部信息。
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    /// 本库的版本。
+    pub version: String,
+    /// 目标架构和操作系统。
+    pub target: String,
+    pub threads: usize,
+    pub batch_invariant: bool,
+    pub compensated_sum: bool,
+    pub model: String,
+    /// 送入模型的完整词序列，包括系统提示和历史轮次。
+    pub prompt: Vec<u16>,
+    pub max_tokens: usize,
+    pub eos: Option<u16>,
+    /// 采样参数，由调用者自行采样时为 `None`。
+    pub sampler: Option<SamplerConfig>,
+    pub seed: Option<u64>,
+}
+
+#[test]
+fn test_determinism() {
+    use crate::{
+        engine::Engine,
+        llmc::{Gpt2, Gpt2Config},
+    };
+    use rand::{SeedableRng, rngs::StdRng};
+    use rw_rc::RwRc;
+    use std::time::Duration;
+
+    let determinism = Determinism {
+        threads: rayon::current_num_threads(),
+        model: "tiny-7".into(),
+    };
+    let model = Gpt2::random(Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7)).map(RwRc::new);
+    let run = || {
+        let mut engine = Engine::new(model.clone(), 8, 16, Duration::from_secs(60));
+        engine.deterministic(determinism.clone()).unwrap();
+        let id = engine.create_session(&[1, 2, 3]);
+        let sampler = SamplerConfig::default();
+        engine
+            .generate_seeded(id, &[4, 5], 8, sampler, 42, |_| &b"x"[..])
+            .unwrap()
+    };
+    let a = run();
+    let b = run();
+    assert_eq!(a.tokens, b.tokens);
+    assert_eq!(a.manifest, b.manifest);
+
+    // 清单记录完整的词序列和采样参数，可以序列化后用来重新生成
+    let manifest = a.manifest.unwrap();
+    assert_eq!(manifest.prompt, [1, 2, 3, 4, 5]);
+    assert_eq!(manifest.seed, Some(42));
+    let json = serde_json::to_string(&manifest).unwrap();
+    assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest);
+
+    // 线程池已经建立，不能改为其他线程数
+    let other = Determinism {
+        threads: determinism.threads + 1,
+        ..determinism
+    };
+    assert!(matches!(other.enable(), Err(ReproError::Threads { .. })))
+}
@@ -0,0 +1,170 @@
+//! 奖励模型：给完整的词序列打分，用于 best-of-n 采样、RLHF 和数据过滤。
+
+use crate::{
+    Blob, Context, Tensor, llmc,
+    nn::{gpt2::Gpt2, linear::Linear},
+};
+use digit_layout::types;
+use rand::Rng;
+use rw_rc::RwRc;
+use std::f32::consts::PI;
+
+/// 给词序列打分，分数越高越好。
+///
+/// 任何 `FnMut(&[u16]) -> f32` 都是奖励模型，可以直接用规则函数过滤数据。
+pub trait RewardModel {
+    fn score(&mut self, tokens: &[u16]) -> f32;
+
+    /// 对多个序列打分，默认逐个调用 [`Self::score`]。
+    fn score_batch(&mut self, seqs: &[&[u16]]) -> Vec<f32> {
+        seqs.iter().map(|tokens| self.score(tokens)).collect()
+    }
+}
+
+impl<F: FnMut(&[u16]) -> f32> RewardModel for F {
+    fn score(&mut self, tokens: &[u16]) -> f32 {
+        self(tokens)
+    }
+}
+
+/// 以 GPT-2 为主干的奖励模型。
+///
+/// 主干输出归一化后的隐藏状态（见 [`Gpt2::hidden_states`]），形状为 `[1, d]` 的价值头
+/// 把最后一个词的隐藏状态线性投影为分数。
+pub struct Gpt2Reward {
+    ctx: Context,
+    gpt2: Gpt2,
+    value_head: Linear,
+    n_ctx: usize,
+}
+
+impl Gpt2Reward {
+    /// `w` 为长度 `d` 的价值头权重，`b` 为偏置。
+    pub fn new(model: llmc::Gpt2<RwRc<Blob>>, w: &[f32], b: f32) -> Self {
+        let d = model.config.d;
+        let n_ctx = model.config.n_seq;
+        assert_eq!(w.len(), d);
+
+        let mut ctx = Context::new(false);
+        let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+        gpt2.hidden_states(true);
+        let f32s = |shape: &[usize], data: &[f32]| {
+            let data: &[u8] = unsafe { data.align_to::<u8>().1 };
+            Tensor::new(types::F32, shape)
+                .map(|_| RwRc::new(Blob::from(data)))
+                .share()
+        };
+        let value_head = ctx.init("value_head", (f32s(&[1, d], w), Some(f32s(&[1], &[b]))));
+        Self {
+            ctx,
+            gpt2,
+            value_head,
+            n_ctx,
+        }
+    }
+
+    /// 价值头按正态分布随机初始化，标准差为 `1 / sqrt(d + 1)`，偏置为 0。
+    pub fn random(model: llmc::Gpt2<RwRc<Blob>>, rng: &mut impl Rng) -> Self {
+        let d = model.config.d;
+        let std = 1. / ((d + 1) as f32).sqrt();
+        // Box-Muller
+        let w = (0..d)
+            .map(|_| {
+                let u = 1. - rng.random::<f32>();
+                let v = rng.random::<f32>();
+                std * (-2. * u.ln()).sqrt() * (2. * PI * v).cos()
+            })
+            .collect::<Vec<_>>();
+        Self::new(model, &w, 0.)
+    }
+}
+
+impl RewardModel for Gpt2Reward {
+    fn score(&mut self, tokens: &[u16]) -> f32 {
+        self.score_batch(&[tokens])[0]
+    }
+
+    /// 右填充到最长的序列一起前向，因果注意力保证填充不影响有效位置。
+    fn score_batch(&mut self, seqs: &[&[u16]]) -> Vec<f32> {
+        let Self {
+            ctx,
+            gpt2,
+            value_head,
+            n_ctx,
+        } = self;
+        let Some(n_seq) = seqs.iter().map(|tokens| tokens.len()).max() else {
+            return vec![];
+        };
+        assert!(seqs.iter().all(|tokens| !tokens.is_empty()));
+        assert!(n_seq <= *n_ctx, "sequence exceeds n_ctx {n_ctx}");
+
+        let mut buf = vec![0u16; seqs.len() * n_seq];
+        for (dst, tokens) in buf.chunks_mut(n_seq).zip(seqs) {
+            dst[..tokens.len()].copy_from_slice(tokens)
+        }
+        let tokens = Tensor::new(types::U16, &[seqs.len(), n_seq])
+            .map(|_| RwRc::new(buf[..].into()))
+            .share();
+        let x = ctx.forward("gpt2", gpt2, [tokens]);
+        let y = ctx.forward("value_head", value_head, x);
+
+        // 输出是连续的 `[batch, n_seq, 1]`
+        let y = &y[0];
+        let ([], buf, []) = (unsafe { y.get().read().align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let scores = seqs
+            .iter()
+            .enumerate()
+            .map(|(i, tokens)| buf[i * n_seq + tokens.len() - 1])
+            .collect();
+        y.get().release();
+        scores
+    }
+}
+
+#[test]
+fn test_reward() {
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::iter::zip;
+
+    let config = llmc::Gpt2Config {
+        nblk: 1,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let d = config.d;
+    let mut rng = StdRng::seed_from_u64(0);
+    let model = llmc::Gpt2::random(config, &mut rng).map(RwRc::new);
+    let w = (0..d).map(|i| (i % 5) as f32 - 2.).collect::<Vec<_>>();
+    let mut reward = Gpt2Reward::new(model.clone(), &w, 0.5);
+
+    // 分数是最后一个词的隐藏状态与价值头的点积加偏置
+    let seqs: [&[u16]; 3] = [&[1, 2, 3], &[4], &[5, 6, 7, 8, 9]];
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+    gpt2.hidden_states(true);
+    let expected = seqs
+        .iter()
+        .map(|&seq| {
+            let tokens = Tensor::new(types::U16, &[1, seq.len()])
+                .map(|_| RwRc::new(seq.into()))
+                .share();
+            let x = ctx.forward("gpt2", &mut gpt2, [tokens]);
+            let x = x[0].cloned().index(&[0, seq.len() - 1]);
+            let x = x.as_ref().map(|b| &**b.read()).vector::<f32>();
+            zip(x, &w).map(|(x, w)| x * w).sum::<f32>() + 0.5
+        })
+        .collect::<Vec<_>>();
+
+    let batch = reward.score_batch(&seqs);
+    for (seq, (expected, batch)) in zip(seqs, zip(expected, batch)) {
+        let single = reward.score(seq);
+        assert!((single - expected).abs() < 1e-4, "{single} vs {expected}");
+        assert!((batch - expected).abs() < 1e-4, "{batch} vs {expected}")
+    }
+
+    // 规则函数也是奖励模型
+    let mut by_len = |tokens: &[u16]| -(tokens.len() as f32);
+    assert_eq!(by_len.score_batch(&seqs), [-3., -1., -5.]);
+}
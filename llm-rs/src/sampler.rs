@@ -0,0 +1,647 @@
+//! 从 logits 采样下一个词：温度、top-k、top-p（核采样）、min-p 和 mirostat，以及采样前的惩罚、logit 偏置和允许词集合。
+
+use crate::{Blob, Tensor, op::topk};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use rw_rc::RwRc;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// 采样参数，按温度缩放、top-k、top-p 的顺序作用。
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct SamplerConfig {
+    /// 温度，`0` 为贪心解码。
+    pub temperature: f32,
+    /// 只在概率最大的 `top_k` 个词中采样，`0` 表示不限制。
+    pub top_k: usize,
+    /// 只在累积概率达到 `top_p` 的最少的词中采样，`1` 表示不限制。
+    pub top_p: f32,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 1.,
+            top_k: 0,
+            top_p: 1.,
+        }
+    }
+}
+
+/// 带种子的采样器，相同的种子和 logits 得到相同的词。
+pub struct Sampler {
+    config: SamplerConfig,
+    chain: SamplerChain,
+}
+
+impl SamplerConfig {
+    /// 等价的采样链步骤，可以与惩罚一起组成 [`SamplingState`]。
+    pub fn steps(&self) -> Vec<SamplerStep> {
+        let &Self {
+            temperature,
+            top_k,
+            top_p,
+        } = self;
+        assert!(temperature >= 0.);
+        assert!(0. < top_p && top_p <= 1.);
+        // top-k 与温度无关，先截断可以少算缩放
+        if temperature == 0. {
+            vec![SamplerStep::Temperature(0.)]
+        } else {
+            vec![
+                SamplerStep::TopK(top_k),
+                SamplerStep::Temperature(temperature),
+                SamplerStep::TopP(top_p),
+            ]
+        }
+    }
+}
+
+impl Sampler {
+    pub fn new(config: SamplerConfig, seed: u64) -> Self {
+        Self {
+            config,
+            chain: SamplerChain::new(config.steps(), seed),
+        }
+    }
+
+    pub fn config(&self) -> &SamplerConfig {
+        &self.config
+    }
+
+    /// 从有效词表的 logits 中采样一个词，可以直接作为生成函数的 `sample` 参数。
+    pub fn sample(&mut self, logits: &[f32]) -> u16 {
+        self.chain.sample(logits)
+    }
+
+    /// 对 `[..., n_voc_padded]` 的 logits 逐行采样，只考虑前 `n_voc` 个词。
+    pub fn sample_tensor(&mut self, logits: &Tensor<RwRc<Blob>>, n_voc: usize) -> Vec<u16> {
+        let n_voc_padded = *logits.shape().last().unwrap();
+        let buf = logits.get().read();
+        let ([], buf, []) = (unsafe { buf.align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let ans = buf
+            .chunks_exact(n_voc_padded)
+            .map(|row| self.sample(&row[..n_voc]))
+            .collect();
+        logits.get().release();
+        ans
+    }
+}
+
+/// [`SamplerChain`] 中的一步，依次作用于候选词。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SamplerStep {
+    /// logits 除以温度，`0` 只保留最大值。
+    Temperature(f32),
+    /// 只保留最大的 `k` 个。
+    TopK(usize),
+    /// 只保留累积概率首次达到 `p` 的最少的词。
+    TopP(f32),
+    /// 只保留概率不低于最大概率 `p` 倍的词。
+    MinP(f32),
+    /// Mirostat v2：丢弃信息量（`-log2 p`）超过 `mu` 的词，采样后按观测到的信息量与目标 `tau`
+    /// 的差以学习率 `eta` 调整 `mu`，使生成文本的信息量保持在 `tau` 附近。应当放在最后。
+    Mirostat { tau: f32, eta: f32 },
+}
+
+/// 按顺序组合的采样步骤，最后在剩余的候选中按 softmax 概率采样。
+///
+/// Mirostat 的 `mu` 是跨步的状态，每个请求应当使用自己的采样链。
+pub struct SamplerChain {
+    steps: Vec<SamplerStep>,
+    /// 各步的 mirostat 状态，初始为 `2 tau`。
+    mus: Vec<f32>,
+    rng: StdRng,
+}
+
+impl SamplerChain {
+    pub fn new(steps: Vec<SamplerStep>, seed: u64) -> Self {
+        let mus = steps
+            .iter()
+            .map(|step| match *step {
+                SamplerStep::Temperature(t) => {
+                    assert!(t >= 0.);
+                    0.
+                }
+                SamplerStep::TopP(p) | SamplerStep::MinP(p) => {
+                    assert!((0. ..=1.).contains(&p));
+                    0.
+                }
+                SamplerStep::Mirostat { tau, .. } => 2. * tau,
+                SamplerStep::TopK(_) => 0.,
+            })
+            .collect();
+        Self {
+            steps,
+            mus,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn steps(&self) -> &[SamplerStep] {
+        &self.steps
+    }
+
+    /// 从有效词表的 logits 中采样一个词，可以直接作为生成函数的 `sample` 参数。
+    ///
+    /// logits 为负无穷的词被禁止，不会被选中。
+    pub fn sample(&mut self, logits: &[f32]) -> u16 {
+        let mut candidates = Candidates {
+            items: logits
+                .iter()
+                .copied()
+                .enumerate()
+                .filter(|&(_, x)| x != f32::NEG_INFINITY)
+                .collect(),
+            sorted: false,
+        };
+        assert!(!candidates.items.is_empty(), "all tokens are banned");
+        let mut mirostat = Vec::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            match *step {
+                SamplerStep::Temperature(t) => candidates.temperature(t),
+                SamplerStep::TopK(k) => candidates.top_k(k),
+                SamplerStep::TopP(p) => candidates.top_p(p),
+                SamplerStep::MinP(p) => candidates.min_p(p),
+                SamplerStep::Mirostat { .. } => {
+                    candidates.max_surprise(self.mus[i]);
+                    mirostat.push(i)
+                }
+            }
+        }
+
+        let probs = softmax(candidates.items.iter().map(|&(_, x)| x));
+        let sum = probs.iter().sum::<f32>();
+        let chosen = pick(&probs, self.rng.random());
+        let surprise = -(probs[chosen] / sum).log2();
+        for i in mirostat {
+            let SamplerStep::Mirostat { tau, eta } = self.steps[i] else {
+                unreachable!()
+            };
+            self.mus[i] -= eta * (surprise - tau)
+        }
+        candidates.items[chosen].0 as _
+    }
+}
+
+/// 采样链中剩余的候选词及其 logits，`sorted` 表示已按 logits 从大到小排列。
+struct Candidates {
+    items: Vec<(usize, f32)>,
+    sorted: bool,
+}
+
+impl Candidates {
+    fn temperature(&mut self, t: f32) {
+        if t == 0. {
+            let best = topk::argmax(&self.logits());
+            self.items = vec![self.items[best]];
+            self.sorted = true
+        } else {
+            self.items.iter_mut().for_each(|(_, x)| *x /= t)
+        }
+    }
+
+    fn top_k(&mut self, k: usize) {
+        if k > 0 && k < self.items.len() {
+            self.items = topk::top_k(&self.logits(), k)
+                .into_iter()
+                .map(|(i, _)| self.items[i])
+                .collect();
+            self.sorted = true
+        }
+    }
+
+    fn top_p(&mut self, p: f32) {
+        if p >= 1. {
+            return;
+        }
+        self.sort();
+        let probs = softmax(self.items.iter().map(|&(_, x)| x));
+        let limit = probs.iter().sum::<f32>() * p;
+        let mut acc = 0.;
+        let n = probs
+            .iter()
+            .position(|&p| {
+                acc += p;
+                acc >= limit
+            })
+            .map_or(probs.len(), |i| i + 1);
+        self.items.truncate(n)
+    }
+
+    fn min_p(&mut self, p: f32) {
+        let max = self.logits().into_iter().fold(f32::NEG_INFINITY, f32::max);
+        // 相对最大概率的比值就是 logits 之差的指数
+        let threshold = max + p.ln();
+        self.items.retain(|&(_, x)| x >= threshold)
+    }
+
+    fn max_surprise(&mut self, mu: f32) {
+        self.sort();
+        let probs = softmax(self.items.iter().map(|&(_, x)| x));
+        let sum = probs.iter().sum::<f32>();
+        let n = probs
+            .iter()
+            .take_while(|&&p| -(p / sum).log2() <= mu)
+            .count();
+        self.items.truncate(n.max(1))
+    }
+
+    fn sort(&mut self) {
+        if !self.sorted {
+            self.items
+                .sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+            self.sorted = true
+        }
+    }
+
+    fn logits(&self) -> Vec<f32> {
+        self.items.iter().map(|&(_, x)| x).collect()
+    }
+}
+
+/// 根据序列中已有的词调整 logits 的惩罚，在采样前作用。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Penalties {
+    /// 重复惩罚：出现过的词正的 logits 除以它、负的乘以它，`1` 表示不惩罚。
+    pub repeat: f32,
+    /// 频率惩罚：logits 减去它乘以出现次数。
+    pub frequency: f32,
+    /// 存在惩罚：出现过的词 logits 减去它。
+    pub presence: f32,
+    /// 只统计最近的词数，`0` 表示整个序列。
+    pub last_n: usize,
+}
+
+impl Default for Penalties {
+    fn default() -> Self {
+        Self {
+            repeat: 1.,
+            frequency: 0.,
+            presence: 0.,
+            last_n: 0,
+        }
+    }
+}
+
+/// 把 `bias` 中各词的偏置加到 `logits` 上，偏置为负无穷时禁止这个词，超出词表的词被忽略。
+pub fn apply_logit_bias(logits: &mut [f32], bias: &HashMap<u16, f32>) {
+    for (&token, &b) in bias {
+        if let Some(x) = logits.get_mut(token as usize) {
+            *x += b
+        }
+    }
+}
+
+/// 允许采样的词集合，按位存储，每步以 `O(n_voc / 64)` 屏蔽 logits。
+///
+/// 适合每步固定的限制，如只输出数字或只输出标签中出现的词；需要按已生成内容变化的限制用
+/// [`crate::grammar::Matcher`]。
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TokenMask {
+    bits: Vec<u64>,
+}
+
+impl TokenMask {
+    /// 不允许任何词的空集合。
+    pub fn new(n_voc: usize) -> Self {
+        Self {
+            bits: vec![0; n_voc.div_ceil(64)],
+        }
+    }
+
+    pub fn from_tokens(n_voc: usize, tokens: impl IntoIterator<Item = u16>) -> Self {
+        let mut mask = Self::new(n_voc);
+        for token in tokens {
+            mask.insert(token)
+        }
+        mask
+    }
+
+    /// 允许解码后满足 `pred` 的词，如 `|t| t.iter().all(u8::is_ascii_digit)`。
+    pub fn from_decoded<'a>(
+        n_voc: usize,
+        decode: impl Fn(u16) -> &'a [u8],
+        pred: impl Fn(&[u8]) -> bool,
+    ) -> Self {
+        Self::from_tokens(n_voc, (0..n_voc as u16).filter(|&t| pred(decode(t))))
+    }
+
+    pub fn insert(&mut self, token: u16) {
+        let i = token as usize;
+        assert!(
+            i < self.bits.len() * 64,
+            "token {token} is out of vocabulary"
+        );
+        self.bits[i / 64] |= 1 << (i % 64)
+    }
+
+    pub fn contains(&self, token: u16) -> bool {
+        let i = token as usize;
+        self.bits
+            .get(i / 64)
+            .is_some_and(|w| w >> (i % 64) & 1 == 1)
+    }
+
+    /// 允许的词数。
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// 把不允许的词的 logits 置为负无穷，超出集合范围的填充部分也被屏蔽。
+    pub fn apply(&self, logits: &mut [f32]) {
+        for (i, chunk) in logits.chunks_mut(64).enumerate() {
+            let word = self.bits.get(i).copied().unwrap_or(0);
+            if word == !0 {
+                continue;
+            }
+            for (j, x) in chunk.iter_mut().enumerate() {
+                if word >> j & 1 == 0 {
+                    *x = f32::NEG_INFINITY
+                }
+            }
+        }
+    }
+}
+
+/// 一个请求的采样状态：采样链、惩罚、logit 偏置、允许词集合和这个序列的词历史，每个请求使用自己的状态。
+pub struct SamplingState {
+    chain: SamplerChain,
+    penalties: Penalties,
+    logit_bias: HashMap<u16, f32>,
+    allowed: Option<TokenMask>,
+    history: VecDeque<u16>,
+    counts: HashMap<u16, usize>,
+}
+
+impl SamplingState {
+    pub fn new(chain: SamplerChain, penalties: Penalties) -> Self {
+        assert!(penalties.repeat > 0.);
+        Self {
+            chain,
+            penalties,
+            logit_bias: HashMap::new(),
+            allowed: None,
+            history: VecDeque::new(),
+            counts: HashMap::new(),
+        }
+    }
+
+    /// 每步在惩罚之后加到 logits 上的偏置，见 [`apply_logit_bias`]。
+    pub fn logit_bias(&mut self, bias: HashMap<u16, f32>) {
+        self.logit_bias = bias
+    }
+
+    /// 每步只在 `mask` 中的词里采样，生成需要停止时应把结束词也放进去。
+    pub fn allowed(&mut self, mask: TokenMask) {
+        assert!(mask.count() > 0, "no token is allowed");
+        self.allowed = Some(mask)
+    }
+
+    /// 记入序列中的一个词，提示中的词也可以记入以参与惩罚。
+    pub fn accept(&mut self, token: u16) {
+        *self.counts.entry(token).or_default() += 1;
+        self.history.push_back(token);
+        let last_n = self.penalties.last_n;
+        if last_n > 0 && self.history.len() > last_n {
+            let old = self.history.pop_front().unwrap();
+            let count = self.counts.get_mut(&old).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&old);
+            }
+        }
+    }
+
+    /// 按历史调整 `logits`。
+    pub fn apply_penalties(&self, logits: &mut [f32]) {
+        let Penalties {
+            repeat,
+            frequency,
+            presence,
+            ..
+        } = self.penalties;
+        for (&token, &count) in &self.counts {
+            let Some(x) = logits.get_mut(token as usize) else {
+                continue;
+            };
+            *x = if *x > 0. { *x / repeat } else { *x * repeat };
+            *x -= frequency * count as f32 + presence
+        }
+    }
+
+    /// 施加惩罚、偏置和允许词集合后用采样链选出下一个词并记入历史，可以直接作为生成函数的 `sample` 参数。
+    pub fn sample(&mut self, logits: &[f32]) -> u16 {
+        let mut logits = logits.to_vec();
+        self.apply_penalties(&mut logits);
+        apply_logit_bias(&mut logits, &self.logit_bias);
+        if let Some(mask) = &self.allowed {
+            mask.apply(&mut logits)
+        }
+        let token = self.chain.sample(&logits);
+        self.accept(token);
+        token
+    }
+}
+
+/// 未归一化的概率。
+fn softmax(logits: impl Iterator<Item = f32> + Clone) -> Vec<f32> {
+    let max = logits.clone().fold(f32::NEG_INFINITY, f32::max);
+    logits.map(|x| (x - max).exp()).collect()
+}
+
+/// 按未归一化的概率 `p` 选择位置，`coin` 为 `[0, 1)` 的均匀随机数。
+fn pick(p: &[f32], coin: f32) -> usize {
+    let limit = p.iter().sum::<f32>() * coin;
+    let mut acc = 0.;
+    for (i, &x) in p.iter().enumerate() {
+        acc += x;
+        if acc > limit {
+            return i;
+        }
+    }
+    p.iter().rposition(|&x| x > 0.).unwrap()
+}
+
+#[test]
+fn test_sampler() {
+    let logits = [1., 3., 2., 0., 2.5];
+    let histogram = |config: SamplerConfig| {
+        let mut sampler = Sampler::new(config, 0);
+        let mut counts = [0; 5];
+        for _ in 0..10_000 {
+            counts[sampler.sample(&logits) as usize] += 1
+        }
+        counts
+    };
+
+    // 贪心
+    let greedy = SamplerConfig {
+        temperature: 0.,
+        ..Default::default()
+    };
+    assert_eq!(histogram(greedy), [0, 10_000, 0, 0, 0]);
+    // 不截断时频率接近 softmax
+    let counts = histogram(SamplerConfig::default());
+    let sum = logits.iter().map(|x: &f32| x.exp()).sum::<f32>();
+    for (count, x) in counts.iter().zip(logits) {
+        let freq = *count as f32 / 10_000.;
+        assert!((freq - x.exp() / sum).abs() < 0.02, "{freq}")
+    }
+    // top-k 只保留最大的两个
+    let top_k = SamplerConfig {
+        top_k: 2,
+        ..Default::default()
+    };
+    let counts = histogram(top_k);
+    assert_eq!([counts[0], counts[2], counts[3]], [0; 3]);
+    assert!(counts[1] > counts[4] && counts[4] > 0);
+    // 最大的概率约 0.46，top-p 0.8 需要前三个
+    let top_p = SamplerConfig {
+        top_p: 0.8,
+        ..Default::default()
+    };
+    let counts = histogram(top_p);
+    assert_eq!([counts[0], counts[3]], [0; 2]);
+    assert!(counts[2] > 0);
+    // 低温集中在最大值上
+    let cold = SamplerConfig {
+        temperature: 0.05,
+        ..Default::default()
+    };
+    assert_eq!(histogram(cold)[1], 10_000);
+    // 相同种子结果相同
+    assert_eq!(histogram(top_p), counts);
+
+    let tensor = Tensor::new(digit_layout::types::F32, &[2, 1, 8]).map(|_| {
+        let data = [
+            0., 5., 0., 0., 0., 0., 0., 0., 0., 0., 0., 5., 0., 0., 9., 9f32,
+        ];
+        RwRc::new(Blob::from(&data[..]))
+    });
+    let mut sampler = Sampler::new(greedy, 0);
+    assert_eq!(sampler.sample_tensor(&tensor, 5), [1, 3])
+}
+
+#[test]
+fn test_sampler_chain() {
+    let logits = [1., 3., 2., 0., 2.5];
+    let histogram = |steps: Vec<SamplerStep>| {
+        let mut chain = SamplerChain::new(steps, 0);
+        let mut counts = [0; 5];
+        for _ in 0..10_000 {
+            counts[chain.sample(&logits) as usize] += 1
+        }
+        counts
+    };
+
+    // 相对最大概率：0.14, 1, 0.37, 0.05, 0.61
+    let counts = histogram(vec![SamplerStep::MinP(0.3)]);
+    assert_eq!([counts[0], counts[3]], [0; 2]);
+    assert!(counts[2] > 0 && counts[4] > counts[2]);
+    // 先 top-k 再 min-p
+    let counts = histogram(vec![SamplerStep::TopK(2), SamplerStep::MinP(0.3)]);
+    assert_eq!([counts[0], counts[2], counts[3]], [0; 3]);
+
+    // mirostat 的目标信息量越低，选择越集中
+    let logits = (0..64).map(|i| -(i as f32) / 8.).collect::<Vec<_>>();
+    let distinct = |tau: f32| {
+        let mut chain = SamplerChain::new(vec![SamplerStep::Mirostat { tau, eta: 0.1 }], 0);
+        let mut seen = [false; 64];
+        let mut top = 0;
+        for _ in 0..2000 {
+            let t = chain.sample(&logits) as usize;
+            seen[t] = true;
+            top += (t == 0) as usize
+        }
+        (seen.iter().filter(|&&x| x).count(), top)
+    };
+    let (narrow, top) = distinct(0.5);
+    let (wide, _) = distinct(6.);
+    assert!(top > 1000, "{top}");
+    assert!(narrow < wide, "{narrow} vs {wide}")
+}
+
+#[test]
+fn test_penalties() {
+    let greedy = || SamplerChain::new(vec![SamplerStep::Temperature(0.)], 0);
+    let penalties = Penalties {
+        repeat: 2.,
+        frequency: 0.5,
+        presence: 1.,
+        last_n: 3,
+    };
+    let mut state = SamplingState::new(greedy(), penalties);
+    for t in [0, 1, 1, 2] {
+        state.accept(t)
+    }
+    // 窗口中是 1, 1, 2
+    let mut logits = [4., 4., -1., 0.5];
+    state.apply_penalties(&mut logits);
+    assert_eq!(logits, [4., 2. - 1. - 1., -2. - 0.5 - 1., 0.5]);
+
+    // 存在惩罚足够大时贪心解码不再重复
+    let mut state = SamplingState::new(
+        greedy(),
+        Penalties {
+            presence: 100.,
+            ..Default::default()
+        },
+    );
+    let logits = [3., 2., 1., 0.];
+    let tokens = (0..4).map(|_| state.sample(&logits)).collect::<Vec<_>>();
+    assert_eq!(tokens, [0, 1, 2, 3])
+}
+
+#[test]
+fn test_logit_bias() {
+    let logits = [3., 2., 1., 0.];
+    let mut biased = logits;
+    apply_logit_bias(
+        &mut biased,
+        &HashMap::from([(0, f32::NEG_INFINITY), (3, 2.5), (9, 1.)]),
+    );
+    assert_eq!(biased, [f32::NEG_INFINITY, 2., 1., 2.5]);
+
+    // 偏置强制输出某个词
+    let greedy = || SamplerChain::new(vec![SamplerStep::Temperature(0.)], 0);
+    let mut state = SamplingState::new(greedy(), Penalties::default());
+    state.logit_bias(HashMap::from([(2, 100.)]));
+    assert_eq!(state.sample(&logits), 2);
+
+    // 被禁止的词在任何温度下都不会被采到
+    let banned = HashMap::from([(0, f32::NEG_INFINITY), (1, f32::NEG_INFINITY)]);
+    let chain = SamplerChain::new(vec![SamplerStep::Temperature(2.)], 1);
+    let mut state = SamplingState::new(chain, Penalties::default());
+    state.logit_bias(banned);
+    assert!((0..100).all(|_| state.sample(&logits) >= 2))
+}
+
+#[test]
+fn test_token_mask() {
+    let mask = TokenMask::from_tokens(70, [1, 3, 65]);
+    assert_eq!(mask.count(), 3);
+    assert!(mask.contains(65) && !mask.contains(64) && !mask.contains(200));
+    // 填充部分也被屏蔽
+    let mut logits = vec![0.; 72];
+    mask.apply(&mut logits);
+    let kept = (0..72).filter(|&i| logits[i] == 0.).collect::<Vec<_>>();
+    assert_eq!(kept, [1, 3, 65]);
+
+    let text = [&b"1"[..], b"a", b"42", b"4x"];
+    let digits = TokenMask::from_decoded(
+        4,
+        |t| text[t as usize],
+        |t| t.iter().all(u8::is_ascii_digit),
+    );
+    assert_eq!(digits, TokenMask::from_tokens(4, [0, 2]));
+
+    // 高温下也只采到允许的词
+    let logits = [3., 2., 1., 0.];
+    let chain = SamplerChain::new(vec![SamplerStep::Temperature(2.)], 1);
+    let mut state = SamplingState::new(chain, Penalties::default());
+    state.logit_bias(HashMap::from([(1, 100.)]));
+    state.allowed(digits);
+    assert!((0..100).all(|_| matches!(state.sample(&logits), 0 | 2)))
+}
@@ -0,0 +1,314 @@
+//! 连续批处理：请求结束后立即让排队的请求加入正在解码的批。
+
+use crate::{
+    Blob, Context, Tensor, generate::FinishReason, llmc, nn::gpt2::Gpt2, truncate::Truncation,
+};
+use digit_layout::types;
+use rw_rc::RwRc;
+use serde::Serialize;
+use std::{collections::VecDeque, rc::Rc};
+
+/// 提交给 [`Scheduler`] 的请求号。
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize)]
+pub struct RequestId(u64);
+
+/// 一个结束的请求。
+#[derive(Clone, Debug, Serialize)]
+pub struct Completion {
+    pub id: RequestId,
+    /// 截断后实际使用的提示长度。
+    pub n_prompt: usize,
+    pub tokens: Vec<u16>,
+    pub logprobs: Vec<f32>,
+    pub finish_reason: FinishReason,
+}
+
+struct Waiting {
+    id: RequestId,
+    prompt: Vec<u16>,
+    max_tokens: usize,
+}
+
+struct Running {
+    id: RequestId,
+    /// 在分页 KV 缓存中的序列号。
+    seq: usize,
+    /// 为这个请求预留的块数，按最长可能的序列计算。
+    blocks: usize,
+    n_prompt: usize,
+    max_tokens: usize,
+    tokens: Vec<u16>,
+    logprobs: Vec<f32>,
+}
+
+/// 连续批处理的生成调度器。
+///
+/// 所有请求共享一个分页 KV 缓存，每次 [`Self::step`] 先把正在运行的请求各解码一个词，
+/// 它们的序列长度可以不同，再在批大小和空闲块允许时按提交顺序接纳排队的请求并预填充。
+/// 接纳时按提示加上 `max_tokens` 预留块，运行中的请求不会因块不足而中断。
+pub struct Scheduler {
+    ctx: Context,
+    gpt2: Gpt2,
+    n_ctx: usize,
+    n_voc: usize,
+    block_size: usize,
+    n_blocks: usize,
+    max_batch: usize,
+    eos: Option<u16>,
+    waiting: VecDeque<Waiting>,
+    running: Vec<Running>,
+    next_id: u64,
+}
+
+impl Scheduler {
+    /// 分页 KV 缓存共 `n_blocks` 个 `block_size` 行的块，同时解码的请求不超过 `max_batch` 个。
+    pub fn new(
+        model: llmc::Gpt2<RwRc<Blob>>,
+        block_size: usize,
+        n_blocks: usize,
+        max_batch: usize,
+    ) -> Self {
+        assert!(max_batch > 0);
+        let n_ctx = model.config.n_seq;
+        let n_voc = model.config.n_voc;
+        let mut ctx = Context::new(false);
+        let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+        gpt2.paged_kv_cache(block_size, n_blocks);
+        Self {
+            ctx,
+            gpt2,
+            n_ctx,
+            n_voc,
+            block_size,
+            n_blocks,
+            max_batch,
+            eos: None,
+            waiting: VecDeque::new(),
+            running: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// 生成此词时停止，它不计入结果。
+    pub fn eos(&mut self, eos: Option<u16>) {
+        self.eos = eos
+    }
+
+    /// 提交一个请求，它在之后的 [`Self::step`] 中被接纳。提示过长时保留结尾。
+    pub fn submit(&mut self, prompt: &[u16], max_tokens: usize) -> RequestId {
+        assert!(!prompt.is_empty());
+        let prompt = Truncation::KeepTail.apply(prompt, self.n_ctx);
+        assert!(
+            self.blocks(prompt.len(), max_tokens) <= self.n_blocks,
+            "request can never fit in the KV cache"
+        );
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        self.waiting.push_back(Waiting {
+            id,
+            prompt,
+            max_tokens,
+        });
+        id
+    }
+
+    /// 正在解码的请求数。
+    pub fn n_running(&self) -> usize {
+        self.running.len()
+    }
+
+    /// 排队等待的请求数。
+    pub fn n_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.running.is_empty() && self.waiting.is_empty()
+    }
+
+    /// 推进一步：正在运行的请求各解码一个词，再接纳排队的请求，返回这一步结束的请求。
+    pub fn step(&mut self, mut sample: impl FnMut(&[f32]) -> u16) -> Vec<Completion> {
+        let mut done = Vec::new();
+
+        if !self.running.is_empty() {
+            let ids = self.running.iter().map(|r| r.seq).collect::<Vec<_>>();
+            let input = self
+                .running
+                .iter()
+                .map(|r| *r.tokens.last().unwrap())
+                .collect::<Vec<_>>();
+            self.gpt2.select_sequences(&ids);
+            let logits = self.forward(&input, [input.len(), 1]);
+            for (i, running) in self.running.iter_mut().enumerate() {
+                let logits = logits.cloned().index(&[i, 0]);
+                let logits = &logits.as_ref().map(|b| &**b.read()).vector::<f32>()[..self.n_voc];
+                push(running, logits, &mut sample, self.eos)
+            }
+            self.retire(&mut done)
+        }
+
+        while let Some(next) = self.waiting.front() {
+            let reserved = self.running.iter().map(|r| r.blocks).sum::<usize>();
+            let blocks = self.blocks(next.prompt.len(), next.max_tokens);
+            if self.running.len() == self.max_batch || reserved + blocks > self.n_blocks {
+                break;
+            }
+            let Waiting {
+                id,
+                prompt,
+                max_tokens,
+            } = self.waiting.pop_front().unwrap();
+            let mut running = Running {
+                id,
+                seq: self.gpt2.add_sequence(),
+                blocks,
+                n_prompt: prompt.len(),
+                max_tokens,
+                tokens: prompt,
+                logprobs: Vec::new(),
+            };
+            if max_tokens > 0 {
+                self.gpt2.select_sequences(&[running.seq]);
+                let len = running.n_prompt;
+                let logits = self.forward(&running.tokens, [1, len]);
+                let logits = logits.cloned().index(&[0, len - 1]);
+                let logits = &logits.as_ref().map(|b| &**b.read()).vector::<f32>()[..self.n_voc];
+                push(&mut running, logits, &mut sample, self.eos)
+            }
+            tracing::debug!(?id, n_prompt = running.n_prompt, "admitted");
+            self.running.push(running);
+            self.retire(&mut done)
+        }
+        done
+    }
+
+    fn forward(&mut self, tokens: &[u16], shape: [usize; 2]) -> Rc<Tensor<RwRc<Blob>>> {
+        let tokens = Tensor::new(types::U16, &shape)
+            .map(|_| Blob::from(tokens))
+            .map(RwRc::new);
+        let logits = self.ctx.forward("gpt2", &mut self.gpt2, [tokens.share()]);
+        logits.into_iter().next().unwrap()
+    }
+
+    /// 最长可能的序列占用的块数。
+    fn blocks(&self, n_prompt: usize, max_tokens: usize) -> usize {
+        (n_prompt + max_tokens)
+            .min(self.n_ctx)
+            .div_ceil(self.block_size)
+    }
+
+    /// 移出结束的请求，归还它们的块。
+    fn retire(&mut self, done: &mut Vec<Completion>) {
+        let mut i = 0;
+        while i < self.running.len() {
+            let running = &self.running[i];
+            let generated = running.tokens.len() - running.n_prompt;
+            let finish_reason = if running.logprobs.len() < generated {
+                // 生成了结束词，它不计入结果
+                Some(FinishReason::Stop)
+            } else if generated == running.max_tokens || running.tokens.len() > self.n_ctx {
+                Some(FinishReason::Length)
+            } else {
+                None
+            };
+            let Some(finish_reason) = finish_reason else {
+                i += 1;
+                continue;
+            };
+            let mut running = self.running.remove(i);
+            self.gpt2.remove_sequence(running.seq);
+            if finish_reason == FinishReason::Stop {
+                running.tokens.pop();
+            }
+            tracing::debug!(id = ?running.id, ?finish_reason, "finished");
+            done.push(Completion {
+                id: running.id,
+                n_prompt: running.n_prompt,
+                tokens: running.tokens.split_off(running.n_prompt),
+                logprobs: running.logprobs,
+                finish_reason,
+            })
+        }
+    }
+}
+
+/// 从 `logits` 采样下一个词接在请求之后，结束词不记录对数概率，由 [`Scheduler::retire`] 识别。
+fn push(
+    running: &mut Running,
+    logits: &[f32],
+    sample: &mut impl FnMut(&[f32]) -> u16,
+    eos: Option<u16>,
+) {
+    let next = sample(logits);
+    running.tokens.push(next);
+    if Some(next) != eos {
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>();
+        running
+            .logprobs
+            .push(logits[next as usize] - max - sum.ln())
+    }
+}
+
+#[test]
+fn test_scheduler() {
+    use crate::{
+        generate::{GenerationConfig, generate_cached},
+        op::topk,
+    };
+    use rand::{SeedableRng, rngs::StdRng};
+    use std::collections::HashMap;
+
+    let model = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7))
+        .map(RwRc::new);
+    let argmax = |logits: &[f32]| topk::argmax(logits) as u16;
+    let requests = [
+        (&[1, 2, 3][..], 5),
+        (&[4, 5, 6, 7, 8, 9][..], 2),
+        (&[10][..], 7),
+        (&[11, 12][..], 3),
+        (&[13; 60][..], 8),
+    ];
+
+    // 逐个单独生成作为参照
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model.clone());
+    let expected = requests.map(|(prompt, max_tokens)| {
+        let config = GenerationConfig {
+            n_ctx: 64,
+            n_voc: 64,
+            max_tokens,
+            eos: None,
+            truncation: Truncation::KeepTail,
+            echo: false,
+            stop: None,
+            cost: Default::default(),
+        };
+        generate_cached(&mut ctx, "gpt2", &mut gpt2, prompt, &config, argmax, |_| {
+            &[]
+        })
+    });
+
+    let mut scheduler = Scheduler::new(model, 8, 12, 2);
+    let ids = requests.map(|(prompt, max_tokens)| scheduler.submit(prompt, max_tokens));
+    let mut results = HashMap::new();
+    let mut steps = 0;
+    while !scheduler.is_idle() {
+        for completion in scheduler.step(argmax) {
+            results.insert(completion.id, completion);
+        }
+        assert!(scheduler.n_running() <= 2);
+        steps += 1
+    }
+    // 请求结束后立即接纳新的请求，总步数少于逐个处理
+    assert!(steps < requests.iter().map(|(_, n)| n).sum::<usize>());
+
+    for (id, expected) in ids.iter().zip(&expected) {
+        let result = &results[id];
+        assert_eq!(result.tokens, expected.tokens);
+        assert_eq!(result.finish_reason, expected.finish_reason);
+        for (a, b) in result.logprobs.iter().zip(&expected.logprobs) {
+            assert!((a - b).abs() < 1e-4, "{a} vs {b}")
+        }
+    }
+}
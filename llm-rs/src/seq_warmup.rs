@@ -0,0 +1,82 @@
+//! 序列长度预热：训练初期使用短序列，逐步增长到完整长度，每步的词数基本不变。
+
+/// 按步数决定每批的形状 `[batch_size, seq_len]`。
+#[derive(Clone, Copy, Debug)]
+pub struct SeqLenWarmup {
+    /// 每步的目标词数，通常是完整长度时的 `batch_size * max_seq_len`。
+    pub tokens_per_step: usize,
+    pub min_seq_len: usize,
+    pub max_seq_len: usize,
+    /// 序列长度线性增长到 `max_seq_len` 所用的步数。
+    pub warmup_steps: usize,
+    /// 序列长度取此数的倍数，减少不同形状的数量。
+    pub multiple_of: usize,
+}
+
+impl SeqLenWarmup {
+    /// 第 `step` 步的批形状，`batch_size` 取使总词数不超过 `tokens_per_step` 的最大值，至少为 1。
+    pub fn shape(&self, step: usize) -> [usize; 2] {
+        let &Self {
+            tokens_per_step,
+            min_seq_len,
+            max_seq_len,
+            warmup_steps,
+            multiple_of,
+        } = self;
+        assert!(0 < min_seq_len && min_seq_len <= max_seq_len && multiple_of > 0);
+
+        let seq_len = if step >= warmup_steps {
+            max_seq_len
+        } else {
+            min_seq_len + (max_seq_len - min_seq_len) * step / warmup_steps
+        };
+        let seq_len = (seq_len / multiple_of * multiple_of).clamp(min_seq_len, max_seq_len);
+        [(tokens_per_step / seq_len).max(1), seq_len]
+    }
+
+    /// 第 `step` 步的词数。
+    pub fn tokens(&self, step: usize) -> usize {
+        let [batch_size, seq_len] = self.shape(step);
+        batch_size * seq_len
+    }
+
+    /// 前 `step` 步累计的词数，用于按词数计量训练进度。
+    pub fn tokens_before(&self, step: usize) -> usize {
+        (0..step).map(|s| self.tokens(s)).sum()
+    }
+
+    /// 学习率的缩放系数：本步词数与目标词数之比，整除时为 1。
+    pub fn lr_scale(&self, step: usize) -> f32 {
+        self.tokens(step) as f32 / self.tokens_per_step as f32
+    }
+}
+
+#[test]
+fn test_seq_len_warmup() {
+    use crate::llmc::DataLoader;
+
+    let warmup = SeqLenWarmup {
+        tokens_per_step: 256,
+        min_seq_len: 16,
+        max_seq_len: 64,
+        warmup_steps: 10,
+        multiple_of: 16,
+    };
+    let shapes = (0..12).map(|s| warmup.shape(s)).collect::<Vec<_>>();
+    assert_eq!(shapes[0], [16, 16]);
+    assert_eq!(shapes[5], [8, 32]);
+    assert_eq!(shapes[10], [4, 64]);
+    assert!(shapes.windows(2).all(|w| w[0][1] <= w[1][1]));
+    assert!((0..12).all(|s| warmup.lr_scale(s) <= 1.));
+    assert_eq!(warmup.tokens_before(2), 512);
+
+    // 改变形状后从词序列中的同一位置继续读取
+    let tokens = (0..1025).map(|i| i as u16).collect::<Vec<_>>();
+    let mut loader = DataLoader::from_tokens(tokens, 16, 16, false);
+    loader.load();
+    loader.reshape(4, 64);
+    assert_eq!(loader.shape(), [4, 64]);
+    assert_eq!(loader.load()[0][0], 256);
+    loader.reshape(6, 32);
+    assert_eq!(loader.load()[0][0], 6 * 32 * 3);
+}
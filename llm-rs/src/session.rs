@@ -0,0 +1,184 @@
+//! 预先分配全部缓冲区的推理会话。
+
+use crate::{Blob, Context, Graph, Tensor, llmc, nn::gpt2::Gpt2};
+use digit_layout::types;
+use rw_rc::RwRc;
+use std::rc::Rc;
+
+/// 形状固定为 `[max_batch, max_seq]` 的 GPT-2 推理会话。
+///
+/// 构造时执行一次前向并捕获所有激活缓冲区，之后每次 [`Self::forward`] 只写入输入词并重放，
+/// 不再分配激活。因果注意力保证较短的输入不受末尾填充的影响。
+/// 需要序列单独解码和在批中解码得到逐位相同的结果时，先打开 [`Self::set_batch_invariant`]。
+pub struct InferenceSession {
+    ctx: Context,
+    gpt2: Gpt2,
+    graph: Graph,
+    max_batch: usize,
+    max_seq: usize,
+    n_voc: usize,
+    tokens: Rc<Tensor<RwRc<Blob>>>,
+    logits: Rc<Tensor<RwRc<Blob>>>,
+}
+
+impl InferenceSession {
+    pub fn new(model: llmc::Gpt2<RwRc<Blob>>, max_batch: usize, max_seq: usize) -> Self {
+        let n_ctx = model.config.n_seq;
+        assert!(max_batch > 0 && max_seq > 0);
+        assert!(max_seq <= n_ctx, "max_seq exceeds n_ctx {n_ctx}");
+        let n_voc = model.config.n_voc;
+
+        let mut ctx = Context::new(false);
+        let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+        let tokens = Tensor::new(types::U16, &[max_batch, max_seq])
+            .map(Blob::new_zeroed)
+            .map(RwRc::new)
+            .share();
+        let (logits, graph) = ctx.capture(|ctx| ctx.forward("gpt2", &mut gpt2, [tokens.clone()]));
+        let logits = logits.into_iter().next().unwrap();
+        Self {
+            ctx,
+            gpt2,
+            graph,
+            max_batch,
+            max_seq,
+            n_voc,
+            tokens,
+            logits,
+        }
+    }
+
+    /// 见 [`Context::set_batch_invariant`]，只影响本会话。
+    pub fn set_batch_invariant(&mut self, enabled: bool) {
+        self.ctx.set_batch_invariant(enabled)
+    }
+
+    /// 预分配的激活缓冲区总字节数。
+    pub fn nbytes(&self) -> usize {
+        self.graph.nbytes()
+    }
+
+    /// 对每行输入执行前向，行数和长度不超过构造时的上限，其余位置以 0 填充。
+    pub fn forward(&mut self, rows: &[&[u16]]) {
+        let Self {
+            ctx,
+            gpt2,
+            graph,
+            max_batch,
+            max_seq,
+            tokens,
+            logits,
+            ..
+        } = self;
+        assert!(rows.len() <= *max_batch);
+
+        // 释放上次 `logits` 留下的读标记
+        logits.get().release();
+        {
+            let buf = tokens.get().write();
+            let ([], buf, []) = (unsafe { buf.align_to_mut::<u16>() }) else {
+                unreachable!()
+            };
+            buf.fill(0);
+            for (dst, row) in buf.chunks_mut(*max_seq).zip(rows) {
+                assert!(row.len() <= *max_seq);
+                dst[..row.len()].copy_from_slice(row)
+            }
+            tokens.get().release()
+        }
+
+        // 输出写入捕获时的缓冲区，`logits` 直接看到新的结果
+        let _ = ctx.replay(graph, |ctx| ctx.forward("gpt2", gpt2, [tokens.clone()]));
+    }
+
+    /// 上一次前向中第 `row` 行第 `pos` 个位置的 logits，只含有效词表。
+    pub fn logits(&self, row: usize, pos: usize) -> &[f32] {
+        assert!(row < self.max_batch && pos < self.max_seq);
+        let buf = self.logits.get().read();
+        let ([], buf, []) = (unsafe { buf.align_to::<f32>() }) else {
+            unreachable!()
+        };
+        let n_voc_padded = buf.len() / (self.max_batch * self.max_seq);
+        &buf[(row * self.max_seq + pos) * n_voc_padded..][..self.n_voc]
+    }
+}
+
+#[test]
+fn test_session() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let config = llmc::Gpt2Config {
+        nblk: 1,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let model = llmc::Gpt2::random(config, &mut StdRng::seed_from_u64(0)).map(RwRc::new);
+    let mut session = InferenceSession::new(model.clone(), 2, 8);
+
+    let mut ctx = Context::new(false);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+    let tokens = [1, 2, 3];
+    let tokens_ = Tensor::new(types::U16, &[1, 3])
+        .map(|_| RwRc::new(tokens[..].into()))
+        .share();
+    let y = ctx.forward("gpt2", &mut gpt2, [tokens_]);
+    let y = y[0].cloned().index(&[0, 2]);
+    let y = y.as_ref().map(|b| &**b.read()).vector::<f32>();
+
+    for _ in 0..2 {
+        session.forward(&[&[7, 7], &tokens]);
+        let logits = session.logits(1, 2);
+        for (a, b) in logits.iter().zip(y) {
+            assert!((a - b).abs() < 1e-5)
+        }
+    }
+}
+
+#[test]
+fn test_batch_invariant() {
+    use crate::op::topk::argmax;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let model = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(0))
+        .map(RwRc::new);
+    let n_new = 6;
+
+    // 单独解码：每步对 `[1, len]` 的输入做前向，前几步的矩阵乘只有一两行
+    let mut ctx = Context::new(false);
+    ctx.set_batch_invariant(true);
+    let mut gpt2 = ctx.init::<Gpt2>("gpt2", model.clone());
+    let mut alone = vec![1];
+    let mut alone_logits = Vec::new();
+    for _ in 0..n_new {
+        let len = alone.len();
+        let tokens = Tensor::new(types::U16, &[1, len])
+            .map(|_| RwRc::new(alone[..].into()))
+            .share();
+        let y = ctx.forward("gpt2", &mut gpt2, [tokens]);
+        let y = y[0].cloned().index(&[0, len - 1]);
+        let logits = y.as_ref().map(|b| &**b.read()).vector::<f32>()[..64].to_vec();
+        alone.push(argmax(&logits) as u16);
+        alone_logits.push(logits)
+    }
+
+    // 在批中解码：其他行长度不同，末尾以 0 填充
+    let mut session = InferenceSession::new(model, 4, 12);
+    session.set_batch_invariant(true);
+    let mut rows = [vec![9; 5], vec![1], vec![4, 4, 4], vec![7, 7]];
+    for logits in &alone_logits {
+        session.forward(&rows.each_ref().map(Vec::as_slice));
+        for (i, row) in rows.iter_mut().enumerate() {
+            let logits_ = session.logits(i, row.len() - 1);
+            if i == 1 {
+                assert!(
+                    logits_
+                        .iter()
+                        .zip(logits)
+                        .all(|(a, b)| a.to_bits() == b.to_bits())
+                )
+            }
+            row.push(argmax(logits_) as _)
+        }
+    }
+    assert_eq!(rows[1], alone)
+}
@@ -0,0 +1,230 @@
+//! 生成结果的快照测试。
+//!
+//! 固定模型、提示和采样种子，把生成的词序列与记录在仓库中的快照逐词比较。
+//! 采样器或算子的改动改变了输出时测试失败，确认改动符合预期后设置
+//! `LLM_RS_UPDATE_SNAPSHOTS=1` 重新运行测试即可更新快照。
+
+use crate::{
+    Context,
+    generate::{GenerationConfig, generate_cached},
+    nn::gpt2::Gpt2,
+    op::topk,
+};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::{fmt::Write, fs, io, path::Path};
+
+/// 设置此环境变量时，[`check`] 用实际输出覆盖快照文件。
+pub const UPDATE_ENV: &str = "LLM_RS_UPDATE_SNAPSHOTS";
+
+/// 一个快照用例。
+#[derive(Clone, PartialEq, Debug)]
+pub struct Case {
+    /// 用例名，在快照文件中唯一。
+    pub name: &'static str,
+    pub prompt: Vec<u16>,
+    pub max_tokens: usize,
+    /// 采样种子，`None` 表示贪心解码。
+    pub seed: Option<u64>,
+}
+
+/// 运行一个用例，返回生成的词。
+pub fn run(
+    ctx: &mut Context,
+    name: &str,
+    model: &mut Gpt2,
+    config: &GenerationConfig,
+    case: &Case,
+) -> Vec<u16> {
+    let config = GenerationConfig {
+        max_tokens: case.max_tokens,
+        ..config.clone()
+    };
+    let decode = |_| &[][..];
+    let result = match case.seed {
+        Some(seed) => {
+            let mut rng = StdRng::seed_from_u64(seed);
+            generate_cached(
+                ctx,
+                name,
+                model,
+                &case.prompt,
+                &config,
+                |logits| sample(logits, rng.random()),
+                decode,
+            )
+        }
+        None => generate_cached(
+            ctx,
+            name,
+            model,
+            &case.prompt,
+            &config,
+            |logits| topk::argmax(logits) as _,
+            decode,
+        ),
+    };
+    result.tokens
+}
+
+/// 把 `actual` 与快照文件比较，不一致时返回逐用例的差异说明。
+///
+/// 快照文件每行一个用例：`name: t0 t1 ...`。
+pub fn check(
+    path: impl AsRef<Path>,
+    actual: &[(&str, Vec<u16>)],
+) -> io::Result<Result<(), String>> {
+    let path = path.as_ref();
+    if std::env::var_os(UPDATE_ENV).is_some() {
+        fs::write(path, format(actual))?;
+        return Ok(Ok(()));
+    }
+
+    let expected = parse(&fs::read_to_string(path)?)?;
+    let mut diff = String::new();
+    for (name, tokens) in actual {
+        match expected.iter().find(|(n, _)| n == name) {
+            Some((_, expected)) if expected == tokens => {}
+            Some((_, expected)) => {
+                let pos = tokens
+                    .iter()
+                    .zip(expected)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                writeln!(diff, "{name}: diverged at token {pos}").unwrap();
+                writeln!(diff, "  expected {expected:?}").unwrap();
+                writeln!(diff, "  actual   {tokens:?}").unwrap()
+            }
+            None => writeln!(diff, "{name}: missing from snapshot").unwrap(),
+        }
+    }
+    for (name, _) in &expected {
+        if !actual.iter().any(|(n, _)| n == name) {
+            writeln!(diff, "{name}: no longer produced").unwrap()
+        }
+    }
+    Ok(if diff.is_empty() {
+        Ok(())
+    } else {
+        diff.push_str(&format!(
+            "rerun with {UPDATE_ENV}=1 to accept the new outputs"
+        ));
+        Err(diff)
+    })
+}
+
+fn format(results: &[(&str, Vec<u16>)]) -> String {
+    let mut ans = String::new();
+    for (name, tokens) in results {
+        ans.push_str(name);
+        ans.push(':');
+        for t in tokens {
+            write!(ans, " {t}").unwrap()
+        }
+        ans.push('\n')
+    }
+    ans
+}
+
+fn parse(text: &str) -> io::Result<Vec<(String, Vec<u16>)>> {
+    let invalid =
+        |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bad line {line:?}"));
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (name, tokens) = line.split_once(':').ok_or_else(|| invalid(line))?;
+            let tokens = tokens
+                .split_whitespace()
+                .map(|t| t.parse().map_err(|_| invalid(line)))
+                .collect::<io::Result<_>>()?;
+            Ok((name.trim().to_string(), tokens))
+        })
+        .collect()
+}
+
+/// 按 softmax 概率采样，`coin` 为 `[0, 1)` 的均匀随机数。
+fn sample(logits: &[f32], coin: f32) -> u16 {
+    let max = logits[topk::argmax(logits)];
+    let sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>();
+    let limit = sum * coin;
+    let mut acc = 0.;
+    for (i, x) in logits.iter().enumerate() {
+        acc += (x - max).exp();
+        if acc >= limit {
+            return i as _;
+        }
+    }
+    (logits.len() - 1) as _
+}
+
+#[test]
+fn test_generation_snapshots() {
+    use crate::{Blob, Tensor, llmc, truncate::Truncation};
+    use digit_layout::types;
+    use rw_rc::RwRc;
+
+    // 固定种子的小模型，不依赖外部权重文件。权重比训练的初始化大得多，
+    // 否则嵌入在残差中占主导，输出总是重复最后一个词
+    let config = llmc::Gpt2Config::tiny(64);
+    let mut rng = StdRng::seed_from_u64(0);
+    let gpt2 = llmc::Gpt2::from_fn(config.clone(), |name| {
+        let shape = config.shape(name);
+        let values = if name.ends_with("norm.w") {
+            vec![1f32; shape.iter().product()]
+        } else {
+            (0..shape.iter().product())
+                .map(|_| rng.random_range(-0.2..0.2))
+                .collect()
+        };
+        Tensor::new(types::F32, &shape).map(|_| Blob::from(&values[..]))
+    });
+    let config = GenerationConfig {
+        n_ctx: gpt2.config.n_seq,
+        n_voc: gpt2.config.n_voc,
+        max_tokens: 0,
+        eos: None,
+        truncation: Truncation::KeepTail,
+        echo: false,
+        stop: None,
+        cost: gpt2.config.cost(),
+    };
+    // 矩阵乘使用批不变的实现，输出不依赖 gemm 的分块方式
+    let mut ctx = Context::new(false);
+    ctx.set_batch_invariant(true);
+    let mut model = ctx.init::<Gpt2>("gpt2", gpt2.map(RwRc::new));
+
+    let cases = [
+        Case {
+            name: "greedy",
+            prompt: vec![1, 2, 3, 4, 5],
+            max_tokens: 16,
+            seed: None,
+        },
+        Case {
+            name: "greedy-single",
+            prompt: vec![42],
+            max_tokens: 16,
+            seed: None,
+        },
+        Case {
+            name: "sample-1",
+            prompt: vec![1, 2, 3, 4, 5],
+            max_tokens: 16,
+            seed: Some(1),
+        },
+        Case {
+            name: "sample-2",
+            prompt: vec![7, 7, 7],
+            max_tokens: 16,
+            seed: Some(2),
+        },
+    ];
+    let actual = cases
+        .iter()
+        .map(|case| (case.name, run(&mut ctx, "gpt2", &mut model, &config, case)))
+        .collect::<Vec<_>>();
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("snapshots/generate.snap");
+    if let Err(diff) = check(path, &actual).unwrap() {
+        panic!("generation snapshots changed:\n{diff}")
+    }
+}
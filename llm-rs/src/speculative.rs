@@ -0,0 +1,388 @@
+//! 投机解码：小的草稿模型逐词提出 `k` 个候选，目标模型一次前向批量验证，
+//! 按拒绝采样规则接受或改写，输出分布与只用目标模型采样相同。
+
+use crate::{
+    Blob, Context, Tensor,
+    generate::{
+        CacheStats, Compute, FinishReason, GenerationConfig, GenerationResult, Timing, check_stop,
+    },
+    nn::gpt2::Gpt2,
+    op::topk,
+};
+use digit_layout::types;
+use rw_rc::RwRc;
+use serde::Serialize;
+use std::time::Instant;
+
+/// 投机解码的参数。
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SpeculativeConfig {
+    /// 每轮草稿的词数。
+    pub k: usize,
+    /// 采样温度，`0` 为贪心解码，此时输出与目标模型贪心生成相同。
+    pub temperature: f32,
+}
+
+/// 草稿的接受情况。
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug, Serialize)]
+pub struct SpeculativeStats {
+    /// 目标模型验证的轮数。
+    pub rounds: usize,
+    /// 草稿模型提出的词数。
+    pub drafted: usize,
+    /// 被接受的草稿词数。
+    pub accepted: usize,
+}
+
+impl SpeculativeStats {
+    pub fn acceptance_rate(&self) -> f64 {
+        self.accepted as f64 / self.drafted.max(1) as f64
+    }
+}
+
+/// 用草稿模型 `draft` 加速目标模型 `target` 的生成，`coin` 产生 `[0, 1)` 的均匀随机数。
+///
+/// 两个模型都以 KV 缓存增量解码，被拒绝的草稿从缓存中回退。
+/// 生成前清空两个模型的缓存，模型原本没有开启缓存时生成后关闭。
+/// 对数概率、缓存统计和计算量只计入目标模型。
+#[allow(clippy::too_many_arguments)]
+pub fn generate_speculative<'a>(
+    ctx: &mut Context,
+    (target_name, target): (&str, &mut Gpt2),
+    (draft_name, draft): (&str, &mut Gpt2),
+    prompt: &[u16],
+    config: &GenerationConfig,
+    speculative: &SpeculativeConfig,
+    mut coin: impl FnMut() -> f32,
+    decode: impl Fn(u16) -> &'a [u8],
+) -> (GenerationResult, SpeculativeStats) {
+    let &GenerationConfig {
+        n_ctx,
+        n_voc,
+        max_tokens,
+        eos,
+        truncation,
+        echo,
+        ref stop,
+        cost,
+    } = config;
+    let &SpeculativeConfig { k, temperature } = speculative;
+    assert!(!prompt.is_empty() && n_ctx > 0);
+
+    let enabled = [&mut *target, &mut *draft].map(|model| {
+        let enabled = model.cached_len().is_some();
+        if enabled {
+            model.clear_kv_cache()
+        } else {
+            model.kv_cache(true)
+        }
+        let id = model.add_sequence();
+        model.select_sequences(&[id]);
+        (enabled, id)
+    });
+    let [(_, target_id), (_, draft_id)] = enabled;
+
+    let mut tokens = truncation.apply(prompt, n_ctx);
+    let n_prompt = tokens.len();
+    let mut logprobs = Vec::new();
+    let mut prompt_logprobs = None;
+    let mut text = Vec::new();
+    let mut timing = Timing::default();
+    let mut cache = CacheStats::default();
+    let mut compute = Compute::default();
+    let mut stats = SpeculativeStats::default();
+
+    let finish_reason = loop {
+        let len = tokens.len();
+        if len - n_prompt == max_tokens || len > n_ctx {
+            break FinishReason::Length;
+        }
+        let time = Instant::now();
+        // 目标模型的缓存不能超过上下文，验证后的额外一个词也不能超过 `max_tokens`
+        let k = k.min(n_ctx - len).min(max_tokens - (len - n_prompt) - 1);
+
+        let mut seq = tokens.clone();
+        let mut qs = Vec::with_capacity(k);
+        for _ in 0..k {
+            let cached = draft.sequence_len(draft_id);
+            let rows = forward(ctx, draft_name, draft, &seq[cached..], n_voc);
+            let q = probs(rows.last().unwrap(), temperature);
+            seq.push(sample(&q, coin()) as _);
+            qs.push(q)
+        }
+
+        let cached = target.sequence_len(target_id);
+        let rows = forward(ctx, target_name, target, &seq[cached..], n_voc);
+        cache.reused += cached;
+        cache.computed += seq.len() - cached;
+        compute.flops += cost.flops(seq.len()) - cost.flops(cached);
+        compute.time += time.elapsed();
+        stats.rounds += 1;
+        stats.drafted += k;
+
+        if echo && prompt_logprobs.is_none() {
+            // 第一轮从头计算，第 j 行预测第 j + 1 个词
+            let targets = &tokens[1..];
+            prompt_logprobs = Some(
+                rows.iter()
+                    .zip(targets)
+                    .map(|(logits, &t)| log_softmax(logits, t as _))
+                    .collect::<Vec<_>>(),
+            )
+        }
+
+        // 第 i 行预测第 len + i 个位置
+        let rows = &rows[len - 1 - cached..];
+        let mut finish = None;
+        for (i, logits) in rows.iter().enumerate() {
+            let p = probs(logits, temperature);
+            let (next, accepted) = match qs.get(i) {
+                Some(q) => verify(&p, q, seq[len + i] as _, &mut coin),
+                None => (sample(&p, coin()), false),
+            };
+            if Some(next as u16) == eos {
+                finish = Some(FinishReason::Stop);
+                break;
+            }
+            logprobs.push(log_softmax(logits, next));
+            tokens.push(next as _);
+            stats.accepted += accepted as usize;
+            text.extend_from_slice(decode(next as _));
+            if check_stop(stop, &tokens[n_prompt..], &mut logprobs, &mut text, &decode) {
+                tokens.truncate(n_prompt + logprobs.len());
+                finish = Some(FinishReason::Criteria);
+                break;
+            }
+            if !accepted {
+                break;
+            }
+        }
+
+        *if len == n_prompt {
+            &mut timing.prefill
+        } else {
+            &mut timing.decode
+        } += time.elapsed();
+        if let Some(reason) = finish {
+            break reason;
+        }
+        // 两个模型都只保留已确定的词，最后一个词留作下一轮的输入
+        target.truncate_sequence(target_id, tokens.len() - 1);
+        draft.truncate_sequence(draft_id, tokens.len() - 1)
+    };
+
+    for (model, (enabled, _)) in [target, draft].into_iter().zip(enabled) {
+        if !enabled {
+            model.kv_cache(false)
+        }
+    }
+
+    let tokens = tokens.split_off(n_prompt);
+    tracing::debug!(
+        n_prompt,
+        n_tokens = tokens.len(),
+        ?finish_reason,
+        rounds = stats.rounds,
+        acceptance = stats.acceptance_rate(),
+        "generated speculatively"
+    );
+    let result = GenerationResult {
+        n_prompt,
+        text: String::from_utf8_lossy(&text).into_owned(),
+        tokens,
+        logprobs,
+        prompt_logprobs,
+        finish_reason,
+        timing,
+        cache,
+        compute,
+        manifest: None,
+    };
+    (result, stats)
+}
+
+/// 对新词做一次缓存前向，返回每个位置有效词表的 logits。
+fn forward(
+    ctx: &mut Context,
+    name: &str,
+    model: &mut Gpt2,
+    tokens: &[u16],
+    n_voc: usize,
+) -> Vec<Vec<f32>> {
+    let tokens_ = Tensor::new(types::U16, &[1, tokens.len()])
+        .map(|_| Blob::from(tokens))
+        .map(RwRc::new);
+    let logits = ctx.forward(name, model, [tokens_.share()]);
+    (0..tokens.len())
+        .map(|i| {
+            let logits = logits[0].cloned().index(&[0, i]);
+            logits.as_ref().map(|b| &**b.read()).vector::<f32>()[..n_voc].to_vec()
+        })
+        .collect()
+}
+
+/// `logits` 中第 `i` 个词的对数概率。
+fn log_softmax(logits: &[f32], i: usize) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>();
+    logits[i] - max - sum.ln()
+}
+
+/// 温度 `temperature` 下的概率分布，温度为 0 时集中在最大值上。
+fn probs(logits: &[f32], temperature: f32) -> Vec<f32> {
+    if temperature == 0. {
+        let mut ans = vec![0.; logits.len()];
+        ans[topk::argmax(logits)] = 1.;
+        return ans;
+    }
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp = logits
+        .iter()
+        .map(|x| ((x - max) / temperature).exp())
+        .collect::<Vec<_>>();
+    let sum = exp.iter().sum::<f32>();
+    exp.into_iter().map(|x| x / sum).collect()
+}
+
+/// 按（未归一化的）概率 `p` 采样，`coin` 为 `[0, 1)` 的均匀随机数。
+fn sample(p: &[f32], coin: f32) -> usize {
+    let limit = p.iter().sum::<f32>() * coin;
+    let mut acc = 0.;
+    for (i, &x) in p.iter().enumerate() {
+        acc += x;
+        if acc > limit {
+            return i;
+        }
+    }
+    // 舍入误差导致没有越过时取最后一个概率非零的词
+    p.iter().rposition(|&x| x > 0.).unwrap()
+}
+
+/// 以 `min(1, p[x] / q[x])` 的概率接受从 `q` 中采样的草稿 `x`，
+/// 拒绝时从 `max(0, p - q)` 中重新采样，返回选中的词和是否接受。
+fn verify(p: &[f32], q: &[f32], x: usize, coin: &mut impl FnMut() -> f32) -> (usize, bool) {
+    if coin() * q[x] < p[x] {
+        return (x, true);
+    }
+    let residual = p
+        .iter()
+        .zip(q)
+        .map(|(p, q)| (p - q).max(0.))
+        .collect::<Vec<_>>();
+    let next = if residual.iter().any(|&x| x > 0.) {
+        sample(&residual, coin())
+    } else {
+        sample(p, coin())
+    };
+    (next, false)
+}
+
+#[test]
+fn test_verify() {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    // 从 q 中起草再验证，得到的分布应当是 p
+    let p = [0.5, 0.3, 0.2, 0.];
+    let q = [0.1, 0.2, 0.3, 0.4];
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut coin = || rng.random::<f32>();
+    let n = 100_000;
+    let mut counts = [0; 4];
+    for _ in 0..n {
+        let x = sample(&q, coin());
+        counts[verify(&p, &q, x, &mut coin).0] += 1
+    }
+    for (count, p) in counts.iter().zip(p) {
+        let freq = *count as f32 / n as f32;
+        assert!((freq - p).abs() < 0.01, "{freq} vs {p}")
+    }
+}
+
+#[test]
+fn test_generate_speculative() {
+    use crate::{generate::generate_cached, llmc, truncate::Truncation};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use std::iter::zip;
+
+    let target = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7));
+    let draft = llmc::Gpt2Config {
+        nblk: 1,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(64)
+    };
+    let draft = llmc::Gpt2::random(draft, &mut StdRng::seed_from_u64(1));
+    let config = GenerationConfig {
+        n_ctx: target.config.n_seq,
+        n_voc: target.config.n_voc,
+        max_tokens: 12,
+        eos: None,
+        truncation: Truncation::KeepTail,
+        echo: false,
+        stop: None,
+        cost: target.config.cost(),
+    };
+    let mut ctx = Context::new(false);
+    let mut same = ctx.init::<Gpt2>("same", target.clone().map(RwRc::new));
+    let mut target = ctx.init::<Gpt2>("target", target.map(RwRc::new));
+    let mut draft = ctx.init::<Gpt2>("draft", draft.map(RwRc::new));
+    let mut rng = StdRng::seed_from_u64(0);
+    let decode = |_| &b"x"[..];
+
+    let prompt = [1, 2, 3, 4, 5];
+    let expected = generate_cached(
+        &mut ctx,
+        "target",
+        &mut target,
+        &prompt,
+        &config,
+        |logits| topk::argmax(logits) as _,
+        decode,
+    );
+    let greedy = SpeculativeConfig {
+        k: 4,
+        temperature: 0.,
+    };
+    // 贪心时输出与目标模型相同，与草稿模型无关
+    for (name, draft) in [("draft", &mut draft), ("same", &mut same)] {
+        let (result, stats) = generate_speculative(
+            &mut ctx,
+            ("target", &mut target),
+            (name, draft),
+            &prompt,
+            &config,
+            &greedy,
+            || rng.random(),
+            decode,
+        );
+        assert_eq!(result.tokens, expected.tokens);
+        assert_eq!(result.text.len(), 12);
+        for (a, b) in zip(&result.logprobs, &expected.logprobs) {
+            assert!((a - b).abs() < 1e-4, "{a} vs {b}")
+        }
+        assert!(stats.accepted <= stats.drafted);
+        if name == "same" {
+            // 草稿就是目标模型时全部接受，每轮产生 k + 1 个词
+            assert_eq!(stats.accepted, stats.drafted);
+            assert_eq!(stats.rounds, 12usize.div_ceil(5))
+        }
+    }
+    assert_eq!(target.cached_len(), None);
+
+    let sampled = SpeculativeConfig {
+        k: 3,
+        temperature: 1.,
+    };
+    let (result, stats) = generate_speculative(
+        &mut ctx,
+        ("target", &mut target),
+        ("draft", &mut draft),
+        &prompt,
+        &config,
+        &sampled,
+        || rng.random(),
+        decode,
+    );
+    assert_eq!(result.tokens.len(), 12);
+    assert_eq!(result.finish_reason, FinishReason::Length);
+    assert_eq!(stats.rounds + stats.accepted, 12)
+}
@@ -0,0 +1,303 @@
+//! 可组合的停止条件，见 [`crate::generate::GenerationConfig::stop`]。
+
+use regex::bytes::Regex;
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+/// 停止条件，每生成一个词检查一次，满足时停止生成。结果默认保留最后一个词，[`Self::cut`] 可以截断。
+pub trait StopCriteria: fmt::Debug {
+    /// `tokens` 是已生成的词，`text` 是它们解码得到的字节。
+    fn should_stop(&self, tokens: &[u16], text: &[u8]) -> bool;
+
+    /// [`Self::should_stop`] 满足后结果的截断位置，缺省不截断。
+    fn cut(&self, _tokens: &[u16], _text: &[u8]) -> Option<Cut> {
+        None
+    }
+
+    /// `text` 中可以确定不会被之后的 [`Self::cut`] 截掉的前缀长度，流式输出时其后的部分留在缓冲中。
+    /// `decode` 将词转为字节，与生成时相同。缺省为整个文本。
+    fn safe_len<'a>(
+        &self,
+        _tokens: &[u16],
+        text: &[u8],
+        _decode: &dyn Fn(u16) -> &'a [u8],
+    ) -> usize {
+        text.len()
+    }
+}
+
+/// 停止时结果的截断位置。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Cut {
+    /// 保留前若干个词和它们的文本。
+    Tokens(usize),
+    /// 文本保留前若干字节，词只保留完整落在其中的。
+    Text(usize),
+}
+
+impl Cut {
+    /// 保留的词数和字节数，`decode` 将词转为字节，与生成时相同。
+    pub fn resolve<'a>(self, tokens: &[u16], decode: impl Fn(u16) -> &'a [u8]) -> (usize, usize) {
+        let mut end = 0;
+        let mut n = 0;
+        for &t in tokens {
+            let next = end + decode(t).len();
+            match self {
+                Self::Tokens(len) if n == len => break,
+                Self::Text(len) if next > len => break,
+                _ => {}
+            }
+            end = next;
+            n += 1
+        }
+        match self {
+            Self::Tokens(_) => (n, end),
+            Self::Text(len) => (n, len),
+        }
+    }
+}
+
+/// 生成的词数达到上限。
+#[derive(Clone, Copy, Debug)]
+pub struct MaxTokens(pub usize);
+
+impl StopCriteria for MaxTokens {
+    fn should_stop(&self, tokens: &[u16], _: &[u8]) -> bool {
+        tokens.len() >= self.0
+    }
+}
+
+/// 生成的文本中出现任一字符串，可以跨越词的边界。
+#[derive(Clone, Debug)]
+pub struct StopStrings(pub Vec<String>);
+
+impl StopCriteria for StopStrings {
+    fn should_stop(&self, _: &[u16], text: &[u8]) -> bool {
+        self.0.iter().any(|s| {
+            let s = s.as_bytes();
+            !s.is_empty() && text.windows(s.len()).any(|w| w == s)
+        })
+    }
+}
+
+/// 停止序列：生成的文本中出现任一字符串，或生成的词以任一词序列结尾时停止，
+/// 结果截断到停止序列之前，停止序列本身不出现在结果中。字符串可以跨越词的边界。
+#[derive(Clone, Default, Debug)]
+pub struct StopSequences {
+    pub strings: Vec<String>,
+    pub tokens: Vec<Vec<u16>>,
+}
+
+impl StopCriteria for StopSequences {
+    fn should_stop(&self, tokens: &[u16], text: &[u8]) -> bool {
+        self.cut(tokens, text).is_some()
+    }
+
+    fn cut(&self, tokens: &[u16], text: &[u8]) -> Option<Cut> {
+        // 最早出现的字符串优先
+        let text_cut = self
+            .strings
+            .iter()
+            .map(String::as_bytes)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| text.windows(s.len()).position(|w| w == s))
+            .min()
+            .map(Cut::Text);
+        let tokens_cut = self
+            .tokens
+            .iter()
+            .filter(|s| !s.is_empty() && tokens.ends_with(s))
+            .map(|s| tokens.len() - s.len())
+            .min()
+            .map(Cut::Tokens);
+        text_cut.or(tokens_cut)
+    }
+
+    /// 文本末尾是某个停止字符串的开头，或生成的词以某个词序列的开头结尾时，这部分留在缓冲中，
+    /// 等之后的词确定是否停止。
+    fn safe_len<'a>(&self, tokens: &[u16], text: &[u8], decode: &dyn Fn(u16) -> &'a [u8]) -> usize {
+        let strings = self.strings.iter().flat_map(|s| {
+            let s = s.as_bytes();
+            (1..s.len())
+                .filter(|&k| text.ends_with(&s[..k]))
+                .map(|k| text.len() - k)
+        });
+        let tokens = self.tokens.iter().flat_map(|s| {
+            (1..s.len())
+                .filter(|&k| tokens.ends_with(&s[..k]))
+                .map(|k| {
+                    let held = tokens[tokens.len() - k..]
+                        .iter()
+                        .map(|&t| decode(t).len())
+                        .sum::<usize>();
+                    text.len().saturating_sub(held)
+                })
+        });
+        strings.chain(tokens).fold(text.len(), usize::min)
+    }
+}
+
+/// 生成的文本与正则匹配。
+#[derive(Clone, Debug)]
+pub struct RegexMatch(pub Regex);
+
+impl StopCriteria for RegexMatch {
+    fn should_stop(&self, _: &[u16], text: &[u8]) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+/// 到达墙钟截止时间。
+#[derive(Clone, Copy, Debug)]
+pub struct Deadline(pub Instant);
+
+impl StopCriteria for Deadline {
+    fn should_stop(&self, _: &[u16], _: &[u8]) -> bool {
+        Instant::now() >= self.0
+    }
+}
+
+/// 外部取消，克隆的句柄共享同一个标记，可以在其他线程调用 [`Self::cancel`]。
+#[derive(Clone, Default, Debug)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl StopCriteria for Cancellation {
+    fn should_stop(&self, _: &[u16], _: &[u8]) -> bool {
+        self.is_cancelled()
+    }
+}
+
+/// 任一条件满足时停止。
+#[derive(Clone, Default, Debug)]
+pub struct AnyOf(pub Vec<Arc<dyn StopCriteria + Send + Sync>>);
+
+impl StopCriteria for AnyOf {
+    fn should_stop(&self, tokens: &[u16], text: &[u8]) -> bool {
+        self.0.iter().any(|c| c.should_stop(tokens, text))
+    }
+
+    /// 第一个满足的条件的截断位置。
+    fn cut(&self, tokens: &[u16], text: &[u8]) -> Option<Cut> {
+        self.0
+            .iter()
+            .find(|c| c.should_stop(tokens, text))
+            .and_then(|c| c.cut(tokens, text))
+    }
+
+    fn safe_len<'a>(&self, tokens: &[u16], text: &[u8], decode: &dyn Fn(u16) -> &'a [u8]) -> usize {
+        self.0
+            .iter()
+            .map(|c| c.safe_len(tokens, text, decode))
+            .fold(text.len(), usize::min)
+    }
+}
+
+/// 所有条件同时满足时停止。
+#[derive(Clone, Default, Debug)]
+pub struct AllOf(pub Vec<Arc<dyn StopCriteria + Send + Sync>>);
+
+impl StopCriteria for AllOf {
+    fn should_stop(&self, tokens: &[u16], text: &[u8]) -> bool {
+        self.0.iter().all(|c| c.should_stop(tokens, text))
+    }
+
+    /// 第一个要求截断的条件的截断位置。
+    fn cut(&self, tokens: &[u16], text: &[u8]) -> Option<Cut> {
+        self.0.iter().find_map(|c| c.cut(tokens, text))
+    }
+
+    fn safe_len<'a>(&self, tokens: &[u16], text: &[u8], decode: &dyn Fn(u16) -> &'a [u8]) -> usize {
+        self.0
+            .iter()
+            .map(|c| c.safe_len(tokens, text, decode))
+            .fold(text.len(), usize::min)
+    }
+}
+
+#[test]
+fn test_stop_criteria() {
+    use std::time::Duration;
+
+    assert!(!MaxTokens(3).should_stop(&[1, 2], b""));
+    assert!(MaxTokens(3).should_stop(&[1, 2, 3], b""));
+
+    let strings = StopStrings(vec!["\n\n".into(), "END".into()]);
+    assert!(!strings.should_stop(&[], b"a\nEN"));
+    assert!(strings.should_stop(&[], b"a\nEND."));
+    assert!(strings.should_stop(&[], b"x\n\n"));
+
+    let regex = RegexMatch(Regex::new(r"\d{3}$").unwrap());
+    assert!(!regex.should_stop(&[], b"ab12"));
+    assert!(regex.should_stop(&[], b"ab123"));
+
+    let now = Instant::now();
+    assert!(!Deadline(now + Duration::from_secs(60)).should_stop(&[], b""));
+    assert!(Deadline(now).should_stop(&[], b""));
+
+    let cancel = Cancellation::new();
+    let handle = cancel.clone();
+    assert!(!cancel.should_stop(&[], b""));
+    std::thread::spawn(move || handle.cancel()).join().unwrap();
+    assert!(cancel.should_stop(&[], b""));
+
+    let any = AnyOf(vec![Arc::new(MaxTokens(2)), Arc::new(strings.clone())]);
+    assert!(any.should_stop(&[1, 2], b""));
+    assert!(any.should_stop(&[1], b"END"));
+    let all = AllOf(vec![Arc::new(MaxTokens(2)), Arc::new(strings)]);
+    assert!(!all.should_stop(&[1, 2], b""));
+    assert!(all.should_stop(&[1, 2], b"END"))
+}
+
+#[test]
+fn test_stop_sequences() {
+    let stop = StopSequences {
+        strings: vec!["\n\n".into(), "END".into()],
+        tokens: vec![vec![7, 8]],
+    };
+    // 每个词解码为两个字节
+    let decode = |_| &b"xy"[..];
+
+    assert!(!stop.should_stop(&[1, 2], b"a\nEN"));
+    assert_eq!(stop.cut(&[1, 2, 3], b"a\nEND"), Some(Cut::Text(2)));
+    assert_eq!(Cut::Text(2).resolve(&[1, 2, 3], decode), (1, 2));
+    assert_eq!(Cut::Text(3).resolve(&[1, 2, 3], decode), (1, 3));
+    // 同时出现时取最早的字符串
+    assert_eq!(stop.cut(&[], b"END\n\n"), Some(Cut::Text(0)));
+
+    assert!(!stop.should_stop(&[7, 1, 8], b""));
+    assert_eq!(stop.cut(&[1, 7, 8], b""), Some(Cut::Tokens(1)));
+    assert_eq!(Cut::Tokens(1).resolve(&[1, 7, 8], decode), (1, 2));
+
+    let safe_len = |tokens: &[u16], text: &[u8]| stop.safe_len(tokens, text, &decode);
+    assert_eq!(safe_len(&[], b"abc"), 3);
+    assert_eq!(safe_len(&[], b"abc\n"), 3);
+    assert_eq!(safe_len(&[], b"abcEN"), 3);
+    assert_eq!(safe_len(&[], b"abcE"), 3);
+    // 以词序列的开头结尾时留下这个词的文本
+    assert_eq!(safe_len(&[1, 7], b"xyxy"), 2);
+    assert_eq!(safe_len(&[7, 1], b"xyxy"), 4);
+
+    let any = AnyOf(vec![Arc::new(MaxTokens(2)), Arc::new(stop)]);
+    assert_eq!(any.cut(&[1, 2], b"END"), None);
+    assert_eq!(any.cut(&[1], b"END"), Some(Cut::Text(0)))
+}
@@ -0,0 +1,153 @@
+//! 几秒内就能训练的合成任务。
+//!
+//! 样本按字节编码（字符级），词表大小为 [`N_VOC`]，每个样本以换行结尾。
+
+use rand::Rng;
+use std::{fmt, str::FromStr};
+
+/// 字符级词表大小。
+pub const N_VOC: usize = 256;
+
+/// 合成任务。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Task {
+    /// 复制 `|` 之前的 6 个字符，例如 `qwerty|qwerty`。
+    Copy,
+    /// 两位数以内的加法，例如 `12+45=57`，需要比其他任务多得多的步数。
+    Addition,
+    /// 重复一遍随机字符串，给出第二遍的首字符后补全其余部分，考察归纳头，
+    /// 例如 `kdpwmzqakdpwmzqa`。
+    Induction,
+}
+
+impl Task {
+    pub const ALL: [Self; 3] = [Self::Copy, Self::Addition, Self::Induction];
+
+    /// 生成一个样本。
+    pub fn example(self, rng: &mut impl Rng) -> String {
+        match self {
+            Self::Copy => {
+                let s = letters(6, rng);
+                format!("{s}|{s}\n")
+            }
+            Self::Addition => {
+                let a = rng.random_range(0..100);
+                let b = rng.random_range(0..100);
+                format!("{a}+{b}={}\n", a + b)
+            }
+            Self::Induction => {
+                let s = letters(8, rng);
+                format!("{s}{s}\n")
+            }
+        }
+    }
+
+    /// 将样本拆分为提示和需要模型补全的答案（不含换行）。
+    pub fn split(self, example: &str) -> (&str, &str) {
+        let example = example.trim_end_matches('\n');
+        let mid = match self {
+            Self::Copy => example.find('|').unwrap() + 1,
+            Self::Addition => example.find('=').unwrap() + 1,
+            Self::Induction => example.len() / 2 + 1,
+        };
+        example.split_at(mid)
+    }
+
+    /// 生成 `n_rows` 行，每行一个从行首开始的样本，用换行填充到 `n_seq` 个词。
+    ///
+    /// 末尾多一个词，使 [`crate::llmc::DataLoader::from_tokens`] 能取到最后一行的目标。
+    pub fn tokens(self, n_seq: usize, n_rows: usize, rng: &mut impl Rng) -> Vec<u16> {
+        let mut ans = Vec::with_capacity(n_seq * n_rows + 1);
+        for _ in 0..n_rows {
+            let example = self.example(rng);
+            assert!(example.len() <= n_seq);
+            ans.extend(example.bytes().map(u16::from));
+            ans.resize(ans.len().next_multiple_of(n_seq), b'\n' as _)
+        }
+        ans.push(b'\n' as _);
+        ans
+    }
+}
+
+fn letters(n: usize, rng: &mut impl Rng) -> String {
+    (0..n)
+        .map(|_| rng.random_range(b'a'..=b'z') as char)
+        .collect()
+}
+
+impl FromStr for Task {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(Self::Copy),
+            "addition" | "add" => Ok(Self::Addition),
+            "induction" => Ok(Self::Induction),
+            _ => Err(format!(
+                "unknown task \"{s}\", expect copy, addition or induction"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Copy => write!(f, "copy"),
+            Self::Addition => write!(f, "addition"),
+            Self::Induction => write!(f, "induction"),
+        }
+    }
+}
+
+#[test]
+fn test_tiny_overfit() {
+    use crate::{Context, Tensor, llmc, nn, optimizer::AdamW};
+    use digit_layout::types;
+    use rand::{SeedableRng, rngs::StdRng};
+    use rw_rc::RwRc;
+
+    let config = llmc::Gpt2Config {
+        nblk: 1,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(N_VOC)
+    };
+    let shape = [2, 16];
+    let mut rng = StdRng::seed_from_u64(0);
+    let tokens = Task::Copy.tokens(shape[1], shape[0], &mut rng);
+    let inputs = &tokens[..shape[0] * shape[1]];
+    let targets = &tokens[1..][..shape[0] * shape[1]];
+
+    let mut ctx = Context::new(false);
+    let gpt2 = llmc::Gpt2::random(config, &mut rng).map(RwRc::new);
+    let mut gpt2 = ctx.init::<nn::gpt2::Gpt2>("gpt2", gpt2);
+    let mut loss = ctx.init::<nn::loss::Loss>("loss", N_VOC);
+    let mut adamw = AdamW::new(1e-2, 0.9, 0.999, 1e-8, 0.);
+
+    let mut history = Vec::new();
+    for _ in 0..20 {
+        let tokens = Tensor::new(types::U16, &shape).map(|_| RwRc::new(inputs.into()));
+        let targets = Tensor::new(types::U16, &shape).map(|_| RwRc::new(targets.into()));
+        let logits = ctx.forward("gpt2", &mut gpt2, [tokens.share()]);
+        let losses = ctx.forward("loss", &mut loss, [logits[0].clone(), targets.share()]);
+        let losses_ = losses[0].cloned().merge(0, 2);
+        let losses_ = losses_.as_ref().map(|b| &**b.read()).vector::<f32>();
+        history.push(losses_.iter().sum::<f32>() / losses_.len() as f32);
+
+        ctx.zero_grad();
+        let dlosses = ctx.tensor(types::F32, &shape);
+        dlosses
+            .cloned()
+            .merge(0, 2)
+            .as_ref()
+            .map(|b| &mut **b.write())
+            .vector_mut::<f32>()
+            .fill(1. / losses_.len() as f32);
+        let dlogits = ctx.backward("loss", &mut loss, [dlosses.share()]);
+        let _ = ctx.backward("gpt2", &mut gpt2, dlogits);
+        ctx.update(&mut adamw);
+        adamw.next()
+    }
+    assert!(history[0] > 5., "{history:?}");
+    assert!(history[19] < history[0] / 2., "{history:?}")
+}
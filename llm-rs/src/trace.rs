@@ -0,0 +1,339 @@
+//! 把 tracing span 导出为 Chrome 跟踪事件格式，可以用 Perfetto（<https://ui.perfetto.dev>）或 `chrome://tracing` 打开。
+//!
+//! 每次进入和退出 span 记为一个完整事件（`"ph": "X"`），嵌套的模块 span 在时间线上显示为层级，
+//! 同一时间线上能看到训练步、生成请求、每层和每个算子模块的耗时。
+//! 事件名取 span 的 `name` 字段，没有时用 span 名；类别是 span 名；参数包含所有字段，
+//! 模块还有完整路径 `path`，如 `gpt2.blk[0].attn`。tracing 事件记为瞬时事件（`"ph": "i"`）。
+
+use serde_json::{Map, Value, json};
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::Instant,
+};
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan};
+
+/// 设置此环境变量时 [`crate::log::init`] 把跟踪写到它指定的文件。
+pub const TRACE_ENV: &str = "LLM_RS_TRACE";
+
+/// 记录 span 计时的 [`tracing_subscriber::Layer`]，用 [`layer()`] 或 [`file()`] 创建。
+pub struct ChromeLayer {
+    start: Instant,
+    pid: u32,
+    out: Arc<Mutex<Output>>,
+}
+
+/// 析构时写完跟踪文件，之后的事件被丢弃。
+pub struct FlushGuard(Arc<Mutex<Output>>);
+
+struct Output {
+    writer: Box<dyn Write + Send>,
+    first: bool,
+    finished: bool,
+    /// 已经写出名字的线程。
+    named: HashSet<u64>,
+}
+
+/// 写到 `writer` 的跟踪层，以及写完跟踪的守卫。
+pub fn layer(writer: impl Write + Send + 'static) -> (ChromeLayer, FlushGuard) {
+    let out = Arc::new(Mutex::new(Output {
+        writer: Box::new(writer),
+        first: true,
+        finished: false,
+        named: HashSet::new(),
+    }));
+    let layer = ChromeLayer {
+        start: Instant::now(),
+        pid: std::process::id(),
+        out: out.clone(),
+    };
+    (layer, FlushGuard(out))
+}
+
+/// 写到文件 `path` 的跟踪层，以及写完跟踪的守卫。
+pub fn file(path: impl AsRef<Path>) -> io::Result<(ChromeLayer, FlushGuard)> {
+    File::create(path).map(|f| layer(BufWriter::new(f)))
+}
+
+impl Output {
+    fn write(&mut self, tid: u64, event: Value) {
+        if self.finished {
+            return;
+        }
+        if self.named.insert(tid) {
+            let thread = thread::current();
+            let name = thread.name().map_or_else(|| format!("{tid}"), String::from);
+            let pid = event["pid"].clone();
+            self.push(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": pid,
+                "tid": tid,
+                "args": { "name": name },
+            }))
+        }
+        self.push(event)
+    }
+
+    fn push(&mut self, event: Value) {
+        let sep = if self.first { "[\n" } else { ",\n" };
+        self.first = false;
+        let _ = write!(self.writer, "{sep}{event}");
+    }
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        let mut out = self.0.lock().unwrap();
+        if out.first {
+            let _ = write!(out.writer, "[");
+        }
+        let _ = writeln!(out.writer, "\n]");
+        let _ = out.writer.flush();
+        out.finished = true
+    }
+}
+
+/// span 的名字、参数和尚未退出的进入时刻。
+struct Timing {
+    name: String,
+    cat: &'static str,
+    args: Fields,
+    entered: Vec<f64>,
+}
+
+#[derive(Default)]
+struct Fields(Map<String, Value>);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+}
+
+impl ChromeLayer {
+    /// 从创建此层开始的微秒数。
+    fn now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64() * 1e6
+    }
+}
+
+/// 当前线程的编号，从 0 开始按首次记录的顺序分配。
+fn tid() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    thread_local! {
+        static TID: Cell<Option<u64>> = const { Cell::new(None) };
+    }
+    TID.with(|tid| {
+        let id = tid
+            .get()
+            .unwrap_or_else(|| NEXT.fetch_add(1, Ordering::Relaxed));
+        tid.set(Some(id));
+        id
+    })
+}
+
+impl<S> tracing_subscriber::Layer<S> for ChromeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        let mut args = Fields::default();
+        attrs.record(&mut args);
+        let cat = span.name();
+        let name = match args.0.get("name") {
+            Some(Value::String(name)) => name.clone(),
+            _ => cat.into(),
+        };
+        if cat == "module" {
+            // 连续的模块 span 拼出模块路径
+            let parent = span.parent().and_then(|parent| {
+                let ext = parent.extensions();
+                ext.get::<Timing>()
+                    .filter(|t| t.cat == "module")
+                    .and_then(|t| t.args.0.get("path").cloned())
+            });
+            let path = match parent {
+                Some(Value::String(parent)) => format!("{parent}.{name}"),
+                _ => name.clone(),
+            };
+            args.0.insert("path".into(), path.into());
+        }
+        span.extensions_mut().insert(Timing {
+            name,
+            cat,
+            args,
+            entered: Vec::new(),
+        })
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+            values.record(&mut timing.args)
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let now = self.now();
+        let span = ctx.span(id).unwrap();
+        if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+            timing.entered.push(now)
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let now = self.now();
+        let span = ctx.span(id).unwrap();
+        let mut ext = span.extensions_mut();
+        let Some(timing) = ext.get_mut::<Timing>() else {
+            return;
+        };
+        let Some(ts) = timing.entered.pop() else {
+            return;
+        };
+        let tid = tid();
+        let event = json!({
+            "name": timing.name,
+            "cat": timing.cat,
+            "ph": "X",
+            "ts": ts,
+            "dur": now - ts,
+            "pid": self.pid,
+            "tid": tid,
+            "args": timing.args.0,
+        });
+        self.out.lock().unwrap().write(tid, event)
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let ts = self.now();
+        let mut args = Fields::default();
+        event.record(&mut args);
+        let name = match args.0.remove("message") {
+            Some(Value::String(message)) => message,
+            _ => event.metadata().name().into(),
+        };
+        let tid = tid();
+        let event = json!({
+            "name": name,
+            "cat": event.metadata().target(),
+            "ph": "i",
+            "s": "t",
+            "ts": ts,
+            "pid": self.pid,
+            "tid": tid,
+            "args": args.0,
+        });
+        self.out.lock().unwrap().write(tid, event)
+    }
+}
+
+#[test]
+fn test_chrome_trace() {
+    use crate::{Blob, Tensor, llmc, nn::gpt2::Gpt2};
+    use digit_layout::types;
+    use rand::{SeedableRng, rngs::StdRng};
+    use rw_rc::RwRc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buf = Shared::default();
+    let (layer, guard) = layer(buf.clone());
+    let subscriber = tracing_subscriber::registry().with(layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let model = llmc::Gpt2::random(llmc::Gpt2Config::tiny(64), &mut StdRng::seed_from_u64(7))
+            .map(RwRc::new);
+        let mut ctx = crate::Context::new(false);
+        let mut gpt2 = ctx.init::<Gpt2>("gpt2", model);
+        let _span = tracing::info_span!("step", step = 3).entered();
+        let tokens = Tensor::new(types::U16, &[1, 4])
+            .map(|_| Blob::from(&[1u16, 2, 3, 4][..]))
+            .map(RwRc::new);
+        let _ = ctx.forward("gpt2", &mut gpt2, [tokens.share()]);
+        tracing::info!(loss = 1.5, "trained")
+    });
+    drop(guard);
+
+    let events = serde_json::from_slice::<Vec<Value>>(&buf.0.lock().unwrap()).unwrap();
+    let find = |ph: &str, name: &str| {
+        events
+            .iter()
+            .filter(|e| e["ph"] == ph && e["name"] == name)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(find("M", "thread_name").len(), 1);
+
+    let step = find("X", "step");
+    assert_eq!(step.len(), 1);
+    assert_eq!(step[0]["args"]["step"], 3);
+
+    // 初始化和前向各有一次 gpt2 模块 span，前向的那次嵌套在 step 中
+    let gpt2 = find("X", "gpt2");
+    assert_eq!(gpt2.len(), 2);
+    let range = |e: &Value| {
+        let ts = e["ts"].as_f64().unwrap();
+        (ts, ts + e["dur"].as_f64().unwrap())
+    };
+    let (step_start, step_end) = range(step[0]);
+    let (start, end) = range(gpt2[1]);
+    assert!(step_start <= start && end <= step_end);
+
+    let blk = find("X", "blk[1]");
+    assert!(!blk.is_empty());
+    assert!(blk.iter().all(|e| e["args"]["path"] == "gpt2.blk[1]"));
+    let (blk_start, blk_end) = range(blk.last().unwrap());
+    assert!(start <= blk_start && blk_end <= end);
+
+    let trained = find("i", "trained");
+    assert_eq!(trained.len(), 1);
+    assert_eq!(trained[0]["args"]["loss"], 1.5)
+}
@@ -0,0 +1,3 @@
+//! 训练流程。
+
+pub mod ppo;
@@ -0,0 +1,412 @@
+//! 实验性的 PPO 强化学习微调。
+//!
+//! 每轮从策略模型采样一组补全（[`Ppo::rollout`]），用奖励模型打分，
+//! 再以截断的策略比率多次更新策略（[`Ppo::step`]）。每个词的奖励包含对参考模型的 KL 惩罚，
+//! 参考模型是构造时策略权重的副本，不参与训练。
+//!
+//! 没有价值网络：优势是逐词奖励的累计回报在整批中标准化的结果，批均值充当基线。
+
+use crate::{
+    Blob, Context, Tensor,
+    generate::{GenerationConfig, generate_cached},
+    llmc,
+    nn::{gpt2::Gpt2, loss::Loss},
+    optimizer::AdamW,
+    reward::RewardModel,
+    truncate::Truncation,
+};
+use digit_layout::types;
+use rand::Rng;
+use rw_rc::RwRc;
+use serde::Serialize;
+use std::iter::zip;
+
+/// PPO 超参数。
+#[derive(Clone, Debug)]
+pub struct PpoConfig {
+    /// 每个提示采样的补全数。
+    pub n_rollouts: usize,
+    /// 每个补全最多生成的词数。
+    pub max_tokens: usize,
+    /// 结束词，生成时停止，它不计入补全。
+    pub eos: Option<u16>,
+    /// 策略比率的截断范围 `[1 - clip, 1 + clip]`。
+    pub clip: f32,
+    /// KL 惩罚系数。
+    pub kl_coef: f32,
+    /// 每批补全更新策略的次数。
+    pub epochs: usize,
+}
+
+impl Default for PpoConfig {
+    fn default() -> Self {
+        Self {
+            n_rollouts: 8,
+            max_tokens: 32,
+            eos: None,
+            clip: 0.2,
+            kl_coef: 0.05,
+            epochs: 4,
+        }
+    }
+}
+
+/// 一个补全及其打分。
+#[derive(Clone, Debug)]
+pub struct Rollout {
+    /// 提示和补全。
+    pub tokens: Vec<u16>,
+    pub n_prompt: usize,
+    /// 采样时策略对每个补全词的对数概率。
+    pub logprobs: Vec<f32>,
+    /// 参考模型对每个补全词的对数概率。
+    pub ref_logprobs: Vec<f32>,
+    /// 奖励模型对整个序列的打分。
+    pub reward: f32,
+}
+
+/// 一次 [`Ppo::step`] 的统计。
+#[derive(Clone, Copy, Default, Debug, Serialize)]
+pub struct PpoStats {
+    /// 平均奖励。
+    pub reward: f32,
+    /// 采样策略与参考模型的平均逐词 KL 估计。
+    pub kl: f32,
+    /// 最后一次更新中比率被截断的词的比例。
+    pub clip_fraction: f32,
+    /// 最后一次更新的平均截断目标，取负作为损失。
+    pub loss: f32,
+}
+
+pub struct Ppo {
+    config: PpoConfig,
+    n_ctx: usize,
+    n_voc: usize,
+    ctx: Context,
+    policy: Gpt2,
+    loss: Loss,
+    ref_ctx: Context,
+    reference: Gpt2,
+    ref_loss: Loss,
+}
+
+impl Ppo {
+    /// 以 `model` 为初始策略，参考模型是它的一份独立副本。
+    pub fn new(model: llmc::Gpt2<RwRc<Blob>>, config: PpoConfig) -> Self {
+        let n_ctx = model.config.n_seq;
+        let n_voc = model.config.n_voc;
+        let frozen = model
+            .clone()
+            .map(|blob| RwRc::new(Blob::from(&**blob.read())));
+
+        let mut ctx = Context::new(false);
+        let policy = ctx.init::<Gpt2>("gpt2", model);
+        let loss = ctx.init::<Loss>("loss", n_voc);
+        let mut ref_ctx = Context::new(false);
+        let reference = ref_ctx.init::<Gpt2>("gpt2", frozen);
+        let ref_loss = ref_ctx.init::<Loss>("loss", n_voc);
+        Self {
+            config,
+            n_ctx,
+            n_voc,
+            ctx,
+            policy,
+            loss,
+            ref_ctx,
+            reference,
+            ref_loss,
+        }
+    }
+
+    /// 当前的策略模型。
+    pub fn policy(&mut self) -> &mut Gpt2 {
+        &mut self.policy
+    }
+
+    /// 从 `prompt` 采样 [`PpoConfig::n_rollouts`] 个补全，记录对数概率并用 `reward` 打分。
+    pub fn rollout(
+        &mut self,
+        prompt: &[u16],
+        reward: &mut impl RewardModel,
+        rng: &mut impl Rng,
+    ) -> Vec<Rollout> {
+        let Self {
+            config,
+            n_ctx,
+            n_voc,
+            ctx,
+            policy,
+            loss,
+            ref_ctx,
+            reference,
+            ref_loss,
+        } = self;
+        let generation = GenerationConfig {
+            n_ctx: *n_ctx,
+            n_voc: *n_voc,
+            max_tokens: config.max_tokens,
+            eos: config.eos,
+            truncation: Truncation::KeepTail,
+            echo: false,
+            stop: None,
+            cost: Default::default(),
+        };
+        let seqs = (0..config.n_rollouts)
+            .map(|_| {
+                let result = generate_cached(
+                    ctx,
+                    "gpt2",
+                    policy,
+                    prompt,
+                    &generation,
+                    |logits| sample(logits, rng.random()),
+                    |_| &[],
+                );
+                let n_prompt = result.n_prompt;
+                let mut tokens = prompt[prompt.len() - n_prompt..].to_vec();
+                tokens.extend(result.tokens);
+                (tokens, n_prompt)
+            })
+            .collect::<Vec<_>>();
+
+        // 采样路径与训练路径的前向有末位差异，旧的对数概率用训练路径重算，首次更新的比率恰为 1
+        let logprobs = token_logprobs(ctx, policy, loss, &seqs);
+        let ref_logprobs = token_logprobs(ref_ctx, reference, ref_loss, &seqs);
+        let rewards = reward.score_batch(&seqs.iter().map(|(t, _)| &t[..]).collect::<Vec<_>>());
+        izip_rollouts(seqs, logprobs, ref_logprobs, rewards)
+    }
+
+    /// 用一批补全更新策略 [`PpoConfig::epochs`] 次。
+    pub fn step(&mut self, rollouts: &[Rollout], optimizer: &mut AdamW) -> PpoStats {
+        let Self {
+            config,
+            ctx,
+            policy,
+            loss,
+            ..
+        } = self;
+        let &PpoConfig {
+            clip,
+            kl_coef,
+            epochs,
+            ..
+        } = &*config;
+        let rollouts = rollouts
+            .iter()
+            .filter(|r| r.tokens.len() > r.n_prompt)
+            .collect::<Vec<_>>();
+        let n_rollouts = rollouts.len().max(1) as f32;
+        let mut stats = PpoStats {
+            reward: rollouts.iter().map(|r| r.reward).sum::<f32>() / n_rollouts,
+            ..Default::default()
+        };
+        if rollouts.is_empty() {
+            return stats;
+        }
+
+        // 逐词奖励：对参考模型的 KL 惩罚，最后一个词再加上序列的奖励
+        let mut returns = rollouts
+            .iter()
+            .map(|r| {
+                let mut rewards = zip(&r.logprobs, &r.ref_logprobs)
+                    .map(|(lp, ref_lp)| -kl_coef * (lp - ref_lp))
+                    .collect::<Vec<_>>();
+                *rewards.last_mut().unwrap() += r.reward;
+                // 累计回报
+                let mut acc = 0.;
+                for r in rewards.iter_mut().rev() {
+                    acc += *r;
+                    *r = acc
+                }
+                rewards
+            })
+            .collect::<Vec<_>>();
+        let n_tokens = returns.iter().map(Vec::len).sum::<usize>() as f32;
+        stats.kl = rollouts
+            .iter()
+            .flat_map(|r| zip(&r.logprobs, &r.ref_logprobs).map(|(lp, ref_lp)| lp - ref_lp))
+            .sum::<f32>()
+            / n_tokens;
+        let mean = returns.iter().flatten().sum::<f32>() / n_tokens;
+        let var = returns
+            .iter()
+            .flatten()
+            .map(|r| (r - mean).powi(2))
+            .sum::<f32>()
+            / n_tokens;
+        let std_inv = 1. / (var.sqrt() + 1e-8);
+        for r in returns.iter_mut().flatten() {
+            *r = (*r - mean) * std_inv
+        }
+        let advantages = returns;
+
+        let seqs = rollouts
+            .iter()
+            .map(|r| (r.tokens.clone(), r.n_prompt))
+            .collect::<Vec<_>>();
+        let (tokens, targets, n_seq) = pad(&seqs);
+        let shape = [seqs.len(), n_seq];
+        for _ in 0..epochs {
+            let tokens = Tensor::new(types::U16, &shape).map(|_| RwRc::new((&*tokens).into()));
+            let targets = Tensor::new(types::U16, &shape).map(|_| RwRc::new((&*targets).into()));
+            let logits = ctx.forward("gpt2", policy, [tokens.share()]);
+            let losses = ctx.forward("loss", loss, [logits[0].clone(), targets.share()]);
+            ctx.zero_grad();
+
+            // 截断目标 min(ρA, clip(ρ)A) 对 -log p 的梯度为 ρA，比率被截断时为 0
+            let losses = read_f32(&losses[0]);
+            let mut dlosses = vec![0f32; losses.len()];
+            let (mut objective, mut n_clipped) = (0., 0);
+            for (i, (r, advantages)) in zip(&rollouts, &advantages).enumerate() {
+                for (j, (&lp_old, &a)) in zip(&r.logprobs, advantages).enumerate() {
+                    let k = i * n_seq + r.n_prompt - 1 + j;
+                    let ratio = (-losses[k] - lp_old).exp();
+                    let clipped = ratio.clamp(1. - clip, 1. + clip);
+                    objective += (ratio * a).min(clipped * a);
+                    if (ratio * a) <= (clipped * a) {
+                        dlosses[k] = ratio * a / n_tokens
+                    } else {
+                        n_clipped += 1
+                    }
+                }
+            }
+            stats.loss = -objective / n_tokens;
+            stats.clip_fraction = n_clipped as f32 / n_tokens;
+
+            let dlosses = Tensor::new(types::F32, &shape).map(|_| RwRc::new((&*dlosses).into()));
+            let dlogits = ctx.backward("loss", loss, [dlosses.share()]);
+            let _ = ctx.backward("gpt2", policy, dlogits);
+            ctx.update(optimizer);
+            optimizer.next()
+        }
+        stats
+    }
+}
+
+fn izip_rollouts(
+    seqs: Vec<(Vec<u16>, usize)>,
+    logprobs: Vec<Vec<f32>>,
+    ref_logprobs: Vec<Vec<f32>>,
+    rewards: Vec<f32>,
+) -> Vec<Rollout> {
+    zip(zip(seqs, logprobs), zip(ref_logprobs, rewards))
+        .map(
+            |(((tokens, n_prompt), logprobs), (ref_logprobs, reward))| Rollout {
+                tokens,
+                n_prompt,
+                logprobs,
+                ref_logprobs,
+                reward,
+            },
+        )
+        .collect()
+}
+
+/// 右填充为 `[n, n_seq]` 的输入和目标，目标是左移一位的输入。
+fn pad(seqs: &[(Vec<u16>, usize)]) -> (Vec<u16>, Vec<u16>, usize) {
+    let n_seq = seqs.iter().map(|(t, _)| t.len()).max().unwrap();
+    let mut tokens = vec![0; seqs.len() * n_seq];
+    let mut targets = vec![0; seqs.len() * n_seq];
+    for ((seq, _), (tokens, targets)) in zip(
+        seqs,
+        zip(tokens.chunks_mut(n_seq), targets.chunks_mut(n_seq)),
+    ) {
+        tokens[..seq.len()].copy_from_slice(seq);
+        targets[..seq.len() - 1].copy_from_slice(&seq[1..])
+    }
+    (tokens, targets, n_seq)
+}
+
+/// 每个序列补全部分各词的对数概率。
+fn token_logprobs(
+    ctx: &mut Context,
+    model: &mut Gpt2,
+    loss: &mut Loss,
+    seqs: &[(Vec<u16>, usize)],
+) -> Vec<Vec<f32>> {
+    let (tokens, targets, n_seq) = pad(seqs);
+    let shape = [seqs.len(), n_seq];
+    let tokens = Tensor::new(types::U16, &shape).map(|_| RwRc::new((&*tokens).into()));
+    let targets = Tensor::new(types::U16, &shape).map(|_| RwRc::new((&*targets).into()));
+    let logits = ctx.forward("gpt2", model, [tokens.share()]);
+    let losses = ctx.forward("loss", loss, [logits[0].clone(), targets.share()]);
+    let losses = read_f32(&losses[0]);
+    zip(seqs, losses.chunks(n_seq))
+        .map(|((seq, n_prompt), losses)| {
+            losses[n_prompt - 1..seq.len() - 1]
+                .iter()
+                .map(|l| -l)
+                .collect()
+        })
+        .collect()
+}
+
+fn read_f32(t: &Tensor<RwRc<Blob>>) -> Vec<f32> {
+    let ([], buf, []) = (unsafe { t.get().read().align_to::<f32>() }) else {
+        unreachable!()
+    };
+    let ans = buf.to_vec();
+    t.get().release();
+    ans
+}
+
+/// 按 softmax 概率采样，`coin` 为 `[0, 1)` 的均匀随机数。
+fn sample(logits: &[f32], coin: f32) -> u16 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum = logits.iter().map(|x| (x - max).exp()).sum::<f32>();
+    let limit = sum * coin;
+    let mut acc = 0.;
+    for (i, x) in logits.iter().enumerate() {
+        acc += (x - max).exp();
+        if acc >= limit {
+            return i as _;
+        }
+    }
+    (logits.len() - 1) as _
+}
+
+#[test]
+fn test_ppo() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let config = llmc::Gpt2Config {
+        nblk: 1,
+        d: 32,
+        ..llmc::Gpt2Config::tiny(16)
+    };
+    let mut rng = StdRng::seed_from_u64(0);
+    let model = llmc::Gpt2::random(config, &mut rng).map(RwRc::new);
+    let mut ppo = Ppo::new(
+        model,
+        PpoConfig {
+            n_rollouts: 8,
+            max_tokens: 4,
+            ..Default::default()
+        },
+    );
+    let mut adamw = AdamW::new(1e-2, 0.9, 0.999, 1e-8, 0.);
+    // 补全中词 1 的比例
+    let mut reward = |tokens: &[u16]| {
+        let completion = &tokens[1..];
+        completion.iter().filter(|&&t| t == 1).count() as f32 / completion.len() as f32
+    };
+
+    let mut history = Vec::new();
+    for i in 0..15 {
+        let rollouts = ppo.rollout(&[0], &mut reward, &mut rng);
+        assert!(
+            rollouts
+                .iter()
+                .all(|r| r.logprobs.len() == r.tokens.len() - 1)
+        );
+        let stats = ppo.step(&rollouts, &mut adamw);
+        if i == 0 {
+            // 策略与参考模型相同
+            assert!(stats.kl.abs() < 1e-6)
+        }
+        history.push(stats.reward)
+    }
+    let early = history[..5].iter().sum::<f32>() / 5.;
+    let late = history[10..].iter().sum::<f32>() / 5.;
+    assert!(late > early + 0.2, "{history:?}")
+}
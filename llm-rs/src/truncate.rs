@@ -0,0 +1,80 @@
+//! 输入超过上下文长度时的截断策略。
+
+/// 截断策略。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Truncation {
+    /// 保留开头。
+    KeepHead,
+    /// 保留结尾，生成时最常用。
+    KeepTail,
+    /// 保留开头和结尾，中间替换为一个省略词。
+    DropMiddle { ellipsis: u16 },
+}
+
+impl Truncation {
+    /// 将 `tokens` 截断到不超过 `n_ctx` 个词。
+    pub fn apply(self, tokens: &[u16], n_ctx: usize) -> Vec<u16> {
+        let len = tokens.len();
+        if len <= n_ctx {
+            return tokens.to_vec();
+        }
+        match self {
+            Self::KeepHead => tokens[..n_ctx].to_vec(),
+            Self::KeepTail => tokens[len - n_ctx..].to_vec(),
+            Self::DropMiddle { .. } if n_ctx < 3 => Self::KeepTail.apply(tokens, n_ctx),
+            Self::DropMiddle { ellipsis } => {
+                // 结尾多保留一个词，靠近生成位置的内容更重要
+                let head = (n_ctx - 1) / 2;
+                let tail = n_ctx - 1 - head;
+                let mut ans = Vec::with_capacity(n_ctx);
+                ans.extend_from_slice(&tokens[..head]);
+                ans.push(ellipsis);
+                ans.extend_from_slice(&tokens[len - tail..]);
+                ans
+            }
+        }
+    }
+
+    /// 按消息截断对话并拼接，结果不超过 `n_ctx` 个词。
+    ///
+    /// 第一条消息视为系统提示，优先保留；其余消息从最新的开始整条放入，
+    /// 放不下的那一条按本策略截断到剩余长度，更早的消息全部丢弃。
+    pub fn apply_chat(self, messages: &[&[u16]], n_ctx: usize) -> Vec<u16> {
+        let [system, history @ ..] = messages else {
+            return Vec::new();
+        };
+        let system = self.apply(system, n_ctx);
+
+        let mut budget = n_ctx - system.len();
+        let mut kept = Vec::new();
+        for msg in history.iter().rev() {
+            if budget == 0 {
+                break;
+            }
+            let msg = self.apply(msg, budget);
+            budget -= msg.len();
+            kept.push(msg)
+        }
+
+        let mut ans = system;
+        ans.extend(kept.into_iter().rev().flatten());
+        ans
+    }
+}
+
+#[test]
+fn test_truncation() {
+    let tokens = (0..10).collect::<Vec<u16>>();
+    assert_eq!(Truncation::KeepHead.apply(&tokens, 4), [0, 1, 2, 3]);
+    assert_eq!(Truncation::KeepTail.apply(&tokens, 4), [6, 7, 8, 9]);
+    let drop = Truncation::DropMiddle { ellipsis: 99 };
+    assert_eq!(drop.apply(&tokens, 6), [0, 1, 99, 7, 8, 9]);
+    assert_eq!(drop.apply(&tokens, 20), tokens);
+
+    let chat: [&[u16]; 4] = [&[1, 1], &[2, 2, 2], &[3, 3, 3, 3], &[4, 4]];
+    assert_eq!(
+        Truncation::KeepTail.apply_chat(&chat, 7),
+        [1, 1, 3, 3, 3, 4, 4]
+    );
+    assert_eq!(Truncation::KeepTail.apply_chat(&chat, 1), [1]);
+}
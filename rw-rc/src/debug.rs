@@ -0,0 +1,82 @@
+//! 记录读写占用者，在冲突时报告。
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt,
+    panic::Location,
+};
+
+thread_local! {
+    static NEXT_ID: Cell<usize> = const { Cell::new(0) };
+    static LABELS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) fn next_id() -> usize {
+    NEXT_ID.with(|id| {
+        let ans = id.get();
+        id.set(ans + 1);
+        ans
+    })
+}
+
+/// 当前的标签，没有标签时为 `-`。
+pub(crate) fn label() -> String {
+    LABELS.with_borrow(|labels| labels.last().cloned().unwrap_or_else(|| "-".into()))
+}
+
+/// 标签守卫：存在期间进入读写状态的副本都记录此标签，例如模块路径。
+pub struct Label(());
+
+impl Label {
+    pub fn push(label: impl Into<String>) -> Self {
+        LABELS.with_borrow_mut(|labels| labels.push(label.into()));
+        Self(())
+    }
+}
+
+impl Drop for Label {
+    fn drop(&mut self) {
+        LABELS.with_borrow_mut(|labels| labels.pop());
+    }
+}
+
+struct Holder {
+    id: usize,
+    write: bool,
+    label: String,
+    location: &'static Location<'static>,
+}
+
+/// 当前占用读写状态的副本。
+#[derive(Default)]
+pub(crate) struct Holders(RefCell<Vec<Holder>>);
+
+impl Holders {
+    pub fn insert(&self, id: usize, write: bool, location: &'static Location<'static>) {
+        let holder = Holder {
+            id,
+            write,
+            label: label(),
+            location,
+        };
+        let mut holders = self.0.borrow_mut();
+        match holders.iter_mut().find(|h| h.id == id) {
+            Some(h) => *h = holder,
+            None => holders.push(holder),
+        }
+    }
+
+    pub fn remove(&self, id: usize) {
+        self.0.borrow_mut().retain(|h| h.id != id)
+    }
+}
+
+impl fmt::Display for Holders {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for h in &*self.0.borrow() {
+            let op = if h.write { "write" } else { "read" };
+            writeln!(f, "  #{} {op} at {} ({})", h.id, h.label, h.location)?
+        }
+        Ok(())
+    }
+}
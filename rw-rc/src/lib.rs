@@ -1,16 +1,26 @@
+#[cfg(feature = "debug-borrow")]
+mod debug;
+
 use std::{
     cell::Cell,
     cmp,
     hash::Hash,
+    panic::Location,
     rc::{Rc, Weak},
 };
 
+#[cfg(feature = "debug-borrow")]
+pub use debug::Label;
+
 /// 带有预期读写状态的引用计数。
 pub struct RwRc<T> {
     /// 共享的对象和状态。
     rc: Rc<Internal<T>>,
     /// 此副本占用的读写状态。
     state: Cell<RwState>,
+    /// 此副本的编号，用于记录占用者。
+    #[cfg(feature = "debug-borrow")]
+    id: usize,
 }
 
 #[repr(transparent)]
@@ -22,6 +32,9 @@ struct Internal<T> {
     val: Cell<T>,
     /// 共享读写状态。
     flag: RwFlag,
+    /// 当前占用读写状态的副本。
+    #[cfg(feature = "debug-borrow")]
+    holders: debug::Holders,
 }
 
 /// 副本读写状态。
@@ -43,10 +56,7 @@ impl<T> From<T> for RwRc<T> {
 
 impl<T> Clone for RwRc<T> {
     fn clone(&self) -> Self {
-        Self {
-            rc: self.rc.clone(),
-            state: Cell::new(RwState::Hold),
-        }
+        Self::hold(self.rc.clone())
     }
 }
 
@@ -58,21 +68,31 @@ impl<T> Drop for RwRc<T> {
 
 impl<T> RwRc<T> {
     pub fn new(val: T) -> Self {
+        Self::hold(Rc::new(Internal {
+            val: Cell::new(val),
+            flag: RwFlag::new(),
+            #[cfg(feature = "debug-borrow")]
+            holders: Default::default(),
+        }))
+    }
+
+    fn hold(rc: Rc<Internal<T>>) -> Self {
         Self {
-            rc: Rc::new(Internal {
-                val: Cell::new(val),
-                flag: RwFlag::new(),
-            }),
+            rc,
             state: Cell::new(RwState::Hold),
+            #[cfg(feature = "debug-borrow")]
+            id: debug::next_id(),
         }
     }
 
+    #[track_caller]
     pub fn try_read(&self) -> Option<&T> {
         match self.state.get() {
             RwState::Hold => {
                 if !self.rc.flag.read() {
                     return None;
                 }
+                self.record(false, Location::caller())
             }
             RwState::Read | RwState::Write => {}
         }
@@ -80,16 +100,24 @@ impl<T> RwRc<T> {
         Some(unsafe { &*self.rc.val.as_ptr() })
     }
 
+    #[track_caller]
+    #[allow(clippy::mut_from_ref)]
     pub fn try_write(&self) -> Option<&mut T> {
         match self.state.get() {
             RwState::Hold => {
                 if !self.rc.flag.write() {
                     return None;
                 }
+                self.record(true, Location::caller())
             }
             RwState::Read => {
                 self.rc.flag.release_read();
-                assert!(self.rc.flag.write())
+                if !self.rc.flag.write() {
+                    // 其他副本也在读，恢复原状态
+                    assert!(self.rc.flag.read());
+                    return None;
+                }
+                self.record(true, Location::caller())
             }
             RwState::Write => {}
         }
@@ -103,14 +131,49 @@ impl<T> RwRc<T> {
             RwState::Read => self.rc.flag.release_read(),
             RwState::Write => self.rc.flag.release_write(),
         }
+        #[cfg(feature = "debug-borrow")]
+        self.rc.holders.remove(self.id)
     }
 
+    #[track_caller]
     pub fn read(&self) -> &T {
-        self.try_read().unwrap()
+        match self.try_read() {
+            Some(val) => val,
+            None => self.conflict("read"),
+        }
     }
 
+    #[track_caller]
+    #[allow(clippy::mut_from_ref)]
     pub fn write(&self) -> &mut T {
-        self.try_write().unwrap()
+        match self.try_write() {
+            Some(val) => val,
+            None => self.conflict("write"),
+        }
+    }
+
+    /// 记录此副本进入读或写状态的位置。
+    #[allow(unused_variables)]
+    fn record(&self, write: bool, location: &'static Location<'static>) {
+        #[cfg(feature = "debug-borrow")]
+        self.rc.holders.insert(self.id, write, location)
+    }
+
+    /// 读写冲突，开启 `debug-borrow` 时列出当前的占用者。
+    #[track_caller]
+    fn conflict(&self, op: &str) -> ! {
+        #[cfg(feature = "debug-borrow")]
+        {
+            panic!(
+                "cannot {op} RwRc at {}, held by:\n{}",
+                debug::label(),
+                self.rc.holders
+            )
+        }
+        #[cfg(not(feature = "debug-borrow"))]
+        {
+            panic!("cannot {op} RwRc, enable feature `debug-borrow` to see the holders")
+        }
     }
 
     pub fn weak(&self) -> RwWeak<T> {
@@ -152,10 +215,7 @@ impl<T> Ord for RwWeak<T> {
 
 impl<T> RwWeak<T> {
     pub fn hold(&self) -> Option<RwRc<T>> {
-        self.0.upgrade().map(|rc| RwRc {
-            rc,
-            state: Cell::new(RwState::Hold),
-        })
+        self.0.upgrade().map(RwRc::hold)
     }
 }
 
@@ -205,3 +265,12 @@ impl RwFlag {
         self.0.set(0)
     }
 }
+
+#[test]
+#[should_panic(expected = "cannot write RwRc")]
+fn test_conflict() {
+    let a = RwRc::new(0);
+    let b = a.clone();
+    let _ = a.read();
+    let _ = b.write();
+}
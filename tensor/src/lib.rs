@@ -78,7 +78,7 @@ impl<T, const N: usize> Tensor<T, N> {
         &mut self.data
     }
 
-    pub fn shape(&self) -> Cow<[usize]> {
+    pub fn shape(&self) -> Cow<'_, [usize]> {
         match self.dt.group_size() {
             1 => self.layout.shape().into(),
             g => {
@@ -90,6 +90,10 @@ impl<T, const N: usize> Tensor<T, N> {
     }
 
     pub fn is_contiguous(&self) -> bool {
+        // ndarray-layout 不能合并各维都是 1 的形状，只有一个元素时总是连续的
+        if self.layout.shape().iter().all(|&d| d == 1) {
+            return true;
+        }
         match self.layout.merge_be(0, self.layout.ndim()) {
             Some(layout) => {
                 let &[s] = layout.strides() else {
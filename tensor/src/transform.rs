@@ -14,9 +14,36 @@ impl<T, const N: usize> Tensor<T, N> {
     }
 
     pub fn merge(self, axis: usize, len: usize) -> Self {
+        let mut layout = self.layout;
+        // ndarray-layout 合并长度全为 1 的维度时会多出维度，先去掉多余的维度
+        if layout.shape()[axis..][..len].iter().all(|&d| d == 1) {
+            for _ in 1..len {
+                layout = layout.index(axis, 0)
+            }
+        } else {
+            layout = layout.merge_be(axis, len).unwrap()
+        }
+        Self {
+            dt: self.dt,
+            layout,
+            data: self.data,
+        }
+    }
+
+    /// 取 `axis` 维的 `[start, start + len)`，不复制数据。
+    pub fn slice(self, axis: usize, start: usize, len: usize) -> Self {
+        Self {
+            dt: self.dt,
+            layout: self.layout.slice(axis, start, 1, len),
+            data: self.data,
+        }
+    }
+
+    /// 把 `axis` 维拆分为 `tiles`，高维在前。
+    pub fn tile(self, axis: usize, tiles: &[usize]) -> Self {
         Self {
             dt: self.dt,
-            layout: self.layout.merge_be(axis, len).unwrap(),
+            layout: self.layout.tile_be(axis, tiles),
             data: self.data,
         }
     }